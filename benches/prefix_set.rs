@@ -0,0 +1,46 @@
+//! Compares [`PrefixSet`] against a linear `starts_with` scan over path lists of growing size.
+//!
+//! `cargo bench --bench prefix_set`
+
+use std::{hint::black_box, time::Instant};
+
+use proxy_sdk::matcher::PrefixSet;
+
+const ITERATIONS: u32 = 20_000;
+
+fn paths(count: usize) -> Vec<String> {
+    (0..count)
+        .map(|i| format!("/api/v{}/tenant-{}/resource-{}/", i % 7, i / 7, i))
+        .collect()
+}
+
+fn time(name: &str, count: usize, f: impl Fn() -> bool) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{name:>12} {count:>6} prefixes: {elapsed:?}");
+}
+
+fn main() {
+    for count in [100, 1_000, 10_000] {
+        let prefixes = paths(count);
+        let set = prefixes
+            .iter()
+            .fold(PrefixSet::builder(), |builder, x| {
+                builder.prefix(x.as_bytes(), ())
+            })
+            .build();
+        // misses the list, so the scan visits every prefix
+        let input = "/api/v3/tenant-unknown/resource-1/items?page=2";
+        time("prefix set", count, || {
+            set.find(black_box(input.as_bytes())).is_some()
+        });
+        time("linear scan", count, || {
+            prefixes
+                .iter()
+                .any(|x| black_box(input).starts_with(x.as_str()))
+        });
+    }
+}
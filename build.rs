@@ -1,7 +1,13 @@
 fn main() {
     prost_build::Config::default()
         .compile_protos(
-            &["proto/grpc_service.proto", "proto/attributes.proto"],
+            &[
+                "proto/grpc_service.proto",
+                "proto/attributes.proto",
+                "proto/ratelimit.proto",
+                "proto/ext_authz.proto",
+                "proto/otlp_trace.proto",
+            ],
             &["proto"],
         )
         .unwrap();
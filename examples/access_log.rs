@@ -0,0 +1,169 @@
+//! Envoy access log plugin that formats each request as a JSON line and ships batches to a collector over a GRPC stream.
+//! The collector receives each batch as a single message of newline-delimited JSON.
+//!
+//! Example configuration:
+//! `{"cluster": "log_collector", "fields": {"status": "response.code", "path": "request.path"}}`
+
+use std::time::Duration;
+
+use log::{warn, Level};
+use proxy_sdk::{
+    access_log::JsonLogFormat, json::Value, BaseContext, Context, GrpcStreamBuilder,
+    GrpcStreamClose, GrpcStreamHandle, HttpContext, RootContext, Upstream,
+};
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    #[global_allocator]
+    static ALLOC: dlmalloc::GlobalDlmalloc = dlmalloc::GlobalDlmalloc;
+
+    #[no_mangle]
+    pub extern "C" fn free(from: *mut std::ffi::c_void) {
+        unsafe { drop(Box::from_raw(from as *mut u8)) };
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[global_allocator]
+    static ALLOC: Mallocator = Mallocator;
+
+    pub struct Mallocator;
+
+    unsafe impl GlobalAlloc for Mallocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            malloc(layout.size())
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+            free(ptr);
+        }
+    }
+
+    extern "C" {
+        fn malloc(size: usize) -> *mut u8;
+        fn free(ptr: *mut u8);
+    }
+}
+
+/// Flush once this many bytes are pending, even before the next tick
+const MAX_PENDING: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct NoopContext;
+
+impl BaseContext for NoopContext {}
+
+impl HttpContext for NoopContext {}
+
+pub struct LogShipperRoot {
+    format: JsonLogFormat,
+    cluster: String,
+    pending: Vec<u8>,
+    stream: Option<GrpcStreamHandle>,
+}
+
+impl Default for LogShipperRoot {
+    fn default() -> Self {
+        Self {
+            format: JsonLogFormat::default_fields(),
+            cluster: "log_collector".to_string(),
+            pending: vec![],
+            stream: None,
+        }
+    }
+}
+
+impl LogShipperRoot {
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        if self.stream.is_none() {
+            let opened = GrpcStreamBuilder::default()
+                .cluster(Upstream::envoy_upstream(&self.cluster, &self.cluster))
+                .service("logs.v1.LogCollector")
+                .method("Push")
+                .on_close(|root: &mut LogShipperRoot, close: &GrpcStreamClose| {
+                    warn!("log stream closed: {:?}", close.status_code());
+                    root.stream = None;
+                })
+                .build()
+                .expect("missing stream fields")
+                .open();
+            match opened {
+                Ok(handle) => self.stream = Some(handle),
+                Err(e) => {
+                    warn!("failed to open log stream: {e:?}");
+                    return;
+                }
+            }
+        }
+        let Some(stream) = self.stream else {
+            return;
+        };
+        match stream.send(Some(&self.pending), false) {
+            Ok(()) => self.pending.clear(),
+            Err(e) => {
+                warn!("failed to send logs: {e:?}");
+                stream.cancel();
+                self.stream = None;
+            }
+        }
+    }
+}
+
+impl BaseContext for LogShipperRoot {
+    fn on_log(&mut self) {
+        self.pending
+            .extend_from_slice(self.format.format().as_bytes());
+        self.pending.push(b'\n');
+        if self.pending.len() >= MAX_PENDING {
+            self.flush();
+        }
+    }
+}
+
+impl RootContext for LogShipperRoot {
+    fn on_configure(&mut self, configuration: Option<Vec<u8>>) -> bool {
+        if let Some(configuration) = configuration {
+            let Ok(config) = Value::parse(configuration) else {
+                warn!("invalid access log configuration");
+                return false;
+            };
+            if let Some(cluster) = config.get("cluster").and_then(Value::as_str) {
+                self.cluster = cluster.to_string();
+            }
+            if let Some(fields) = config.get("fields") {
+                let Some(format) = JsonLogFormat::from_config(fields) else {
+                    warn!("invalid access log fields");
+                    return false;
+                };
+                self.format = format;
+            }
+        }
+        proxy_sdk::set_tick_period(Duration::from_secs(1));
+        true
+    }
+
+    fn on_tick(&mut self) {
+        self.flush();
+    }
+
+    fn create_context(&mut self) -> Context {
+        Context::Http(Box::<NoopContext>::default())
+    }
+}
+
+fn init() {
+    proxy_sdk::reset();
+    proxy_sdk::set_log_level(Level::Info);
+    proxy_sdk::set_root_context_factory(LogShipperRoot::default);
+}
+
+#[no_mangle]
+pub fn _start() {
+    init();
+}
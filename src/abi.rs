@@ -0,0 +1,53 @@
+use std::fmt;
+
+/// A proxy-wasm ABI version this crate can be built against.
+///
+/// The version is selected at build time: the default build exports `proxy_abi_version_0_2_1`,
+/// and the `abi-0-2-0` feature exports `proxy_abi_version_0_2_0` instead, importing only hostcalls present in 0.2.0.
+/// Hosts pick the callback and hostcall set from the exported marker, so only one is exported per build.
+/// Callback signatures are identical between 0.2.0 and 0.2.1. 0.3 is not yet finalized and is not supported.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum AbiVersion {
+    V0_2_0,
+    V0_2_1,
+}
+
+impl AbiVersion {
+    /// The ABI version this build exports
+    pub const fn current() -> Self {
+        if cfg!(feature = "abi-0-2-0") {
+            AbiVersion::V0_2_0
+        } else {
+            AbiVersion::V0_2_1
+        }
+    }
+
+    /// Whether `proxy_continue_stream` and `proxy_close_stream` may be used on any stream type.
+    /// Otherwise, only HTTP streams can be resumed and nothing can be closed.
+    pub const fn supports_stream_control(&self) -> bool {
+        matches!(self, AbiVersion::V0_2_1)
+    }
+
+    /// Whether the host log level can be queried
+    pub const fn supports_log_level(&self) -> bool {
+        matches!(self, AbiVersion::V0_2_1)
+    }
+}
+
+impl fmt::Display for AbiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbiVersion::V0_2_0 => write!(f, "0.2.0"),
+            AbiVersion::V0_2_1 => write!(f, "0.2.1"),
+        }
+    }
+}
+
+#[cfg(not(feature = "abi-0-2-0"))]
+#[no_mangle]
+pub extern "C" fn proxy_abi_version_0_2_1() {}
+
+#[cfg(feature = "abi-0-2-0")]
+#[no_mangle]
+pub extern "C" fn proxy_abi_version_0_2_0() {}
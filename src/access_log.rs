@@ -0,0 +1,392 @@
+//! Helpers for running as an Envoy access log plugin, where [`crate::BaseContext::on_log`] is called on the root context after each request.
+//!
+//! Envoy runs access log plugins on their root context only: there are no HTTP or stream contexts, and the root
+//! context gets `on_log` once for each logged request or connection, with the attributes of that request readable
+//! for the duration of the call. To handle logs in their own context, return [`crate::Context::AccessLog`] from
+//! [`crate::RootContext::create_context`]: the root context is asked once, on the first log, and the context then
+//! gets [`AccessLogContext::on_access_log`] for every log after the `on_log` of the root context, until the root
+//! context is deleted.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    json::{self, Value},
+    property::{decode_property, envoy::Attributes, get_property, ResponseFlags},
+    time::{civil_from_days, MONTHS},
+    BaseContext,
+};
+
+/// Context of an access log plugin, see the [module documentation](self).
+///
+/// Hosts creating a context for each stream of a filter also accept it, for filters that only log: the context then
+/// gets the log of its own stream, and the filter callbacks continue.
+pub trait AccessLogContext: BaseContext {
+    /// Called for each logged request or connection
+    fn on_access_log(&mut self, entry: &LogEntry);
+}
+
+/// Typed view of the attributes of a logged request or connection. Each attribute is read from the host when
+/// called, so only read them during [`AccessLogContext::on_access_log`].
+pub struct LogEntry {
+    attributes: Attributes,
+}
+
+impl LogEntry {
+    pub(crate) fn get() -> Self {
+        Self {
+            attributes: Attributes::get(),
+        }
+    }
+
+    /// All attributes, for those without an accessor here
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    /// Final HTTP status code sent downstream. `None` for connections and requests reset before a response.
+    pub fn response_code(&self) -> Option<u32> {
+        self.attributes.response.code()
+    }
+
+    /// Why the response code was chosen, i.e. `via_upstream` or `upstream_reset_before_response_started{...}`
+    pub fn response_code_details(&self) -> Option<String> {
+        self.attributes.response.code_details()
+    }
+
+    pub fn response_flags(&self) -> ResponseFlags {
+        ResponseFlags(self.attributes.response.flags().unwrap_or_default())
+    }
+
+    pub fn grpc_status(&self) -> Option<u32> {
+        self.attributes.response.grpc_status()
+    }
+
+    /// When the first byte of the request was received
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.attributes.request.time()
+    }
+
+    /// From the first byte of the request to the last byte of the response
+    pub fn duration(&self) -> Option<Duration> {
+        self.attributes.request.duration()
+    }
+
+    /// Bytes received from downstream, including headers
+    pub fn bytes_received(&self) -> Option<usize> {
+        self.attributes.request.total_size()
+    }
+
+    /// Bytes sent downstream, including headers and trailers
+    pub fn bytes_sent(&self) -> Option<usize> {
+        self.attributes.response.total_size()
+    }
+
+    pub fn method(&self) -> Option<String> {
+        self.attributes.request.method()
+    }
+
+    pub fn path(&self) -> Option<String> {
+        self.attributes.request.path()
+    }
+
+    pub fn request_id(&self) -> Option<String> {
+        self.attributes.request.id()
+    }
+
+    pub fn upstream_cluster(&self) -> Option<String> {
+        self.attributes.configuration.cluster_name()
+    }
+
+    pub fn upstream_address(&self) -> Option<std::net::SocketAddr> {
+        self.attributes.upstream.address()
+    }
+
+    /// Why the upstream connection failed, if it did
+    pub fn upstream_transport_failure_reason(&self) -> Option<String> {
+        self.attributes.upstream.transport_failure_reason()
+    }
+
+    /// Why the downstream connection was terminated, if it was
+    pub fn termination_details(&self) -> Option<String> {
+        self.attributes.connection.termination_details()
+    }
+}
+
+impl fmt::Debug for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogEntry").finish_non_exhaustive()
+    }
+}
+
+/// Formats the current request in the NCSA common log format:
+/// `remote - - [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326`
+pub fn common_log_line() -> String {
+    let attributes = Attributes::get();
+    let remote = attributes
+        .connection
+        .source_address()
+        .map(|x| x.ip().to_string());
+    let time = attributes
+        .request
+        .time()
+        .map(format_clf_time)
+        .unwrap_or_else(|| "-".to_string());
+    let method = attributes.request.method();
+    let path = attributes.request.path();
+    let protocol = attributes.request.protocol();
+    let code = attributes.response.code();
+    let size = attributes.response.size();
+    format!(
+        "{} - - [{time}] \"{} {} {}\" {} {}",
+        remote.as_deref().unwrap_or("-"),
+        method.as_deref().unwrap_or("-"),
+        path.as_deref().unwrap_or("-"),
+        protocol.as_deref().unwrap_or("-"),
+        code.map(|x| x.to_string()).as_deref().unwrap_or("-"),
+        size.map(|x| x.to_string()).as_deref().unwrap_or("-"),
+    )
+}
+
+/// Formats a timestamp as `10/Oct/2000:13:55:36 +0000`
+pub fn format_clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let rem = secs % 86400;
+    format!(
+        "{day:02}/{}/{year:04}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// How a property value is encoded by the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PropertyKind {
+    String,
+    Int,
+    Bool,
+    Timestamp,
+    Duration,
+}
+
+fn property_kind(path: &str) -> PropertyKind {
+    match path {
+        "request.size"
+        | "request.total_size"
+        | "response.code"
+        | "response.flags"
+        | "response.grpc_status"
+        | "response.size"
+        | "response.total_size"
+        | "source.port"
+        | "destination.port"
+        | "upstream.port"
+        | "connection.id" => PropertyKind::Int,
+        "connection.mtls" => PropertyKind::Bool,
+        "request.time" => PropertyKind::Timestamp,
+        "request.duration" => PropertyKind::Duration,
+        _ => PropertyKind::String,
+    }
+}
+
+/// Reads a property as a JSON value, typed according to the known attribute encodings
+pub(crate) fn property_value(path: &str) -> Value {
+    let raw = get_property(path);
+    if raw.is_none() {
+        return Value::Null;
+    }
+    match property_kind(path) {
+        PropertyKind::String => decode_property::<String>(path, raw).into(),
+        PropertyKind::Int => decode_property::<i64>(path, raw).into(),
+        PropertyKind::Bool => decode_property::<bool>(path, raw).into(),
+        PropertyKind::Timestamp => decode_property::<SystemTime>(path, raw)
+            .map(|x| {
+                x.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64()
+            })
+            .into(),
+        PropertyKind::Duration => decode_property::<Duration>(path, raw)
+            .map(|x| x.as_secs_f64() * 1000.0)
+            .into(),
+    }
+}
+
+/// Formats access log entries as JSON objects with operator selected fields.
+/// Timestamps are rendered as unix seconds and durations as milliseconds.
+#[derive(Clone, Debug, Default)]
+pub struct JsonLogFormat {
+    fields: Vec<(String, String)>,
+}
+
+impl JsonLogFormat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A format with commonly logged request, response and connection fields
+    pub fn default_fields() -> Self {
+        Self::new()
+            .field("start_time", "request.time")
+            .field("method", "request.method")
+            .field("path", "request.path")
+            .field("protocol", "request.protocol")
+            .field("response_code", "response.code")
+            .field("response_flags", "response.flags")
+            .field("bytes_received", "request.total_size")
+            .field("bytes_sent", "response.total_size")
+            .field("duration_ms", "request.duration")
+            .field("user_agent", "request.useragent")
+            .field("request_id", "request.id")
+            .field("authority", "request.host")
+            .field("downstream_remote_address", "source.address")
+            .field("upstream_host", "upstream.address")
+            .field("upstream_cluster", "xds.cluster_name")
+            .field("route_name", "xds.route_name")
+    }
+
+    /// Builds a format from a JSON object of output field name to attribute path,
+    /// i.e. `{"status": "response.code", "path": "request.path"}`
+    pub fn from_config(config: &Value) -> Option<Self> {
+        let mut out = Self::new();
+        for (name, path) in config.as_object()? {
+            out = out.field(name.clone(), path.as_str()?.to_string());
+        }
+        Some(out)
+    }
+
+    /// Adds an output field `name` read from the attribute `path`
+    pub fn field(mut self, name: impl Into<String>, path: impl Into<String>) -> Self {
+        self.fields.push((name.into(), path.into()));
+        self
+    }
+
+    /// Reads all fields for the current request
+    pub fn entry(&self) -> Value {
+        Value::Object(
+            self.fields
+                .iter()
+                .map(|(name, path)| (name.clone(), property_value(path)))
+                .collect(),
+        )
+    }
+
+    /// Formats the current request as a single JSON line (without a trailing newline)
+    pub fn format(&self) -> String {
+        self.entry().to_string()
+    }
+
+    /// Parses a format from raw plugin configuration
+    pub fn parse(config: &[u8]) -> Option<Self> {
+        Self::from_config(&json::Value::parse(config).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_clf_time() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(971185336);
+        assert_eq!(format_clf_time(time), "10/Oct/2000:13:42:16 +0000");
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(format_clf_time(time), "29/Feb/2000:00:00:00 +0000");
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_access_log_context() {
+        use std::cell::RefCell;
+
+        use crate::{
+            dispatcher::{self, proxy_on_context_create, proxy_on_delete, proxy_on_log},
+            testing::{reset_host, set_property},
+            Context, RootContext,
+        };
+
+        thread_local! {
+            static EVENTS: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+        }
+
+        fn event(event: String) {
+            EVENTS.with_borrow_mut(|x| x.push(event));
+        }
+
+        #[derive(Default)]
+        struct Root;
+
+        impl BaseContext for Root {
+            fn on_log(&mut self) {
+                event("root".to_string());
+            }
+        }
+
+        impl RootContext for Root {
+            fn create_context(&mut self) -> Context {
+                event("create".to_string());
+                Context::AccessLog(Box::new(Logger(0)))
+            }
+        }
+
+        struct Logger(usize);
+
+        impl BaseContext for Logger {
+            fn on_delete(&mut self) {
+                event(format!("delete after {}", self.0));
+            }
+        }
+
+        impl AccessLogContext for Logger {
+            fn on_access_log(&mut self, entry: &LogEntry) {
+                self.0 += 1;
+                event(format!(
+                    "{:?} {}",
+                    entry.response_code(),
+                    entry.response_flags()
+                ));
+            }
+        }
+
+        reset_host();
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        set_property(["response", "code"], 503i64.to_le_bytes());
+        set_property(["response", "flags"], (1i64 << 5).to_le_bytes());
+        proxy_on_log(1);
+        set_property(["response", "code"], 200i64.to_le_bytes());
+        set_property(["response", "flags"], 0i64.to_le_bytes());
+        proxy_on_log(1);
+        // a context created for a stream gets the log of its stream, and its filter callbacks continue
+        proxy_on_context_create(2, 1);
+        assert_eq!(
+            dispatcher::proxy_on_request_headers(2, 0, 1),
+            crate::FilterHeadersStatus::Continue
+        );
+        proxy_on_log(2);
+        proxy_on_delete(2);
+        proxy_on_delete(1);
+        assert_eq!(
+            EVENTS.with_borrow_mut(std::mem::take),
+            [
+                "root",
+                "create",
+                "Some(503) UF",
+                "root",
+                "Some(200) -",
+                "create",
+                "Some(200) -",
+                "delete after 1",
+                "delete after 2",
+            ]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+
+use log::warn;
+
+use crate::{Counter, GrpcCode, HttpControl};
+
+/// Result of an authorization check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// The request may proceed.
+    Allow,
+    /// The request must be rejected with the given response.
+    Deny {
+        status_code: u32,
+        body: Option<Vec<u8>>,
+    },
+}
+
+/// Reasons an authorization backend failed to produce a [`Decision`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// The backend did not respond in time.
+    Timeout,
+    /// The backend responded with an error or could not be reached.
+    Backend(String),
+}
+
+impl AuthFailure {
+    /// Classifies a failed GRPC call to an authorization backend.
+    pub fn from_grpc(code: GrpcCode, message: Option<&str>) -> Self {
+        match code {
+            GrpcCode::DeadlineExceeded => Self::Timeout,
+            code => Self::Backend(match message {
+                Some(message) => format!("{code:?}: {message}"),
+                None => format!("{code:?}"),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthFailure::Timeout => write!(f, "authorization backend timed out"),
+            AuthFailure::Backend(e) => write!(f, "authorization backend failed: {e}"),
+        }
+    }
+}
+
+/// Whether to let traffic through when an authorization backend fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FailureMode {
+    /// Allow the request.
+    Open,
+    /// Deny the request.
+    Closed,
+}
+
+/// Describes how to behave when an authorization backend errors or times out.
+#[derive(Clone, Debug)]
+pub struct FailurePolicy {
+    /// Behavior when the backend returns an error. Default is [`FailureMode::Closed`].
+    pub on_error: FailureMode,
+    /// Behavior when the backend times out. Default is [`FailureMode::Closed`].
+    pub on_timeout: FailureMode,
+    /// Status code sent when failing closed. Default is 403.
+    pub deny_status_code: u32,
+    /// Counter incremented for every request that failed open.
+    pub failed_open_metric: String,
+    /// Counter incremented for every request that failed closed.
+    pub failed_closed_metric: String,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        Self {
+            on_error: FailureMode::Closed,
+            on_timeout: FailureMode::Closed,
+            deny_status_code: 403,
+            failed_open_metric: "auth_failed_open".to_string(),
+            failed_closed_metric: "auth_failed_closed".to_string(),
+        }
+    }
+}
+
+impl FailurePolicy {
+    /// A policy that allows traffic on any backend failure.
+    pub fn fail_open() -> Self {
+        Self {
+            on_error: FailureMode::Open,
+            on_timeout: FailureMode::Open,
+            ..Default::default()
+        }
+    }
+
+    /// A policy that denies traffic on any backend failure.
+    pub fn fail_closed() -> Self {
+        Self::default()
+    }
+
+    /// The [`FailureMode`] that applies to `failure`.
+    pub fn mode_for(&self, failure: &AuthFailure) -> FailureMode {
+        match failure {
+            AuthFailure::Timeout => self.on_timeout,
+            AuthFailure::Backend(_) => self.on_error,
+        }
+    }
+
+    /// Resolves a backend failure into a [`Decision`], recording the matching metric.
+    pub fn resolve(&self, failure: &AuthFailure) -> Decision {
+        match self.mode_for(failure) {
+            FailureMode::Open => {
+                warn!("{failure}, failing open");
+                Counter::define(&self.failed_open_metric).increment(1);
+                Decision::Allow
+            }
+            FailureMode::Closed => {
+                warn!("{failure}, failing closed");
+                Counter::define(&self.failed_closed_metric).increment(1);
+                Decision::Deny {
+                    status_code: self.deny_status_code,
+                    body: None,
+                }
+            }
+        }
+    }
+}
+
+impl Decision {
+    /// Resolves the outcome of an authorization backend call, applying `policy` if the backend failed.
+    pub fn or_fail_open(result: Result<Decision, AuthFailure>, policy: &FailurePolicy) -> Self {
+        match result {
+            Ok(decision) => decision,
+            Err(failure) => policy.resolve(&failure),
+        }
+    }
+
+    /// Returns `true` if the request may proceed.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Decision::Allow)
+    }
+
+    /// Resumes the paused request/response when allowed, otherwise sends the denial as a local response.
+    pub fn apply(&self, control: &impl HttpControl) {
+        match self {
+            Decision::Allow => control.resume(),
+            Decision::Deny { status_code, body } => {
+                if let Err(e) = control.send_http_response(*status_code, &[], body.as_deref()) {
+                    warn!("failed to send auth denial: {e:?}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_for() {
+        let timeout = AuthFailure::Timeout;
+        let backend = AuthFailure::Backend("Unavailable".to_string());
+        let policy = FailurePolicy {
+            on_error: FailureMode::Open,
+            ..Default::default()
+        };
+        assert_eq!(policy.mode_for(&timeout), FailureMode::Closed);
+        assert_eq!(policy.mode_for(&backend), FailureMode::Open);
+        let policy = FailurePolicy {
+            on_timeout: FailureMode::Open,
+            ..Default::default()
+        };
+        assert_eq!(policy.mode_for(&timeout), FailureMode::Open);
+        assert_eq!(policy.mode_for(&backend), FailureMode::Closed);
+        assert_eq!(
+            AuthFailure::from_grpc(GrpcCode::DeadlineExceeded, None),
+            timeout
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_resolve() {
+        use crate::testing::{metric, reset_host};
+
+        reset_host();
+        let timeout = AuthFailure::Timeout;
+        let backend = AuthFailure::Backend("Unavailable".to_string());
+        let policy = FailurePolicy {
+            deny_status_code: 503,
+            ..FailurePolicy::fail_open()
+        };
+        assert_eq!(policy.resolve(&timeout), Decision::Allow);
+        assert_eq!(policy.resolve(&backend), Decision::Allow);
+        assert_eq!(metric("auth_failed_open"), Some(2));
+        assert_eq!(metric("auth_failed_closed"), None);
+
+        let policy = FailurePolicy {
+            deny_status_code: 503,
+            ..FailurePolicy::fail_closed()
+        };
+        let denied = Decision::Deny {
+            status_code: 503,
+            body: None,
+        };
+        assert_eq!(policy.resolve(&timeout), denied);
+        assert_eq!(policy.resolve(&backend), denied);
+        assert_eq!(metric("auth_failed_open"), Some(2));
+        assert_eq!(metric("auth_failed_closed"), Some(2));
+
+        // an answer from the backend is never overridden, nor counted
+        let deny = Decision::Deny {
+            status_code: 401,
+            body: Some(b"no".to_vec()),
+        };
+        assert_eq!(
+            Decision::or_fail_open(Ok(deny.clone()), &FailurePolicy::fail_open()),
+            deny
+        );
+        assert_eq!(
+            Decision::or_fail_open(Ok(Decision::Allow), &policy),
+            Decision::Allow
+        );
+        assert_eq!(Decision::or_fail_open(Err(timeout), &policy), denied);
+        assert_eq!(metric("auth_failed_open"), Some(2));
+        assert_eq!(metric("auth_failed_closed"), Some(3));
+    }
+}
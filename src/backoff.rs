@@ -0,0 +1,201 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+use crate::instant_now;
+
+/// How [`Backoff`] randomizes delays, to keep workers that failed together from retrying together
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Jitter {
+    /// Plain exponential delays
+    None,
+    /// A random delay between zero and the exponential delay
+    Full,
+    /// A random delay between the initial delay and three times the previous delay
+    Decorrelated,
+}
+
+/// Exponential backoff with jitter, capped at a maximum delay and reset on success.
+///
+/// Call [`Backoff::fail`] after a failed attempt and [`Backoff::succeed`] after a successful one. Before retrying,
+/// i.e. from [`crate::RootContext::on_tick`], check [`Backoff::is_ready`]. The tick period bounds how precisely delays are kept.
+/// A clone continues from the same attempt but draws its own jitter, so retries copied into each [`crate::RetryPolicy`]
+/// call don't wake in lockstep.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: Jitter,
+    attempts: Cell<u32>,
+    previous: Cell<Duration>,
+    retry_at: Cell<Option<Instant>>,
+    state: Cell<u64>,
+}
+
+impl Backoff {
+    /// Starts at `initial`, doubling up to `max`, with decorrelated jitter
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            multiplier: 2.0,
+            jitter: Jitter::Decorrelated,
+            attempts: Cell::new(0),
+            previous: Cell::new(Duration::ZERO),
+            retry_at: Cell::new(None),
+            state: Cell::new(RandomState::new().build_hasher().finish() | 1),
+        }
+    }
+
+    /// Growth of exponential delays per failure. Default is 2.
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Seeds the jitter, i.e. for reproducible tests
+    pub fn seed(self, seed: u64) -> Self {
+        self.state.set(seed | 1);
+        self
+    }
+
+    /// Failures since the last success or reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts.get()
+    }
+
+    /// Computes the delay before the next attempt and counts a failure, without scheduling it
+    pub fn next_delay(&self) -> Duration {
+        let attempts = self.attempts.get();
+        self.attempts.set(attempts.saturating_add(1));
+        let exponential = self
+            .initial
+            .mul_f64(self.multiplier.powi(attempts.min(64) as i32))
+            .min(self.max);
+        let delay = match self.jitter {
+            Jitter::None => exponential,
+            Jitter::Full => exponential.mul_f64(self.random()),
+            Jitter::Decorrelated => {
+                let upper = (self.previous.get() * 3).max(self.initial);
+                self.initial + (upper - self.initial).mul_f64(self.random())
+            }
+        }
+        .min(self.max);
+        self.previous.set(delay);
+        delay
+    }
+
+    /// Records a failed attempt, returning the delay before the next one
+    pub fn fail(&self) -> Duration {
+        let delay = self.next_delay();
+        self.retry_at.set(Some(instant_now() + delay));
+        delay
+    }
+
+    /// Records a successful attempt, resetting the delay
+    pub fn succeed(&self) {
+        self.attempts.set(0);
+        self.previous.set(Duration::ZERO);
+        self.retry_at.set(None);
+    }
+
+    /// When the next attempt may start, if backing off
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at.get()
+    }
+
+    /// Time left until the next attempt may start
+    pub fn remaining(&self) -> Duration {
+        self.retry_at.get().map_or(Duration::ZERO, |x| {
+            x.saturating_duration_since(instant_now())
+        })
+    }
+
+    /// Returns `true` if an attempt may start now
+    pub fn is_ready(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// Uniform in `[0, 1)`
+    fn random(&self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// xorshift64
+    fn next_u64(&self) -> u64 {
+        let mut x = self.state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.set(x);
+        x
+    }
+}
+
+/// Clones get their own jitter sequence, seeded from this one, so copies of one policy don't retry in lockstep
+impl Clone for Backoff {
+    fn clone(&self) -> Self {
+        // splitmix64 finalizer, so the clone's sequence isn't this one shifted by a step
+        let mut seed = self.next_u64();
+        seed = (seed ^ (seed >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        seed = (seed ^ (seed >> 27)).wrapping_mul(0x94d049bb133111eb);
+        seed ^= seed >> 31;
+        Self {
+            initial: self.initial,
+            max: self.max,
+            multiplier: self.multiplier,
+            jitter: self.jitter,
+            attempts: self.attempts.clone(),
+            previous: self.previous.clone(),
+            retry_at: self.retry_at.clone(),
+            state: Cell::new(seed | 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).jitter(Jitter::None);
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        backoff.succeed();
+        assert_eq!(backoff.attempts(), 0);
+        assert!(backoff.is_ready());
+        assert_eq!(backoff.fail(), Duration::from_millis(100));
+        assert!(!backoff.is_ready());
+
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1)).seed(7);
+        for _ in 0..32 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_secs(1));
+        }
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1))
+            .jitter(Jitter::Full)
+            .seed(7);
+        assert!(backoff.next_delay() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_backoff_clone() {
+        let seeded = || Backoff::new(Duration::from_millis(100), Duration::from_secs(10)).seed(7);
+        let delays = |backoff: Backoff| (0..4).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        let backoff = seeded();
+        assert_ne!(delays(backoff.clone()), delays(backoff.clone()));
+        // clones of a seeded backoff stay reproducible
+        assert_eq!(delays(seeded().clone()), delays(seeded().clone()));
+    }
+}
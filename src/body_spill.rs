@@ -0,0 +1,228 @@
+//! Body accumulation for bodies larger than the in-VM buffer budget.
+//!
+//! A [`SpillAccumulator`] keeps the start of a body in memory and spills the rest to [`SharedData`] in fixed size chunks,
+//! then replays the full body at end of stream. This trades shared data hostcalls for VM memory.
+//!
+//! Shared data has no expiry or deletion, so every chunk is stamped with an expiry time and released chunks are
+//! overwritten with empty values. Chunks read after their expiry are treated as lost.
+
+use std::{
+    cell::Cell,
+    fmt,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{shared_data::worker_seed, BodyReader, Counter, HttpBodyControl, SharedData};
+
+const SPILLED_BYTES: &str = "body_spill_bytes";
+const SPILLED_CHUNKS: &str = "body_spill_chunks";
+const SPILL_HOSTCALLS: &str = "body_spill_hostcalls";
+const SPILL_LOST: &str = "body_spill_lost";
+
+thread_local! {
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Error of [`SpillAccumulator::replay`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpillError {
+    /// A spilled chunk expired, or was overwritten or never stored
+    Lost { chunk: u32 },
+}
+
+impl fmt::Display for SpillError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpillError::Lost { chunk } => write!(f, "spilled body chunk {chunk} was lost"),
+        }
+    }
+}
+
+impl std::error::Error for SpillError {}
+
+/// Accumulates a body across body callbacks, keeping at most `memory_budget` bytes in memory and spilling the overflow
+/// to shared data. Released on drop.
+pub struct SpillAccumulator {
+    key_prefix: String,
+    memory_budget: usize,
+    chunk_size: usize,
+    ttl: Duration,
+    head: Vec<u8>,
+    pending: Vec<u8>,
+    spilled_chunks: u32,
+    spilled_bytes: usize,
+}
+
+impl SpillAccumulator {
+    /// Creates an accumulator with keys under `prefix`. Defaults to a 1 MiB memory budget, 64 KiB chunks and a 60 second TTL.
+    pub fn new(prefix: impl AsRef<str>) -> Self {
+        let id = NEXT_ID.get();
+        NEXT_ID.set(id.wrapping_add(1));
+        Self {
+            key_prefix: format!("{}:{:x}:{id}", prefix.as_ref(), worker_seed()),
+            memory_budget: 1024 * 1024,
+            chunk_size: 64 * 1024,
+            ttl: Duration::from_secs(60),
+            head: vec![],
+            pending: vec![],
+            spilled_chunks: 0,
+            spilled_bytes: 0,
+        }
+    }
+
+    /// Bytes kept in memory before spilling starts
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = bytes;
+        self
+    }
+
+    /// Size of each spilled chunk. Larger chunks mean fewer hostcalls.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    /// How long spilled chunks stay valid, bounding how long a stream may take to finish
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Total bytes accumulated
+    pub fn len(&self) -> usize {
+        self.head.len() + self.spilled_bytes + self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes currently spilled to shared data
+    pub fn spilled_bytes(&self) -> usize {
+        self.spilled_bytes
+    }
+
+    /// Appends the current body block
+    pub fn push_body<B: HttpBodyControl>(&mut self, body: &B) {
+        BodyReader::new(body).read_chunks(self.chunk_size, |chunk| {
+            self.push(chunk);
+            true
+        });
+    }
+
+    /// Appends `data`
+    pub fn push(&mut self, mut data: &[u8]) {
+        if self.spilled_chunks == 0 && self.pending.is_empty() {
+            let fit = self
+                .memory_budget
+                .saturating_sub(self.head.len())
+                .min(data.len());
+            self.head.extend_from_slice(&data[..fit]);
+            data = &data[fit..];
+        }
+        while !data.is_empty() {
+            let take = (self.chunk_size - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() == self.chunk_size {
+                let chunk = std::mem::take(&mut self.pending);
+                self.spill(&chunk);
+            }
+        }
+    }
+
+    fn key(&self, chunk: u32) -> String {
+        format!("{}:{chunk}", self.key_prefix)
+    }
+
+    fn spill(&mut self, chunk: &[u8]) {
+        let expiry = (crate::now() + self.ttl)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut value = Vec::with_capacity(chunk.len() + 8);
+        value.extend_from_slice(&expiry.to_le_bytes());
+        value.extend_from_slice(chunk);
+        SharedData::from_key(self.key(self.spilled_chunks)).set(value);
+        self.spilled_chunks += 1;
+        self.spilled_bytes += chunk.len();
+        Counter::define(SPILLED_BYTES).increment(chunk.len() as i64);
+        Counter::define(SPILLED_CHUNKS).increment(1);
+        Counter::define(SPILL_HOSTCALLS).increment(1);
+    }
+
+    /// Calls `f` with consecutive pieces of the full body, in order, until the body ends or `f` returns `false`.
+    /// Spilled chunks are loaded one at a time, so at most one chunk beyond the memory budget is held at once.
+    pub fn replay(&self, mut f: impl FnMut(&[u8]) -> bool) -> Result<(), SpillError> {
+        if !self.head.is_empty() && !f(&self.head) {
+            return Ok(());
+        }
+        let now = crate::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        for chunk in 0..self.spilled_chunks {
+            Counter::define(SPILL_HOSTCALLS).increment(1);
+            let value = SharedData::from_key(self.key(chunk))
+                .get()
+                .filter(|x| x.len() > 8)
+                .filter(|x| u64::from_le_bytes(x[..8].try_into().unwrap()) >= now);
+            let Some(value) = value else {
+                Counter::define(SPILL_LOST).increment(1);
+                return Err(SpillError::Lost { chunk });
+            };
+            if !f(&value[8..]) {
+                return Ok(());
+            }
+        }
+        if !self.pending.is_empty() {
+            f(&self.pending);
+        }
+        Ok(())
+    }
+
+    /// Loads the full body into memory
+    pub fn to_vec(&self) -> Result<Vec<u8>, SpillError> {
+        let mut out = Vec::with_capacity(self.len());
+        self.replay(|x| {
+            out.extend_from_slice(x);
+            true
+        })?;
+        Ok(out)
+    }
+
+    /// Clears the accumulated body, overwriting spilled chunks with empty values
+    pub fn release(&mut self) {
+        for chunk in 0..self.spilled_chunks {
+            SharedData::from_key(self.key(chunk)).set([]);
+            Counter::define(SPILL_HOSTCALLS).increment(1);
+        }
+        self.head.clear();
+        self.pending.clear();
+        self.spilled_chunks = 0;
+        self.spilled_bytes = 0;
+    }
+}
+
+impl Drop for SpillAccumulator {
+    fn drop(&mut self) {
+        if self.spilled_chunks > 0 {
+            self.release();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_within_budget() {
+        let mut body = SpillAccumulator::new("body").memory_budget(16);
+        body.push(b"hello ");
+        body.push(b"world");
+        assert_eq!(body.len(), 11);
+        assert_eq!(body.spilled_bytes(), 0);
+        assert_eq!(body.to_vec().unwrap(), b"hello world");
+    }
+}
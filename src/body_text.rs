@@ -0,0 +1,281 @@
+//! Decoded and charset-normalized views of text bodies, for scanners that expect UTF-8.
+//!
+//! [`BodyText::decode`] undoes the `content-encoding` of a body, reports its raw and decoded sizes,
+//! and transcodes text bodies in the charset of their `content-type` (or a detected one) to UTF-8.
+
+use std::fmt;
+
+use crate::compression::{CompressionError, GrpcCompression};
+
+/// Character encodings of text bodies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Charset {
+    Utf8,
+    /// ISO-8859-1. Browsers treat this label as windows-1252, see [`Charset::from_label`].
+    Latin1,
+    Windows1252,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Charset {
+    /// Parses a charset label following the WHATWG encoding standard, which maps `latin1`, `iso-8859-1` and `us-ascii`
+    /// to windows-1252. `None` for charsets not supported here.
+    pub fn from_label(label: &str) -> Option<Self> {
+        let label = label.trim().trim_matches('"').to_ascii_lowercase();
+        Some(match &*label {
+            "utf-8" | "utf8" | "unicode-1-1-utf-8" => Charset::Utf8,
+            "iso-8859-1" | "iso8859-1" | "latin1" | "l1" | "us-ascii" | "ascii"
+            | "windows-1252" | "cp1252" | "x-cp1252" => Charset::Windows1252,
+            "utf-16le" | "utf-16" => Charset::Utf16Le,
+            "utf-16be" => Charset::Utf16Be,
+            _ => return None,
+        })
+    }
+
+    /// Charset from a byte order mark at the start of `data`, and the length of the mark
+    pub fn from_bom(data: &[u8]) -> Option<(Self, usize)> {
+        match data {
+            [0xEF, 0xBB, 0xBF, ..] => Some((Charset::Utf8, 3)),
+            [0xFF, 0xFE, ..] => Some((Charset::Utf16Le, 2)),
+            [0xFE, 0xFF, ..] => Some((Charset::Utf16Be, 2)),
+            _ => None,
+        }
+    }
+
+    /// Decodes `data` to UTF-8. Returns whether any bytes were invalid and replaced with U+FFFD.
+    pub fn decode(&self, data: &[u8]) -> (String, bool) {
+        match self {
+            Charset::Utf8 => match String::from_utf8_lossy(data) {
+                std::borrow::Cow::Borrowed(x) => (x.to_string(), false),
+                std::borrow::Cow::Owned(x) => (x, true),
+            },
+            Charset::Latin1 => (data.iter().map(|x| *x as char).collect(), false),
+            Charset::Windows1252 => (data.iter().map(|x| windows_1252(*x)).collect(), false),
+            Charset::Utf16Le | Charset::Utf16Be => {
+                let units = data.chunks(2).map(|x| match (self, x) {
+                    (Charset::Utf16Le, [a, b]) => u16::from_le_bytes([*a, *b]),
+                    (_, [a, b]) => u16::from_be_bytes([*a, *b]),
+                    _ => 0xFFFD,
+                });
+                let mut lossy = !data.len().is_multiple_of(2);
+                let out = char::decode_utf16(units)
+                    .map(|x| {
+                        x.unwrap_or_else(|_| {
+                            lossy = true;
+                            char::REPLACEMENT_CHARACTER
+                        })
+                    })
+                    .collect();
+                (out, lossy)
+            }
+        }
+    }
+}
+
+/// Windows-1252 differs from ISO-8859-1 only in 0x80..=0x9F
+fn windows_1252(byte: u8) -> char {
+    const HIGH: [char; 32] = [
+        '\u{20AC}', '\u{81}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{8D}',
+        '\u{017D}', '\u{8F}', '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}',
+        '\u{2013}', '\u{2014}', '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}',
+        '\u{9D}', '\u{017E}', '\u{0178}',
+    ];
+    match byte {
+        0x80..=0x9F => HIGH[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+/// Error of [`BodyText::decode`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BodyTextError {
+    /// The `content-encoding` is not supported, i.e. `br`
+    UnsupportedEncoding(String),
+    /// The body could not be decompressed
+    Compression(CompressionError),
+}
+
+impl fmt::Display for BodyTextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyTextError::UnsupportedEncoding(x) => write!(f, "unsupported content-encoding {x}"),
+            BodyTextError::Compression(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BodyTextError {}
+
+/// Whether a media type is text a scanner can read
+pub fn is_text_media_type(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || matches!(
+            &*media_type,
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-www-form-urlencoded"
+                | "application/graphql"
+                | "application/x-ndjson"
+        )
+}
+
+/// `charset` parameter of a `content-type`
+fn charset_param(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then_some(value.trim())
+    })
+}
+
+/// Raw and normalized views of a body
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BodyText {
+    /// Size of the body as sent, before content decoding
+    pub raw_size: usize,
+    /// The body after undoing its `content-encoding`
+    pub decoded: Vec<u8>,
+    /// Charset the text was decoded from. `None` for non-text bodies.
+    pub charset: Option<Charset>,
+    /// The decoded body as UTF-8. `None` for non-text bodies.
+    pub text: Option<String>,
+    /// Whether invalid bytes were replaced while transcoding
+    pub lossy: bool,
+}
+
+impl BodyText {
+    /// Decodes `raw` as described by its `content-type` and `content-encoding` headers. The decoded body is limited to `max_size`.
+    ///
+    /// Text bodies without a charset are read as UTF-8, or windows-1252 if they aren't valid UTF-8. Bodies without a
+    /// `content-type` are treated as text if they are valid UTF-8 or start with a byte order mark.
+    pub fn decode(
+        content_type: Option<&str>,
+        content_encoding: Option<&str>,
+        raw: &[u8],
+        max_size: usize,
+    ) -> Result<Self, BodyTextError> {
+        let mut decoded = raw.to_vec();
+        // codings are listed in the order they were applied
+        for coding in content_encoding.unwrap_or_default().rsplit(',') {
+            let coding = coding.trim();
+            let compression = match coding.to_ascii_lowercase().as_str() {
+                "" | "identity" => continue,
+                "gzip" | "x-gzip" => GrpcCompression::Gzip,
+                "deflate" => GrpcCompression::Deflate,
+                _ => return Err(BodyTextError::UnsupportedEncoding(coding.to_string())),
+            };
+            decoded = compression
+                .decompress(&decoded, max_size)
+                .map_err(BodyTextError::Compression)?;
+        }
+
+        let bom = Charset::from_bom(&decoded);
+        let declared = content_type
+            .and_then(charset_param)
+            .and_then(Charset::from_label);
+        let charset = match content_type {
+            Some(x) if !is_text_media_type(x) => None,
+            _ if bom.is_some() => bom.map(|x| x.0),
+            Some(_) => Some(declared.unwrap_or_else(|| sniff(&decoded))),
+            None => std::str::from_utf8(&decoded).ok().map(|_| Charset::Utf8),
+        };
+        let (text, lossy) = match charset {
+            Some(charset) => {
+                let start = bom.map_or(0, |x| x.1);
+                let (text, lossy) = charset.decode(&decoded[start..]);
+                (Some(text), lossy)
+            }
+            None => (None, false),
+        };
+        Ok(Self {
+            raw_size: raw.len(),
+            decoded,
+            charset,
+            text,
+            lossy,
+        })
+    }
+
+    /// Like [`BodyText::decode`], reading `content-type` and `content-encoding` from `headers`
+    pub fn from_headers<N: AsRef<str>, V: AsRef<[u8]>>(
+        headers: &[(N, V)],
+        raw: &[u8],
+        max_size: usize,
+    ) -> Result<Self, BodyTextError> {
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|(x, _)| x.as_ref().eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| std::str::from_utf8(value.as_ref()).ok())
+        };
+        Self::decode(get("content-type"), get("content-encoding"), raw, max_size)
+    }
+
+    /// Size after content decoding
+    pub fn decoded_size(&self) -> usize {
+        self.decoded.len()
+    }
+
+    /// The normalized text if this is a text body, otherwise the decoded bytes
+    pub fn scannable(&self) -> &[u8] {
+        self.text.as_ref().map_or(&self.decoded, |x| x.as_bytes())
+    }
+}
+
+/// Charset of text declared without one
+fn sniff(data: &[u8]) -> Charset {
+    match std::str::from_utf8(data) {
+        Ok(_) => Charset::Utf8,
+        Err(_) => Charset::Windows1252,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_text() {
+        let body = BodyText::decode(
+            Some("text/plain; charset=ISO-8859-1"),
+            None,
+            b"caf\xe9 \x80",
+            1024,
+        )
+        .unwrap();
+        assert_eq!(body.charset, Some(Charset::Windows1252));
+        assert_eq!(body.text.as_deref(), Some("café €"));
+        assert_eq!(body.scannable(), "café €".as_bytes());
+
+        let gzip = GrpcCompression::Gzip.compress(b"{\"ssn\":\"123\"}");
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("content-encoding", "gzip"),
+        ];
+        let body = BodyText::from_headers(&headers, &gzip, 1024).unwrap();
+        assert_eq!(body.raw_size, gzip.len());
+        assert_eq!(body.decoded_size(), 13);
+        assert_eq!(body.text.as_deref(), Some("{\"ssn\":\"123\"}"));
+
+        let body = BodyText::decode(None, None, b"\xff\xfeh\0i\0", 1024).unwrap();
+        assert_eq!(body.text.as_deref(), Some("hi"));
+        let body = BodyText::decode(Some("image/png"), None, b"\x89PNG", 1024).unwrap();
+        assert_eq!(body.text, None);
+        assert!(matches!(
+            BodyText::decode(None, Some("br"), b"", 1024),
+            Err(BodyTextError::UnsupportedEncoding(_))
+        ));
+    }
+}
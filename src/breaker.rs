@@ -0,0 +1,532 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+
+use crate::{instant_now, property::envoy::Attributes, Counter, Gauge};
+
+/// Guards an optional plugin feature (i.e. body scanning) with an error and latency budget.
+/// When either budget is exhausted within a window, the feature is disabled for a cool-off period,
+/// `{name}_breaker_tripped` is incremented and `{name}_breaker_open` is set to 1.
+/// Share between contexts with an [`std::rc::Rc`] or a `thread_local`.
+#[derive(Debug)]
+pub struct Breaker {
+    name: String,
+    max_errors: u32,
+    max_slow: u32,
+    latency_budget: Option<Duration>,
+    window: Duration,
+    cool_off: Duration,
+    window_start: Cell<Option<Instant>>,
+    errors: Cell<u32>,
+    slow: Cell<u32>,
+    disabled_until: Cell<Option<Instant>>,
+}
+
+impl Breaker {
+    /// Creates a breaker for the feature `name` allowing 10 errors per minute, with no latency budget and a 5 minute cool-off.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            max_errors: 10,
+            max_slow: 10,
+            latency_budget: None,
+            window: Duration::from_secs(60),
+            cool_off: Duration::from_secs(300),
+            window_start: Cell::new(None),
+            errors: Cell::new(0),
+            slow: Cell::new(0),
+            disabled_until: Cell::new(None),
+        }
+    }
+
+    /// Number of errors within a window that trips the breaker
+    pub fn max_errors(mut self, max_errors: u32) -> Self {
+        self.max_errors = max_errors;
+        self
+    }
+
+    /// Calls taking longer than `budget` count as slow
+    pub fn latency_budget(mut self, budget: Duration) -> Self {
+        self.latency_budget = Some(budget);
+        self
+    }
+
+    /// Number of slow calls within a window that trips the breaker
+    pub fn max_slow(mut self, max_slow: u32) -> Self {
+        self.max_slow = max_slow;
+        self
+    }
+
+    /// Length of the window errors and slow calls are counted over
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// How long the feature stays disabled after tripping
+    pub fn cool_off(mut self, cool_off: Duration) -> Self {
+        self.cool_off = cool_off;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` if the guarded feature may run. Re-enables the feature once the cool-off has elapsed.
+    pub fn is_enabled(&self) -> bool {
+        let Some(until) = self.disabled_until.get() else {
+            return true;
+        };
+        if instant_now() < until {
+            return false;
+        }
+        info!("re-enabling '{}' after cool-off", self.name);
+        self.disabled_until.set(None);
+        self.reset_window(None);
+        Gauge::define(format!("{}_breaker_open", self.name)).record(0);
+        true
+    }
+
+    /// Runs `f` if the feature is enabled, recording its outcome and latency. Returns `None` if disabled.
+    pub fn run<T, E>(&self, f: impl FnOnce() -> Result<T, E>) -> Option<Result<T, E>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let start = instant_now();
+        let out = f();
+        self.record(out.is_ok(), instant_now().saturating_duration_since(start));
+        Some(out)
+    }
+
+    /// Records the outcome of a guarded call made outside of [`Breaker::run`], i.e. one spanning callbacks.
+    pub fn record(&self, success: bool, elapsed: Duration) {
+        if self.disabled_until.get().is_some() {
+            return;
+        }
+        let now = instant_now();
+        match self.window_start.get() {
+            Some(start) if now.saturating_duration_since(start) < self.window => (),
+            _ => self.reset_window(Some(now)),
+        }
+        if !success {
+            self.errors.set(self.errors.get() + 1);
+        }
+        if self.latency_budget.is_some_and(|budget| elapsed > budget) {
+            self.slow.set(self.slow.get() + 1);
+        }
+        if self.errors.get() >= self.max_errors {
+            self.trip(now, "error");
+        } else if self.slow.get() >= self.max_slow {
+            self.trip(now, "latency");
+        }
+    }
+
+    /// Disables the feature for the cool-off period
+    fn trip(&self, now: Instant, budget: &str) {
+        warn!(
+            "disabling '{}' for {:?}: {budget} budget exhausted ({} errors, {} slow calls)",
+            self.name,
+            self.cool_off,
+            self.errors.get(),
+            self.slow.get()
+        );
+        self.disabled_until.set(Some(now + self.cool_off));
+        Counter::define(format!("{}_breaker_tripped", self.name)).increment(1);
+        Gauge::define(format!("{}_breaker_open", self.name)).record(1);
+    }
+
+    fn reset_window(&self, start: Option<Instant>) {
+        self.window_start.set(start);
+        self.errors.set(0);
+        self.slow.set(0);
+    }
+}
+
+/// What a [`RouteBreaker`] keeps separate budgets for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum RouteKey {
+    /// The route name, `xds.route_name`
+    #[default]
+    Route,
+    /// The upstream cluster, `xds.cluster_name`
+    Cluster,
+}
+
+impl RouteKey {
+    /// Key of the active context, `None` if the host does not know it
+    pub fn current(&self) -> Option<String> {
+        let attributes = Attributes::get();
+        match self {
+            RouteKey::Route => attributes.configuration.route_name(),
+            RouteKey::Cluster => attributes.configuration.cluster_name(),
+        }
+    }
+}
+
+/// State of the guarded feature for one route of a [`RouteBreaker`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BreakerState {
+    /// The feature runs
+    Closed,
+    /// The feature is bypassed until the cool-off elapses
+    Open,
+    /// The feature runs again, and is bypassed again on the first error or slow call before the probe period elapses
+    Probing,
+}
+
+#[derive(Debug)]
+struct RouteWindow {
+    state: BreakerState,
+    /// End of the cool-off while open, of the probe period while probing
+    until: Option<Instant>,
+    window_start: Instant,
+    calls: u32,
+    errors: u32,
+    slow: u32,
+}
+
+impl RouteWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: BreakerState::Closed,
+            until: None,
+            window_start: now,
+            calls: 0,
+            errors: 0,
+            slow: 0,
+        }
+    }
+
+    fn reset_window(&mut self, now: Instant) {
+        self.window_start = now;
+        self.calls = 0;
+        self.errors = 0;
+        self.slow = 0;
+    }
+}
+
+/// Guards an optional plugin feature (i.e. body scanning) with error and latency rate budgets per route or cluster,
+/// so a route where the feature is failing or slow bypasses it without disabling it for the others.
+///
+/// Once a route made `min_calls` calls within a window and either the share of failed calls or the share of calls over
+/// the latency budget exceeds its maximum, the feature is bypassed on that route for a cool-off period. It then runs
+/// again for a probe period, during which a single failed or slow call bypasses it again. Trips increment
+/// `{name}_route_breaker_tripped`, and `{name}_route_breaker_open` holds the number of bypassed routes. Share between
+/// contexts with an [`std::rc::Rc`] or a `thread_local`.
+///
+/// Latency is the time the feature took, as measured by [`RouteBreaker::run`]. For work spanning callbacks, record the
+/// time spent in the plugin instead, i.e. from [`crate::cost::cost`].
+///
+/// ```ignore
+/// thread_local! {
+///     static SCANNING: RouteBreaker = RouteBreaker::new("body_scan").latency_budget(Duration::from_millis(2));
+/// }
+///
+/// fn on_http_request_body(&mut self, body: &RequestBody) -> FilterDataStatus {
+///     if let Some(route) = RouteKey::Route.current() {
+///         SCANNING.with(|x| x.run(&route, || self.scanner.scan(body)));
+///     }
+///     FilterDataStatus::Continue
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RouteBreaker {
+    name: String,
+    min_calls: u32,
+    max_error_rate: f64,
+    latency_budget: Option<Duration>,
+    max_slow_rate: f64,
+    window: Duration,
+    cool_off: Duration,
+    probe_period: Duration,
+    max_routes: usize,
+    routes: RefCell<HashMap<String, RouteWindow>>,
+}
+
+impl RouteBreaker {
+    /// Creates a breaker for the feature `name` tripping on routes with half their calls failing within a minute, after at
+    /// least 20 calls, with no latency budget, a 30 second cool-off and a 10 second probe period. Tracks up to 1024
+    /// routes.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            min_calls: 20,
+            max_error_rate: 0.5,
+            latency_budget: None,
+            max_slow_rate: 0.5,
+            window: Duration::from_secs(60),
+            cool_off: Duration::from_secs(30),
+            probe_period: Duration::from_secs(10),
+            max_routes: 1024,
+            routes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Calls within a window before the rates of a route are checked
+    pub fn min_calls(mut self, min_calls: u32) -> Self {
+        self.min_calls = min_calls.max(1);
+        self
+    }
+
+    /// Share of failed calls within a window that trips a route, from 0 to 1
+    pub fn max_error_rate(mut self, rate: f64) -> Self {
+        self.max_error_rate = rate;
+        self
+    }
+
+    /// Calls taking longer than `budget` count as slow
+    pub fn latency_budget(mut self, budget: Duration) -> Self {
+        self.latency_budget = Some(budget);
+        self
+    }
+
+    /// Share of slow calls within a window that trips a route, from 0 to 1
+    pub fn max_slow_rate(mut self, rate: f64) -> Self {
+        self.max_slow_rate = rate;
+        self
+    }
+
+    /// Length of the window calls are counted over
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// How long the feature is bypassed on a route after tripping
+    pub fn cool_off(mut self, cool_off: Duration) -> Self {
+        self.cool_off = cool_off;
+        self
+    }
+
+    /// How long a route must go without errors or slow calls after the cool-off to close again
+    pub fn probe_period(mut self, probe_period: Duration) -> Self {
+        self.probe_period = probe_period;
+        self
+    }
+
+    /// Routes tracked at most. Calls on other routes always run and are not recorded.
+    pub fn max_routes(mut self, max_routes: usize) -> Self {
+        self.max_routes = max_routes;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// State of the feature on `route`, moving to probing once the cool-off elapsed and closing once the probe period
+    /// elapsed
+    pub fn state(&self, route: &str) -> BreakerState {
+        let mut routes = self.routes.borrow_mut();
+        let Some(window) = routes.get_mut(route) else {
+            return BreakerState::Closed;
+        };
+        let now = instant_now();
+        match (window.state, window.until) {
+            (BreakerState::Open, Some(until)) if now >= until => {
+                info!("probing '{}' on route '{route}' after cool-off", self.name);
+                window.state = BreakerState::Probing;
+                window.until = Some(now + self.probe_period);
+                window.reset_window(now);
+                drop(routes);
+                self.record_open();
+                return BreakerState::Probing;
+            }
+            (BreakerState::Probing, Some(until)) if now >= until => {
+                info!("re-enabling '{}' on route '{route}'", self.name);
+                window.state = BreakerState::Closed;
+                window.until = None;
+            }
+            _ => (),
+        }
+        window.state
+    }
+
+    /// Returns `true` if the guarded feature may run on `route`
+    pub fn is_enabled(&self, route: &str) -> bool {
+        self.state(route) != BreakerState::Open
+    }
+
+    /// Runs `f` if the feature is enabled on `route`, recording its outcome and latency. Returns `None` if bypassed.
+    pub fn run<T, E>(&self, route: &str, f: impl FnOnce() -> Result<T, E>) -> Option<Result<T, E>> {
+        if !self.is_enabled(route) {
+            return None;
+        }
+        let start = instant_now();
+        let out = f();
+        self.record(
+            route,
+            out.is_ok(),
+            instant_now().saturating_duration_since(start),
+        );
+        Some(out)
+    }
+
+    /// Records the outcome of a guarded call on `route` made outside of [`RouteBreaker::run`], i.e. one spanning
+    /// callbacks
+    pub fn record(&self, route: &str, success: bool, elapsed: Duration) {
+        let now = instant_now();
+        let mut routes = self.routes.borrow_mut();
+        if !routes.contains_key(route) {
+            if routes.len() >= self.max_routes {
+                debug!(
+                    "not tracking route '{route}' of '{}': too many routes",
+                    self.name
+                );
+                return;
+            }
+            routes.insert(route.to_string(), RouteWindow::new(now));
+        }
+        let window = routes.get_mut(route).unwrap();
+        let slow = self.latency_budget.is_some_and(|budget| elapsed > budget);
+        let tripped = match window.state {
+            BreakerState::Open => return,
+            BreakerState::Probing => {
+                (!success || slow).then_some(if success { "latency" } else { "error" })
+            }
+            BreakerState::Closed => {
+                if now.saturating_duration_since(window.window_start) >= self.window {
+                    window.reset_window(now);
+                }
+                window.calls += 1;
+                window.errors += !success as u32;
+                window.slow += slow as u32;
+                let rate = |count: u32| count as f64 / window.calls as f64;
+                if window.calls < self.min_calls {
+                    None
+                } else if rate(window.errors) > self.max_error_rate {
+                    Some("error")
+                } else if rate(window.slow) > self.max_slow_rate {
+                    Some("latency")
+                } else {
+                    None
+                }
+            }
+        };
+        let Some(budget) = tripped else {
+            return;
+        };
+        warn!(
+            "bypassing '{}' on route '{route}' for {:?}: {budget} budget exhausted ({} errors, {} slow of {} calls)",
+            self.name, self.cool_off, window.errors, window.slow, window.calls
+        );
+        window.state = BreakerState::Open;
+        window.until = Some(now + self.cool_off);
+        drop(routes);
+        Counter::define(format!("{}_route_breaker_tripped", self.name)).increment(1);
+        self.record_open();
+    }
+
+    /// Routes the feature is bypassed on
+    pub fn open_routes(&self) -> Vec<String> {
+        self.routes
+            .borrow()
+            .iter()
+            .filter(|(_, x)| x.state == BreakerState::Open)
+            .map(|(route, _)| route.clone())
+            .collect()
+    }
+
+    fn record_open(&self) {
+        let open = self
+            .routes
+            .borrow()
+            .values()
+            .filter(|x| x.state == BreakerState::Open)
+            .count();
+        Gauge::define(format!("{}_route_breaker_open", self.name)).record(open as u64);
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::testing::{metric, reset_host};
+
+    #[test]
+    fn test_breaker() {
+        reset_host();
+        let breaker = Breaker::new("scan")
+            .max_errors(2)
+            .max_slow(2)
+            .latency_budget(Duration::from_secs(1))
+            .cool_off(Duration::from_millis(5));
+        assert_eq!(breaker.run(|| Err::<(), _>("failed")), Some(Err("failed")));
+        assert!(breaker.is_enabled());
+        breaker.record(false, Duration::ZERO);
+        assert!(!breaker.is_enabled());
+        assert_eq!(breaker.run(|| Ok::<_, ()>(())), None);
+        assert_eq!(metric("scan_breaker_tripped"), Some(1));
+        assert_eq!(metric("scan_breaker_open"), Some(1));
+
+        std::thread::sleep(Duration::from_millis(6));
+        assert!(breaker.is_enabled());
+        assert_eq!(metric("scan_breaker_open"), Some(0));
+        // errors before the cool-off are forgotten
+        breaker.record(false, Duration::ZERO);
+        assert!(breaker.is_enabled());
+        breaker.record(true, Duration::from_secs(2));
+        assert!(breaker.is_enabled());
+        breaker.record(true, Duration::from_secs(2));
+        assert!(!breaker.is_enabled());
+        assert_eq!(metric("scan_breaker_tripped"), Some(2));
+    }
+
+    #[test]
+    fn test_breaker_window() {
+        reset_host();
+        let breaker = Breaker::new("scan")
+            .max_errors(2)
+            .window(Duration::from_millis(5));
+        breaker.record(false, Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(6));
+        // the first error fell out of the window
+        breaker.record(false, Duration::ZERO);
+        assert!(breaker.is_enabled());
+        breaker.record(false, Duration::ZERO);
+        assert!(!breaker.is_enabled());
+    }
+
+    #[test]
+    fn test_route_breaker() {
+        reset_host();
+        let breaker = RouteBreaker::new("scan")
+            .min_calls(4)
+            .max_error_rate(0.25)
+            .latency_budget(Duration::from_secs(1))
+            .cool_off(Duration::from_millis(5))
+            .probe_period(Duration::from_millis(5));
+        for success in [true, false, true] {
+            breaker.record("checkout", success, Duration::ZERO);
+        }
+        for _ in 0..4 {
+            breaker.record("search", true, Duration::from_secs(2));
+        }
+        assert!(breaker.is_enabled("checkout"));
+        assert_eq!(breaker.state("search"), BreakerState::Open);
+        assert_eq!(breaker.run("search", || Ok::<_, ()>(())), None);
+        breaker.record("checkout", false, Duration::ZERO);
+        assert_eq!(breaker.state("checkout"), BreakerState::Open);
+        assert_eq!(metric("scan_route_breaker_tripped"), Some(2));
+        assert_eq!(metric("scan_route_breaker_open"), Some(2));
+        assert!(breaker.is_enabled("other"));
+
+        std::thread::sleep(Duration::from_millis(6));
+        assert_eq!(breaker.state("search"), BreakerState::Probing);
+        assert_eq!(breaker.open_routes(), ["checkout"]);
+        assert_eq!(breaker.run("search", || Ok::<_, ()>(1)), Some(Ok(1)));
+        // a single error while probing trips again
+        assert_eq!(breaker.state("checkout"), BreakerState::Probing);
+        breaker.record("checkout", false, Duration::ZERO);
+        assert_eq!(breaker.state("checkout"), BreakerState::Open);
+
+        std::thread::sleep(Duration::from_millis(6));
+        assert_eq!(breaker.state("search"), BreakerState::Closed);
+        assert_eq!(metric("scan_route_breaker_tripped"), Some(3));
+    }
+}
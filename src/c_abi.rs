@@ -0,0 +1,284 @@
+//! Stable C ABI for C/C++ proxies embedding a native build of a plugin.
+//!
+//! The `proxy_on_*` exports follow the proxy-wasm ABI and use pointer sized integers and Rust enums.
+//! The `proxy_sdk_*` shims here only use fixed width integers, cover the lifecycle calls that are plain Rust functions
+//! (reset, deferred work), and are described by [`HEADER`]. Their signatures only change with [`C_ABI_VERSION`].
+//!
+//! Embedders can write the header from a build script with [`write_header`], in place of running cbindgen.
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::{io, path::Path};
+
+use crate::{
+    dispatcher::{
+        proxy_on_configure, proxy_on_context_create, proxy_on_delete, proxy_on_done,
+        proxy_on_downstream_connection_close, proxy_on_downstream_data, proxy_on_log,
+        proxy_on_new_connection, proxy_on_request_body, proxy_on_request_headers,
+        proxy_on_request_metadata, proxy_on_request_trailers, proxy_on_response_body,
+        proxy_on_response_headers, proxy_on_response_metadata, proxy_on_response_trailers,
+        proxy_on_tick, proxy_on_upstream_connection_close, proxy_on_upstream_data,
+        proxy_on_vm_start,
+    },
+    CloseType,
+};
+
+/// Version of the `proxy_sdk_*` surface, bumped on any incompatible change
+pub const C_ABI_VERSION: u32 = 1;
+
+/// C header declaring the `proxy_sdk_*` shims
+pub const HEADER: &str = r#"/* Generated by proxy-sdk. Do not edit. */
+#ifndef PROXY_SDK_H
+#define PROXY_SDK_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PROXY_SDK_C_ABI_VERSION 1
+
+/* Close types */
+#define PROXY_SDK_CLOSE_UNKNOWN 0
+#define PROXY_SDK_CLOSE_LOCAL 1
+#define PROXY_SDK_CLOSE_REMOTE 2
+
+/* Statuses returned by event shims are the proxy-wasm filter statuses, i.e. 0 is continue. */
+
+uint32_t proxy_sdk_c_abi_version(void);
+void proxy_sdk_reset(void);
+uint32_t proxy_sdk_dispatch_pending(void);
+
+void proxy_sdk_create_context(uint32_t context_id, uint32_t root_context_id);
+uint32_t proxy_sdk_vm_start(uint32_t root_context_id, uint32_t vm_configuration_size);
+uint32_t proxy_sdk_configure(uint32_t root_context_id, uint32_t plugin_configuration_size);
+void proxy_sdk_tick(uint32_t root_context_id);
+uint32_t proxy_sdk_done(uint32_t context_id);
+void proxy_sdk_log(uint32_t context_id);
+void proxy_sdk_delete(uint32_t context_id);
+
+uint32_t proxy_sdk_new_connection(uint32_t context_id);
+uint32_t proxy_sdk_downstream_data(uint32_t context_id, uint32_t data_size, uint32_t end_of_stream);
+uint32_t proxy_sdk_upstream_data(uint32_t context_id, uint32_t data_size, uint32_t end_of_stream);
+void proxy_sdk_downstream_close(uint32_t context_id, uint32_t close_type);
+void proxy_sdk_upstream_close(uint32_t context_id, uint32_t close_type);
+
+uint32_t proxy_sdk_request_headers(uint32_t context_id, uint32_t header_count, uint32_t end_of_stream);
+uint32_t proxy_sdk_request_body(uint32_t context_id, uint32_t body_size, uint32_t end_of_stream);
+uint32_t proxy_sdk_request_trailers(uint32_t context_id, uint32_t trailer_count);
+uint32_t proxy_sdk_response_headers(uint32_t context_id, uint32_t header_count, uint32_t end_of_stream);
+uint32_t proxy_sdk_response_body(uint32_t context_id, uint32_t body_size, uint32_t end_of_stream);
+uint32_t proxy_sdk_response_trailers(uint32_t context_id, uint32_t trailer_count);
+uint32_t proxy_sdk_request_metadata(uint32_t context_id, uint32_t element_count);
+uint32_t proxy_sdk_response_metadata(uint32_t context_id, uint32_t element_count);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PROXY_SDK_H */
+"#;
+
+/// Writes [`HEADER`] to `path`, i.e. from a build script. Leaves the file untouched if it is already current.
+pub fn write_header(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    if std::fs::read_to_string(path).is_ok_and(|x| x == HEADER) {
+        return Ok(());
+    }
+    std::fs::write(path, HEADER)
+}
+
+fn close_type(value: u32) -> CloseType {
+    match value {
+        1 => CloseType::Local,
+        2 => CloseType::Remote,
+        _ => CloseType::Unknown,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_c_abi_version() -> u32 {
+    C_ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_reset() {
+    crate::reset();
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_dispatch_pending() -> u32 {
+    crate::dispatcher::dispatch_pending() as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_create_context(context_id: u32, root_context_id: u32) {
+    proxy_on_context_create(context_id as usize, root_context_id as usize)
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_vm_start(root_context_id: u32, vm_configuration_size: u32) -> u32 {
+    proxy_on_vm_start(root_context_id as usize, vm_configuration_size as usize) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_configure(root_context_id: u32, plugin_configuration_size: u32) -> u32 {
+    proxy_on_configure(root_context_id as usize, plugin_configuration_size as usize) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_tick(root_context_id: u32) {
+    proxy_on_tick(root_context_id as usize)
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_done(context_id: u32) -> u32 {
+    proxy_on_done(context_id as usize) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_log(context_id: u32) {
+    proxy_on_log(context_id as usize)
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_delete(context_id: u32) {
+    proxy_on_delete(context_id as usize)
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_new_connection(context_id: u32) -> u32 {
+    proxy_on_new_connection(context_id as usize) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_downstream_data(
+    context_id: u32,
+    data_size: u32,
+    end_of_stream: u32,
+) -> u32 {
+    proxy_on_downstream_data(
+        context_id as usize,
+        data_size as usize,
+        end_of_stream as usize,
+    ) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_upstream_data(
+    context_id: u32,
+    data_size: u32,
+    end_of_stream: u32,
+) -> u32 {
+    proxy_on_upstream_data(
+        context_id as usize,
+        data_size as usize,
+        end_of_stream as usize,
+    ) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_downstream_close(context_id: u32, close: u32) {
+    proxy_on_downstream_connection_close(context_id as usize, close_type(close))
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_upstream_close(context_id: u32, close: u32) {
+    proxy_on_upstream_connection_close(context_id as usize, close_type(close))
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_request_headers(
+    context_id: u32,
+    header_count: u32,
+    end_of_stream: u32,
+) -> u32 {
+    proxy_on_request_headers(
+        context_id as usize,
+        header_count as usize,
+        end_of_stream as usize,
+    ) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_request_body(
+    context_id: u32,
+    body_size: u32,
+    end_of_stream: u32,
+) -> u32 {
+    proxy_on_request_body(
+        context_id as usize,
+        body_size as usize,
+        end_of_stream as usize,
+    ) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_request_trailers(context_id: u32, trailer_count: u32) -> u32 {
+    proxy_on_request_trailers(context_id as usize, trailer_count as usize) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_response_headers(
+    context_id: u32,
+    header_count: u32,
+    end_of_stream: u32,
+) -> u32 {
+    proxy_on_response_headers(
+        context_id as usize,
+        header_count as usize,
+        end_of_stream as usize,
+    ) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_response_body(
+    context_id: u32,
+    body_size: u32,
+    end_of_stream: u32,
+) -> u32 {
+    proxy_on_response_body(
+        context_id as usize,
+        body_size as usize,
+        end_of_stream as usize,
+    ) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_response_trailers(context_id: u32, trailer_count: u32) -> u32 {
+    proxy_on_response_trailers(context_id as usize, trailer_count as usize) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_request_metadata(context_id: u32, element_count: u32) -> u32 {
+    proxy_on_request_metadata(context_id as usize, element_count as usize) as u32
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_sdk_response_metadata(context_id: u32, element_count: u32) -> u32 {
+    proxy_on_response_metadata(context_id as usize, element_count as usize) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_declares_all_shims() {
+        let source = include_str!("c_abi.rs");
+        let exported = source
+            .lines()
+            .filter_map(|x| x.strip_prefix("pub extern \"C\" fn "))
+            .map(|x| x.split('(').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(exported.len(), 23);
+        for name in exported {
+            assert!(
+                HEADER.contains(&format!(" {name}(")),
+                "{name} missing from header"
+            );
+        }
+        assert!(HEADER.contains(&format!("PROXY_SDK_C_ABI_VERSION {C_ABI_VERSION}")));
+    }
+}
@@ -0,0 +1,190 @@
+//! Response cacheability from `Cache-Control`, `Expires`, `Age` and `Vary`, following RFC 9111.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::time::parse_http_date;
+
+/// Who may store a response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum CacheScope {
+    /// Shared and private caches
+    #[default]
+    Public,
+    /// Only the client's private cache
+    Private,
+}
+
+/// Request headers a cached response varies on
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum Vary {
+    #[default]
+    None,
+    /// Lowercased header names
+    Headers(Vec<String>),
+    /// `Vary: *`, the response can't be reused
+    Any,
+}
+
+/// Caching verdict for a response
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Cacheability {
+    /// Whether the evaluating cache may store the response at all
+    pub storable: bool,
+    pub scope: CacheScope,
+    /// Remaining freshness lifetime, after subtracting `Age`. `None` when no explicit lifetime is given.
+    pub ttl: Option<Duration>,
+    /// `no-cache`: stored responses must be revalidated before every use
+    pub no_cache: bool,
+    /// `must-revalidate` or `proxy-revalidate`: stale responses must not be served
+    pub must_revalidate: bool,
+    /// `stale-while-revalidate` window
+    pub stale_while_revalidate: Option<Duration>,
+    pub vary: Vary,
+}
+
+impl Cacheability {
+    /// Evaluates response headers for a shared cache (a proxy) or a private cache.
+    pub fn from_headers<N: AsRef<str>, V: AsRef<[u8]>>(headers: &[(N, V)], shared: bool) -> Self {
+        Self::from_headers_at(headers, shared, crate::now())
+    }
+
+    /// Like [`Cacheability::from_headers`], using `now` when the response has no `Date`
+    pub fn from_headers_at<N: AsRef<str>, V: AsRef<[u8]>>(
+        headers: &[(N, V)],
+        shared: bool,
+        now: SystemTime,
+    ) -> Self {
+        fn header_values<'a, N: AsRef<str>, V: AsRef<[u8]>>(
+            headers: &'a [(N, V)],
+            name: &'a str,
+        ) -> impl Iterator<Item = &'a str> {
+            headers
+                .iter()
+                .filter(move |(x, _)| x.as_ref().eq_ignore_ascii_case(name))
+                .filter_map(|(_, value)| std::str::from_utf8(value.as_ref()).ok())
+        }
+        let values = |name| header_values(headers, name);
+        let mut out = Cacheability {
+            storable: true,
+            ..Default::default()
+        };
+        let (mut max_age, mut s_maxage) = (None, None);
+        let mut no_store = false;
+        for directive in values("cache-control").flat_map(|x| x.split(',')) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = || {
+                argument
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .map(Duration::from_secs)
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => no_store = true,
+                "private" => out.scope = CacheScope::Private,
+                "public" => (),
+                "no-cache" => out.no_cache = true,
+                "must-revalidate" | "proxy-revalidate" => out.must_revalidate = true,
+                // invalid values are treated as stale, per RFC 9111 4.2.1
+                "max-age" => max_age = Some(seconds().unwrap_or_default()),
+                "s-maxage" => s_maxage = Some(seconds().unwrap_or_default()),
+                "stale-while-revalidate" => out.stale_while_revalidate = seconds(),
+                _ => (),
+            }
+        }
+        let lifetime = s_maxage.filter(|_| shared).or(max_age).or_else(|| {
+            let expires = values("expires").next()?;
+            // invalid `Expires` values, such as `0`, mean already expired
+            let expires = parse_http_date(expires).unwrap_or(UNIX_EPOCH);
+            let date = values("date")
+                .next()
+                .and_then(parse_http_date)
+                .unwrap_or(now);
+            Some(expires.duration_since(date).unwrap_or_default())
+        });
+        let age = values("age")
+            .next()
+            .and_then(|x| x.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        out.ttl = lifetime.map(|x| x.saturating_sub(age));
+        let mut vary = vec![];
+        for name in values("vary").flat_map(|x| x.split(',')) {
+            let name = name.trim();
+            if name == "*" {
+                out.vary = Vary::Any;
+                break;
+            }
+            if !name.is_empty() {
+                vary.push(name.to_ascii_lowercase());
+            }
+        }
+        if out.vary != Vary::Any && !vary.is_empty() {
+            out.vary = Vary::Headers(vary);
+        }
+        out.storable =
+            !no_store && out.vary != Vary::Any && !(shared && out.scope == CacheScope::Private);
+        out
+    }
+
+    /// Whether a stored response may be served without revalidation
+    pub fn is_fresh(&self) -> bool {
+        self.storable && !self.no_cache && self.ttl.is_some_and(|x| !x.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(headers: &[(&str, &str)], shared: bool) -> Cacheability {
+        Cacheability::from_headers_at(headers, shared, UNIX_EPOCH)
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let out = evaluate(
+            &[
+                ("cache-control", "public, max-age=600, s-maxage=60"),
+                ("age", "10"),
+                ("vary", "Accept-Encoding, Origin"),
+            ],
+            true,
+        );
+        assert!(out.is_fresh());
+        assert_eq!(out.ttl, Some(Duration::from_secs(50)));
+        assert_eq!(
+            out.vary,
+            Vary::Headers(vec!["accept-encoding".to_string(), "origin".to_string()])
+        );
+
+        let private = [("Cache-Control", "private, max-age=60")];
+        assert!(!evaluate(&private, true).storable);
+        assert!(evaluate(&private, false).is_fresh());
+        assert!(!evaluate(&[("cache-control", "no-store")], false).storable);
+        assert!(!evaluate(&[("vary", "*")], false).storable);
+        assert!(!evaluate(&[("cache-control", "no-cache, max-age=60")], false).is_fresh());
+    }
+
+    #[test]
+    fn test_expires() {
+        let out = evaluate(
+            &[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+            ],
+            true,
+        );
+        assert_eq!(out.ttl, Some(Duration::from_secs(3600)));
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(784111777))
+        );
+        let expired = evaluate(
+            &[("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "0")],
+            true,
+        );
+        assert_eq!(expired.ttl, Some(Duration::ZERO));
+    }
+}
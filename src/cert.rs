@@ -0,0 +1,521 @@
+//! Typed X.509 certificate details, for certificate pinning and expiry alerting.
+//!
+//! Envoy exposes the subject, first SANs and SHA-256 digest of peer certificates as connection properties,
+//! see [`ConnectionAttributes::peer_certificate`](crate::property::envoy::ConnectionAttributes::peer_certificate).
+//! The certificates themselves are only exposed through the `x-forwarded-client-cert` header, once the HTTP connection manager
+//! is configured with `forward_client_cert_details: SANITIZE_SET` and `set_current_client_cert_details` with `cert` and `chain`.
+//! [`ForwardedClientCert`] parses that header, and [`CertInfo`] the certificates in it.
+
+use std::{
+    fmt,
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{time::days_from_civil, HeaderStr};
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xA0;
+const TAG_EXTENSIONS: u8 = 0xA3;
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_SAN_DNS: u8 = 0x82;
+const TAG_SAN_URI: u8 = 0x86;
+const TAG_SAN_IP: u8 = 0x87;
+
+/// `2.5.29.17`
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+/// `2.5.29.19`
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1D, 0x13];
+
+/// Error parsing a certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertError {
+    /// A PEM block was not terminated or not valid base64
+    Pem,
+    /// The DER encoding ended early or did not have the expected structure
+    Der(&'static str),
+}
+
+impl fmt::Display for CertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertError::Pem => write!(f, "invalid PEM"),
+            CertError::Der(what) => write!(f, "invalid certificate: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for CertError {}
+
+/// Details of an X.509 certificate. The signature is not checked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertInfo {
+    /// Serial number in lowercase hex
+    pub serial: String,
+    /// Subject in RFC 4514 form, i.e. `CN=client.example.com,O=Example,C=US`, like Envoy's `subject_peer_certificate`
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    pub dns_sans: Vec<String>,
+    pub uri_sans: Vec<String>,
+    pub ip_sans: Vec<IpAddr>,
+    /// Whether the basic constraints mark this as a CA certificate
+    pub is_ca: bool,
+    pub der: Vec<u8>,
+}
+
+impl CertInfo {
+    pub fn from_der(der: &[u8]) -> Result<Self, CertError> {
+        let mut certificate = Der::new(der).expect(TAG_SEQUENCE, "certificate")?;
+        let mut tbs = certificate.expect(TAG_SEQUENCE, "tbsCertificate")?;
+        if tbs.peek() == Some(TAG_VERSION) {
+            tbs.next()?;
+        }
+        let serial = tbs.expect(TAG_INTEGER, "serial")?.data;
+        tbs.expect(TAG_SEQUENCE, "signature")?;
+        let issuer = name(tbs.expect(TAG_SEQUENCE, "issuer")?)?;
+        let mut validity = tbs.expect(TAG_SEQUENCE, "validity")?;
+        let not_before = time(&mut validity)?;
+        let not_after = time(&mut validity)?;
+        let subject = name(tbs.expect(TAG_SEQUENCE, "subject")?)?;
+        tbs.expect(TAG_SEQUENCE, "subjectPublicKeyInfo")?;
+
+        let mut info = Self {
+            serial: serial
+                .iter()
+                .skip_while(|x| **x == 0 && serial.len() > 1)
+                .map(|x| format!("{x:02x}"))
+                .collect(),
+            subject,
+            issuer,
+            not_before,
+            not_after,
+            dns_sans: vec![],
+            uri_sans: vec![],
+            ip_sans: vec![],
+            is_ca: false,
+            der: der.to_vec(),
+        };
+        while let Some((tag, mut extensions)) = tbs.next()? {
+            if tag != TAG_EXTENSIONS {
+                continue;
+            }
+            let mut extensions = extensions.expect(TAG_SEQUENCE, "extensions")?;
+            while let Some((_, mut extension)) = extensions.next()? {
+                let oid = extension.expect(TAG_OID, "extension id")?.data;
+                if extension.peek() == Some(TAG_BOOLEAN) {
+                    extension.next()?;
+                }
+                let mut value = extension.expect(TAG_OCTET_STRING, "extension value")?;
+                match oid {
+                    OID_SUBJECT_ALT_NAME => info.read_sans(value)?,
+                    OID_BASIC_CONSTRAINTS => {
+                        let mut constraints = value.expect(TAG_SEQUENCE, "basic constraints")?;
+                        if let Some((TAG_BOOLEAN, flag)) = constraints.next()? {
+                            info.is_ca = flag.data.first().is_some_and(|x| *x != 0);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    fn read_sans(&mut self, mut value: Der) -> Result<(), CertError> {
+        let mut names = value.expect(TAG_SEQUENCE, "subject alt names")?;
+        while let Some((tag, name)) = names.next()? {
+            let text = || String::from_utf8_lossy(name.data).into_owned();
+            match tag {
+                TAG_SAN_DNS => self.dns_sans.push(text()),
+                TAG_SAN_URI => self.uri_sans.push(text()),
+                TAG_SAN_IP => match name.data.len() {
+                    4 => self
+                        .ip_sans
+                        .push(<[u8; 4]>::try_from(name.data).unwrap().into()),
+                    16 => self
+                        .ip_sans
+                        .push(<[u8; 16]>::try_from(name.data).unwrap().into()),
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses every `CERTIFICATE` block of a PEM document, in order
+    pub fn from_pem_chain(pem: &str) -> Result<Vec<Self>, CertError> {
+        const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+        const END: &str = "-----END CERTIFICATE-----";
+        let mut out = vec![];
+        let mut rest = pem;
+        while let Some(start) = rest.find(BEGIN) {
+            let body = &rest[start + BEGIN.len()..];
+            let end = body.find(END).ok_or(CertError::Pem)?;
+            let base64: String = body[..end]
+                .chars()
+                .filter(|x| !x.is_ascii_whitespace())
+                .collect();
+            let der = crate::base64::decode(base64).ok_or(CertError::Pem)?;
+            out.push(Self::from_der(&der)?);
+            rest = &body[end + END.len()..];
+        }
+        Ok(out)
+    }
+
+    /// Whether `now` is within the validity period
+    pub fn is_valid_at(&self, now: SystemTime) -> bool {
+        self.not_before <= now && now <= self.not_after
+    }
+
+    /// Time left until expiry. `None` once expired.
+    pub fn expires_in(&self, now: SystemTime) -> Option<Duration> {
+        self.not_after.duration_since(now).ok()
+    }
+
+    /// Whether the issuer and subject are the same, i.e. a root or self-signed certificate
+    pub fn is_self_issued(&self) -> bool {
+        self.subject == self.issuer
+    }
+}
+
+/// Minimal DER reader
+#[derive(Clone, Copy)]
+struct Der<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.first().copied()
+    }
+
+    /// Next element and its contents, `None` at the end
+    fn next(&mut self) -> Result<Option<(u8, Der<'a>)>, CertError> {
+        let Some(&tag) = self.data.first() else {
+            return Ok(None);
+        };
+        let truncated = CertError::Der("truncated");
+        let first = *self.data.get(1).ok_or(truncated.clone())?;
+        let (length, header) = if first < 0x80 {
+            (first as usize, 2)
+        } else {
+            let count = (first & 0x7F) as usize;
+            if count == 0 || count > 4 {
+                return Err(CertError::Der("unsupported length"));
+            }
+            let bytes = self.data.get(2..2 + count).ok_or(truncated.clone())?;
+            (
+                bytes.iter().fold(0usize, |acc, x| (acc << 8) | *x as usize),
+                2 + count,
+            )
+        };
+        let contents = self.data.get(header..header + length).ok_or(truncated)?;
+        self.data = &self.data[header + length..];
+        Ok(Some((tag, Der::new(contents))))
+    }
+
+    fn expect(&mut self, tag: u8, what: &'static str) -> Result<Der<'a>, CertError> {
+        match self.next()? {
+            Some((x, contents)) if x == tag => Ok(contents),
+            _ => Err(CertError::Der(what)),
+        }
+    }
+}
+
+/// Formats a Name as RFC 4514, last RDN first
+fn name(mut sequence: Der) -> Result<String, CertError> {
+    let mut rdns = vec![];
+    while let Some((_, mut set)) = sequence.next()? {
+        let mut attributes = vec![];
+        while let Some((_, mut attribute)) = set.next()? {
+            let oid = attribute.expect(TAG_OID, "attribute type")?.data;
+            let Some((_, value)) = attribute.next()? else {
+                return Err(CertError::Der("attribute value"));
+            };
+            let key = match oid {
+                [0x55, 0x04, 0x03] => "CN".to_string(),
+                [0x55, 0x04, 0x06] => "C".to_string(),
+                [0x55, 0x04, 0x07] => "L".to_string(),
+                [0x55, 0x04, 0x08] => "ST".to_string(),
+                [0x55, 0x04, 0x0A] => "O".to_string(),
+                [0x55, 0x04, 0x0B] => "OU".to_string(),
+                _ => format_oid(oid),
+            };
+            attributes.push(format!(
+                "{key}={}",
+                escape_rdn(&String::from_utf8_lossy(value.data))
+            ));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Ok(rdns.join(","))
+}
+
+fn escape_rdn(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        if matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, '#' | ' '))
+        {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn format_oid(oid: &[u8]) -> String {
+    let mut parts = vec![];
+    let mut value = 0u64;
+    for byte in oid {
+        value = (value << 7) | (*byte & 0x7F) as u64;
+        if byte & 0x80 != 0 {
+            continue;
+        }
+        if parts.is_empty() {
+            let first = (value / 40).min(2);
+            parts.push(first);
+            parts.push(value - first * 40);
+        } else {
+            parts.push(value);
+        }
+        value = 0;
+    }
+    parts
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn time(validity: &mut Der) -> Result<SystemTime, CertError> {
+    let invalid = CertError::Der("time");
+    let Some((tag, value)) = validity.next()? else {
+        return Err(invalid);
+    };
+    let text = std::str::from_utf8(value.data).map_err(|_| invalid.clone())?;
+    let text = text.strip_suffix('Z').ok_or(invalid.clone())?;
+    let (year, rest) = match tag {
+        TAG_UTC_TIME if text.len() == 12 => {
+            let year: u64 = text[..2].parse().map_err(|_| invalid.clone())?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &text[2..],
+            )
+        }
+        TAG_GENERALIZED_TIME if text.len() == 14 => {
+            (text[..4].parse().map_err(|_| invalid.clone())?, &text[4..])
+        }
+        _ => return Err(invalid),
+    };
+    let field = |i: usize| -> Result<u64, CertError> {
+        rest.get(i * 2..i * 2 + 2)
+            .and_then(|x| x.parse().ok())
+            .ok_or(CertError::Der("time"))
+    };
+    let (month, day) = (field(0)?, field(1)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return Err(invalid);
+    }
+    let days = days_from_civil(year, month, day).ok_or(invalid)?;
+    Ok(UNIX_EPOCH
+        + Duration::from_secs(days * 86400 + field(2)? * 3600 + field(3)? * 60 + field(4)?))
+}
+
+/// What Envoy exposes about the peer certificate of a connection as properties
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerCertificate {
+    /// Whether the peer presented a certificate. Only known for downstream connections. With a validation context
+    /// configured, Envoy closes connections whose certificate fails validation, so a presented certificate is a validated one.
+    pub presented: Option<bool>,
+    pub subject: Option<String>,
+    /// First DNS SAN
+    pub dns_san: Option<String>,
+    /// First URI SAN
+    pub uri_san: Option<String>,
+    /// Hex SHA-256 digest of the DER certificate
+    pub sha256_digest: Option<String>,
+    /// Why the TLS handshake failed, i.e. on certificate validation failures
+    pub failure_reason: Option<String>,
+}
+
+impl PeerCertificate {
+    /// Whether the certificate digest is one of `pins`, as hex SHA-256 digests of DER certificates
+    pub fn matches_pin<S: AsRef<str>>(&self, pins: impl IntoIterator<Item = S>) -> bool {
+        let Some(digest) = &self.sha256_digest else {
+            return false;
+        };
+        pins.into_iter()
+            .any(|pin| pin.as_ref().replace(':', "").eq_ignore_ascii_case(digest))
+    }
+}
+
+/// One element of an `x-forwarded-client-cert` header, describing the client certificate seen by one proxy
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ForwardedClientCert {
+    /// SAN of the proxy's own certificate
+    pub by: Option<String>,
+    /// Hex SHA-256 digest of the client certificate
+    pub hash: Option<String>,
+    /// PEM of the client certificate
+    pub cert: Option<String>,
+    /// PEM of the client certificate chain
+    pub chain: Option<String>,
+    pub subject: Option<String>,
+    pub uri: Vec<String>,
+    pub dns: Vec<String>,
+}
+
+impl ForwardedClientCert {
+    /// Parses every element of an `x-forwarded-client-cert` header. The last element was added by the closest proxy.
+    pub fn parse(header: &str) -> Vec<Self> {
+        split_unquoted(header, ',')
+            .into_iter()
+            .map(|element| {
+                let mut out = Self::default();
+                for pair in split_unquoted(element, ';') {
+                    let Some((key, value)) = pair.split_once('=') else {
+                        continue;
+                    };
+                    let value = value.trim();
+                    let value = value
+                        .strip_prefix('"')
+                        .and_then(|x| x.strip_suffix('"'))
+                        .map(|x| x.replace("\\\"", "\""))
+                        .unwrap_or_else(|| value.to_string());
+                    let decode = |x: &str| HeaderStr::from(x).percent_decode().to_utf8_lossy();
+                    match &*key.trim().to_ascii_lowercase() {
+                        "by" => out.by = Some(value),
+                        "hash" => out.hash = Some(value),
+                        "cert" => out.cert = Some(decode(&value)),
+                        "chain" => out.chain = Some(decode(&value)),
+                        "subject" => out.subject = Some(value),
+                        "uri" => out.uri.push(value),
+                        "dns" => out.dns.push(value),
+                        _ => (),
+                    }
+                }
+                out
+            })
+            .collect()
+    }
+
+    /// The client certificate followed by the rest of its chain, leaf first
+    pub fn certificates(&self) -> Result<Vec<CertInfo>, CertError> {
+        let mut out = match &self.cert {
+            Some(cert) => CertInfo::from_pem_chain(cert)?,
+            None => vec![],
+        };
+        if let Some(chain) = &self.chain {
+            for cert in CertInfo::from_pem_chain(chain)? {
+                if !out.iter().any(|x| x.der == cert.der) {
+                    out.push(cert);
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn split_unquoted(input: &str, separator: char) -> Vec<&str> {
+    let mut out = vec![];
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                out.push(&input[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    out.push(&input[start..]);
+    out.retain(|x| !x.trim().is_empty());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB/jCCAaWgAwIBAgICEjQwCgYIKoZIzj0EAwIwPDELMAkGA1UEBhMCVVMxEDAO
+BgNVBAoMB0V4YW1wbGUxGzAZBgNVBAMMEmNsaWVudC5leGFtcGxlLmNvbTAeFw0y
+NjEwMTUwODI1MTBaFw0yNzEwMTUwODI1MTBaMDwxCzAJBgNVBAYTAlVTMRAwDgYD
+VQQKDAdFeGFtcGxlMRswGQYDVQQDDBJjbGllbnQuZXhhbXBsZS5jb20wWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAARp9p/TG/ljUy3VPXk9vLbX93FecrTQvS0Y55yd
+r7HJmNobdVsSu7vV49N7yzlNVF2Z5gxWUxXBfdG0netkhuPxo4GWMIGTMB0GA1Ud
+DgQWBBR1d9SM8AoGoAf1QRT4EB1CyLBBTzAfBgNVHSMEGDAWgBR1d9SM8AoGoAf1
+QRT4EB1CyLBBTzAPBgNVHRMBAf8EBTADAQH/MEAGA1UdEQQ5MDeCEmNsaWVudC5l
+eGFtcGxlLmNvbYYbc3BpZmZlOi8vZXhhbXBsZS5jb20vY2xpZW50hwQKAAABMAoG
+CCqGSM49BAMCA0cAMEQCIC7QhplVu7pzcmeG1hjsbnKz/v7arsoCYxApbdDRMIJ3
+AiBoJRV2Ueof0QW7z2lmNFuUGC5is/lwxxcaaRW+9YWYAA==
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_cert_info() {
+        let chain = CertInfo::from_pem_chain(CERT).unwrap();
+        let cert = &chain[0];
+        assert_eq!(cert.serial, "1234");
+        assert_eq!(cert.subject, "CN=client.example.com,O=Example,C=US");
+        assert!(cert.is_self_issued() && cert.is_ca);
+        assert_eq!(
+            cert.not_before,
+            UNIX_EPOCH + Duration::from_secs(1792052710)
+        );
+        assert_eq!(cert.not_after, UNIX_EPOCH + Duration::from_secs(1823588710));
+        assert_eq!(cert.dns_sans, vec!["client.example.com"]);
+        assert_eq!(cert.uri_sans, vec!["spiffe://example.com/client"]);
+        assert_eq!(cert.ip_sans, vec![IpAddr::from([10, 0, 0, 1])]);
+        assert_eq!(
+            cert.expires_in(UNIX_EPOCH + Duration::from_secs(1823588700)),
+            Some(Duration::from_secs(10))
+        );
+        assert!(!cert.is_valid_at(UNIX_EPOCH));
+        assert_eq!(format_oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D]), "1.2.840.10045");
+    }
+
+    #[test]
+    fn test_forwarded_client_cert() {
+        let encoded: String = CERT
+            .bytes()
+            .map(|x| match x {
+                b'\n' => "%0A".to_string(),
+                b' ' => "%20".to_string(),
+                b'+' => "%2B".to_string(),
+                b'/' => "%2F".to_string(),
+                b'=' => "%3D".to_string(),
+                x => (x as char).to_string(),
+            })
+            .collect();
+        let header = format!(
+            "By=spiffe://a;Hash=abc;URI=spiffe://b,By=spiffe://c;Cert=\"{encoded}\";Subject=\"CN=x,O=y\";DNS=x.com;DNS=y.com"
+        );
+        let elements = ForwardedClientCert::parse(&header);
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements[0].uri, vec!["spiffe://b"]);
+        assert_eq!(elements[1].subject.as_deref(), Some("CN=x,O=y"));
+        assert_eq!(elements[1].dns, vec!["x.com", "y.com"]);
+        let certs = elements[1].certificates().unwrap();
+        assert_eq!(certs[0].serial, "1234");
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+
+use log::warn;
+
+use crate::{
+    downstream_writer::append, hostcalls::BufferType, HttpControl, HttpError, HttpHeaderControl,
+    ResponseBody, ResponseHeaders,
+};
+
+/// How a [`ChunkedResponse`] was sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResponseMode {
+    /// Sent with the first chunk as body, the rest is appended chunk by chunk from the response callbacks
+    Streamed,
+    /// Generated up front and sent as a single local response
+    Buffered,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Progress {
+    Pending,
+    Streaming,
+    Done,
+}
+
+type Source = Box<dyn FnMut() -> Option<Vec<u8>>>;
+
+/// A generated local response sent in chunks, i.e. to serve reports or files built by the plugin without holding the
+/// whole body in plugin memory.
+///
+/// `send_http_response` takes a single body. When [`ChunkedResponse::streaming`] is set, the local response is sent
+/// with the first chunk only, and the remaining chunks are appended to the response body as the host runs the local
+/// response through the response callbacks of the sending filter, as Envoy does. The host then buffers the body, but
+/// the plugin only ever holds one chunk. Only enable it on such hosts: others would deliver the first chunk alone.
+/// Otherwise, the chunks are collected and sent as one body.
+///
+/// Send from the request callbacks and stop the request, then forward the response callbacks:
+///
+/// ```ignore
+/// fn on_http_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
+///     let mut rows = self.report.rows();
+///     let mut response = ChunkedResponse::new(200, move || rows.next().map(|x| x.to_csv()))
+///         .header("content-type", "text/csv")
+///         .streaming(true);
+///     response.send(headers).ok();
+///     self.response = Some(response);
+///     FilterHeadersStatus::StopIteration
+/// }
+///
+/// fn on_http_response_headers(&mut self, headers: &ResponseHeaders) -> FilterHeadersStatus {
+///     if let Some(response) = &self.response {
+///         response.on_response_headers(headers);
+///     }
+///     FilterHeadersStatus::Continue
+/// }
+///
+/// fn on_http_response_body(&mut self, body: &ResponseBody) -> FilterDataStatus {
+///     if let Some(response) = &mut self.response {
+///         response.on_response_body(body);
+///     }
+///     FilterDataStatus::Continue
+/// }
+/// ```
+pub struct ChunkedResponse {
+    status: u32,
+    headers: Vec<(String, Vec<u8>)>,
+    streaming: bool,
+    source: Source,
+    progress: Progress,
+    mode: Option<ResponseMode>,
+    sent: usize,
+}
+
+impl ChunkedResponse {
+    /// Responds with `status` and the chunks returned by `source` until it returns `None`
+    pub fn new(status: u32, source: impl FnMut() -> Option<Vec<u8>> + 'static) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            streaming: false,
+            source: Box::new(source),
+            progress: Progress::Pending,
+            mode: None,
+            sent: 0,
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Appends chunks from the response callbacks, for hosts running local responses through the sending filter.
+    /// Off by default.
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    /// Sends the response, from a request callback of the HTTP context. Once sent, only returns how it was sent.
+    pub fn send(&mut self, http: &impl HttpControl) -> Result<ResponseMode, HttpError> {
+        if let Some(mode) = self.mode {
+            return Ok(mode);
+        }
+        let (mode, body) = if self.streaming {
+            (
+                ResponseMode::Streamed,
+                self.next_chunk().unwrap_or_default(),
+            )
+        } else {
+            let mut body = vec![];
+            while let Some(chunk) = self.next_chunk() {
+                body.extend_from_slice(&chunk);
+            }
+            (ResponseMode::Buffered, body)
+        };
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| (&**name, &**value))
+            .collect::<Vec<_>>();
+        http.send_http_response(self.status, &headers, Some(&body))?;
+        self.sent = body.len();
+        self.mode = Some(mode);
+        self.progress = match mode {
+            ResponseMode::Streamed if !body.is_empty() => Progress::Streaming,
+            _ => Progress::Done,
+        };
+        Ok(mode)
+    }
+
+    /// Drops the `content-length` the host set for the first chunk, as the body grows past it
+    pub fn on_response_headers(&self, headers: &ResponseHeaders) {
+        if self.progress == Progress::Streaming {
+            headers.remove("content-length");
+        }
+    }
+
+    /// Appends the remaining chunks to the body of the local response. Returns `true` once the whole body was sent.
+    pub fn on_response_body(&mut self, body: &ResponseBody) -> bool {
+        if self.progress != Progress::Streaming || !body.end_of_stream() {
+            return self.progress == Progress::Done;
+        }
+        while let Some(chunk) = self.next_chunk() {
+            if let Err(e) = append(BufferType::HttpResponseBody, &chunk) {
+                warn!(
+                    "chunked response truncated after {} bytes: {e:?}",
+                    self.sent
+                );
+                body.reset();
+                break;
+            }
+            self.sent += chunk.len();
+        }
+        self.progress = Progress::Done;
+        true
+    }
+
+    /// Body bytes handed to the host so far
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Skips empty chunks, which the source may return between pieces of work
+    fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match (self.source)() {
+                Some(chunk) if chunk.is_empty() => continue,
+                chunk => return chunk,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ChunkedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkedResponse")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("streaming", &self.streaming)
+            .field("progress", &self.progress)
+            .field("mode", &self.mode)
+            .field("sent", &self.sent)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::HttpScenario, BaseContext, Context, FilterDataStatus, FilterHeadersStatus,
+        HttpContext, RequestHeaders, RootContext,
+    };
+
+    struct Root(bool);
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn create_context(&mut self) -> Context {
+            Context::Http(Box::new(Report {
+                streaming: self.0,
+                response: None,
+            }))
+        }
+    }
+
+    struct Report {
+        streaming: bool,
+        response: Option<ChunkedResponse>,
+    }
+
+    impl BaseContext for Report {}
+
+    impl HttpContext for Report {
+        fn on_http_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
+            let mut rows = ["id,name\n", "", "1,a\n", "2,b\n"].into_iter();
+            let mut response = ChunkedResponse::new(200, move || rows.next().map(Vec::from))
+                .header("content-type", "text/csv")
+                .streaming(self.streaming);
+            let mode = response.send(headers).unwrap();
+            assert_eq!(response.send(headers), Ok(mode));
+            self.response = Some(response);
+            FilterHeadersStatus::StopIteration
+        }
+
+        fn on_http_response_headers(&mut self, headers: &ResponseHeaders) -> FilterHeadersStatus {
+            if let Some(response) = &self.response {
+                response.on_response_headers(headers);
+            }
+            FilterHeadersStatus::Continue
+        }
+
+        fn on_http_response_body(&mut self, body: &ResponseBody) -> FilterDataStatus {
+            if let Some(response) = &mut self.response {
+                assert!(response.on_response_body(body));
+                assert_eq!(response.sent(), 16);
+            }
+            FilterDataStatus::Continue
+        }
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        // the host runs the local response through the response callbacks
+        let outcome = HttpScenario::new(|| Root(true))
+            .request_headers([(":path", "/report.csv")])
+            .expect(FilterHeadersStatus::StopIteration)
+            .response_headers([(":status", "200"), ("content-length", "8")])
+            .response_body_end("id,name\n")
+            .run();
+        let local = outcome.local_response.unwrap();
+        assert_eq!(local.body, b"id,name\n");
+        assert_eq!(outcome.response.get_header("content-length"), None);
+        assert_eq!(outcome.response.body, b"id,name\n1,a\n2,b\n");
+
+        let outcome = HttpScenario::new(|| Root(false))
+            .request_headers([(":path", "/report.csv")])
+            .response_headers([(":status", "200"), ("content-length", "16")])
+            .response_body_end("id,name\n1,a\n2,b\n")
+            .run();
+        let local = outcome.local_response.unwrap();
+        assert_eq!(local.body, b"id,name\n1,a\n2,b\n");
+        assert_eq!(local.headers[0].0, "content-type");
+        assert_eq!(
+            outcome.response.get_header("content-length"),
+            Some(&b"16"[..])
+        );
+        assert_eq!(outcome.response.body, b"id,name\n1,a\n2,b\n");
+    }
+}
@@ -0,0 +1,781 @@
+//! GRPC message compression: `gzip` and `deflate` codecs and the 5-byte GRPC message framing carrying the compressed flag.
+//!
+//! Hosts frame messages sent through [`crate::GrpcCall`] and [`crate::GrpcStream`] themselves, never setting the compressed flag,
+//! so per-message compression applies to GRPC spoken over [`crate::HttpCall`] or to messages injected into GRPC bodies.
+//! Received messages that still carry a compressed frame are decompressed by `decompressed_body` on
+//! [`crate::GrpcCallResponse`] and [`crate::GrpcStreamMessage`].
+//! [`GrpcFrame`] reads and writes frames explicitly, i.e. to parse or inject messages in GRPC bodies.
+
+use std::fmt;
+
+use crate::Counter;
+
+/// Default upper bound on a decompressed message, guarding against decompression bombs
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// A `grpc-encoding` algorithm
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum GrpcCompression {
+    #[default]
+    Identity,
+    Gzip,
+    /// zlib format, as GRPC names it
+    Deflate,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompressionError {
+    /// Compressed data is corrupt or truncated
+    Corrupt(&'static str),
+    /// Decompressed data exceeds the allowed size
+    TooLarge,
+    /// The frame is flagged compressed, but the encoding is identity
+    MissingEncoding,
+    /// The frame header is incomplete or its length does not match
+    InvalidFrame,
+    /// The message is not a valid protobuf message of the expected type
+    Decode(prost::DecodeError),
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionError::Corrupt(e) => write!(f, "corrupt compressed data: {e}"),
+            CompressionError::TooLarge => write!(f, "decompressed message too large"),
+            CompressionError::MissingEncoding => {
+                write!(f, "compressed message without a grpc-encoding")
+            }
+            CompressionError::InvalidFrame => write!(f, "invalid grpc message frame"),
+            CompressionError::Decode(e) => write!(f, "invalid protobuf message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {}
+
+impl From<prost::DecodeError> for CompressionError {
+    fn from(value: prost::DecodeError) -> Self {
+        CompressionError::Decode(value)
+    }
+}
+
+impl GrpcCompression {
+    /// Parses a `grpc-encoding` value
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            x if x.eq_ignore_ascii_case("identity") => Some(GrpcCompression::Identity),
+            x if x.eq_ignore_ascii_case("gzip") => Some(GrpcCompression::Gzip),
+            x if x.eq_ignore_ascii_case("deflate") => Some(GrpcCompression::Deflate),
+            _ => None,
+        }
+    }
+
+    /// `grpc-encoding` value
+    pub fn name(&self) -> &'static str {
+        match self {
+            GrpcCompression::Identity => "identity",
+            GrpcCompression::Gzip => "gzip",
+            GrpcCompression::Deflate => "deflate",
+        }
+    }
+
+    /// Picks the first of `preference` listed in the peer's `grpc-accept-encoding`, or identity
+    pub fn negotiate(accept_encoding: &str, preference: &[GrpcCompression]) -> Self {
+        let accepted = accept_encoding
+            .split(',')
+            .filter_map(GrpcCompression::from_name)
+            .collect::<Vec<_>>();
+        preference
+            .iter()
+            .copied()
+            .find(|x| accepted.contains(x))
+            .unwrap_or_default()
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            GrpcCompression::Identity => data.to_vec(),
+            GrpcCompression::Gzip => {
+                let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+                deflate(data, &mut out);
+                out.extend_from_slice(&crc32(data).to_le_bytes());
+                out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                out
+            }
+            GrpcCompression::Deflate => {
+                let mut out = vec![0x78, 0x01];
+                deflate(data, &mut out);
+                out.extend_from_slice(&adler32(data).to_be_bytes());
+                out
+            }
+        }
+    }
+
+    /// Decompresses `data`, failing if the output would exceed `max_size`
+    pub fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, CompressionError> {
+        match self {
+            GrpcCompression::Identity if data.len() > max_size => Err(CompressionError::TooLarge),
+            GrpcCompression::Identity => Ok(data.to_vec()),
+            GrpcCompression::Gzip => {
+                let corrupt = CompressionError::Corrupt;
+                if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+                    return Err(corrupt("bad gzip header"));
+                }
+                let flags = data[3];
+                let mut pos = 10;
+                if flags & 4 != 0 {
+                    let extra = data.get(pos..pos + 2).ok_or(corrupt("truncated"))?;
+                    pos += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+                }
+                for flag in [8, 16] {
+                    if flags & flag != 0 {
+                        let end = data
+                            .get(pos..)
+                            .and_then(|x| x.iter().position(|x| *x == 0))
+                            .ok_or(corrupt("truncated"))?;
+                        pos += end + 1;
+                    }
+                }
+                if flags & 2 != 0 {
+                    pos += 2;
+                }
+                let body = data.get(pos..).ok_or(corrupt("truncated"))?;
+                let (out, used) = inflate(body, max_size)?;
+                let trailer = body.get(used..used + 8).ok_or(corrupt("truncated"))?;
+                if u32::from_le_bytes(trailer[..4].try_into().unwrap()) != crc32(&out)
+                    || u32::from_le_bytes(trailer[4..].try_into().unwrap()) != out.len() as u32
+                {
+                    return Err(corrupt("gzip checksum mismatch"));
+                }
+                Ok(out)
+            }
+            GrpcCompression::Deflate => {
+                let corrupt = CompressionError::Corrupt;
+                if data.len() < 6
+                    || data[0] & 0x0f != 8
+                    || !(data[0] as u16 * 256 + data[1] as u16).is_multiple_of(31)
+                    || data[1] & 0x20 != 0
+                {
+                    return Err(corrupt("bad zlib header"));
+                }
+                let (out, used) = inflate(&data[2..], max_size)?;
+                let trailer = data
+                    .get(2 + used..2 + used + 4)
+                    .ok_or(corrupt("truncated"))?;
+                if u32::from_be_bytes(trailer.try_into().unwrap()) != adler32(&out) {
+                    return Err(corrupt("zlib checksum mismatch"));
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Frames `message` for the wire, compressing it unless `compression` is identity
+pub fn encode_frame(message: &[u8], compression: GrpcCompression) -> Vec<u8> {
+    GrpcFrame::compress(message, compression).encode()
+}
+
+/// Decodes the first frame of `data`, decompressing it with `compression` if flagged.
+/// Returns the message and the number of bytes consumed.
+pub fn decode_frame(
+    data: &[u8],
+    compression: GrpcCompression,
+    max_size: usize,
+) -> Result<(Vec<u8>, usize), CompressionError> {
+    let (frame, used) = GrpcFrame::decode(data)?.ok_or(CompressionError::InvalidFrame)?;
+    Ok((frame.message(compression, max_size)?, used))
+}
+
+/// Returns the protobuf message of a received body, stripping a frame header if the host left one in place
+/// and decompressing the message if that frame is flagged compressed.
+/// A protobuf message can never start with `0x00` or `0x01`, so a single frame is detected unambiguously.
+/// The algorithm is detected from the gzip or zlib header.
+pub fn unwrap_message(body: Vec<u8>, max_size: usize) -> Result<Vec<u8>, CompressionError> {
+    let framed = body.len() >= 5
+        && body[0] <= 1
+        && u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize == body.len() - 5;
+    if !framed {
+        return Ok(body);
+    }
+    let compression = match body.get(5..7) {
+        _ if body[0] == 0 => GrpcCompression::Identity,
+        Some([0x1f, 0x8b]) => GrpcCompression::Gzip,
+        _ => GrpcCompression::Deflate,
+    };
+    let (message, _) = decode_frame(&body, compression, max_size)?;
+    if body[0] == 1 {
+        Counter::define("grpc_compressed_bytes_received").increment(body.len() as i64 - 5);
+        Counter::define("grpc_decompressed_bytes_received").increment(message.len() as i64);
+    }
+    Ok(message)
+}
+
+/// A single length-prefixed GRPC message, as carried in GRPC bodies.
+/// The payload is compressed with the `grpc-encoding` of the stream if the frame is flagged compressed.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct GrpcFrame {
+    pub compressed: bool,
+    pub payload: Vec<u8>,
+}
+
+impl GrpcFrame {
+    /// An uncompressed frame of `message`
+    pub fn new(message: impl Into<Vec<u8>>) -> Self {
+        Self {
+            compressed: false,
+            payload: message.into(),
+        }
+    }
+
+    /// A frame of `message` compressed with `compression`, uncompressed if identity
+    pub fn compress(message: &[u8], compression: GrpcCompression) -> Self {
+        Self {
+            compressed: compression != GrpcCompression::Identity,
+            payload: compression.compress(message),
+        }
+    }
+
+    /// An uncompressed frame of an encoded protobuf message
+    pub fn from_message<M: prost::Message>(message: &M) -> Self {
+        Self::new(message.encode_to_vec())
+    }
+
+    /// Decodes the first frame of `data`. Returns the frame and the number of bytes consumed,
+    /// or `None` if `data` does not hold a complete frame yet, i.e. when reading a body chunk by chunk.
+    pub fn decode(data: &[u8]) -> Result<Option<(Self, usize)>, CompressionError> {
+        let Some(header) = data.get(..5) else {
+            return Ok(None);
+        };
+        if header[0] > 1 {
+            return Err(CompressionError::InvalidFrame);
+        }
+        let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+        Ok(data.get(5..5 + len).map(|payload| {
+            (
+                Self {
+                    compressed: header[0] == 1,
+                    payload: payload.to_vec(),
+                },
+                5 + len,
+            )
+        }))
+    }
+
+    /// Decodes all frames of `data`, which must end on a frame boundary
+    pub fn decode_all(mut data: &[u8]) -> Result<Vec<Self>, CompressionError> {
+        let mut frames = vec![];
+        while !data.is_empty() {
+            let (frame, used) = Self::decode(data)?.ok_or(CompressionError::InvalidFrame)?;
+            frames.push(frame);
+            data = &data[used..];
+        }
+        Ok(frames)
+    }
+
+    /// Frames the payload for the wire, i.e. for injection into a GRPC body
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload.len() + 5);
+        self.encode_into(&mut out);
+        out
+    }
+
+    /// Appends the framed payload to `out`
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.push(self.compressed as u8);
+        out.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+    }
+
+    pub fn header(&self) -> crate::GrpcFrameHeader {
+        crate::GrpcFrameHeader {
+            compressed: self.compressed,
+            length: self.payload.len(),
+        }
+    }
+
+    /// The message of the frame, decompressed with `compression` if flagged
+    pub fn message(
+        &self,
+        compression: GrpcCompression,
+        max_size: usize,
+    ) -> Result<Vec<u8>, CompressionError> {
+        match (self.compressed, compression) {
+            (false, _) => Ok(self.payload.clone()),
+            (true, GrpcCompression::Identity) => Err(CompressionError::MissingEncoding),
+            (true, compression) => compression.decompress(&self.payload, max_size),
+        }
+    }
+
+    /// The message of the frame decoded as a protobuf message
+    pub fn decode_message<M: prost::Message + Default>(
+        &self,
+        compression: GrpcCompression,
+        max_size: usize,
+    ) -> Result<M, CompressionError> {
+        if !self.compressed {
+            return Ok(M::decode(&self.payload[..])?);
+        }
+        Ok(M::decode(&self.message(compression, max_size)?[..])?)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u8,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u8) -> Result<u32, CompressionError> {
+        let mut out = 0u32;
+        for i in 0..count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or(CompressionError::Corrupt("truncated"))?;
+            out |= (((byte >> self.bit) & 1) as u32) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(out)
+    }
+
+    fn align(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// Canonical Huffman code, decoded a bit at a time
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for len in lengths {
+            counts[*len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, CompressionError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(CompressionError::Corrupt("invalid huffman code"))
+    }
+}
+
+/// Decompresses raw deflate data, returning the output and the number of input bytes consumed
+fn inflate(data: &[u8], max_size: usize) -> Result<(Vec<u8>, usize), CompressionError> {
+    let corrupt = CompressionError::Corrupt;
+    let mut reader = BitReader {
+        data,
+        pos: 0,
+        bit: 0,
+    };
+    let mut out: Vec<u8> = vec![];
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = data
+                    .get(reader.pos..reader.pos + 4)
+                    .ok_or(corrupt("truncated"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(corrupt("stored block length mismatch"));
+                }
+                reader.pos += 4;
+                let block = data
+                    .get(reader.pos..reader.pos + len as usize)
+                    .ok_or(corrupt("truncated"))?;
+                if out.len() + block.len() > max_size {
+                    return Err(CompressionError::TooLarge);
+                }
+                out.extend_from_slice(block);
+                reader.pos += len as usize;
+            }
+            kind @ (1 | 2) => {
+                let (literals, distances) = if kind == 1 {
+                    let mut lengths = [8u8; 288];
+                    lengths[144..256].fill(9);
+                    lengths[256..280].fill(7);
+                    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+                } else {
+                    dynamic_tables(&mut reader)?
+                };
+                loop {
+                    let symbol = literals.decode(&mut reader)?;
+                    match symbol {
+                        0..=255 => {
+                            if out.len() >= max_size {
+                                return Err(CompressionError::TooLarge);
+                            }
+                            out.push(symbol as u8);
+                        }
+                        256 => break,
+                        257..=285 => {
+                            let i = symbol as usize - 257;
+                            let len =
+                                LENGTH_BASE[i] as usize + reader.bits(LENGTH_EXTRA[i])? as usize;
+                            let d = distances.decode(&mut reader)? as usize;
+                            if d >= 30 {
+                                return Err(corrupt("invalid distance code"));
+                            }
+                            let dist = DIST_BASE[d] as usize + reader.bits(DIST_EXTRA[d])? as usize;
+                            if dist > out.len() {
+                                return Err(corrupt("distance too far back"));
+                            }
+                            if out.len() + len > max_size {
+                                return Err(CompressionError::TooLarge);
+                            }
+                            let start = out.len() - dist;
+                            for i in 0..len {
+                                out.push(out[start + i]);
+                            }
+                        }
+                        _ => return Err(corrupt("invalid literal code")),
+                    }
+                }
+            }
+            _ => return Err(corrupt("invalid block type")),
+        }
+        if last {
+            reader.align();
+            return Ok((out, reader.pos));
+        }
+    }
+}
+
+fn dynamic_tables(reader: &mut BitReader) -> Result<(Huffman, Huffman), CompressionError> {
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for i in ORDER.iter().take(code_count) {
+        code_lengths[*i] = reader.bits(3)? as u8;
+    }
+    let codes = Huffman::new(&code_lengths);
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match codes.decode(reader)? {
+            x @ 0..=15 => (x as u8, 1),
+            16 => (
+                *lengths
+                    .last()
+                    .ok_or(CompressionError::Corrupt("repeat without length"))?,
+                3 + reader.bits(2)?,
+            ),
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(CompressionError::Corrupt("code lengths overflow"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitWriter<'_> {
+    fn bits(&mut self, value: u32, count: u8) {
+        self.buffer |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit
+    fn code(&mut self, code: u32, len: u8) {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn literal(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol as u32, 8),
+            144..=255 => self.code(0x190 + symbol as u32 - 144, 9),
+            256..=279 => self.code(symbol as u32 - 256, 7),
+            _ => self.code(0xc0 + symbol as u32 - 280, 8),
+        }
+    }
+
+    fn finish(mut self) {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.count = 0;
+    }
+}
+
+/// Compresses `data` as a single fixed Huffman deflate block with LZ77 matching
+fn deflate(data: &[u8], out: &mut Vec<u8>) {
+    const WINDOW: usize = 32768;
+    const HASH_BITS: u32 = 15;
+    const MAX_CHAIN: usize = 32;
+    let mut writer = BitWriter {
+        out,
+        buffer: 0,
+        count: 0,
+    };
+    writer.bits(1, 1);
+    writer.bits(1, 2);
+    let hash = |i: usize| {
+        let x = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+        (x.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    };
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |i: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if i + 2 < data.len() {
+            let h = hash(i);
+            prev[i] = head[h];
+            head[h] = i;
+        }
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        if i + 2 < data.len() {
+            let mut candidate = head[hash(i)];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW && chain < MAX_CHAIN {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[i..])
+                    .take(258)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, i - candidate);
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+        if best.0 >= 3 {
+            let (len, dist) = best;
+            let l = LENGTH_BASE
+                .iter()
+                .rposition(|x| *x as usize <= len)
+                .unwrap();
+            writer.literal(257 + l as u16);
+            writer.bits((len - LENGTH_BASE[l] as usize) as u32, LENGTH_EXTRA[l]);
+            let d = DIST_BASE.iter().rposition(|x| *x as usize <= dist).unwrap();
+            writer.code(d as u32, 5);
+            writer.bits((dist - DIST_BASE[d] as usize) as u32, DIST_EXTRA[d]);
+            for j in i..i + len {
+                insert(j, &mut head, &mut prev);
+            }
+            i += len;
+        } else {
+            writer.literal(data[i] as u16);
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    writer.literal(256);
+    writer.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text = b"hello hello hello, grpc compression! ".repeat(50);
+        for compression in [
+            GrpcCompression::Identity,
+            GrpcCompression::Gzip,
+            GrpcCompression::Deflate,
+        ] {
+            let compressed = compression.compress(&text);
+            if compression != GrpcCompression::Identity {
+                assert!(compressed.len() < text.len() / 4);
+            }
+            assert_eq!(compression.decompress(&compressed, 1 << 20).unwrap(), text);
+            assert_eq!(
+                compression.decompress(&compressed, 100),
+                Err(CompressionError::TooLarge)
+            );
+        }
+        assert_eq!(
+            GrpcCompression::Gzip.decompress(&[], 10).unwrap_err(),
+            CompressionError::Corrupt("bad gzip header")
+        );
+    }
+
+    #[test]
+    fn test_known_streams() {
+        // `printf 'hello' | gzip -n`
+        let gzip = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x07, 0x00, 0x86, 0xa6, 0x10, 0x36, 0x05, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(
+            GrpcCompression::Gzip.decompress(&gzip, 100).unwrap(),
+            b"hello"
+        );
+        // zlib stored block
+        let zlib = [
+            0x78, 0x01, 0x01, 0x02, 0x00, 0xfd, 0xff, b'h', b'i', 0x01, 0x3b, 0x00, 0xd2,
+        ];
+        assert_eq!(
+            GrpcCompression::Deflate.decompress(&zlib, 100).unwrap(),
+            b"hi"
+        );
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn test_frames() {
+        let message = b"\x0a\x05hello".repeat(20);
+        let frame = encode_frame(&message, GrpcCompression::Gzip);
+        assert_eq!(frame[0], 1);
+        assert_eq!(
+            decode_frame(&frame, GrpcCompression::Gzip, 1 << 20).unwrap(),
+            (message.clone(), frame.len())
+        );
+        assert_eq!(
+            decode_frame(&frame, GrpcCompression::Identity, 1 << 20),
+            Err(CompressionError::MissingEncoding)
+        );
+        let plain = encode_frame(&message, GrpcCompression::Identity);
+        assert_eq!(&plain[5..], &message[..]);
+        assert_eq!(
+            GrpcCompression::negotiate("identity, deflate,gzip", &[GrpcCompression::Gzip]),
+            GrpcCompression::Gzip
+        );
+        assert_eq!(
+            GrpcCompression::negotiate("identity", &[GrpcCompression::Gzip]),
+            GrpcCompression::Identity
+        );
+    }
+
+    #[test]
+    fn test_grpc_frame() {
+        let message = prost_types::Duration {
+            seconds: 30,
+            nanos: 5,
+        };
+        let mut body = GrpcFrame::from_message(&message).encode();
+        GrpcFrame::compress(b"second", GrpcCompression::Gzip).encode_into(&mut body);
+        let frames = GrpcFrame::decode_all(&body).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            frames[0]
+                .decode_message::<prost_types::Duration>(GrpcCompression::Identity, 1 << 20)
+                .unwrap(),
+            message
+        );
+        assert!(frames[1].header().compressed);
+        assert_eq!(
+            frames[1].message(GrpcCompression::Gzip, 1 << 20).unwrap(),
+            b"second"
+        );
+        assert_eq!(GrpcFrame::decode(&body[..4]), Ok(None));
+        assert_eq!(
+            GrpcFrame::decode(&body[..body.len() - 1])
+                .unwrap()
+                .unwrap()
+                .1,
+            9
+        );
+        assert_eq!(
+            GrpcFrame::decode_all(&body[..body.len() - 1]),
+            Err(CompressionError::InvalidFrame)
+        );
+        assert!(matches!(
+            GrpcFrame::new(b"\xff".to_vec())
+                .decode_message::<prost_types::Duration>(GrpcCompression::Identity, 1 << 20),
+            Err(CompressionError::Decode(_))
+        ));
+    }
+}
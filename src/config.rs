@@ -0,0 +1,670 @@
+//! Versioned plugin configuration with forward migrations.
+//!
+//! Each schema version is its own type, parsed from JSON. A [`ConfigSchema`] chains the versions with migration
+//! functions, detects the version field of a configuration and migrates older configurations up to the latest type.
+//! This lets a plugin be rolled out before the Envoy configuration it receives is updated.
+//!
+//! A [`ConfigFetcher`] polls a configuration from an HTTP endpoint instead, i.e. a control plane, and hands it to the
+//! root context whenever it changes.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    downcast_box::DowncastBox, hash::Xxh64, instant_now, json::Value, Backoff, Counter, Gauge,
+    HttpCall, HttpCallResponse, RootContext, Upstream,
+};
+
+/// Error produced while loading a versioned configuration
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// The configuration is missing or not valid JSON
+    Parse(String),
+    /// The version field is newer than any known version, or not one of the known versions
+    UnsupportedVersion(u32),
+    /// The version field is not a non-negative integer
+    InvalidVersion,
+    /// A field is not part of the schema
+    UnknownField(String),
+    /// A field is missing or invalid
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse(e) => write!(f, "invalid configuration: {e}"),
+            ConfigError::UnsupportedVersion(v) => {
+                write!(f, "unsupported configuration version {v}")
+            }
+            ConfigError::InvalidVersion => write!(f, "configuration version is not an integer"),
+            ConfigError::UnknownField(x) => write!(f, "unknown configuration field '{x}'"),
+            ConfigError::Invalid(e) => write!(f, "invalid configuration: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Rejects members of `value` that are not in `allowed`, for strict parsing. The version field must be listed.
+pub fn deny_unknown_fields(value: &Value, allowed: &[&str]) -> Result<(), ConfigError> {
+    let Some(members) = value.as_object() else {
+        return Err(ConfigError::Invalid(format!(
+            "expected object, found {}",
+            value.type_name()
+        )));
+    };
+    match members.iter().find(|(name, _)| !allowed.contains(&&**name)) {
+        Some((name, _)) => Err(ConfigError::UnknownField(name.clone())),
+        None => Ok(()),
+    }
+}
+
+type Loader<T> = Rc<dyn Fn(u32, &Value) -> Result<T, ConfigError>>;
+
+/// A loaded configuration
+#[derive(Clone, Debug, PartialEq)]
+pub struct Loaded<T> {
+    pub config: T,
+    /// Schema version of the received configuration
+    pub version: u32,
+    /// Whether the configuration was migrated from an older version
+    pub migrated: bool,
+}
+
+/// A chain of configuration versions, ending in `T`
+pub struct ConfigSchema<T> {
+    version_field: String,
+    default_version: Option<u32>,
+    gauge: Option<String>,
+    versions: Vec<u32>,
+    loader: Loader<T>,
+}
+
+impl<T: 'static> ConfigSchema<T> {
+    /// Starts a schema at its oldest `version`, parsed by `parse`
+    pub fn new(version: u32, parse: impl Fn(&Value) -> Result<T, ConfigError> + 'static) -> Self {
+        Self {
+            version_field: "version".to_string(),
+            default_version: None,
+            gauge: Some("config_schema_version".to_string()),
+            versions: vec![version],
+            loader: Rc::new(move |_, value| parse(value)),
+        }
+    }
+
+    /// Adds a newer `version` parsed by `parse`. Configurations of older versions are loaded as before, then passed to `migrate`.
+    /// Panics if `version` is not newer than the previous version.
+    pub fn then<U: 'static>(
+        self,
+        version: u32,
+        parse: impl Fn(&Value) -> Result<U, ConfigError> + 'static,
+        migrate: impl Fn(T) -> Result<U, ConfigError> + 'static,
+    ) -> ConfigSchema<U> {
+        assert!(
+            version > self.latest(),
+            "configuration versions must be increasing"
+        );
+        let previous = self.loader;
+        let mut versions = self.versions;
+        versions.push(version);
+        ConfigSchema {
+            version_field: self.version_field,
+            default_version: self.default_version,
+            gauge: self.gauge,
+            versions,
+            loader: Rc::new(move |detected, value| {
+                if detected == version {
+                    parse(value)
+                } else {
+                    migrate(previous(detected, value)?)
+                }
+            }),
+        }
+    }
+
+    /// Name of the top-level version field. Default is `version`.
+    pub fn version_field(mut self, name: impl Into<String>) -> Self {
+        self.version_field = name.into();
+        self
+    }
+
+    /// Version assumed when the version field is absent, i.e. configurations written before versioning.
+    /// By default, a missing version field is an error.
+    pub fn default_version(mut self, version: u32) -> Self {
+        self.default_version = Some(version);
+        self
+    }
+
+    /// Gauge recording the version of the received configuration. Default is `config_schema_version`.
+    pub fn gauge(mut self, name: impl Into<String>) -> Self {
+        self.gauge = Some(name.into());
+        self
+    }
+
+    /// Disables the version gauge
+    pub fn no_gauge(mut self) -> Self {
+        self.gauge = None;
+        self
+    }
+
+    /// Latest known version
+    pub fn latest(&self) -> u32 {
+        *self.versions.last().unwrap()
+    }
+
+    /// Detects the version of `value`
+    pub fn detect_version(&self, value: &Value) -> Result<u32, ConfigError> {
+        let version = match value.get(&self.version_field) {
+            None => self.default_version.ok_or(ConfigError::Invalid(format!(
+                "missing '{}'",
+                self.version_field
+            )))?,
+            Some(version) => version
+                .as_i64()
+                .and_then(|x| u32::try_from(x).ok())
+                .ok_or(ConfigError::InvalidVersion)?,
+        };
+        if !self.versions.contains(&version) {
+            return Err(ConfigError::UnsupportedVersion(version));
+        }
+        Ok(version)
+    }
+
+    /// Loads an already parsed configuration, migrating it to the latest version
+    pub fn load_value(&self, value: &Value) -> Result<Loaded<T>, ConfigError> {
+        let version = self.detect_version(value)?;
+        let config = (self.loader)(version, value)?;
+        let migrated = version != self.latest();
+        if migrated {
+            info!(
+                "migrated configuration from version {version} to {}",
+                self.latest()
+            );
+        }
+        Ok(Loaded {
+            config,
+            version,
+            migrated,
+        })
+    }
+
+    /// Loads a JSON configuration, i.e. from `on_configure`, migrating it to the latest version and recording its version in the gauge
+    pub fn load(&self, configuration: Option<&[u8]>) -> Result<Loaded<T>, ConfigError> {
+        let configuration = configuration.unwrap_or(b"{}");
+        let value = Value::parse(configuration).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let loaded = self.load_value(&value)?;
+        if let Some(gauge) = &self.gauge {
+            Gauge::define(gauge).record(loaded.version as u64);
+        }
+        Ok(loaded)
+    }
+}
+
+type OnChange<T> = Box<dyn Fn(&mut DowncastBox<dyn RootContext>, Rc<T>)>;
+type Parse<T> = Box<dyn Fn(&[u8]) -> Result<T, ConfigError>>;
+
+struct FetcherState<T> {
+    name: String,
+    upstream: Upstream<'static>,
+    authority: String,
+    path: String,
+    headers: Vec<(String, Vec<u8>)>,
+    interval: Duration,
+    timeout: Duration,
+    backoff: Backoff,
+    parse: Parse<T>,
+    on_change: Option<OnChange<T>>,
+    current: RefCell<Option<Rc<T>>>,
+    etag: RefCell<Option<Vec<u8>>>,
+    last_modified: RefCell<Option<Vec<u8>>>,
+    body_hash: Cell<Option<u64>>,
+    next_poll: Cell<Option<Instant>>,
+    in_flight: Cell<bool>,
+}
+
+/// Polls a configuration over HTTP, from [`RootContext::on_tick`], and delivers it to a root context callback only
+/// when it changed.
+///
+/// Requests are conditional: the `ETag` and `Last-Modified` of the last accepted response are sent back in
+/// `if-none-match` and `if-modified-since`, so the server may answer `304`. A `200` with the same body as the active
+/// configuration is not delivered either. Failed requests back off exponentially; a configuration that fails to parse
+/// is counted and skipped, keeping the active one. Cheap to clone: clones share their state.
+///
+/// `{name}_config_updates`, `{name}_config_fetch_failed` and `{name}_config_rejected` count the outcomes.
+///
+/// ```ignore
+/// let fetcher = ConfigFetcher::builder("policy", Upstream::envoy_upstream("control", "control"), "control", "/policy", |x| {
+///     schema.load(Some(x)).map(|x| x.config)
+/// })
+/// .on_change(|root: &mut Root, config| root.config = config)
+/// .build();
+/// // in on_tick
+/// fetcher.poll();
+/// ```
+pub struct ConfigFetcher<T>(Rc<FetcherState<T>>);
+
+impl<T> Clone for ConfigFetcher<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: 'static> ConfigFetcher<T> {
+    /// Fetches a GET of `path` on `upstream`, parsed by `parse`. `name` identifies the configuration in logs and metrics.
+    pub fn builder(
+        name: impl Into<String>,
+        upstream: Upstream<'static>,
+        authority: impl Into<String>,
+        path: impl Into<String>,
+        parse: impl Fn(&[u8]) -> Result<T, ConfigError> + 'static,
+    ) -> ConfigFetcherBuilder<T> {
+        ConfigFetcherBuilder(FetcherState {
+            name: name.into(),
+            upstream,
+            authority: authority.into(),
+            path: path.into(),
+            headers: vec![],
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(60)),
+            parse: Box::new(parse),
+            on_change: None,
+            current: RefCell::new(None),
+            etag: RefCell::new(None),
+            last_modified: RefCell::new(None),
+            body_hash: Cell::new(None),
+            next_poll: Cell::new(None),
+            in_flight: Cell::new(false),
+        })
+    }
+
+    /// The active configuration, once one was fetched
+    pub fn current(&self) -> Option<Rc<T>> {
+        self.0.current.borrow().clone()
+    }
+
+    /// Fetches the configuration if the poll interval elapsed and no backoff is pending. Call from `on_tick`.
+    pub fn poll(&self) {
+        let due = self.0.next_poll.get().is_none_or(|x| instant_now() >= x);
+        if due && self.0.backoff.is_ready() {
+            self.refresh();
+        }
+    }
+
+    /// Fetches the configuration now, unless a fetch is already in flight
+    pub fn refresh(&self) {
+        if self.0.in_flight.get() {
+            return;
+        }
+        let etag = self.0.etag.borrow().clone();
+        let last_modified = self.0.last_modified.borrow().clone();
+        let mut headers: Vec<(&str, &[u8])> = vec![
+            (":method", b"GET"),
+            (":path", self.0.path.as_bytes()),
+            (":authority", self.0.authority.as_bytes()),
+        ];
+        headers.extend(
+            self.0
+                .headers
+                .iter()
+                .map(|(name, value)| (&**name, &**value)),
+        );
+        if let Some(etag) = &etag {
+            headers.push(("if-none-match", etag));
+        }
+        if let Some(last_modified) = &last_modified {
+            headers.push(("if-modified-since", last_modified));
+        }
+        let fetcher = self.clone();
+        let result = HttpCall {
+            upstream: self.0.upstream.clone(),
+            headers,
+            trailers: vec![],
+            body: None,
+            timeout: Some(self.0.timeout),
+            inherit_request_policy: false,
+            retry: None,
+            callback: Some(Box::new(move |root, response| {
+                fetcher.on_response(root, response)
+            })),
+        }
+        .dispatch();
+        match result {
+            Ok(()) => self.0.in_flight.set(true),
+            Err(e) => self.failed(format_args!("dispatch failed: {e:?}")),
+        }
+    }
+
+    fn on_response(&self, root: &mut DowncastBox<dyn RootContext>, response: &HttpCallResponse) {
+        self.0.in_flight.set(false);
+        let status = response.header(":status").unwrap_or_default();
+        match &*status {
+            b"304" => return self.schedule(),
+            b"200" => (),
+            b"" => return self.failed(format_args!("no response")),
+            status => {
+                return self.failed(format_args!("status {}", String::from_utf8_lossy(status)))
+            }
+        }
+        let body = response.full_body().unwrap_or_default();
+        let hash = Xxh64::hash(0, &body);
+        if self.0.body_hash.get() == Some(hash) {
+            return self.schedule();
+        }
+        let config = match (self.0.parse)(&body) {
+            Ok(config) => Rc::new(config),
+            Err(e) => {
+                warn!("{} config rejected: {e}", self.0.name);
+                Counter::define(format!("{}_config_rejected", self.0.name)).increment(1);
+                return self.schedule();
+            }
+        };
+        *self.0.etag.borrow_mut() = response.header("etag");
+        *self.0.last_modified.borrow_mut() = response.header("last-modified");
+        self.0.body_hash.set(Some(hash));
+        *self.0.current.borrow_mut() = Some(config.clone());
+        Counter::define(format!("{}_config_updates", self.0.name)).increment(1);
+        self.schedule();
+        if let Some(on_change) = &self.0.on_change {
+            on_change(root, config);
+        }
+    }
+
+    fn schedule(&self) {
+        self.0.backoff.succeed();
+        self.0.next_poll.set(Some(instant_now() + self.0.interval));
+    }
+
+    fn failed(&self, reason: fmt::Arguments) {
+        let delay = self.0.backoff.fail();
+        warn!(
+            "{} config fetch failed: {reason}, retrying in {delay:?}",
+            self.0.name
+        );
+        Counter::define(format!("{}_config_fetch_failed", self.0.name)).increment(1);
+    }
+}
+
+impl<T> fmt::Debug for ConfigFetcher<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigFetcher")
+            .field("name", &self.0.name)
+            .field("path", &self.0.path)
+            .field("interval", &self.0.interval)
+            .field("loaded", &self.0.current.borrow().is_some())
+            .field("in_flight", &self.0.in_flight.get())
+            .finish()
+    }
+}
+
+/// Options of a [`ConfigFetcher`]
+pub struct ConfigFetcherBuilder<T>(FetcherState<T>);
+
+impl<T: 'static> ConfigFetcherBuilder<T> {
+    /// Time between fetches. Default is 30 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.0.interval = interval;
+        self
+    }
+
+    /// Timeout of fetches. Default is 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.0.timeout = timeout;
+        self
+    }
+
+    /// Delays after failed fetches. Default is 1 second doubling up to a minute.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.0.backoff = backoff;
+        self
+    }
+
+    /// Adds a header to fetches, i.e. `authorization`
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.0.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Called with each new configuration, on the root context that polled
+    pub fn on_change<R: RootContext + 'static>(
+        mut self,
+        callback: impl Fn(&mut R, Rc<T>) + 'static,
+    ) -> Self {
+        self.0.on_change = Some(Box::new(move |root, config| {
+            callback(
+                root.as_any_mut().downcast_mut().expect("invalid root type"),
+                config,
+            )
+        }));
+        self
+    }
+
+    pub fn build(self) -> ConfigFetcher<T> {
+        ConfigFetcher(Rc::new(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct V1 {
+        limit: i64,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct V2 {
+        limits: Vec<i64>,
+    }
+
+    fn parse_v1(value: &Value) -> Result<V1, ConfigError> {
+        deny_unknown_fields(value, &["version", "limit"])?;
+        let limit = value
+            .get("limit")
+            .and_then(Value::as_i64)
+            .ok_or_else(|| ConfigError::Invalid("limit".to_string()))?;
+        Ok(V1 { limit })
+    }
+
+    fn parse_v2(value: &Value) -> Result<V2, ConfigError> {
+        deny_unknown_fields(value, &["version", "limits"])?;
+        let limits = value
+            .get("limits")
+            .and_then(Value::as_array)
+            .ok_or_else(|| ConfigError::Invalid("limits".to_string()))?
+            .iter()
+            .filter_map(Value::as_i64)
+            .collect();
+        Ok(V2 { limits })
+    }
+
+    #[test]
+    fn test_migrate() {
+        let schema = ConfigSchema::new(1, parse_v1)
+            .then(2, parse_v2, |v1| {
+                Ok(V2 {
+                    limits: vec![v1.limit],
+                })
+            })
+            .default_version(1);
+        let value = |x: &str| Value::parse(x).unwrap();
+        assert_eq!(
+            schema.load_value(&value(r#"{"limit": 5}"#)).unwrap(),
+            Loaded {
+                config: V2 { limits: vec![5] },
+                version: 1,
+                migrated: true
+            }
+        );
+        assert_eq!(
+            schema
+                .load_value(&value(r#"{"version": 2, "limits": [1, 2]}"#))
+                .unwrap()
+                .config,
+            V2 { limits: vec![1, 2] }
+        );
+        assert_eq!(
+            schema.load_value(&value(r#"{"version": 3}"#)),
+            Err(ConfigError::UnsupportedVersion(3))
+        );
+        assert_eq!(
+            schema.load_value(&value(r#"{"version": 1, "limit": 1, "extra": 0}"#)),
+            Err(ConfigError::UnknownField("extra".to_string()))
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    mod fetcher {
+        use super::*;
+        use crate::{
+            dispatcher::{
+                self, proxy_on_context_create, proxy_on_http_call_response, proxy_on_tick,
+            },
+            hostcalls::{deserialize_map_bytes, BufferType, MapType},
+            native::{clear_host, set_host, Host},
+            testing::{metric, reset_host},
+            BaseContext, Context, Status,
+        };
+
+        type Headers = Vec<(String, Vec<u8>)>;
+
+        thread_local! {
+            static REQUESTS: RefCell<Vec<Headers>> = const { RefCell::new(vec![]) };
+            static DELIVERED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+        }
+
+        struct CallHost;
+
+        impl Host for CallHost {
+            unsafe fn proxy_http_call(
+                &self,
+                _upstream_data: *const u8,
+                _upstream_size: usize,
+                headers_data: *const u8,
+                headers_size: usize,
+                _body_data: *const u8,
+                _body_size: usize,
+                _trailers_data: *const u8,
+                _trailers_size: usize,
+                _timeout: u32,
+                return_token: *mut u32,
+            ) -> Status {
+                let headers =
+                    deserialize_map_bytes(std::slice::from_raw_parts(headers_data, headers_size))
+                        .unwrap();
+                REQUESTS.with_borrow_mut(|x| {
+                    x.push(headers);
+                    *return_token = x.len() as u32;
+                });
+                Status::Ok
+            }
+        }
+
+        struct Root(ConfigFetcher<String>);
+
+        impl Default for Root {
+            fn default() -> Self {
+                let fetcher = ConfigFetcher::builder(
+                    "policy",
+                    Upstream::envoy_upstream("control", "control"),
+                    "control",
+                    "/policy",
+                    |x| match std::str::from_utf8(x) {
+                        Ok(x) if !x.is_empty() => Ok(x.to_string()),
+                        _ => Err(ConfigError::Invalid("empty".to_string())),
+                    },
+                )
+                .interval(Duration::ZERO)
+                .on_change(|_: &mut Root, config: Rc<String>| {
+                    DELIVERED.with_borrow_mut(|x| x.push(config.to_string()))
+                })
+                .build();
+                Self(fetcher)
+            }
+        }
+
+        impl BaseContext for Root {}
+
+        impl RootContext for Root {
+            fn on_tick(&mut self) {
+                self.0.poll();
+            }
+
+            fn create_context(&mut self) -> Context {
+                unreachable!()
+            }
+        }
+
+        fn respond(status: &str, etag: Option<&str>, body: &str) {
+            crate::testing::host::with_host(|host| {
+                let headers = host.header_map(MapType::HttpCallResponseHeaders);
+                *headers = vec![(":status".to_string(), status.as_bytes().to_vec())];
+                if let Some(etag) = etag {
+                    headers.push(("etag".to_string(), etag.as_bytes().to_vec()));
+                }
+                host.buffers.insert(
+                    BufferType::HttpCallResponseBody as u32,
+                    body.as_bytes().to_vec(),
+                );
+            });
+            let token = REQUESTS.with_borrow(|x| x.len());
+            proxy_on_http_call_response(1, token, 2, body.len(), 0);
+        }
+
+        fn last_header(name: &str) -> Option<Vec<u8>> {
+            REQUESTS.with_borrow(|x| {
+                x.last()?
+                    .iter()
+                    .find(|(x, _)| x == name)
+                    .map(|(_, value)| value.clone())
+            })
+        }
+
+        #[test]
+        fn test_config_fetcher() {
+            reset_host();
+            set_host(CallHost);
+            dispatcher::reset_local(Root::default);
+            proxy_on_context_create(1, 0);
+
+            proxy_on_tick(1);
+            // in flight
+            proxy_on_tick(1);
+            assert_eq!(REQUESTS.with_borrow(|x| x.len()), 1);
+            respond("200", Some("\"v1\""), "a=1");
+            assert_eq!(DELIVERED.with_borrow(|x| x.clone()), ["a=1"]);
+
+            proxy_on_tick(1);
+            assert_eq!(last_header("if-none-match"), Some(b"\"v1\"".to_vec()));
+            respond("304", None, "");
+            proxy_on_tick(1);
+            respond("200", None, "a=1");
+            proxy_on_tick(1);
+            respond("200", None, "");
+            assert_eq!(metric("policy_config_rejected"), Some(1));
+            proxy_on_tick(1);
+            respond("200", None, "a=2");
+            assert_eq!(DELIVERED.with_borrow(|x| x.clone()), ["a=1", "a=2"]);
+            assert_eq!(last_header("if-none-match"), Some(b"\"v1\"".to_vec()));
+
+            proxy_on_tick(1);
+            assert_eq!(last_header("if-none-match"), None);
+            respond("503", None, "");
+            assert_eq!(metric("policy_config_fetch_failed"), Some(1));
+            let requests = REQUESTS.with_borrow(|x| x.len());
+            // backing off
+            proxy_on_tick(1);
+            assert_eq!(REQUESTS.with_borrow(|x| x.len()), requests);
+            assert_eq!(metric("policy_config_updates"), Some(2));
+            clear_host();
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{fmt, marker::PhantomData};
+
+use log::warn;
+
+use crate::{
+    json::Value,
+    property::envoy::{ListenerDirection, WasmAttributes},
+    BaseContext, Context, HttpContext, RootContext, StreamContext,
+};
+
+/// Kind of context a root context creates, i.e. whether the plugin is attached as an HTTP or a network filter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum ContextKind {
+    #[default]
+    Http,
+    Stream,
+}
+
+impl ContextKind {
+    /// Parses `http`, or `stream`, `network` and `tcp` for a network filter
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim() {
+            x if x.eq_ignore_ascii_case("http") => Some(ContextKind::Http),
+            x if x.eq_ignore_ascii_case("stream")
+                || x.eq_ignore_ascii_case("network")
+                || x.eq_ignore_ascii_case("tcp") =>
+            {
+                Some(ContextKind::Stream)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ContextKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextKind::Http => write!(f, "http"),
+            ContextKind::Stream => write!(f, "stream"),
+        }
+    }
+}
+
+/// Root context creating either `H` or `S` contexts, so the same module can be attached as an HTTP filter on some
+/// listeners and as a network filter on others.
+///
+/// The kind is read from the plugin configuration: a JSON object with a `context_kind` member, a JSON string,
+/// or the plain name, i.e. `{"context_kind": "stream"}` or `stream`. Without one, the kind is chosen per context
+/// from the listener direction, then falls back to the default kind.
+///
+/// ```ignore
+/// set_root_context_factory(|| {
+///     ConfigurableRoot::<MyHttpFilter, MyNetworkFilter>::new()
+///         .direction(ListenerDirection::Outbound, ContextKind::Stream)
+/// });
+/// ```
+pub struct ConfigurableRoot<H, S> {
+    field: String,
+    default_kind: ContextKind,
+    directions: Vec<(ListenerDirection, ContextKind)>,
+    configured: Option<ContextKind>,
+    configuration: Option<Vec<u8>>,
+    _contexts: PhantomData<fn() -> (H, S)>,
+}
+
+impl<H, S> Default for ConfigurableRoot<H, S> {
+    fn default() -> Self {
+        Self {
+            field: "context_kind".to_string(),
+            default_kind: ContextKind::Http,
+            directions: vec![],
+            configured: None,
+            configuration: None,
+            _contexts: PhantomData,
+        }
+    }
+}
+
+impl<H, S> ConfigurableRoot<H, S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name of the configuration member holding the kind. Default is `context_kind`.
+    pub fn field(mut self, field: impl Into<String>) -> Self {
+        self.field = field.into();
+        self
+    }
+
+    /// Kind used if neither the configuration nor the listener direction select one. Default is HTTP.
+    pub fn default_kind(mut self, kind: ContextKind) -> Self {
+        self.default_kind = kind;
+        self
+    }
+
+    /// Kind used on listeners with this direction, unless the configuration selects one
+    pub fn direction(mut self, direction: ListenerDirection, kind: ContextKind) -> Self {
+        self.directions.retain(|(x, _)| *x != direction);
+        self.directions.push((direction, kind));
+        self
+    }
+
+    /// Kind selected by the last plugin configuration, if any
+    pub fn configured_kind(&self) -> Option<ContextKind> {
+        self.configured
+    }
+
+    /// Last plugin configuration received
+    pub fn configuration(&self) -> Option<&[u8]> {
+        self.configuration.as_deref()
+    }
+
+    /// Kind of the next context, for the current listener
+    pub fn kind(&self) -> ContextKind {
+        if let Some(kind) = self.configured {
+            return kind;
+        }
+        if !self.directions.is_empty() {
+            if let Some(direction) = WasmAttributes::get().listener_direction() {
+                if let Some((_, kind)) = self.directions.iter().find(|(x, _)| *x == direction) {
+                    return *kind;
+                }
+            }
+        }
+        self.default_kind
+    }
+
+    /// `Ok(None)` if the configuration does not select a kind
+    fn parse_kind(&self, configuration: &[u8]) -> Result<Option<ContextKind>, String> {
+        let text = String::from_utf8_lossy(configuration);
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        let name = match Value::parse(configuration) {
+            Ok(value) if value.as_object().is_some() => match value.get(&self.field) {
+                None => return Ok(None),
+                Some(x) => x
+                    .as_str()
+                    .ok_or_else(|| format!("'{}' is not a string", self.field))?
+                    .to_string(),
+            },
+            Ok(Value::String(x)) => x,
+            Ok(_) => return Ok(None),
+            Err(_) => text.into_owned(),
+        };
+        ContextKind::from_name(&name)
+            .map(Some)
+            .ok_or_else(|| format!("unknown context kind '{}'", name.trim()))
+    }
+}
+
+impl<H, S> BaseContext for ConfigurableRoot<H, S> {}
+
+impl<H, S> RootContext for ConfigurableRoot<H, S>
+where
+    H: HttpContext + Default + 'static,
+    S: StreamContext + Default + 'static,
+{
+    fn on_configure(&mut self, configuration: Option<Vec<u8>>) -> bool {
+        let configured = match configuration.as_deref().map(|x| self.parse_kind(x)) {
+            Some(Err(e)) => {
+                warn!("invalid plugin configuration: {e}");
+                return false;
+            }
+            Some(Ok(kind)) => kind,
+            None => None,
+        };
+        self.configured = configured;
+        self.configuration = configuration;
+        true
+    }
+
+    fn create_context(&mut self) -> Context {
+        match self.kind() {
+            ContextKind::Http => Context::Http(Box::new(H::default())),
+            ContextKind::Stream => Context::Stream(Box::new(S::default())),
+        }
+    }
+}
+
+impl<H, S> fmt::Debug for ConfigurableRoot<H, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigurableRoot")
+            .field("field", &self.field)
+            .field("default_kind", &self.default_kind)
+            .field("directions", &self.directions)
+            .field("configured", &self.configured)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kind() {
+        let root = ConfigurableRoot::<(), ()>::new();
+        assert_eq!(
+            root.parse_kind(br#"{"context_kind": "network", "rules": []}"#),
+            Ok(Some(ContextKind::Stream))
+        );
+        assert_eq!(root.parse_kind(br#"{"rules": []}"#), Ok(None));
+        assert_eq!(root.parse_kind(br#""http""#), Ok(Some(ContextKind::Http)));
+        assert_eq!(root.parse_kind(b"tcp\n"), Ok(Some(ContextKind::Stream)));
+        assert_eq!(root.parse_kind(b""), Ok(None));
+        assert!(root.parse_kind(br#"{"context_kind": "udp"}"#).is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_create_context() {
+        use std::cell::RefCell;
+
+        use crate::{
+            dispatcher::{self, proxy_on_configure, proxy_on_context_create},
+            hostcalls::BufferType,
+            testing::{host::with_host, reset_host, set_property},
+        };
+
+        thread_local! {
+            static CREATED: RefCell<Vec<ContextKind>> = const { RefCell::new(vec![]) };
+        }
+
+        struct Filter;
+
+        impl Default for Filter {
+            fn default() -> Self {
+                CREATED.with_borrow_mut(|x| x.push(ContextKind::Http));
+                Filter
+            }
+        }
+
+        impl BaseContext for Filter {}
+
+        impl HttpContext for Filter {}
+
+        struct Connection;
+
+        impl Default for Connection {
+            fn default() -> Self {
+                CREATED.with_borrow_mut(|x| x.push(ContextKind::Stream));
+                Connection
+            }
+        }
+
+        impl BaseContext for Connection {}
+
+        impl StreamContext for Connection {}
+
+        reset_host();
+        dispatcher::reset_local(|| {
+            ConfigurableRoot::<Filter, Connection>::new()
+                .direction(ListenerDirection::Outbound, ContextKind::Stream)
+        });
+        proxy_on_context_create(1, 0);
+        proxy_on_context_create(2, 1);
+        set_property(["listener_direction"], 2i64.to_le_bytes());
+        proxy_on_context_create(3, 1);
+
+        let configuration = br#"{"context_kind": "http"}"#.to_vec();
+        let size = configuration.len();
+        with_host(|host| {
+            host.buffers
+                .insert(BufferType::PluginConfiguration as u32, configuration)
+        });
+        assert_ne!(proxy_on_configure(1, size), 0);
+        proxy_on_context_create(4, 1);
+        assert_eq!(
+            CREATED.with_borrow(|x| x.clone()),
+            vec![ContextKind::Http, ContextKind::Stream, ContextKind::Http]
+        );
+    }
+}
@@ -1,14 +1,17 @@
 use std::any::Any;
 
-use crate::{http::HttpContext, stream::StreamContext};
+use crate::{access_log::AccessLogContext, http::HttpContext, stream::StreamContext};
 
 pub enum Context {
     Http(Box<dyn HttpContext>),
     Stream(Box<dyn StreamContext>),
+    /// Receives the logs of an access log plugin, see [`crate::access_log`]
+    AccessLog(Box<dyn AccessLogContext>),
 }
 
 pub trait BaseContext {
-    /// Called for access log WASM plugins. Not well supported in this crate. Unclear what context this gets called on.
+    /// Called on HTTP and stream contexts once their request or connection completed, and on the root context of access
+    /// log plugins for each logged request or connection. See [`crate::access_log`].
     fn on_log(&mut self) {}
 
     /// Called when all processing is complete in the proxy for this context.
@@ -17,6 +20,10 @@ pub trait BaseContext {
     fn on_done(&mut self) -> bool {
         true
     }
+
+    /// Called right before the context is removed and dropped, with the context still set as the effective context.
+    /// Use it to cancel outstanding callouts, flush buffers or release shared data locks, which `Drop` can't do reliably.
+    fn on_delete(&mut self) {}
 }
 
 #[allow(unused_variables)]
@@ -36,7 +43,8 @@ pub trait RootContext: BaseContext + Any {
     /// Called every tick period as set by [`crate::time::set_tick_period`]
     fn on_tick(&mut self) {}
 
-    /// Called to initiate a new HTTP or Stream context.
+    /// Called to initiate a new HTTP or Stream context. Access log plugins are asked once, on the first log of the root
+    /// context, see [`crate::access_log`].
     fn create_context(&mut self) -> Context;
 }
 
@@ -0,0 +1,236 @@
+//! Per context accounting of hostcalls, bytes exchanged with the host and time spent in plugin callbacks,
+//! for capacity analysis of the filter overhead per route.
+//!
+//! Accounting is off by default. Once enabled with [`set_enabled`], read the figures of the active context with
+//! [`cost`], i.e. from `on_log`, and optionally attach them to the request with [`Cost::attach_header`] or
+//! [`Cost::attach_property`]. Only contexts created while enabled are accounted, and their figures are dropped
+//! when they are deleted.
+//!
+//! ```ignore
+//! fn on_http_response_headers(&mut self, headers: &ResponseHeaders) -> FilterHeadersStatus {
+//!     proxy_sdk::cost::cost().attach_header(headers, "x-wasm-cost");
+//!     FilterHeadersStatus::Continue
+//! }
+//! ```
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{dispatcher, instant_now, property, HttpHeaderControl};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Resources a context used so far
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Cost {
+    /// Callbacks the host made into the context, including call responses, deferred closures and tasks run in it
+    pub callbacks: u64,
+    /// Hostcalls made while the context was effective
+    pub hostcalls: u64,
+    /// Bytes copied from the host, i.e. bodies, headers and properties read
+    pub bytes_read: u64,
+    /// Bytes handed to the host, i.e. bodies and headers written
+    pub bytes_written: u64,
+    /// Time spent in those callbacks
+    pub time: Duration,
+}
+
+impl Cost {
+    /// Attaches the figures as a header, i.e. on the response for clients measuring the overhead
+    pub fn attach_header(&self, headers: &impl HttpHeaderControl, name: &str) {
+        headers.set(name, self.to_string());
+    }
+
+    /// Attaches the figures as a property, which Envoy stores as the filter state `wasm.{name}`,
+    /// i.e. for access logs with `%FILTER_STATE(wasm.{name}:PLAIN)%`
+    pub fn attach_property(&self, name: &str) {
+        property::set_property(name, self.to_string());
+    }
+}
+
+/// `callbacks=3;hostcalls=41;read=5120;written=64;time_us=180`
+impl fmt::Display for Cost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "callbacks={};hostcalls={};read={};written={};time_us={}",
+            self.callbacks,
+            self.hostcalls,
+            self.bytes_read,
+            self.bytes_written,
+            self.time.as_micros()
+        )
+    }
+}
+
+struct Costs {
+    generation: usize,
+    contexts: HashMap<u32, Cost>,
+}
+
+thread_local! {
+    static COSTS: RefCell<Costs> = RefCell::new(Costs {
+        generation: 0,
+        contexts: HashMap::new(),
+    });
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Figures of the active context
+pub fn cost() -> Cost {
+    cost_of(dispatcher::current_context_id()).unwrap_or_default()
+}
+
+/// Figures of a live context
+pub fn cost_of(context_id: u32) -> Option<Cost> {
+    COSTS.with_borrow_mut(|costs| current(costs).contexts.get(&context_id).copied())
+}
+
+/// Drops accounting of a [`crate::reset`] dispatcher
+fn current(costs: &mut Costs) -> &mut Costs {
+    let generation = dispatcher::generation();
+    if costs.generation != generation {
+        costs.generation = generation;
+        costs.contexts.clear();
+    }
+    costs
+}
+
+fn charge(context_id: u32, f: impl FnOnce(&mut Cost)) {
+    if !is_enabled() {
+        return;
+    }
+    COSTS.with_borrow_mut(|costs| {
+        if let Some(cost) = current(costs).contexts.get_mut(&context_id) {
+            f(cost)
+        }
+    });
+}
+
+fn charge_active(f: impl FnOnce(&mut Cost)) {
+    if is_enabled() {
+        charge(dispatcher::current_context_id(), f);
+    }
+}
+
+pub(crate) fn hostcall() {
+    charge_active(|x| x.hostcalls += 1);
+}
+
+pub(crate) fn read(bytes: usize) {
+    charge_active(|x| x.bytes_read += bytes as u64);
+}
+
+pub(crate) fn written(bytes: usize) {
+    charge_active(|x| x.bytes_written += bytes as u64);
+}
+
+/// Starts accounting a created context
+pub(crate) fn track(context_id: u32) {
+    if is_enabled() {
+        COSTS.with_borrow_mut(|costs| current(costs).contexts.insert(context_id, Cost::default()));
+    }
+}
+
+/// Start of a callback, if accounting
+pub(crate) fn start() -> Option<Instant> {
+    is_enabled().then(instant_now)
+}
+
+/// Charges a callback started at `start` to `context_id`
+pub(crate) fn callback(context_id: u32, start: Option<Instant>) {
+    let Some(start) = start else {
+        return;
+    };
+    let time = instant_now().saturating_duration_since(start);
+    charge(context_id, |x| {
+        x.callbacks += 1;
+        x.time += time;
+    });
+}
+
+pub(crate) fn remove(context_id: u32) {
+    COSTS.with_borrow_mut(|costs| current(costs).contexts.remove(&context_id));
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        dispatcher::{
+            proxy_on_context_create, proxy_on_delete, proxy_on_log, proxy_on_request_headers,
+        },
+        hostcalls::MapType,
+        testing::reset_host,
+        BaseContext, Context, FilterHeadersStatus, HttpContext, RequestHeaders, RootContext,
+    };
+
+    thread_local! {
+        static LOGGED: RefCell<Option<Cost>> = const { RefCell::new(None) };
+    }
+
+    #[derive(Default)]
+    struct Root;
+
+    struct Filter;
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn create_context(&mut self) -> Context {
+            Context::Http(Box::new(Filter))
+        }
+    }
+
+    impl BaseContext for Filter {
+        fn on_log(&mut self) {
+            LOGGED.with_borrow_mut(|x| *x = Some(cost()));
+            cost().attach_property("cost");
+        }
+    }
+
+    impl HttpContext for Filter {
+        fn on_http_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
+            headers.get(":path");
+            headers.set("x-a", "123");
+            FilterHeadersStatus::Continue
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        reset_host();
+        set_enabled(true);
+        crate::testing::host::with_host(|host| {
+            *host.header_map(MapType::HttpRequestHeaders) =
+                vec![(":path".to_string(), b"/abc".to_vec())]
+        });
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        proxy_on_context_create(2, 1);
+        proxy_on_request_headers(2, 1, 0);
+        proxy_on_log(2);
+        set_enabled(false);
+        let logged = LOGGED.with_borrow(|x| x.unwrap());
+        assert_eq!(logged.callbacks, 2);
+        assert!(logged.hostcalls >= 2);
+        assert_eq!(logged.bytes_read, 4);
+        assert_eq!(logged.bytes_written, 6);
+        assert!(cost_of(2).is_some());
+        proxy_on_delete(2);
+        assert_eq!(cost_of(2), None);
+        assert!(logged.to_string().starts_with("callbacks=2;"));
+    }
+}
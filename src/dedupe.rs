@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use crate::{hash::Xxh64, now, time::unix_millis, Counter, SharedData};
+
+/// Deduplicates findings across requests and workers in [`SharedData`], so a finding keyed by rule, matched value and route
+/// is emitted once per window. Occurrences within the window are counted and reported with the next emission.
+/// Decisions increment `{name}_findings_emitted` or `{name}_findings_suppressed`.
+///
+/// Matched values are only stored as XXH64 hashes.
+#[derive(Clone, Debug)]
+pub struct FindingDeduper {
+    name: String,
+    window: Duration,
+    max_retries: u32,
+}
+
+/// Result of [`FindingDeduper::observe`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dedup {
+    /// The finding should be emitted. `suppressed` occurrences were deduplicated in the previous window.
+    Emit { suppressed: u64 },
+    /// An identical finding was emitted within the window. This is its `count`th deduplicated occurrence.
+    Suppressed { count: u64 },
+}
+
+impl Dedup {
+    pub fn should_emit(&self) -> bool {
+        matches!(self, Dedup::Emit { .. })
+    }
+}
+
+impl FindingDeduper {
+    /// Creates a deduper named `name` with a 1 minute window
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            window: Duration::from_secs(60),
+            max_retries: 4,
+        }
+    }
+
+    /// How long identical findings are suppressed after an emission
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// CAS attempts before giving up and emitting. Default is 4.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries.max(1);
+        self
+    }
+
+    /// Digest of a finding: the rule, the XXH64 of the matched value and the route
+    pub fn digest(rule: &str, value: &[u8], route: &str) -> u64 {
+        let mut hasher = Xxh64::new(0);
+        hasher.update(rule.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&Xxh64::hash(0, value).to_le_bytes());
+        hasher.update(route.as_bytes());
+        hasher.digest()
+    }
+
+    /// Records a finding and decides whether to emit it
+    pub fn observe(&self, rule: &str, value: &[u8], route: &str) -> Dedup {
+        self.observe_digest(Self::digest(rule, value, route))
+    }
+
+    /// Records a finding by its [`FindingDeduper::digest`] and decides whether to emit it
+    pub fn observe_digest(&self, digest: u64) -> Dedup {
+        let dedup = self.update(digest);
+        let metric = if dedup.should_emit() {
+            "emitted"
+        } else {
+            "suppressed"
+        };
+        Counter::define(format!("{}_findings_{metric}", self.name)).increment(1);
+        dedup
+    }
+
+    fn update(&self, digest: u64) -> Dedup {
+        let data = SharedData::from_key(format!("{}_finding:{digest:016x}", self.name));
+        for _ in 0..self.max_retries {
+            let now = unix_millis(now());
+            let (value, cas) = data.get_with_cas();
+            let (dedup, entry) = match value.as_deref().and_then(decode_entry) {
+                Some((expiry, count)) if expiry > now => (
+                    Dedup::Suppressed { count: count + 1 },
+                    encode_entry(expiry, count + 1),
+                ),
+                entry => (
+                    Dedup::Emit {
+                        suppressed: entry.map_or(0, |(_, count)| count),
+                    },
+                    encode_entry(now.saturating_add(self.window.as_millis() as u64), 0),
+                ),
+            };
+            let updated = match cas {
+                Some(cas) => data.set_with_cas(entry, cas),
+                None => {
+                    data.set(entry);
+                    true
+                }
+            };
+            if updated {
+                return dedup;
+            }
+        }
+        // better a duplicate than a lost finding
+        Dedup::Emit { suppressed: 0 }
+    }
+}
+
+/// Expiry in unix milliseconds, then the count of suppressed occurrences
+fn encode_entry(expiry: u64, count: u64) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out[..8].copy_from_slice(&expiry.to_le_bytes());
+    out[8..].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+fn decode_entry(value: &[u8]) -> Option<(u64, u64)> {
+    let value: &[u8; 16] = value.try_into().ok()?;
+    Some((
+        u64::from_le_bytes(value[..8].try_into().unwrap()),
+        u64::from_le_bytes(value[8..].try_into().unwrap()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        let digest = FindingDeduper::digest("ssn", b"123-45-6789", "/users");
+        assert_eq!(
+            digest,
+            FindingDeduper::digest("ssn", b"123-45-6789", "/users")
+        );
+        assert_ne!(
+            digest,
+            FindingDeduper::digest("ssn", b"123-45-6789", "/orders")
+        );
+        assert_ne!(
+            digest,
+            FindingDeduper::digest("ssn", b"123-45-6780", "/users")
+        );
+        assert_eq!(decode_entry(&encode_entry(7, 3)), Some((7, 3)));
+        assert_eq!(decode_entry(&[0; 8]), None);
+    }
+}
@@ -3,6 +3,7 @@
 use log::{debug, error, warn};
 
 use crate::{
+    access_log::{AccessLogContext, LogEntry},
     check_concern,
     context::{Context, RootContext},
     downcast_box::DowncastBox,
@@ -10,25 +11,32 @@ use crate::{
     grpc_stream::{GrpcStreamClose, GrpcStreamHandle, GrpcStreamMessage},
     hostcalls::{self, BufferType},
     http::{
-        HttpContext, RequestBody, RequestHeaders, RequestTrailers, ResponseBody, ResponseHeaders,
-        ResponseTrailers,
+        HttpContext, RequestBody, RequestHeaders, RequestMetadata, RequestTrailers, ResponseBody,
+        ResponseHeaders, ResponseMetadata, ResponseTrailers,
     },
     http_call::HttpCallResponse,
+    phase::{HttpPhase, PhaseState},
     property::envoy::Attributes,
     queue::Queue,
     stream::{DownstreamData, StreamClose, StreamContext, UpstreamData},
-    CloseType, FilterDataStatus, FilterHeadersStatus, FilterStreamStatus, FilterTrailersStatus,
-    GrpcCode,
+    CloseType, FilterDataStatus, FilterHeadersStatus, FilterMetadataStatus, FilterStreamStatus,
+    FilterTrailersStatus, GrpcCode, Status,
 };
 use std::{
+    any::Any,
     cell::{Cell, RefCell, RefMut},
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Mutex,
     },
+    time::Instant,
 };
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::grpc_call::PolledGrpcResponse;
+
 #[cfg(feature = "stream-metadata")]
 pub use crate::grpc_stream::{GrpcStreamInitialMetadata, GrpcStreamTrailingMetadata};
 
@@ -42,6 +50,37 @@ pub(crate) fn reset() {
     *ROOT_INIT.lock().unwrap() = None;
 }
 
+/// Phase state of the active HTTP context
+pub(crate) fn http_phase() -> Option<PhaseState> {
+    dispatch(|d| d.http_phases.borrow().get(&d.active_id.get()).copied())
+}
+
+/// Marks the active HTTP context to skip its response callbacks
+pub(crate) fn skip_response() {
+    dispatch(|d| {
+        let id = d.active_id.get();
+        if let Some(state) = d.http_phases.borrow_mut().get_mut(&id) {
+            state.skip_response = true;
+        }
+    })
+}
+
+/// Whether the active context is a stream (L4) context, and if so, whether it is in `on_upstream_data`
+pub(crate) fn stream_state() -> Option<bool> {
+    dispatch(|d| {
+        let id = d.active_id.get();
+        d.stream_ids
+            .borrow()
+            .contains(&id)
+            .then(|| d.upstream_data_id.get() == Some(id))
+    })
+}
+
+/// Incremented by every [`crate::reset`], for thread local caches that must not outlive the dispatcher state
+pub(crate) fn generation() -> usize {
+    DISPATCHER_GEN.load(Ordering::Relaxed)
+}
+
 pub(crate) fn root_id() -> u32 {
     DISPATCHER.with(|x| x.active_root_id.get())
 }
@@ -60,9 +99,166 @@ where
     })
 }
 
+/// Runs `f` for a host event, then runs any closures deferred during it.
+fn dispatch_event<F, R>(f: F) -> R
+where
+    F: FnOnce(&Dispatcher) -> R,
+{
+    let out = dispatch(f);
+    run_deferred();
+    crate::executor::run_ready();
+    out
+}
+
+/// Like [`dispatch_event`], for a host event of the context `context_id`
+fn dispatch_context_event<F, R>(context_id: usize, f: F) -> R
+where
+    F: FnOnce(&Dispatcher) -> R,
+{
+    let start = crate::cost::start();
+    let out = dispatch(f);
+    crate::cost::callback(context_id as u32, start);
+    run_deferred();
+    crate::executor::run_ready();
+    out
+}
+
+/// Upper bound on deferred closures run after a single host event, in case deferred closures keep deferring more.
+/// Any remaining closures run after the next event.
+const MAX_DEFERRED_PER_EVENT: usize = 1024;
+
+fn run_deferred() -> usize {
+    for i in 0..MAX_DEFERRED_PER_EVENT {
+        let Some(deferred) = dispatch(|d| d.deferred.borrow_mut().pop_front()) else {
+            return i;
+        };
+        dispatch(|d| d.run_deferred(deferred));
+    }
+    MAX_DEFERRED_PER_EVENT
+}
+
+/// Runs closures deferred outside of a host callback, for embedders that drive the event loop themselves.
+/// Returns the number of closures run.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn dispatch_pending() -> usize {
+    run_deferred() + crate::executor::run_ready()
+}
+
+/// Schedules `callback` to run with the current root context right after the current host callback returns,
+/// once the SDK no longer holds any borrows of its contexts. The effective context is restored to the one active when `defer` was called,
+/// or the root context if that context no longer exists.
+pub fn defer<R: RootContext + 'static>(callback: impl FnOnce(&mut R) + 'static) {
+    defer_boxed(Box::new(move |root| {
+        callback(root.as_any_mut().downcast_mut().expect("invalid root type"))
+    }));
+}
+
+pub(crate) fn defer_boxed(callback: Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>)>) {
+    dispatch(|d| {
+        d.deferred.borrow_mut().push_back(Deferred {
+            context_id: d.active_id.get(),
+            root_context_id: d.active_root_id.get(),
+            callback,
+        })
+    });
+}
+
+/// Runs `f` in the effective context `context_id`, or its root context if that context no longer exists.
+/// `None` if the root context no longer exists either.
+pub(crate) fn in_task_context<T>(
+    context_id: u32,
+    root_context_id: u32,
+    f: impl FnOnce() -> T,
+) -> Option<T> {
+    if !dispatch(|d| d.context_roots.borrow().contains_key(&root_context_id)) {
+        return None;
+    }
+    let _ctx = EffectiveContext::enter(context_id, root_context_id, "task")
+        .or_else(|| EffectiveContext::enter(root_context_id, root_context_id, "task"))?;
+    Some(f())
+}
+
+/// Runs `f` with the active root context, unless the SDK is borrowing it
+pub(crate) fn with_active_root<T>(
+    f: impl FnOnce(&mut DowncastBox<dyn RootContext>) -> T,
+) -> Option<T> {
+    dispatch(|d| {
+        let mut roots = d.roots.try_borrow_mut().ok()?;
+        let root = roots.get_mut(&d.active_root_id.get())?;
+        Some(f(&mut root.data))
+    })
+}
+
+/// Runs `f` with the active HTTP or stream context. `None` in root contexts, or where the SDK borrows the context.
+pub(crate) fn with_active_context<T>(f: impl FnOnce(&mut dyn Any) -> T) -> Option<T> {
+    dispatch(|d| {
+        let id = d.active_id.get();
+        let mut http_streams = d.http_streams.try_borrow_mut().ok()?;
+        if let Some(http_stream) = http_streams.get_mut(&id) {
+            return Some(f(&mut *http_stream.data));
+        }
+        drop(http_streams);
+        let mut streams = d.streams.try_borrow_mut().ok()?;
+        let stream = streams.get_mut(&id)?;
+        Some(f(&mut *stream.data))
+    })
+}
+
+/// Queues work for a root context, signaling its wakeup queue if it has one
+pub(crate) fn spawn_boxed(
+    root_context_id: u32,
+    work: Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>)>,
+) {
+    let wakeup = dispatch(|d| {
+        let mut spawned = d.spawned.borrow_mut();
+        let spawned = spawned.entry(root_context_id).or_default();
+        spawned.work.push_back(work);
+        let wakeup = spawned.wakeup.filter(|_| !spawned.signaled);
+        spawned.signaled |= wakeup.is_some();
+        wakeup
+    });
+    if let Some(queue) = wakeup {
+        crate::log_concern("spawn-wakeup", hostcalls::enqueue_shared_queue(queue, []));
+    }
+}
+
+/// Number of spawned work items waiting for a root context
+pub(crate) fn spawned_count(root_context_id: u32) -> usize {
+    dispatch(|d| {
+        d.spawned
+            .borrow()
+            .get(&root_context_id)
+            .map_or(0, |x| x.work.len())
+    })
+}
+
+pub(crate) fn set_spawn_wakeup(root_context_id: u32, queue: u32) {
+    dispatch(|d| {
+        d.spawned
+            .borrow_mut()
+            .entry(root_context_id)
+            .or_default()
+            .wakeup = Some(queue)
+    });
+}
+
 static ROOT_INIT: Mutex<Option<Box<dyn Fn() -> DowncastBox<dyn RootContext> + Send + Sync>>> =
     Mutex::new(None);
 
+#[cfg(all(feature = "testing", not(target_arch = "wasm32")))]
+thread_local! {
+    /// Overrides [`ROOT_INIT`] on this thread, so tests with different root contexts can run in parallel
+    static LOCAL_ROOT_INIT: RefCell<Option<Box<dyn Fn() -> DowncastBox<dyn RootContext>>>> = RefCell::default();
+}
+
+/// Resets the dispatcher of this thread and sets a root context factory for it alone
+#[cfg(all(feature = "testing", not(target_arch = "wasm32")))]
+pub(crate) fn reset_local<R: RootContext + 'static>(root: impl Fn() -> R + 'static) {
+    dispatch(|d| d.reset());
+    LOCAL_ROOT_INIT
+        .with_borrow_mut(|init| *init = Some(Box::new(move || DowncastBox::new(Box::new(root())))));
+}
+
 struct HttpCallback {
     context_id: u32,
     root_context_id: u32,
@@ -105,6 +301,35 @@ struct GrpcStreamCallback {
     >,
 }
 
+#[derive(Default)]
+struct QueueCallbacks {
+    callbacks: Vec<Box<dyn FnMut(&mut DowncastBox<dyn RootContext>, Queue)>>,
+    /// HTTP or stream contexts subscribed via [`Queue::subscribe_context`]
+    contexts: Vec<u32>,
+    /// Set while callbacks are taken out for delivery
+    delivering: bool,
+    /// Set if root callbacks were unsubscribed during delivery
+    unsubscribed: bool,
+    /// Contexts unsubscribed during delivery
+    unsubscribed_contexts: Vec<u32>,
+}
+
+/// Work submitted through [`crate::RootHandle::spawn`] for a root context
+#[derive(Default)]
+struct Spawned {
+    work: VecDeque<Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>)>>,
+    /// Queue that wakes the root context when work is spawned
+    wakeup: Option<u32>,
+    /// Whether the wakeup queue was signaled since the work last ran
+    signaled: bool,
+}
+
+struct Deferred {
+    context_id: u32,
+    root_context_id: u32,
+    callback: Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>)>,
+}
+
 struct StreamInfo {
     parent_context_id: u32,
     data: Box<dyn StreamContext>,
@@ -119,16 +344,41 @@ struct RootInfo {
     data: DowncastBox<dyn RootContext>,
 }
 
+struct AccessLogInfo {
+    parent_context_id: u32,
+    data: Box<dyn AccessLogContext>,
+}
+
 #[derive(Default)]
 struct Dispatcher {
     roots: RefCell<HashMap<u32, RootInfo>>,
     streams: RefCell<HashMap<u32, StreamInfo>>,
     http_streams: RefCell<HashMap<u32, HttpStreamInfo>>,
+    /// Access log contexts created for a stream
+    access_logs: RefCell<HashMap<u32, AccessLogInfo>>,
+    /// Access log context of each root context that was logged to, `None` if it does not create one
+    root_access_logs: RefCell<HashMap<u32, Option<Box<dyn AccessLogContext>>>>,
     http_callbacks: RefCell<HashMap<u32, HttpCallback>>,
     grpc_callbacks: RefCell<HashMap<u32, GrpcCallback>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    polled_grpc: RefCell<HashMap<u32, Option<PolledGrpcResponse>>>,
     grpc_streams: RefCell<HashMap<u32, GrpcStreamCallback>>,
-    queue_callbacks:
-        RefCell<HashMap<u32, Box<dyn FnMut(&mut DowncastBox<dyn RootContext>, Queue)>>>,
+    http_phases: RefCell<HashMap<u32, PhaseState>>,
+    /// Ids of live stream (L4) contexts
+    stream_ids: RefCell<HashSet<u32>>,
+    /// Root context id of every live context, readable while the context maps are borrowed
+    context_roots: RefCell<HashMap<u32, u32>>,
+    /// Stream contexts whose last `on_downstream_data` stopped iteration, so the host redelivers the data
+    stopped_downstream: RefCell<HashSet<u32>>,
+    /// Stream contexts whose last `on_upstream_data` stopped iteration
+    stopped_upstream: RefCell<HashSet<u32>>,
+    /// Stream context currently in `on_upstream_data`
+    upstream_data_id: Cell<Option<u32>>,
+    /// Keyed by (root context id, queue id)
+    queue_callbacks: RefCell<HashMap<(u32, u32), QueueCallbacks>>,
+    /// Keyed by root context id
+    spawned: RefCell<HashMap<u32, Spawned>>,
+    deferred: RefCell<VecDeque<Deferred>>,
     active_id: Cell<u32>,
     active_root_id: Cell<u32>,
     generation: Cell<usize>,
@@ -139,10 +389,23 @@ impl Dispatcher {
         self.roots.borrow_mut().clear();
         self.streams.borrow_mut().clear();
         self.http_streams.borrow_mut().clear();
+        self.access_logs.borrow_mut().clear();
+        self.root_access_logs.borrow_mut().clear();
         self.http_callbacks.borrow_mut().clear();
         self.grpc_callbacks.borrow_mut().clear();
+        #[cfg(not(target_arch = "wasm32"))]
+        self.polled_grpc.borrow_mut().clear();
         self.grpc_streams.borrow_mut().clear();
+        self.http_phases.borrow_mut().clear();
+        self.stream_ids.borrow_mut().clear();
+        self.stopped_downstream.borrow_mut().clear();
+        self.stopped_upstream.borrow_mut().clear();
+        self.context_roots.borrow_mut().clear();
+        self.upstream_data_id.set(None);
         self.queue_callbacks.borrow_mut().clear();
+        self.spawned.borrow_mut().clear();
+        self.deferred.borrow_mut().clear();
+        crate::executor::reset();
         self.roots.borrow_mut().clear();
         self.active_id.set(0);
         self.active_root_id.set(0);
@@ -152,12 +415,19 @@ impl Dispatcher {
         roots: &'a mut RefMut<'_, HashMap<u32, RootInfo>>,
         root_context_id: u32,
     ) -> &'a mut DowncastBox<dyn RootContext> {
-        roots.entry(root_context_id).or_insert_with(|| RootInfo {
-            data: ROOT_INIT
-                .lock()
-                .unwrap()
-                .as_ref()
-                .expect("missing root_context_factory")(),
+        roots.entry(root_context_id).or_insert_with(|| {
+            #[cfg(all(feature = "testing", not(target_arch = "wasm32")))]
+            if let Some(data) = LOCAL_ROOT_INIT.with_borrow(|init| init.as_ref().map(|init| init()))
+            {
+                return RootInfo { data };
+            }
+            RootInfo {
+                data: ROOT_INIT
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .expect("missing root_context_factory")(),
+            }
         });
         &mut roots.get_mut(&root_context_id).unwrap().data
     }
@@ -200,6 +470,26 @@ pub(crate) fn register_grpc_callback(
     });
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn register_polled_grpc(token: u32) {
+    dispatch(|d| d.polled_grpc.borrow_mut().insert(token, None));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn take_polled_grpc(token: u32, remove: bool) -> Option<PolledGrpcResponse> {
+    dispatch(|d| {
+        let mut polled = d.polled_grpc.borrow_mut();
+        if remove {
+            return polled.remove(&token).flatten();
+        }
+        let response = polled.get_mut(&token)?.take();
+        if response.is_some() {
+            polled.remove(&token);
+        }
+        response
+    })
+}
+
 #[cfg(feature = "stream-metadata")]
 pub(crate) fn register_grpc_stream_initial_meta(
     token: u32,
@@ -333,30 +623,126 @@ pub(crate) fn register_queue_callback<R: RootContext + 'static>(
     mut callback: impl FnMut(&mut R, Queue) + 'static,
 ) {
     dispatch(|d| {
-        d.queue_callbacks.borrow_mut().insert(
-            token,
-            Box::new(move |root, queue| {
+        d.queue_callbacks
+            .borrow_mut()
+            .entry((d.active_root_id.get(), token))
+            .or_default()
+            .callbacks
+            .push(Box::new(move |root, queue| {
                 callback(
                     root.as_any_mut().downcast_mut().expect("invalid root type"),
                     queue,
                 )
-            }),
-        );
+            }));
+    })
+}
+
+pub(crate) fn register_queue_context(token: u32) -> bool {
+    dispatch(|d| {
+        let context_id = d.active_id.get();
+        if context_id == d.active_root_id.get() {
+            return false;
+        }
+        let mut queue_callbacks = d.queue_callbacks.borrow_mut();
+        let entry = queue_callbacks
+            .entry((d.active_root_id.get(), token))
+            .or_default();
+        if !entry.contexts.contains(&context_id) {
+            entry.contexts.push(context_id);
+        }
+        true
+    })
+}
+
+pub(crate) fn unregister_queue_callbacks(token: u32) {
+    dispatch(|d| {
+        let context_id = d.active_id.get();
+        let mut queue_callbacks = d.queue_callbacks.borrow_mut();
+        let key = (d.active_root_id.get(), token);
+        let Some(entry) = queue_callbacks.get_mut(&key) else {
+            return;
+        };
+        if context_id != d.active_root_id.get() {
+            entry.contexts.retain(|x| *x != context_id);
+            if entry.delivering {
+                entry.unsubscribed_contexts.push(context_id);
+            }
+        } else if entry.delivering {
+            entry.callbacks.clear();
+            entry.unsubscribed = true;
+        } else {
+            entry.callbacks.clear();
+        }
+        if !entry.delivering && entry.callbacks.is_empty() && entry.contexts.is_empty() {
+            queue_callbacks.remove(&key);
+        }
     })
 }
 
+/// Id of the context the current callback runs in
+pub fn current_context_id() -> u32 {
+    dispatch(|d| d.active_id.get())
+}
+
+/// Error of [`with_context`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContextError {
+    /// No live context of this VM has the id
+    Unknown(u32),
+    /// The host refused to switch to the context
+    Status(Status),
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContextError::Unknown(id) => write!(f, "unknown context {id}"),
+            ContextError::Status(status) => write!(f, "failed to enter context: {status:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ContextError {}
+
+/// Runs `f` with `context_id` as the effective context, restoring the current context afterwards, even if `f` panics.
+/// Hostcalls made by `f` apply to that context, i.e. to push data to a request waiting on a queue from a root context.
+///
+/// The id must be a live context of this VM, as returned by [`current_context_id`] within it.
+/// High-level APIs that check the HTTP phase check the phase of `context_id`.
+pub fn with_context<T>(context_id: u32, f: impl FnOnce() -> T) -> Result<T, ContextError> {
+    let Some(root_id) = dispatch(|d| d.context_roots.borrow().get(&context_id).copied()) else {
+        return Err(ContextError::Unknown(context_id));
+    };
+    let _ctx = EffectiveContext::try_enter(context_id, root_id, "with_context")
+        .map_err(ContextError::Status)?;
+    Ok(f())
+}
+
 struct EffectiveContext {
     name: &'static str,
+    id: u32,
     prior: u32,
     prior_root: u32,
+    /// Start of a callback run by the SDK, accounted to the context when dropped
+    start: Option<Instant>,
 }
 
 impl EffectiveContext {
     pub fn enter(id: u32, root_id: u32, name: &'static str) -> Option<Self> {
-        if let Err(e) = hostcalls::set_effective_context(id) {
-            debug!("failed to assume context {root_id}/{id} for {name}: {e:?}");
-            return None;
-        };
+        match Self::try_enter(id, root_id, name) {
+            Ok(mut ctx) => {
+                ctx.start = crate::cost::start();
+                Some(ctx)
+            }
+            Err(e) => {
+                debug!("failed to assume context {root_id}/{id} for {name}: {e:?}");
+                None
+            }
+        }
+    }
+
+    pub fn try_enter(id: u32, root_id: u32, name: &'static str) -> Result<Self, Status> {
+        hostcalls::set_effective_context(id)?;
         let (prior, prior_root) = dispatch(|d| {
             let prior = d.active_id.get();
             d.active_id.set(id);
@@ -364,16 +750,19 @@ impl EffectiveContext {
             d.active_root_id.set(root_id);
             (prior, prior_root)
         });
-        Some(Self {
+        Ok(Self {
             name,
+            id,
             prior,
             prior_root,
+            start: None,
         })
     }
 }
 
 impl Drop for EffectiveContext {
     fn drop(&mut self) {
+        crate::cost::callback(self.id, self.start);
         if let Err(e) = hostcalls::set_effective_context(self.prior) {
             debug!("failed to reset context for {}: {e:?}", self.name);
         };
@@ -389,6 +778,49 @@ impl Dispatcher {
         Self::default()
     }
 
+    /// Runs the work spawned for a root context so far. Work spawned meanwhile waits for the next run.
+    fn run_spawned(&self, root_context_id: u32) {
+        let work = match self.spawned.borrow_mut().get_mut(&root_context_id) {
+            Some(spawned) => {
+                spawned.signaled = false;
+                std::mem::take(&mut spawned.work)
+            }
+            None => return,
+        };
+        for work in work {
+            self.active_id.set(root_context_id);
+            self.active_root_id.set(root_context_id);
+            let mut roots = self.roots.borrow_mut();
+            let Some(root) = roots.get_mut(&root_context_id) else {
+                return;
+            };
+            work(&mut root.data);
+        }
+    }
+
+    fn run_deferred(&self, deferred: Deferred) {
+        let mut roots = self.roots.borrow_mut();
+        let Some(root) = roots.get_mut(&deferred.root_context_id) else {
+            debug!("dropping deferred callback for non-existing root context");
+            return;
+        };
+        let Some(_ctx) = EffectiveContext::enter(
+            deferred.context_id,
+            deferred.root_context_id,
+            "deferred callback",
+        )
+        .or_else(|| {
+            EffectiveContext::enter(
+                deferred.root_context_id,
+                deferred.root_context_id,
+                "deferred callback",
+            )
+        }) else {
+            return;
+        };
+        (deferred.callback)(&mut root.data);
+    }
+
     fn do_create_subcontext(&self, root_context_id: u32, context_id: u32) {
         let mut roots = self.roots.borrow_mut();
         let root = Self::root(&mut roots, root_context_id);
@@ -410,6 +842,7 @@ impl Dispatcher {
                 }
             }
             Context::Stream(context) => {
+                self.stream_ids.borrow_mut().insert(context_id);
                 if self
                     .streams
                     .borrow_mut()
@@ -425,6 +858,29 @@ impl Dispatcher {
                     warn!("reused context_id without proper cleanup");
                 }
             }
+            Context::AccessLog(context) => {
+                if self
+                    .access_logs
+                    .borrow_mut()
+                    .insert(
+                        context_id,
+                        AccessLogInfo {
+                            parent_context_id: root_context_id,
+                            data: context,
+                        },
+                    )
+                    .is_some()
+                {
+                    warn!("reused context_id without proper cleanup");
+                }
+            }
+        }
+    }
+
+    /// Warns of a filter callback for a context that is not a filter, unless it is an access log context
+    fn missing_filter(&self, context_id: u32, callback: &str) {
+        if !self.access_logs.borrow().contains_key(&context_id) {
+            warn!("no filter context found for {callback}: {context_id}");
         }
     }
 
@@ -432,8 +888,16 @@ impl Dispatcher {
         if parent_context_id == 0 {
             let mut roots = self.roots.borrow_mut();
             Self::root(&mut roots, context_id);
+            self.context_roots
+                .borrow_mut()
+                .insert(context_id, context_id);
+            crate::cost::track(context_id);
         } else if self.roots.borrow().contains_key(&parent_context_id) {
             self.do_create_subcontext(parent_context_id, context_id);
+            self.context_roots
+                .borrow_mut()
+                .insert(context_id, parent_context_id);
+            crate::cost::track(context_id);
         } else {
             warn!("attempted to create context {context_id} under unknown context {parent_context_id}");
         }
@@ -448,6 +912,10 @@ impl Dispatcher {
             self.active_id.set(context_id);
             self.active_root_id.set(stream.parent_context_id);
             stream.data.on_done()
+        } else if let Some(log) = self.access_logs.borrow_mut().get_mut(&context_id) {
+            self.active_id.set(context_id);
+            self.active_root_id.set(log.parent_context_id);
+            log.data.on_done()
         } else if self.roots.borrow().contains_key(&context_id) {
             self.active_id.set(context_id);
             self.active_root_id.set(context_id);
@@ -463,29 +931,81 @@ impl Dispatcher {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
             self.active_id.set(context_id);
             self.active_root_id.set(http_stream.parent_context_id);
+            self.enter_http_phase(context_id, HttpPhase::Log);
             http_stream.data.on_log();
         } else if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
             self.active_id.set(context_id);
             self.active_root_id.set(stream.parent_context_id);
             stream.data.on_log();
+        } else if let Some(log) = self.access_logs.borrow_mut().get_mut(&context_id) {
+            self.active_id.set(context_id);
+            self.active_root_id.set(log.parent_context_id);
+            log.data.on_log();
+            log.data.on_access_log(&LogEntry::get());
         } else if self.roots.borrow().contains_key(&context_id) {
             self.active_id.set(context_id);
             self.active_root_id.set(context_id);
             let mut roots = self.roots.borrow_mut();
-            Self::root(&mut roots, context_id).on_log();
+            let root = Self::root(&mut roots, context_id);
+            root.on_log();
+            let mut root_access_logs = self.root_access_logs.borrow_mut();
+            let log =
+                root_access_logs
+                    .entry(context_id)
+                    .or_insert_with(|| match root.create_context() {
+                        Context::AccessLog(context) => Some(context),
+                        _ => None,
+                    });
+            drop(roots);
+            if let Some(log) = log {
+                log.on_access_log(&LogEntry::get());
+            }
         } else {
             warn!("on_log called on unknown context: {context_id}");
         }
     }
 
     fn on_delete(&self, context_id: u32) {
+        if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
+            self.active_id.set(context_id);
+            self.active_root_id.set(http_stream.parent_context_id);
+            http_stream.data.on_delete();
+        } else if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
+            self.active_id.set(context_id);
+            self.active_root_id.set(stream.parent_context_id);
+            stream.data.on_delete();
+        } else if let Some(log) = self.access_logs.borrow_mut().get_mut(&context_id) {
+            self.active_id.set(context_id);
+            self.active_root_id.set(log.parent_context_id);
+            log.data.on_delete();
+        } else if self.roots.borrow().contains_key(&context_id) {
+            self.active_id.set(context_id);
+            self.active_root_id.set(context_id);
+            if let Some(Some(mut log)) = self.root_access_logs.borrow_mut().remove(&context_id) {
+                log.on_delete();
+            }
+            let mut roots = self.roots.borrow_mut();
+            Self::root(&mut roots, context_id).on_delete();
+        }
+        self.context_roots.borrow_mut().remove(&context_id);
+        crate::cost::remove(context_id);
+
         if self.http_streams.borrow_mut().remove(&context_id).is_some() {
+            self.http_phases.borrow_mut().remove(&context_id);
             return;
         }
         if self.streams.borrow_mut().remove(&context_id).is_some() {
+            self.stream_ids.borrow_mut().remove(&context_id);
+            self.stopped_downstream.borrow_mut().remove(&context_id);
+            self.stopped_upstream.borrow_mut().remove(&context_id);
+            return;
+        }
+        if self.access_logs.borrow_mut().remove(&context_id).is_some() {
             return;
         }
         if self.roots.borrow_mut().remove(&context_id).is_some() {
+            self.spawned.borrow_mut().remove(&context_id);
+            crate::executor::drop_root_tasks(context_id);
             return;
         }
         warn!("deleting unknown context_id {context_id}");
@@ -496,6 +1016,7 @@ impl Dispatcher {
             warn!("received on_vm_start for non-root-context: {context_id}");
             return true;
         }
+        crate::logger::sync_host_log_level(false);
         let Some(configuration) = check_concern(
             "vm-start-config",
             hostcalls::get_buffer(BufferType::VmConfiguration, 0, vm_configuration_size),
@@ -513,6 +1034,7 @@ impl Dispatcher {
             warn!("received on_configure for non-root-context: {context_id}");
             return true;
         }
+        crate::logger::sync_host_log_level(false);
         let Some(configuration) = check_concern(
             "configure-fetch",
             hostcalls::get_buffer(
@@ -534,6 +1056,8 @@ impl Dispatcher {
             warn!("received on_tick for non-root-context: {context_id}");
             return;
         }
+        crate::logger::sync_host_log_level(true);
+        self.run_spawned(context_id);
         self.active_id.set(context_id);
         self.active_root_id.set(context_id);
         let mut roots = self.roots.borrow_mut();
@@ -545,13 +1069,85 @@ impl Dispatcher {
             warn!("received on_queue_ready for non-root-context: {context_id}");
             return;
         }
-        if let Some(callback) = self.queue_callbacks.borrow_mut().get_mut(&queue_id) {
+        let wakeup = self
+            .spawned
+            .borrow()
+            .get(&context_id)
+            .and_then(|x| x.wakeup);
+        if wakeup == Some(queue_id) {
+            while let Ok(Some(_)) = hostcalls::dequeue_shared_queue(queue_id) {}
+            self.run_spawned(context_id);
+            return;
+        }
+        let key = (context_id, queue_id);
+        // callbacks are taken out during delivery, so they may (un)subscribe
+        let (mut callbacks, mut contexts) = match self.queue_callbacks.borrow_mut().get_mut(&key) {
+            Some(entry) if !entry.delivering => {
+                entry.delivering = true;
+                (
+                    std::mem::take(&mut entry.callbacks),
+                    std::mem::take(&mut entry.contexts),
+                )
+            }
+            _ => return,
+        };
+        self.active_id.set(context_id);
+        self.active_root_id.set(context_id);
+        for callback in &mut callbacks {
             let mut roots = self.roots.borrow_mut();
-            callback(
-                &mut roots.get_mut(&context_id).unwrap().data,
-                Queue(queue_id),
-            );
+            let Some(root) = roots.get_mut(&context_id) else {
+                break;
+            };
+            callback(&mut root.data, Queue(queue_id));
+        }
+        contexts.retain(|id| self.deliver_queue_to_context(*id, context_id, Queue(queue_id)));
+
+        let mut queue_callbacks = self.queue_callbacks.borrow_mut();
+        let Some(entry) = queue_callbacks.get_mut(&key) else {
+            return;
+        };
+        if !entry.unsubscribed {
+            callbacks.append(&mut entry.callbacks);
+            entry.callbacks = callbacks;
+        }
+        // contexts unsubscribed during delivery were removed from neither list, so drop them here
+        let unsubscribed_contexts = std::mem::take(&mut entry.unsubscribed_contexts);
+        contexts.retain(|x| !unsubscribed_contexts.contains(x));
+        for id in entry.contexts.drain(..) {
+            if !contexts.contains(&id) {
+                contexts.push(id);
+            }
+        }
+        entry.contexts = contexts;
+        entry.delivering = false;
+        entry.unsubscribed = false;
+        if entry.callbacks.is_empty() && entry.contexts.is_empty() {
+            queue_callbacks.remove(&key);
+        }
+    }
+
+    /// Calls `on_queue_ready` on a subscribed HTTP or stream context. Returns `false` if the context no longer exists.
+    fn deliver_queue_to_context(
+        &self,
+        context_id: u32,
+        root_context_id: u32,
+        queue: Queue,
+    ) -> bool {
+        let exists = self.http_streams.borrow().contains_key(&context_id)
+            || self.streams.borrow().contains_key(&context_id);
+        if !exists {
+            return false;
+        }
+        let Some(_ctx) = EffectiveContext::enter(context_id, root_context_id, "queue delivery")
+        else {
+            return true;
+        };
+        if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
+            http_stream.data.on_queue_ready(queue);
+        } else if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
+            stream.data.on_queue_ready(queue);
         }
+        true
     }
 
     fn on_new_connection(&self, context_id: u32) -> FilterStreamStatus {
@@ -561,9 +1157,7 @@ impl Dispatcher {
         } else {
             // self.do_create_subcontext(context_id);
             // let Some(context) = self.streams.get_mut(&context_id) else {
-            warn!(
-                "no http context found for context (and was not implicitly created): {context_id}"
-            );
+            self.missing_filter(context_id, "on_new_connection");
             return FilterStreamStatus::Continue;
             // };
             // context
@@ -585,11 +1179,15 @@ impl Dispatcher {
         };
         self.active_id.set(context_id);
         self.active_root_id.set(stream.parent_context_id);
-        stream.data.on_downstream_data(&DownstreamData {
+        let redelivered = self.stopped_downstream.borrow().contains(&context_id);
+        let status = stream.data.on_downstream_data(&DownstreamData {
             data_size,
             end_of_stream,
+            redelivered,
             attributes: Attributes::get(),
-        })
+        });
+        Self::track_stopped(&self.stopped_downstream, context_id, status);
+        status
     }
 
     fn on_downstream_close(&self, context_id: u32, close_type: CloseType) {
@@ -617,11 +1215,25 @@ impl Dispatcher {
         };
         self.active_id.set(context_id);
         self.active_root_id.set(stream.parent_context_id);
-        stream.data.on_upstream_data(&UpstreamData {
+        self.upstream_data_id.set(Some(context_id));
+        let redelivered = self.stopped_upstream.borrow().contains(&context_id);
+        let status = stream.data.on_upstream_data(&UpstreamData {
             data_size,
             end_of_stream,
+            redelivered,
             attributes: Attributes::get(),
-        })
+        });
+        self.upstream_data_id.set(None);
+        Self::track_stopped(&self.stopped_upstream, context_id, status);
+        status
+    }
+
+    fn track_stopped(stopped: &RefCell<HashSet<u32>>, context_id: u32, status: FilterStreamStatus) {
+        if status == FilterStreamStatus::StopIteration {
+            stopped.borrow_mut().insert(context_id);
+        } else {
+            stopped.borrow_mut().remove(&context_id);
+        }
     }
 
     fn on_upstream_close(&self, context_id: u32, close_type: CloseType) {
@@ -637,6 +1249,46 @@ impl Dispatcher {
         })
     }
 
+    fn enter_http_phase(&self, context_id: u32, phase: HttpPhase) {
+        self.http_phases
+            .borrow_mut()
+            .entry(context_id)
+            .and_modify(|state| state.phase = phase)
+            .or_insert_with(|| PhaseState::new(phase));
+    }
+
+    fn enter_http_callback(
+        &self,
+        context_id: u32,
+        phase: HttpPhase,
+        end_of_stream: bool,
+        body_size: usize,
+    ) {
+        self.enter_http_phase(context_id, phase);
+        if let Some(state) = self.http_phases.borrow_mut().get_mut(&context_id) {
+            state.received(end_of_stream, body_size);
+        }
+    }
+
+    /// Whether the response callbacks of the context are skipped. Marks them continued if so.
+    fn response_skipped(&self, context_id: u32) -> bool {
+        let skipped = self
+            .http_phases
+            .borrow()
+            .get(&context_id)
+            .is_some_and(|x| x.skip_response);
+        if skipped {
+            self.http_phase_continued(context_id, true);
+        }
+        skipped
+    }
+
+    fn http_phase_continued(&self, context_id: u32, continued: bool) {
+        if let Some(state) = self.http_phases.borrow_mut().get_mut(&context_id) {
+            state.continued(continued);
+        }
+    }
+
     fn on_http_request_headers(
         &self,
         context_id: u32,
@@ -649,18 +1301,27 @@ impl Dispatcher {
         } else {
             // self.do_create_subcontext(context_id);
             // let Some(context) = self.http_streams.get_mut(&context_id) else {
-            warn!("no http context found for on_http_request_headers (and was not implicitly created): {context_id}");
+            self.missing_filter(context_id, "on_http_request_headers");
             return FilterHeadersStatus::Continue;
             // };
             // context
         };
         self.active_id.set(context_id);
         self.active_root_id.set(context.parent_context_id);
-        context.data.on_http_request_headers(&RequestHeaders {
+        self.enter_http_callback(context_id, HttpPhase::RequestHeaders, end_of_stream, 0);
+        let status = context.data.on_http_request_headers(&RequestHeaders {
             header_count,
             end_of_stream,
             attributes: Attributes::get(),
-        })
+        });
+        self.http_phase_continued(
+            context_id,
+            matches!(
+                status,
+                FilterHeadersStatus::Continue | FilterHeadersStatus::ContinueAndEndStream
+            ),
+        );
+        status
     }
 
     fn on_http_request_body(
@@ -671,16 +1332,23 @@ impl Dispatcher {
     ) -> FilterDataStatus {
         let mut http_streams = self.http_streams.borrow_mut();
         let Some(context) = http_streams.get_mut(&context_id) else {
-            warn!("no http context found for on_http_request_body: {context_id}");
+            self.missing_filter(context_id, "on_http_request_body");
             return FilterDataStatus::Continue;
         };
         self.active_id.set(context_id);
         self.active_root_id.set(context.parent_context_id);
-        context.data.on_http_request_body(&RequestBody {
+        self.enter_http_callback(context_id, HttpPhase::RequestBody, end_of_stream, body_size);
+        if crate::memory::is_shedding() {
+            self.http_phase_continued(context_id, true);
+            return FilterDataStatus::Continue;
+        }
+        let status = context.data.on_http_request_body(&RequestBody {
             body_size,
             end_of_stream,
             attributes: Attributes::get(),
-        })
+        });
+        self.http_phase_continued(context_id, status == FilterDataStatus::Continue);
+        status
     }
 
     fn on_http_request_trailers(
@@ -690,14 +1358,35 @@ impl Dispatcher {
     ) -> FilterTrailersStatus {
         let mut http_streams = self.http_streams.borrow_mut();
         let Some(context) = http_streams.get_mut(&context_id) else {
-            warn!("no http context found for on_http_request_trailers: {context_id}");
+            self.missing_filter(context_id, "on_http_request_trailers");
             return FilterTrailersStatus::Continue;
         };
         self.active_id.set(context_id);
         self.active_root_id.set(context.parent_context_id);
-        context.data.on_http_request_trailers(&RequestTrailers {
+        self.enter_http_callback(context_id, HttpPhase::RequestTrailers, true, 0);
+        let status = context.data.on_http_request_trailers(&RequestTrailers {
             trailer_count,
             attributes: Attributes::get(),
+        });
+        self.http_phase_continued(context_id, status == FilterTrailersStatus::Continue);
+        status
+    }
+
+    fn on_http_request_metadata(
+        &self,
+        context_id: u32,
+        element_count: usize,
+    ) -> FilterMetadataStatus {
+        let mut http_streams = self.http_streams.borrow_mut();
+        let Some(context) = http_streams.get_mut(&context_id) else {
+            self.missing_filter(context_id, "on_http_request_metadata");
+            return FilterMetadataStatus::Continue;
+        };
+        self.active_id.set(context_id);
+        self.active_root_id.set(context.parent_context_id);
+        context.data.on_http_request_metadata(&RequestMetadata {
+            element_count,
+            attributes: Attributes::get(),
         })
     }
 
@@ -709,16 +1398,28 @@ impl Dispatcher {
     ) -> FilterHeadersStatus {
         let mut http_streams = self.http_streams.borrow_mut();
         let Some(context) = http_streams.get_mut(&context_id) else {
-            warn!("no http context found for on_http_response_headers: {context_id}");
+            self.missing_filter(context_id, "on_http_response_headers");
             return FilterHeadersStatus::Continue;
         };
         self.active_id.set(context_id);
         self.active_root_id.set(context.parent_context_id);
-        context.data.on_http_response_headers(&ResponseHeaders {
+        self.enter_http_callback(context_id, HttpPhase::ResponseHeaders, end_of_stream, 0);
+        if self.response_skipped(context_id) {
+            return FilterHeadersStatus::Continue;
+        }
+        let status = context.data.on_http_response_headers(&ResponseHeaders {
             header_count,
             end_of_stream,
             attributes: Attributes::get(),
-        })
+        });
+        self.http_phase_continued(
+            context_id,
+            matches!(
+                status,
+                FilterHeadersStatus::Continue | FilterHeadersStatus::ContinueAndEndStream
+            ),
+        );
+        status
     }
 
     fn on_http_response_body(
@@ -729,16 +1430,31 @@ impl Dispatcher {
     ) -> FilterDataStatus {
         let mut http_streams = self.http_streams.borrow_mut();
         let Some(context) = http_streams.get_mut(&context_id) else {
-            warn!("no http context found for on_http_response_body: {context_id}");
+            self.missing_filter(context_id, "on_http_response_body");
             return FilterDataStatus::Continue;
         };
         self.active_id.set(context_id);
         self.active_root_id.set(context.parent_context_id);
-        context.data.on_http_response_body(&ResponseBody {
+        self.enter_http_callback(
+            context_id,
+            HttpPhase::ResponseBody,
+            end_of_stream,
+            body_size,
+        );
+        if self.response_skipped(context_id) {
+            return FilterDataStatus::Continue;
+        }
+        if crate::memory::is_shedding() {
+            self.http_phase_continued(context_id, true);
+            return FilterDataStatus::Continue;
+        }
+        let status = context.data.on_http_response_body(&ResponseBody {
             body_size,
             end_of_stream,
             attributes: Attributes::get(),
-        })
+        });
+        self.http_phase_continued(context_id, status == FilterDataStatus::Continue);
+        status
     }
 
     fn on_http_response_trailers(
@@ -748,14 +1464,46 @@ impl Dispatcher {
     ) -> FilterTrailersStatus {
         let mut http_streams = self.http_streams.borrow_mut();
         let Some(context) = http_streams.get_mut(&context_id) else {
-            warn!("no http context found for on_http_response_trailers: {context_id}");
+            self.missing_filter(context_id, "on_http_response_trailers");
             return FilterTrailersStatus::Continue;
         };
         self.active_id.set(context_id);
         self.active_root_id.set(context.parent_context_id);
-        context.data.on_http_response_trailers(&ResponseTrailers {
+        self.enter_http_callback(context_id, HttpPhase::ResponseTrailers, true, 0);
+        if self.response_skipped(context_id) {
+            return FilterTrailersStatus::Continue;
+        }
+        let status = context.data.on_http_response_trailers(&ResponseTrailers {
             trailer_count,
             attributes: Attributes::get(),
+        });
+        self.http_phase_continued(context_id, status == FilterTrailersStatus::Continue);
+        status
+    }
+
+    fn on_http_response_metadata(
+        &self,
+        context_id: u32,
+        element_count: usize,
+    ) -> FilterMetadataStatus {
+        let mut http_streams = self.http_streams.borrow_mut();
+        let Some(context) = http_streams.get_mut(&context_id) else {
+            self.missing_filter(context_id, "on_http_response_metadata");
+            return FilterMetadataStatus::Continue;
+        };
+        self.active_id.set(context_id);
+        self.active_root_id.set(context.parent_context_id);
+        let skipped = self
+            .http_phases
+            .borrow()
+            .get(&context_id)
+            .is_some_and(|x| x.skip_response);
+        if skipped {
+            return FilterMetadataStatus::Continue;
+        }
+        context.data.on_http_response_metadata(&ResponseMetadata {
+            element_count,
+            attributes: Attributes::get(),
         })
     }
 
@@ -819,7 +1567,28 @@ impl Dispatcher {
         );
     }
 
+    /// Stores the response of a polled grpc call, returning `false` if `token_id` isn't polled
+    #[cfg(not(target_arch = "wasm32"))]
+    fn complete_polled_grpc(
+        &self,
+        token_id: u32,
+        response: impl FnOnce() -> GrpcCallResponse,
+    ) -> bool {
+        let mut polled = self.polled_grpc.borrow_mut();
+        let Some(slot) = polled.get_mut(&token_id) else {
+            return false;
+        };
+        *slot = Some(PolledGrpcResponse::read(&response()));
+        true
+    }
+
     fn on_grpc_receive(&self, token_id: u32, response_size: usize) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.complete_polled_grpc(token_id, || {
+            GrpcCallResponse::new(token_id, GrpcCode::Ok, None, response_size)
+        }) {
+            return;
+        }
         if let Some(callback) = self.grpc_callbacks.borrow_mut().remove(&token_id) {
             let mut roots = self.roots.borrow_mut();
             let Some(root) = roots.get_mut(&callback.root_context_id) else {
@@ -895,6 +1664,15 @@ impl Dispatcher {
     }
 
     fn on_grpc_close(&self, token_id: u32, status_code: u32) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.complete_polled_grpc(token_id, || {
+            let (status, message) =
+                check_concern("grpc-call-close-status", hostcalls::get_grpc_status())
+                    .unwrap_or((status_code, None));
+            GrpcCallResponse::new(token_id, status.into(), message, 0)
+        }) {
+            return;
+        }
         if let Some(callback) = self.grpc_callbacks.borrow_mut().remove(&token_id) {
             let mut roots = self.roots.borrow_mut();
             let Some(root) = roots.get_mut(&callback.root_context_id) else {
@@ -955,47 +1733,55 @@ impl Dispatcher {
 
 #[no_mangle]
 pub extern "C" fn proxy_on_context_create(context_id: usize, root_context_id: usize) {
-    dispatch(|d| d.on_create_context(context_id as u32, root_context_id as u32))
+    dispatch_context_event(context_id, |d| {
+        d.on_create_context(context_id as u32, root_context_id as u32)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_done(context_id: usize) -> usize {
-    dispatch(|d| d.on_done(context_id as u32)) as usize
+    dispatch_context_event(context_id, |d| d.on_done(context_id as u32)) as usize
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_log(context_id: usize) {
-    dispatch(|d| d.on_log(context_id as u32))
+    dispatch_context_event(context_id, |d| d.on_log(context_id as u32))
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_delete(context_id: usize) {
-    dispatch(|d| d.on_delete(context_id as u32))
+    dispatch_context_event(context_id, |d| d.on_delete(context_id as u32))
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_vm_start(context_id: usize, vm_configuration_size: usize) -> usize {
-    dispatch(|d| d.on_vm_start(context_id as u32, vm_configuration_size)) as usize
+    dispatch_context_event(context_id, |d| {
+        d.on_vm_start(context_id as u32, vm_configuration_size)
+    }) as usize
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_configure(context_id: usize, plugin_configuration_size: usize) -> usize {
-    dispatch(|d| d.on_configure(context_id as u32, plugin_configuration_size)) as usize
+    dispatch_context_event(context_id, |d| {
+        d.on_configure(context_id as u32, plugin_configuration_size)
+    }) as usize
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_tick(context_id: usize) {
-    dispatch(|d| d.on_tick(context_id as u32))
+    dispatch_context_event(context_id, |d| d.on_tick(context_id as u32))
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_queue_ready(context_id: usize, queue_id: usize) {
-    dispatch(|d| d.on_queue_ready(context_id as u32, queue_id as u32))
+    dispatch_context_event(context_id, |d| {
+        d.on_queue_ready(context_id as u32, queue_id as u32)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_new_connection(context_id: usize) -> FilterStreamStatus {
-    dispatch(|d| d.on_new_connection(context_id as u32))
+    dispatch_context_event(context_id, |d| d.on_new_connection(context_id as u32))
 }
 
 #[no_mangle]
@@ -1004,12 +1790,16 @@ pub extern "C" fn proxy_on_downstream_data(
     data_size: usize,
     end_of_stream: usize,
 ) -> FilterStreamStatus {
-    dispatch(|d| d.on_downstream_data(context_id as u32, data_size, end_of_stream != 0))
+    dispatch_context_event(context_id, |d| {
+        d.on_downstream_data(context_id as u32, data_size, end_of_stream != 0)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_downstream_connection_close(context_id: usize, close_type: CloseType) {
-    dispatch(|d| d.on_downstream_close(context_id as u32, close_type))
+    dispatch_context_event(context_id, |d| {
+        d.on_downstream_close(context_id as u32, close_type)
+    })
 }
 
 #[no_mangle]
@@ -1018,12 +1808,16 @@ pub extern "C" fn proxy_on_upstream_data(
     data_size: usize,
     end_of_stream: usize,
 ) -> FilterStreamStatus {
-    dispatch(|d| d.on_upstream_data(context_id as u32, data_size, end_of_stream != 0))
+    dispatch_context_event(context_id, |d| {
+        d.on_upstream_data(context_id as u32, data_size, end_of_stream != 0)
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_upstream_connection_close(context_id: usize, close_type: CloseType) {
-    dispatch(|d| d.on_upstream_close(context_id as u32, close_type))
+    dispatch_context_event(context_id, |d| {
+        d.on_upstream_close(context_id as u32, close_type)
+    })
 }
 
 #[no_mangle]
@@ -1032,7 +1826,9 @@ pub extern "C" fn proxy_on_request_headers(
     num_headers: usize,
     end_of_stream: usize,
 ) -> FilterHeadersStatus {
-    dispatch(|d| d.on_http_request_headers(context_id as u32, num_headers, end_of_stream != 0))
+    dispatch_context_event(context_id, |d| {
+        d.on_http_request_headers(context_id as u32, num_headers, end_of_stream != 0)
+    })
 }
 
 #[no_mangle]
@@ -1041,7 +1837,9 @@ pub extern "C" fn proxy_on_request_body(
     body_size: usize,
     end_of_stream: usize,
 ) -> FilterDataStatus {
-    dispatch(|d| d.on_http_request_body(context_id as u32, body_size, end_of_stream != 0))
+    dispatch_context_event(context_id, |d| {
+        d.on_http_request_body(context_id as u32, body_size, end_of_stream != 0)
+    })
 }
 
 #[no_mangle]
@@ -1049,7 +1847,19 @@ pub extern "C" fn proxy_on_request_trailers(
     context_id: usize,
     num_trailers: usize,
 ) -> FilterTrailersStatus {
-    dispatch(|d| d.on_http_request_trailers(context_id as u32, num_trailers))
+    dispatch_context_event(context_id, |d| {
+        d.on_http_request_trailers(context_id as u32, num_trailers)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_on_request_metadata(
+    context_id: usize,
+    num_elements: usize,
+) -> FilterMetadataStatus {
+    dispatch_context_event(context_id, |d| {
+        d.on_http_request_metadata(context_id as u32, num_elements)
+    })
 }
 
 #[no_mangle]
@@ -1058,7 +1868,9 @@ pub extern "C" fn proxy_on_response_headers(
     num_headers: usize,
     end_of_stream: usize,
 ) -> FilterHeadersStatus {
-    dispatch(|d| d.on_http_response_headers(context_id as u32, num_headers, end_of_stream != 0))
+    dispatch_context_event(context_id, |d| {
+        d.on_http_response_headers(context_id as u32, num_headers, end_of_stream != 0)
+    })
 }
 
 #[no_mangle]
@@ -1067,7 +1879,9 @@ pub extern "C" fn proxy_on_response_body(
     body_size: usize,
     end_of_stream: usize,
 ) -> FilterDataStatus {
-    dispatch(|d| d.on_http_response_body(context_id as u32, body_size, end_of_stream != 0))
+    dispatch_context_event(context_id, |d| {
+        d.on_http_response_body(context_id as u32, body_size, end_of_stream != 0)
+    })
 }
 
 #[no_mangle]
@@ -1075,7 +1889,19 @@ pub extern "C" fn proxy_on_response_trailers(
     context_id: usize,
     num_trailers: usize,
 ) -> FilterTrailersStatus {
-    dispatch(|d| d.on_http_response_trailers(context_id as u32, num_trailers))
+    dispatch_context_event(context_id, |d| {
+        d.on_http_response_trailers(context_id as u32, num_trailers)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_on_response_metadata(
+    context_id: usize,
+    num_elements: usize,
+) -> FilterMetadataStatus {
+    dispatch_context_event(context_id, |d| {
+        d.on_http_response_metadata(context_id as u32, num_elements)
+    })
 }
 
 #[no_mangle]
@@ -1086,7 +1912,9 @@ pub extern "C" fn proxy_on_http_call_response(
     body_size: usize,
     num_trailers: usize,
 ) {
-    dispatch(|d| d.on_http_call_response(token_id as u32, num_headers, body_size, num_trailers))
+    dispatch_event(|d| {
+        d.on_http_call_response(token_id as u32, num_headers, body_size, num_trailers)
+    })
 }
 
 #[cfg(feature = "stream-metadata")]
@@ -1102,7 +1930,7 @@ pub extern "C" fn proxy_on_grpc_receive_initial_metadata(
 
 #[no_mangle]
 pub extern "C" fn proxy_on_grpc_receive(_context_id: usize, token_id: usize, response_size: usize) {
-    dispatch(|d| d.on_grpc_receive(token_id as u32, response_size))
+    dispatch_event(|d| d.on_grpc_receive(token_id as u32, response_size))
 }
 
 #[cfg(feature = "stream-metadata")]
@@ -1112,10 +1940,10 @@ pub extern "C" fn proxy_on_grpc_receive_trailing_metadata(
     token_id: usize,
     trailers: usize,
 ) {
-    dispatch(|d| d.on_grpc_receive_trailing_metadata(token_id as usize, trailers as usize))
+    dispatch_event(|d| d.on_grpc_receive_trailing_metadata(token_id as usize, trailers as usize))
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_grpc_close(_context_id: usize, token_id: usize, status_code: usize) {
-    dispatch(|d| d.on_grpc_close(token_id as u32, status_code as u32))
+    dispatch_event(|d| d.on_grpc_close(token_id as u32, status_code as u32))
 }
@@ -0,0 +1,476 @@
+//! DNS-over-HTTPS (RFC 8484) resolution through [`HttpCall`], for hosts that expose no resolver.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    defer, downcast_box::DowncastBox, instant_now, Counter, HttpCall, RootContext, Upstream,
+};
+
+/// DNS record types the resolver can query
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    const fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    /// The name is empty, has an empty or oversized label, or is longer than 253 bytes
+    InvalidName,
+    /// The response is not a valid DNS message
+    Malformed(&'static str),
+    /// The server answered with a failure response code other than NXDOMAIN
+    ServerFailure(u8),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::InvalidName => write!(f, "invalid dns name"),
+            DnsError::Malformed(e) => write!(f, "malformed dns response: {e}"),
+            DnsError::ServerFailure(rcode) => write!(f, "dns server failure (rcode {rcode})"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+/// Encodes a recursive query for `name` in DNS wire format
+pub fn encode_query(id: u16, name: &str, record_type: RecordType) -> Result<Vec<u8>, DnsError> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 {
+        return Err(DnsError::InvalidName);
+    }
+    let mut out = Vec::with_capacity(18 + name.len());
+    out.extend_from_slice(&id.to_be_bytes());
+    // recursion desired
+    out.extend_from_slice(&0x0100u16.to_be_bytes());
+    out.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out.extend_from_slice(&record_type.code().to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    Ok(out)
+}
+
+/// Addresses from a DNS response
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DnsAnswer {
+    pub addresses: Vec<IpAddr>,
+    /// Lowest TTL of the address records, `None` if there were none
+    pub ttl: Option<Duration>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DnsError> {
+        let out = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or(DnsError::Malformed("truncated"))?;
+        self.offset += len;
+        Ok(out)
+    }
+
+    fn u16(&mut self) -> Result<u16, DnsError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DnsError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn skip_name(&mut self) -> Result<(), DnsError> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Ok(()),
+                len if len & 0xC0 == 0xC0 => {
+                    self.take(1)?;
+                    return Ok(());
+                }
+                len if len & 0xC0 != 0 => return Err(DnsError::Malformed("bad label")),
+                len => {
+                    self.take(len as usize)?;
+                }
+            }
+        }
+    }
+}
+
+/// Parses A and AAAA records out of a DNS response. NXDOMAIN yields an empty answer.
+pub fn parse_response(data: &[u8]) -> Result<DnsAnswer, DnsError> {
+    let mut reader = Reader { data, offset: 0 };
+    let _id = reader.u16()?;
+    let flags = reader.u16()?;
+    if flags & 0x8000 == 0 {
+        return Err(DnsError::Malformed("not a response"));
+    }
+    let question_count = reader.u16()?;
+    let answer_count = reader.u16()?;
+    reader.take(4)?;
+    match (flags & 0xF) as u8 {
+        0 => (),
+        3 => return Ok(DnsAnswer::default()),
+        rcode => return Err(DnsError::ServerFailure(rcode)),
+    }
+    for _ in 0..question_count {
+        reader.skip_name()?;
+        reader.take(4)?;
+    }
+    let mut answer = DnsAnswer::default();
+    for _ in 0..answer_count {
+        reader.skip_name()?;
+        let record_type = reader.u16()?;
+        let _class = reader.u16()?;
+        let ttl = Duration::from_secs(reader.u32()? as u64);
+        let len = reader.u16()? as usize;
+        let data = reader.take(len)?;
+        let address = match (record_type, len) {
+            (1, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (28, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => continue,
+        };
+        answer.addresses.push(address);
+        answer.ttl = Some(answer.ttl.map_or(ttl, |x| x.min(ttl)));
+    }
+    Ok(answer)
+}
+
+struct CacheEntry {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+type Waiter = Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>, Vec<IpAddr>)>;
+
+struct Pending {
+    remaining: usize,
+    failed: bool,
+    addresses: Vec<IpAddr>,
+    ttl: Option<Duration>,
+    waiters: Vec<Waiter>,
+}
+
+/// Resolves names with DNS-over-HTTPS POST requests, caching answers for their TTL.
+/// Concurrent lookups of the same name share a single query. Keep in an [`Rc`] in the root context.
+/// Lookups increment `dns_cache_hit`, `dns_cache_miss` and `dns_query_failed`.
+pub struct DohResolver {
+    upstream: Upstream<'static>,
+    authority: String,
+    path: String,
+    timeout: Duration,
+    record_types: Vec<RecordType>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    cache: RefCell<HashMap<String, CacheEntry>>,
+    pending: RefCell<HashMap<String, Pending>>,
+}
+
+impl DohResolver {
+    /// Creates a resolver sending queries to `upstream` with the given `:authority`, for A and AAAA records.
+    pub fn new(upstream: Upstream<'static>, authority: impl Into<String>) -> Self {
+        Self {
+            upstream,
+            authority: authority.into(),
+            path: "/dns-query".to_string(),
+            timeout: Duration::from_secs(2),
+            record_types: vec![RecordType::A, RecordType::Aaaa],
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(30),
+            max_entries: 4096,
+            cache: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    /// Default is `/dns-query`
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Default is 2 seconds
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Record types queried for each name. Default is A and AAAA.
+    pub fn record_types(mut self, record_types: Vec<RecordType>) -> Self {
+        self.record_types = record_types;
+        self
+    }
+
+    /// Bounds applied to record TTLs. Default is 5 seconds to 1 hour.
+    pub fn ttl_bounds(mut self, min_ttl: Duration, max_ttl: Duration) -> Self {
+        self.min_ttl = min_ttl;
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// How long names without addresses are cached. Default is 30 seconds.
+    pub fn negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// Default is 4096
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Cached addresses of `name`, if present and not expired
+    pub fn cached(&self, name: &str) -> Option<Vec<IpAddr>> {
+        let name = normalize(name);
+        let cache = self.cache.borrow();
+        let entry = cache.get(&name)?;
+        (entry.expires > instant_now()).then(|| entry.addresses.clone())
+    }
+
+    /// Resolves `name`, calling `callback` with its addresses. The callback always runs after the current host callback returns,
+    /// and receives an empty list if the name does not exist or the lookup failed.
+    pub fn resolve<R: RootContext + 'static>(
+        self: &Rc<Self>,
+        name: &str,
+        callback: impl FnOnce(&mut R, Vec<IpAddr>) + 'static,
+    ) {
+        if self.record_types.is_empty() {
+            defer(move |root: &mut R| callback(root, vec![]));
+            return;
+        }
+        if let Some(addresses) = self.cached(name) {
+            Counter::define("dns_cache_hit").increment(1);
+            defer(move |root: &mut R| callback(root, addresses));
+            return;
+        }
+        Counter::define("dns_cache_miss").increment(1);
+        let name = normalize(name);
+        let waiter: Waiter = Box::new(move |root, addresses| {
+            callback(
+                root.as_any_mut().downcast_mut().expect("invalid root type"),
+                addresses,
+            )
+        });
+        if let Some(pending) = self.pending.borrow_mut().get_mut(&name) {
+            pending.waiters.push(waiter);
+            return;
+        }
+        self.pending.borrow_mut().insert(
+            name.clone(),
+            Pending {
+                remaining: self.record_types.len(),
+                failed: false,
+                addresses: vec![],
+                ttl: None,
+                waiters: vec![waiter],
+            },
+        );
+        for record_type in self.record_types.clone() {
+            if let Err(e) = self.query(&name, record_type) {
+                warn!("failed to query {name} over doh: {e}");
+                Counter::define("dns_query_failed").increment(1);
+                self.complete(None, &name, None);
+            }
+        }
+    }
+
+    fn query(self: &Rc<Self>, name: &str, record_type: RecordType) -> Result<(), String> {
+        let body = encode_query(0, name, record_type).map_err(|e| e.to_string())?;
+        let resolver = self.clone();
+        let owned_name = name.to_string();
+        HttpCall {
+            upstream: self.upstream.clone(),
+            headers: vec![
+                (":method", b"POST"),
+                (":path", self.path.as_bytes()),
+                (":authority", self.authority.as_bytes()),
+                ("content-type", b"application/dns-message"),
+                ("accept", b"application/dns-message"),
+            ],
+            trailers: vec![],
+            body: Some(&body),
+            timeout: Some(self.timeout),
+            inherit_request_policy: false,
+            retry: None,
+            callback: Some(Box::new(move |root, response| {
+                let status = response.header(":status").unwrap_or_default();
+                let answer = if status != b"200" {
+                    Err(format!("status {}", String::from_utf8_lossy(&status)))
+                } else {
+                    parse_response(&response.full_body().unwrap_or_default())
+                        .map_err(|e| e.to_string())
+                };
+                let answer = match answer {
+                    Ok(answer) => Some(answer),
+                    Err(e) => {
+                        warn!("doh query for {owned_name} failed: {e}");
+                        Counter::define("dns_query_failed").increment(1);
+                        None
+                    }
+                };
+                resolver.complete(Some(root), &owned_name, answer);
+            })),
+        }
+        .dispatch()
+        .map_err(|e| format!("{e:?}"))
+    }
+
+    /// Records one finished query for `name`. Once all record types are in, caches the result and calls the waiters.
+    /// `answer` is `None` for failed queries, which are not cached.
+    fn complete(
+        &self,
+        root: Option<&mut DowncastBox<dyn RootContext>>,
+        name: &str,
+        answer: Option<DnsAnswer>,
+    ) {
+        let mut pending_map = self.pending.borrow_mut();
+        let Some(pending) = pending_map.get_mut(name) else {
+            return;
+        };
+        pending.remaining = pending.remaining.saturating_sub(1);
+        if let Some(answer) = answer {
+            pending.addresses.extend(answer.addresses);
+            pending.ttl = match (pending.ttl, answer.ttl) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        } else {
+            pending.failed = true;
+        }
+        if pending.remaining > 0 {
+            return;
+        }
+        let pending = pending_map.remove(name).unwrap();
+        drop(pending_map);
+        if !pending.failed {
+            let ttl = match pending.ttl {
+                Some(ttl) => ttl.clamp(self.min_ttl, self.max_ttl),
+                None => self.negative_ttl,
+            };
+            self.insert(name, pending.addresses.clone(), ttl);
+        }
+        debug!("resolved {name} to {:?}", pending.addresses);
+        match root {
+            Some(root) => {
+                for waiter in pending.waiters {
+                    waiter(root, pending.addresses.clone());
+                }
+            }
+            None => {
+                let addresses = pending.addresses;
+                for waiter in pending.waiters {
+                    let addresses = addresses.clone();
+                    crate::dispatcher::defer_boxed(Box::new(move |root| waiter(root, addresses)));
+                }
+            }
+        }
+    }
+
+    fn insert(&self, name: &str, addresses: Vec<IpAddr>, ttl: Duration) {
+        let now = instant_now();
+        let mut cache = self.cache.borrow_mut();
+        if cache.len() >= self.max_entries {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() >= self.max_entries {
+            cache.clear();
+        }
+        cache.insert(
+            name.to_string(),
+            CacheEntry {
+                addresses,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0, "example.com.", RecordType::A).unwrap();
+        assert_eq!(
+            query,
+            [
+                0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3,
+                b'c', b'o', b'm', 0, 0, 1, 0, 1
+            ]
+        );
+        assert_eq!(
+            encode_query(0, "a..b", RecordType::A),
+            Err(DnsError::InvalidName)
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut response = encode_query(0, "example.com", RecordType::A).unwrap();
+        // response flag, 2 answers
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+        for (ttl, address) in [(300u32, [93, 184, 216, 34]), (60, [93, 184, 216, 35])] {
+            // compressed name pointing at the question
+            response.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+            response.extend_from_slice(&ttl.to_be_bytes());
+            response.extend_from_slice(&[0, 4]);
+            response.extend_from_slice(&address);
+        }
+        let answer = parse_response(&response).unwrap();
+        assert_eq!(
+            answer.addresses,
+            [
+                IpAddr::from([93, 184, 216, 34]),
+                IpAddr::from([93, 184, 216, 35])
+            ]
+        );
+        assert_eq!(answer.ttl, Some(Duration::from_secs(60)));
+
+        // NXDOMAIN
+        response[3] = 0x83;
+        assert_eq!(parse_response(&response), Ok(DnsAnswer::default()));
+        response[3] = 0x82;
+        assert_eq!(parse_response(&response), Err(DnsError::ServerFailure(2)));
+        assert!(parse_response(&response[..20]).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::fmt;
+
+use crate::{
+    dispatcher,
+    hostcalls::{self, BufferType},
+    HttpError, HttpPhase, Status, WrongPhase,
+};
+
+/// How a [`DownstreamWriter`] delivered data to the client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WriteMechanism {
+    /// Written directly to the downstream connection. Native stream contexts only.
+    DirectWrite,
+    /// Appended to the upstream data or response body currently being forwarded downstream
+    Injected,
+    /// Sent as a local response, replacing the upstream response
+    LocalResponse,
+}
+
+/// Error of [`DownstreamWriter::write`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WriteError {
+    /// The active context has no downstream client, i.e. a root context
+    NoClient,
+    /// No mechanism is available for the current HTTP phase, i.e. request trailers after the response started
+    WrongPhase(WrongPhase),
+    /// Stream contexts can only inject data from `on_upstream_data` on this target
+    NotInUpstreamData,
+    /// The host rejected the write
+    Status(Status),
+}
+
+impl From<Status> for WriteError {
+    fn from(value: Status) -> Self {
+        WriteError::Status(value)
+    }
+}
+
+impl From<HttpError> for WriteError {
+    fn from(value: HttpError) -> Self {
+        match value {
+            HttpError::Status(status) => WriteError::Status(status),
+            HttpError::WrongPhase(e) => WriteError::WrongPhase(e),
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::NoClient => write!(f, "active context has no downstream client"),
+            WriteError::WrongPhase(e) => e.fmt(f),
+            WriteError::NotInUpstreamData => {
+                write!(f, "stream data can only be injected from on_upstream_data")
+            }
+            WriteError::Status(status) => write!(f, "{status:?}"),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+/// Sends generated data to the downstream client of the active context, using whichever mechanism the target and
+/// current phase allow:
+/// * HTTP contexts send a local response while the response has not started, then inject into the response body.
+/// * Stream contexts write directly on native targets, otherwise inject into upstream data.
+#[derive(Clone, Debug)]
+pub struct DownstreamWriter {
+    status: u32,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Default for DownstreamWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DownstreamWriter {
+    /// Creates a writer that sends local responses with status 200
+    pub fn new() -> Self {
+        Self {
+            status: 200,
+            headers: vec![],
+        }
+    }
+
+    /// Status code of a local response
+    pub fn status(mut self, status: u32) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Adds a header to a local response
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sends `data` downstream, returning the mechanism used
+    pub fn write(&self, data: &[u8]) -> Result<WriteMechanism, WriteError> {
+        if let Some(state) = dispatcher::http_phase() {
+            return match state.check_send_response("DownstreamWriter::write") {
+                Ok(()) => {
+                    let headers = self
+                        .headers
+                        .iter()
+                        .map(|(name, value)| (&**name, &**value))
+                        .collect::<Vec<_>>();
+                    hostcalls::send_http_response(self.status, &headers, Some(data))?;
+                    Ok(WriteMechanism::LocalResponse)
+                }
+                Err(_) if state.phase == HttpPhase::ResponseBody => {
+                    append(BufferType::HttpResponseBody, data)?;
+                    Ok(WriteMechanism::Injected)
+                }
+                Err(e) => Err(WriteError::WrongPhase(e)),
+            };
+        }
+        let Some(in_upstream_data) = dispatcher::stream_state() else {
+            return Err(WriteError::NoClient);
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let _ = in_upstream_data;
+            hostcalls::write_downstream(data)?;
+            Ok(WriteMechanism::DirectWrite)
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            if !in_upstream_data {
+                return Err(WriteError::NotInUpstreamData);
+            }
+            append(BufferType::UpstreamData, data)?;
+            Ok(WriteMechanism::Injected)
+        }
+    }
+}
+
+/// Appends to a buffer. Hosts append when `start` is past the end of the buffer.
+pub(crate) fn append(buffer: BufferType, data: &[u8]) -> Result<(), Status> {
+    hostcalls::set_buffer(buffer, usize::MAX, 0, data)
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::TcpScenario, BaseContext, Context, FilterStreamStatus, RootContext, StreamContext,
+        UpstreamData,
+    };
+
+    struct Root;
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn create_context(&mut self) -> Context {
+            Context::Stream(Box::new(Greeter))
+        }
+    }
+
+    struct Greeter;
+
+    impl BaseContext for Greeter {}
+
+    impl StreamContext for Greeter {
+        fn on_upstream_data(&mut self, _data: &UpstreamData) -> FilterStreamStatus {
+            assert_eq!(
+                DownstreamWriter::new().write(b"hello "),
+                Ok(WriteMechanism::DirectWrite)
+            );
+            FilterStreamStatus::Continue
+        }
+    }
+
+    #[test]
+    fn test_stream_direct_write() {
+        let outcome = TcpScenario::new(|| Root).connect().upstream("world").run();
+        assert_eq!(outcome.written_downstream, b"hello ");
+        assert_eq!(outcome.forwarded_downstream, b"world");
+    }
+}
@@ -0,0 +1,862 @@
+//! GRPC calls described at runtime. Requests and responses are JSON, encoded with a protobuf `FileDescriptorSet`
+//! provided in plugin configuration (i.e. `protoc --include_imports --descriptor_set_out`), following the proto3 JSON mapping.
+
+use std::{collections::HashMap, fmt, rc::Rc, time::Duration};
+
+use prost::Message;
+use prost_types::{
+    field_descriptor_proto::{Label, Type},
+    DescriptorProto, FileDescriptorSet,
+};
+
+use crate::{
+    base64, json::Value, GrpcCall, GrpcCancelHandle, GrpcCode, RootContext, Status, Upstream,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DynamicError {
+    /// The descriptor set could not be decoded
+    Descriptor(String),
+    UnknownMethod(String),
+    UnknownMessage(String),
+    UnknownField {
+        message: String,
+        field: String,
+    },
+    /// A JSON value does not fit the field type
+    InvalidValue {
+        field: String,
+        expected: &'static str,
+    },
+    /// The response is not a valid protobuf message
+    Malformed(&'static str),
+    /// The call completed with a non-OK status
+    Grpc {
+        code: GrpcCode,
+        message: Option<String>,
+    },
+    Dispatch(Status),
+}
+
+impl fmt::Display for DynamicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DynamicError::Descriptor(e) => write!(f, "invalid descriptor set: {e}"),
+            DynamicError::UnknownMethod(name) => write!(f, "unknown method {name}"),
+            DynamicError::UnknownMessage(name) => write!(f, "unknown message {name}"),
+            DynamicError::UnknownField { message, field } => {
+                write!(f, "unknown field {field} in {message}")
+            }
+            DynamicError::InvalidValue { field, expected } => {
+                write!(f, "invalid value for {field}, expected {expected}")
+            }
+            DynamicError::Malformed(e) => write!(f, "malformed message: {e}"),
+            DynamicError::Grpc { code, message } => match message {
+                Some(message) => write!(f, "grpc call failed with {code:?}: {message}"),
+                None => write!(f, "grpc call failed with {code:?}"),
+            },
+            DynamicError::Dispatch(status) => write!(f, "failed to dispatch grpc call: {status:?}"),
+        }
+    }
+}
+
+impl std::error::Error for DynamicError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum FieldKind {
+    Double,
+    Float,
+    Int64,
+    Uint64,
+    Int32,
+    Fixed64,
+    Fixed32,
+    Bool,
+    String,
+    Bytes,
+    Uint32,
+    Sfixed32,
+    Sfixed64,
+    Sint32,
+    Sint64,
+    Enum(String),
+    Message(String),
+}
+
+impl FieldKind {
+    const fn wire_type(&self) -> u8 {
+        match self {
+            FieldKind::Double | FieldKind::Fixed64 | FieldKind::Sfixed64 => 1,
+            FieldKind::Float | FieldKind::Fixed32 | FieldKind::Sfixed32 => 5,
+            FieldKind::String | FieldKind::Bytes | FieldKind::Message(_) => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FieldDesc {
+    name: String,
+    json_name: String,
+    number: u32,
+    kind: FieldKind,
+    repeated: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+struct MessageDesc {
+    fields: Vec<FieldDesc>,
+    map_entry: bool,
+}
+
+/// Input and output message names of a method
+#[derive(Clone, Debug)]
+struct MethodDesc {
+    input: String,
+    output: String,
+}
+
+/// Messages, enums and services indexed from a `FileDescriptorSet`
+#[derive(Clone, Debug, Default)]
+pub struct DescriptorPool {
+    messages: HashMap<String, MessageDesc>,
+    enums: HashMap<String, Vec<(String, i32)>>,
+    /// Keyed by `package.Service/Method`
+    methods: HashMap<String, MethodDesc>,
+}
+
+fn full_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{prefix}.{name}")
+    }
+}
+
+fn lower_camel(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+impl DescriptorPool {
+    /// Indexes a binary `FileDescriptorSet`
+    pub fn decode(descriptor_set: &[u8]) -> Result<Self, DynamicError> {
+        let set = FileDescriptorSet::decode(descriptor_set)
+            .map_err(|e| DynamicError::Descriptor(e.to_string()))?;
+        Ok(Self::from_set(&set))
+    }
+
+    /// Indexes a base64 encoded binary `FileDescriptorSet`, as embedded in JSON plugin configuration
+    pub fn from_base64(descriptor_set: &str) -> Result<Self, DynamicError> {
+        let raw = base64::decode(descriptor_set.trim())
+            .ok_or_else(|| DynamicError::Descriptor("invalid base64".to_string()))?;
+        Self::decode(&raw)
+    }
+
+    pub fn from_set(set: &FileDescriptorSet) -> Self {
+        let mut pool = Self::default();
+        for file in &set.file {
+            let package = file.package();
+            for message in &file.message_type {
+                pool.add_message(package, message);
+            }
+            for enum_type in &file.enum_type {
+                pool.enums.insert(
+                    full_name(package, enum_type.name()),
+                    enum_type
+                        .value
+                        .iter()
+                        .map(|v| (v.name().to_string(), v.number()))
+                        .collect(),
+                );
+            }
+            for service in &file.service {
+                let service_name = full_name(package, service.name());
+                for method in &service.method {
+                    pool.methods.insert(
+                        format!("{service_name}/{}", method.name()),
+                        MethodDesc {
+                            input: method.input_type().trim_start_matches('.').to_string(),
+                            output: method.output_type().trim_start_matches('.').to_string(),
+                        },
+                    );
+                }
+            }
+        }
+        pool
+    }
+
+    fn add_message(&mut self, prefix: &str, message: &DescriptorProto) {
+        let name = full_name(prefix, message.name());
+        for nested in &message.nested_type {
+            self.add_message(&name, nested);
+        }
+        for enum_type in &message.enum_type {
+            self.enums.insert(
+                full_name(&name, enum_type.name()),
+                enum_type
+                    .value
+                    .iter()
+                    .map(|v| (v.name().to_string(), v.number()))
+                    .collect(),
+            );
+        }
+        let fields = message
+            .field
+            .iter()
+            .filter_map(|field| {
+                let type_name = field.type_name().trim_start_matches('.').to_string();
+                let kind = match field.r#type() {
+                    Type::Double => FieldKind::Double,
+                    Type::Float => FieldKind::Float,
+                    Type::Int64 => FieldKind::Int64,
+                    Type::Uint64 => FieldKind::Uint64,
+                    Type::Int32 => FieldKind::Int32,
+                    Type::Fixed64 => FieldKind::Fixed64,
+                    Type::Fixed32 => FieldKind::Fixed32,
+                    Type::Bool => FieldKind::Bool,
+                    Type::String => FieldKind::String,
+                    Type::Bytes => FieldKind::Bytes,
+                    Type::Uint32 => FieldKind::Uint32,
+                    Type::Sfixed32 => FieldKind::Sfixed32,
+                    Type::Sfixed64 => FieldKind::Sfixed64,
+                    Type::Sint32 => FieldKind::Sint32,
+                    Type::Sint64 => FieldKind::Sint64,
+                    Type::Enum => FieldKind::Enum(type_name),
+                    Type::Message => FieldKind::Message(type_name),
+                    // groups are deprecated and unsupported
+                    Type::Group => return None,
+                };
+                Some(FieldDesc {
+                    name: field.name().to_string(),
+                    json_name: field
+                        .json_name
+                        .clone()
+                        .unwrap_or_else(|| lower_camel(field.name())),
+                    number: field.number() as u32,
+                    kind,
+                    repeated: field.label() == Label::Repeated,
+                })
+            })
+            .collect();
+        self.messages.insert(
+            name,
+            MessageDesc {
+                fields,
+                map_entry: message
+                    .options
+                    .as_ref()
+                    .and_then(|x| x.map_entry)
+                    .unwrap_or_default(),
+            },
+        );
+    }
+
+    fn message(&self, name: &str) -> Result<&MessageDesc, DynamicError> {
+        self.messages
+            .get(name)
+            .ok_or_else(|| DynamicError::UnknownMessage(name.to_string()))
+    }
+
+    fn method(&self, service: &str, method: &str) -> Result<&MethodDesc, DynamicError> {
+        let name = format!("{service}/{method}");
+        self.methods
+            .get(&name)
+            .ok_or(DynamicError::UnknownMethod(name))
+    }
+
+    /// Encodes a JSON object as the protobuf message `message` (full name, i.e. `package.Message`)
+    pub fn encode(&self, message: &str, value: &Value) -> Result<Vec<u8>, DynamicError> {
+        let mut out = vec![];
+        self.encode_message(message, value, &mut out)?;
+        Ok(out)
+    }
+
+    /// Decodes the protobuf message `message` to JSON. 64 bit integers are strings, bytes are base64 and fields absent on the wire are omitted.
+    pub fn decode_message(&self, message: &str, data: &[u8]) -> Result<Value, DynamicError> {
+        let desc = self.message(message)?;
+        let mut values: Vec<Option<Value>> = vec![None; desc.fields.len()];
+        let mut reader = WireReader { data, offset: 0 };
+        while !reader.is_empty() {
+            let tag = reader.varint()?;
+            let number = (tag >> 3) as u32;
+            let wire_type = (tag & 7) as u8;
+            let Some(index) = desc.fields.iter().position(|f| f.number == number) else {
+                reader.skip(wire_type)?;
+                continue;
+            };
+            let field = &desc.fields[index];
+            if field.repeated && wire_type == 2 && field.kind.wire_type() != 2 {
+                let packed = reader.length_delimited()?;
+                let mut packed = WireReader {
+                    data: packed,
+                    offset: 0,
+                };
+                while !packed.is_empty() {
+                    let value = self.decode_value(field, field.kind.wire_type(), &mut packed)?;
+                    push_repeated(&mut values[index], value);
+                }
+                continue;
+            }
+            let value = self.decode_value(field, wire_type, &mut reader)?;
+            if !field.repeated {
+                values[index] = Some(value);
+                continue;
+            }
+            let map_entry = match &field.kind {
+                FieldKind::Message(name) => self.message(name)?.map_entry,
+                _ => false,
+            };
+            if !map_entry {
+                push_repeated(&mut values[index], value);
+                continue;
+            }
+            let key = match value.get("key") {
+                Some(Value::String(key)) => key.clone(),
+                Some(key) => key.to_string(),
+                None => String::new(),
+            };
+            let value = value.get("value").cloned().unwrap_or_default();
+            match values[index].get_or_insert_with(|| Value::Object(vec![])) {
+                Value::Object(entries) => entries.push((key, value)),
+                _ => unreachable!(),
+            }
+        }
+        Ok(Value::Object(
+            desc.fields
+                .iter()
+                .zip(values)
+                .filter_map(|(field, value)| Some((field.json_name.clone(), value?)))
+                .collect(),
+        ))
+    }
+
+    fn decode_value(
+        &self,
+        field: &FieldDesc,
+        wire_type: u8,
+        reader: &mut WireReader,
+    ) -> Result<Value, DynamicError> {
+        if wire_type != field.kind.wire_type() {
+            return Err(DynamicError::Malformed("unexpected wire type"));
+        }
+        Ok(match &field.kind {
+            FieldKind::Double => Value::Number(f64::from_bits(reader.fixed64()?)),
+            FieldKind::Float => Value::Number(f32::from_bits(reader.fixed32()?) as f64),
+            FieldKind::Int64 => Value::String((reader.varint()? as i64).to_string()),
+            FieldKind::Uint64 => Value::String(reader.varint()?.to_string()),
+            FieldKind::Int32 => Value::Number(reader.varint()? as i32 as f64),
+            FieldKind::Fixed64 => Value::String(reader.fixed64()?.to_string()),
+            FieldKind::Fixed32 => Value::Number(reader.fixed32()? as f64),
+            FieldKind::Bool => Value::Bool(reader.varint()? != 0),
+            FieldKind::String => {
+                Value::String(String::from_utf8_lossy(reader.length_delimited()?).into_owned())
+            }
+            FieldKind::Bytes => Value::String(base64::encode(reader.length_delimited()?)),
+            FieldKind::Uint32 => Value::Number(reader.varint()? as u32 as f64),
+            FieldKind::Sfixed32 => Value::Number(reader.fixed32()? as i32 as f64),
+            FieldKind::Sfixed64 => Value::String((reader.fixed64()? as i64).to_string()),
+            FieldKind::Sint32 => Value::Number(zigzag_decode(reader.varint()?) as i32 as f64),
+            FieldKind::Sint64 => Value::String(zigzag_decode(reader.varint()?).to_string()),
+            FieldKind::Enum(name) => {
+                let number = reader.varint()? as i32;
+                self.enums
+                    .get(name)
+                    .and_then(|values| values.iter().find(|(_, n)| *n == number))
+                    .map(|(name, _)| Value::String(name.clone()))
+                    .unwrap_or(Value::Number(number as f64))
+            }
+            FieldKind::Message(name) => self.decode_message(name, reader.length_delimited()?)?,
+        })
+    }
+
+    fn encode_message(
+        &self,
+        message: &str,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<(), DynamicError> {
+        let desc = self.message(message)?;
+        let Value::Object(members) = value else {
+            return Err(DynamicError::InvalidValue {
+                field: message.to_string(),
+                expected: "object",
+            });
+        };
+        for (key, value) in members {
+            let Some(field) = desc
+                .fields
+                .iter()
+                .find(|f| &f.json_name == key || &f.name == key)
+            else {
+                return Err(DynamicError::UnknownField {
+                    message: message.to_string(),
+                    field: key.clone(),
+                });
+            };
+            if value.is_null() {
+                continue;
+            }
+            if !field.repeated {
+                self.encode_field(field, value, out)?;
+                continue;
+            }
+            let map_entry = match &field.kind {
+                FieldKind::Message(name) => self.message(name)?.map_entry.then_some(name),
+                _ => None,
+            };
+            if let Some(entry_name) = map_entry {
+                let Value::Object(entries) = value else {
+                    return Err(invalid(field, "object"));
+                };
+                let entry_desc = self.message(entry_name)?;
+                let (Some(key_field), Some(value_field)) = (
+                    entry_desc.fields.iter().find(|f| f.number == 1),
+                    entry_desc.fields.iter().find(|f| f.number == 2),
+                ) else {
+                    return Err(DynamicError::UnknownMessage(entry_name.clone()));
+                };
+                for (key, value) in entries {
+                    let key = match key_field.kind {
+                        FieldKind::String => Value::String(key.clone()),
+                        FieldKind::Bool => Value::Bool(key == "true"),
+                        _ => Value::String(key.clone()),
+                    };
+                    let mut entry = vec![];
+                    self.encode_field(key_field, &key, &mut entry)?;
+                    self.encode_field(value_field, value, &mut entry)?;
+                    write_varint(out, (field.number as u64) << 3 | 2);
+                    write_varint(out, entry.len() as u64);
+                    out.extend_from_slice(&entry);
+                }
+                continue;
+            }
+            let Value::Array(items) = value else {
+                return Err(invalid(field, "array"));
+            };
+            if field.kind.wire_type() == 2 {
+                for item in items {
+                    self.encode_field(field, item, out)?;
+                }
+                continue;
+            }
+            let mut packed = vec![];
+            for item in items {
+                self.encode_scalar(field, item, &mut packed)?;
+            }
+            write_varint(out, (field.number as u64) << 3 | 2);
+            write_varint(out, packed.len() as u64);
+            out.extend_from_slice(&packed);
+        }
+        Ok(())
+    }
+
+    fn encode_field(
+        &self,
+        field: &FieldDesc,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<(), DynamicError> {
+        write_varint(
+            out,
+            (field.number as u64) << 3 | field.kind.wire_type() as u64,
+        );
+        match &field.kind {
+            FieldKind::String => {
+                let value = value.as_str().ok_or_else(|| invalid(field, "string"))?;
+                write_varint(out, value.len() as u64);
+                out.extend_from_slice(value.as_bytes());
+            }
+            FieldKind::Bytes => {
+                let value = value
+                    .as_str()
+                    .and_then(base64::decode)
+                    .ok_or_else(|| invalid(field, "base64 string"))?;
+                write_varint(out, value.len() as u64);
+                out.extend_from_slice(&value);
+            }
+            FieldKind::Message(name) => {
+                let mut nested = vec![];
+                self.encode_message(name, value, &mut nested)?;
+                write_varint(out, nested.len() as u64);
+                out.extend_from_slice(&nested);
+            }
+            _ => self.encode_scalar(field, value, out)?,
+        }
+        Ok(())
+    }
+
+    /// Encodes a numeric, boolean or enum value without a tag
+    fn encode_scalar(
+        &self,
+        field: &FieldDesc,
+        value: &Value,
+        out: &mut Vec<u8>,
+    ) -> Result<(), DynamicError> {
+        match &field.kind {
+            FieldKind::Double => {
+                out.extend_from_slice(&float(field, value)?.to_bits().to_le_bytes())
+            }
+            FieldKind::Float => {
+                out.extend_from_slice(&(float(field, value)? as f32).to_bits().to_le_bytes())
+            }
+            FieldKind::Int64 | FieldKind::Int32 => write_varint(out, integer(field, value)? as u64),
+            FieldKind::Uint64 | FieldKind::Uint32 => write_varint(out, unsigned(field, value)?),
+            FieldKind::Fixed64 => out.extend_from_slice(&unsigned(field, value)?.to_le_bytes()),
+            FieldKind::Fixed32 => {
+                out.extend_from_slice(&(unsigned(field, value)? as u32).to_le_bytes())
+            }
+            FieldKind::Sfixed64 => out.extend_from_slice(&integer(field, value)?.to_le_bytes()),
+            FieldKind::Sfixed32 => {
+                out.extend_from_slice(&(integer(field, value)? as i32).to_le_bytes())
+            }
+            FieldKind::Sint64 | FieldKind::Sint32 => {
+                write_varint(out, zigzag_encode(integer(field, value)?))
+            }
+            FieldKind::Bool => write_varint(
+                out,
+                value.as_bool().ok_or_else(|| invalid(field, "bool"))? as u64,
+            ),
+            FieldKind::Enum(name) => {
+                let number = match value {
+                    Value::String(value) => self
+                        .enums
+                        .get(name)
+                        .and_then(|values| values.iter().find(|(n, _)| n == value))
+                        .map(|(_, number)| *number)
+                        .ok_or_else(|| invalid(field, "enum value"))?,
+                    value => integer(field, value)? as i32,
+                };
+                write_varint(out, number as i64 as u64)
+            }
+            FieldKind::String | FieldKind::Bytes | FieldKind::Message(_) => {
+                return Err(invalid(field, "scalar"))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(field: &FieldDesc, expected: &'static str) -> DynamicError {
+    DynamicError::InvalidValue {
+        field: field.name.clone(),
+        expected,
+    }
+}
+
+fn push_repeated(slot: &mut Option<Value>, value: Value) {
+    match slot.get_or_insert_with(|| Value::Array(vec![])) {
+        Value::Array(items) => items.push(value),
+        _ => unreachable!(),
+    }
+}
+
+fn float(field: &FieldDesc, value: &Value) -> Result<f64, DynamicError> {
+    match value {
+        Value::Number(x) => Ok(*x),
+        Value::String(x) => match x.as_str() {
+            "NaN" => Ok(f64::NAN),
+            "Infinity" => Ok(f64::INFINITY),
+            "-Infinity" => Ok(f64::NEG_INFINITY),
+            x => x.parse().map_err(|_| invalid(field, "number")),
+        },
+        _ => Err(invalid(field, "number")),
+    }
+}
+
+fn integer(field: &FieldDesc, value: &Value) -> Result<i64, DynamicError> {
+    match value {
+        Value::Number(x) if x.fract() == 0.0 => Ok(*x as i64),
+        Value::String(x) => x.parse().map_err(|_| invalid(field, "integer")),
+        _ => Err(invalid(field, "integer")),
+    }
+}
+
+fn unsigned(field: &FieldDesc, value: &Value) -> Result<u64, DynamicError> {
+    match value {
+        Value::Number(x) if x.fract() == 0.0 && *x >= 0.0 => Ok(*x as u64),
+        Value::String(x) => x.parse().map_err(|_| invalid(field, "unsigned integer")),
+        _ => Err(invalid(field, "unsigned integer")),
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn zigzag_decode(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+struct WireReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> WireReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DynamicError> {
+        let out = self
+            .data
+            .get(self.offset..self.offset.saturating_add(len))
+            .ok_or(DynamicError::Malformed("truncated"))?;
+        self.offset += len;
+        Ok(out)
+    }
+
+    fn varint(&mut self) -> Result<u64, DynamicError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DynamicError::Malformed("varint too long"))
+    }
+
+    fn fixed64(&mut self) -> Result<u64, DynamicError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn fixed32(&mut self) -> Result<u32, DynamicError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn length_delimited(&mut self) -> Result<&'a [u8], DynamicError> {
+        let len = self.varint()? as usize;
+        self.take(len)
+    }
+
+    fn skip(&mut self, wire_type: u8) -> Result<(), DynamicError> {
+        match wire_type {
+            0 => {
+                self.varint()?;
+            }
+            1 => {
+                self.take(8)?;
+            }
+            2 => {
+                self.length_delimited()?;
+            }
+            5 => {
+                self.take(4)?;
+            }
+            _ => return Err(DynamicError::Malformed("unsupported wire type")),
+        }
+        Ok(())
+    }
+}
+
+/// Calls GRPC methods by name with JSON payloads
+#[derive(Clone)]
+pub struct DynamicGrpc {
+    pool: Rc<DescriptorPool>,
+    upstream: Upstream<'static>,
+    timeout: Duration,
+}
+
+impl DynamicGrpc {
+    pub fn new(pool: Rc<DescriptorPool>, upstream: Upstream<'static>) -> Self {
+        Self {
+            pool,
+            upstream,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Default is 10 seconds
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Encodes `payload` as the input of `service`/`method` (i.e. `package.Service`, `Method`), dispatches it,
+    /// and calls `callback` with the decoded response.
+    pub fn call<R: RootContext + 'static>(
+        &self,
+        service: &str,
+        method: &str,
+        initial_metadata: &[(&str, &[u8])],
+        payload: &Value,
+        callback: impl FnOnce(&mut R, Result<Value, DynamicError>) + 'static,
+    ) -> Result<GrpcCancelHandle, DynamicError> {
+        let desc = self.pool.method(service, method)?;
+        let message = self.pool.encode(&desc.input, payload)?;
+        let pool = self.pool.clone();
+        let output = desc.output.clone();
+        GrpcCall {
+            upstream: self.upstream.clone(),
+            service,
+            method,
+            initial_metadata: initial_metadata.to_vec(),
+            message: Some(message.into()),
+            timeout: Some(self.timeout),
+            callback: Some(Box::new(move |root, response| {
+                let result = if response.status_code() != GrpcCode::Ok {
+                    Err(DynamicError::Grpc {
+                        code: response.status_code(),
+                        message: response.status_message().map(str::to_string),
+                    })
+                } else {
+                    let body = if response.body_size() > 0 {
+                        response.full_body().unwrap_or_default()
+                    } else {
+                        vec![]
+                    };
+                    pool.decode_message(&output, &body)
+                };
+                callback(
+                    root.as_any_mut().downcast_mut().expect("invalid root type"),
+                    result,
+                )
+            })),
+        }
+        .dispatch()
+        .map_err(DynamicError::Dispatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_types::{
+        EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+        MessageOptions,
+    };
+
+    use super::*;
+
+    fn field(
+        name: &str,
+        number: i32,
+        kind: Type,
+        label: Label,
+        type_name: &str,
+    ) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(label as i32),
+            r#type: Some(kind as i32),
+            type_name: (!type_name.is_empty()).then(|| type_name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn pool() -> DescriptorPool {
+        let entry = DescriptorProto {
+            name: Some("LabelsEntry".to_string()),
+            field: vec![
+                field("key", 1, Type::String, Label::Optional, ""),
+                field("value", 2, Type::String, Label::Optional, ""),
+            ],
+            options: Some(MessageOptions {
+                map_entry: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let request = DescriptorProto {
+            name: Some("Request".to_string()),
+            field: vec![
+                field("user_id", 1, Type::Int64, Label::Optional, ""),
+                field("scores", 2, Type::Int32, Label::Repeated, ""),
+                field("kind", 3, Type::Enum, Label::Optional, ".test.Kind"),
+                field(
+                    "labels",
+                    4,
+                    Type::Message,
+                    Label::Repeated,
+                    ".test.Request.LabelsEntry",
+                ),
+                field("inner", 5, Type::Message, Label::Optional, ".test.Request"),
+                field("payload", 6, Type::Bytes, Label::Optional, ""),
+                field("delta", 7, Type::Sint32, Label::Optional, ""),
+            ],
+            nested_type: vec![entry],
+            ..Default::default()
+        };
+        DescriptorPool::from_set(&FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("test.proto".to_string()),
+                package: Some("test".to_string()),
+                message_type: vec![request],
+                enum_type: vec![EnumDescriptorProto {
+                    name: Some("Kind".to_string()),
+                    value: vec![
+                        EnumValueDescriptorProto {
+                            name: Some("UNKNOWN".to_string()),
+                            number: Some(0),
+                            options: None,
+                        },
+                        EnumValueDescriptorProto {
+                            name: Some("ADMIN".to_string()),
+                            number: Some(1),
+                            options: None,
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        })
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let pool = pool();
+        let input = Value::parse(
+            r#"{"userId":"123","scores":[1,2,300],"kind":"ADMIN","labels":{"a":"b"},"inner":{"delta":-5},"payload":"aGk="}"#,
+        )
+        .unwrap();
+        let encoded = pool.encode("test.Request", &input).unwrap();
+        // packed scores
+        assert_eq!(&encoded[..2], &[0x08, 123]);
+        assert_eq!(&encoded[2..8], &[0x12, 4, 1, 2, 0xAC, 0x02]);
+        let decoded = pool.decode_message("test.Request", &encoded).unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let pool = pool();
+        assert!(matches!(
+            pool.encode("test.Request", &Value::parse(r#"{"nope":1}"#).unwrap()),
+            Err(DynamicError::UnknownField { .. })
+        ));
+        assert!(matches!(
+            pool.encode(
+                "test.Request",
+                &Value::parse(r#"{"kind":"OTHER"}"#).unwrap()
+            ),
+            Err(DynamicError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            pool.encode("test.Missing", &Value::Object(vec![])),
+            Err(DynamicError::UnknownMessage(_))
+        ));
+    }
+}
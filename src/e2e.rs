@@ -0,0 +1,762 @@
+//! End-to-end tests of a plugin in a real Envoy, for integration tests of SDK users. Requires the `e2e` feature.
+//!
+//! [`WasmBuild`] builds the plugin, [`EnvoyConfig`] renders a bootstrap with it configured in front of an upstream,
+//! i.e. an [`EchoServer`], and [`Envoy`] runs it and drives HTTP/1.1 traffic through it. Envoy is run from `ENVOY_BIN`,
+//! `envoy` on the `PATH`, or `func-e run` if `func-e` is on the `PATH` instead.
+//!
+//! ```ignore
+//! #[test]
+//! fn blocks_secrets() {
+//!     let wasm = WasmBuild::example("mini_proxy").build().unwrap();
+//!     let upstream = EchoServer::start().unwrap();
+//!     let envoy = Envoy::start(EnvoyConfig::new(wasm, upstream.port()).configuration(r#"{"mode":"block"}"#)).unwrap();
+//!     let response = envoy.get("/secret").unwrap();
+//!     assert_eq!(response.status, 403);
+//!     assert_eq!(envoy.metric("mini_proxy_blocked"), Some(1));
+//!     assert!(envoy.logs().contains("blocked request"));
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Builds a plugin to Wasm with cargo
+#[derive(Clone, Debug)]
+pub struct WasmBuild {
+    manifest_dir: PathBuf,
+    example: Option<String>,
+    target: String,
+    profile: String,
+}
+
+impl WasmBuild {
+    /// Builds the library of the crate under test, which must have a `cdylib` crate type
+    pub fn library() -> Self {
+        Self {
+            manifest_dir: env::var_os("CARGO_MANIFEST_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(".")),
+            example: None,
+            target: "wasm32-unknown-unknown".to_string(),
+            profile: "release".to_string(),
+        }
+    }
+
+    /// Builds an example of the crate under test
+    pub fn example(name: impl Into<String>) -> Self {
+        Self {
+            example: Some(name.into()),
+            ..Self::library()
+        }
+    }
+
+    pub fn manifest_dir(mut self, manifest_dir: impl Into<PathBuf>) -> Self {
+        self.manifest_dir = manifest_dir.into();
+        self
+    }
+
+    /// Default is `wasm32-unknown-unknown`
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    /// Default is `release`
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    /// Runs the build, returning the path of the `.wasm` file
+    pub fn build(&self) -> io::Result<PathBuf> {
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let mut command = Command::new(cargo);
+        command
+            .current_dir(&self.manifest_dir)
+            .args([
+                "build",
+                "--target",
+                &self.target,
+                "--profile",
+                &self.profile,
+            ])
+            .args(["--message-format", "short"]);
+        if let Some(example) = &self.example {
+            command.args(["--example", example]);
+        }
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "wasm build failed:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        let profile_dir = match &*self.profile {
+            "dev" => "debug",
+            x => x,
+        };
+        let target_dir = env::var_os("CARGO_TARGET_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.manifest_dir.join("target"))
+            .join(&self.target)
+            .join(profile_dir);
+        let path = match &self.example {
+            Some(example) => target_dir.join("examples").join(format!("{example}.wasm")),
+            None => {
+                let name = package_name(&self.manifest_dir)?.replace('-', "_");
+                target_dir.join(format!("{name}.wasm"))
+            }
+        };
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("build output {} not found", path.display()),
+            ));
+        }
+        Ok(path)
+    }
+}
+
+/// Name of the package in `Cargo.toml`, without a TOML parser
+fn package_name(manifest_dir: &Path) -> io::Result<String> {
+    let manifest = fs::read_to_string(manifest_dir.join("Cargo.toml"))?;
+    manifest
+        .lines()
+        .skip_while(|x| x.trim() != "[package]")
+        .find_map(|x| {
+            let (key, value) = x.split_once('=')?;
+            (key.trim() == "name").then(|| value.trim().trim_matches('"').to_string())
+        })
+        .ok_or_else(|| io::Error::other("package name not found in Cargo.toml"))
+}
+
+/// An Envoy bootstrap with a listener running the plugin as an HTTP filter in front of a single upstream
+#[derive(Clone, Debug)]
+pub struct EnvoyConfig {
+    wasm: PathBuf,
+    upstream_port: u16,
+    listener_port: u16,
+    admin_port: u16,
+    name: String,
+    root_id: String,
+    configuration: Option<String>,
+    vm_configuration: Option<String>,
+    runtime: String,
+}
+
+impl EnvoyConfig {
+    /// Runs `wasm` in front of `127.0.0.1:{upstream_port}`. Listener and admin ports are picked among free ports.
+    pub fn new(wasm: impl Into<PathBuf>, upstream_port: u16) -> Self {
+        Self {
+            wasm: wasm.into(),
+            upstream_port,
+            listener_port: free_port(),
+            admin_port: free_port(),
+            name: "plugin".to_string(),
+            root_id: String::new(),
+            configuration: None,
+            vm_configuration: None,
+            runtime: "envoy.wasm.runtime.v8".to_string(),
+        }
+    }
+
+    /// Plugin name and VM ID. Default is `plugin`.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn root_id(mut self, root_id: impl Into<String>) -> Self {
+        self.root_id = root_id.into();
+        self
+    }
+
+    /// Plugin configuration, read in `on_configure`
+    pub fn configuration(mut self, configuration: impl Into<String>) -> Self {
+        self.configuration = Some(configuration.into());
+        self
+    }
+
+    /// VM configuration, read in `on_vm_start`
+    pub fn vm_configuration(mut self, vm_configuration: impl Into<String>) -> Self {
+        self.vm_configuration = Some(vm_configuration.into());
+        self
+    }
+
+    /// Wasm runtime. Default is `envoy.wasm.runtime.v8`.
+    pub fn runtime(mut self, runtime: impl Into<String>) -> Self {
+        self.runtime = runtime.into();
+        self
+    }
+
+    pub fn listener_port(&self) -> u16 {
+        self.listener_port
+    }
+
+    pub fn admin_port(&self) -> u16 {
+        self.admin_port
+    }
+
+    /// The bootstrap as YAML
+    pub fn render(&self) -> String {
+        fn string_value(value: &Option<String>) -> String {
+            match value {
+                Some(value) => format!(
+                    "\n                    \"@type\": type.googleapis.com/google.protobuf.StringValue\n                    value: {}",
+                    yaml_string(value)
+                ),
+                None => " {}".to_string(),
+            }
+        }
+        format!(
+            r#"admin:
+  address:
+    socket_address: {{ address: 127.0.0.1, port_value: {admin_port} }}
+static_resources:
+  listeners:
+  - name: e2e
+    address:
+      socket_address: {{ address: 127.0.0.1, port_value: {listener_port} }}
+    filter_chains:
+    - filters:
+      - name: envoy.filters.network.http_connection_manager
+        typed_config:
+          "@type": type.googleapis.com/envoy.extensions.filters.network.http_connection_manager.v3.HttpConnectionManager
+          stat_prefix: e2e
+          route_config:
+            name: e2e
+            virtual_hosts:
+            - name: e2e
+              domains: ["*"]
+              routes:
+              - match: {{ prefix: "/" }}
+                route: {{ cluster: upstream }}
+          http_filters:
+          - name: envoy.filters.http.wasm
+            typed_config:
+              "@type": type.googleapis.com/envoy.extensions.filters.http.wasm.v3.Wasm
+              config:
+                name: {name}
+                root_id: {root_id}
+                configuration:{configuration}
+                vm_config:
+                  vm_id: {name}
+                  runtime: {runtime}
+                  configuration:{vm_configuration}
+                  code:
+                    local:
+                      filename: {wasm}
+          - name: envoy.filters.http.router
+            typed_config:
+              "@type": type.googleapis.com/envoy.extensions.filters.http.router.v3.Router
+  clusters:
+  - name: upstream
+    type: STATIC
+    connect_timeout: 1s
+    load_assignment:
+      cluster_name: upstream
+      endpoints:
+      - lb_endpoints:
+        - endpoint:
+            address:
+              socket_address: {{ address: 127.0.0.1, port_value: {upstream_port} }}
+"#,
+            admin_port = self.admin_port,
+            listener_port = self.listener_port,
+            name = yaml_string(&self.name),
+            root_id = yaml_string(&self.root_id),
+            configuration = string_value(&self.configuration),
+            runtime = yaml_string(&self.runtime),
+            vm_configuration = string_value(&self.vm_configuration),
+            wasm = yaml_string(&self.wasm.to_string_lossy()),
+            upstream_port = self.upstream_port,
+        )
+    }
+}
+
+/// A double quoted YAML scalar
+fn yaml_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A port free at the time of the call
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|x| x.local_addr())
+        .map(|x| x.port())
+        .expect("failed to find a free port")
+}
+
+/// A running Envoy, killed on drop
+pub struct Envoy {
+    child: Child,
+    dir: PathBuf,
+    listener_port: u16,
+    admin_port: u16,
+}
+
+static INSTANCES: AtomicUsize = AtomicUsize::new(0);
+
+impl Envoy {
+    /// Starts Envoy and waits up to 30 seconds for it to be ready
+    pub fn start(config: EnvoyConfig) -> io::Result<Self> {
+        let dir = env::temp_dir().join(format!(
+            "proxy-sdk-e2e-{}-{}",
+            std::process::id(),
+            INSTANCES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir)?;
+        let bootstrap = dir.join("envoy.yaml");
+        fs::write(&bootstrap, config.render())?;
+        let mut command = envoy_command();
+        command
+            .arg("-c")
+            .arg(&bootstrap)
+            .args(["--log-level", "info", "--base-id"])
+            .arg(config.admin_port.to_string())
+            .arg("--log-path")
+            .arg(dir.join("envoy.log"))
+            .stdout(Stdio::null())
+            .stderr(File::create(dir.join("stderr.log"))?);
+        let child = command.spawn()?;
+        let mut envoy = Self {
+            child,
+            dir,
+            listener_port: config.listener_port,
+            admin_port: config.admin_port,
+        };
+        envoy.wait_ready(Duration::from_secs(30))?;
+        Ok(envoy)
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait()? {
+                return Err(io::Error::other(format!(
+                    "envoy exited with {status}:\n{}",
+                    self.logs()
+                )));
+            }
+            if self
+                .admin("/ready")
+                .is_ok_and(|x| x.status == 200 && x.text().trim() == "LIVE")
+            {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("envoy not ready:\n{}", self.logs()),
+        ))
+    }
+
+    pub fn listener_port(&self) -> u16 {
+        self.listener_port
+    }
+
+    pub fn admin_port(&self) -> u16 {
+        self.admin_port
+    }
+
+    /// Sends a request through the listener
+    pub fn request(&self, request: &HttpRequest) -> io::Result<HttpResponse> {
+        request.send(self.listener_port)
+    }
+
+    pub fn get(&self, path: &str) -> io::Result<HttpResponse> {
+        self.request(&HttpRequest::new("GET", path))
+    }
+
+    /// Sends a GET request to the admin interface
+    pub fn admin(&self, path: &str) -> io::Result<HttpResponse> {
+        HttpRequest::new("GET", path).send(self.admin_port)
+    }
+
+    /// Counters and gauges, as listed by the admin interface. Metrics defined by the plugin keep their name.
+    pub fn metrics(&self) -> io::Result<HashMap<String, u64>> {
+        Ok(parse_stats(&self.admin("/stats")?.text()))
+    }
+
+    pub fn metric(&self, name: &str) -> Option<u64> {
+        self.metrics().ok()?.remove(name)
+    }
+
+    /// Everything Envoy logged so far, including logs of the plugin
+    pub fn logs(&self) -> String {
+        ["stderr.log", "envoy.log"]
+            .iter()
+            .filter_map(|x| fs::read_to_string(self.dir.join(x)).ok())
+            .collect()
+    }
+
+    /// Waits up to `timeout` for a log line containing `needle`, as plugins may log after the response was sent
+    pub fn wait_for_log(&self, needle: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.logs().contains(needle) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Envoy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl fmt::Debug for Envoy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Envoy")
+            .field("pid", &self.child.id())
+            .field("listener_port", &self.listener_port)
+            .field("admin_port", &self.admin_port)
+            .finish()
+    }
+}
+
+fn envoy_command() -> Command {
+    if let Some(bin) = env::var_os("ENVOY_BIN") {
+        return Command::new(bin);
+    }
+    let on_path = |name: &str| {
+        env::var_os("PATH")
+            .is_some_and(|path| env::split_paths(&path).any(|x| x.join(name).is_file()))
+    };
+    if !on_path("envoy") && on_path("func-e") {
+        let mut command = Command::new("func-e");
+        command.arg("run");
+        return command;
+    }
+    Command::new("envoy")
+}
+
+/// `name: value` lines of the admin `/stats` output. Histograms are skipped.
+fn parse_stats(text: &str) -> HashMap<String, u64> {
+    text.lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(": ")?;
+            Some((name.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// An HTTP/1.1 request, sent on a fresh connection
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub timeout: Duration,
+}
+
+impl HttpRequest {
+    pub fn new(method: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            method: method.into(),
+            path: path.into(),
+            headers: vec![],
+            body: vec![],
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn send(&self, port: u16) -> io::Result<HttpResponse> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\ncontent-length: {}\r\n",
+            self.method,
+            self.path,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(&self.body)?;
+        let mut raw = vec![];
+        stream.read_to_end(&mut raw)?;
+        HttpResponse::parse(&raw)
+    }
+}
+
+/// A response read by [`HttpRequest::send`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// Names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// First value of a header
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &**value)
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// Parses a complete response of a closed connection
+    pub fn parse(raw: &[u8]) -> io::Result<Self> {
+        let invalid = |x: &str| io::Error::new(io::ErrorKind::InvalidData, x.to_string());
+        let split = raw
+            .windows(4)
+            .position(|x| x == b"\r\n\r\n")
+            .ok_or_else(|| invalid("incomplete response head"))?;
+        let head = std::str::from_utf8(&raw[..split]).map_err(|_| invalid("non UTF-8 head"))?;
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|x| x.split(' ').nth(1))
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| invalid("invalid status line"))?;
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|x| {
+                let (name, value) = x.split_once(':')?;
+                Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            })
+            .collect();
+        let mut response = Self {
+            status,
+            headers,
+            body: raw[split + 4..].to_vec(),
+        };
+        if response
+            .header("transfer-encoding")
+            .is_some_and(|x| x.eq_ignore_ascii_case("chunked"))
+        {
+            response.body =
+                decode_chunked(&response.body).ok_or_else(|| invalid("invalid chunked body"))?;
+        } else if let Some(length) = response
+            .header("content-length")
+            .and_then(|x| x.parse().ok())
+        {
+            response.body.truncate(length);
+        }
+        Ok(response)
+    }
+}
+
+fn decode_chunked(mut raw: &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    loop {
+        let line_end = raw.windows(2).position(|x| x == b"\r\n")?;
+        let size = std::str::from_utf8(&raw[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        raw = &raw[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(raw.get(..size)?);
+        raw = raw.get(size + 2..)?;
+    }
+}
+
+/// An upstream answering every request with 200 and a body describing it: the request line, then the headers,
+/// then the body. Stops on drop.
+pub struct EchoServer {
+    port: u16,
+    stop: Arc<AtomicBool>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl EchoServer {
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let stop = Arc::new(AtomicBool::new(false));
+        let requests = Arc::new(AtomicUsize::new(0));
+        {
+            let stop = stop.clone();
+            let requests = requests.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let requests = requests.clone();
+                    thread::spawn(move || while let Ok(true) = echo(&stream, &requests) {});
+                }
+            });
+        }
+        Ok(Self {
+            port,
+            stop,
+            requests,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Requests answered so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // wakes the accept loop
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+impl fmt::Debug for EchoServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EchoServer")
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+/// Answers one request of a keep-alive connection. `false` once the connection is done.
+fn echo(stream: &TcpStream, requests: &AtomicUsize) -> io::Result<bool> {
+    let mut reader = BufReader::new(stream);
+    let mut echoed = String::new();
+    let mut content_length = 0;
+    let mut close = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(false);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match &*name.trim().to_ascii_lowercase() {
+                "content-length" => content_length = value.parse().unwrap_or_default(),
+                "connection" => close = value.eq_ignore_ascii_case("close"),
+                _ => (),
+            }
+        }
+        echoed.push_str(line);
+        echoed.push('\n');
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let mut body_echo = echoed.into_bytes();
+    body_echo.push(b'\n');
+    body_echo.extend_from_slice(&body);
+    let mut writer = stream;
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n\r\n",
+        body_echo.len()
+    )?;
+    writer.write_all(&body_echo)?;
+    requests.fetch_add(1, Ordering::Relaxed);
+    if close {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    Ok(!close)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let config = EnvoyConfig::new("/tmp/plugin.wasm", 8080)
+            .name("mini_proxy")
+            .configuration(r#"{"mode":"block"}"#);
+        let yaml = config.render();
+        assert!(yaml.contains("port_value: 8080"));
+        assert!(yaml.contains(r#"value: "{\"mode\":\"block\"}""#));
+        assert!(yaml.contains(r#"filename: "/tmp/plugin.wasm""#));
+        assert!(yaml.contains("configuration: {}"));
+    }
+
+    #[test]
+    fn test_http() {
+        let response = HttpResponse::parse(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.body, b"abcde");
+        assert_eq!(
+            parse_stats("wasm.plugin.hits: 3\nhistogram: P0(nan,1)\n").get("wasm.plugin.hits"),
+            Some(&3)
+        );
+
+        let server = EchoServer::start().unwrap();
+        let response = HttpRequest::new("POST", "/echo")
+            .header("x-a", "1")
+            .body("hello")
+            .send(server.port())
+            .unwrap();
+        assert_eq!(response.status, 200);
+        let text = response.text();
+        assert!(text.starts_with("POST /echo HTTP/1.1\n"));
+        assert!(text.contains("x-a: 1\n"));
+        assert!(text.ends_with("\n\nhello"));
+        assert_eq!(server.requests(), 1);
+    }
+}
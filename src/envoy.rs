@@ -4,15 +4,63 @@ use prost::Message;
 
 use crate::upstream::Upstream;
 
-use self::grpc_service::grpc_service::{
-    google_grpc::{channel_credentials::CredentialSpecifier, ChannelCredentials, SslCredentials},
-    EnvoyGrpc, GoogleGrpc, TargetSpecifier,
+use self::grpc_service::{
+    data_source::Specifier,
+    grpc_service::{
+        google_grpc::{
+            channel_args::{value::ValueSpecifier, Value},
+            channel_credentials::CredentialSpecifier,
+            ChannelArgs, ChannelCredentials, SslCredentials,
+        },
+        EnvoyGrpc, GoogleGrpc, TargetSpecifier,
+    },
+    DataSource,
 };
 
 mod grpc_service {
     include!(concat!(env!("OUT_DIR"), "/envoy.config.core.v3.rs"));
 }
 
+/// Certificate verification options for [`Upstream::grpc_upstream_tls`].
+///
+/// The Google gRPC client has no SPKI pinning; pin keys on an Envoy cluster's validation context and use [`Upstream::envoy_upstream`] instead.
+#[derive(Default, Debug, Clone)]
+pub struct GrpcTls {
+    /// PEM encoded roots trusted for the server certificate. Default is the system roots.
+    pub root_certs: Option<String>,
+    /// PEM encoded client certificate chain and private key, for mutual TLS
+    pub client_cert: Option<(String, String)>,
+    /// Name the server certificate must present as a SAN, instead of the host of the target URI
+    pub expected_san: Option<String>,
+}
+
+impl GrpcTls {
+    pub fn root_certs(mut self, pem: impl ToString) -> Self {
+        self.root_certs = Some(pem.to_string());
+        self
+    }
+
+    pub fn client_cert(
+        mut self,
+        cert_chain_pem: impl ToString,
+        private_key_pem: impl ToString,
+    ) -> Self {
+        self.client_cert = Some((cert_chain_pem.to_string(), private_key_pem.to_string()));
+        self
+    }
+
+    pub fn expected_san(mut self, name: impl ToString) -> Self {
+        self.expected_san = Some(name.to_string());
+        self
+    }
+}
+
+fn inline(value: &str) -> Option<DataSource> {
+    Some(DataSource {
+        specifier: Some(Specifier::InlineString(value.to_string())),
+    })
+}
+
 impl<'a> Upstream<'a> {
     /// Creates an Envoy-compatible upstream configuration for the given upstream cluster name
     pub fn envoy_upstream(cluster_name: impl ToString, authority: impl ToString) -> Self {
@@ -79,4 +127,48 @@ impl<'a> Upstream<'a> {
         };
         Self(Cow::Owned(service.encode_to_vec()))
     }
+
+    /// Like [`Upstream::grpc_upstream`], but verifies the server certificate against `tls`.
+    /// Verification failures are reported by `tls_validation_failed` on the call or stream response.
+    pub fn grpc_upstream_tls(target_uri: impl ToString, tls: GrpcTls) -> Self {
+        let target_uri = target_uri.to_string();
+        let target_uri = target_uri
+            .strip_prefix("https://")
+            .unwrap_or(&*target_uri)
+            .to_string();
+        let mut channel_args = ChannelArgs::default();
+        if let Some(san) = &tls.expected_san {
+            channel_args.args.insert(
+                "grpc.ssl_target_name_override".to_string(),
+                Value {
+                    value_specifier: Some(ValueSpecifier::StringValue(san.clone())),
+                },
+            );
+        }
+        let service = grpc_service::GrpcService {
+            target_specifier: Some(TargetSpecifier::GoogleGrpc(GoogleGrpc {
+                channel_credentials: Some(ChannelCredentials {
+                    credential_specifier: Some(CredentialSpecifier::SslCredentials(
+                        SslCredentials {
+                            root_certs: tls.root_certs.as_deref().and_then(inline),
+                            private_key: tls.client_cert.as_ref().and_then(|(_, key)| inline(key)),
+                            cert_chain: tls
+                                .client_cert
+                                .as_ref()
+                                .and_then(|(chain, _)| inline(chain)),
+                        },
+                    )),
+                }),
+                target_uri,
+                call_credentials: vec![],
+                channel_args: Some(channel_args),
+                config: Default::default(),
+                credentials_factory_name: String::new(),
+                per_stream_buffer_limit_bytes: None,
+                stat_prefix: "leaksignal_command".to_string(),
+            })),
+            ..Default::default()
+        };
+        Self(Cow::Owned(service.encode_to_vec()))
+    }
 }
@@ -0,0 +1,258 @@
+//! Single-threaded executor for sequential async code, i.e. several outbound calls in a row from a root context.
+//!
+//! Tasks are polled right after the host callback that woke them returns, once the SDK no longer borrows its contexts.
+//! While polling, the effective context is the one active when the task was spawned, or its root context if that context is gone.
+//!
+//! ```ignore
+//! fn on_tick(&mut self) {
+//!     spawn_local(async {
+//!         let Ok(token) = HttpCallBuilder::default()/* .. */.build().unwrap().dispatch_async() else { return };
+//!         let token = token.await;
+//!         let Ok(config) = GrpcCallBuilder::default()/* .. */.build().unwrap().dispatch_async() else { return };
+//!         let config = config.await;
+//!         with_root(|root: &mut MyRoot| root.config = config.body);
+//!     });
+//! }
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+};
+
+use log::debug;
+
+use crate::{dispatcher, RootContext};
+
+/// Upper bound on task polls after a single host event, in case tasks keep waking each other.
+/// Any remaining tasks are polled after the next event.
+const MAX_POLLS_PER_EVENT: usize = 1024;
+
+struct Task {
+    context_id: u32,
+    root_context_id: u32,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+#[derive(Default)]
+struct Executor {
+    next_id: Cell<u64>,
+    tasks: RefCell<HashMap<u64, Task>>,
+    ready: RefCell<VecDeque<u64>>,
+}
+
+thread_local! {
+    static EXECUTOR: Executor = Executor::default();
+}
+
+struct TaskWaker(u64);
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        EXECUTOR.with(|x| x.ready.borrow_mut().push_back(self.0));
+    }
+}
+
+/// Spawns a task in the active context. It is first polled right after the current host callback returns.
+/// Tasks are dropped when their root context is deleted.
+pub fn spawn_local(future: impl Future<Output = ()> + 'static) {
+    let task = Task {
+        context_id: crate::current_context_id(),
+        root_context_id: dispatcher::root_id(),
+        future: Box::pin(future),
+    };
+    EXECUTOR.with(|x| {
+        let id = x.next_id.get();
+        x.next_id.set(id + 1);
+        x.tasks.borrow_mut().insert(id, task);
+        x.ready.borrow_mut().push_back(id);
+    });
+}
+
+/// Runs `f` with the root context of the running task. `None` outside of a task, where the SDK borrows the root context.
+pub fn with_root<R: RootContext + 'static, T>(f: impl FnOnce(&mut R) -> T) -> Option<T> {
+    dispatcher::with_active_root(|root| {
+        f(root.as_any_mut().downcast_mut().expect("invalid root type"))
+    })
+}
+
+/// Polls woken tasks. Returns the number of polls.
+pub(crate) fn run_ready() -> usize {
+    for i in 0..MAX_POLLS_PER_EVENT {
+        let Some(id) = EXECUTOR.with(|x| x.ready.borrow_mut().pop_front()) else {
+            return i;
+        };
+        // taken out while polling, so the task can spawn others
+        let Some(mut task) = EXECUTOR.with(|x| x.tasks.borrow_mut().remove(&id)) else {
+            continue;
+        };
+        let waker = Waker::from(Arc::new(TaskWaker(id)));
+        let poll = dispatcher::in_task_context(task.context_id, task.root_context_id, || {
+            task.future.as_mut().poll(&mut Context::from_waker(&waker))
+        });
+        match poll {
+            Some(Poll::Pending) => {
+                EXECUTOR.with(|x| x.tasks.borrow_mut().insert(id, task));
+            }
+            Some(Poll::Ready(())) => (),
+            None => debug!("dropping task for non-existing root context"),
+        }
+    }
+    MAX_POLLS_PER_EVENT
+}
+
+/// Drops the tasks of a deleted root context
+pub(crate) fn drop_root_tasks(root_context_id: u32) {
+    let dropped: Vec<Task> = EXECUTOR.with(|x| {
+        let mut tasks = x.tasks.borrow_mut();
+        let ids: Vec<u64> = tasks
+            .iter()
+            .filter(|(_, task)| task.root_context_id == root_context_id)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter().filter_map(|id| tasks.remove(&id)).collect()
+    });
+    drop(dropped);
+}
+
+pub(crate) fn reset() {
+    let dropped = EXECUTOR.with(|x| {
+        x.ready.borrow_mut().clear();
+        std::mem::take(&mut *x.tasks.borrow_mut())
+    });
+    drop(dropped);
+}
+
+struct Slot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Future of a response delivered by a host callback, i.e. from [`crate::HttpCall::dispatch_async`].
+/// Stays pending if the callback never runs, i.e. when the context that dispatched the call is deleted first.
+pub struct ResponseFuture<T> {
+    slot: Rc<RefCell<Slot<T>>>,
+}
+
+/// Completes a [`ResponseFuture`]
+pub(crate) struct ResponseSender<T> {
+    slot: Rc<RefCell<Slot<T>>>,
+}
+
+impl<T> ResponseFuture<T> {
+    pub(crate) fn new() -> (Self, ResponseSender<T>) {
+        let slot = Rc::new(RefCell::new(Slot {
+            value: None,
+            waker: None,
+        }));
+        (Self { slot: slot.clone() }, ResponseSender { slot })
+    }
+}
+
+impl<T> ResponseSender<T> {
+    pub(crate) fn send(self, value: T) {
+        let waker = {
+            let mut slot = self.slot.borrow_mut();
+            slot.value = Some(value);
+            slot.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Future for ResponseFuture<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = self.slot.borrow_mut();
+        match slot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for ResponseFuture<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseFuture")
+            .field("ready", &self.slot.borrow().value.is_some())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        dispatcher::{proxy_on_context_create, proxy_on_tick},
+        testing::reset_host,
+        BaseContext, Context, HttpContext,
+    };
+
+    thread_local! {
+        static SENDER: RefCell<Option<ResponseSender<u32>>> = const { RefCell::new(None) };
+        static RESPONSES: RefCell<Vec<(u32, u32)>> = const { RefCell::new(vec![]) };
+    }
+
+    #[derive(Default)]
+    struct Root {
+        ticks: u32,
+    }
+
+    struct Filter;
+
+    impl BaseContext for Filter {}
+
+    impl HttpContext for Filter {}
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn create_context(&mut self) -> Context {
+            Context::Http(Box::new(Filter))
+        }
+
+        fn on_tick(&mut self) {
+            self.ticks += 1;
+            if self.ticks > 1 {
+                return;
+            }
+            assert!(with_root(|_: &mut Root| ()).is_none());
+            let (future, sender) = ResponseFuture::new();
+            SENDER.with_borrow_mut(|x| *x = Some(sender));
+            spawn_local(async move {
+                let response = future.await;
+                let ticks = with_root(|root: &mut Root| root.ticks).unwrap();
+                RESPONSES.with_borrow_mut(|x| x.push((ticks, response)));
+            });
+        }
+    }
+
+    #[test]
+    fn test_spawn_local() {
+        reset_host();
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        proxy_on_tick(1);
+        proxy_on_tick(1);
+        assert_eq!(dispatcher::dispatch_pending(), 0);
+        SENDER.with_borrow_mut(|x| x.take()).unwrap().send(7);
+        assert_eq!(dispatcher::dispatch_pending(), 1);
+        assert_eq!(RESPONSES.with_borrow(|x| x.clone()), vec![(2, 7)]);
+    }
+}
@@ -0,0 +1,594 @@
+//! A small expression language for conditions in plugin configuration, i.e.
+//! `request.path startsWith "/admin" && !connection.mtls`.
+//!
+//! Expressions are compiled once with [`Expr::compile`], typically in `on_configure`, and evaluated per request.
+//! They cannot loop or call into the host beyond reading attributes and headers, so they are safe to take from config.
+//!
+//! * Literals: `"string"` (with `\"`, `\\`, `\n` and `\t` escapes), integers, `true`, `false` and `null`
+//! * Attributes by their Envoy name, i.e. `request.method`, `source.address` or `connection.mtls`. Unset attributes are `null`.
+//! * Request headers as `request.headers["x-api-key"]` or `header("x-api-key")`
+//! * Comparisons `==`, `!=`, `<`, `<=`, `>`, `>=`, and the string operators `startsWith`, `endsWith`, `contains`
+//!   and `matches`, which takes a regex literal
+//! * Boolean operators `!`, `&&` and `||`, and parentheses
+//! * Functions `lower(x)`, `upper(x)`, `trim(x)`, `size(x)` and `has(x)`, which is `true` if `x` is not `null`
+//!
+//! Operators on values of the wrong type evaluate to `false` rather than failing. As a condition, `null`, `false`, `0` and
+//! `""` are false and everything else is true.
+
+use std::fmt;
+
+use regex::Regex;
+
+use crate::{property::envoy::Attributes, HttpHeaderControl, RequestHeaders};
+
+/// Error of [`Expr::compile`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExprError {
+    /// Byte offset of the error in the source
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+/// Value of an expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    String(String),
+}
+
+impl Value {
+    /// Whether this value counts as true in a condition
+    pub fn truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(x) => *x,
+            Value::Int(x) => *x != 0,
+            Value::String(x) => !x.is_empty(),
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl From<Option<String>> for Value {
+    fn from(value: Option<String>) -> Self {
+        value.map_or(Value::Null, Value::String)
+    }
+}
+
+impl From<Option<bool>> for Value {
+    fn from(value: Option<bool>) -> Self {
+        value.map_or(Value::Null, Value::Bool)
+    }
+}
+
+fn int(value: Option<impl TryInto<i64>>) -> Value {
+    value
+        .and_then(|x| x.try_into().ok())
+        .map_or(Value::Null, Value::Int)
+}
+
+fn string(value: Option<impl ToString>) -> Value {
+    value.map_or(Value::Null, |x| Value::String(x.to_string()))
+}
+
+type Getter = fn(&Attributes) -> Value;
+
+/// Attributes available to expressions, by their Envoy name
+const ATTRIBUTES: &[(&str, Getter)] = &[
+    ("request.path", |a| a.request.path().into()),
+    ("request.url_path", |a| a.request.url_path().into()),
+    ("request.host", |a| a.request.host().into()),
+    ("request.scheme", |a| a.request.scheme().into()),
+    ("request.method", |a| a.request.method().into()),
+    ("request.referer", |a| a.request.referer().into()),
+    ("request.useragent", |a| a.request.useragent().into()),
+    ("request.id", |a| a.request.id().into()),
+    ("request.protocol", |a| a.request.protocol().into()),
+    ("request.query", |a| a.request.query().into()),
+    ("request.size", |a| int(a.request.size())),
+    ("request.total_size", |a| int(a.request.total_size())),
+    ("response.code", |a| int(a.response.code())),
+    ("response.code_details", |a| {
+        a.response.code_details().into()
+    }),
+    ("response.grpc_status", |a| int(a.response.grpc_status())),
+    ("source.address", |a| string(a.connection.source_address())),
+    ("source.port", |a| int(a.connection.source_port())),
+    ("destination.address", |a| {
+        string(a.connection.destination_address())
+    }),
+    ("destination.port", |a| int(a.connection.destination_port())),
+    ("connection.id", |a| int(a.connection.id())),
+    ("connection.mtls", |a| a.connection.mtls().into()),
+    ("connection.requested_server_name", |a| {
+        a.connection.requested_server_name().into()
+    }),
+    ("connection.tls_version", |a| {
+        a.connection.tls_version().into()
+    }),
+    ("connection.subject_peer_certificate", |a| {
+        a.connection.subject_peer_certificate().into()
+    }),
+    ("connection.dns_san_peer_certificate", |a| {
+        a.connection.dns_san_peer_certificate().into()
+    }),
+    ("connection.uri_san_peer_certificate", |a| {
+        a.connection.uri_san_peer_certificate().into()
+    }),
+    ("upstream.address", |a| string(a.upstream.address())),
+    ("upstream.port", |a| int(a.upstream.port())),
+    ("xds.cluster_name", |a| {
+        a.configuration.cluster_name().into()
+    }),
+    ("xds.route_name", |a| a.configuration.route_name().into()),
+    ("xds.filter_chain_name", |a| {
+        a.configuration.filter_chain_name().into()
+    }),
+    ("plugin_name", |a| a.wasm.plugin_name().into()),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Function {
+    Lower,
+    Upper,
+    Trim,
+    Size,
+    Has,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Literal(Value),
+    Attribute(&'static str, Getter),
+    Header(String),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(Compare, Box<Node>, Box<Node>),
+    Matches(Box<Node>, Regex),
+    Call(Function, Box<Node>),
+}
+
+/// What an expression reads from
+trait Scope {
+    fn attribute(&self, name: &'static str, getter: Getter) -> Value;
+
+    fn header(&self, name: &str) -> Option<String>;
+}
+
+impl Scope for (&Attributes, &RequestHeaders) {
+    fn attribute(&self, _name: &'static str, getter: Getter) -> Value {
+        getter(self.0)
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.1
+            .get(name)
+            .map(|x| String::from_utf8_lossy(&x).into_owned())
+    }
+}
+
+impl Node {
+    fn eval(&self, scope: &impl Scope) -> Value {
+        match self {
+            Node::Literal(x) => x.clone(),
+            Node::Attribute(name, getter) => scope.attribute(name, *getter),
+            Node::Header(name) => scope.header(name).into(),
+            Node::Not(x) => Value::Bool(!x.eval(scope).truthy()),
+            Node::And(a, b) => Value::Bool(a.eval(scope).truthy() && b.eval(scope).truthy()),
+            Node::Or(a, b) => Value::Bool(a.eval(scope).truthy() || b.eval(scope).truthy()),
+            Node::Compare(op, a, b) => Value::Bool(compare(*op, &a.eval(scope), &b.eval(scope))),
+            Node::Matches(x, regex) => {
+                Value::Bool(x.eval(scope).as_str().is_some_and(|x| regex.is_match(x)))
+            }
+            Node::Call(function, x) => match (function, x.eval(scope)) {
+                (Function::Has, x) => Value::Bool(x != Value::Null),
+                (Function::Lower, Value::String(x)) => Value::String(x.to_lowercase()),
+                (Function::Upper, Value::String(x)) => Value::String(x.to_uppercase()),
+                (Function::Trim, Value::String(x)) => Value::String(x.trim().to_string()),
+                (Function::Size, Value::String(x)) => Value::Int(x.len() as i64),
+                _ => Value::Null,
+            },
+        }
+    }
+}
+
+fn compare(op: Compare, a: &Value, b: &Value) -> bool {
+    let ordering = match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    let strings = a.as_str().zip(b.as_str());
+    match op {
+        Compare::Eq => a == b,
+        Compare::Ne => a != b,
+        Compare::Lt => ordering.is_some_and(|x| x.is_lt()),
+        Compare::Le => ordering.is_some_and(|x| x.is_le()),
+        Compare::Gt => ordering.is_some_and(|x| x.is_gt()),
+        Compare::Ge => ordering.is_some_and(|x| x.is_ge()),
+        Compare::StartsWith => strings.is_some_and(|(a, b)| a.starts_with(b)),
+        Compare::EndsWith => strings.is_some_and(|(a, b)| a.ends_with(b)),
+        Compare::Contains => strings.is_some_and(|(a, b)| a.contains(b)),
+    }
+}
+
+/// A compiled expression
+#[derive(Clone, Debug)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    /// Parses `source`, resolving attribute names and compiling regexes
+    pub fn compile(source: impl Into<String>) -> Result<Self, ExprError> {
+        let source = source.into();
+        let mut parser = Parser {
+            tokens: tokenize(&source)?,
+            position: 0,
+            end: source.len(),
+        };
+        let root = parser.or()?;
+        if let Some((offset, token)) = parser.tokens.get(parser.position) {
+            return Err(error(*offset, format!("unexpected {token}")));
+        }
+        Ok(Self { source, root })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluates the expression for the current request
+    pub fn eval(&self, attributes: &Attributes, headers: &RequestHeaders) -> Value {
+        self.root.eval(&(attributes, headers))
+    }
+
+    /// Evaluates the expression as a condition, see [`Value::truthy`]
+    pub fn matches(&self, attributes: &Attributes, headers: &RequestHeaders) -> bool {
+        self.eval(attributes, headers).truthy()
+    }
+}
+
+fn error(offset: usize, message: impl Into<String>) -> ExprError {
+    ExprError {
+        offset,
+        message: message.into(),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Int(i64),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(x) => write!(f, "'{x}'"),
+            Token::String(x) => write!(f, "{x:?}"),
+            Token::Int(x) => write!(f, "{x}"),
+            Token::Symbol(x) => write!(f, "'{x}'"),
+        }
+    }
+}
+
+const SYMBOLS: &[&str] = &[
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "[", "]", ",",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let bytes = source.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = if c == b'"' {
+            let mut value = String::new();
+            let mut chars = source[i + 1..].char_indices();
+            loop {
+                let Some((offset, c)) = chars.next() else {
+                    return Err(error(start, "unterminated string"));
+                };
+                match c {
+                    '"' => {
+                        i += offset + 2;
+                        break;
+                    }
+                    '\\' => match chars.next() {
+                        Some((_, '"')) => value.push('"'),
+                        Some((_, '\\')) => value.push('\\'),
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 't')) => value.push('\t'),
+                        _ => return Err(error(i + 1 + offset, "invalid escape")),
+                    },
+                    c => value.push(c),
+                }
+            }
+            Token::String(value)
+        } else if c.is_ascii_digit()
+            || (c == b'-' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+        {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            Token::Int(
+                source[start..i]
+                    .parse()
+                    .map_err(|_| error(start, "integer out of range"))?,
+            )
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len()
+                && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_' || bytes[i] == b'.')
+            {
+                i += 1;
+            }
+            Token::Ident(source[start..i].to_string())
+        } else {
+            let Some(symbol) = SYMBOLS.iter().find(|x| source[i..].starts_with(**x)) else {
+                return Err(error(start, "unexpected character"));
+            };
+            i += symbol.len();
+            Token::Symbol(symbol)
+        };
+        out.push((start, token));
+    }
+    Ok(out)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|x| &x.1)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.end, |x| x.0)
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), ExprError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| error(self.end, "unexpected end of expression"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(x)) if *x == symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ExprError> {
+        if !self.eat(symbol) {
+            return Err(error(self.offset(), format!("expected '{symbol}'")));
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Node, ExprError> {
+        let mut node = self.and()?;
+        while self.eat("||") {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, ExprError> {
+        let mut node = self.comparison()?;
+        while self.eat("&&") {
+            node = Node::And(Box::new(node), Box::new(self.comparison()?));
+        }
+        Ok(node)
+    }
+
+    fn comparison(&mut self) -> Result<Node, ExprError> {
+        let left = self.unary()?;
+        let op = match self.peek() {
+            Some(Token::Symbol("==")) => Compare::Eq,
+            Some(Token::Symbol("!=")) => Compare::Ne,
+            Some(Token::Symbol("<")) => Compare::Lt,
+            Some(Token::Symbol("<=")) => Compare::Le,
+            Some(Token::Symbol(">")) => Compare::Gt,
+            Some(Token::Symbol(">=")) => Compare::Ge,
+            Some(Token::Ident(x)) if x == "startsWith" => Compare::StartsWith,
+            Some(Token::Ident(x)) if x == "endsWith" => Compare::EndsWith,
+            Some(Token::Ident(x)) if x == "contains" => Compare::Contains,
+            Some(Token::Ident(x)) if x == "matches" => {
+                self.position += 1;
+                let (offset, token) = self.next()?;
+                let Token::String(pattern) = token else {
+                    return Err(error(offset, "'matches' takes a string literal"));
+                };
+                let regex = Regex::new(&pattern).map_err(|e| error(offset, e.to_string()))?;
+                return Ok(Node::Matches(Box::new(left), regex));
+            }
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(Node::Compare(op, Box::new(left), Box::new(self.unary()?)))
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Node, ExprError> {
+        let (offset, token) = self.next()?;
+        let name = match token {
+            Token::String(x) => return Ok(Node::Literal(Value::String(x))),
+            Token::Int(x) => return Ok(Node::Literal(Value::Int(x))),
+            Token::Symbol("(") => {
+                let node = self.or()?;
+                self.expect(")")?;
+                return Ok(node);
+            }
+            Token::Symbol(_) => return Err(error(offset, format!("unexpected {token}"))),
+            Token::Ident(x) => x,
+        };
+        match &*name {
+            "true" => return Ok(Node::Literal(Value::Bool(true))),
+            "false" => return Ok(Node::Literal(Value::Bool(false))),
+            "null" => return Ok(Node::Literal(Value::Null)),
+            _ => (),
+        }
+        if self.eat("(") {
+            let node = if name == "header" {
+                Node::Header(self.header_name()?)
+            } else {
+                let function = match &*name {
+                    "lower" => Function::Lower,
+                    "upper" => Function::Upper,
+                    "trim" => Function::Trim,
+                    "size" => Function::Size,
+                    "has" => Function::Has,
+                    _ => return Err(error(offset, format!("unknown function '{name}'"))),
+                };
+                Node::Call(function, Box::new(self.or()?))
+            };
+            self.expect(")")?;
+            return Ok(node);
+        }
+        if name == "request.headers" {
+            self.expect("[")?;
+            let node = Node::Header(self.header_name()?);
+            self.expect("]")?;
+            return Ok(node);
+        }
+        match ATTRIBUTES.iter().find(|(x, _)| *x == name) {
+            Some((name, getter)) => Ok(Node::Attribute(name, *getter)),
+            None => Err(error(offset, format!("unknown attribute '{name}'"))),
+        }
+    }
+
+    fn header_name(&mut self) -> Result<String, ExprError> {
+        match self.next()? {
+            (_, Token::String(x)) => Ok(x.to_ascii_lowercase()),
+            (offset, _) => Err(error(offset, "expected a header name")),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    struct MockScope {
+        attributes: HashMap<&'static str, Value>,
+        headers: HashMap<&'static str, &'static str>,
+    }
+
+    impl Scope for MockScope {
+        fn attribute(&self, name: &'static str, _getter: Getter) -> Value {
+            self.attributes.get(name).cloned().unwrap_or(Value::Null)
+        }
+
+        fn header(&self, name: &str) -> Option<String> {
+            self.headers.get(name).map(|x| x.to_string())
+        }
+    }
+
+    fn eval(source: &str, scope: &MockScope) -> Value {
+        Expr::compile(source).unwrap().root.eval(scope)
+    }
+
+    #[test]
+    fn test_expr() {
+        let scope = MockScope {
+            attributes: [
+                ("request.path", Value::String("/admin/users".to_string())),
+                ("response.code", Value::Int(404)),
+            ]
+            .into(),
+            headers: [("x-tenant", " Acme ")].into(),
+        };
+        let condition = r#"request.path startsWith "/admin" && !connection.mtls"#;
+        assert_eq!(eval(condition, &scope), Value::Bool(true));
+        assert_eq!(
+            eval("response.code >= 400 && response.code < 500", &scope),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            eval(r#"lower(trim(header("X-Tenant"))) == "acme""#, &scope),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            eval(r#"request.headers["x-tenant"] contains "cm""#, &scope),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            eval(r#"request.path matches "^/admin/\\w+$""#, &scope),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            eval("has(request.host) || size(request.path)", &scope),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            eval(r#"response.code == "404""#, &scope),
+            Value::Bool(false)
+        );
+        assert_eq!(eval("(false || null) == false", &scope), Value::Bool(true));
+
+        let error = Expr::compile("request.paht == 1").unwrap_err();
+        assert_eq!(error.offset, 0);
+        assert!(Expr::compile("request.path ==").is_err());
+        assert!(Expr::compile(r#"request.path matches "(""#).is_err());
+        assert!(Expr::compile("(true").is_err());
+        assert!(Expr::compile("true true").is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Compatibility layer for Envoy's ext_authz gRPC API (`envoy.service.auth.v3.Authorization`), letting plugins call existing external authorization servers.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use log::warn;
+use prost::Message;
+use prost_types::Timestamp;
+
+use crate::{
+    auth::{AuthFailure, Decision, FailurePolicy},
+    property::envoy::Attributes,
+    GrpcCall, GrpcCallResponse, GrpcCancelHandle, GrpcCode, HttpControl, HttpHeaderControl,
+    RequestHeaders, ResponseHeaders, RootContext, Status, Upstream,
+};
+
+/// Generated ext_authz messages
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/envoy.service.auth.v3.rs"));
+}
+use proto::{
+    address, attribute_context, check_response::HttpResponse,
+    header_value_option::HeaderAppendAction, socket_address, Address, AttributeContext,
+    CheckRequest, CheckResponse, HeaderValueOption, SocketAddress,
+};
+
+/// Builds a `CheckRequest` for the current request from its attributes and `headers`, optionally including the request `body`.
+pub fn check_request(headers: &impl HttpHeaderControl, body: Option<&[u8]>) -> CheckRequest {
+    let attributes = Attributes::get();
+    let connection = &attributes.connection;
+    let request = &attributes.request;
+
+    let mut header_map: HashMap<String, String> = HashMap::new();
+    for (name, value) in headers.all() {
+        let value = String::from_utf8_lossy(&value);
+        header_map
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|x| {
+                x.push(',');
+                x.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+
+    let mut http = attribute_context::HttpRequest {
+        id: request.id().unwrap_or_default(),
+        method: request.method().unwrap_or_default(),
+        path: request.path().unwrap_or_default(),
+        host: request.host().unwrap_or_default(),
+        scheme: request.scheme().unwrap_or_default(),
+        size: request.size().map(|x| x as i64).unwrap_or(-1),
+        protocol: request.protocol().unwrap_or_default(),
+        headers: header_map,
+        ..Default::default()
+    };
+    if let Some(body) = body {
+        match std::str::from_utf8(body) {
+            Ok(body) => http.body = body.to_string(),
+            Err(_) => http.raw_body = body.to_vec(),
+        }
+    }
+
+    CheckRequest {
+        attributes: Some(AttributeContext {
+            source: Some(attribute_context::Peer {
+                address: connection.source_address().map(socket_address),
+                principal: connection
+                    .uri_san_peer_certificate()
+                    .or_else(|| connection.dns_san_peer_certificate())
+                    .unwrap_or_default(),
+                ..Default::default()
+            }),
+            destination: Some(attribute_context::Peer {
+                address: connection.destination_address().map(socket_address),
+                principal: connection
+                    .uri_san_local_certificate()
+                    .or_else(|| connection.dns_san_local_certificate())
+                    .unwrap_or_default(),
+                ..Default::default()
+            }),
+            request: Some(attribute_context::Request {
+                time: request.time().map(|x| {
+                    let since_epoch = x.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    Timestamp {
+                        seconds: since_epoch.as_secs() as i64,
+                        nanos: since_epoch.subsec_nanos() as i32,
+                    }
+                }),
+                http: Some(http),
+            }),
+            context_extensions: HashMap::new(),
+            tls_session: connection
+                .requested_server_name()
+                .filter(|x| !x.is_empty())
+                .map(|sni| attribute_context::TlsSession { sni }),
+        }),
+    }
+}
+
+fn socket_address(address: SocketAddr) -> Address {
+    Address {
+        address: Some(address::Address::SocketAddress(SocketAddress {
+            address: address.ip().to_string(),
+            port_specifier: Some(socket_address::PortSpecifier::PortValue(
+                address.port() as u32
+            )),
+        })),
+    }
+}
+
+/// Client for an ext_authz server.
+#[derive(Clone, Debug)]
+pub struct ExtAuthz {
+    pub upstream: Upstream<'static>,
+    /// Timeout for the `Check` call. Default is 200ms like Envoy.
+    pub timeout: Duration,
+    /// Sent as `context_extensions` with every check
+    pub context_extensions: HashMap<String, String>,
+}
+
+impl ExtAuthz {
+    const SERVICE: &'static str = "envoy.service.auth.v3.Authorization";
+    const METHOD: &'static str = "Check";
+
+    pub fn new(upstream: Upstream<'static>) -> Self {
+        Self {
+            upstream,
+            timeout: Duration::from_millis(200),
+            context_extensions: HashMap::new(),
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn context_extension(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.context_extensions.insert(key.into(), value.into());
+        self
+    }
+
+    /// Sends a `CheckRequest` for the current request. HTTP filters should pause until `callback` is called.
+    pub fn check<R: RootContext + 'static>(
+        &self,
+        headers: &impl HttpHeaderControl,
+        body: Option<&[u8]>,
+        callback: impl FnOnce(&mut R, Result<CheckResponse, AuthFailure>) + 'static,
+    ) -> Result<GrpcCancelHandle, Status> {
+        let mut request = check_request(headers, body);
+        if let Some(attributes) = &mut request.attributes {
+            attributes.context_extensions = self.context_extensions.clone();
+        }
+        let message = request.encode_to_vec();
+        GrpcCall {
+            upstream: self.upstream.clone(),
+            service: Self::SERVICE,
+            method: Self::METHOD,
+            initial_metadata: vec![],
+            message: Some(message.into()),
+            timeout: Some(self.timeout),
+            callback: Some(Box::new(move |root, response| {
+                callback(
+                    root.as_any_mut().downcast_mut().expect("invalid root type"),
+                    Self::parse_response(response),
+                )
+            })),
+        }
+        .dispatch()
+    }
+
+    fn parse_response(response: &GrpcCallResponse) -> Result<CheckResponse, AuthFailure> {
+        if response.status_code() != GrpcCode::Ok {
+            return Err(AuthFailure::from_grpc(
+                response.status_code(),
+                response.status_message(),
+            ));
+        }
+        CheckResponse::decode(&*response.full_body().unwrap_or_default())
+            .map_err(|e| AuthFailure::Backend(format!("invalid CheckResponse: {e}")))
+    }
+}
+
+/// Converts a `CheckResponse` into a [`Decision`], ignoring any header mutations.
+pub fn decision(response: &CheckResponse) -> Decision {
+    if response.status.as_ref().map(|x| x.code).unwrap_or_default() == 0 {
+        return Decision::Allow;
+    }
+    match &response.http_response {
+        Some(HttpResponse::DeniedResponse(denied)) => Decision::Deny {
+            status_code: denied
+                .status
+                .as_ref()
+                .map(|x| x.code)
+                .filter(|x| *x > 0)
+                .unwrap_or(403) as u32,
+            body: Some(denied.body.as_bytes().to_vec()).filter(|x| !x.is_empty()),
+        },
+        _ => Decision::Deny {
+            status_code: 403,
+            body: None,
+        },
+    }
+}
+
+/// Result of [`apply_check_result`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CheckOutcome {
+    pub decision: Decision,
+    /// Headers the server asked to add to the downstream response. Apply them with [`CheckOutcome::apply_response_headers`].
+    pub response_headers_to_add: Vec<HeaderValueOption>,
+}
+
+impl CheckOutcome {
+    /// Applies `response_headers_to_add`, to be called from [`crate::HttpContext::on_http_response_headers`].
+    pub fn apply_response_headers(&self, headers: &ResponseHeaders) {
+        for option in &self.response_headers_to_add {
+            apply_header_option(headers, option);
+        }
+    }
+}
+
+/// Applies the result of [`ExtAuthz::check`] to the paused request, from within the check callback.
+/// Allowed requests get their header and query mutations applied and are resumed, denied requests are sent the server's local response.
+/// Failures are resolved with `policy`.
+pub fn apply_check_result(
+    result: Result<CheckResponse, AuthFailure>,
+    policy: &FailurePolicy,
+) -> CheckOutcome {
+    let request = RequestHeaders {
+        header_count: 0,
+        end_of_stream: false,
+        attributes: Attributes::get(),
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(failure) => {
+            let decision = policy.resolve(&failure);
+            decision.apply(&request);
+            return CheckOutcome {
+                decision,
+                response_headers_to_add: vec![],
+            };
+        }
+    };
+    let decision = decision(&response);
+    let mut response_headers_to_add = vec![];
+    match (&decision, response.http_response) {
+        (Decision::Allow, Some(HttpResponse::OkResponse(ok))) => {
+            for option in &ok.headers {
+                apply_header_option(&request, option);
+            }
+            for name in &ok.headers_to_remove {
+                request.remove(name);
+            }
+            if !ok.query_parameters_to_set.is_empty() || !ok.query_parameters_to_remove.is_empty() {
+                if let Some(path) = request.get(":path") {
+                    let set = ok
+                        .query_parameters_to_set
+                        .iter()
+                        .map(|x| (x.key.as_str(), x.value.as_str()))
+                        .collect::<Vec<_>>();
+                    let path = rewrite_query(
+                        &String::from_utf8_lossy(&path),
+                        &set,
+                        &ok.query_parameters_to_remove,
+                    );
+                    request.set(":path", path);
+                }
+            }
+            response_headers_to_add = ok.response_headers_to_add;
+            request.resume();
+        }
+        (Decision::Deny { status_code, body }, http_response) => {
+            let headers = match &http_response {
+                Some(HttpResponse::DeniedResponse(denied)) => denied
+                    .headers
+                    .iter()
+                    .filter_map(|x| x.header.as_ref())
+                    .map(|x| (x.key.as_str(), header_value(x)))
+                    .collect(),
+                _ => vec![],
+            };
+            if let Err(e) = request.send_http_response(*status_code, &headers, body.as_deref()) {
+                warn!("failed to send ext_authz denial: {e:?}");
+            }
+        }
+        (Decision::Allow, _) => request.resume(),
+    }
+    CheckOutcome {
+        decision,
+        response_headers_to_add,
+    }
+}
+
+fn header_value(header: &proto::HeaderValue) -> &[u8] {
+    if header.raw_value.is_empty() {
+        header.value.as_bytes()
+    } else {
+        &header.raw_value
+    }
+}
+
+fn apply_header_option(headers: &impl HttpHeaderControl, option: &HeaderValueOption) {
+    let Some(header) = &option.header else {
+        return;
+    };
+    let value = header_value(header);
+    // the deprecated `append` field takes precedence when set, like in Envoy
+    let action = match option.append {
+        Some(true) => HeaderAppendAction::AppendIfExistsOrAdd,
+        Some(false) => HeaderAppendAction::OverwriteIfExistsOrAdd,
+        None => HeaderAppendAction::from_i32(option.append_action)
+            .unwrap_or(HeaderAppendAction::AppendIfExistsOrAdd),
+    };
+    match action {
+        HeaderAppendAction::AppendIfExistsOrAdd => headers.add(&header.key, value),
+        HeaderAppendAction::AddIfAbsent => {
+            if headers.get(&header.key).is_none() {
+                headers.add(&header.key, value);
+            }
+        }
+        HeaderAppendAction::OverwriteIfExistsOrAdd => headers.set(&header.key, value),
+        HeaderAppendAction::OverwriteIfExists => {
+            if headers.get(&header.key).is_some() {
+                headers.set(&header.key, value);
+            }
+        }
+    }
+}
+
+/// Sets and removes query parameters in a request path. Parameter values are used as-is and are expected to be URL encoded.
+pub fn rewrite_query(path: &str, set: &[(&str, &str)], remove: &[String]) -> String {
+    let (base, query) = path.split_once('?').unwrap_or((path, ""));
+    let mut params = query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .filter(|x| {
+            let key = x.split_once('=').map(|(key, _)| key).unwrap_or(x);
+            !remove.iter().any(|x| x == key) && !set.iter().any(|(x, _)| *x == key)
+        })
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    params.extend(set.iter().map(|(key, value)| format!("{key}={value}")));
+    if params.is_empty() {
+        return base.to_string();
+    }
+    format!("{base}?{}", params.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::{DeniedHttpResponse, HttpStatus};
+
+    #[test]
+    fn test_decision() {
+        assert_eq!(decision(&CheckResponse::default()), Decision::Allow);
+        let denied = CheckResponse {
+            status: Some(proto::Status {
+                code: 7,
+                message: String::new(),
+            }),
+            http_response: Some(HttpResponse::DeniedResponse(DeniedHttpResponse {
+                status: Some(HttpStatus { code: 401 }),
+                headers: vec![],
+                body: "nope".to_string(),
+            })),
+            dynamic_metadata: None,
+        };
+        assert_eq!(
+            decision(&denied),
+            Decision::Deny {
+                status_code: 401,
+                body: Some(b"nope".to_vec())
+            }
+        );
+    }
+
+    #[test]
+    fn test_rewrite_query() {
+        assert_eq!(
+            rewrite_query("/a?x=1&token=abc&y", &[("x", "2")], &["token".to_string()]),
+            "/a?y&x=2"
+        );
+        assert_eq!(
+            rewrite_query("/a?token=1", &[], &["token".to_string()]),
+            "/a"
+        );
+        assert_eq!(rewrite_query("/a", &[("z", "1")], &[]), "/a?z=1");
+    }
+}
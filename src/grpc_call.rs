@@ -1,17 +1,15 @@
-use std::{
-    fmt,
-    ops::{Bound, RangeBounds},
-    time::Duration,
-};
+use std::{any::Any, borrow::Cow, fmt, ops::RangeBounds, time::Duration};
 
 use derive_builder::Builder;
+use log::debug;
 
 use crate::{
+    calculate_range,
     downcast_box::DowncastBox,
     hostcalls::{self, BufferType, MapType},
     log_concern,
     upstream::Upstream,
-    RootContext, Status,
+    CalloutError, CalloutQuota, ResponseFuture, RootContext, Status,
 };
 
 /// Outbound GRPC call
@@ -31,7 +29,7 @@ pub struct GrpcCall<'a> {
     pub initial_metadata: Vec<(&'a str, &'a [u8])>,
     /// An optional request body to send with the request.
     #[builder(setter(strip_option, into), default)]
-    pub message: Option<&'a [u8]>,
+    pub message: Option<Cow<'a, [u8]>>,
     /// A timeout on waiting for a response. Default is 10 seconds.
     #[builder(setter(strip_option, into), default)]
     pub timeout: Option<Duration>,
@@ -54,6 +52,28 @@ impl<'a> GrpcCallBuilder<'a> {
         })));
         self
     }
+
+    /// Set a response callback on the HTTP or stream context that dispatched the call, instead of on its root context.
+    /// The callback is not called if that context is gone by the time the response arrives.
+    pub fn context_callback<C: Any>(
+        mut self,
+        callback: impl FnOnce(&mut C, &GrpcCallResponse) + 'static,
+    ) -> Self {
+        self.callback = Some(Some(Box::new(move |_, resp| {
+            let called = crate::dispatcher::with_active_context(|context| {
+                callback(context.downcast_mut().expect("invalid context type"), resp)
+            });
+            if called.is_none() {
+                debug!("dropping grpc call response for non-existing context");
+            }
+        })));
+        self
+    }
+
+    /// Set the request body to an encoded protobuf message
+    pub fn message_proto(self, message: &impl prost::Message) -> Self {
+        self.message(message.encode_to_vec())
+    }
 }
 
 impl<'a> GrpcCall<'a> {
@@ -66,7 +86,7 @@ impl<'a> GrpcCall<'a> {
             self.service,
             self.method,
             &self.initial_metadata,
-            self.message,
+            self.message.as_deref(),
             self.timeout.unwrap_or(Self::DEFAULT_TIMEOUT),
         )?;
         if let Some(callback) = self.callback {
@@ -74,6 +94,95 @@ impl<'a> GrpcCall<'a> {
         }
         Ok(GrpcCancelHandle(token))
     }
+
+    /// Sends this `GrpcCall` if `quota` allows it, counting the message against the quota's byte limit.
+    pub fn dispatch_within(self, quota: &CalloutQuota) -> Result<GrpcCancelHandle, CalloutError> {
+        quota.acquire(self.message.as_ref().map_or(0, |x| x.len()) as u64)?;
+        Ok(self.dispatch()?)
+    }
+
+    /// Sends this `GrpcCall`, returning a future of the response for tasks of [`crate::spawn_local`].
+    /// Any callback set on this call is replaced.
+    pub fn dispatch_async(mut self) -> Result<ResponseFuture<PolledGrpcResponse>, Status> {
+        let (future, sender) = ResponseFuture::new();
+        self.callback = Some(Box::new(move |_, response| {
+            sender.send(PolledGrpcResponse::read(response))
+        }));
+        self.dispatch()?;
+        Ok(future)
+    }
+
+    /// Sends this `GrpcCall` over the network without a callback, for embedders that control the event loop.
+    /// The response is retrieved with [`GrpcCallHandle::try_take_response`]. Any callback set on this call is not invoked.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn dispatch_polled(self) -> Result<GrpcCallHandle, Status> {
+        let token = hostcalls::dispatch_grpc_call(
+            &self.upstream.0,
+            self.service,
+            self.method,
+            &self.initial_metadata,
+            self.message.as_deref(),
+            self.timeout.unwrap_or(Self::DEFAULT_TIMEOUT),
+        )?;
+        crate::dispatcher::register_polled_grpc(token);
+        Ok(GrpcCallHandle(token))
+    }
+}
+
+/// Handle to a GRPC call dispatched with [`GrpcCall::dispatch_polled`]. Dropping it discards any response.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct GrpcCallHandle(u32);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl GrpcCallHandle {
+    /// GRPC handle ID of the call
+    pub fn handle_id(&self) -> u32 {
+        self.0
+    }
+
+    /// Takes the response if it has arrived. Returns `None` while the call is in flight and after the response was taken.
+    pub fn try_take_response(&self) -> Option<PolledGrpcResponse> {
+        crate::dispatcher::take_polled_grpc(self.0, false)
+    }
+
+    /// Attempts to cancel the GRPC call
+    pub fn cancel(&self) {
+        hostcalls::cancel_grpc_call(self.0).ok();
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for GrpcCallHandle {
+    fn drop(&mut self) {
+        crate::dispatcher::take_polled_grpc(self.0, true);
+    }
+}
+
+/// Owned response of a polled or async GRPC call, read out of the host while the response was available
+#[derive(Clone, Debug)]
+pub struct PolledGrpcResponse {
+    pub status_code: GrpcCode,
+    pub status_message: Option<String>,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, Vec<u8>)>,
+}
+
+impl PolledGrpcResponse {
+    pub(crate) fn read(response: &GrpcCallResponse) -> Self {
+        Self {
+            status_code: response.status_code(),
+            status_message: response.status_message().map(str::to_string),
+            headers: response.headers(),
+            body: if response.body_size() > 0 {
+                response.full_body().unwrap_or_default()
+            } else {
+                vec![]
+            },
+            trailers: response.trailers(),
+        }
+    }
 }
 
 /// GRPC Call Handle to cancel a request
@@ -201,6 +310,26 @@ impl PartialEq<GrpcCode> for u32 {
     }
 }
 
+/// The Google gRPC client reports handshake and verification failures as `Unavailable`, distinguished only by message.
+pub(crate) fn is_tls_validation_failure(code: GrpcCode, message: Option<&str>) -> bool {
+    const MARKERS: &[&str] = &[
+        "certificate_verify_failed",
+        "certificate verify failed",
+        "is not in peer certificate",
+        "peer name",
+        "handshake failed",
+        "ssl_error_ssl",
+    ];
+    if code != GrpcCode::Unavailable {
+        return false;
+    }
+    let Some(message) = message else {
+        return false;
+    };
+    let message = message.to_ascii_lowercase();
+    MARKERS.iter().any(|marker| message.contains(marker))
+}
+
 /// Response type for [`GrpcCall::callback`]
 pub struct GrpcCallResponse {
     handle_id: u32,
@@ -244,6 +373,11 @@ impl GrpcCallResponse {
         self.body_size
     }
 
+    /// Whether the call failed because the upstream certificate did not pass verification
+    pub fn tls_validation_failed(&self) -> bool {
+        is_tls_validation_failure(self.status_code, self.message.as_deref())
+    }
+
     /// Get all response headers
     pub fn headers(&self) -> Vec<(String, Vec<u8>)> {
         log_concern(
@@ -263,22 +397,34 @@ impl GrpcCallResponse {
 
     /// Get a range of the response body
     pub fn body(&self, range: impl RangeBounds<usize>) -> Option<Vec<u8>> {
-        let start = match range.start_bound() {
-            Bound::Included(x) => *x,
-            Bound::Excluded(x) => x.saturating_sub(1),
-            Bound::Unbounded => 0,
-        };
-        let size = match range.end_bound() {
-            Bound::Included(x) => *x + 1,
-            Bound::Excluded(x) => *x,
-            Bound::Unbounded => self.body_size,
+        self.receive_buffer().get(range)
+    }
+
+    /// Reads a range of the response body into `out`, replacing its content and reusing its allocation.
+    /// Returns `false` if there is no body.
+    pub fn read_into(&self, range: impl RangeBounds<usize>, out: &mut Vec<u8>) -> bool {
+        self.receive_buffer().read_into(range, out)
+    }
+
+    /// Calls `f` with a range of the response body, read into a pooled scratch buffer
+    pub fn with_slice<R>(
+        &self,
+        range: impl RangeBounds<usize>,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Option<R> {
+        self.receive_buffer().with_slice(range, f)
+    }
+
+    /// The GRPC frame header, if the host left one in place
+    pub fn frame_header(&self) -> Option<GrpcFrameHeader> {
+        self.receive_buffer().frame_header()
+    }
+
+    fn receive_buffer(&self) -> ReceiveBuffer {
+        ReceiveBuffer {
+            size: self.body_size,
+            context: "grpc-call-body",
         }
-        .min(self.body_size)
-        .saturating_sub(start);
-        log_concern(
-            "grpc-call-body",
-            hostcalls::get_buffer(BufferType::GrpcReceiveBuffer, start, size),
-        )
     }
 
     /// Get the entire response body
@@ -286,6 +432,21 @@ impl GrpcCallResponse {
         self.body(..)
     }
 
+    /// Get the response message, decompressing it if the host delivered a compressed GRPC frame
+    pub fn decompressed_body(&self) -> Result<Vec<u8>, crate::compression::CompressionError> {
+        crate::compression::unwrap_message(
+            self.full_body().unwrap_or_default(),
+            crate::compression::DEFAULT_MAX_MESSAGE_SIZE,
+        )
+    }
+
+    /// Get the response message decoded as a protobuf message, whether or not the host left the GRPC frame header in place
+    pub fn decode_body<M: prost::Message + Default>(
+        &self,
+    ) -> Result<M, crate::compression::CompressionError> {
+        Ok(M::decode(&self.decompressed_body()?[..])?)
+    }
+
     /// Get all response trailers
     pub fn trailers(&self) -> Vec<(String, Vec<u8>)> {
         log_concern(
@@ -303,3 +464,120 @@ impl GrpcCallResponse {
         )
     }
 }
+
+/// Header of a GRPC frame: a compressed flag and the length of the message that follows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GrpcFrameHeader {
+    pub compressed: bool,
+    pub length: usize,
+}
+
+/// Reads of the GRPC receive buffer, shared by call responses and stream messages
+pub(crate) struct ReceiveBuffer {
+    pub(crate) size: usize,
+    pub(crate) context: &'static str,
+}
+
+impl ReceiveBuffer {
+    pub(crate) fn get(&self, range: impl RangeBounds<usize>) -> Option<Vec<u8>> {
+        let (start, size) = calculate_range(range, self.size);
+        log_concern(
+            self.context,
+            hostcalls::get_buffer(BufferType::GrpcReceiveBuffer, start, size),
+        )
+    }
+
+    pub(crate) fn read_into(&self, range: impl RangeBounds<usize>, out: &mut Vec<u8>) -> bool {
+        let (start, size) = calculate_range(range, self.size);
+        log_concern(
+            self.context,
+            hostcalls::get_buffer_into(BufferType::GrpcReceiveBuffer, start, size, out),
+        )
+    }
+
+    pub(crate) fn with_slice<R>(
+        &self,
+        range: impl RangeBounds<usize>,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Option<R> {
+        hostcalls::with_buffer(|buf| self.read_into(range, buf).then(|| f(buf)))
+    }
+
+    /// A body is framed if it starts with a frame header whose length covers the rest of it,
+    /// see [`crate::compression::unwrap_message`]
+    pub(crate) fn frame_header(&self) -> Option<GrpcFrameHeader> {
+        if self.size < 5 {
+            return None;
+        }
+        let header = self.with_slice(..5, |x| <[u8; 5]>::try_from(x).ok())??;
+        let length = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+        (header[0] <= 1 && length == self.size - 5).then_some(GrpcFrameHeader {
+            compressed: header[0] == 1,
+            length,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_validation_failure() {
+        assert!(is_tls_validation_failure(
+            GrpcCode::Unavailable,
+            Some("failed to connect to all addresses; last error: UNKNOWN: Ssl handshake failed: SSL_ERROR_SSL: error:1000007d:SSL routines:OPENSSL_internal:CERTIFICATE_VERIFY_FAILED")
+        ));
+        assert!(is_tls_validation_failure(
+            GrpcCode::Unavailable,
+            Some("Peer name api.internal is not in peer certificate")
+        ));
+        assert!(!is_tls_validation_failure(
+            GrpcCode::Unavailable,
+            Some("Connection refused")
+        ));
+        assert!(!is_tls_validation_failure(
+            GrpcCode::Internal,
+            Some("certificate verify failed")
+        ));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_proto_messages() {
+        use crate::{compression::GrpcFrame, testing::host::with_host};
+
+        let request = prost_types::Duration {
+            seconds: 5,
+            nanos: 0,
+        };
+        let call = GrpcCallBuilder::default()
+            .upstream(&"rls")
+            .service("svc")
+            .method("Get")
+            .message_proto(&request)
+            .build()
+            .unwrap();
+        assert_eq!(
+            call.message.as_deref(),
+            Some(&*prost::Message::encode_to_vec(&request))
+        );
+
+        crate::testing::reset_host();
+        for body in [
+            call.message.as_deref().unwrap().to_vec(),
+            GrpcFrame::from_message(&request).encode(),
+        ] {
+            let size = body.len();
+            with_host(|host| {
+                host.buffers
+                    .insert(BufferType::GrpcReceiveBuffer as u32, body)
+            });
+            let response = GrpcCallResponse::new(1, GrpcCode::Ok, None, size);
+            assert_eq!(
+                response.decode_body::<prost_types::Duration>().unwrap(),
+                request
+            );
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::{collections::VecDeque, fmt, marker::PhantomData};
+
+use crate::{Counter, Gauge, GrpcStreamHandle};
+
+/// What a full [`GrpcSendQueue`] drops to make room
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum DropPolicy {
+    /// Drops the oldest queued messages, i.e. for telemetry where recent data matters most
+    #[default]
+    DropOldest,
+    /// Refuses the new message
+    DropNewest,
+}
+
+/// Result of [`GrpcSendQueue::push`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PushOutcome {
+    /// Sent on the stream, along with any message queued before it
+    Sent,
+    /// Queued until the stream is writable
+    Queued,
+    /// Dropped, as the queue is full under [`DropPolicy::DropNewest`] or the message alone exceeds the byte cap
+    Dropped,
+}
+
+struct QueueMetrics {
+    depth: Gauge,
+    bytes: Gauge,
+    dropped: Counter,
+}
+
+/// Outbound messages of a GRPC stream, buffered while the stream is not open or not writable, i.e. while reconnecting
+/// to a control plane, and flushed in order once it is.
+///
+/// Attach the stream with [`GrpcSendQueue::attach`] once opened, and detach it from `on_close`. A failed send leaves the
+/// message at the front of the queue for the next flush.
+///
+/// ```ignore
+/// let mut queue = GrpcSendQueue::<LogEntry>::new().max_bytes(4 << 20).metrics("log_export");
+/// queue.push(&entry); // queued, no stream yet
+/// queue.attach(stream.open()?); // flushes
+/// ```
+pub struct GrpcSendQueue<M> {
+    handle: Option<GrpcStreamHandle>,
+    queue: VecDeque<Vec<u8>>,
+    bytes: usize,
+    max_messages: usize,
+    max_bytes: usize,
+    drop_policy: DropPolicy,
+    dropped: u64,
+    metrics: Option<QueueMetrics>,
+    _message: PhantomData<fn(&M)>,
+}
+
+impl<M: prost::Message> GrpcSendQueue<M> {
+    /// Holds up to 1024 messages and 1 MiB by default
+    pub fn new() -> Self {
+        Self {
+            handle: None,
+            queue: VecDeque::new(),
+            bytes: 0,
+            max_messages: 1024,
+            max_bytes: 1 << 20,
+            drop_policy: DropPolicy::default(),
+            dropped: 0,
+            metrics: None,
+            _message: PhantomData,
+        }
+    }
+
+    pub fn max_messages(mut self, max_messages: usize) -> Self {
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// Caps the encoded size of queued messages
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn drop_policy(mut self, drop_policy: DropPolicy) -> Self {
+        self.drop_policy = drop_policy;
+        self
+    }
+
+    /// Reports the queue through gauges `{prefix}_queue_depth` and `{prefix}_queue_bytes`, and counter `{prefix}_queue_dropped`
+    pub fn metrics(mut self, prefix: &str) -> Self {
+        self.metrics = Some(QueueMetrics {
+            depth: Gauge::define(format!("{prefix}_queue_depth")),
+            bytes: Gauge::define(format!("{prefix}_queue_bytes")),
+            dropped: Counter::define(format!("{prefix}_queue_dropped")),
+        });
+        self
+    }
+
+    /// Sends to `handle` from now on, starting with queued messages
+    pub fn attach(&mut self, handle: GrpcStreamHandle) -> usize {
+        self.handle = Some(handle);
+        self.flush()
+    }
+
+    /// Stops sending, i.e. once the stream closed. Messages are queued until the next [`GrpcSendQueue::attach`].
+    pub fn detach(&mut self) -> Option<GrpcStreamHandle> {
+        self.handle.take()
+    }
+
+    pub fn handle(&self) -> Option<GrpcStreamHandle> {
+        self.handle
+    }
+
+    /// Encodes and sends `message`, or queues it if the stream is not writable or older messages are still queued
+    pub fn push(&mut self, message: &M) -> PushOutcome {
+        self.push_encoded(message.encode_to_vec())
+    }
+
+    /// Like [`GrpcSendQueue::push`], for an already encoded message
+    pub fn push_encoded(&mut self, message: Vec<u8>) -> PushOutcome {
+        if message.len() > self.max_bytes {
+            self.record_dropped(1);
+            return PushOutcome::Dropped;
+        }
+        if self.queue.is_empty() {
+            if let Some(handle) = self.handle {
+                if handle.send(Some(&message), false).is_ok() {
+                    return PushOutcome::Sent;
+                }
+            }
+        }
+        let mut dropped = 0;
+        while self.queue.len() >= self.max_messages || self.bytes + message.len() > self.max_bytes {
+            if self.drop_policy == DropPolicy::DropNewest || self.queue.is_empty() {
+                self.record_dropped(1);
+                self.record_depth();
+                return PushOutcome::Dropped;
+            }
+            if let Some(oldest) = self.queue.pop_front() {
+                self.bytes -= oldest.len();
+                dropped += 1;
+            }
+        }
+        self.record_dropped(dropped);
+        self.bytes += message.len();
+        self.queue.push_back(message);
+        if self.flush() > 0 && self.queue.is_empty() {
+            return PushOutcome::Sent;
+        }
+        self.record_depth();
+        PushOutcome::Queued
+    }
+
+    /// Sends queued messages in order until the queue is empty or a send fails. Returns the number sent.
+    pub fn flush(&mut self) -> usize {
+        let Some(handle) = self.handle else {
+            return 0;
+        };
+        let mut sent = 0;
+        while let Some(message) = self.queue.front() {
+            if handle.send(Some(message), false).is_err() {
+                break;
+            }
+            self.bytes -= message.len();
+            self.queue.pop_front();
+            sent += 1;
+        }
+        self.record_depth();
+        sent
+    }
+
+    /// Queued messages
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Encoded size of queued messages
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Messages dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Drops all queued messages, without counting them as dropped
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.bytes = 0;
+        self.record_depth();
+    }
+
+    fn record_depth(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.depth.record(self.queue.len() as u64);
+            metrics.bytes.record(self.bytes as u64);
+        }
+    }
+
+    fn record_dropped(&mut self, dropped: u64) {
+        if dropped == 0 {
+            return;
+        }
+        self.dropped += dropped;
+        if let Some(metrics) = &self.metrics {
+            metrics.dropped.increment(dropped as i64);
+        }
+    }
+}
+
+impl<M: prost::Message> Default for GrpcSendQueue<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M> fmt::Debug for GrpcSendQueue<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcSendQueue")
+            .field("handle", &self.handle.map(|x| x.0))
+            .field("len", &self.queue.len())
+            .field("bytes", &self.bytes)
+            .field("max_messages", &self.max_messages)
+            .field("max_bytes", &self.max_bytes)
+            .field("drop_policy", &self.drop_policy)
+            .field("dropped", &self.dropped)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        native::{clear_host, set_host, Host},
+        testing::{metric, reset_host},
+        Status,
+    };
+
+    #[derive(Default)]
+    struct Stream {
+        writable: bool,
+        sent: Vec<Vec<u8>>,
+    }
+
+    struct StreamHost(Rc<RefCell<Stream>>);
+
+    impl Host for StreamHost {
+        unsafe fn proxy_grpc_send(
+            &self,
+            _token: u32,
+            message_ptr: *const u8,
+            message_len: usize,
+            _end_stream: bool,
+        ) -> Status {
+            let mut stream = self.0.borrow_mut();
+            if !stream.writable {
+                return Status::BadArgument;
+            }
+            let message = std::slice::from_raw_parts(message_ptr, message_len);
+            stream.sent.push(message.to_vec());
+            Status::Ok
+        }
+    }
+
+    fn value(x: &str) -> String {
+        x.to_string()
+    }
+
+    #[test]
+    fn test_send_queue() {
+        reset_host();
+        let stream = Rc::new(RefCell::new(Stream::default()));
+        set_host(StreamHost(stream.clone()));
+        let mut queue = GrpcSendQueue::<String>::new()
+            .max_messages(2)
+            .metrics("export");
+        assert_eq!(queue.push(&value("a")), PushOutcome::Queued);
+        assert_eq!(queue.push(&value("b")), PushOutcome::Queued);
+        assert_eq!(queue.push(&value("c")), PushOutcome::Queued);
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(metric("export_queue_depth"), Some(2));
+
+        // opened, but not writable yet
+        assert_eq!(queue.attach(GrpcStreamHandle(1)), 0);
+        assert_eq!(queue.len(), 2);
+        stream.borrow_mut().writable = true;
+        // queued behind older messages, which make room under the cap first
+        assert_eq!(queue.push(&value("d")), PushOutcome::Sent);
+        assert_eq!(queue.dropped(), 2);
+        assert!(queue.is_empty());
+        assert_eq!(queue.push(&value("e")), PushOutcome::Sent);
+        clear_host();
+
+        let sent: Vec<_> = stream
+            .borrow()
+            .sent
+            .iter()
+            .map(|x| <String as prost::Message>::decode(&x[..]).unwrap())
+            .collect();
+        assert_eq!(sent, ["c", "d", "e"]);
+        assert_eq!(metric("export_queue_depth"), Some(0));
+        assert_eq!(metric("export_queue_dropped"), Some(2));
+
+        let mut strict = GrpcSendQueue::<String>::new()
+            .max_messages(1)
+            .drop_policy(DropPolicy::DropNewest);
+        strict.push(&value("a"));
+        assert_eq!(strict.push(&value("b")), PushOutcome::Dropped);
+        assert_eq!(strict.bytes(), 3);
+    }
+}
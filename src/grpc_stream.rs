@@ -1,19 +1,15 @@
-use std::{
-    fmt,
-    ops::{Bound, RangeBounds},
-};
+use std::{fmt, ops::RangeBounds};
 
 use derive_builder::Builder;
 
 use crate::{
     downcast_box::DowncastBox,
-    grpc_call::GrpcCode,
-    hostcalls::{self, BufferType},
-    log_concern, RootContext, Status, Upstream,
+    grpc_call::{is_tls_validation_failure, GrpcCode, GrpcFrameHeader, ReceiveBuffer},
+    hostcalls, RootContext, Status, Upstream,
 };
 
 #[cfg(feature = "stream-metadata")]
-use crate::hostcalls::MapType;
+use crate::{hostcalls::MapType, log_concern};
 
 /// Outbound GRPC stream (bidirectional)
 #[derive(Builder)]
@@ -267,28 +263,55 @@ impl GrpcStreamMessage {
 
     /// Get a range of the message body
     pub fn body(&self, range: impl RangeBounds<usize>) -> Option<Vec<u8>> {
-        let start = match range.start_bound() {
-            Bound::Included(x) => *x,
-            Bound::Excluded(x) => x.saturating_sub(1),
-            Bound::Unbounded => 0,
-        };
-        let size = match range.end_bound() {
-            Bound::Included(x) => *x + 1,
-            Bound::Excluded(x) => *x,
-            Bound::Unbounded => self.body_size,
+        self.receive_buffer().get(range)
+    }
+
+    /// Reads a range of the message body into `out`, replacing its content and reusing its allocation.
+    /// Returns `false` if there is no body.
+    pub fn read_into(&self, range: impl RangeBounds<usize>, out: &mut Vec<u8>) -> bool {
+        self.receive_buffer().read_into(range, out)
+    }
+
+    /// Calls `f` with a range of the message body, read into a pooled scratch buffer
+    pub fn with_slice<R>(
+        &self,
+        range: impl RangeBounds<usize>,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Option<R> {
+        self.receive_buffer().with_slice(range, f)
+    }
+
+    /// The GRPC frame header, if the host left one in place
+    pub fn frame_header(&self) -> Option<GrpcFrameHeader> {
+        self.receive_buffer().frame_header()
+    }
+
+    fn receive_buffer(&self) -> ReceiveBuffer {
+        ReceiveBuffer {
+            size: self.body_size,
+            context: "grpc-stream-message-body",
         }
-        .min(self.body_size)
-        .saturating_sub(start);
-        log_concern(
-            "grpc-stream-message-body",
-            hostcalls::get_buffer(BufferType::GrpcReceiveBuffer, start, size),
-        )
     }
 
     /// Get the entire message body
     pub fn full_body(&self) -> Option<Vec<u8>> {
         self.body(..self.body_size)
     }
+
+    /// Get the message, decompressing it if the host delivered a compressed GRPC frame
+    pub fn decompressed_body(&self) -> Result<Vec<u8>, crate::compression::CompressionError> {
+        crate::compression::unwrap_message(
+            self.full_body().unwrap_or_default(),
+            crate::compression::DEFAULT_MAX_MESSAGE_SIZE,
+        )
+    }
+
+    /// Get the message decoded as a protobuf message, whether or not the host left the GRPC frame header in place
+    pub fn decode_body<M: prost::Message + Default>(
+        &self,
+    ) -> Result<M, crate::compression::CompressionError> {
+        Ok(M::decode(&self.decompressed_body()?[..])?)
+    }
 }
 
 /// Response type for [`GrpcStream::on_trailing_metadata`]
@@ -356,4 +379,9 @@ impl GrpcStreamClose {
     pub fn status_message(&self) -> Option<&str> {
         self.message.as_deref()
     }
+
+    /// Whether the stream closed because the upstream certificate did not pass verification
+    pub fn tls_validation_failed(&self) -> bool {
+        is_tls_validation_failure(self.status_code, self.message.as_deref())
+    }
 }
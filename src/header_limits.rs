@@ -0,0 +1,140 @@
+use std::{cell::Cell, fmt};
+
+use crate::Counter;
+
+/// A header map over its limits, see [`crate::HttpHeaderControl::all_limited`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HeaderOverflow {
+    /// The map holds `count` pairs
+    Pairs { count: usize, max: usize },
+    /// Names and values of the map total `size` bytes
+    Bytes { size: usize, max: usize },
+}
+
+impl fmt::Display for HeaderOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderOverflow::Pairs { count, max } => {
+                write!(f, "header map holds {count} pairs, over the limit of {max}")
+            }
+            HeaderOverflow::Bytes { size, max } => {
+                write!(f, "header map holds {size} bytes, over the limit of {max}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderOverflow {}
+
+/// What header reads do with a map over the [`HeaderLimits`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HeaderLimitAction {
+    /// Reads no header at all
+    #[default]
+    Refuse,
+    /// Reads the leading headers within the limits
+    Clip,
+}
+
+/// Limits applied by the SDK to every read of a whole header map, i.e. [`crate::HttpHeaderControl::all`] and
+/// [`crate::HeaderMap`], so a hostile request with a huge header block can't make the VM run out of memory.
+/// Single header reads are not limited.
+///
+/// An oversized map is logged and counted in the `header_map_overflow` counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HeaderLimits {
+    /// Total size of names and values
+    pub max_bytes: usize,
+    pub max_pairs: usize,
+    pub action: HeaderLimitAction,
+}
+
+impl HeaderLimits {
+    /// Refuses maps over `max_bytes` or `max_pairs`
+    pub fn new(max_bytes: usize, max_pairs: usize) -> Self {
+        Self {
+            max_bytes,
+            max_pairs,
+            action: HeaderLimitAction::default(),
+        }
+    }
+
+    pub fn action(mut self, action: HeaderLimitAction) -> Self {
+        self.action = action;
+        self
+    }
+}
+
+thread_local! {
+    static LIMITS: Cell<Option<HeaderLimits>> = const { Cell::new(None) };
+}
+
+/// Limits reads of whole header maps from now on. `None`, the default, reads maps of any size.
+pub fn set_header_limits(limits: Option<HeaderLimits>) {
+    LIMITS.set(limits);
+}
+
+pub fn header_limits() -> Option<HeaderLimits> {
+    LIMITS.get()
+}
+
+pub(crate) fn record_overflow() {
+    Counter::define("header_map_overflow").increment(1);
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        hostcalls::MapType,
+        property::envoy::Attributes,
+        testing::{metric, reset_host},
+        HttpHeaderControl, RequestHeaders,
+    };
+
+    #[test]
+    fn test_header_limits() {
+        reset_host();
+        crate::testing::host::with_host(|host| {
+            *host.header_map(MapType::HttpRequestHeaders) = vec![
+                (":path".to_string(), b"/".to_vec()),
+                ("x-a".to_string(), b"1234".to_vec()),
+                ("x-b".to_string(), vec![b'b'; 4096]),
+            ]
+        });
+        let headers = RequestHeaders {
+            header_count: 3,
+            end_of_stream: false,
+            attributes: Attributes::get(),
+        };
+        assert_eq!(
+            headers.all_limited(1024, 2),
+            Err(HeaderOverflow::Pairs { count: 3, max: 2 })
+        );
+        assert_eq!(
+            headers.all_limited(1024, 8),
+            Err(HeaderOverflow::Bytes {
+                size: 4112,
+                max: 1024
+            })
+        );
+        assert_eq!(headers.all_limited(8192, 8).unwrap().len(), 3);
+
+        set_header_limits(Some(HeaderLimits::new(1024, 8)));
+        assert!(headers.all().is_empty());
+        assert!(headers.cached().is_empty());
+        set_header_limits(Some(
+            HeaderLimits::new(1024, 8).action(HeaderLimitAction::Clip),
+        ));
+        assert_eq!(
+            headers.all(),
+            vec![
+                (":path".to_string(), b"/".to_vec()),
+                ("x-a".to_string(), b"1234".to_vec()),
+            ]
+        );
+        set_header_limits(None);
+        assert_eq!(headers.all().len(), 3);
+        assert_eq!(metric("header_map_overflow"), Some(3));
+    }
+}
@@ -0,0 +1,221 @@
+/// Headers defined as comma-separated lists, which are safe to split on unquoted commas
+const LIST_HEADERS: &[&str] = &[
+    "accept",
+    "accept-charset",
+    "accept-encoding",
+    "accept-language",
+    "accept-patch",
+    "accept-ranges",
+    "access-control-allow-headers",
+    "access-control-allow-methods",
+    "access-control-expose-headers",
+    "access-control-request-headers",
+    "allow",
+    "alt-svc",
+    "cache-control",
+    "connection",
+    "content-encoding",
+    "content-language",
+    "expect",
+    "forwarded",
+    "if-match",
+    "if-none-match",
+    "link",
+    "pragma",
+    "prefer",
+    "preference-applied",
+    "proxy-authenticate",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "vary",
+    "via",
+    "warning",
+    "www-authenticate",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "grpc-accept-encoding",
+];
+
+/// How multiple values of a header are combined into one, i.e. in the `request.headers` and `response.headers`
+/// attributes, which comma-join repeated headers regardless of their syntax.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HeaderJoin {
+    /// A comma-separated list: split on commas outside of quoted strings, join with `, `
+    List,
+    /// `Cookie`: pairs may be joined with `; ` or `, `, split on both
+    Cookie,
+    /// `Set-Cookie`: each value is one cookie. Expiry dates contain commas, so values are only split before
+    /// a comma followed by a new `name=` pair. Never joined.
+    SetCookie,
+    /// Any other header, i.e. `Date` or `User-Agent`, which may contain commas of its own. Never split or joined.
+    Single,
+}
+
+impl HeaderJoin {
+    /// Joining rule of header `name`
+    pub fn of(name: &str) -> Self {
+        if name.eq_ignore_ascii_case("cookie") {
+            HeaderJoin::Cookie
+        } else if name.eq_ignore_ascii_case("set-cookie") {
+            HeaderJoin::SetCookie
+        } else if LIST_HEADERS.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+            HeaderJoin::List
+        } else {
+            HeaderJoin::Single
+        }
+    }
+
+    /// Splits a possibly joined value into its parts, trimming whitespace and dropping empty list elements
+    pub fn split<'a>(&self, value: &'a [u8]) -> Vec<&'a [u8]> {
+        let parts = match self {
+            HeaderJoin::Single => return vec![value.trim_ascii()],
+            HeaderJoin::List => split_unquoted(value, |_, _| true, b","),
+            HeaderJoin::Cookie => split_unquoted(value, |_, _| true, b",;"),
+            HeaderJoin::SetCookie => split_unquoted(value, starts_cookie, b","),
+        };
+        parts
+            .into_iter()
+            .map(<[u8]>::trim_ascii)
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+
+    /// Joins separate values into one header value. `None` for headers which must stay separate.
+    pub fn join<V: AsRef<[u8]>>(&self, values: &[V]) -> Option<Vec<u8>> {
+        let separator: &[u8] = match self {
+            HeaderJoin::List => b", ",
+            HeaderJoin::Cookie => b"; ",
+            HeaderJoin::SetCookie | HeaderJoin::Single if values.len() > 1 => return None,
+            HeaderJoin::SetCookie | HeaderJoin::Single => b"",
+        };
+        Some(
+            values
+                .iter()
+                .map(AsRef::as_ref)
+                .collect::<Vec<_>>()
+                .join(separator),
+        )
+    }
+}
+
+/// Whether `rest`, the text after a comma, starts a new `name=value` cookie
+fn starts_cookie(_: &[u8], rest: &[u8]) -> bool {
+    let rest = rest.trim_ascii_start();
+    let Some(name_end) = rest.iter().position(|x| *x == b'=') else {
+        return false;
+    };
+    name_end > 0
+        && rest[..name_end]
+            .iter()
+            .all(|x| x.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(x))
+}
+
+/// Splits `value` on any of `separators` outside of double quotes, where `split_here(before, after)` agrees
+fn split_unquoted<'a>(
+    value: &'a [u8],
+    split_here: impl Fn(&[u8], &[u8]) -> bool,
+    separators: &[u8],
+) -> Vec<&'a [u8]> {
+    let mut out = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, byte) in value.iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if quoted && *byte == b'\\' {
+            escaped = true;
+        } else if *byte == b'"' {
+            quoted = !quoted;
+        } else if !quoted
+            && separators.contains(byte)
+            && split_here(&value[start..i], &value[i + 1..])
+        {
+            out.push(&value[start..i]);
+            start = i + 1;
+        }
+    }
+    out.push(&value[start..]);
+    out
+}
+
+/// Splits header `value` following the joining rule of `name`, see [`HeaderJoin`]
+pub fn split_header_value<'a>(name: &str, value: &'a [u8]) -> Vec<&'a [u8]> {
+    HeaderJoin::of(name).split(value)
+}
+
+/// Expands a header map with comma-joined values, i.e. from [`crate::property::envoy::Attributes`], into one entry per value.
+/// Headers which can't be split safely are kept as is.
+pub fn expand_joined_headers<N: AsRef<str>, V: AsRef<[u8]>>(
+    headers: &[(N, V)],
+) -> Vec<(String, Vec<u8>)> {
+    headers
+        .iter()
+        .flat_map(|(name, value)| {
+            let name = name.as_ref();
+            split_header_value(name, value.as_ref())
+                .into_iter()
+                .map(move |x| (name.to_string(), x.to_vec()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_header_value() {
+        assert_eq!(
+            split_header_value(
+                "Accept",
+                br#"text/html, application/json;q="0.9,x", */*;q=0.1"#
+            ),
+            vec![
+                &b"text/html"[..],
+                br#"application/json;q="0.9,x""#,
+                b"*/*;q=0.1"
+            ]
+        );
+        assert_eq!(
+            split_header_value("date", b"Wed, 21 Oct 2015 07:28:00 GMT"),
+            vec![&b"Wed, 21 Oct 2015 07:28:00 GMT"[..]]
+        );
+        assert_eq!(
+            split_header_value(
+                "set-cookie",
+                b"a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=/,b=2"
+            ),
+            vec![
+                &b"a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT; Path=/"[..],
+                b"b=2"
+            ]
+        );
+        assert_eq!(
+            split_header_value("cookie", b"a=1; b=2,c=3"),
+            vec![&b"a=1"[..], b"b=2", b"c=3"]
+        );
+    }
+
+    #[test]
+    fn test_join() {
+        assert_eq!(
+            HeaderJoin::of("cookie").join(&["a=1", "b=2"]),
+            Some(b"a=1; b=2".to_vec())
+        );
+        assert_eq!(
+            HeaderJoin::of("vary").join(&["accept", "origin"]),
+            Some(b"accept, origin".to_vec())
+        );
+        assert_eq!(HeaderJoin::of("set-cookie").join(&["a=1", "b=2"]), None);
+        assert_eq!(
+            HeaderJoin::of("user-agent").join(&["curl"]),
+            Some(b"curl".to_vec())
+        );
+        let expanded = expand_joined_headers(&[("vary", "a, b"), ("date", "x, y")]);
+        assert_eq!(expanded.len(), 3);
+    }
+}
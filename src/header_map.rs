@@ -0,0 +1,184 @@
+use std::{fmt, str::FromStr};
+
+use crate::{HeaderStr, HttpHeaderControl};
+
+/// Snapshot of a header block, read with a single hostcall by [`HttpHeaderControl::cached`].
+///
+/// Reads are served from the snapshot. Mutations apply to the snapshot and are written back with a single hostcall
+/// by [`HeaderMap::flush`], or when the map is dropped. Header names are compared case-insensitively.
+/// Headers changed through the [`HttpHeaderControl`] meanwhile are overwritten by the flush.
+pub struct HeaderMap<'a, H: HttpHeaderControl> {
+    control: &'a H,
+    headers: Vec<(String, Vec<u8>)>,
+    dirty: bool,
+}
+
+impl<'a, H: HttpHeaderControl> HeaderMap<'a, H> {
+    pub(crate) fn new(control: &'a H) -> Self {
+        Self {
+            headers: control.all(),
+            control,
+            dirty: false,
+        }
+    }
+
+    /// First value of a header
+    pub fn get(&self, name: impl AsRef<str>) -> Option<&[u8]> {
+        self.get_all(name).next()
+    }
+
+    /// First value of a header, keeping the raw bytes
+    pub fn get_str(&self, name: impl AsRef<str>) -> Option<HeaderStr> {
+        self.get(name).map(|x| HeaderStr::new(x.to_vec()))
+    }
+
+    /// First value of a header as UTF-8. `None` if missing or not UTF-8.
+    pub fn get_utf8(&self, name: impl AsRef<str>) -> Option<&str> {
+        std::str::from_utf8(self.get(name)?).ok()
+    }
+
+    /// First value of a header parsed as `T`, i.e. `content-length` as a `u64`. `None` if missing or invalid.
+    pub fn parse<T: FromStr>(&self, name: impl AsRef<str>) -> Option<T> {
+        self.get_utf8(name)?.trim().parse().ok()
+    }
+
+    /// All values of a header, in order
+    pub fn get_all(&self, name: impl AsRef<str>) -> impl Iterator<Item = &[u8]> {
+        let name = name.as_ref().to_ascii_lowercase();
+        self.headers
+            .iter()
+            .filter(move |(x, _)| x.eq_ignore_ascii_case(&name))
+            .map(|(_, value)| &**value)
+    }
+
+    pub fn contains(&self, name: impl AsRef<str>) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.headers.iter().map(|(name, value)| (&**name, &**value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Replaces all values of a header
+    pub fn set(&mut self, name: impl AsRef<str>, value: impl AsRef<[u8]>) {
+        let name = name.as_ref().to_ascii_lowercase();
+        match self
+            .headers
+            .iter()
+            .position(|(x, _)| x.eq_ignore_ascii_case(&name))
+        {
+            Some(i) => {
+                self.headers[i].1 = value.as_ref().to_vec();
+                let mut index = 0;
+                self.headers.retain(|(x, _)| {
+                    index += 1;
+                    index - 1 <= i || !x.eq_ignore_ascii_case(&name)
+                });
+            }
+            None => self.headers.push((name, value.as_ref().to_vec())),
+        }
+        self.dirty = true;
+    }
+
+    /// Appends a value of a header
+    pub fn add(&mut self, name: impl AsRef<str>, value: impl AsRef<[u8]>) {
+        self.headers
+            .push((name.as_ref().to_ascii_lowercase(), value.as_ref().to_vec()));
+        self.dirty = true;
+    }
+
+    /// Removes all values of a header
+    pub fn remove(&mut self, name: impl AsRef<str>) {
+        let len = self.headers.len();
+        self.headers
+            .retain(|(x, _)| !x.eq_ignore_ascii_case(name.as_ref()));
+        self.dirty |= self.headers.len() != len;
+    }
+
+    /// Whether there are mutations not written back yet
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Writes mutations back to the host, if any
+    pub fn flush(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        let headers: Vec<(&str, &[u8])> = self.iter().collect();
+        self.control.set_all(&headers);
+        self.dirty = false;
+    }
+}
+
+impl<H: HttpHeaderControl> Drop for HeaderMap<'_, H> {
+    fn drop(&mut self) {
+        self.flush();
+        if crate::zeroize::is_sensitive_mode() {
+            for (_, value) in &mut self.headers {
+                crate::zeroize::zeroize_vec(value);
+            }
+        }
+    }
+}
+
+impl<H: HttpHeaderControl> fmt::Debug for HeaderMap<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(
+                self.iter()
+                    .map(|(name, value)| (name, String::from_utf8_lossy(value))),
+            )
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        hostcalls::MapType, property::envoy::Attributes, testing::reset_host, RequestHeaders,
+    };
+
+    #[test]
+    fn test_header_map() {
+        reset_host();
+        crate::testing::host::with_host(|host| {
+            *host.header_map(MapType::HttpRequestHeaders) = vec![
+                (":path".to_string(), b"/".to_vec()),
+                ("content-length".to_string(), b"12".to_vec()),
+                ("x-a".to_string(), b"1".to_vec()),
+                ("x-a".to_string(), b"2".to_vec()),
+            ]
+        });
+        let headers = RequestHeaders {
+            header_count: 4,
+            end_of_stream: false,
+            attributes: Attributes::get(),
+        };
+        let mut map = headers.cached();
+        assert_eq!(map.parse::<u64>("Content-Length"), Some(12));
+        assert_eq!(map.get_all("x-a").count(), 2);
+        map.set("X-A", "3");
+        map.add("x-b", "4");
+        map.remove("content-length");
+        assert_eq!(headers.get("x-a"), Some(b"1".to_vec()));
+        drop(map);
+        assert_eq!(
+            headers.all(),
+            vec![
+                (":path".to_string(), b"/".to_vec()),
+                ("x-a".to_string(), b"3".to_vec()),
+                ("x-b".to_string(), b"4".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,357 @@
+//! Metrics declared in plugin configuration, labelled with header and attribute values of each request.
+//!
+//! ```json
+//! [
+//!   {
+//!     "name": "requests",
+//!     "labels": [
+//!       { "name": "class", "property": "response.code", "transform": "status_class" },
+//!       { "name": "route", "property": "xds.route_name", "default": "none" },
+//!       { "name": "tenant", "request_header": "x-tenant", "values": ["a", "b"] }
+//!     ]
+//!   },
+//!   { "name": "request_duration_ms", "type": "histogram", "value": "request.duration" }
+//! ]
+//! ```
+//!
+//! Each distinct label combination is a separate metric named `{name}.{label}.{value}...`,
+//! defined once per root context through the metric handle cache.
+
+use std::{cell::RefCell, collections::HashSet, fmt};
+
+use crate::{
+    access_log::property_value,
+    hostcalls::{self, MapType},
+    json::Value,
+    metrics::MetricKind,
+    Counter, Gauge, Histogram,
+};
+
+/// Label value used for label combinations beyond a rule's `max_series`
+pub const OVERFLOW_LABEL: &str = "overflow";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderMetricsError(pub String);
+
+impl fmt::Display for HeaderMetricsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid metric configuration: {}", self.0)
+    }
+}
+
+impl std::error::Error for HeaderMetricsError {}
+
+/// Where a label value is read from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LabelSource {
+    RequestHeader(String),
+    ResponseHeader(String),
+    /// An attribute path, i.e. `response.code`
+    Property(String),
+}
+
+/// Normalization applied to a label value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LabelTransform {
+    #[default]
+    None,
+    /// `404` becomes `4xx`
+    StatusClass,
+    Lowercase,
+}
+
+impl LabelTransform {
+    fn apply(&self, value: String) -> String {
+        match self {
+            LabelTransform::None => value,
+            LabelTransform::StatusClass => match value.as_bytes().first() {
+                Some(c @ b'1'..=b'5') if value.len() == 3 => format!("{}xx", *c as char),
+                _ => value,
+            },
+            LabelTransform::Lowercase => value.to_ascii_lowercase(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelRule {
+    pub name: String,
+    pub source: LabelSource,
+    pub transform: LabelTransform,
+    /// Used when the source is absent. Default is `unknown`.
+    pub default: String,
+    /// If set, values outside this list are replaced with `other`, bounding cardinality
+    pub allowed: Option<Vec<String>>,
+}
+
+impl LabelRule {
+    /// Resolves the label value from a raw source value
+    pub fn value(&self, raw: Option<String>) -> String {
+        let Some(raw) = raw.filter(|x| !x.is_empty()) else {
+            return self.default.clone();
+        };
+        let value = self.transform.apply(raw);
+        match &self.allowed {
+            Some(allowed) if !allowed.contains(&value) => "other".to_string(),
+            _ => sanitize(&value),
+        }
+    }
+}
+
+/// Replaces characters that would split or break a stat name
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// A metric derived from request values
+#[derive(Debug)]
+pub struct MetricRule {
+    pub name: String,
+    pub kind: MetricKind,
+    /// Attribute path recorded for gauges and histograms, or added for counters. Counters increment by 1 when unset.
+    pub value: Option<String>,
+    pub labels: Vec<LabelRule>,
+    /// Distinct label combinations tracked before new ones are folded into [`OVERFLOW_LABEL`]. Default is 1000.
+    pub max_series: usize,
+    series: RefCell<HashSet<String>>,
+}
+
+impl MetricRule {
+    /// Metric name for the current label values, read through `lookup`
+    pub fn series_name(&self, lookup: impl Fn(&LabelSource) -> Option<String>) -> String {
+        let mut name = self.name.clone();
+        for label in &self.labels {
+            name.push('.');
+            name.push_str(&label.name);
+            name.push('.');
+            name.push_str(&label.value(lookup(&label.source)));
+        }
+        let mut series = self.series.borrow_mut();
+        if series.contains(&name) {
+            return name;
+        }
+        if series.len() >= self.max_series {
+            let mut name = self.name.clone();
+            for label in &self.labels {
+                name.push('.');
+                name.push_str(&label.name);
+                name.push('.');
+                name.push_str(OVERFLOW_LABEL);
+            }
+            return name;
+        }
+        series.insert(name.clone());
+        name
+    }
+
+    fn from_config(config: &Value) -> Result<Self, HeaderMetricsError> {
+        let error = |message: &str| HeaderMetricsError(message.to_string());
+        let name = config
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| error("metric is missing a name"))?;
+        let kind = match config.get("type").and_then(Value::as_str) {
+            None | Some("counter") => MetricKind::Counter,
+            Some("gauge") => MetricKind::Gauge,
+            Some("histogram") => MetricKind::Histogram,
+            Some(other) => return Err(error(&format!("unknown metric type {other}"))),
+        };
+        let value = config
+            .get("value")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if kind != MetricKind::Counter && value.is_none() {
+            return Err(error(&format!("{name} requires a value")));
+        }
+        let mut labels = vec![];
+        for label in config
+            .get("labels")
+            .and_then(Value::as_array)
+            .unwrap_or_default()
+        {
+            let label_name = label
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| error(&format!("label of {name} is missing a name")))?;
+            let string = |key: &str| label.get(key).and_then(Value::as_str).map(str::to_string);
+            let source = if let Some(header) = string("request_header") {
+                LabelSource::RequestHeader(header.to_ascii_lowercase())
+            } else if let Some(header) = string("response_header") {
+                LabelSource::ResponseHeader(header.to_ascii_lowercase())
+            } else if let Some(path) = string("property") {
+                LabelSource::Property(path)
+            } else {
+                return Err(error(&format!(
+                    "label {label_name} of {name} has no source"
+                )));
+            };
+            let transform = match string("transform").as_deref() {
+                None => LabelTransform::None,
+                Some("status_class") => LabelTransform::StatusClass,
+                Some("lowercase") => LabelTransform::Lowercase,
+                Some(other) => return Err(error(&format!("unknown transform {other}"))),
+            };
+            labels.push(LabelRule {
+                name: sanitize(label_name),
+                source,
+                transform,
+                default: string("default").unwrap_or_else(|| "unknown".to_string()),
+                allowed: label.get("values").and_then(Value::as_array).map(|values| {
+                    values
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(str::to_string)
+                        .collect()
+                }),
+            });
+        }
+        Ok(Self {
+            name: name.to_string(),
+            kind,
+            value,
+            labels,
+            max_series: config
+                .get("max_series")
+                .and_then(Value::as_i64)
+                .map(|x| x.max(0) as usize)
+                .unwrap_or(1000),
+            series: Default::default(),
+        })
+    }
+}
+
+/// Evaluates configured [`MetricRule`]s against each request
+#[derive(Debug, Default)]
+pub struct HeaderMetrics {
+    pub rules: Vec<MetricRule>,
+}
+
+impl HeaderMetrics {
+    /// Builds rules from a JSON array of metric definitions, see the module documentation
+    pub fn from_config(config: &Value) -> Result<Self, HeaderMetricsError> {
+        let rules = config
+            .as_array()
+            .ok_or_else(|| HeaderMetricsError("expected an array of metrics".to_string()))?
+            .iter()
+            .map(MetricRule::from_config)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Parses rules from raw plugin configuration
+    pub fn parse(config: &[u8]) -> Result<Self, HeaderMetricsError> {
+        let config = Value::parse(config).map_err(|e| HeaderMetricsError(e.to_string()))?;
+        Self::from_config(&config)
+    }
+
+    /// Records all metrics for the current request. Call from `on_log`, when request and response values are final.
+    pub fn record(&self) {
+        for rule in &self.rules {
+            let name = rule.series_name(lookup);
+            let value = rule.value.as_deref().map(property_value);
+            let value = match &value {
+                Some(Value::Number(x)) => Some(*x as i64),
+                Some(_) => continue,
+                None => None,
+            };
+            match rule.kind {
+                MetricKind::Counter => Counter::define(name).increment(value.unwrap_or(1)),
+                MetricKind::Gauge => Gauge::define(name).record(value.unwrap_or_default() as u64),
+                MetricKind::Histogram => {
+                    Histogram::define(name).record(value.unwrap_or_default() as u64)
+                }
+            }
+        }
+    }
+}
+
+fn lookup(source: &LabelSource) -> Option<String> {
+    let header = |map, name: &str| {
+        hostcalls::get_map_value(map, name)
+            .ok()
+            .flatten()
+            .map(|x| String::from_utf8_lossy(&x).into_owned())
+    };
+    match source {
+        LabelSource::RequestHeader(name) => header(MapType::HttpRequestHeaders, name),
+        LabelSource::ResponseHeader(name) => header(MapType::HttpResponseHeaders, name),
+        LabelSource::Property(path) => match property_value(path) {
+            Value::Null => None,
+            Value::String(x) => Some(x),
+            Value::Number(x) if x.fract() == 0.0 => Some((x as i64).to_string()),
+            other => Some(other.to_string()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_series_name() {
+        let metrics = HeaderMetrics::parse(
+            br#"[{"name": "requests", "max_series": 2, "labels": [
+                {"name": "class", "property": "response.code", "transform": "status_class"},
+                {"name": "tenant", "request_header": "X-Tenant", "values": ["a", "b"]}
+            ]}]"#,
+        )
+        .unwrap();
+        let rule = &metrics.rules[0];
+        assert_eq!(
+            rule.labels[1].source,
+            LabelSource::RequestHeader("x-tenant".to_string())
+        );
+        let lookup = |code: &'static str, tenant: Option<&'static str>| {
+            move |source: &LabelSource| match source {
+                LabelSource::Property(_) => Some(code.to_string()),
+                _ => tenant.map(str::to_string),
+            }
+        };
+        assert_eq!(
+            rule.series_name(lookup("404", Some("a"))),
+            "requests.class.4xx.tenant.a"
+        );
+        assert_eq!(
+            rule.series_name(lookup("200", Some("zzz"))),
+            "requests.class.2xx.tenant.other"
+        );
+        assert_eq!(
+            rule.series_name(lookup("503", None)),
+            "requests.class.overflow.tenant.overflow"
+        );
+        assert_eq!(
+            rule.series_name(lookup("404", Some("a"))),
+            "requests.class.4xx.tenant.a"
+        );
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert!(HeaderMetrics::parse(br#"[{"name": "x", "type": "histogram"}]"#).is_err());
+        assert!(HeaderMetrics::parse(br#"[{"name": "x", "labels": [{"name": "y"}]}]"#).is_err());
+        assert!(HeaderMetrics::parse(br#"{"name": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_label_value() {
+        let label = LabelRule {
+            name: "host".to_string(),
+            source: LabelSource::RequestHeader("host".to_string()),
+            transform: LabelTransform::Lowercase,
+            default: "none".to_string(),
+            allowed: None,
+        };
+        assert_eq!(
+            label.value(Some("API.Example.com".to_string())),
+            "api_example_com"
+        );
+        assert_eq!(label.value(None), "none");
+    }
+}
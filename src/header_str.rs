@@ -0,0 +1,189 @@
+use std::{cell::OnceCell, fmt, str::Utf8Error};
+
+/// Raw header or property bytes, with UTF-8 validation performed once on first use.
+/// Never converts lossily: callers pick [`HeaderStr::as_str`], [`HeaderStr::to_latin1_lossy`] or work on the bytes.
+#[derive(Clone, Default)]
+pub struct HeaderStr {
+    bytes: Vec<u8>,
+    utf8: OnceCell<Result<(), Utf8Error>>,
+}
+
+impl HeaderStr {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self {
+            bytes: bytes.into(),
+            utf8: OnceCell::new(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The value as UTF-8, or the validation error. Validation is cached.
+    pub fn as_str(&self) -> Result<&str, Utf8Error> {
+        (*self
+            .utf8
+            .get_or_init(|| std::str::from_utf8(&self.bytes).map(|_| ())))?;
+        // SAFETY: validated above
+        Ok(unsafe { std::str::from_utf8_unchecked(&self.bytes) })
+    }
+
+    pub fn is_utf8(&self) -> bool {
+        self.as_str().is_ok()
+    }
+
+    /// Decodes the bytes as ISO-8859-1, the historical HTTP header charset. Every byte maps to one character,
+    /// so this never fails, but multi-byte UTF-8 sequences come out as several characters.
+    pub fn to_latin1_lossy(&self) -> String {
+        self.bytes.iter().map(|&b| b as char).collect()
+    }
+
+    /// Decodes the bytes as UTF-8, replacing invalid sequences with `U+FFFD`
+    pub fn to_utf8_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+
+    /// Decodes `%XX` escapes. Invalid escapes are kept as is. The result is not checked for UTF-8.
+    pub fn percent_decode(&self) -> HeaderStr {
+        HeaderStr::new(percent_decode(&self.bytes, false))
+    }
+
+    /// Compares against an ASCII value case insensitively, i.e. for header values like `chunked`
+    pub fn eq_ignore_ascii_case(&self, other: &str) -> bool {
+        self.bytes.eq_ignore_ascii_case(other.as_bytes())
+    }
+}
+
+/// Decodes `%XX` escapes, and `+` as a space if `plus_as_space` (form encoded queries). Invalid escapes are kept as is.
+pub(crate) fn percent_decode(input: &[u8], plus_as_space: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'%' => {
+                let hex = |b: u8| (b as char).to_digit(16);
+                if let Some((high, low)) = input
+                    .get(i + 1..i + 3)
+                    .and_then(|x| Some((hex(x[0])?, hex(x[1])?)))
+                {
+                    out.push((high * 16 + low) as u8);
+                    i += 3;
+                    continue;
+                }
+                out.push(b'%');
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    out
+}
+
+impl From<Vec<u8>> for HeaderStr {
+    fn from(value: Vec<u8>) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<&str> for HeaderStr {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+impl AsRef<[u8]> for HeaderStr {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl PartialEq for HeaderStr {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for HeaderStr {}
+
+impl PartialEq<str> for HeaderStr {
+    fn eq(&self, other: &str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for HeaderStr {
+    fn eq(&self, other: &&str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
+impl PartialEq<[u8]> for HeaderStr {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.bytes == other
+    }
+}
+
+/// Valid UTF-8 is written as is, invalid bytes are escaped as `\xNN`
+impl fmt::Display for HeaderStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Ok(value) = self.as_str() {
+            return f.write_str(value);
+        }
+        for chunk in self.bytes.utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            for byte in chunk.invalid() {
+                write!(f, "\\x{byte:02x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for HeaderStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_str() {
+        let value = HeaderStr::from("caf\u{e9}");
+        assert_eq!(value.as_str(), Ok("caf\u{e9}"));
+        assert_eq!(value.to_latin1_lossy(), "caf\u{c3}\u{a9}");
+
+        let value = HeaderStr::new(vec![b'a', 0xff, b'b']);
+        assert!(value.as_str().is_err());
+        assert_eq!(value.to_string(), "a\\xffb");
+        assert_eq!(value.to_latin1_lossy(), "a\u{ff}b");
+
+        assert_eq!(HeaderStr::from("a%20b%2").percent_decode(), "a b%2");
+        assert_eq!(HeaderStr::from("%zz%41").percent_decode(), "%zzA");
+        assert_eq!(HeaderStr::from("a+b%2B").percent_decode(), "a+b+");
+        assert_eq!(percent_decode(b"a+b%2B%", true), b"a b+%");
+        assert_eq!(
+            HeaderStr::from("%C3%A9%FF")
+                .percent_decode()
+                .to_utf8_lossy(),
+            "\u{e9}\u{fffd}"
+        );
+        assert!(HeaderStr::from("Chunked").eq_ignore_ascii_case("chunked"));
+    }
+}
@@ -0,0 +1,139 @@
+/// Headers that only apply to a single connection, regardless of the `Connection` header
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// What to do with hop-by-hop headers when copying headers onto another request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum HopPolicy {
+    /// Remove all hop-by-hop headers, except `te: trailers` which HTTP/2 and GRPC allow
+    #[default]
+    Strip,
+    /// Like `Strip`, but keep `upgrade` and `connection: upgrade` for protocol upgrades, i.e. websockets
+    PreserveUpgrade,
+    /// Copy all headers unchanged
+    Preserve,
+}
+
+/// Identifies hop-by-hop headers of a header map: the fixed set from RFC 9110, any `Proxy-*` header,
+/// and headers listed in `Connection`, including HTTP/1.0 style `Connection: keep-alive`.
+#[derive(Clone, Debug, Default)]
+pub struct HopByHop {
+    connection_listed: Vec<String>,
+}
+
+impl HopByHop {
+    /// Reads the `Connection` header tokens of `headers`
+    pub fn from_headers<N: AsRef<str>, V: AsRef<[u8]>>(headers: &[(N, V)]) -> Self {
+        let connection_listed = headers
+            .iter()
+            .filter(|(name, _)| name.as_ref().eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, value)| {
+                String::from_utf8_lossy(value.as_ref())
+                    .split(',')
+                    .map(|x| x.trim().to_ascii_lowercase())
+                    .filter(|x| !x.is_empty())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        Self { connection_listed }
+    }
+
+    /// Whether `name` only applies to the current connection
+    pub fn is_hop_by_hop(&self, name: &str) -> bool {
+        HOP_BY_HOP.iter().any(|x| x.eq_ignore_ascii_case(name))
+            || name
+                .get(..6)
+                .is_some_and(|x| x.eq_ignore_ascii_case("proxy-"))
+            || self
+                .connection_listed
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(name))
+    }
+
+    /// Whether the header should be kept under `policy`
+    pub fn keep(&self, policy: HopPolicy, name: &str, value: &[u8]) -> bool {
+        if policy == HopPolicy::Preserve || !self.is_hop_by_hop(name) {
+            return true;
+        }
+        if name.eq_ignore_ascii_case("te") {
+            return value.trim_ascii().eq_ignore_ascii_case(b"trailers");
+        }
+        if policy == HopPolicy::PreserveUpgrade {
+            if name.eq_ignore_ascii_case("upgrade") {
+                return true;
+            }
+            if name.eq_ignore_ascii_case("connection") {
+                return self.connection_listed.iter().any(|x| x == "upgrade");
+            }
+        }
+        false
+    }
+
+    /// Removes headers not kept under `policy`
+    pub fn filter<N: AsRef<str>, V: AsRef<[u8]>>(
+        &self,
+        headers: impl IntoIterator<Item = (N, V)>,
+        policy: HopPolicy,
+    ) -> Vec<(N, V)> {
+        headers
+            .into_iter()
+            .filter(|(name, value)| self.keep(policy, name.as_ref(), value.as_ref()))
+            .collect()
+    }
+}
+
+/// Removes hop-by-hop headers from `headers` under `policy`, i.e. before copying request headers onto an [`crate::HttpCall`].
+pub fn strip_hop_by_hop<N: AsRef<str>, V: AsRef<[u8]>>(
+    headers: Vec<(N, V)>,
+    policy: HopPolicy,
+) -> Vec<(N, V)> {
+    let hop = HopByHop::from_headers(&headers);
+    hop.filter(headers, policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        let headers: Vec<(&str, &[u8])> = vec![
+            (":path", b"/"),
+            ("Connection", b"keep-alive, X-Debug, Upgrade"),
+            ("keep-alive", b"timeout=5"),
+            ("x-debug", b"1"),
+            ("te", b"trailers"),
+            ("upgrade", b"websocket"),
+            ("proxy-authorization", b"basic"),
+            ("transfer-encoding", b"chunked"),
+            ("x-request-id", b"abc"),
+        ];
+        fn names<'a>(headers: Vec<(&'a str, &[u8])>) -> Vec<&'a str> {
+            headers.into_iter().map(|x| x.0).collect()
+        }
+        assert_eq!(
+            names(strip_hop_by_hop(headers.clone(), HopPolicy::Strip)),
+            vec![":path", "te", "x-request-id"]
+        );
+        assert_eq!(
+            names(strip_hop_by_hop(
+                headers.clone(),
+                HopPolicy::PreserveUpgrade
+            )),
+            vec![":path", "Connection", "te", "upgrade", "x-request-id"]
+        );
+        assert_eq!(
+            strip_hop_by_hop(headers.clone(), HopPolicy::Preserve).len(),
+            headers.len()
+        );
+        let te: Vec<(&str, &[u8])> = vec![("te", b"gzip")];
+        assert!(strip_hop_by_hop(te, HopPolicy::Strip).is_empty());
+    }
+}
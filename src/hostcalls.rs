@@ -1,9 +1,19 @@
-#![allow(clippy::type_complexity)]
+#![allow(clippy::type_complexity, clippy::too_many_arguments)]
 
+use std::cell::Cell;
 use std::ptr::{null, null_mut, NonNull};
+#[cfg(not(target_arch = "wasm32"))]
+use std::rc::Rc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::Status;
+use crate::{HeaderOverflow, Status};
+
+#[cfg(all(feature = "testing", not(target_arch = "wasm32")))]
+pub(crate) use utils::deserialize_map_bytes;
+pub(crate) use utils::with_buffer;
+pub use utils::{
+    serialize_map_into, serialize_property_path_into, set_serialization_buffer_capacity,
+};
 
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
@@ -55,6 +65,9 @@ pub enum MapType {
     GrpcReceiveTrailingMetadata = 5,
     HttpCallResponseHeaders = 6,
     HttpCallResponseTrailers = 7,
+    /// HTTP/2 METADATA frames, readable during the metadata callbacks of hosts supporting them
+    HttpRequestMetadata = 8,
+    HttpResponseMetadata = 9,
 }
 
 #[repr(u32)]
@@ -66,112 +79,240 @@ pub enum MetricType {
     Histogram = 2,
 }
 
-extern "C" {
-    pub fn proxy_log(level: LogLevel, message_data: *const u8, message_size: usize) -> Status;
-    pub fn proxy_get_log_level(return_level: *mut LogLevel) -> Status;
-    pub fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status;
-    pub fn proxy_set_tick_period_milliseconds(period: u32) -> Status;
-    pub fn proxy_get_buffer_bytes(
+/// Declares the host ABI once. On Wasm, hostcalls are the imported functions. In native mode, they dispatch to the
+/// [`Host`] of the thread, whose methods default to the imported functions.
+macro_rules! host_abi {
+    ($($(#[$attr:meta])* fn $name:ident($($arg:ident: $ty:ty),* $(,)?) -> Status;)*) => {
+        mod imports {
+            use super::*;
+
+            extern "C" {
+                $($(#[$attr])* pub fn $name($($arg: $ty),*) -> Status;)*
+            }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        use imports::*;
+
+        /// The `proxy_*` hostcalls, for native embedders and test harnesses replacing the host the plugin is linked
+        /// against. Methods default to the linked functions, so implementations only override what they provide.
+        ///
+        /// Methods follow the proxy-wasm ABI: pointers are valid for the given sizes, and returned data is allocated with
+        /// [`crate::proxy_on_memory_allocate`], i.e. through [`return_bytes`].
+        #[cfg(not(target_arch = "wasm32"))]
+        #[allow(clippy::missing_safety_doc)]
+        pub trait Host {
+            $(
+                $(#[$attr])*
+                unsafe fn $name(&self, $($arg: $ty),*) -> Status {
+                    imports::$name($($arg),*)
+                }
+            )*
+
+            unsafe fn proxy_write_upstream(&self, data: *const u8, size: usize) -> Status {
+                match &*PROXY_WRITE_UPSTREAM {
+                    Some(proxy_write_upstream) => proxy_write_upstream(data, size),
+                    None => Status::InternalFailure,
+                }
+            }
+
+            unsafe fn proxy_write_downstream(&self, data: *const u8, size: usize) -> Status {
+                match &*PROXY_WRITE_DOWNSTREAM {
+                    Some(proxy_write_downstream) => proxy_write_downstream(data, size),
+                    None => Status::InternalFailure,
+                }
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        mod dispatch {
+            use super::*;
+
+            $(
+                $(#[$attr])*
+                pub unsafe fn $name($($arg: $ty),*) -> Status {
+                    match current_host() {
+                        Some(host) => host.$name($($arg),*),
+                        None => imports::$name($($arg),*),
+                    }
+                }
+            )*
+
+            pub unsafe fn proxy_write_upstream(data: *const u8, size: usize) -> Status {
+                current_host().unwrap_or_else(|| Rc::new(LinkedHost)).proxy_write_upstream(data, size)
+            }
+
+            pub unsafe fn proxy_write_downstream(data: *const u8, size: usize) -> Status {
+                current_host().unwrap_or_else(|| Rc::new(LinkedHost)).proxy_write_downstream(data, size)
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        use dispatch::*;
+    };
+}
+
+/// The host the plugin is linked against
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkedHost;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Host for LinkedHost {}
+
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    static HOST: std::cell::RefCell<Option<Rc<dyn Host>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Replaces the host of the current thread. Hostcalls of contexts on this thread go to `host` from now on.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_host(host: impl Host + 'static) {
+    HOST.with_borrow_mut(|x| *x = Some(Rc::new(host)));
+}
+
+/// Restores the [`LinkedHost`] on the current thread
+#[cfg(not(target_arch = "wasm32"))]
+pub fn clear_host() {
+    HOST.with_borrow_mut(|x| *x = None);
+}
+
+/// Cloned out, so hosts may call back into the plugin
+#[cfg(not(target_arch = "wasm32"))]
+fn current_host() -> Option<Rc<dyn Host>> {
+    HOST.with_borrow(|x| x.clone())
+}
+
+/// Copies `data` into plugin memory through [`crate::proxy_on_memory_allocate`], the way hosts return data
+///
+/// # Safety
+/// `return_data` and `return_size` must be valid for writes.
+#[cfg(not(target_arch = "wasm32"))]
+pub unsafe fn return_bytes(data: &[u8], return_data: *mut *mut u8, return_size: *mut usize) {
+    if data.is_empty() {
+        *return_data = null_mut();
+        *return_size = 0;
+        return;
+    }
+    let out = crate::proxy_on_memory_allocate(data.len());
+    std::ptr::copy_nonoverlapping(data.as_ptr(), out, data.len());
+    *return_data = out;
+    *return_size = data.len();
+}
+
+host_abi! {
+    fn proxy_log(level: LogLevel, message_data: *const u8, message_size: usize) -> Status;
+    #[cfg(not(feature = "abi-0-2-0"))]
+    fn proxy_get_log_level(return_level: *mut LogLevel) -> Status;
+    fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status;
+    fn proxy_set_tick_period_milliseconds(period: u32) -> Status;
+    fn proxy_get_buffer_bytes(
         buffer_type: BufferType,
         start: usize,
         max_size: usize,
         return_buffer_data: *mut *mut u8,
         return_buffer_size: *mut usize,
     ) -> Status;
-    pub fn proxy_set_buffer_bytes(
+    fn proxy_set_buffer_bytes(
         buffer_type: BufferType,
         start: usize,
         size: usize,
         buffer_data: *const u8,
         buffer_size: usize,
     ) -> Status;
-    pub fn proxy_get_header_map_pairs(
+    fn proxy_get_header_map_pairs(
         map_type: MapType,
         return_map_data: *mut *mut u8,
         return_map_size: *mut usize,
     ) -> Status;
-    pub fn proxy_set_header_map_pairs(
+    fn proxy_set_header_map_pairs(
         map_type: MapType,
         map_data: *const u8,
         map_size: usize,
     ) -> Status;
-    pub fn proxy_get_header_map_value(
+    fn proxy_get_header_map_value(
         map_type: MapType,
         key_data: *const u8,
         key_size: usize,
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
     ) -> Status;
-    pub fn proxy_replace_header_map_value(
+    fn proxy_replace_header_map_value(
         map_type: MapType,
         key_data: *const u8,
         key_size: usize,
         value_data: *const u8,
         value_size: usize,
     ) -> Status;
-    pub fn proxy_remove_header_map_value(
+    fn proxy_remove_header_map_value(
         map_type: MapType,
         key_data: *const u8,
         key_size: usize,
     ) -> Status;
-    pub fn proxy_add_header_map_value(
+    fn proxy_add_header_map_value(
         map_type: MapType,
         key_data: *const u8,
         key_size: usize,
         value_data: *const u8,
         value_size: usize,
     ) -> Status;
-    pub fn proxy_get_property(
+    fn proxy_get_property(
         path_data: *const u8,
         path_size: usize,
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
     ) -> Status;
-    pub fn proxy_set_property(
+    fn proxy_set_property(
         path_data: *const u8,
         path_size: usize,
         value_data: *const u8,
         value_size: usize,
     ) -> Status;
-    pub fn proxy_get_shared_data(
+    fn proxy_get_shared_data(
         key_data: *const u8,
         key_size: usize,
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
         return_cas: *mut u32,
     ) -> Status;
-    pub fn proxy_set_shared_data(
+    fn proxy_set_shared_data(
         key_data: *const u8,
         key_size: usize,
         value_data: *const u8,
         value_size: usize,
         cas: u32,
     ) -> Status;
-    pub fn proxy_register_shared_queue(
+    fn proxy_register_shared_queue(
         name_data: *const u8,
         name_size: usize,
         return_id: *mut u32,
     ) -> Status;
-    pub fn proxy_resolve_shared_queue(
+    fn proxy_resolve_shared_queue(
         vm_id_data: *const u8,
         vm_id_size: usize,
         name_data: *const u8,
         name_size: usize,
         return_id: *mut u32,
     ) -> Status;
-    pub fn proxy_dequeue_shared_queue(
+    fn proxy_dequeue_shared_queue(
         queue_id: u32,
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
     ) -> Status;
-    pub fn proxy_enqueue_shared_queue(
+    fn proxy_enqueue_shared_queue(
         queue_id: u32,
         value_data: *const u8,
         value_size: usize,
     ) -> Status;
-    pub fn proxy_continue_stream(stream_type: StreamType) -> Status;
-    pub fn proxy_close_stream(stream_type: StreamType) -> Status;
-    pub fn proxy_send_local_response(
+    #[cfg(not(feature = "abi-0-2-0"))]
+    fn proxy_continue_stream(stream_type: StreamType) -> Status;
+    #[cfg(not(feature = "abi-0-2-0"))]
+    fn proxy_close_stream(stream_type: StreamType) -> Status;
+    #[cfg(feature = "abi-0-2-0")]
+    fn proxy_continue_request() -> Status;
+    #[cfg(feature = "abi-0-2-0")]
+    fn proxy_continue_response() -> Status;
+    fn proxy_send_local_response(
         status_code: u32,
         status_code_details_data: *const u8,
         status_code_details_size: usize,
@@ -181,7 +322,7 @@ extern "C" {
         headers_size: usize,
         grpc_status: i32,
     ) -> Status;
-    pub fn proxy_http_call(
+    fn proxy_http_call(
         upstream_data: *const u8,
         upstream_size: usize,
         headers_data: *const u8,
@@ -193,7 +334,7 @@ extern "C" {
         timeout: u32,
         return_token: *mut u32,
     ) -> Status;
-    pub fn proxy_grpc_call(
+    fn proxy_grpc_call(
         upstream_data: *const u8,
         upstream_size: usize,
         service_name_data: *const u8,
@@ -207,7 +348,7 @@ extern "C" {
         timeout: u32,
         return_callout_id: *mut u32,
     ) -> Status;
-    pub fn proxy_grpc_stream(
+    fn proxy_grpc_stream(
         upstream_data: *const u8,
         upstream_size: usize,
         service_name_data: *const u8,
@@ -218,21 +359,21 @@ extern "C" {
         initial_metadata_size: usize,
         return_stream_id: *mut u32,
     ) -> Status;
-    pub fn proxy_grpc_send(
+    fn proxy_grpc_send(
         token: u32,
         message_ptr: *const u8,
         message_len: usize,
         end_stream: bool,
     ) -> Status;
-    pub fn proxy_grpc_cancel(token_id: u32) -> Status;
-    pub fn proxy_grpc_close(token_id: u32) -> Status;
-    pub fn proxy_get_status(
+    fn proxy_grpc_cancel(token_id: u32) -> Status;
+    fn proxy_grpc_close(token_id: u32) -> Status;
+    fn proxy_get_status(
         return_code: *mut u32,
         return_message_data: *mut *mut u8,
         return_message_size: *mut usize,
     ) -> Status;
-    pub fn proxy_set_effective_context(context_id: u32) -> Status;
-    pub fn proxy_call_foreign_function(
+    fn proxy_set_effective_context(context_id: u32) -> Status;
+    fn proxy_call_foreign_function(
         function_name_data: *const u8,
         function_name_size: usize,
         arguments_data: *const u8,
@@ -240,19 +381,20 @@ extern "C" {
         results_data: *mut *mut u8,
         results_size: *mut usize,
     ) -> Status;
-    pub fn proxy_done() -> Status;
-    pub fn proxy_define_metric(
+    fn proxy_done() -> Status;
+    fn proxy_define_metric(
         metric_type: MetricType,
         name_data: *const u8,
         name_size: usize,
         return_id: *mut u32,
     ) -> Status;
-    pub fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status;
-    pub fn proxy_record_metric(metric_id: u32, value: u64) -> Status;
-    pub fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status;
+    fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status;
+    fn proxy_record_metric(metric_id: u32, value: u64) -> Status;
+    fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status;
 }
 
 pub fn log(level: LogLevel, message: &str) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_log(level, message.as_ptr(), message.len()) {
             Status::Ok => Ok(()),
@@ -261,8 +403,9 @@ pub fn log(level: LogLevel, message: &str) -> Result<(), Status> {
     }
 }
 
-#[allow(dead_code)]
+#[cfg(not(feature = "abi-0-2-0"))]
 pub fn get_log_level() -> Result<LogLevel, Status> {
+    crate::cost::hostcall();
     let mut return_level = LogLevel::Trace;
     unsafe {
         match proxy_get_log_level(&mut return_level) {
@@ -272,7 +415,14 @@ pub fn get_log_level() -> Result<LogLevel, Status> {
     }
 }
 
+/// Not available in ABI 0.2.0
+#[cfg(feature = "abi-0-2-0")]
+pub fn get_log_level() -> Result<LogLevel, Status> {
+    Err(Status::Unimplemented)
+}
+
 pub fn get_current_time() -> Result<SystemTime, Status> {
+    crate::cost::hostcall();
     let mut return_time = 0;
     unsafe {
         match proxy_get_current_time_nanoseconds(&mut return_time) {
@@ -283,6 +433,7 @@ pub fn get_current_time() -> Result<SystemTime, Status> {
 }
 
 pub fn set_tick_period(period: Duration) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_set_tick_period_milliseconds(period.as_millis() as u32) {
             Status::Ok => Ok(()),
@@ -291,11 +442,18 @@ pub fn set_tick_period(period: Duration) -> Result<(), Status> {
     }
 }
 
+/// Takes ownership of bytes allocated by the host
+unsafe fn host_bytes(data: *mut u8, size: usize) -> Vec<u8> {
+    crate::cost::read(size);
+    Vec::from_raw_parts(data, size, size)
+}
+
 pub fn get_buffer(
     buffer_type: BufferType,
     start: usize,
     max_size: usize,
 ) -> Result<Option<Vec<u8>>, Status> {
+    crate::cost::hostcall();
     let mut return_data = null_mut();
     let mut return_size = 0;
     unsafe {
@@ -306,21 +464,76 @@ pub fn get_buffer(
             &mut return_data,
             &mut return_size,
         ) {
-            Status::Ok => Ok(NonNull::new(return_data).map(|return_data| {
-                Vec::from_raw_parts(return_data.as_ptr(), return_size, return_size)
-            })),
+            Status::Ok => Ok(NonNull::new(return_data)
+                .map(|return_data| host_bytes(return_data.as_ptr(), return_size))),
             Status::NotFound => Ok(None),
             e => Err(e),
         }
     }
 }
 
+thread_local! {
+    static ALLOCATION_TARGET: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// Takes the caller provided buffer registered by [`get_buffer_into`], if the host allocation of `size` bytes fits in it.
+pub(crate) fn take_allocation_target(size: usize) -> Option<*mut u8> {
+    let (ptr, capacity) = ALLOCATION_TARGET.with(|x| x.take())?;
+    (size <= capacity).then_some(ptr as *mut u8)
+}
+
+/// Like [`get_buffer`], but reads into `out` (replacing its content). When the data fits in the capacity of `out`,
+/// the host writes directly into it instead of a fresh allocation. Returns `false` if there is no data.
+pub fn get_buffer_into(
+    buffer_type: BufferType,
+    start: usize,
+    max_size: usize,
+    out: &mut Vec<u8>,
+) -> Result<bool, Status> {
+    crate::cost::hostcall();
+    out.clear();
+    out.reserve(max_size);
+    let target = out.as_mut_ptr();
+    let mut return_data = null_mut();
+    let mut return_size = 0;
+    ALLOCATION_TARGET.with(|x| x.set(Some((target as usize, out.capacity()))));
+    let status = unsafe {
+        proxy_get_buffer_bytes(
+            buffer_type,
+            start,
+            max_size,
+            &mut return_data,
+            &mut return_size,
+        )
+    };
+    ALLOCATION_TARGET.with(|x| x.set(None));
+    match status {
+        Status::Ok => {
+            let Some(return_data) = NonNull::new(return_data) else {
+                return Ok(false);
+            };
+            if return_data.as_ptr() == target {
+                crate::cost::read(return_size);
+                unsafe { out.set_len(return_size) };
+            } else {
+                let data = unsafe { host_bytes(return_data.as_ptr(), return_size) };
+                out.extend_from_slice(&data);
+            }
+            Ok(true)
+        }
+        Status::NotFound => Ok(false),
+        e => Err(e),
+    }
+}
+
 pub fn set_buffer(
     buffer_type: BufferType,
     start: usize,
     size: usize,
     value: &[u8],
 ) -> Result<(), Status> {
+    crate::cost::hostcall();
+    crate::cost::written(value.len());
     unsafe {
         match proxy_set_buffer_bytes(buffer_type, start, size, value.as_ptr(), value.len()) {
             Status::Ok => Ok(()),
@@ -330,14 +543,14 @@ pub fn set_buffer(
 }
 
 pub fn get_map(map_type: MapType) -> Result<Option<Vec<(String, Vec<u8>)>>, Status> {
+    crate::cost::hostcall();
     unsafe {
         let mut return_data = null_mut();
         let mut return_size = 0;
         match proxy_get_header_map_pairs(map_type, &mut return_data, &mut return_size) {
             Status::Ok => NonNull::new(return_data)
                 .map(|return_data| {
-                    let serialized_map =
-                        Vec::from_raw_parts(return_data.as_ptr(), return_size, return_size);
+                    let serialized_map = host_bytes(return_data.as_ptr(), return_size);
                     utils::deserialize_map_bytes(&serialized_map)
                 })
                 .transpose(),
@@ -347,17 +560,53 @@ pub fn get_map(map_type: MapType) -> Result<Option<Vec<(String, Vec<u8>)>>, Stat
     }
 }
 
-pub fn set_map(map_type: MapType, map: &[(&str, &[u8])]) -> Result<(), Status> {
-    let serialized_map = utils::serialize_map(map);
+/// Like [`get_map`], refusing or clipping a map over `max_pairs` pairs or `max_bytes` of names and values.
+/// The host still copies the serialized map into the VM, but it is not deserialized past the limits.
+pub fn get_map_limited(
+    map_type: MapType,
+    max_bytes: usize,
+    max_pairs: usize,
+    clip: bool,
+) -> Result<Option<(Vec<(String, Vec<u8>)>, Option<HeaderOverflow>)>, Status> {
+    crate::cost::hostcall();
     unsafe {
+        let mut return_data = null_mut();
+        let mut return_size = 0;
+        match proxy_get_header_map_pairs(map_type, &mut return_data, &mut return_size) {
+            Status::Ok => NonNull::new(return_data)
+                .map(|return_data| {
+                    let serialized_map = host_bytes(return_data.as_ptr(), return_size);
+                    utils::deserialize_map_bytes_limited(
+                        &serialized_map,
+                        max_bytes,
+                        max_pairs,
+                        clip,
+                    )
+                })
+                .transpose(),
+            Status::NotFound => Ok(None),
+            e => Err(e),
+        }
+    }
+}
+
+pub fn set_map(map_type: MapType, map: &[(&str, &[u8])]) -> Result<(), Status> {
+    crate::cost::hostcall();
+    crate::cost::written(
+        map.iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum(),
+    );
+    utils::with_serialized_map(map, |serialized_map| unsafe {
         match proxy_set_header_map_pairs(map_type, serialized_map.as_ptr(), serialized_map.len()) {
             Status::Ok => Ok(()),
             e => Err(e),
         }
-    }
+    })
 }
 
 pub fn get_map_value(map_type: MapType, key: &str) -> Result<Option<Vec<u8>>, Status> {
+    crate::cost::hostcall();
     let mut return_data = null_mut();
     let mut return_size = 0;
     unsafe {
@@ -368,9 +617,8 @@ pub fn get_map_value(map_type: MapType, key: &str) -> Result<Option<Vec<u8>>, St
             &mut return_data,
             &mut return_size,
         ) {
-            Status::Ok => Ok(NonNull::new(return_data).map(|return_data| {
-                Vec::from_raw_parts(return_data.as_ptr(), return_size, return_size)
-            })),
+            Status::Ok => Ok(NonNull::new(return_data)
+                .map(|return_data| host_bytes(return_data.as_ptr(), return_size))),
             Status::NotFound => Ok(None),
             e => Err(e),
         }
@@ -378,6 +626,8 @@ pub fn get_map_value(map_type: MapType, key: &str) -> Result<Option<Vec<u8>>, St
 }
 
 pub fn set_map_value(map_type: MapType, key: &str, value: Option<&[u8]>) -> Result<(), Status> {
+    crate::cost::hostcall();
+    crate::cost::written(key.len() + value.map_or(0, |x| x.len()));
     unsafe {
         if let Some(value) = value {
             match proxy_replace_header_map_value(
@@ -400,6 +650,8 @@ pub fn set_map_value(map_type: MapType, key: &str, value: Option<&[u8]>) -> Resu
 }
 
 pub fn add_map_value(map_type: MapType, key: &str, value: &[u8]) -> Result<(), Status> {
+    crate::cost::hostcall();
+    crate::cost::written(key.len() + value.len());
     unsafe {
         match proxy_add_header_map_value(
             map_type,
@@ -417,32 +669,32 @@ pub fn add_map_value(map_type: MapType, key: &str, value: &[u8]) -> Result<(), S
 pub fn get_property<S: AsRef<str>>(
     path: impl IntoIterator<Item = S>,
 ) -> Result<Option<Vec<u8>>, Status> {
-    let serialized_path = utils::serialize_property_path(path);
+    crate::cost::hostcall();
     let mut return_data = null_mut();
     let mut return_size = 0;
-    unsafe {
+    utils::with_serialized_property_path(path, |serialized_path| unsafe {
         match proxy_get_property(
             serialized_path.as_ptr(),
             serialized_path.len(),
             &mut return_data,
             &mut return_size,
         ) {
-            Status::Ok => Ok(NonNull::new(return_data).map(|return_data| {
-                Vec::from_raw_parts(return_data.as_ptr(), return_size, return_size)
-            })),
+            Status::Ok => Ok(NonNull::new(return_data)
+                .map(|return_data| host_bytes(return_data.as_ptr(), return_size))),
             Status::NotFound => Ok(None),
             e => Err(e),
         }
-    }
+    })
 }
 
 pub fn set_property<S: AsRef<str>>(
     path: impl IntoIterator<Item = S>,
     value: Option<impl AsRef<[u8]>>,
 ) -> Result<(), Status> {
-    let serialized_path = utils::serialize_property_path(path);
+    crate::cost::hostcall();
     let value = value.as_ref().map(|x| x.as_ref());
-    unsafe {
+    crate::cost::written(value.map_or(0, |x| x.len()));
+    utils::with_serialized_property_path(path, |serialized_path| unsafe {
         match proxy_set_property(
             serialized_path.as_ptr(),
             serialized_path.len(),
@@ -452,10 +704,11 @@ pub fn set_property<S: AsRef<str>>(
             Status::Ok => Ok(()),
             e => Err(e),
         }
-    }
+    })
 }
 
 pub fn get_shared_data(key: impl AsRef<str>) -> Result<(Option<Vec<u8>>, Option<u32>), Status> {
+    crate::cost::hostcall();
     let mut return_data = null_mut();
     let mut return_size = 0;
     let mut return_cas = 0;
@@ -474,9 +727,8 @@ pub fn get_shared_data(key: impl AsRef<str>) -> Result<(Option<Vec<u8>>, Option<
                     cas => Some(cas),
                 };
                 Ok((
-                    NonNull::new(return_data).map(|return_data| {
-                        Vec::from_raw_parts(return_data.as_ptr(), return_size, return_size)
-                    }),
+                    NonNull::new(return_data)
+                        .map(|return_data| host_bytes(return_data.as_ptr(), return_size)),
                     cas,
                 ))
             }
@@ -491,8 +743,10 @@ pub fn set_shared_data(
     value: Option<impl AsRef<[u8]>>,
     cas: Option<u32>,
 ) -> Result<(), Status> {
+    crate::cost::hostcall();
     let key = key.as_ref();
     let value = value.as_ref().map(|x| x.as_ref());
+    crate::cost::written(value.map_or(0, |x| x.len()));
     unsafe {
         match proxy_set_shared_data(
             key.as_ptr(),
@@ -508,6 +762,7 @@ pub fn set_shared_data(
 }
 
 pub fn register_shared_queue(name: impl AsRef<str>) -> Result<u32, Status> {
+    crate::cost::hostcall();
     let name = name.as_ref();
     unsafe {
         let mut return_id = 0;
@@ -522,6 +777,7 @@ pub fn resolve_shared_queue(
     vm_id: impl AsRef<str>,
     name: impl AsRef<str>,
 ) -> Result<Option<u32>, Status> {
+    crate::cost::hostcall();
     let vm_id = vm_id.as_ref();
     let name = name.as_ref();
     let mut return_id = 0;
@@ -541,15 +797,12 @@ pub fn resolve_shared_queue(
 }
 
 pub fn dequeue_shared_queue(queue_id: u32) -> Result<Option<Vec<u8>>, Status> {
+    crate::cost::hostcall();
     let mut return_data = null_mut();
     let mut return_size = 0;
     unsafe {
         match proxy_dequeue_shared_queue(queue_id, &mut return_data, &mut return_size) {
-            Status::Ok => Ok(Some(Vec::from_raw_parts(
-                return_data,
-                return_size,
-                return_size,
-            ))),
+            Status::Ok => Ok(Some(host_bytes(return_data, return_size))),
             Status::Empty => Ok(None),
             e => Err(e),
         }
@@ -557,7 +810,9 @@ pub fn dequeue_shared_queue(queue_id: u32) -> Result<Option<Vec<u8>>, Status> {
 }
 
 pub fn enqueue_shared_queue(queue_id: u32, value: impl AsRef<[u8]>) -> Result<(), Status> {
+    crate::cost::hostcall();
     let value = value.as_ref();
+    crate::cost::written(value.len());
     unsafe {
         match proxy_enqueue_shared_queue(queue_id, value.as_ptr(), value.len()) {
             Status::Ok => Ok(()),
@@ -566,75 +821,80 @@ pub fn enqueue_shared_queue(queue_id: u32, value: impl AsRef<[u8]>) -> Result<()
     }
 }
 
-pub fn resume_downstream() -> Result<(), Status> {
+#[cfg(not(feature = "abi-0-2-0"))]
+fn continue_stream(stream_type: StreamType) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
-        match proxy_continue_stream(StreamType::Downstream) {
+        match proxy_continue_stream(stream_type) {
             Status::Ok => Ok(()),
             e => Err(e),
         }
     }
 }
 
-pub fn resume_upstream() -> Result<(), Status> {
-    unsafe {
-        match proxy_continue_stream(StreamType::Upstream) {
-            Status::Ok => Ok(()),
-            e => Err(e),
+/// ABI 0.2.0 can only resume HTTP streams
+#[cfg(feature = "abi-0-2-0")]
+fn continue_stream(stream_type: StreamType) -> Result<(), Status> {
+    crate::cost::hostcall();
+    let status = unsafe {
+        match stream_type {
+            StreamType::HttpRequest => proxy_continue_request(),
+            StreamType::HttpResponse => proxy_continue_response(),
+            _ => return Err(Status::Unimplemented),
         }
+    };
+    match status {
+        Status::Ok => Ok(()),
+        e => Err(e),
     }
 }
 
-pub fn resume_http_request() -> Result<(), Status> {
+#[cfg(not(feature = "abi-0-2-0"))]
+fn close_stream(stream_type: StreamType) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
-        match proxy_continue_stream(StreamType::HttpRequest) {
+        match proxy_close_stream(stream_type) {
             Status::Ok => Ok(()),
             e => Err(e),
         }
     }
 }
 
+/// ABI 0.2.0 has no way to close or reset a stream
+#[cfg(feature = "abi-0-2-0")]
+fn close_stream(_stream_type: StreamType) -> Result<(), Status> {
+    Err(Status::Unimplemented)
+}
+
+pub fn resume_downstream() -> Result<(), Status> {
+    continue_stream(StreamType::Downstream)
+}
+
+pub fn resume_upstream() -> Result<(), Status> {
+    continue_stream(StreamType::Upstream)
+}
+
+pub fn resume_http_request() -> Result<(), Status> {
+    continue_stream(StreamType::HttpRequest)
+}
+
 pub fn resume_http_response() -> Result<(), Status> {
-    unsafe {
-        match proxy_continue_stream(StreamType::HttpResponse) {
-            Status::Ok => Ok(()),
-            e => Err(e),
-        }
-    }
+    continue_stream(StreamType::HttpResponse)
 }
 
 pub fn close_downstream() -> Result<(), Status> {
-    unsafe {
-        match proxy_close_stream(StreamType::Downstream) {
-            Status::Ok => Ok(()),
-            e => Err(e),
-        }
-    }
+    close_stream(StreamType::Downstream)
 }
 pub fn close_upstream() -> Result<(), Status> {
-    unsafe {
-        match proxy_close_stream(StreamType::Upstream) {
-            Status::Ok => Ok(()),
-            e => Err(e),
-        }
-    }
+    close_stream(StreamType::Upstream)
 }
 
 pub fn reset_http_request() -> Result<(), Status> {
-    unsafe {
-        match proxy_close_stream(StreamType::HttpRequest) {
-            Status::Ok => Ok(()),
-            e => Err(e),
-        }
-    }
+    close_stream(StreamType::HttpRequest)
 }
 
 pub fn reset_http_response() -> Result<(), Status> {
-    unsafe {
-        match proxy_close_stream(StreamType::HttpResponse) {
-            Status::Ok => Ok(()),
-            e => Err(e),
-        }
-    }
+    close_stream(StreamType::HttpResponse)
 }
 
 pub fn send_http_response(
@@ -642,8 +902,9 @@ pub fn send_http_response(
     headers: &[(&str, &[u8])],
     body: Option<&[u8]>,
 ) -> Result<(), Status> {
-    let serialized_headers = utils::serialize_map(headers);
-    unsafe {
+    crate::cost::hostcall();
+    crate::cost::written(body.map_or(0, |x| x.len()));
+    utils::with_serialized_map(headers, |serialized_headers| unsafe {
         match proxy_send_local_response(
             status_code,
             null(),
@@ -657,7 +918,7 @@ pub fn send_http_response(
             Status::Ok => Ok(()),
             e => Err(e),
         }
-    }
+    })
 }
 
 pub fn dispatch_http_call(
@@ -667,26 +928,28 @@ pub fn dispatch_http_call(
     trailers: &[(&str, &[u8])],
     timeout: Duration,
 ) -> Result<u32, Status> {
-    let serialized_headers = utils::serialize_map(headers);
-    let serialized_trailers = utils::serialize_map(trailers);
+    crate::cost::hostcall();
+    crate::cost::written(body.map_or(0, |x| x.len()));
     let mut return_token = 0;
-    unsafe {
-        match proxy_http_call(
-            upstream.as_ptr(),
-            upstream.len(),
-            serialized_headers.as_ptr(),
-            serialized_headers.len(),
-            body.map_or(null(), |body| body.as_ptr()),
-            body.map_or(0, |body| body.len()),
-            serialized_trailers.as_ptr(),
-            serialized_trailers.len(),
-            timeout.as_millis() as u32,
-            &mut return_token,
-        ) {
-            Status::Ok => Ok(return_token),
-            e => Err(e),
-        }
-    }
+    utils::with_serialized_map(headers, |serialized_headers| {
+        utils::with_serialized_map(trailers, |serialized_trailers| unsafe {
+            match proxy_http_call(
+                upstream.as_ptr(),
+                upstream.len(),
+                serialized_headers.as_ptr(),
+                serialized_headers.len(),
+                body.map_or(null(), |body| body.as_ptr()),
+                body.map_or(0, |body| body.len()),
+                serialized_trailers.as_ptr(),
+                serialized_trailers.len(),
+                timeout.as_millis() as u32,
+                &mut return_token,
+            ) {
+                Status::Ok => Ok(return_token),
+                e => Err(e),
+            }
+        })
+    })
 }
 
 pub fn dispatch_grpc_call(
@@ -697,9 +960,10 @@ pub fn dispatch_grpc_call(
     message: Option<&[u8]>,
     timeout: Duration,
 ) -> Result<u32, Status> {
+    crate::cost::hostcall();
+    crate::cost::written(message.map_or(0, |x| x.len()));
     let mut return_callout_id = 0;
-    let serialized_initial_metadata = utils::serialize_map(initial_metadata);
-    unsafe {
+    utils::with_serialized_map(initial_metadata, |serialized_initial_metadata| unsafe {
         match proxy_grpc_call(
             upstream_name.as_ptr(),
             upstream_name.len(),
@@ -717,7 +981,7 @@ pub fn dispatch_grpc_call(
             Status::Ok => Ok(return_callout_id),
             e => Err(e),
         }
-    }
+    })
 }
 
 pub fn open_grpc_stream(
@@ -726,9 +990,9 @@ pub fn open_grpc_stream(
     method_name: &str,
     initial_metadata: &[(&str, &[u8])],
 ) -> Result<u32, Status> {
+    crate::cost::hostcall();
     let mut return_stream_id = 0;
-    let serialized_initial_metadata = utils::serialize_map(initial_metadata);
-    unsafe {
+    utils::with_serialized_map(initial_metadata, |serialized_initial_metadata| unsafe {
         match proxy_grpc_stream(
             upstream_name.as_ptr(),
             upstream_name.len(),
@@ -743,7 +1007,7 @@ pub fn open_grpc_stream(
             Status::Ok => Ok(return_stream_id),
             e => Err(e),
         }
-    }
+    })
 }
 
 pub fn send_grpc_stream_message(
@@ -751,6 +1015,8 @@ pub fn send_grpc_stream_message(
     message: Option<&[u8]>,
     end_stream: bool,
 ) -> Result<(), Status> {
+    crate::cost::hostcall();
+    crate::cost::written(message.map_or(0, |x| x.len()));
     unsafe {
         match proxy_grpc_send(
             token,
@@ -765,6 +1031,7 @@ pub fn send_grpc_stream_message(
 }
 
 pub fn cancel_grpc_call(token_id: u32) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_grpc_cancel(token_id) {
             Status::Ok => Ok(()),
@@ -774,6 +1041,7 @@ pub fn cancel_grpc_call(token_id: u32) -> Result<(), Status> {
 }
 
 pub fn cancel_grpc_stream(token_id: u32) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_grpc_cancel(token_id) {
             Status::Ok => Ok(()),
@@ -783,6 +1051,7 @@ pub fn cancel_grpc_stream(token_id: u32) -> Result<(), Status> {
 }
 
 pub fn close_grpc_stream(token_id: u32) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_grpc_close(token_id) {
             Status::Ok => Ok(()),
@@ -792,6 +1061,7 @@ pub fn close_grpc_stream(token_id: u32) -> Result<(), Status> {
 }
 
 pub fn get_grpc_status() -> Result<(u32, Option<String>), Status> {
+    crate::cost::hostcall();
     let mut return_code = 0;
     let mut return_data = null_mut();
     let mut return_size = 0;
@@ -800,12 +1070,7 @@ pub fn get_grpc_status() -> Result<(u32, Option<String>), Status> {
             Status::Ok => Ok((
                 return_code,
                 NonNull::new(return_data).and_then(|return_data| {
-                    String::from_utf8(Vec::from_raw_parts(
-                        return_data.as_ptr(),
-                        return_size,
-                        return_size,
-                    ))
-                    .ok()
+                    String::from_utf8(host_bytes(return_data.as_ptr(), return_size)).ok()
                 }),
             )),
             e => Err(e),
@@ -814,6 +1079,7 @@ pub fn get_grpc_status() -> Result<(u32, Option<String>), Status> {
 }
 
 pub fn set_effective_context(context_id: u32) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_set_effective_context(context_id) {
             Status::Ok => Ok(()),
@@ -827,6 +1093,7 @@ pub fn call_foreign_function(
     function_name: impl AsRef<str>,
     arguments: Option<impl AsRef<[u8]>>,
 ) -> Result<Option<Vec<u8>>, Status> {
+    crate::cost::hostcall();
     let mut return_data = null_mut();
     let mut return_size = 0;
     let function_name = function_name.as_ref();
@@ -840,26 +1107,30 @@ pub fn call_foreign_function(
             &mut return_data,
             &mut return_size,
         ) {
-            Status::Ok => Ok(NonNull::new(return_data).map(|return_data| {
-                Vec::from_raw_parts(return_data.as_ptr(), return_size, return_size)
-            })),
+            Status::Ok => Ok(NonNull::new(return_data)
+                .map(|return_data| host_bytes(return_data.as_ptr(), return_size))),
             e => Err(e),
         }
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(all(not(target_arch = "wasm32"), not(feature = "testing")))]
 lazy_static::lazy_static! {
     static ref LIBRARY: libloading::os::unix::Library = libloading::os::unix::Library::this();
     static ref PROXY_WRITE_UPSTREAM: Option<libloading::os::unix::Symbol<unsafe extern "C" fn(*const u8, usize) -> Status>> = unsafe { LIBRARY.get(b"proxy_write_upstream").ok() };
     static ref PROXY_WRITE_DOWNSTREAM: Option<libloading::os::unix::Symbol<unsafe extern "C" fn(*const u8, usize) -> Status>> = unsafe { LIBRARY.get(b"proxy_write_downstream").ok() };
 }
 
+#[cfg(all(not(target_arch = "wasm32"), feature = "testing"))]
+lazy_static::lazy_static! {
+    static ref PROXY_WRITE_UPSTREAM: Option<unsafe extern "C" fn(*const u8, usize) -> Status> = Some(crate::testing::host::proxy_write_upstream);
+    static ref PROXY_WRITE_DOWNSTREAM: Option<unsafe extern "C" fn(*const u8, usize) -> Status> = Some(crate::testing::host::proxy_write_downstream);
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 pub fn write_upstream(buffer: &[u8]) -> Result<(), Status> {
-    let Some(proxy_write_upstream) = &*PROXY_WRITE_UPSTREAM else {
-        return Err(Status::InternalFailure);
-    };
+    crate::cost::hostcall();
+    crate::cost::written(buffer.len());
     match unsafe { proxy_write_upstream(buffer.as_ptr(), buffer.len()) } {
         Status::Ok => Ok(()),
         e => Err(e),
@@ -868,9 +1139,8 @@ pub fn write_upstream(buffer: &[u8]) -> Result<(), Status> {
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn write_downstream(buffer: &[u8]) -> Result<(), Status> {
-    let Some(proxy_write_downstream) = &*PROXY_WRITE_DOWNSTREAM else {
-        return Err(Status::InternalFailure);
-    };
+    crate::cost::hostcall();
+    crate::cost::written(buffer.len());
     match unsafe { proxy_write_downstream(buffer.as_ptr(), buffer.len()) } {
         Status::Ok => Ok(()),
         e => Err(e),
@@ -878,6 +1148,7 @@ pub fn write_downstream(buffer: &[u8]) -> Result<(), Status> {
 }
 
 pub fn done() -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_done() {
             Status::Ok => Ok(()),
@@ -887,6 +1158,7 @@ pub fn done() -> Result<(), Status> {
 }
 
 pub fn define_metric(metric_type: MetricType, name: &str) -> Result<u32, Status> {
+    crate::cost::hostcall();
     let mut return_id = 0;
     unsafe {
         match proxy_define_metric(metric_type, name.as_ptr(), name.len(), &mut return_id) {
@@ -897,6 +1169,7 @@ pub fn define_metric(metric_type: MetricType, name: &str) -> Result<u32, Status>
 }
 
 pub fn get_metric(metric_id: u32) -> Result<u64, Status> {
+    crate::cost::hostcall();
     let mut return_value = 0;
     unsafe {
         match proxy_get_metric(metric_id, &mut return_value) {
@@ -907,6 +1180,7 @@ pub fn get_metric(metric_id: u32) -> Result<u64, Status> {
 }
 
 pub fn record_metric(metric_id: u32, value: u64) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_record_metric(metric_id, value) {
             Status::Ok => Ok(()),
@@ -916,6 +1190,7 @@ pub fn record_metric(metric_id: u32, value: u64) -> Result<(), Status> {
 }
 
 pub fn increment_metric(metric_id: u32, offset: i64) -> Result<(), Status> {
+    crate::cost::hostcall();
     unsafe {
         match proxy_increment_metric(metric_id, offset) {
             Status::Ok => Ok(()),
@@ -925,62 +1200,224 @@ pub fn increment_metric(metric_id: u32, offset: i64) -> Result<(), Status> {
 }
 
 mod utils {
-    use super::Status;
-    use std::ops::Range;
+    use super::{HeaderOverflow, Status};
+    use std::{cell::RefCell, ops::Range};
 
-    pub(super) fn serialize_property_path<S: AsRef<str>>(
+    struct BufferPool {
+        free: Vec<Vec<u8>>,
+        initial_capacity: usize,
+        max_retained_capacity: usize,
+    }
+
+    thread_local! {
+        static BUFFERS: RefCell<BufferPool> = const {
+            RefCell::new(BufferPool {
+                free: Vec::new(),
+                initial_capacity: 1024,
+                max_retained_capacity: 64 * 1024,
+            })
+        };
+    }
+
+    /// Sets the initial capacity of newly allocated serialization buffers, and the largest capacity a buffer may have to be kept for reuse.
+    /// Buffers that grew beyond `max_retained_capacity` are freed after use rather than returned to the pool.
+    pub fn set_serialization_buffer_capacity(
+        initial_capacity: usize,
+        max_retained_capacity: usize,
+    ) {
+        BUFFERS.with_borrow_mut(|pool| {
+            pool.initial_capacity = initial_capacity;
+            pool.max_retained_capacity = max_retained_capacity;
+            pool.free.retain(|x| x.capacity() <= max_retained_capacity);
+        });
+    }
+
+    /// Borrows a cleared buffer from the thread local pool for the duration of `f`. Safe to nest.
+    pub(crate) fn with_buffer<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+        let mut buf = BUFFERS.with_borrow_mut(|pool| {
+            pool.free
+                .pop()
+                .unwrap_or_else(|| Vec::with_capacity(pool.initial_capacity))
+        });
+        let out = f(&mut buf);
+        buf.clear();
+        crate::zeroize::wipe_if_sensitive(&mut buf);
+        BUFFERS.with_borrow_mut(|pool| {
+            if buf.capacity() <= pool.max_retained_capacity {
+                pool.free.push(buf);
+            }
+        });
+        out
+    }
+
+    pub(super) fn with_serialized_map<R>(map: &[(&str, &[u8])], f: impl FnOnce(&[u8]) -> R) -> R {
+        with_buffer(|buf| {
+            serialize_map_into(map, buf);
+            f(buf)
+        })
+    }
+
+    pub(super) fn with_serialized_property_path<S: AsRef<str>, R>(
         path: impl IntoIterator<Item = S>,
-    ) -> Vec<u8> {
-        let mut out = Vec::new();
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> R {
+        with_buffer(|buf| {
+            serialize_property_path_into(path, buf);
+            f(buf)
+        })
+    }
+
+    /// Serializes a property path into `out` in the proxy-wasm wire format (NUL separated), appending to any existing content.
+    pub fn serialize_property_path_into<S: AsRef<str>>(
+        path: impl IntoIterator<Item = S>,
+        out: &mut Vec<u8>,
+    ) {
+        let start = out.len();
         for part in path {
             out.extend_from_slice(part.as_ref().as_bytes());
             out.push(0);
         }
-        if !out.is_empty() {
+        if out.len() > start {
             out.pop();
         }
-        out
     }
 
-    pub(super) fn serialize_map(map: &[(&str, &[u8])]) -> Vec<u8> {
+    /// Serializes a header map into `out` in the proxy-wasm wire format, appending to any existing content.
+    pub fn serialize_map_into(map: &[(&str, &[u8])], out: &mut Vec<u8>) {
         let mut size: usize = 4;
         for (name, value) in map {
             size += name.len() + value.len() + 10;
         }
-        let mut bytes = Vec::with_capacity(size);
-        bytes.extend_from_slice(&(map.len() as u32).to_le_bytes());
+        out.reserve(size);
+        out.extend_from_slice(&(map.len() as u32).to_le_bytes());
         for (name, value) in map {
-            bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
-            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
         }
         for (name, value) in map {
-            bytes.extend_from_slice(name.as_bytes());
-            bytes.push(0);
-            bytes.extend_from_slice(value);
-            bytes.push(0);
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.extend_from_slice(value);
+            out.push(0);
         }
-        bytes
     }
 
-    pub(super) fn deserialize_map_bytes(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Status> {
+    pub(crate) fn deserialize_map_bytes(bytes: &[u8]) -> Result<Vec<(String, Vec<u8>)>, Status> {
+        deserialize_map_bytes_limited(bytes, usize::MAX, usize::MAX, false).map(|(map, _)| map)
+    }
+
+    /// Deserializes a map of at most `max_pairs` pairs and `max_bytes` of names and values. Sizes are checked from the
+    /// index before copying anything. On overflow, returns the leading pairs within the limits if `clip`, else none.
+    pub(crate) fn deserialize_map_bytes_limited(
+        bytes: &[u8],
+        max_bytes: usize,
+        max_pairs: usize,
+        clip: bool,
+    ) -> Result<(Vec<(String, Vec<u8>)>, Option<HeaderOverflow>), Status> {
         let mut map = Vec::new();
         if bytes.is_empty() {
-            return Ok(map);
+            return Ok((map, None));
         }
         let get = |r: Range<usize>| bytes.get(r).ok_or(Status::ParseFailure);
+        let len = |s: usize| -> Result<usize, Status> {
+            Ok(u32::from_le_bytes(get(s..s + 4)?.try_into().unwrap()) as usize)
+        };
 
-        let size = u32::from_le_bytes(get(0..4)?.try_into().unwrap()) as usize;
+        let size = len(0)?;
+        let overflow = if size > max_pairs {
+            Some(HeaderOverflow::Pairs {
+                count: size,
+                max: max_pairs,
+            })
+        } else {
+            let mut total = 0usize;
+            for n in 0..size {
+                total = total.saturating_add(len(4 + n * 8)?.saturating_add(len(8 + n * 8)?));
+            }
+            (total > max_bytes).then_some(HeaderOverflow::Bytes {
+                size: total,
+                max: max_bytes,
+            })
+        };
+        if overflow.is_some() && !clip {
+            return Ok((map, overflow));
+        }
         let mut p = 4 + size * 8;
-        for n in 0..size {
+        let mut used = 0;
+        for n in 0..size.min(max_pairs) {
             let s = 4 + n * 8;
-            let size = u32::from_le_bytes(get(s..s + 4)?.try_into().unwrap()) as usize;
-            let key = get(p..p + size)?;
-            p += size + 1;
-            let size = u32::from_le_bytes(get(s + 4..s + 8)?.try_into().unwrap()) as usize;
-            let value = get(p..p + size)?;
-            p += size + 1;
+            let (key_size, value_size) = (len(s)?, len(s + 4)?);
+            used += key_size + value_size;
+            if used > max_bytes {
+                break;
+            }
+            let key = get(p..p + key_size)?;
+            p += key_size + 1;
+            let value = get(p..p + value_size)?;
+            p += value_size + 1;
             map.push((String::from_utf8(key.to_vec()).unwrap(), value.to_vec()));
         }
-        Ok(map)
+        Ok((map, overflow))
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingHost {
+        logs: std::cell::RefCell<Vec<(LogLevel, String)>>,
+    }
+
+    impl Host for Rc<RecordingHost> {
+        unsafe fn proxy_log(
+            &self,
+            level: LogLevel,
+            message_data: *const u8,
+            message_size: usize,
+        ) -> Status {
+            let message = std::slice::from_raw_parts(message_data, message_size);
+            self.logs
+                .borrow_mut()
+                .push((level, String::from_utf8_lossy(message).into_owned()));
+            Status::Ok
+        }
+
+        unsafe fn proxy_get_current_time_nanoseconds(&self, return_time: *mut u64) -> Status {
+            *return_time = 5_000_000_000;
+            Status::Ok
+        }
+
+        unsafe fn proxy_get_property(
+            &self,
+            _path_data: *const u8,
+            _path_size: usize,
+            return_value_data: *mut *mut u8,
+            return_value_size: *mut usize,
+        ) -> Status {
+            return_bytes(b"10.0.0.1:80", return_value_data, return_value_size);
+            Status::Ok
+        }
+    }
+
+    #[test]
+    fn test_host() {
+        let host = Rc::new(RecordingHost::default());
+        set_host(host.clone());
+        log(LogLevel::Warn, "hello").unwrap();
+        assert_eq!(
+            get_current_time().unwrap(),
+            UNIX_EPOCH + Duration::from_secs(5)
+        );
+        assert_eq!(
+            get_property(["source", "address"]).unwrap().as_deref(),
+            Some(&b"10.0.0.1:80"[..])
+        );
+        clear_host();
+        assert_eq!(
+            *host.logs.borrow(),
+            vec![(LogLevel::Warn, "hello".to_string())]
+        );
     }
 }
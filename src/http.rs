@@ -1,12 +1,21 @@
-use std::ops::RangeBounds;
+use std::{any::Any, ops::RangeBounds};
+
+use log::warn;
 
 use crate::{
     calculate_range,
     context::BaseContext,
+    header_limits::{header_limits, record_overflow},
+    header_map::HeaderMap,
+    header_str::HeaderStr,
     hostcalls::{self, BufferType, MapType},
+    json::{JsonError, Value},
     log_concern,
+    phase::{BodyProgress, HttpError},
     property::envoy::Attributes,
-    Status,
+    queue::Queue,
+    zeroize::{wipe_if_sensitive, ZeroizingVec},
+    HeaderLimitAction, HeaderOverflow, Status, StreamDataControl,
 };
 
 /// Defines control functions for http data
@@ -32,14 +41,26 @@ pub trait HttpControl {
         log_concern(Self::TYPE.reset(), Self::TYPE.call_reset())
     }
 
-    /// Send an early HTTP response, terminating the current request/response
+    /// Send an early HTTP response, terminating the current request/response.
+    /// Fails with [`WrongPhase`](crate::WrongPhase) once the response headers were forwarded downstream.
     fn send_http_response(
         &self,
         status_code: u32,
         headers: &[(&str, &[u8])],
         body: Option<&[u8]>,
-    ) -> Result<(), Status> {
-        hostcalls::send_http_response(status_code, headers, body)
+    ) -> Result<(), HttpError> {
+        if let Some(state) = crate::dispatcher::http_phase() {
+            state.check_send_response("send_http_response")?;
+        }
+        hostcalls::send_http_response(status_code, headers, body)?;
+        Ok(())
+    }
+
+    /// Exempts the rest of this transaction from response processing, i.e. for health checks.
+    /// The response header, body and trailer callbacks of this context then continue without being called, saving their hostcalls.
+    /// `on_log` is still called.
+    fn skip_response(&self) {
+        crate::dispatcher::skip_response();
     }
 
     /// Mark this transaction as complete
@@ -48,6 +69,20 @@ pub trait HttpControl {
     }
 }
 
+/// Checks a header mutation against the phase of the active HTTP context, as the host silently ignores it in the wrong phase
+fn check_header_write(operation: &'static str, header_type: &HeaderType) -> bool {
+    let Some(state) = crate::dispatcher::http_phase() else {
+        return true;
+    };
+    match state.check_header_write(operation, header_type) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("[concern-{operation}] {e}");
+            false
+        }
+    }
+}
+
 /// Defines functions to interact with header data
 pub trait HttpHeaderControl: HttpControl {
     /// The header type
@@ -56,13 +91,58 @@ pub trait HttpHeaderControl: HttpControl {
     /// Number of headers contained in block
     fn header_count(&self) -> usize;
 
-    /// Get all headers in this block
+    /// Get all headers in this block, within the [`crate::HeaderLimits`] if set with [`crate::set_header_limits`]
     fn all(&self) -> Vec<(String, Vec<u8>)> {
-        log_concern(
-            Self::HEADER_TYPE.all(),
-            hostcalls::get_map(Self::HEADER_TYPE.map()),
-        )
-        .unwrap_or_default()
+        let Some(limits) = header_limits() else {
+            return log_concern(
+                Self::HEADER_TYPE.all(),
+                hostcalls::get_map(Self::HEADER_TYPE.map()),
+            )
+            .unwrap_or_default();
+        };
+        let clip = limits.action == HeaderLimitAction::Clip;
+        let result = if !clip && self.header_count() > limits.max_pairs {
+            let overflow = HeaderOverflow::Pairs {
+                count: self.header_count(),
+                max: limits.max_pairs,
+            };
+            Ok(Some((vec![], Some(overflow))))
+        } else {
+            hostcalls::get_map_limited(
+                Self::HEADER_TYPE.map(),
+                limits.max_bytes,
+                limits.max_pairs,
+                clip,
+            )
+        };
+        let (headers, overflow) = log_concern(Self::HEADER_TYPE.all(), result).unwrap_or_default();
+        if let Some(overflow) = overflow {
+            warn!("[concern-{}] {overflow}", Self::HEADER_TYPE.all());
+            record_overflow();
+        }
+        headers
+    }
+
+    /// Get all headers in this block, failing if there are more than `max_pairs` or their names and values total more
+    /// than `max_bytes`. The header count is checked before reading anything. Ignores the SDK-wide [`crate::HeaderLimits`].
+    fn all_limited(
+        &self,
+        max_bytes: usize,
+        max_pairs: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, HeaderOverflow> {
+        if self.header_count() > max_pairs {
+            return Err(HeaderOverflow::Pairs {
+                count: self.header_count(),
+                max: max_pairs,
+            });
+        }
+        let result =
+            hostcalls::get_map_limited(Self::HEADER_TYPE.map(), max_bytes, max_pairs, false);
+        match log_concern(Self::HEADER_TYPE.all(), result) {
+            Some((_, Some(overflow))) => Err(overflow),
+            Some((headers, None)) => Ok(headers),
+            None => Ok(vec![]),
+        }
     }
 
     /// Check for a specific header value
@@ -73,8 +153,37 @@ pub trait HttpHeaderControl: HttpControl {
         )
     }
 
+    /// Check for a specific header value, keeping the raw bytes
+    fn get_str(&self, name: impl AsRef<str>) -> Option<HeaderStr> {
+        self.get(name).map(HeaderStr::new)
+    }
+
+    /// Check for a specific header value, wiped on drop. See [`crate::zeroize`].
+    fn get_zeroizing(&self, name: impl AsRef<str>) -> Option<ZeroizingVec> {
+        self.get(name).map(ZeroizingVec::from)
+    }
+
+    /// Get all headers in this block, values wiped on drop. See [`crate::zeroize`].
+    fn all_zeroizing(&self) -> Vec<(String, ZeroizingVec)> {
+        self.all()
+            .into_iter()
+            .map(|(name, value)| (name, value.into()))
+            .collect()
+    }
+
+    /// Get all headers in this block, keeping the raw value bytes
+    fn all_str(&self) -> Vec<(String, HeaderStr)> {
+        self.all()
+            .into_iter()
+            .map(|(name, value)| (name, HeaderStr::new(value)))
+            .collect()
+    }
+
     /// Set a specific header
     fn set(&self, name: impl AsRef<str>, value: impl AsRef<[u8]>) {
+        if !check_header_write(Self::HEADER_TYPE.set(), &Self::HEADER_TYPE) {
+            return;
+        }
         log_concern(
             Self::HEADER_TYPE.set(),
             hostcalls::set_map_value(Self::HEADER_TYPE.map(), name.as_ref(), Some(value.as_ref())),
@@ -83,6 +192,9 @@ pub trait HttpHeaderControl: HttpControl {
 
     /// Replace all headers in this block
     fn set_all(&self, values: &[(&str, &[u8])]) {
+        if !check_header_write(Self::HEADER_TYPE.set_all(), &Self::HEADER_TYPE) {
+            return;
+        }
         log_concern(
             Self::HEADER_TYPE.set_all(),
             hostcalls::set_map(Self::HEADER_TYPE.map(), values),
@@ -91,6 +203,9 @@ pub trait HttpHeaderControl: HttpControl {
 
     /// Add a header to this block (append to existing if present)
     fn add(&self, name: impl AsRef<str>, value: impl AsRef<[u8]>) {
+        if !check_header_write(Self::HEADER_TYPE.add(), &Self::HEADER_TYPE) {
+            return;
+        }
         log_concern(
             Self::HEADER_TYPE.add(),
             hostcalls::add_map_value(Self::HEADER_TYPE.map(), name.as_ref(), value.as_ref()),
@@ -99,11 +214,22 @@ pub trait HttpHeaderControl: HttpControl {
 
     /// Remove a header from this block
     fn remove(&self, name: impl AsRef<str>) {
+        if !check_header_write(Self::HEADER_TYPE.remove(), &Self::HEADER_TYPE) {
+            return;
+        }
         log_concern(
             Self::HEADER_TYPE.remove(),
             hostcalls::set_map_value(Self::HEADER_TYPE.map(), name.as_ref(), None),
         );
     }
+
+    /// Reads all headers of this block once, for filters inspecting many headers. See [`HeaderMap`].
+    fn cached(&self) -> HeaderMap<'_, Self>
+    where
+        Self: Sized,
+    {
+        HeaderMap::new(self)
+    }
 }
 
 /// Defines functions to interact with body data
@@ -134,6 +260,16 @@ pub trait HttpBodyControl: HttpControl {
         self.get(..)
     }
 
+    /// Get a range of the body block content, wiped on drop. See [`crate::zeroize`].
+    fn get_zeroizing(&self, range: impl RangeBounds<usize>) -> Option<ZeroizingVec> {
+        self.get(range).map(ZeroizingVec::from)
+    }
+
+    /// Get the entire body block content, wiped on drop
+    fn all_zeroizing(&self) -> Option<ZeroizingVec> {
+        self.get_zeroizing(..)
+    }
+
     /// Replace the entire body block with `value`
     fn replace(&self, value: &[u8]) {
         self.set(.., value);
@@ -143,6 +279,186 @@ pub trait HttpBodyControl: HttpControl {
     fn clear(&self) {
         self.replace(&[]);
     }
+
+    /// Parses the body block as JSON. Only the whole body parses, so buffer it until [`HttpControl::end_of_stream`].
+    fn json(&self) -> Result<Value, JsonError> {
+        Value::parse(self.all().unwrap_or_default())
+    }
+
+    /// Replace the entire body block with `value` serialized as compact JSON
+    fn replace_json(&self, value: &Value) {
+        self.replace(&value.to_bytes());
+    }
+}
+
+/// Reads an HTTP body or stream data in fixed size chunks through a single pooled buffer, instead of allocating for every read.
+pub struct BodyReader {
+    buffer_type: BufferType,
+    size: usize,
+    context: &'static str,
+}
+
+impl BodyReader {
+    /// Reader for an HTTP body block
+    pub fn new<B: HttpBodyControl>(body: &B) -> Self {
+        Self {
+            buffer_type: B::TYPE.buffer(),
+            size: body.body_size(),
+            context: B::TYPE.get(),
+        }
+    }
+
+    /// Reader for a chunk of stream data
+    pub fn stream<S: StreamDataControl>(data: &S) -> Self {
+        Self {
+            buffer_type: S::TYPE.buffer(),
+            size: data.data_size(),
+            context: S::TYPE.get(),
+        }
+    }
+
+    /// Total size of the data
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Calls `f` with consecutive chunks of at most `chunk_size` bytes, until the data ends or `f` returns `false`.
+    /// The slice is only valid during the call. Returns the number of bytes read.
+    pub fn read_chunks(&self, chunk_size: usize, mut f: impl FnMut(&[u8]) -> bool) -> usize {
+        let chunk_size = chunk_size.max(1);
+        hostcalls::with_buffer(|buf| {
+            let mut offset = 0;
+            while offset < self.size {
+                let size = chunk_size.min(self.size - offset);
+                match hostcalls::get_buffer_into(self.buffer_type, offset, size, buf) {
+                    Ok(true) if !buf.is_empty() => (),
+                    Ok(_) => break,
+                    Err(e) => {
+                        log_concern::<()>(self.context, Err(e));
+                        break;
+                    }
+                }
+                offset += buf.len();
+                if !f(buf) {
+                    break;
+                }
+            }
+            offset
+        })
+    }
+}
+
+/// What [`BodyAccumulator`] does with a body larger than its limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BodyLimitAction {
+    /// Stop accumulating and let the body through unprocessed
+    PassThrough,
+    /// Send a local response with this status code, i.e. 413
+    Reject(u32),
+}
+
+/// Outcome of [`BodyAccumulator::feed`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Accumulation {
+    /// More body is expected
+    Buffering,
+    /// The body is complete, see [`BodyAccumulator::body`]
+    Complete,
+    /// The body exceeded the limit and was let through
+    Overflowed,
+    /// The body exceeded the limit and a local response was sent
+    Rejected,
+}
+
+/// Accumulates a request or response body until end of stream, up to a size limit.
+///
+/// Keep one per direction in the HTTP context and call [`BodyAccumulator::feed`] from each body callback, returning the
+/// status it yields. The host buffers the body meanwhile, so it is read once, when complete.
+#[derive(Clone, Debug)]
+pub struct BodyAccumulator {
+    max_size: usize,
+    on_limit: BodyLimitAction,
+    state: Accumulation,
+    body: Option<Vec<u8>>,
+}
+
+impl BodyAccumulator {
+    /// Accumulates bodies of up to `max_size` bytes, letting larger ones through
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            on_limit: BodyLimitAction::PassThrough,
+            state: Accumulation::Buffering,
+            body: None,
+        }
+    }
+
+    pub fn on_limit(mut self, on_limit: BodyLimitAction) -> Self {
+        self.on_limit = on_limit;
+        self
+    }
+
+    /// Buffers the body until end of stream, returning the status to return from the body callback.
+    pub fn feed(&mut self, body: &impl HttpBodyControl) -> FilterDataStatus {
+        if self.state != Accumulation::Buffering {
+            return FilterDataStatus::Continue;
+        }
+        if body.body_size() > self.max_size {
+            self.state = match self.on_limit {
+                BodyLimitAction::PassThrough => Accumulation::Overflowed,
+                BodyLimitAction::Reject(status) => {
+                    if let Err(e) = body.send_http_response(status, &[], None) {
+                        warn!("failed to reject oversized body: {e:?}");
+                    }
+                    Accumulation::Rejected
+                }
+            };
+            return match self.state {
+                Accumulation::Rejected => FilterDataStatus::StopIterationNoBuffer,
+                _ => FilterDataStatus::Continue,
+            };
+        }
+        if !body.end_of_stream() {
+            return FilterDataStatus::StopAllIterationAndBuffer;
+        }
+        self.body = Some(body.all().unwrap_or_default());
+        self.state = Accumulation::Complete;
+        FilterDataStatus::Continue
+    }
+
+    pub fn state(&self) -> Accumulation {
+        self.state
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state == Accumulation::Complete
+    }
+
+    /// The complete body, once accumulated
+    pub fn body(&self) -> Option<&[u8]> {
+        self.body.as_deref()
+    }
+
+    /// Takes the complete body, once accumulated
+    pub fn take_body(&mut self) -> Option<Vec<u8>> {
+        self.body.take()
+    }
+
+    /// Starts over, i.e. for the next message of a reused context
+    pub fn reset(&mut self) {
+        self.state = Accumulation::Buffering;
+        if let Some(mut body) = self.body.take() {
+            wipe_if_sensitive(&mut body);
+        }
+    }
+}
+
+impl Drop for BodyAccumulator {
+    fn drop(&mut self) {
+        if let Some(body) = &mut self.body {
+            wipe_if_sensitive(body);
+        }
+    }
 }
 
 /// Defines which section the header data belongs too
@@ -151,6 +467,10 @@ pub enum HeaderType {
     RequestTrailers,
     ResponseHeaders,
     ResponseTrailers,
+    /// HTTP/2 METADATA frames sent by the client
+    RequestMetadata,
+    /// HTTP/2 METADATA frames sent by the upstream
+    ResponseMetadata,
 }
 
 impl HeaderType {
@@ -160,6 +480,8 @@ impl HeaderType {
             Self::RequestTrailers => "get-all-request-trailer",
             Self::ResponseHeaders => "get-all-response-header",
             Self::ResponseTrailers => "get-all-response-trailer",
+            Self::RequestMetadata => "get-all-request-metadata",
+            Self::ResponseMetadata => "get-all-response-metadata",
         }
     }
 
@@ -169,6 +491,8 @@ impl HeaderType {
             Self::RequestTrailers => "get-request-trailer",
             Self::ResponseHeaders => "get-response-header",
             Self::ResponseTrailers => "get-response-trailer",
+            Self::RequestMetadata => "get-request-metadata",
+            Self::ResponseMetadata => "get-response-metadata",
         }
     }
 
@@ -178,6 +502,8 @@ impl HeaderType {
             Self::RequestTrailers => "set-request-trailer",
             Self::ResponseHeaders => "set-response-header",
             Self::ResponseTrailers => "set-response-trailer",
+            Self::RequestMetadata => "set-request-metadata",
+            Self::ResponseMetadata => "set-response-metadata",
         }
     }
 
@@ -187,6 +513,8 @@ impl HeaderType {
             Self::RequestTrailers => "set-all-request-trailers",
             Self::ResponseHeaders => "set-all-response-headers",
             Self::ResponseTrailers => "set-all-response-trailers",
+            Self::RequestMetadata => "set-all-request-metadata",
+            Self::ResponseMetadata => "set-all-response-metadata",
         }
     }
 
@@ -196,6 +524,8 @@ impl HeaderType {
             Self::RequestTrailers => "add-request-trailers",
             Self::ResponseHeaders => "add-response-headers",
             Self::ResponseTrailers => "add-response-trailers",
+            Self::RequestMetadata => "add-request-metadata",
+            Self::ResponseMetadata => "add-response-metadata",
         }
     }
 
@@ -205,6 +535,8 @@ impl HeaderType {
             Self::RequestTrailers => "remove-request-trailers",
             Self::ResponseHeaders => "remove-response-headers",
             Self::ResponseTrailers => "remove-response-trailers",
+            Self::RequestMetadata => "remove-request-metadata",
+            Self::ResponseMetadata => "remove-response-metadata",
         }
     }
 
@@ -214,6 +546,8 @@ impl HeaderType {
             HeaderType::RequestTrailers => MapType::HttpRequestTrailers,
             HeaderType::ResponseHeaders => MapType::HttpResponseHeaders,
             HeaderType::ResponseTrailers => MapType::HttpResponseTrailers,
+            HeaderType::RequestMetadata => MapType::HttpRequestMetadata,
+            HeaderType::ResponseMetadata => MapType::HttpResponseMetadata,
         }
     }
 }
@@ -296,6 +630,14 @@ pub enum FilterTrailersStatus {
     StopIteration = 1,
 }
 
+/// Return status for metadata callbacks. Metadata frames cannot be held back.
+#[repr(usize)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[non_exhaustive]
+pub enum FilterMetadataStatus {
+    Continue = 0,
+}
+
 /// Return status for body callbacks
 #[repr(usize)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
@@ -379,6 +721,35 @@ impl HttpHeaderControl for RequestTrailers {
     }
 }
 
+impl RequestTrailers {
+    /// Callbacks received before these trailers, i.e. whether a body preceded them
+    pub fn progress(&self) -> BodyProgress {
+        BodyProgress::request().unwrap_or_default()
+    }
+}
+
+/// HTTP/2 METADATA frames received from the client, readable and mutable with [`HttpHeaderControl`]
+pub struct RequestMetadata {
+    pub(crate) element_count: usize,
+    pub(crate) attributes: Attributes,
+}
+
+impl HttpControl for RequestMetadata {
+    const TYPE: HttpType = HttpType::Request;
+
+    fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+}
+
+impl HttpHeaderControl for RequestMetadata {
+    const HEADER_TYPE: HeaderType = HeaderType::RequestMetadata;
+
+    fn header_count(&self) -> usize {
+        self.element_count
+    }
+}
+
 pub struct ResponseHeaders {
     pub(crate) header_count: usize,
     pub(crate) end_of_stream: bool,
@@ -450,9 +821,38 @@ impl HttpHeaderControl for ResponseTrailers {
     }
 }
 
+impl ResponseTrailers {
+    /// Callbacks received before these trailers, i.e. whether a body preceded them
+    pub fn progress(&self) -> BodyProgress {
+        BodyProgress::response().unwrap_or_default()
+    }
+}
+
+/// HTTP/2 METADATA frames received from the upstream, readable and mutable with [`HttpHeaderControl`]
+pub struct ResponseMetadata {
+    pub(crate) element_count: usize,
+    pub(crate) attributes: Attributes,
+}
+
+impl HttpControl for ResponseMetadata {
+    const TYPE: HttpType = HttpType::Response;
+
+    fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+}
+
+impl HttpHeaderControl for ResponseMetadata {
+    const HEADER_TYPE: HeaderType = HeaderType::ResponseMetadata;
+
+    fn header_count(&self) -> usize {
+        self.element_count
+    }
+}
+
 /// Context for a HTTP filter plugin.
 #[allow(unused_variables)]
-pub trait HttpContext: BaseContext {
+pub trait HttpContext: BaseContext + Any {
     /// Called one or more times as the proxy receives request headers. If `headers.end_of_stream()` is true, then they are the last request headers.
     fn on_http_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
         FilterHeadersStatus::Continue
@@ -468,6 +868,11 @@ pub trait HttpContext: BaseContext {
         FilterTrailersStatus::Continue
     }
 
+    /// Called for HTTP/2 METADATA frames sent by the client, at any point of the request. Only some hosts deliver them.
+    fn on_http_request_metadata(&mut self, metadata: &RequestMetadata) -> FilterMetadataStatus {
+        FilterMetadataStatus::Continue
+    }
+
     /// Called one or more times as the proxy receives response headers. If `headers.end_of_stream()` is true, then they are the last response headers.
     fn on_http_response_headers(&mut self, headers: &ResponseHeaders) -> FilterHeadersStatus {
         FilterHeadersStatus::Continue
@@ -482,4 +887,150 @@ pub trait HttpContext: BaseContext {
     fn on_http_response_trailers(&mut self, trailers: &ResponseTrailers) -> FilterTrailersStatus {
         FilterTrailersStatus::Continue
     }
+
+    /// Called for HTTP/2 METADATA frames sent by the upstream, at any point of the response. Only some hosts deliver them.
+    fn on_http_response_metadata(&mut self, metadata: &ResponseMetadata) -> FilterMetadataStatus {
+        FilterMetadataStatus::Continue
+    }
+
+    /// Called when a queue subscribed to with [`crate::Queue::subscribe_context`] has data available.
+    fn on_queue_ready(&mut self, queue: Queue) {}
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::testing::{host::with_host, reset_host};
+
+    #[test]
+    fn test_body_accumulator() {
+        reset_host();
+        let body = |size: usize, end_of_stream: bool| {
+            with_host(|host| {
+                host.buffers
+                    .insert(BufferType::HttpRequestBody as u32, vec![b'a'; size])
+            });
+            RequestBody {
+                body_size: size,
+                end_of_stream,
+                attributes: Attributes::get(),
+            }
+        };
+        let mut accumulator = BodyAccumulator::new(8);
+        assert_eq!(
+            accumulator.feed(&body(4, false)),
+            FilterDataStatus::StopAllIterationAndBuffer
+        );
+        assert_eq!(accumulator.body(), None);
+        assert_eq!(accumulator.feed(&body(8, true)), FilterDataStatus::Continue);
+        assert_eq!(accumulator.take_body(), Some(vec![b'a'; 8]));
+
+        let mut accumulator = BodyAccumulator::new(8).on_limit(BodyLimitAction::Reject(413));
+        assert_eq!(
+            accumulator.feed(&body(9, false)),
+            FilterDataStatus::StopIterationNoBuffer
+        );
+        assert_eq!(accumulator.state(), Accumulation::Rejected);
+        assert_eq!(
+            accumulator.feed(&body(12, true)),
+            FilterDataStatus::Continue
+        );
+        assert_eq!(
+            with_host(|host| host.local_response.as_ref().map(|x| x.status_code)),
+            Some(413)
+        );
+    }
+
+    #[test]
+    fn test_body_json() {
+        reset_host();
+        with_host(|host| {
+            host.buffers.insert(
+                BufferType::HttpRequestBody as u32,
+                br#"{"user": "a", "admin": true}"#.to_vec(),
+            )
+        });
+        let body = RequestBody {
+            body_size: 28,
+            end_of_stream: true,
+            attributes: Attributes::get(),
+        };
+        let mut value = body.json().unwrap();
+        assert_eq!(value.get("user").and_then(Value::as_str), Some("a"));
+        if let Value::Object(members) = &mut value {
+            members.retain(|(name, _)| name != "admin");
+        }
+        body.replace_json(&value);
+        assert_eq!(
+            with_host(|host| host.buffers[&(BufferType::HttpRequestBody as u32)].clone()),
+            br#"{"user":"a"}"#
+        );
+
+        with_host(|host| {
+            host.buffers
+                .insert(BufferType::HttpRequestBody as u32, b"{\"user\"".to_vec())
+        });
+        let body = RequestBody {
+            body_size: 7,
+            end_of_stream: false,
+            attributes: Attributes::get(),
+        };
+        assert!(body.json().is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        use crate::{
+            dispatcher::{self, proxy_on_context_create, proxy_on_request_metadata},
+            BaseContext, Context, RootContext,
+        };
+
+        #[derive(Default)]
+        struct Root;
+
+        impl BaseContext for Root {}
+
+        impl RootContext for Root {
+            fn create_context(&mut self) -> Context {
+                Context::Http(Box::new(Filter))
+            }
+        }
+
+        struct Filter;
+
+        impl BaseContext for Filter {}
+
+        impl HttpContext for Filter {
+            fn on_http_request_metadata(
+                &mut self,
+                metadata: &RequestMetadata,
+            ) -> FilterMetadataStatus {
+                assert_eq!(metadata.header_count(), 1);
+                if let Some(trace) = metadata.get("trace") {
+                    metadata.set("trace-seen", trace);
+                }
+                FilterMetadataStatus::Continue
+            }
+        }
+
+        reset_host();
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        proxy_on_context_create(2, 1);
+        with_host(|host| {
+            host.header_map(MapType::HttpRequestMetadata)
+                .push(("trace".to_string(), b"abc".to_vec()))
+        });
+        assert_eq!(
+            proxy_on_request_metadata(2, 1),
+            FilterMetadataStatus::Continue
+        );
+        assert_eq!(
+            with_host(|host| host.header_map(MapType::HttpRequestMetadata).clone()),
+            vec![
+                ("trace".to_string(), b"abc".to_vec()),
+                ("trace-seen".to_string(), b"abc".to_vec()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,673 @@
+//! HTTP/1 visibility for stream (L4) filters, for plugins that must run as a network filter in front of HTTP/1 traffic.
+//!
+//! [`Http1Correlator`] parses message boundaries from both directions of the byte stream and pairs each response
+//! with its request, including pipelined requests, then reports them through [`Http1Hooks`].
+//! It only observes, the data passes through unchanged. The filter must continue iteration on every chunk, as
+//! data buffered by stopping iteration would be delivered again.
+
+use std::{collections::VecDeque, fmt};
+
+use crate::StreamDataControl;
+
+/// Why an [`Http1Correlator`] stopped parsing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Http1Error {
+    /// A start line and headers exceeded the maximum head size
+    HeadTooLarge,
+    /// The stream is not valid HTTP/1
+    Malformed(&'static str),
+    /// A response arrived without a request to pair it with
+    UnexpectedResponse,
+    /// More requests were pipelined than the maximum pending
+    TooManyPending,
+}
+
+impl fmt::Display for Http1Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Http1Error::HeadTooLarge => write!(f, "message head too large"),
+            Http1Error::Malformed(x) => write!(f, "malformed message: {x}"),
+            Http1Error::UnexpectedResponse => write!(f, "response without request"),
+            Http1Error::TooManyPending => write!(f, "too many pipelined requests"),
+        }
+    }
+}
+
+impl std::error::Error for Http1Error {}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case(name))
+        .map(|(_, value)| &**value)
+}
+
+/// Start line and headers of a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Http1Request {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Http1Request {
+    /// First value of a header, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// Status line and headers of a response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Http1Response {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Http1Response {
+    /// First value of a header, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// A request and its response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Http1Transaction {
+    pub request: Http1Request,
+    /// `None` if the connection closed before the response
+    pub response: Option<Http1Response>,
+    /// Request body size after undoing chunked encoding
+    pub request_body_size: u64,
+    /// Response body size after undoing chunked encoding
+    pub response_body_size: u64,
+}
+
+/// Callbacks of an [`Http1Correlator`]. All methods default to doing nothing.
+#[allow(unused_variables)]
+pub trait Http1Hooks {
+    /// A request head was parsed
+    fn on_request(&mut self, request: &Http1Request) {}
+
+    /// Part of a request body, without chunked encoding
+    fn on_request_body(&mut self, request: &Http1Request, chunk: &[u8]) {}
+
+    /// A final (non-1xx) response head was parsed
+    fn on_response(&mut self, request: &Http1Request, response: &Http1Response) {}
+
+    /// Part of a response body, without chunked encoding
+    fn on_response_body(&mut self, request: &Http1Request, response: &Http1Response, chunk: &[u8]) {
+    }
+
+    /// Both the request and the response are complete, or the connection closed
+    fn on_transaction(&mut self, transaction: Http1Transaction) {}
+
+    /// Parsing stopped, the rest of the connection is not observed
+    fn on_error(&mut self, error: &Http1Error) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Chunk {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    Length(u64),
+    Chunked(Chunk),
+    UntilClose,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Head,
+    Body(Framing),
+}
+
+enum Event<'a> {
+    Head(Vec<u8>),
+    Body(&'a [u8]),
+    End,
+}
+
+/// Message boundaries of one direction
+#[derive(Clone, Debug)]
+struct Parser {
+    state: State,
+    /// Partial line of a head or of chunk framing
+    line: Vec<u8>,
+    /// Complete lines of the current head
+    head: Vec<u8>,
+    max_head_size: usize,
+}
+
+impl Parser {
+    fn new(max_head_size: usize) -> Self {
+        Self {
+            state: State::Head,
+            line: vec![],
+            head: vec![],
+            max_head_size,
+        }
+    }
+
+    /// Consumes up to `\n`, returning the line once complete
+    fn take_line(&mut self, data: &[u8]) -> Result<(usize, Option<Vec<u8>>), Http1Error> {
+        let (consumed, complete) = match data.iter().position(|x| *x == b'\n') {
+            Some(end) => (end + 1, true),
+            None => (data.len(), false),
+        };
+        self.line.extend_from_slice(&data[..consumed]);
+        if self.head.len() + self.line.len() > self.max_head_size {
+            return Err(Http1Error::HeadTooLarge);
+        }
+        Ok((consumed, complete.then(|| std::mem::take(&mut self.line))))
+    }
+
+    /// Parses the next event from `data`, returning the bytes consumed
+    fn next<'a>(&mut self, data: &'a [u8]) -> Result<(usize, Option<Event<'a>>), Http1Error> {
+        if data.is_empty() && self.state != State::Body(Framing::Length(0)) {
+            return Ok((0, None));
+        }
+        match self.state {
+            State::Head => {
+                let (consumed, line) = self.take_line(data)?;
+                let Some(line) = line else {
+                    return Ok((consumed, None));
+                };
+                let empty = matches!(&line[..], b"\n" | b"\r\n");
+                // empty lines before a message are ignored
+                if empty && self.head.is_empty() {
+                    return Ok((consumed, None));
+                }
+                self.head.extend_from_slice(&line);
+                if !empty {
+                    return Ok((consumed, None));
+                }
+                Ok((consumed, Some(Event::Head(std::mem::take(&mut self.head)))))
+            }
+            State::Body(Framing::Length(0)) => {
+                self.state = State::Head;
+                Ok((0, Some(Event::End)))
+            }
+            State::Body(Framing::Length(remaining)) => {
+                let size = data.len().min(remaining.min(usize::MAX as u64) as usize);
+                self.state = State::Body(Framing::Length(remaining - size as u64));
+                Ok((size, Some(Event::Body(&data[..size]))))
+            }
+            State::Body(Framing::UntilClose) => Ok((data.len(), Some(Event::Body(data)))),
+            State::Body(Framing::Chunked(Chunk::Data(remaining))) => {
+                let size = data.len().min(remaining.min(usize::MAX as u64) as usize);
+                let remaining = remaining - size as u64;
+                self.state = State::Body(Framing::Chunked(match remaining {
+                    0 => Chunk::DataEnd,
+                    x => Chunk::Data(x),
+                }));
+                Ok((size, Some(Event::Body(&data[..size]))))
+            }
+            State::Body(Framing::Chunked(chunk)) => {
+                let (consumed, line) = self.take_line(data)?;
+                let Some(line) = line else {
+                    return Ok((consumed, None));
+                };
+                let line = line.strip_suffix(b"\n").unwrap_or(&line);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                match chunk {
+                    Chunk::Size => {
+                        let size = std::str::from_utf8(line)
+                            .ok()
+                            .and_then(|x| x.split(';').next())
+                            .and_then(|x| u64::from_str_radix(x.trim(), 16).ok())
+                            .ok_or(Http1Error::Malformed("invalid chunk size"))?;
+                        self.state = State::Body(Framing::Chunked(match size {
+                            0 => Chunk::Trailers,
+                            x => Chunk::Data(x),
+                        }));
+                    }
+                    Chunk::DataEnd if line.is_empty() => {
+                        self.state = State::Body(Framing::Chunked(Chunk::Size));
+                    }
+                    Chunk::DataEnd => {
+                        return Err(Http1Error::Malformed("missing chunk terminator"))
+                    }
+                    Chunk::Trailers if line.is_empty() => {
+                        self.state = State::Head;
+                        return Ok((consumed, Some(Event::End)));
+                    }
+                    Chunk::Trailers | Chunk::Data(_) => (),
+                }
+                Ok((consumed, None))
+            }
+        }
+    }
+}
+
+type Headers = Vec<(String, String)>;
+
+/// Splits a head into its start line parts and headers
+fn parse_head(raw: &[u8]) -> Result<([String; 3], Headers), Http1Error> {
+    let raw = std::str::from_utf8(raw).map_err(|_| Http1Error::Malformed("non UTF-8 head"))?;
+    let mut lines = raw.lines().filter(|x| !x.is_empty());
+    let start = lines
+        .next()
+        .ok_or(Http1Error::Malformed("missing start line"))?;
+    let mut parts = start.splitn(3, ' ');
+    let mut part = || parts.next().unwrap_or_default().to_string();
+    let start = [part(), part(), part()];
+    let headers = lines
+        .map(|line| {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(Http1Error::Malformed("invalid header line"))?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((start, headers))
+}
+
+/// Body framing of a message from its headers, see RFC 9112 section 6.3
+fn body_framing(headers: &[(String, String)], is_response: bool) -> Result<Framing, Http1Error> {
+    if let Some(encoding) = find_header(headers, "transfer-encoding") {
+        let last = encoding.rsplit(',').next().unwrap_or_default().trim();
+        return Ok(match last.eq_ignore_ascii_case("chunked") {
+            true => Framing::Chunked(Chunk::Size),
+            false if is_response => Framing::UntilClose,
+            false => return Err(Http1Error::Malformed("request body length unknown")),
+        });
+    }
+    match find_header(headers, "content-length") {
+        Some(length) => length
+            .parse()
+            .map(Framing::Length)
+            .map_err(|_| Http1Error::Malformed("invalid content-length")),
+        None if is_response => Ok(Framing::UntilClose),
+        None => Ok(Framing::Length(0)),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    request: Http1Request,
+    request_body_size: u64,
+    request_done: bool,
+    response: Option<Http1Response>,
+    response_body_size: u64,
+    response_done: bool,
+}
+
+/// Pairs HTTP/1 requests and responses seen by a stream filter. See the [module docs](self).
+#[derive(Clone, Debug)]
+pub struct Http1Correlator {
+    request: Parser,
+    response: Parser,
+    /// In order of the requests. Completed transactions are removed from the front.
+    pending: VecDeque<Pending>,
+    max_pending: usize,
+    /// Parsing stopped after an error or a protocol upgrade
+    stopped: bool,
+}
+
+impl Default for Http1Correlator {
+    fn default() -> Self {
+        Self {
+            request: Parser::new(64 * 1024),
+            response: Parser::new(64 * 1024),
+            pending: VecDeque::new(),
+            max_pending: 32,
+            stopped: false,
+        }
+    }
+}
+
+impl Http1Correlator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum size of a start line and headers. Default is 64 KiB.
+    pub fn max_head_size(mut self, max_head_size: usize) -> Self {
+        self.request.max_head_size = max_head_size;
+        self.response.max_head_size = max_head_size;
+        self
+    }
+
+    /// Maximum number of requests awaiting a response. Default is 32.
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Number of requests not yet completed by a response
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether the connection is still parsed. Parsing stops after an error, or once the connection is upgraded
+    /// (i.e. WebSocket or `CONNECT`) as it is then no longer HTTP/1.
+    pub fn is_active(&self) -> bool {
+        !self.stopped
+    }
+
+    /// Feeds data from [`crate::StreamContext::on_downstream_data`]
+    pub fn on_downstream_data(
+        &mut self,
+        data: &impl StreamDataControl,
+        hooks: &mut impl Http1Hooks,
+    ) {
+        if self.stopped {
+            return;
+        }
+        let chunk = data.all().unwrap_or_default();
+        self.feed_request(&chunk, data.end_of_stream(), hooks);
+    }
+
+    /// Feeds data from [`crate::StreamContext::on_upstream_data`]
+    pub fn on_upstream_data(&mut self, data: &impl StreamDataControl, hooks: &mut impl Http1Hooks) {
+        if self.stopped {
+            return;
+        }
+        let chunk = data.all().unwrap_or_default();
+        self.feed_response(&chunk, data.end_of_stream(), hooks);
+    }
+
+    /// Feeds bytes sent by the client
+    pub fn feed_request(&mut self, data: &[u8], end_of_stream: bool, hooks: &mut impl Http1Hooks) {
+        let mut offset = 0;
+        while !self.stopped {
+            let (consumed, event) = match self.request.next(&data[offset..]) {
+                Ok((_, None)) if offset == data.len() => break,
+                Ok(x) => x,
+                Err(e) => return self.stop(Some(e), hooks),
+            };
+            offset += consumed;
+            match event {
+                Some(Event::Head(raw)) => {
+                    if let Err(e) = self.request_head(&raw, hooks) {
+                        return self.stop(Some(e), hooks);
+                    }
+                }
+                Some(Event::Body(chunk)) => {
+                    if let Some(pending) = self.pending.back_mut() {
+                        pending.request_body_size += chunk.len() as u64;
+                        hooks.on_request_body(&pending.request, chunk);
+                    }
+                }
+                Some(Event::End) => {
+                    if let Some(pending) = self.pending.back_mut() {
+                        pending.request_done = true;
+                    }
+                    self.complete(hooks);
+                }
+                None => (),
+            }
+        }
+        if end_of_stream {
+            self.stop(None, hooks);
+        }
+    }
+
+    /// Feeds bytes sent by the server
+    pub fn feed_response(&mut self, data: &[u8], end_of_stream: bool, hooks: &mut impl Http1Hooks) {
+        let mut offset = 0;
+        while !self.stopped {
+            let (consumed, event) = match self.response.next(&data[offset..]) {
+                Ok((_, None)) if offset == data.len() => break,
+                Ok(x) => x,
+                Err(e) => return self.stop(Some(e), hooks),
+            };
+            offset += consumed;
+            match event {
+                Some(Event::Head(raw)) => {
+                    if let Err(e) = self.response_head(&raw, hooks) {
+                        return self.stop(Some(e), hooks);
+                    }
+                }
+                Some(Event::Body(chunk)) => {
+                    if let Some(pending) = self.responding() {
+                        pending.response_body_size += chunk.len() as u64;
+                        if let Some(response) = &pending.response {
+                            hooks.on_response_body(&pending.request, response, chunk);
+                        }
+                    }
+                }
+                Some(Event::End) => self.response_end(hooks),
+                None => (),
+            }
+        }
+        if end_of_stream && self.response.state == State::Body(Framing::UntilClose) {
+            self.response_end(hooks);
+        }
+        if end_of_stream {
+            self.stop(None, hooks);
+        }
+    }
+
+    /// The transaction whose response is being read
+    fn responding(&mut self) -> Option<&mut Pending> {
+        self.pending.iter_mut().find(|x| !x.response_done)
+    }
+
+    fn request_head(&mut self, raw: &[u8], hooks: &mut impl Http1Hooks) -> Result<(), Http1Error> {
+        let ([method, path, version], headers) = parse_head(raw)?;
+        if !version.starts_with("HTTP/1.") {
+            return Err(Http1Error::Malformed("not an HTTP/1 request"));
+        }
+        if self.pending.len() >= self.max_pending {
+            return Err(Http1Error::TooManyPending);
+        }
+        let framing = body_framing(&headers, false)?;
+        let request = Http1Request {
+            method,
+            path,
+            version,
+            headers,
+        };
+        hooks.on_request(&request);
+        self.pending.push_back(Pending {
+            request,
+            request_body_size: 0,
+            request_done: false,
+            response: None,
+            response_body_size: 0,
+            response_done: false,
+        });
+        self.request.state = State::Body(framing);
+        Ok(())
+    }
+
+    fn response_head(&mut self, raw: &[u8], hooks: &mut impl Http1Hooks) -> Result<(), Http1Error> {
+        let ([version, status, reason], headers) = parse_head(raw)?;
+        if !version.starts_with("HTTP/1.") {
+            return Err(Http1Error::Malformed("not an HTTP/1 response"));
+        }
+        let status: u16 = status
+            .parse()
+            .map_err(|_| Http1Error::Malformed("invalid status code"))?;
+        let pending = self.responding().ok_or(Http1Error::UnexpectedResponse)?;
+        let tunnel = status == 101
+            || (pending.request.method.eq_ignore_ascii_case("CONNECT")
+                && (200..300).contains(&status));
+        // interim responses, i.e. 100 Continue, precede the final response
+        if (100..200).contains(&status) && !tunnel {
+            return Ok(());
+        }
+        let framing = if pending.request.method.eq_ignore_ascii_case("HEAD")
+            || matches!(status, 204 | 304)
+            || tunnel
+        {
+            Framing::Length(0)
+        } else {
+            body_framing(&headers, true)?
+        };
+        let response = Http1Response {
+            version,
+            status,
+            reason,
+            headers,
+        };
+        hooks.on_response(&pending.request, &response);
+        pending.response = Some(response);
+        // the request side of a tunnel is no longer HTTP
+        pending.request_done |= tunnel;
+        self.response.state = State::Body(framing);
+        if tunnel {
+            self.response_end(hooks);
+            self.stopped = true;
+        }
+        Ok(())
+    }
+
+    fn response_end(&mut self, hooks: &mut impl Http1Hooks) {
+        if let Some(pending) = self.responding() {
+            pending.response_done = true;
+        }
+        self.response.state = State::Head;
+        self.complete(hooks);
+    }
+
+    /// Reports transactions at the front that are complete in both directions
+    fn complete(&mut self, hooks: &mut impl Http1Hooks) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|x| x.request_done && x.response_done)
+        {
+            let pending = self.pending.pop_front().unwrap();
+            hooks.on_transaction(Http1Transaction {
+                request: pending.request,
+                response: pending.response,
+                request_body_size: pending.request_body_size,
+                response_body_size: pending.response_body_size,
+            });
+        }
+    }
+
+    /// Stops parsing, reporting the error if any and all incomplete transactions
+    fn stop(&mut self, error: Option<Http1Error>, hooks: &mut impl Http1Hooks) {
+        if let Some(error) = &error {
+            hooks.on_error(error);
+        }
+        self.stopped = true;
+        for pending in std::mem::take(&mut self.pending) {
+            hooks.on_transaction(Http1Transaction {
+                request: pending.request,
+                response: pending.response,
+                request_body_size: pending.request_body_size,
+                response_body_size: pending.response_body_size,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        requests: Vec<String>,
+        response_body: Vec<u8>,
+        transactions: Vec<Http1Transaction>,
+        errors: Vec<Http1Error>,
+    }
+
+    impl Http1Hooks for Recorder {
+        fn on_request(&mut self, request: &Http1Request) {
+            self.requests.push(request.path.clone());
+        }
+
+        fn on_response_body(&mut self, _: &Http1Request, _: &Http1Response, chunk: &[u8]) {
+            self.response_body.extend_from_slice(chunk);
+        }
+
+        fn on_transaction(&mut self, transaction: Http1Transaction) {
+            self.transactions.push(transaction);
+        }
+
+        fn on_error(&mut self, error: &Http1Error) {
+            self.errors.push(error.clone());
+        }
+    }
+
+    #[test]
+    fn test_pipelined() {
+        let mut correlator = Http1Correlator::new();
+        let mut hooks = Recorder::default();
+        correlator.feed_request(
+            b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel",
+            false,
+            &mut hooks,
+        );
+        correlator.feed_request(b"loHEAD /c HTTP/1.1\r\n\r\n", false, &mut hooks);
+        assert_eq!(hooks.requests, ["/a", "/b", "/c"]);
+        assert_eq!(correlator.pending(), 3);
+
+        correlator.feed_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n",
+            false,
+            &mut hooks,
+        );
+        correlator.feed_response(
+            b"2;x=y\r\nde\r\n0\r\n\r\nHTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n",
+            false,
+            &mut hooks,
+        );
+        correlator.feed_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n",
+            false,
+            &mut hooks,
+        );
+        assert_eq!(hooks.response_body, b"abcde");
+        assert_eq!(hooks.transactions.len(), 3);
+        let [a, b, c] = &hooks.transactions[..] else {
+            unreachable!()
+        };
+        assert_eq!((a.request.path.as_str(), a.response_body_size), ("/a", 5));
+        assert_eq!(b.request_body_size, 5);
+        assert_eq!(b.response.as_ref().map(|x| x.status), Some(201));
+        assert_eq!(
+            c.response.as_ref().and_then(|x| x.header("content-length")),
+            Some("10")
+        );
+        assert_eq!(correlator.pending(), 0);
+        assert!(hooks.errors.is_empty());
+    }
+
+    #[test]
+    fn test_close_and_upgrade() {
+        let mut correlator = Http1Correlator::new();
+        let mut hooks = Recorder::default();
+        correlator.feed_request(b"GET / HTTP/1.0\r\n\r\n", false, &mut hooks);
+        correlator.feed_response(b"HTTP/1.0 200 OK\r\n\r\nbody", false, &mut hooks);
+        correlator.feed_response(b" until close", true, &mut hooks);
+        assert_eq!(hooks.transactions[0].response_body_size, 16);
+        assert!(!correlator.is_active());
+
+        let mut correlator = Http1Correlator::new();
+        let mut hooks = Recorder::default();
+        correlator.feed_request(
+            b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n",
+            false,
+            &mut hooks,
+        );
+        correlator.feed_response(
+            b"HTTP/1.1 101 Switching Protocols\r\n\r\n\x81\x05",
+            false,
+            &mut hooks,
+        );
+        assert_eq!(hooks.transactions.len(), 1);
+        assert!(!correlator.is_active());
+        assert!(hooks.errors.is_empty());
+
+        let mut correlator = Http1Correlator::new();
+        let mut hooks = Recorder::default();
+        correlator.feed_response(b"HTTP/1.1 200 OK\r\n\r\n", false, &mut hooks);
+        assert_eq!(hooks.errors, [Http1Error::UnexpectedResponse]);
+    }
+}
@@ -1,16 +1,21 @@
 use std::{
+    any::Any,
     ops::{Bound, RangeBounds},
     time::Duration,
 };
 
 use derive_builder::Builder;
+use log::debug;
 
 use crate::{
     downcast_box::DowncastBox,
     hostcalls::{self, BufferType, MapType},
+    http_retry::RetryingCall,
+    idempotency,
+    json::{JsonError, Value},
     log_concern,
     upstream::Upstream,
-    RootContext, Status,
+    CalloutError, CalloutQuota, ResponseFuture, RetryPolicy, RootContext, Status,
 };
 
 /// Outbound HTTP call
@@ -37,6 +42,15 @@ pub struct HttpCall<'a> {
     /// Callback to call when a response has arrived.
     #[builder(setter(custom), default)]
     pub callback: Option<Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>, &HttpCallResponse)>>,
+    /// If dispatched within an HTTP context, copy the request id, retry policy, and timeout headers of the inflight request
+    /// onto this call. The inflight request timeout also bounds `timeout`. Headers set explicitly on this call are kept.
+    /// Retry policy headers are not copied onto a call that may not be retried, see [`crate::Idempotency`], nor onto a call
+    /// with a [`HttpCall::retry`] policy.
+    #[builder(default)]
+    pub inherit_request_policy: bool,
+    /// Retries of failed attempts. The timeout of the call bounds all attempts.
+    #[builder(setter(strip_option), default)]
+    pub retry: Option<RetryPolicy>,
 }
 
 impl<'a> HttpCallBuilder<'a> {
@@ -53,6 +67,23 @@ impl<'a> HttpCallBuilder<'a> {
         })));
         self
     }
+
+    /// Set a response callback on the HTTP or stream context that dispatched the call, instead of on its root context.
+    /// The callback is not called if that context is gone by the time the response arrives.
+    pub fn context_callback<C: Any>(
+        mut self,
+        callback: impl FnOnce(&mut C, &HttpCallResponse) + 'static,
+    ) -> Self {
+        self.callback = Some(Some(Box::new(move |_, resp| {
+            let called = crate::dispatcher::with_active_context(|context| {
+                callback(context.downcast_mut().expect("invalid context type"), resp)
+            });
+            if called.is_none() {
+                debug!("dropping http call response for non-existing context");
+            }
+        })));
+        self
+    }
 }
 
 impl<'a> HttpCall<'a> {
@@ -60,18 +91,117 @@ impl<'a> HttpCall<'a> {
 
     /// Sends this `HttpCall` over the network.
     pub fn dispatch(self) -> Result<(), Status> {
+        let mut timeout = self.timeout.unwrap_or(Self::DEFAULT_TIMEOUT);
+        let may_retry = (self.retry.is_some() || self.inherit_request_policy)
+            && idempotency().classify_headers(&self.headers).may_retry();
+        let inherited = if self.inherit_request_policy && crate::dispatcher::http_phase().is_some()
+        {
+            // Envoy retries of a call the SDK retries itself would multiply the attempts
+            let envoy_retry = may_retry && self.retry.is_none();
+            let inherited = inherited_headers(&self.headers, envoy_retry, |name| {
+                hostcalls::get_map_value(MapType::HttpRequestHeaders, name)
+                    .ok()
+                    .flatten()
+            });
+            if let Some(limit) = inherited_timeout(&inherited) {
+                timeout = timeout.min(limit);
+            }
+            inherited
+        } else {
+            vec![]
+        };
+        let mut headers = self.headers;
+        headers.extend(inherited.iter().map(|(name, value)| (*name, &**value)));
+        if let Some(policy) = self.retry.filter(|x| may_retry && x.attempts() > 1) {
+            return RetryingCall::new(
+                &self.upstream.0,
+                &headers,
+                &self.trailers,
+                self.body,
+                timeout,
+                policy,
+                self.callback,
+            )
+            .start();
+        }
         let token = hostcalls::dispatch_http_call(
             &self.upstream.0,
-            &self.headers,
+            &headers,
             self.body,
             &self.trailers,
-            self.timeout.unwrap_or(Self::DEFAULT_TIMEOUT),
+            timeout,
         )?;
         if let Some(callback) = self.callback {
             crate::dispatcher::register_http_callback(token, callback);
         }
         Ok(())
     }
+
+    /// Sends this `HttpCall`, returning a future of the response for tasks of [`crate::spawn_local`].
+    /// Any callback set on this call is replaced.
+    pub fn dispatch_async(mut self) -> Result<ResponseFuture<OwnedHttpCallResponse>, Status> {
+        let (future, sender) = ResponseFuture::new();
+        self.callback = Some(Box::new(move |_, response| {
+            sender.send(OwnedHttpCallResponse::read(response))
+        }));
+        self.dispatch()?;
+        Ok(future)
+    }
+
+    /// Sends this `HttpCall` if `quota` allows it, counting the request body against the quota's byte limit.
+    pub fn dispatch_within(self, quota: &CalloutQuota) -> Result<(), CalloutError> {
+        quota.acquire(self.body.map_or(0, |x| x.len()) as u64)?;
+        Ok(self.dispatch()?)
+    }
+}
+
+/// Request headers copied by [`HttpCall::inherit_request_policy`]
+const INHERITED_HEADERS: &[&str] = &[
+    "x-request-id",
+    "x-envoy-upstream-rq-timeout-ms",
+    "x-envoy-upstream-rq-per-try-timeout-ms",
+    "x-envoy-max-retries",
+    "x-envoy-retry-on",
+    "x-envoy-retry-grpc-on",
+    "x-envoy-retriable-status-codes",
+    "x-envoy-retriable-header-names",
+];
+
+/// Headers of [`INHERITED_HEADERS`] asking Envoy to retry the call
+const RETRY_HEADERS: &[&str] = &[
+    "x-envoy-max-retries",
+    "x-envoy-retry-on",
+    "x-envoy-retry-grpc-on",
+    "x-envoy-retriable-status-codes",
+    "x-envoy-retriable-header-names",
+];
+
+fn inherited_headers(
+    existing: &[(&str, &[u8])],
+    retry: bool,
+    lookup: impl Fn(&str) -> Option<Vec<u8>>,
+) -> Vec<(&'static str, Vec<u8>)> {
+    INHERITED_HEADERS
+        .iter()
+        .filter(|name| retry || !RETRY_HEADERS.contains(name))
+        .filter(|name| !existing.iter().any(|(x, _)| x.eq_ignore_ascii_case(name)))
+        .filter_map(|name| Some((*name, lookup(name)?)))
+        .collect()
+}
+
+fn inherited_timeout(inherited: &[(&'static str, Vec<u8>)]) -> Option<Duration> {
+    let (_, value) = inherited
+        .iter()
+        .find(|(name, _)| *name == "x-envoy-upstream-rq-timeout-ms")?;
+    match std::str::from_utf8(value)
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?
+    {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
 }
 
 /// Response type for [`HttpCall::callback`]
@@ -148,6 +278,16 @@ impl HttpCallResponse {
         self.body(..)
     }
 
+    /// Parses the response body as JSON. A missing body is an error.
+    pub fn json(&self) -> Result<Value, JsonError> {
+        let body = if self.body_size() > 0 {
+            self.full_body().unwrap_or_default()
+        } else {
+            vec![]
+        };
+        Value::parse(body)
+    }
+
     /// Get all response trailers
     pub fn trailers(&self) -> Vec<(String, Vec<u8>)> {
         log_concern(
@@ -165,3 +305,230 @@ impl HttpCallResponse {
         )
     }
 }
+
+/// Owned response of an HTTP call, read out of the host while the response was available
+#[derive(Clone, Debug)]
+pub struct OwnedHttpCallResponse {
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, Vec<u8>)>,
+}
+
+impl OwnedHttpCallResponse {
+    pub(crate) fn read(response: &HttpCallResponse) -> Self {
+        Self {
+            headers: response.headers(),
+            body: if response.body_size() > 0 {
+                response.full_body().unwrap_or_default()
+            } else {
+                vec![]
+            },
+            trailers: response.trailers(),
+        }
+    }
+
+    /// Get a specific response header
+    pub fn header(&self, name: impl AsRef<str>) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name.as_ref()))
+            .map(|(_, value)| &**value)
+    }
+
+    /// Status code of the response. `None` if the call failed without a response, i.e. on timeout.
+    pub fn status(&self) -> Option<u32> {
+        std::str::from_utf8(self.header(":status")?)
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// Parses the response body as JSON. A missing body is an error.
+    pub fn json(&self) -> Result<Value, JsonError> {
+        Value::parse(&self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inherited_headers() {
+        let existing: Vec<(&str, &[u8])> = vec![(":path", b"/"), ("X-Request-Id", b"mine")];
+        let lookup = |name: &str| match name {
+            "x-request-id" => Some(b"theirs".to_vec()),
+            "x-envoy-upstream-rq-timeout-ms" => Some(b"250".to_vec()),
+            "x-envoy-retry-on" => Some(b"5xx".to_vec()),
+            _ => None,
+        };
+        assert_eq!(
+            inherited_headers(&existing, false, lookup),
+            vec![("x-envoy-upstream-rq-timeout-ms", b"250".to_vec())]
+        );
+        let inherited = inherited_headers(&existing, true, lookup);
+        assert_eq!(
+            inherited,
+            vec![
+                ("x-envoy-upstream-rq-timeout-ms", b"250".to_vec()),
+                ("x-envoy-retry-on", b"5xx".to_vec()),
+            ]
+        );
+        assert_eq!(
+            inherited_timeout(&inherited),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            inherited_timeout(&[("x-envoy-upstream-rq-timeout-ms", b"0".to_vec())]),
+            None
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_inherit_with_retry() {
+        use crate::{
+            dispatcher::{self, proxy_on_context_create, proxy_on_request_headers},
+            testing::{host::with_host, http_calls, reset_host},
+            BaseContext, Context, FilterHeadersStatus, HttpContext, RequestHeaders,
+        };
+
+        #[derive(Default)]
+        struct Root;
+
+        impl BaseContext for Root {}
+
+        impl RootContext for Root {
+            fn create_context(&mut self) -> Context {
+                Context::Http(Box::new(Filter))
+            }
+        }
+
+        struct Filter;
+
+        impl BaseContext for Filter {}
+
+        impl HttpContext for Filter {
+            fn on_http_request_headers(&mut self, _: &RequestHeaders) -> FilterHeadersStatus {
+                for retry in [None, Some(RetryPolicy::new(3))] {
+                    let mut call = HttpCallBuilder::default()
+                        .upstream(&"backend")
+                        .header((":method", &b"GET"[..]))
+                        .inherit_request_policy(true)
+                        .build()
+                        .unwrap();
+                    call.retry = retry;
+                    call.dispatch().unwrap();
+                }
+                FilterHeadersStatus::Continue
+            }
+        }
+
+        reset_host();
+        with_host(|host| {
+            *host.header_map(MapType::HttpRequestHeaders) = vec![
+                ("x-envoy-retry-on".to_string(), b"5xx".to_vec()),
+                (
+                    "x-envoy-upstream-rq-timeout-ms".to_string(),
+                    b"250".to_vec(),
+                ),
+            ]
+        });
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        proxy_on_context_create(2, 1);
+        proxy_on_request_headers(2, 2, 0);
+        let names = |i: usize| -> Vec<String> {
+            http_calls()[i]
+                .headers
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        assert_eq!(
+            names(0),
+            [
+                ":method",
+                "x-envoy-upstream-rq-timeout-ms",
+                "x-envoy-retry-on"
+            ]
+        );
+        // the SDK retries the call, so Envoy must not retry each attempt
+        assert_eq!(names(1), [":method", "x-envoy-upstream-rq-timeout-ms"]);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_context_callback() {
+        use std::cell::RefCell;
+
+        use crate::{
+            dispatcher::{
+                self, proxy_on_context_create, proxy_on_http_call_response,
+                proxy_on_request_headers,
+            },
+            testing::{http_calls, reset_host},
+            BaseContext, Context, FilterHeadersStatus, HttpContext, RequestHeaders,
+        };
+
+        thread_local! {
+            static RESPONSES: RefCell<Vec<(u32, usize)>> = const { RefCell::new(vec![]) };
+        }
+
+        #[derive(Default)]
+        struct Root;
+
+        impl BaseContext for Root {}
+
+        impl RootContext for Root {
+            fn create_context(&mut self) -> Context {
+                Context::Http(Box::new(Filter { id: 0 }))
+            }
+        }
+
+        struct Filter {
+            id: u32,
+        }
+
+        impl BaseContext for Filter {}
+
+        impl HttpContext for Filter {
+            fn on_http_request_headers(&mut self, _: &RequestHeaders) -> FilterHeadersStatus {
+                self.id = crate::current_context_id();
+                let id = self.id.to_string();
+                HttpCallBuilder::default()
+                    .upstream(&"backend")
+                    .header(("x-context", id.as_bytes()))
+                    .context_callback(|filter: &mut Filter, response: &HttpCallResponse| {
+                        RESPONSES.with_borrow_mut(|x| x.push((filter.id, response.body_size())))
+                    })
+                    .build()
+                    .unwrap()
+                    .dispatch()
+                    .unwrap();
+                FilterHeadersStatus::Continue
+            }
+        }
+
+        reset_host();
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        proxy_on_context_create(2, 1);
+        proxy_on_context_create(3, 1);
+        proxy_on_request_headers(3, 0, 1);
+        proxy_on_request_headers(2, 0, 1);
+        let token = |context: &str| {
+            http_calls()
+                .into_iter()
+                .find(|x| {
+                    x.headers
+                        .contains(&("x-context".to_string(), context.into()))
+                })
+                .unwrap()
+                .token as usize
+        };
+        proxy_on_http_call_response(1, token("2"), 0, 5, 0);
+        proxy_on_http_call_response(1, token("3"), 0, 7, 0);
+        assert_eq!(RESPONSES.with_borrow(|x| x.clone()), vec![(2, 5), (3, 7)]);
+    }
+}
@@ -0,0 +1,437 @@
+use std::{fmt, time::Duration};
+
+use crate::{
+    json::Value, upstream::Upstream, HttpCall, HttpCallResponse, OwnedHttpCallResponse,
+    ResponseFuture, RetryPolicy, RootContext, Status,
+};
+
+/// Error parsing a URL of a [`HttpRequestBuilder`]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum UrlError {
+    /// The URL does not start with `http://` or `https://`
+    UnsupportedScheme,
+    MissingHost,
+    InvalidPort,
+    /// The URL carries credentials, which are never sent. Set an `authorization` header instead.
+    Credentials,
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlError::UnsupportedScheme => write!(f, "url scheme must be http or https"),
+            UrlError::MissingHost => write!(f, "url has no host"),
+            UrlError::InvalidPort => write!(f, "url has an invalid port"),
+            UrlError::Credentials => write!(f, "url has credentials"),
+        }
+    }
+}
+
+impl std::error::Error for UrlError {}
+
+/// An absolute `http` or `https` URL, split into the parts sent as pseudo headers
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Url {
+    pub scheme: String,
+    /// Host name or IP address, without the brackets of IPv6 addresses
+    pub host: String,
+    pub port: Option<u16>,
+    /// Path and query, `/` if the URL has no path. The fragment is dropped.
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self, UrlError> {
+        let (scheme, rest) = url.split_once("://").ok_or(UrlError::UnsupportedScheme)?;
+        let scheme = scheme.to_ascii_lowercase();
+        if scheme != "http" && scheme != "https" {
+            return Err(UrlError::UnsupportedScheme);
+        }
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if authority.contains('@') {
+            return Err(UrlError::Credentials);
+        }
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(ipv6) => {
+                let (host, port) = ipv6.split_once(']').ok_or(UrlError::MissingHost)?;
+                match port {
+                    "" => (host, None),
+                    port => (
+                        host,
+                        Some(port.strip_prefix(':').ok_or(UrlError::InvalidPort)?),
+                    ),
+                }
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(UrlError::MissingHost);
+        }
+        let port = port
+            .map(|x| x.parse::<u16>().map_err(|_| UrlError::InvalidPort))
+            .transpose()?;
+        let path = match path {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{path}"),
+            path => path.to_string(),
+        };
+        Ok(Self {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+            path,
+        })
+    }
+
+    /// Host and port as sent in `:authority`. The port is omitted if it is the default of the scheme.
+    pub fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        let default_port = if self.scheme == "https" { 443 } else { 80 };
+        match self.port {
+            Some(port) if port != default_port => format!("{host}:{port}"),
+            _ => host,
+        }
+    }
+}
+
+/// Outbound HTTP request from a URL, filling in the pseudo headers, `content-length` and `content-type` of an
+/// [`HttpCall`].
+///
+/// The call goes to the cluster named after the host of the URL, unless set with [`HttpRequestBuilder::cluster`].
+///
+/// ```ignore
+/// HttpRequestBuilder::post("https://metadata.internal/v1/lookup")?
+///     .cluster("metadata")
+///     .header("authorization", token)
+///     .json(&Value::Object(vec![("tenant".into(), tenant.into())]))
+///     .send(|root: &mut Root, response| root.on_lookup(response))?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct HttpRequestBuilder {
+    method: String,
+    url: Url,
+    cluster: Option<String>,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
+    inherit_request_policy: bool,
+}
+
+impl HttpRequestBuilder {
+    pub fn new(method: impl Into<String>, url: &str) -> Result<Self, UrlError> {
+        Ok(Self {
+            method: method.into(),
+            url: Url::parse(url)?,
+            cluster: None,
+            headers: vec![],
+            body: None,
+            timeout: None,
+            retry: None,
+            inherit_request_policy: false,
+        })
+    }
+
+    pub fn get(url: &str) -> Result<Self, UrlError> {
+        Self::new("GET", url)
+    }
+
+    pub fn head(url: &str) -> Result<Self, UrlError> {
+        Self::new("HEAD", url)
+    }
+
+    pub fn post(url: &str) -> Result<Self, UrlError> {
+        Self::new("POST", url)
+    }
+
+    pub fn put(url: &str) -> Result<Self, UrlError> {
+        Self::new("PUT", url)
+    }
+
+    pub fn patch(url: &str) -> Result<Self, UrlError> {
+        Self::new("PATCH", url)
+    }
+
+    pub fn delete(url: &str) -> Result<Self, UrlError> {
+        Self::new("DELETE", url)
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Sends to the cluster `cluster` instead of the one named after the host
+    pub fn cluster(mut self, cluster: impl Into<String>) -> Self {
+        self.cluster = Some(cluster.into());
+        self
+    }
+
+    /// Adds a header. Names are lowercased, as HTTP/2 requires.
+    pub fn header(mut self, name: impl AsRef<str>, value: impl Into<Vec<u8>>) -> Self {
+        self.headers
+            .push((name.as_ref().to_ascii_lowercase(), value.into()));
+        self
+    }
+
+    /// Sets the body, sent with its `content-length`
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// Sets a JSON body, with `content-type: application/json` unless a content type was set
+    pub fn json(self, body: &Value) -> Self {
+        self.body(body.to_bytes())
+            .default_header("content-type", b"application/json")
+    }
+
+    /// Timeout of the call. Default is 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// See [`HttpCall::inherit_request_policy`]
+    pub fn inherit_request_policy(mut self, inherit: bool) -> Self {
+        self.inherit_request_policy = inherit;
+        self
+    }
+
+    fn default_header(mut self, name: &str, value: &[u8]) -> Self {
+        if !self.headers.iter().any(|(x, _)| x == name) {
+            self.headers.push((name.to_string(), value.to_vec()));
+        }
+        self
+    }
+
+    /// Sends the request, calling `callback` on the root context with the response
+    pub fn send<R: RootContext + 'static>(
+        self,
+        callback: impl FnOnce(&mut R, &HttpCallResponse) + 'static,
+    ) -> Result<(), Status> {
+        self.with_call(|mut call| {
+            call.callback = Some(Box::new(move |root, response| {
+                callback(
+                    root.as_any_mut().downcast_mut().expect("invalid root type"),
+                    response,
+                )
+            }));
+            call.dispatch()
+        })
+    }
+
+    /// Sends the request, ignoring the response
+    pub fn dispatch(self) -> Result<(), Status> {
+        self.with_call(|call| call.dispatch())
+    }
+
+    /// Sends the request, returning a future of the response for tasks of [`crate::spawn_local`]
+    pub fn dispatch_async(self) -> Result<ResponseFuture<OwnedHttpCallResponse>, Status> {
+        self.with_call(|call| call.dispatch_async())
+    }
+
+    fn with_call<T>(self, f: impl FnOnce(HttpCall<'_>) -> T) -> T {
+        let authority = self.url.authority();
+        let content_length = self.body.as_ref().map(|x| x.len().to_string());
+        let mut headers: Vec<(&str, &[u8])> = vec![
+            (":method", self.method.as_bytes()),
+            (":scheme", self.url.scheme.as_bytes()),
+            (":authority", authority.as_bytes()),
+            (":path", self.url.path.as_bytes()),
+        ];
+        if let Some(content_length) = &content_length {
+            headers.push(("content-length", content_length.as_bytes()));
+        }
+        headers.extend(
+            self.headers
+                .iter()
+                .filter(|(name, _)| content_length.is_none() || name != "content-length")
+                .map(|(name, value)| (&**name, &**value)),
+        );
+        let cluster = self.cluster.as_ref().unwrap_or(&self.url.host);
+        f(HttpCall {
+            upstream: Upstream::from(cluster),
+            headers,
+            trailers: vec![],
+            body: self.body.as_deref(),
+            timeout: self.timeout,
+            callback: None,
+            inherit_request_policy: self.inherit_request_policy,
+            retry: self.retry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_parse() {
+        let url = Url::parse("HTTPS://Metadata.Internal/v1/lookup?id=1#top").unwrap();
+        assert_eq!(url.scheme, "https");
+        assert_eq!(url.host, "metadata.internal");
+        assert_eq!(url.port, None);
+        assert_eq!(url.path, "/v1/lookup?id=1");
+        assert_eq!(url.authority(), "metadata.internal");
+
+        let url = Url::parse("http://10.0.0.1:8080").unwrap();
+        assert_eq!(url.path, "/");
+        assert_eq!(url.authority(), "10.0.0.1:8080");
+        assert_eq!(Url::parse("http://host:80?q").unwrap().authority(), "host");
+        assert_eq!(Url::parse("http://host?q").unwrap().path, "/?q");
+
+        let url = Url::parse("https://[::1]:8443/x").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.authority(), "[::1]:8443");
+        assert_eq!(Url::parse("https://[::1]").unwrap().authority(), "[::1]");
+
+        assert_eq!(Url::parse("host/path"), Err(UrlError::UnsupportedScheme));
+        assert_eq!(Url::parse("ftp://host"), Err(UrlError::UnsupportedScheme));
+        assert_eq!(Url::parse("http:///path"), Err(UrlError::MissingHost));
+        assert_eq!(Url::parse("http://host:x"), Err(UrlError::InvalidPort));
+        assert_eq!(Url::parse("http://[::1]x"), Err(UrlError::InvalidPort));
+        assert_eq!(Url::parse("http://u:p@host"), Err(UrlError::Credentials));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_http_request_dispatch() {
+        use std::cell::RefCell;
+
+        use crate::{
+            dispatcher::{
+                self, proxy_on_context_create, proxy_on_http_call_response, proxy_on_tick,
+            },
+            hostcalls::deserialize_map_bytes,
+            native::{clear_host, set_host, Host},
+            testing::reset_host,
+            BaseContext, Context,
+        };
+
+        type Sent = (String, Vec<(String, Vec<u8>)>, Vec<u8>);
+
+        thread_local! {
+            static SENT: RefCell<Vec<Sent>> = const { RefCell::new(vec![]) };
+            static DELIVERED: RefCell<Vec<usize>> = const { RefCell::new(vec![]) };
+        }
+
+        struct CallHost;
+
+        impl Host for CallHost {
+            unsafe fn proxy_http_call(
+                &self,
+                upstream_data: *const u8,
+                upstream_size: usize,
+                headers_data: *const u8,
+                headers_size: usize,
+                body_data: *const u8,
+                body_size: usize,
+                _trailers_data: *const u8,
+                _trailers_size: usize,
+                _timeout: u32,
+                return_token: *mut u32,
+            ) -> Status {
+                let slice = |data, size| {
+                    if size == 0 {
+                        &[][..]
+                    } else {
+                        std::slice::from_raw_parts(data, size)
+                    }
+                };
+                let upstream = String::from_utf8_lossy(slice(upstream_data, upstream_size));
+                let headers = deserialize_map_bytes(slice(headers_data, headers_size)).unwrap();
+                let body = slice(body_data, body_size).to_vec();
+                SENT.with_borrow_mut(|x| {
+                    x.push((upstream.into_owned(), headers, body));
+                    *return_token = x.len() as u32;
+                });
+                Status::Ok
+            }
+        }
+
+        #[derive(Default)]
+        struct Root;
+
+        impl BaseContext for Root {}
+
+        impl RootContext for Root {
+            fn on_tick(&mut self) {
+                HttpRequestBuilder::post("https://metadata.internal:8443/v1/lookup")
+                    .unwrap()
+                    .header("X-Tenant", "acme")
+                    .json(&Value::Object(vec![("id".into(), "a".into())]))
+                    .send(|_: &mut Root, response: &HttpCallResponse| {
+                        DELIVERED.with_borrow_mut(|x| x.push(response.body_size()))
+                    })
+                    .unwrap();
+                HttpRequestBuilder::get("http://example.com")
+                    .unwrap()
+                    .cluster("outbound")
+                    .header("content-type", "text/plain")
+                    .dispatch()
+                    .unwrap();
+            }
+
+            fn create_context(&mut self) -> Context {
+                unreachable!()
+            }
+        }
+
+        reset_host();
+        set_host(CallHost);
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        proxy_on_tick(1);
+        proxy_on_http_call_response(1, 1, 0, 3, 0);
+        proxy_on_http_call_response(1, 2, 0, 0, 0);
+        clear_host();
+
+        let sent = SENT.with_borrow_mut(std::mem::take);
+        let header = |i: usize, name: &str| {
+            sent[i]
+                .1
+                .iter()
+                .find(|(x, _)| x == name)
+                .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+        };
+        assert_eq!(sent[0].0, "metadata.internal");
+        assert_eq!(header(0, ":method").as_deref(), Some("POST"));
+        assert_eq!(header(0, ":scheme").as_deref(), Some("https"));
+        assert_eq!(
+            header(0, ":authority").as_deref(),
+            Some("metadata.internal:8443")
+        );
+        assert_eq!(header(0, ":path").as_deref(), Some("/v1/lookup"));
+        assert_eq!(header(0, "x-tenant").as_deref(), Some("acme"));
+        assert_eq!(
+            header(0, "content-type").as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(header(0, "content-length").as_deref(), Some("10"));
+        assert_eq!(sent[0].2, br#"{"id":"a"}"#);
+
+        assert_eq!(sent[1].0, "outbound");
+        assert_eq!(header(1, ":method").as_deref(), Some("GET"));
+        assert_eq!(header(1, ":path").as_deref(), Some("/"));
+        assert_eq!(header(1, "content-type").as_deref(), Some("text/plain"));
+        assert_eq!(header(1, "content-length"), None);
+        assert_eq!(DELIVERED.with_borrow(|x| x.clone()), vec![3]);
+    }
+}
@@ -0,0 +1,411 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::{
+    dispatcher, downcast_box::DowncastBox, hostcalls, instant_now, now, parse_retry_after, Backoff,
+    HttpCallResponse, RootContext, Status,
+};
+
+/// Failed attempts of an [`crate::HttpCall`] that a [`RetryPolicy`] retries
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RetryOn {
+    /// Responses with a 5xx status
+    ServerError,
+    /// Responses with status 429
+    TooManyRequests,
+    /// Calls that failed without a response, i.e. on timeout or connection failure
+    Reset,
+}
+
+/// Retries of an [`crate::HttpCall`], dispatched again from the response callback of the failed attempt.
+///
+/// Attempts wait for the [`Backoff`] between them: without delay, the call is dispatched again right from the response
+/// callback, otherwise from the ticks of the root context, so the tick period bounds how precisely delays are kept.
+/// A `Retry-After` on a 429 or 503 response extends the delay, and the call is not retried if it would wait past its
+/// timeout.
+/// Each attempt is bounded by the per-try timeout, and all of them by the timeout of the call. Calls that
+/// [`crate::idempotency`] does not allow to retry are sent once. The callback only sees the last attempt.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    attempts: u32,
+    retry_on: Vec<RetryOn>,
+    per_try_timeout: Option<Duration>,
+    backoff: Backoff,
+}
+
+impl RetryPolicy {
+    /// Makes up to `attempts` attempts, including the first, retrying on any [`RetryOn`] after 25 milliseconds doubling up to a second
+    pub fn new(attempts: u32) -> Self {
+        Self {
+            attempts: attempts.max(1),
+            retry_on: vec![
+                RetryOn::ServerError,
+                RetryOn::TooManyRequests,
+                RetryOn::Reset,
+            ],
+            per_try_timeout: None,
+            backoff: Backoff::new(Duration::from_millis(25), Duration::from_secs(1)),
+        }
+    }
+
+    pub fn retry_on(mut self, retry_on: &[RetryOn]) -> Self {
+        self.retry_on = retry_on.to_vec();
+        self
+    }
+
+    pub fn per_try_timeout(mut self, timeout: Duration) -> Self {
+        self.per_try_timeout = Some(timeout);
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Attempts, including the first
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Whether an attempt answered with `status` is retried, `None` being a call that failed without a response
+    pub fn retries(&self, status: Option<u32>) -> bool {
+        let class = match status {
+            None => RetryOn::Reset,
+            Some(429) => RetryOn::TooManyRequests,
+            Some(500..=599) => RetryOn::ServerError,
+            Some(_) => return false,
+        };
+        self.retry_on.contains(&class)
+    }
+}
+
+fn pairs(map: &[(String, Vec<u8>)]) -> Vec<(&str, &[u8])> {
+    map.iter()
+        .map(|(name, value)| (&**name, &**value))
+        .collect()
+}
+
+type Callback = Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>, &HttpCallResponse)>;
+
+/// An [`crate::HttpCall`] under a [`RetryPolicy`], owning its request to send it again
+pub(crate) struct RetryingCall {
+    upstream: Vec<u8>,
+    headers: Vec<(String, Vec<u8>)>,
+    trailers: Vec<(String, Vec<u8>)>,
+    body: Option<Vec<u8>>,
+    deadline: Instant,
+    retry_at: Instant,
+    policy: RetryPolicy,
+    attempt: u32,
+    callback: Option<Callback>,
+    context_id: u32,
+    root_context_id: u32,
+}
+
+impl RetryingCall {
+    pub(crate) fn new(
+        upstream: &[u8],
+        headers: &[(&str, &[u8])],
+        trailers: &[(&str, &[u8])],
+        body: Option<&[u8]>,
+        timeout: Duration,
+        policy: RetryPolicy,
+        callback: Option<Callback>,
+    ) -> Self {
+        let owned = |x: &[(&str, &[u8])]| {
+            x.iter()
+                .map(|(name, value)| (name.to_string(), value.to_vec()))
+                .collect()
+        };
+        Self {
+            upstream: upstream.to_vec(),
+            headers: owned(headers),
+            trailers: owned(trailers),
+            body: body.map(<[u8]>::to_vec),
+            deadline: instant_now() + timeout,
+            retry_at: instant_now(),
+            policy,
+            attempt: 0,
+            callback,
+            context_id: dispatcher::current_context_id(),
+            root_context_id: dispatcher::root_id(),
+        }
+    }
+
+    /// Dispatches the first attempt
+    pub(crate) fn start(self) -> Result<(), Status> {
+        let token = self.dispatch_attempt()?;
+        self.register(token);
+        Ok(())
+    }
+
+    fn dispatch_attempt(&self) -> Result<u32, Status> {
+        let remaining = self.deadline.saturating_duration_since(instant_now());
+        let timeout = self
+            .policy
+            .per_try_timeout
+            .map_or(remaining, |x| x.min(remaining));
+        hostcalls::dispatch_http_call(
+            &self.upstream,
+            &pairs(&self.headers),
+            self.body.as_deref(),
+            &pairs(&self.trailers),
+            timeout,
+        )
+    }
+
+    fn register(mut self, token: u32) {
+        self.attempt += 1;
+        dispatcher::register_http_callback(
+            token,
+            Box::new(move |root, response| self.on_response(root, response)),
+        );
+    }
+
+    fn on_response(mut self, root: &mut DowncastBox<dyn RootContext>, response: &HttpCallResponse) {
+        let status = (response.num_headers() > 0)
+            .then(|| response.header(":status"))
+            .flatten()
+            .and_then(|x| std::str::from_utf8(&x).ok()?.parse().ok());
+        let retry = self.attempt < self.policy.attempts
+            && self.policy.retries(status)
+            && instant_now() < self.deadline;
+        if !retry {
+            return self.deliver(root, response);
+        }
+        let retry_after = matches!(status, Some(429 | 503))
+            .then(|| response.header("retry-after"))
+            .flatten()
+            .and_then(|x| parse_retry_after(std::str::from_utf8(&x).ok()?, now()));
+        let delay = self
+            .policy
+            .backoff
+            .fail()
+            .max(retry_after.unwrap_or_default());
+        self.retry_at = instant_now() + delay;
+        if self.retry_at >= self.deadline {
+            debug!("not retrying http call asked to retry after {retry_after:?}, past its timeout");
+            return self.deliver(root, response);
+        }
+        debug!(
+            "retrying http call after attempt {} answered {status:?} in {delay:?}",
+            self.attempt
+        );
+        if !delay.is_zero() {
+            let root_context_id = self.root_context_id;
+            return dispatcher::spawn_boxed(root_context_id, Box::new(move |root| self.wait(root)));
+        }
+        match self.dispatch_attempt() {
+            Ok(token) => self.register(token),
+            Err(e) => {
+                warn!("failed to retry http call: {e:?}");
+                self.deliver(root, response)
+            }
+        }
+    }
+
+    /// Runs from the ticks of the root context until the delay of the next attempt elapsed
+    fn wait(self, root: &mut DowncastBox<dyn RootContext>) {
+        if instant_now() < self.retry_at {
+            let root_context_id = self.root_context_id;
+            return dispatcher::spawn_boxed(root_context_id, Box::new(move |root| self.wait(root)));
+        }
+        let (context_id, root_context_id) = (self.context_id, self.root_context_id);
+        dispatcher::in_task_context(context_id, root_context_id, move || {
+            match self.dispatch_attempt() {
+                Ok(token) => self.register(token),
+                Err(e) => {
+                    warn!("failed to retry http call: {e:?}");
+                    // as for a call that failed without a response
+                    self.deliver(root, &HttpCallResponse::new(0, 0, 0))
+                }
+            }
+        });
+    }
+
+    fn deliver(self, root: &mut DowncastBox<dyn RootContext>, response: &HttpCallResponse) {
+        if let Some(callback) = self.callback {
+            callback(root, response);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        dispatcher::{proxy_on_context_create, proxy_on_http_call_response, proxy_on_tick},
+        hostcalls::MapType,
+        native::{clear_host, set_host, Host},
+        testing::reset_host,
+        BaseContext, Context, HttpCallBuilder, Jitter,
+    };
+
+    thread_local! {
+        static TIMEOUTS: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+        static DELIVERED: RefCell<Vec<Option<Vec<u8>>>> = const { RefCell::new(vec![]) };
+        static PENDING: RefCell<Vec<(&'static str, RetryPolicy)>> = const { RefCell::new(vec![]) };
+    }
+
+    struct CallHost;
+
+    impl Host for CallHost {
+        unsafe fn proxy_http_call(
+            &self,
+            _upstream_data: *const u8,
+            _upstream_size: usize,
+            _headers_data: *const u8,
+            _headers_size: usize,
+            _body_data: *const u8,
+            _body_size: usize,
+            _trailers_data: *const u8,
+            _trailers_size: usize,
+            timeout: u32,
+            return_token: *mut u32,
+        ) -> Status {
+            TIMEOUTS.with_borrow_mut(|x| {
+                x.push(timeout);
+                *return_token = x.len() as u32;
+            });
+            Status::Ok
+        }
+    }
+
+    #[derive(Default)]
+    struct Root;
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn on_tick(&mut self) {
+            for (method, policy) in PENDING.with_borrow_mut(std::mem::take) {
+                HttpCallBuilder::default()
+                    .upstream(&"metadata")
+                    .header((":method", method.as_bytes()))
+                    .timeout(Duration::from_secs(5))
+                    .retry(policy)
+                    .callback(|_: &mut Root, response: &HttpCallResponse| {
+                        DELIVERED.with_borrow_mut(|x| x.push(response.header(":status")))
+                    })
+                    .build()
+                    .unwrap()
+                    .dispatch()
+                    .unwrap();
+            }
+        }
+
+        fn create_context(&mut self) -> Context {
+            unreachable!()
+        }
+    }
+
+    fn call(method: &'static str, policy: RetryPolicy) {
+        PENDING.with_borrow_mut(|x| x.push((method, policy)));
+        proxy_on_tick(1);
+    }
+
+    fn respond(status: Option<&str>) {
+        respond_with(status, &[]);
+    }
+
+    fn respond_with(status: Option<&str>, extra: &[(&str, &str)]) {
+        let num_headers = crate::testing::host::with_host(|host| {
+            let headers = host.header_map(MapType::HttpCallResponseHeaders);
+            *headers = status
+                .map(|x| vec![(":status".to_string(), x.as_bytes().to_vec())])
+                .unwrap_or_default();
+            headers.extend(
+                extra
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())),
+            );
+            headers.len()
+        });
+        let token = TIMEOUTS.with_borrow(|x| x.len());
+        proxy_on_http_call_response(1, token, num_headers, 0, 0);
+    }
+
+    fn calls() -> usize {
+        TIMEOUTS.with_borrow(|x| x.len())
+    }
+
+    fn delivered() -> Vec<Option<Vec<u8>>> {
+        DELIVERED.with_borrow_mut(std::mem::take)
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new(3).retry_on(&[RetryOn::ServerError]);
+        assert!(policy.retries(Some(503)));
+        assert!(!policy.retries(Some(429)));
+        assert!(!policy.retries(None));
+        assert!(!policy.retries(Some(404)));
+        assert_eq!(RetryPolicy::new(0).attempts(), 1);
+    }
+
+    #[test]
+    fn test_http_call_retry() {
+        reset_host();
+        set_host(CallHost);
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        let immediate = Backoff::new(Duration::ZERO, Duration::ZERO);
+
+        call(
+            "GET",
+            RetryPolicy::new(3)
+                .per_try_timeout(Duration::from_secs(1))
+                .backoff(immediate.clone()),
+        );
+        respond(Some("503"));
+        respond(None);
+        assert_eq!(calls(), 3);
+        assert!(delivered().is_empty());
+        // out of attempts
+        respond(Some("500"));
+        assert_eq!(delivered(), [Some(b"500".to_vec())]);
+        assert_eq!(TIMEOUTS.with_borrow(|x| x[0]), 1000);
+
+        call("GET", RetryPolicy::new(3).backoff(immediate.clone()));
+        respond(Some("429"));
+        respond(Some("200"));
+        assert_eq!(calls(), 5);
+        assert_eq!(delivered(), [Some(b"200".to_vec())]);
+        assert!(TIMEOUTS.with_borrow(|x| x[3] <= 5000 && x[3] > 4000));
+
+        // asked to retry after the call times out
+        call("GET", RetryPolicy::new(3).backoff(immediate.clone()));
+        respond_with(Some("503"), &[("retry-after", "60")]);
+        assert_eq!(calls(), 6);
+        assert_eq!(delivered(), [Some(b"503".to_vec())]);
+        call("GET", RetryPolicy::new(3).backoff(immediate.clone()));
+        respond_with(Some("429"), &[("retry-after", "0")]);
+        assert_eq!(calls(), 8);
+        respond(Some("200"));
+        assert_eq!(delivered(), [Some(b"200".to_vec())]);
+
+        // not idempotent
+        call("POST", RetryPolicy::new(3).backoff(immediate));
+        respond(Some("503"));
+        assert_eq!(calls(), 9);
+        assert_eq!(delivered(), [Some(b"503".to_vec())]);
+
+        // retried from the ticks once the backoff elapsed
+        let delayed =
+            Backoff::new(Duration::from_millis(5), Duration::from_millis(5)).jitter(Jitter::None);
+        call("GET", RetryPolicy::new(2).backoff(delayed));
+        respond(Some("502"));
+        assert_eq!(calls(), 10);
+        while calls() == 10 {
+            std::thread::sleep(Duration::from_millis(1));
+            proxy_on_tick(1);
+        }
+        respond(Some("204"));
+        clear_host();
+        assert_eq!(delivered(), [Some(b"204".to_vec())]);
+    }
+}
@@ -0,0 +1,206 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use crate::HttpHeaderControl;
+
+/// Whether a request may be sent more than once, see [`Idempotency`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Idempotence {
+    /// Has no side effects, i.e. `GET` and `HEAD`
+    Safe,
+    /// Has the same effect sent once or more, i.e. `PUT` and `DELETE`
+    Idempotent,
+    /// Not idempotent by method, but carries an idempotency key the upstream deduplicates on
+    Keyed,
+    NonIdempotent,
+}
+
+impl Idempotence {
+    /// Whether the request may be sent again after a failed attempt
+    pub fn may_retry(self) -> bool {
+        self != Idempotence::NonIdempotent
+    }
+
+    /// Whether concurrent copies of the request may be in flight, i.e. hedged requests.
+    /// Not for keyed requests, as upstreams commonly reject a key already in progress.
+    pub fn may_hedge(self) -> bool {
+        matches!(self, Idempotence::Safe | Idempotence::Idempotent)
+    }
+}
+
+/// What [`Idempotency`] classifies a request by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestIdentity<'a> {
+    pub method: &'a str,
+    pub path: &'a [u8],
+    /// Value of the idempotency key header, if any
+    pub key: Option<&'a [u8]>,
+}
+
+type Classifier = Rc<dyn Fn(&RequestIdentity) -> Option<Idempotence>>;
+
+/// Classifies requests by idempotence, so retries and hedging never duplicate a request that isn't safe to repeat.
+///
+/// By default, `GET`, `HEAD`, `OPTIONS` and `TRACE` are safe, `PUT` and `DELETE` idempotent (RFC 9110), and any other
+/// method is idempotent only with a non-empty `idempotency-key` header. Overrides apply in order: the hook set with
+/// [`Idempotency::classify_with`], then methods set with [`Idempotency::method`], then the defaults.
+///
+/// Retry and hedging utilities of the SDK check the classifier set with [`set_idempotency`]. In particular,
+/// [`crate::HttpCall::inherit_request_policy`] doesn't copy Envoy retry headers onto calls that may not be retried.
+#[derive(Clone)]
+pub struct Idempotency {
+    key_header: String,
+    methods: Vec<(String, Idempotence)>,
+    classifier: Option<Classifier>,
+}
+
+impl Idempotency {
+    pub fn new() -> Self {
+        Self {
+            key_header: "idempotency-key".to_string(),
+            methods: vec![],
+            classifier: None,
+        }
+    }
+
+    /// Header carrying the idempotency key. Default is `idempotency-key`.
+    pub fn key_header(mut self, name: impl Into<String>) -> Self {
+        self.key_header = name.into().to_ascii_lowercase();
+        self
+    }
+
+    /// Overrides the class of a method, i.e. `POST` as idempotent for an upstream known to deduplicate
+    pub fn method(mut self, method: impl Into<String>, class: Idempotence) -> Self {
+        let method = method.into().to_ascii_uppercase();
+        self.methods.retain(|(x, _)| *x != method);
+        self.methods.push((method, class));
+        self
+    }
+
+    /// Hook deciding before any other rule, i.e. by path for `POST` endpoints that are read-only. `None` falls through.
+    pub fn classify_with(
+        mut self,
+        classifier: impl Fn(&RequestIdentity) -> Option<Idempotence> + 'static,
+    ) -> Self {
+        self.classifier = Some(Rc::new(classifier));
+        self
+    }
+
+    pub fn classify_identity(&self, request: &RequestIdentity) -> Idempotence {
+        if let Some(class) = self.classifier.as_ref().and_then(|x| x(request)) {
+            return class;
+        }
+        let method = request.method;
+        if let Some((_, class)) = self
+            .methods
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(method))
+        {
+            return *class;
+        }
+        match method.to_ascii_uppercase().as_str() {
+            "GET" | "HEAD" | "OPTIONS" | "TRACE" => Idempotence::Safe,
+            "PUT" | "DELETE" => Idempotence::Idempotent,
+            _ if request.key.is_some_and(|x| !x.is_empty()) => Idempotence::Keyed,
+            _ => Idempotence::NonIdempotent,
+        }
+    }
+
+    /// Classifies the request of an HTTP context from its headers
+    pub fn classify(&self, headers: &impl HttpHeaderControl) -> Idempotence {
+        let method = headers.get(":method").unwrap_or_default();
+        let path = headers.get(":path").unwrap_or_default();
+        let key = headers.get(&self.key_header);
+        self.classify_identity(&RequestIdentity {
+            method: std::str::from_utf8(&method).unwrap_or_default(),
+            path: &path,
+            key: key.as_deref(),
+        })
+    }
+
+    /// Classifies a request from a header list, i.e. of a [`crate::HttpCall`]
+    pub fn classify_headers(&self, headers: &[(&str, &[u8])]) -> Idempotence {
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|(x, _)| x.eq_ignore_ascii_case(name))
+                .map(|(_, value)| *value)
+        };
+        self.classify_identity(&RequestIdentity {
+            method: get(":method")
+                .and_then(|x| std::str::from_utf8(x).ok())
+                .unwrap_or_default(),
+            path: get(":path").unwrap_or_default(),
+            key: get(&self.key_header),
+        })
+    }
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Idempotency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Idempotency")
+            .field("key_header", &self.key_header)
+            .field("methods", &self.methods)
+            .field("classifier", &self.classifier.is_some())
+            .finish()
+    }
+}
+
+thread_local! {
+    static IDEMPOTENCY: RefCell<Rc<Idempotency>> = RefCell::new(Rc::new(Idempotency::new()));
+}
+
+/// Sets the classifier checked by the retry and hedging utilities of the SDK
+pub fn set_idempotency(idempotency: Idempotency) {
+    IDEMPOTENCY.set(Rc::new(idempotency));
+}
+
+/// The classifier set with [`set_idempotency`], or the default one
+pub fn idempotency() -> Rc<Idempotency> {
+    IDEMPOTENCY.with_borrow(Rc::clone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotency() {
+        let default = Idempotency::new();
+        let call = |headers: &[(&str, &[u8])]| default.classify_headers(headers);
+        assert_eq!(call(&[(":method", b"GET")]), Idempotence::Safe);
+        assert_eq!(call(&[(":method", b"delete")]), Idempotence::Idempotent);
+        assert_eq!(call(&[(":method", b"POST")]), Idempotence::NonIdempotent);
+        assert_eq!(
+            call(&[(":method", b"POST"), ("Idempotency-Key", b"k1")]),
+            Idempotence::Keyed
+        );
+        assert_eq!(call(&[]), Idempotence::NonIdempotent);
+        assert!(Idempotence::Keyed.may_retry());
+        assert!(!Idempotence::Keyed.may_hedge());
+        assert!(!Idempotence::NonIdempotent.may_retry());
+
+        let custom = Idempotency::new()
+            .key_header("X-Request-Key")
+            .method("put", Idempotence::NonIdempotent)
+            .classify_with(|request| {
+                (request.method == "POST" && request.path.starts_with(b"/search"))
+                    .then_some(Idempotence::Safe)
+            });
+        let call = |headers: &[(&str, &[u8])]| custom.classify_headers(headers);
+        assert_eq!(call(&[(":method", b"PUT")]), Idempotence::NonIdempotent);
+        assert_eq!(
+            call(&[(":method", b"POST"), (":path", b"/search?q=1")]),
+            Idempotence::Safe
+        );
+        assert_eq!(
+            call(&[(":method", b"PATCH"), ("x-request-key", b"k1")]),
+            Idempotence::Keyed
+        );
+    }
+}
@@ -8,38 +8,112 @@ use std::{
 use log::warn;
 
 mod hostcalls;
-pub use hostcalls::call_foreign_function;
+pub use hostcalls::{
+    call_foreign_function, serialize_map_into, serialize_property_path_into,
+    set_serialization_buffer_capacity,
+};
 
 mod status;
 pub use status::*;
 
+mod abi;
+pub use abi::AbiVersion;
+
 mod dispatcher;
-pub use dispatcher::set_root_context_factory;
+pub use dispatcher::{
+    current_context_id, defer, set_root_context_factory, with_context, ContextError,
+};
 
 mod context;
 pub use context::*;
 
+mod root_handle;
+pub use root_handle::RootHandle;
+
+mod executor;
+pub use executor::{spawn_local, with_root, ResponseFuture};
+
+mod configurable_root;
+pub use configurable_root::{ConfigurableRoot, ContextKind};
+
 mod http_call;
 pub use http_call::*;
 
+mod http_retry;
+pub use http_retry::{RetryOn, RetryPolicy};
+
+mod http_request;
+pub use http_request::{HttpRequestBuilder, Url, UrlError};
+
+mod quota;
+pub use quota::{CalloutError, CalloutQuota, QuotaExceeded, QuotaLimit};
+
+mod idempotency;
+pub use idempotency::{idempotency, set_idempotency, Idempotence, Idempotency, RequestIdentity};
+
 mod grpc_call;
 pub use grpc_call::*;
 
 mod grpc_stream;
 pub use grpc_stream::*;
 
+mod grpc_send_queue;
+pub use grpc_send_queue::{DropPolicy, GrpcSendQueue, PushOutcome};
+
+mod managed_grpc_stream;
+pub use managed_grpc_stream::{ManagedGrpcStream, ManagedGrpcStreamBuilder, StreamState};
+
 mod http;
 pub use http::*;
 
+mod header_str;
+pub use header_str::HeaderStr;
+
+mod header_map;
+pub use header_map::HeaderMap;
+
+mod header_limits;
+pub use header_limits::{
+    header_limits, set_header_limits, HeaderLimitAction, HeaderLimits, HeaderOverflow,
+};
+
+mod hop_headers;
+pub use hop_headers::{strip_hop_by_hop, HopByHop, HopPolicy};
+
+mod header_list;
+pub use header_list::{expand_joined_headers, split_header_value, HeaderJoin};
+
+mod phase;
+pub use phase::{BodyProgress, HttpError, HttpPhase, WrongPhase};
+
+mod trailers_only;
+pub use trailers_only::GrpcTrailersOnly;
+
+mod downstream_writer;
+pub use downstream_writer::{DownstreamWriter, WriteError, WriteMechanism};
+
+mod chunked_response;
+pub use chunked_response::{ChunkedResponse, ResponseMode};
+
 mod queue;
 pub use queue::Queue;
 
+mod queue_registry;
+pub use queue_registry::{QueueEntry, QueueRegistry};
+
+mod rendezvous;
+pub use rendezvous::{rendezvous_index, Rebalance, Rendezvous, RendezvousBuilder};
+
 mod shared_data;
-pub use shared_data::SharedData;
+pub use shared_data::{
+    Expiring, ProtoValue, ShardedCounter, SharedCodec, SharedCounter, SharedData, SharedMap,
+    SharedValue,
+};
 
 pub mod property;
 
 mod envoy;
+pub use envoy::GrpcTls;
 
 mod stream;
 pub use stream::*;
@@ -47,31 +121,119 @@ pub use stream::*;
 mod upstream;
 pub use upstream::Upstream;
 
-mod metrics;
-pub use metrics::*;
+pub mod metrics;
+pub use metrics::{
+    ConstCounter, ConstGauge, ConstHistogram, Counter, Gauge, Histogram, MetricsInfo,
+};
+pub mod perf;
+
+pub mod cost;
+
+pub mod memory;
+
+pub mod compression;
+
+pub mod zeroize;
+pub use zeroize::ZeroizingVec;
+
+pub mod body_text;
+
+#[cfg(feature = "body-spill")]
+pub mod body_spill;
+
+#[cfg(all(feature = "e2e", not(target_arch = "wasm32")))]
+pub mod e2e;
 
 mod logger;
-pub use logger::set_log_level;
+pub use logger::{
+    follow_host_log_level, host_log_level, log_format, set_log_format, set_log_level, LogFormat,
+    LogLevelSync,
+};
 
 #[cfg(target_arch = "wasm32")]
 mod rng;
 
+pub mod config;
 pub mod env;
+pub mod runtime;
+pub mod snapshot;
+
+pub mod auth;
+pub mod ext_authz;
+pub mod token;
+pub mod workflow;
+
+pub mod ratelimit;
+pub mod transform;
+
+pub mod base64;
+pub mod json;
+
+pub mod matcher;
+pub mod rules;
+
+pub mod expr;
+
+pub mod cert;
+pub mod http1;
+pub mod sniff;
+pub mod tls;
+
+pub mod access_log;
+pub mod cache_control;
+pub mod header_metrics;
+
+pub mod dns;
+
+#[cfg(feature = "openapi")]
+pub mod openapi;
+
+#[cfg(feature = "otlp")]
+pub mod otlp;
+
+#[cfg(feature = "dynamic-grpc")]
+pub mod dynamic_grpc;
 
 mod time;
 pub use time::*;
 
+mod breaker;
+pub use breaker::{Breaker, BreakerState, RouteBreaker, RouteKey};
+
+mod backoff;
+pub use backoff::{Backoff, Jitter};
+
+mod sampler;
+pub use sampler::AdaptiveSampler;
+
+mod warmup;
+pub use warmup::{Admission, Warmup, WarmupPolicy};
+
+pub mod hash;
+
+mod seen;
+pub use seen::{SeenBody, SeenCache};
+
+mod dedupe;
+pub use dedupe::{Dedup, FindingDeduper};
+
 mod downcast_box;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod native;
 
-#[no_mangle]
-pub extern "C" fn proxy_abi_version_0_2_1() {}
+#[cfg(all(feature = "c-abi", not(target_arch = "wasm32")))]
+pub mod c_abi;
+
+#[cfg(all(feature = "testing", not(target_arch = "wasm32")))]
+pub mod testing;
 
 #[cfg_attr(target_arch = "wasm32", export_name = "malloc")]
 #[no_mangle]
 pub extern "C" fn proxy_on_memory_allocate(size: usize) -> *mut u8 {
+    if let Some(target) = hostcalls::take_allocation_target(size) {
+        return target;
+    }
     let mut vec: Vec<MaybeUninit<u8>> = Vec::with_capacity(size);
     unsafe {
         vec.set_len(size);
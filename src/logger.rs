@@ -1,14 +1,48 @@
+use log::kv::{self, VisitSource};
 use log::{Level, LevelFilter};
 
 use crate::hostcalls::{self, LogLevel};
+use crate::json::write_escaped;
+use crate::{dispatcher, property, Status};
 use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
 use std::panic;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
 struct Logger;
 
 static LOGGER: Logger = Logger;
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static FOLLOW: AtomicU8 = AtomicU8::new(FOLLOW_OFF);
+static FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Plain as u8);
+
+const FOLLOW_OFF: u8 = 0;
+const FOLLOW_CONFIGURE: u8 = 1;
+const FOLLOW_TICK: u8 = 2;
+
+/// When [`follow_host_log_level`] reads the log level of the host again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LogLevelSync {
+    /// On `on_vm_start` and `on_configure`
+    Configure,
+    /// Also on every tick, i.e. to follow runtime changes of the Envoy log level
+    Tick,
+}
+
+/// How log records are written to the host log, see [`set_log_format`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum LogFormat {
+    /// The message, followed by the key-value fields of the record as `key=value`
+    #[default]
+    Plain,
+    /// One JSON object per record, for log aggregation:
+    /// `{"level":"warn","target":"plugin","message":"upstream failed","context_id":2,"request_id":"8f0c…","route":"api","status":503}`.
+    /// `request_id` (the `x-request-id`) and `route` are included when logging from an HTTP context,
+    /// followed by the key-value fields of the record.
+    Json,
+}
 
 impl From<Level> for LogLevel {
     fn from(val: Level) -> Self {
@@ -35,8 +69,7 @@ impl From<LogLevel> for LevelFilter {
     }
 }
 
-/// Sets the log level filter and installs a panic hook to log out panics.
-pub fn set_log_level(level: Level) {
+fn install() {
     if !INITIALIZED.load(Ordering::Relaxed) {
         log::set_logger(&LOGGER).unwrap();
         panic::set_hook(Box::new(|panic_info| {
@@ -44,9 +77,63 @@ pub fn set_log_level(level: Level) {
         }));
         INITIALIZED.store(true, Ordering::Relaxed);
     }
+}
+
+/// Sets the log level filter and installs a panic hook to log out panics.
+/// Stops following the host log level, if [`follow_host_log_level`] was called.
+pub fn set_log_level(level: Level) {
+    install();
+    FOLLOW.store(FOLLOW_OFF, Ordering::Relaxed);
     LOGGER.set_log_level(level.into());
 }
 
+/// Sets how records are written. Key-value fields are passed with the `log` macros, i.e.
+/// `warn!(route = name, status = 503; "upstream failed")`.
+pub fn set_log_format(format: LogFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn log_format() -> LogFormat {
+    match FORMAT.load(Ordering::Relaxed) {
+        1 => LogFormat::Json,
+        _ => LogFormat::Plain,
+    }
+}
+
+/// Log level of the host. Unimplemented on ABI 0.2.0 hosts.
+pub fn host_log_level() -> Result<LevelFilter, Status> {
+    hostcalls::get_log_level().map(Into::into)
+}
+
+/// Sets the log level filter to the log level of the host, and keeps following it as configured by `sync`,
+/// so records the host discards are not formatted and records it keeps are not filtered out.
+/// Installs the logger and panic hook like [`set_log_level`].
+/// Fails on hosts without `proxy_get_log_level`, leaving the log level filter unchanged.
+pub fn follow_host_log_level(sync: LogLevelSync) -> Result<LevelFilter, Status> {
+    install();
+    let level = host_log_level()?;
+    log::set_max_level(level);
+    FOLLOW.store(
+        match sync {
+            LogLevelSync::Configure => FOLLOW_CONFIGURE,
+            LogLevelSync::Tick => FOLLOW_TICK,
+        },
+        Ordering::Relaxed,
+    );
+    Ok(level)
+}
+
+/// Reads the host log level again if following it, from `on_vm_start`/`on_configure` or on tick
+pub(crate) fn sync_host_log_level(tick: bool) {
+    let follow = FOLLOW.load(Ordering::Relaxed);
+    if follow == FOLLOW_OFF || (tick && follow != FOLLOW_TICK) {
+        return;
+    }
+    if let Ok(level) = host_log_level() {
+        log::set_max_level(level);
+    }
+}
+
 impl Logger {
     pub fn set_log_level(&self, level: LogLevel) {
         log::set_max_level(level.into());
@@ -61,9 +148,15 @@ impl log::Log for Logger {
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
             let args = record.args();
-            let message = match args.as_str() {
-                Some(v) => Cow::Borrowed(v),
-                None => Cow::Owned(args.to_string()),
+            let message = match (log_format(), args.as_str()) {
+                (LogFormat::Json, _) => Cow::Owned(format_json(record)),
+                (LogFormat::Plain, _) if record.key_values().count() > 0 => {
+                    let mut out = args.to_string();
+                    let _ = record.key_values().visit(&mut PlainFields(&mut out));
+                    Cow::Owned(out)
+                }
+                (LogFormat::Plain, Some(v)) => Cow::Borrowed(v),
+                (LogFormat::Plain, None) => Cow::Owned(args.to_string()),
             };
             hostcalls::log(record.level().into(), &message).unwrap();
         }
@@ -71,3 +164,211 @@ impl log::Log for Logger {
 
     fn flush(&self) {}
 }
+
+struct PlainFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for PlainFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        write!(self.0, " {key}={value}").map_err(|_| kv::Error::msg("formatting failed"))
+    }
+}
+
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        write_json_field(self.0, key.as_str(), &value)
+            .map_err(|_| kv::Error::msg("formatting failed"))
+    }
+}
+
+fn write_json_field(out: &mut String, key: &str, value: &kv::Value) -> std::fmt::Result {
+    out.push(',');
+    write_escaped(out, key)?;
+    out.push(':');
+    if let Some(x) = value.to_bool() {
+        write!(out, "{x}")
+    } else if let Some(x) = value.to_i64() {
+        write!(out, "{x}")
+    } else if let Some(x) = value.to_u64() {
+        write!(out, "{x}")
+    } else if let Some(x) = value.to_f64().filter(|x| x.is_finite()) {
+        write!(out, "{x}")
+    } else {
+        write_escaped(out, &value.to_string())
+    }
+}
+
+/// Request correlation of the HTTP context last logged from
+struct Correlation {
+    generation: usize,
+    context_id: u32,
+    request_id: Option<String>,
+    route: Option<String>,
+}
+
+thread_local! {
+    static CORRELATION: RefCell<Option<Correlation>> = const { RefCell::new(None) };
+    /// Set while reading correlation properties, so a record logged meanwhile doesn't read them again
+    static CORRELATING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Calls `f` with the request ID and route of the active HTTP context, read once per context
+fn with_correlation(f: impl FnOnce(Option<&str>, Option<&str>)) {
+    let context_id = dispatcher::current_context_id();
+    if CORRELATING.get() || dispatcher::http_phase().is_none() {
+        return f(None, None);
+    }
+    let generation = dispatcher::generation();
+    let cached = CORRELATION.with_borrow(|x| {
+        x.as_ref()
+            .is_some_and(|x| x.generation == generation && x.context_id == context_id)
+    });
+    if !cached {
+        CORRELATING.set(true);
+        let correlation = Correlation {
+            generation,
+            context_id,
+            request_id: property::get_property_string("request.id"),
+            route: property::get_property_string("xds.route_name"),
+        };
+        CORRELATING.set(false);
+        CORRELATION.set(Some(correlation));
+    }
+    CORRELATION.with_borrow(|x| {
+        let x = x.as_ref();
+        f(
+            x.and_then(|x| x.request_id.as_deref()),
+            x.and_then(|x| x.route.as_deref()),
+        )
+    })
+}
+
+fn format_json(record: &log::Record) -> String {
+    let mut out = String::with_capacity(128);
+    let _ = write_json(&mut out, record);
+    out
+}
+
+fn write_json(out: &mut String, record: &log::Record) -> std::fmt::Result {
+    write!(
+        out,
+        "{{\"level\":\"{}\",\"target\":",
+        record.level().as_str().to_ascii_lowercase()
+    )?;
+    write_escaped(out, record.target())?;
+    out.push_str(",\"message\":");
+    match record.args().as_str() {
+        Some(message) => write_escaped(out, message)?,
+        None => write_escaped(out, &record.args().to_string())?,
+    }
+    write!(out, ",\"context_id\":{}", dispatcher::current_context_id())?;
+    let mut result = Ok(());
+    with_correlation(|request_id, route| {
+        for (key, value) in [("request_id", request_id), ("route", route)] {
+            if let Some(value) = value {
+                result = result.and_then(|_| {
+                    write!(out, ",\"{key}\":")?;
+                    write_escaped(out, value)
+                });
+            }
+        }
+    });
+    result?;
+    let _ = record.key_values().visit(&mut JsonFields(out));
+    out.push('}');
+    Ok(())
+}
+
+#[cfg(all(test, feature = "testing", not(feature = "abi-0-2-0")))]
+mod tests {
+    use super::*;
+    use crate::{
+        dispatcher::{proxy_on_context_create, proxy_on_request_headers, proxy_on_tick},
+        testing, BaseContext, Context, FilterHeadersStatus, HttpContext, RequestHeaders,
+        RootContext,
+    };
+
+    thread_local! {
+        static LOGGED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+    }
+
+    #[derive(Default)]
+    struct Root;
+
+    struct Filter;
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn create_context(&mut self) -> Context {
+            Context::Http(Box::new(Filter))
+        }
+    }
+
+    impl BaseContext for Filter {}
+
+    impl HttpContext for Filter {
+        fn on_http_request_headers(&mut self, _headers: &RequestHeaders) -> FilterHeadersStatus {
+            let fields: &[(&str, kv::Value)] =
+                &[("status", 503.into()), ("upstream", "api \"v2\"".into())];
+            let args = format_args!("upstream failed");
+            let record = log::Record::builder()
+                .level(Level::Warn)
+                .target("plugin")
+                .args(args)
+                .key_values(&fields)
+                .build();
+            LOGGED.with_borrow_mut(|x| {
+                x.push(format_json(&record));
+                x.push(format_json(&record));
+            });
+            let mut plain = String::from("upstream failed");
+            record
+                .key_values()
+                .visit(&mut PlainFields(&mut plain))
+                .unwrap();
+            LOGGED.with_borrow_mut(|x| x.push(plain));
+            FilterHeadersStatus::Continue
+        }
+    }
+
+    #[test]
+    fn test_follow_host_log_level() {
+        testing::reset_host();
+        testing::set_log_level(Level::Warn);
+        assert_eq!(
+            follow_host_log_level(LogLevelSync::Tick),
+            Ok(LevelFilter::Warn)
+        );
+        assert_eq!(log::max_level(), LevelFilter::Warn);
+
+        crate::dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        testing::set_log_level(Level::Debug);
+        proxy_on_tick(1);
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+
+        set_log_level(Level::Info);
+        testing::set_log_level(Level::Trace);
+        proxy_on_tick(1);
+        assert_eq!(log::max_level(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_structured_log() {
+        testing::reset_host();
+        testing::set_property(["request", "id"], "8f0c");
+        testing::set_property(["xds", "route_name"], "api");
+        crate::dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        proxy_on_context_create(2, 1);
+        proxy_on_request_headers(2, 0, 0);
+        let logged = LOGGED.with_borrow(|x| x.clone());
+        let expected = r#"{"level":"warn","target":"plugin","message":"upstream failed","context_id":2,"request_id":"8f0c","route":"api","status":503,"upstream":"api \"v2\""}"#;
+        assert_eq!(logged[0], expected);
+        assert_eq!(logged[1], expected);
+        assert_eq!(logged[2], r#"upstream failed status=503 upstream=api "v2""#);
+        assert!(crate::json::Value::parse(&logged[0]).is_ok());
+    }
+}
@@ -0,0 +1,497 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    rc::Rc,
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    dispatcher, downcast_box::DowncastBox, Backoff, Counter, GrpcSendQueue, GrpcStream,
+    GrpcStreamBuilder, GrpcStreamClose, GrpcStreamHandle, GrpcStreamMessage, PushOutcome,
+    RootContext, Upstream,
+};
+
+/// Connection state of a [`ManagedGrpcStream`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamState {
+    /// Not open, i.e. before the first [`ManagedGrpcStream::poll`] or while backing off after a close or failed open.
+    /// Messages are queued.
+    Disconnected,
+    /// Open, sending messages as they are pushed
+    Connected,
+    /// Closed with [`ManagedGrpcStream::close`], and not reopened
+    Stopped,
+}
+
+type OnMessage =
+    Box<dyn FnMut(&mut DowncastBox<dyn RootContext>, GrpcStreamHandle, &GrpcStreamMessage)>;
+type OnState = Box<dyn Fn(&mut DowncastBox<dyn RootContext>, StreamState)>;
+
+struct ManagedState<M> {
+    name: String,
+    cluster: Upstream<'static>,
+    service: String,
+    method: String,
+    metadata: Vec<(String, Vec<u8>)>,
+    backoff: Backoff,
+    queue: RefCell<GrpcSendQueue<M>>,
+    state: Cell<StreamState>,
+    on_message: RefCell<Option<OnMessage>>,
+    on_state: Option<OnState>,
+}
+
+/// Outbound GRPC stream that reopens itself once closed, i.e. for streams to a control plane or a telemetry collector.
+///
+/// [`ManagedGrpcStream::poll`] opens the stream, and reopens it after it closed once the [`Backoff`] allows. Call it
+/// from [`RootContext::on_tick`]: streams are never reopened from their own callbacks. The backoff is reset by the
+/// first message received, so a stream the upstream keeps closing right after opening backs off as well.
+///
+/// Messages pushed while disconnected are buffered in a [`GrpcSendQueue`], bounded by its caps and drop policy, and
+/// flushed in order when the stream reopens. Cheap to clone: clones share their state.
+///
+/// `{name}_stream_opened` counts opens, and `{name}_stream_failed` opens that failed and streams closed other than
+/// through [`ManagedGrpcStream::close`].
+///
+/// ```ignore
+/// let stream = ManagedGrpcStream::<Report>::builder("reports", Upstream::envoy_upstream("control", "control"), "reports.v1.Reports", "Stream")
+///     .queue(GrpcSendQueue::new().max_bytes(4 << 20).metrics("reports"))
+///     .on_message(|root: &mut Root, _, message| root.apply(message))
+///     .on_state(|root: &mut Root, state| root.connected = state == StreamState::Connected)
+///     .build();
+/// stream.send(&report); // queued until connected
+/// // in on_tick
+/// stream.poll();
+/// ```
+pub struct ManagedGrpcStream<M>(Rc<ManagedState<M>>);
+
+impl<M> Clone for ManagedGrpcStream<M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<M: prost::Message + 'static> ManagedGrpcStream<M> {
+    /// Streams to `method` of `service` on `cluster`. `name` identifies the stream in logs and metrics.
+    pub fn builder(
+        name: impl Into<String>,
+        cluster: Upstream<'static>,
+        service: impl Into<String>,
+        method: impl Into<String>,
+    ) -> ManagedGrpcStreamBuilder<M> {
+        ManagedGrpcStreamBuilder(ManagedState {
+            name: name.into(),
+            cluster,
+            service: service.into(),
+            method: method.into(),
+            metadata: vec![],
+            backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(60)),
+            queue: RefCell::new(GrpcSendQueue::new()),
+            state: Cell::new(StreamState::Disconnected),
+            on_message: RefCell::new(None),
+            on_state: None,
+        })
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.0.state.get()
+    }
+
+    /// Handle of the open stream
+    pub fn handle(&self) -> Option<GrpcStreamHandle> {
+        self.0.queue.borrow().handle()
+    }
+
+    /// Encodes and sends `message`, or queues it until the stream is open and writable
+    pub fn send(&self, message: &M) -> PushOutcome {
+        self.0.queue.borrow_mut().push(message)
+    }
+
+    /// Like [`ManagedGrpcStream::send`], for an already encoded message
+    pub fn send_encoded(&self, message: Vec<u8>) -> PushOutcome {
+        self.0.queue.borrow_mut().push_encoded(message)
+    }
+
+    /// Messages waiting for the stream
+    pub fn queued(&self) -> usize {
+        self.0.queue.borrow().len()
+    }
+
+    /// Messages dropped by the queue so far
+    pub fn dropped(&self) -> u64 {
+        self.0.queue.borrow().dropped()
+    }
+
+    /// Opens the stream if it is disconnected and no backoff is pending, otherwise retries queued messages. Call from `on_tick`.
+    pub fn poll(&self) {
+        match self.0.state.get() {
+            StreamState::Disconnected if self.0.backoff.is_ready() => self.open(),
+            StreamState::Connected => {
+                self.0.queue.borrow_mut().flush();
+            }
+            _ => (),
+        }
+    }
+
+    /// Closes the stream for good. Queued messages are kept, but not sent.
+    pub fn close(&self) {
+        if self.0.state.replace(StreamState::Stopped) == StreamState::Stopped {
+            return;
+        }
+        if let Some(handle) = self.0.queue.borrow_mut().detach() {
+            handle.close();
+        }
+        self.notify(StreamState::Stopped);
+    }
+
+    fn open(&self) {
+        let message_stream = self.clone();
+        let close_stream = self.clone();
+        let metadata: Vec<(&str, &[u8])> = self
+            .0
+            .metadata
+            .iter()
+            .map(|(name, value)| (&**name, &**value))
+            .collect();
+        let opened = GrpcStreamBuilder::default()
+            .cluster(self.0.cluster.clone())
+            .service(&*self.0.service)
+            .method(&*self.0.method)
+            .initial_metadata(metadata)
+            .build()
+            .expect("missing stream fields");
+        let opened = GrpcStream {
+            on_message: Some(Box::new(move |root, handle, message| {
+                message_stream.on_message(root, handle, message)
+            })),
+            on_close: Some(Box::new(move |_, close| close_stream.on_close(close))),
+            ..opened
+        }
+        .open();
+        match opened {
+            Ok(handle) => {
+                Counter::define(format!("{}_stream_opened", self.0.name)).increment(1);
+                self.0.state.set(StreamState::Connected);
+                self.0.queue.borrow_mut().attach(handle);
+                self.notify(StreamState::Connected);
+            }
+            Err(e) => {
+                let delay = self.0.backoff.fail();
+                warn!(
+                    "{} stream failed to open: {e:?}, retrying in {delay:?}",
+                    self.0.name
+                );
+                Counter::define(format!("{}_stream_failed", self.0.name)).increment(1);
+            }
+        }
+    }
+
+    fn on_message(
+        &self,
+        root: &mut DowncastBox<dyn RootContext>,
+        handle: GrpcStreamHandle,
+        message: &GrpcStreamMessage,
+    ) {
+        self.0.backoff.succeed();
+        if let Some(on_message) = &mut *self.0.on_message.borrow_mut() {
+            on_message(root, handle, message);
+        }
+    }
+
+    fn on_close(&self, close: &GrpcStreamClose) {
+        if self.handle().is_none_or(|x| x != close.handle_id()) {
+            debug!(
+                "{} stream {} closed after detaching",
+                self.0.name,
+                close.handle_id()
+            );
+            return;
+        }
+        self.0.queue.borrow_mut().detach();
+        self.0.state.set(StreamState::Disconnected);
+        let delay = self.0.backoff.fail();
+        warn!(
+            "{} stream closed: {:?} {}, reopening in {delay:?}",
+            self.0.name,
+            close.status_code(),
+            close.status_message().unwrap_or_default()
+        );
+        Counter::define(format!("{}_stream_failed", self.0.name)).increment(1);
+        self.notify(StreamState::Disconnected);
+    }
+
+    /// Delivers a state change to the root context once the current callback returns
+    fn notify(&self, state: StreamState) {
+        if self.0.on_state.is_none() {
+            return;
+        }
+        let stream = self.clone();
+        dispatcher::defer_boxed(Box::new(move |root| {
+            if let Some(on_state) = &stream.0.on_state {
+                on_state(root, state);
+            }
+        }));
+    }
+}
+
+impl<M> fmt::Debug for ManagedGrpcStream<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ManagedGrpcStream")
+            .field("name", &self.0.name)
+            .field("service", &self.0.service)
+            .field("method", &self.0.method)
+            .field("state", &self.0.state.get())
+            .field("queue", &*self.0.queue.borrow())
+            .finish()
+    }
+}
+
+/// Options of a [`ManagedGrpcStream`]
+pub struct ManagedGrpcStreamBuilder<M>(ManagedState<M>);
+
+impl<M: prost::Message + 'static> ManagedGrpcStreamBuilder<M> {
+    /// Adds initial GRPC metadata, sent on every open
+    pub fn metadata(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.0.metadata.push((name.into(), value.into()));
+        self
+    }
+
+    /// Delays before reopening. Default is 1 second doubling up to a minute.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.0.backoff = backoff;
+        self
+    }
+
+    /// Buffers messages while disconnected. Default holds up to 1024 messages and 1 MiB, dropping the oldest.
+    pub fn queue(mut self, queue: GrpcSendQueue<M>) -> Self {
+        self.0.queue = RefCell::new(queue);
+        self
+    }
+
+    /// Called with messages received on any of the opened streams
+    pub fn on_message<R: RootContext + 'static>(
+        mut self,
+        mut callback: impl FnMut(&mut R, GrpcStreamHandle, &GrpcStreamMessage) + 'static,
+    ) -> Self {
+        self.0.on_message = RefCell::new(Some(Box::new(move |root, handle, message| {
+            callback(
+                root.as_any_mut().downcast_mut().expect("invalid root type"),
+                handle,
+                message,
+            )
+        })));
+        self
+    }
+
+    /// Called on state changes, right after the callback that caused them
+    pub fn on_state<R: RootContext + 'static>(
+        mut self,
+        callback: impl Fn(&mut R, StreamState) + 'static,
+    ) -> Self {
+        self.0.on_state = Some(Box::new(move |root, state| {
+            callback(
+                root.as_any_mut().downcast_mut().expect("invalid root type"),
+                state,
+            )
+        }));
+        self
+    }
+
+    pub fn build(self) -> ManagedGrpcStream<M> {
+        ManagedGrpcStream(Rc::new(self.0))
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        dispatcher::{
+            proxy_on_context_create, proxy_on_grpc_close, proxy_on_grpc_receive, proxy_on_tick,
+        },
+        hostcalls::BufferType,
+        native::{clear_host, return_bytes, set_host, Host},
+        testing::{metric, reset_host},
+        BaseContext, Context, Status,
+    };
+
+    #[derive(Default)]
+    struct Streams {
+        refuse: bool,
+        opened: u32,
+        sent: Vec<(u32, Vec<u8>)>,
+        closed: Vec<u32>,
+    }
+
+    thread_local! {
+        static STREAMS: RefCell<Streams> = RefCell::new(Streams::default());
+        static EVENTS: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+        static STREAM: RefCell<Option<ManagedGrpcStream<String>>> = const { RefCell::new(None) };
+    }
+
+    struct StreamHost;
+
+    impl Host for StreamHost {
+        unsafe fn proxy_grpc_stream(
+            &self,
+            _upstream_data: *const u8,
+            _upstream_size: usize,
+            _service_name_data: *const u8,
+            _service_name_size: usize,
+            _method_name_data: *const u8,
+            _method_name_size: usize,
+            _initial_metadata_data: *const u8,
+            _initial_metadata_size: usize,
+            return_stream_id: *mut u32,
+        ) -> Status {
+            STREAMS.with_borrow_mut(|x| {
+                if x.refuse {
+                    return Status::InternalFailure;
+                }
+                x.opened += 1;
+                *return_stream_id = x.opened;
+                Status::Ok
+            })
+        }
+
+        unsafe fn proxy_grpc_send(
+            &self,
+            token: u32,
+            message_ptr: *const u8,
+            message_len: usize,
+            _end_stream: bool,
+        ) -> Status {
+            let message = std::slice::from_raw_parts(message_ptr, message_len);
+            STREAMS.with_borrow_mut(|x| x.sent.push((token, message.to_vec())));
+            Status::Ok
+        }
+
+        unsafe fn proxy_grpc_close(&self, token_id: u32) -> Status {
+            STREAMS.with_borrow_mut(|x| x.closed.push(token_id));
+            Status::Ok
+        }
+
+        unsafe fn proxy_get_status(
+            &self,
+            return_code: *mut u32,
+            return_message_data: *mut *mut u8,
+            return_message_size: *mut usize,
+        ) -> Status {
+            *return_code = 14;
+            return_bytes(b"upstream reset", return_message_data, return_message_size);
+            Status::Ok
+        }
+    }
+
+    struct Root(ManagedGrpcStream<String>);
+
+    impl Default for Root {
+        fn default() -> Self {
+            let stream = ManagedGrpcStream::builder(
+                "control",
+                Upstream::envoy_upstream("control", "control"),
+                "control.v1.Control",
+                "Stream",
+            )
+            .backoff(Backoff::new(Duration::ZERO, Duration::ZERO))
+            .on_message(|_: &mut Root, handle, message: &GrpcStreamMessage| {
+                let body = message.full_body().unwrap_or_default();
+                EVENTS.with_borrow_mut(|x| {
+                    x.push(format!("{handle}: {}", String::from_utf8_lossy(&body)))
+                });
+            })
+            .on_state(|root: &mut Root, state| {
+                assert_eq!(root.0.state(), state);
+                EVENTS.with_borrow_mut(|x| x.push(format!("{state:?}")));
+            })
+            .build();
+            STREAM.with_borrow_mut(|x| *x = Some(stream.clone()));
+            Self(stream)
+        }
+    }
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn on_tick(&mut self) {
+            self.0.poll();
+        }
+
+        fn create_context(&mut self) -> Context {
+            unreachable!()
+        }
+    }
+
+    fn sent() -> Vec<(u32, String)> {
+        STREAMS.with_borrow(|x| {
+            x.sent
+                .iter()
+                .map(|(token, x)| (*token, <String as prost::Message>::decode(&x[..]).unwrap()))
+                .collect()
+        })
+    }
+
+    fn events() -> Vec<String> {
+        EVENTS.with_borrow_mut(std::mem::take)
+    }
+
+    #[test]
+    fn test_managed_grpc_stream() {
+        reset_host();
+        set_host(StreamHost);
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        let stream = STREAM.with_borrow(|x| x.clone().unwrap());
+
+        assert_eq!(stream.send(&"a".to_string()), PushOutcome::Queued);
+        STREAMS.with_borrow_mut(|x| x.refuse = true);
+        proxy_on_tick(1);
+        assert_eq!(stream.state(), StreamState::Disconnected);
+        assert_eq!(metric("control_stream_failed"), Some(1));
+
+        STREAMS.with_borrow_mut(|x| x.refuse = false);
+        proxy_on_tick(1);
+        assert_eq!(stream.state(), StreamState::Connected);
+        assert_eq!(events(), ["Connected"]);
+        assert_eq!(stream.send(&"b".to_string()), PushOutcome::Sent);
+
+        crate::testing::host::with_host(|host| {
+            host.buffers
+                .insert(BufferType::GrpcReceiveBuffer as u32, b"config".to_vec())
+        });
+        proxy_on_grpc_receive(1, 1, 6);
+        assert_eq!(events(), ["1: config"]);
+
+        proxy_on_grpc_close(1, 1, 14);
+        assert_eq!(stream.state(), StreamState::Disconnected);
+        assert_eq!(events(), ["Disconnected"]);
+        assert_eq!(stream.send(&"c".to_string()), PushOutcome::Queued);
+        proxy_on_tick(1);
+        assert_eq!(stream.handle().map(|x| x.0), Some(2));
+        assert_eq!(stream.queued(), 0);
+        assert_eq!(events(), ["Connected"]);
+
+        // closes of replaced streams are ignored
+        proxy_on_grpc_close(1, 1, 14);
+        assert_eq!(stream.state(), StreamState::Connected);
+
+        stream.close();
+        proxy_on_tick(1);
+        assert_eq!(stream.state(), StreamState::Stopped);
+        assert_eq!(events(), ["Stopped"]);
+        clear_host();
+
+        assert_eq!(
+            sent(),
+            [
+                (1, "a".to_string()),
+                (1, "b".to_string()),
+                (2, "c".to_string())
+            ]
+        );
+        assert_eq!(STREAMS.with_borrow(|x| x.closed.clone()), [2]);
+        assert_eq!(metric("control_stream_opened"), Some(2));
+        assert_eq!(metric("control_stream_failed"), Some(2));
+    }
+}
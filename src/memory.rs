@@ -0,0 +1,224 @@
+//! Memory watermark monitoring with shedding hooks, to keep the VM clear of host-side OOM kills.
+//!
+//! Linear memory never shrinks, so for thresholds to clear again install [`TrackingAllocator`] as the global allocator:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: proxy_sdk::memory::TrackingAllocator<std::alloc::System> =
+//!     proxy_sdk::memory::TrackingAllocator(std::alloc::System);
+//! ```
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use log::warn;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static SHEDDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Global allocator wrapper counting live allocated bytes
+pub struct TrackingAllocator<A>(pub A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        TRACKING.store(true, Ordering::Relaxed);
+        let out = self.0.alloc(layout);
+        if !out.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        out
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        TRACKING.store(true, Ordering::Relaxed);
+        let out = self.0.alloc_zeroed(layout);
+        if !out.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        out
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let out = self.0.realloc(ptr, layout, new_size);
+        if !out.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        }
+        out
+    }
+}
+
+/// A memory usage sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Size of wasm linear memory in bytes. Always 0 on native targets.
+    pub linear_memory: usize,
+    /// Live bytes allocated through [`TrackingAllocator`], if installed
+    pub allocated: Option<usize>,
+}
+
+impl MemoryUsage {
+    /// Allocated bytes if tracked, otherwise the linear memory size
+    pub fn effective(&self) -> usize {
+        self.allocated.unwrap_or(self.linear_memory)
+    }
+}
+
+/// Samples current memory usage
+pub fn usage() -> MemoryUsage {
+    #[cfg(target_arch = "wasm32")]
+    let linear_memory = core::arch::wasm32::memory_size(0) * 65536;
+    #[cfg(not(target_arch = "wasm32"))]
+    let linear_memory = 0;
+    MemoryUsage {
+        linear_memory,
+        allocated: TRACKING
+            .load(Ordering::Relaxed)
+            .then(|| ALLOCATED.load(Ordering::Relaxed)),
+    }
+}
+
+/// Returns `true` while a [`MemoryMonitor`] hard limit is exceeded. Body callbacks are then skipped and bodies pass through.
+pub fn is_shedding() -> bool {
+    SHEDDING.get()
+}
+
+struct Threshold {
+    bytes: usize,
+    callback: Box<dyn FnMut(MemoryUsage)>,
+    triggered: bool,
+}
+
+/// Checks memory usage against thresholds, calling a hook once each time a threshold is crossed.
+/// A threshold re-arms once usage falls below `bytes * release_ratio`. Call [`MemoryMonitor::check`] from [`crate::RootContext::on_tick`].
+pub struct MemoryMonitor {
+    thresholds: Vec<Threshold>,
+    hard_limit: Option<usize>,
+    release_ratio: f64,
+    source: Box<dyn Fn() -> MemoryUsage>,
+}
+
+impl Default for MemoryMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryMonitor {
+    /// Creates a monitor with no thresholds and a release ratio of 0.9
+    pub fn new() -> Self {
+        Self {
+            thresholds: vec![],
+            hard_limit: None,
+            release_ratio: 0.9,
+            source: Box::new(usage),
+        }
+    }
+
+    /// Calls `callback` when usage reaches `bytes`, i.e. to drop caches or disable body buffering
+    pub fn threshold(mut self, bytes: usize, callback: impl FnMut(MemoryUsage) + 'static) -> Self {
+        self.thresholds.push(Threshold {
+            bytes,
+            callback: Box::new(callback),
+            triggered: false,
+        });
+        self
+    }
+
+    /// Above `bytes`, switches body callbacks to pass-through until usage is released. See [`is_shedding`].
+    pub fn hard_limit(mut self, bytes: usize) -> Self {
+        self.hard_limit = Some(bytes);
+        self
+    }
+
+    /// Fraction of a threshold usage must fall below before it triggers again
+    pub fn release_ratio(mut self, ratio: f64) -> Self {
+        self.release_ratio = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Overrides how usage is sampled. Default is [`usage`].
+    pub fn source(mut self, source: impl Fn() -> MemoryUsage + 'static) -> Self {
+        self.source = Box::new(source);
+        self
+    }
+
+    /// Samples usage and runs any hooks for crossed thresholds
+    pub fn check(&mut self) -> MemoryUsage {
+        let usage = (self.source)();
+        let current = usage.effective();
+        for threshold in &mut self.thresholds {
+            if !threshold.triggered && current >= threshold.bytes {
+                threshold.triggered = true;
+                (threshold.callback)(usage);
+            } else if threshold.triggered
+                && (current as f64) < threshold.bytes as f64 * self.release_ratio
+            {
+                threshold.triggered = false;
+            }
+        }
+        if let Some(limit) = self.hard_limit {
+            let shedding = is_shedding();
+            if !shedding && current >= limit {
+                warn!("memory usage {current} exceeds hard limit {limit}, passing bodies through");
+                SHEDDING.set(true);
+            } else if shedding && (current as f64) < limit as f64 * self.release_ratio {
+                warn!("memory usage {current} released below hard limit {limit}");
+                SHEDDING.set(false);
+            }
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn test_thresholds() {
+        let current = Rc::new(Cell::new(0usize));
+        let fired = Rc::new(Cell::new(0u32));
+        let mut monitor = MemoryMonitor::new()
+            .threshold(100, {
+                let fired = fired.clone();
+                move |_| fired.set(fired.get() + 1)
+            })
+            .hard_limit(200)
+            .source({
+                let current = current.clone();
+                move || MemoryUsage {
+                    linear_memory: 0,
+                    allocated: Some(current.get()),
+                }
+            });
+        for (usage, count, shedding) in [
+            (50, 0, false),
+            (100, 1, false),
+            (95, 1, false),
+            (250, 1, true),
+            (185, 1, true),
+            (80, 1, false),
+            (120, 2, false),
+        ] {
+            current.set(usage);
+            monitor.check();
+            assert_eq!(fired.get(), count, "{usage}");
+            assert_eq!(is_shedding(), shedding, "{usage}");
+        }
+    }
+}
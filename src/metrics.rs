@@ -1,7 +1,13 @@
-use std::{cell::RefCell, collections::HashMap};
+//! Envoy metric handles, plus introspection of the metrics defined through this SDK.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+};
 
 use crate::{
-    dispatcher::root_id,
+    dispatcher::{generation, root_id},
     hostcalls::{self, MetricType},
     log_concern, Status,
 };
@@ -13,13 +19,89 @@ pub struct MetricsInfo {
     histograms: HashMap<String, u32>,
 }
 
+impl MetricsInfo {
+    fn ids(&mut self, kind: MetricKind) -> &mut HashMap<String, u32> {
+        match kind {
+            MetricKind::Counter => &mut self.counters,
+            MetricKind::Gauge => &mut self.gauges,
+            MetricKind::Histogram => &mut self.histograms,
+        }
+    }
+}
+
+/// Metric ids defined with the host, valid for one dispatcher generation. After [`crate::reset`], ids of the previous
+/// generation may point to metrics the host no longer knows about, so they are defined again on first use.
+#[derive(Default)]
+struct MetricsCache {
+    generation: usize,
+    roots: HashMap<u32, MetricsInfo>,
+    /// Names of all ids handed out, by generation, so that handles from older generations can be defined again
+    names: HashMap<(usize, MetricKind, u32), String>,
+}
+
+impl MetricsCache {
+    fn current(&mut self) -> &mut Self {
+        let generation = generation();
+        if self.generation != generation {
+            self.generation = generation;
+            self.roots.clear();
+        }
+        self
+    }
+
+    fn define(&mut self, kind: MetricKind, name: &str) -> u32 {
+        let ids = self.current().roots.entry(root_id()).or_default().ids(kind);
+        if let Some(id) = ids.get(name) {
+            return *id;
+        }
+        let id = log_concern("define-metric", hostcalls::define_metric(kind.into(), name));
+        ids.insert(name.to_string(), id);
+        self.names
+            .insert((self.generation, kind, id), name.to_string());
+        id
+    }
+}
+
 thread_local! {
-    static METRICS: RefCell<HashMap<u32, MetricsInfo>> = RefCell::default();
+    static METRICS: RefCell<MetricsCache> = RefCell::default();
+}
+
+/// A metric id and the dispatcher generation it was defined in
+#[derive(Clone, Copy, Debug)]
+struct MetricId {
+    id: u32,
+    generation: usize,
+}
+
+impl MetricId {
+    fn define(kind: MetricKind, name: &str) -> Self {
+        METRICS.with_borrow_mut(|metrics| Self {
+            id: metrics.define(kind, name),
+            generation: metrics.generation,
+        })
+    }
+
+    /// The id to use with the host, defining the metric again if it is from an older generation
+    fn resolve(&self, kind: MetricKind) -> u32 {
+        if self.generation == generation() {
+            return self.id;
+        }
+        METRICS.with_borrow_mut(|metrics| {
+            match metrics
+                .names
+                .get(&(self.generation, kind, self.id))
+                .cloned()
+            {
+                Some(name) => metrics.define(kind, &name),
+                None => self.id,
+            }
+        })
+    }
 }
 
 /// Envoy counter metric handle
 #[derive(Clone, Copy, Debug)]
-pub struct Counter(u32);
+pub struct Counter(MetricId);
 
 /// Const wrapper for [`Counter`]
 pub struct ConstCounter {
@@ -40,42 +122,35 @@ impl ConstCounter {
 impl Counter {
     /// Defines a new counter, reusing an old handle if it already exists. It is safe to call this multiple times with the same name.
     pub fn define(name: impl AsRef<str>) -> Self {
-        METRICS.with_borrow_mut(|metrics| {
-            let metrics = metrics.entry(root_id()).or_default();
-            if let Some(counter) = metrics.counters.get(name.as_ref()) {
-                return Self(*counter);
-            }
-            let out = log_concern(
-                "define-metric",
-                hostcalls::define_metric(MetricType::Counter, name.as_ref()),
-            );
-            metrics.counters.insert(name.as_ref().to_string(), out);
-            Self(out)
-        })
+        Self(MetricId::define(MetricKind::Counter, name.as_ref()))
+    }
+
+    fn id(&self) -> u32 {
+        self.0.resolve(MetricKind::Counter)
     }
 
     /// Retrieves the current metric value
     pub fn get(&self) -> Result<u64, Status> {
-        hostcalls::get_metric(self.0)
+        hostcalls::get_metric(self.id())
     }
 
     /// Records an absolute count of this metric
     pub fn record(&self, value: u64) {
-        log_concern("record-metric", hostcalls::record_metric(self.0, value));
+        log_concern("record-metric", hostcalls::record_metric(self.id(), value));
     }
 
     /// Increments the count of this metric by `offset`
     pub fn increment(&self, offset: i64) {
         log_concern(
             "increment-metric",
-            hostcalls::increment_metric(self.0, offset),
+            hostcalls::increment_metric(self.id(), offset),
         );
     }
 }
 
 /// Envoy gauge metric handle
 #[derive(Clone, Copy, Debug)]
-pub struct Gauge(u32);
+pub struct Gauge(MetricId);
 
 /// Const wrapper for [`Gauge`]
 pub struct ConstGauge {
@@ -96,42 +171,35 @@ impl ConstGauge {
 impl Gauge {
     /// Defines a new gauge, reusing an old handle if it already exists. It is safe to call this multiple times with the same name.
     pub fn define(name: impl AsRef<str>) -> Self {
-        METRICS.with_borrow_mut(|metrics| {
-            let metrics = metrics.entry(root_id()).or_default();
-            if let Some(gauge) = metrics.gauges.get(name.as_ref()) {
-                return Self(*gauge);
-            }
-            let out = log_concern(
-                "define-metric",
-                hostcalls::define_metric(MetricType::Gauge, name.as_ref()),
-            );
-            metrics.gauges.insert(name.as_ref().to_string(), out);
-            Self(out)
-        })
+        Self(MetricId::define(MetricKind::Gauge, name.as_ref()))
+    }
+
+    fn id(&self) -> u32 {
+        self.0.resolve(MetricKind::Gauge)
     }
 
     /// Retrieves the current metric value
     pub fn get(&self) -> Result<u64, Status> {
-        hostcalls::get_metric(self.0)
+        hostcalls::get_metric(self.id())
     }
 
     /// Records an absolute count of this metric
     pub fn record(&self, value: u64) {
-        log_concern("record-metric", hostcalls::record_metric(self.0, value));
+        log_concern("record-metric", hostcalls::record_metric(self.id(), value));
     }
 
     /// Increments the count of this metric by `offset`
     pub fn increment(&self, offset: i64) {
         log_concern(
             "increment-metric",
-            hostcalls::increment_metric(self.0, offset),
+            hostcalls::increment_metric(self.id(), offset),
         );
     }
 }
 
 /// Envoy histogram metric handle
 #[derive(Clone, Copy, Debug)]
-pub struct Histogram(u32);
+pub struct Histogram(MetricId);
 
 /// Const wrapper for [`Histogram`]
 pub struct ConstHistogram {
@@ -152,22 +220,190 @@ impl ConstHistogram {
 impl Histogram {
     /// Defines a new histogram, reusing an old handle if it already exists. It is safe to call this multiple times with the same name.
     pub fn define(name: impl AsRef<str>) -> Self {
-        METRICS.with_borrow_mut(|metrics| {
-            let metrics = metrics.entry(root_id()).or_default();
-            if let Some(histogram) = metrics.histograms.get(name.as_ref()) {
-                return Self(*histogram);
-            }
-            let out = log_concern(
-                "define-metric",
-                hostcalls::define_metric(MetricType::Histogram, name.as_ref()),
-            );
-            metrics.histograms.insert(name.as_ref().to_string(), out);
-            Self(out)
-        })
+        Self(MetricId::define(MetricKind::Histogram, name.as_ref()))
+    }
+
+    fn id(&self) -> u32 {
+        self.0.resolve(MetricKind::Histogram)
     }
 
     /// Records a new item for this histogram
     pub fn record(&self, value: u64) {
-        log_concern("record-metric", hostcalls::record_metric(self.0, value));
+        log_concern("record-metric", hostcalls::record_metric(self.id(), value));
+    }
+}
+
+/// Kind of a metric defined through this SDK
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl From<MetricKind> for MetricType {
+    fn from(value: MetricKind) -> Self {
+        match value {
+            MetricKind::Counter => MetricType::Counter,
+            MetricKind::Gauge => MetricType::Gauge,
+            MetricKind::Histogram => MetricType::Histogram,
+        }
+    }
+}
+
+/// A metric defined through this SDK and its current value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricValue {
+    pub name: String,
+    pub kind: MetricKind,
+    /// Current value from the host. Always `None` for histograms, which cannot be read back.
+    pub value: Option<u64>,
+}
+
+/// Returns all metrics defined by the current root context with their current values, sorted by name.
+pub fn snapshot() -> Vec<MetricValue> {
+    let defined = METRICS.with_borrow_mut(|metrics| {
+        let Some(metrics) = metrics.current().roots.get(&root_id()) else {
+            return vec![];
+        };
+        let kinds = [
+            (MetricKind::Counter, &metrics.counters),
+            (MetricKind::Gauge, &metrics.gauges),
+            (MetricKind::Histogram, &metrics.histograms),
+        ];
+        kinds
+            .into_iter()
+            .flat_map(|(kind, ids)| ids.iter().map(move |(name, id)| (name.clone(), kind, *id)))
+            .collect::<Vec<_>>()
+    });
+    let mut out = defined
+        .into_iter()
+        .map(|(name, kind, id)| MetricValue {
+            name,
+            kind,
+            value: match kind {
+                MetricKind::Histogram => None,
+                _ => hostcalls::get_metric(id).ok(),
+            },
+        })
+        .collect::<Vec<_>>();
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+    out
+}
+
+/// Content type of [`prometheus_text`]
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Renders [`snapshot`] in the Prometheus text exposition format, for hosts where Envoy stats can't be scraped,
+/// i.e. to answer a local response on a debug path or to ship with an [`crate::HttpCall`].
+/// See [`write_prometheus`].
+pub fn prometheus_text(prefix: &str) -> String {
+    let mut out = String::new();
+    write_prometheus(&snapshot(), prefix, &mut out).expect("writing to a String cannot fail");
+    out
+}
+
+/// Writes `metrics` in the Prometheus text exposition format, prepending `prefix` to every name.
+/// Characters not allowed in Prometheus names, i.e. the dots of Envoy stat names, are replaced by `_`.
+/// Histograms and metrics without a value are skipped, as are names that collide once sanitized.
+pub fn write_prometheus(
+    metrics: &[MetricValue],
+    prefix: &str,
+    out: &mut impl fmt::Write,
+) -> fmt::Result {
+    let mut written = HashSet::new();
+    for metric in metrics {
+        let kind = match metric.kind {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => continue,
+        };
+        let Some(value) = metric.value else {
+            continue;
+        };
+        let name = prometheus_name(prefix, &metric.name);
+        if !written.insert(name.clone()) {
+            continue;
+        }
+        writeln!(out, "# TYPE {name} {kind}")?;
+        writeln!(out, "{name} {value}")?;
+    }
+    Ok(())
+}
+
+fn prometheus_name(prefix: &str, name: &str) -> String {
+    let mut out: String = prefix
+        .chars()
+        .chain(name.chars())
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | ':' => c,
+            _ => '_',
+        })
+        .collect();
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+/// Forgets the metric handles cached for the current root context, so that later `define` calls define them again with the host.
+/// Useful on configuration reload when the set of metrics changes.
+pub fn reset_cache() {
+    METRICS.with_borrow_mut(|metrics| metrics.current().roots.remove(&root_id()));
+}
+
+/// Forgets the metric handles cached for all root contexts of this thread
+#[cfg(all(feature = "testing", not(target_arch = "wasm32")))]
+pub(crate) fn reset_all_caches() {
+    METRICS.with_borrow_mut(|metrics| *metrics = MetricsCache::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus() {
+        let metric = |name: &str, kind, value| MetricValue {
+            name: name.to_string(),
+            kind,
+            value,
+        };
+        let mut out = String::new();
+        write_prometheus(
+            &[
+                metric("http.requests", MetricKind::Counter, Some(12)),
+                metric("http-requests", MetricKind::Counter, Some(1)),
+                metric("latency", MetricKind::Histogram, None),
+                metric("2xx", MetricKind::Gauge, Some(3)),
+            ],
+            "",
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            out,
+            "# TYPE http_requests counter\nhttp_requests 12\n# TYPE _2xx gauge\n_2xx 3\n"
+        );
+        assert_eq!(prometheus_name("wasm.", "a/b"), "wasm_a_b");
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_redefine_after_reset() {
+        use crate::testing::{metric, reset_host};
+
+        reset_host();
+        reset_all_caches();
+        let stale = Counter::define("stale_counter");
+        stale.increment(1);
+
+        reset_host();
+        crate::reset();
+        let fresh = Counter::define("fresh_counter");
+        fresh.increment(5);
+        stale.increment(2);
+        assert_eq!(metric("fresh_counter"), Some(5));
+        assert_eq!(metric("stale_counter"), Some(2));
     }
 }
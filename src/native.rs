@@ -1,5 +1,8 @@
 use std::ffi::c_void;
 
+pub use crate::dispatcher::dispatch_pending;
+pub use crate::hostcalls::{clear_host, return_bytes, set_host, Host, LinkedHost};
+
 extern "C" {
     fn proxy_dyn_get_thread_context() -> *const c_void;
     fn proxy_dyn_set_limited_thread_context(thread_context: *const c_void);
@@ -0,0 +1,928 @@
+//! Request validation against an OpenAPI 3 spec fragment.
+//! Only JSON specs are supported. Supported schema keywords are `type`, `nullable`, `enum`, `minimum`, `maximum`,
+//! `exclusiveMinimum`, `exclusiveMaximum`, `minLength`, `maxLength`, `minItems`, `maxItems`, `items`, `properties`,
+//! `required`, `additionalProperties`, `allOf`, `anyOf`, `oneOf` and local `$ref`s. Other keywords are ignored.
+
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use log::warn;
+
+use crate::{
+    header_str::percent_decode, json::Value, FilterDataStatus, FilterHeadersStatus, HeaderStr,
+    HttpBodyControl, HttpControl, HttpHeaderControl, RequestBody, RequestHeaders,
+};
+
+/// Maximum depth of `$ref` resolution before a schema is considered cyclic
+const MAX_REF_DEPTH: usize = 64;
+
+const METHODS: &[&str] = &[
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+/// Error compiling an OpenAPI spec
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenApiError(pub String);
+
+impl fmt::Display for OpenApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid openapi spec: {}", self.0)
+    }
+}
+
+impl std::error::Error for OpenApiError {}
+
+/// A single validation failure
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Where the failure occurred, i.e. `query.limit` or `body/items/0/name`
+    pub location: String,
+    pub message: String,
+}
+
+impl Violation {
+    fn new(location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JsonType {
+    Null,
+    Boolean,
+    Integer,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl JsonType {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "null" => JsonType::Null,
+            "boolean" => JsonType::Boolean,
+            "integer" => JsonType::Integer,
+            "number" => JsonType::Number,
+            "string" => JsonType::String,
+            "array" => JsonType::Array,
+            "object" => JsonType::Object,
+            _ => return None,
+        })
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match (self, value) {
+            (JsonType::Null, Value::Null) => true,
+            (JsonType::Boolean, Value::Bool(_)) => true,
+            (JsonType::Integer, Value::Number(x)) => x.fract() == 0.0,
+            (JsonType::Number, Value::Number(_)) => true,
+            (JsonType::String, Value::String(_)) => true,
+            (JsonType::Array, Value::Array(_)) => true,
+            (JsonType::Object, Value::Object(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A compiled JSON schema
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    reference: Option<String>,
+    types: Vec<JsonType>,
+    nullable: bool,
+    enum_values: Option<Vec<Value>>,
+    minimum: Option<(f64, bool)>,
+    maximum: Option<(f64, bool)>,
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+    min_items: Option<usize>,
+    max_items: Option<usize>,
+    items: Option<Box<Schema>>,
+    properties: Vec<(String, Schema)>,
+    required: Vec<String>,
+    additional_properties: Option<Box<Schema>>,
+    deny_additional_properties: bool,
+    all_of: Vec<Schema>,
+    any_of: Vec<Schema>,
+    one_of: Vec<Schema>,
+}
+
+impl Schema {
+    /// Compiles a schema from its JSON representation
+    pub fn compile(value: &Value) -> Result<Self, OpenApiError> {
+        let Value::Object(_) = value else {
+            return Err(OpenApiError(format!(
+                "schema must be an object, found {}",
+                value.type_name()
+            )));
+        };
+        let mut schema = Schema::default();
+        if let Some(reference) = value.get("$ref") {
+            let reference = reference
+                .as_str()
+                .and_then(|x| x.strip_prefix("#/components/schemas/"))
+                .ok_or_else(|| OpenApiError(format!("unsupported $ref {reference}")))?;
+            schema.reference = Some(reference.to_string());
+            return Ok(schema);
+        }
+        match value.get("type") {
+            Some(Value::String(name)) => schema.types.push(
+                JsonType::parse(name)
+                    .ok_or_else(|| OpenApiError(format!("unknown type '{name}'")))?,
+            ),
+            Some(Value::Array(names)) => {
+                for name in names {
+                    schema.types.push(
+                        name.as_str()
+                            .and_then(JsonType::parse)
+                            .ok_or_else(|| OpenApiError(format!("unknown type {name}")))?,
+                    );
+                }
+            }
+            _ => (),
+        }
+        schema.nullable = value.get("nullable").and_then(Value::as_bool) == Some(true);
+        schema.enum_values = value
+            .get("enum")
+            .and_then(Value::as_array)
+            .map(<[_]>::to_vec);
+        let exclusive = |name: &str| value.get(name).and_then(Value::as_bool) == Some(true);
+        schema.minimum = value
+            .get("minimum")
+            .and_then(Value::as_f64)
+            .map(|x| (x, exclusive("exclusiveMinimum")));
+        schema.maximum = value
+            .get("maximum")
+            .and_then(Value::as_f64)
+            .map(|x| (x, exclusive("exclusiveMaximum")));
+        let size = |name: &str| {
+            value
+                .get(name)
+                .and_then(Value::as_i64)
+                .map(|x| x.max(0) as usize)
+        };
+        schema.min_length = size("minLength");
+        schema.max_length = size("maxLength");
+        schema.min_items = size("minItems");
+        schema.max_items = size("maxItems");
+        if let Some(items) = value.get("items") {
+            schema.items = Some(Box::new(Schema::compile(items)?));
+        }
+        if let Some(properties) = value.get("properties").and_then(Value::as_object) {
+            for (name, property) in properties {
+                schema
+                    .properties
+                    .push((name.clone(), Schema::compile(property)?));
+            }
+        }
+        if let Some(required) = value.get("required").and_then(Value::as_array) {
+            schema.required = required
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect();
+        }
+        match value.get("additionalProperties") {
+            Some(Value::Bool(false)) => schema.deny_additional_properties = true,
+            Some(additional @ Value::Object(_)) => {
+                schema.additional_properties = Some(Box::new(Schema::compile(additional)?))
+            }
+            _ => (),
+        }
+        let list = |name: &str| -> Result<Vec<Schema>, OpenApiError> {
+            value
+                .get(name)
+                .and_then(Value::as_array)
+                .unwrap_or_default()
+                .iter()
+                .map(Schema::compile)
+                .collect()
+        };
+        schema.all_of = list("allOf")?;
+        schema.any_of = list("anyOf")?;
+        schema.one_of = list("oneOf")?;
+        Ok(schema)
+    }
+
+    /// Resolves `$ref`s, returning the concrete schema
+    fn resolve<'a>(
+        &'a self,
+        components: &'a HashMap<String, Schema>,
+    ) -> Result<&'a Schema, String> {
+        let mut current = self;
+        for _ in 0..MAX_REF_DEPTH {
+            match &current.reference {
+                None => return Ok(current),
+                Some(name) => {
+                    current = components
+                        .get(name)
+                        .ok_or_else(|| format!("unknown schema '{name}'"))?
+                }
+            }
+        }
+        Err("schema reference cycle".to_string())
+    }
+
+    fn validate(
+        &self,
+        value: &Value,
+        components: &HashMap<String, Schema>,
+        location: &str,
+        depth: usize,
+        out: &mut Vec<Violation>,
+    ) {
+        if depth > MAX_REF_DEPTH {
+            out.push(Violation::new(location, "value nested too deeply"));
+            return;
+        }
+        let schema = match self.resolve(components) {
+            Ok(x) => x,
+            Err(e) => {
+                out.push(Violation::new(location, e));
+                return;
+            }
+        };
+        if value.is_null() && schema.nullable {
+            return;
+        }
+        if !schema.types.is_empty() && !schema.types.iter().any(|x| x.matches(value)) {
+            let expected = schema
+                .types
+                .iter()
+                .map(|x| format!("{x:?}").to_lowercase())
+                .collect::<Vec<_>>()
+                .join(" or ");
+            out.push(Violation::new(
+                location,
+                format!("expected {expected}, found {}", value.type_name()),
+            ));
+            return;
+        }
+        if let Some(values) = &schema.enum_values {
+            if !values.contains(value) {
+                out.push(Violation::new(
+                    location,
+                    "value is not one of the allowed values",
+                ));
+            }
+        }
+        match value {
+            Value::Number(x) => {
+                if let Some((min, exclusive)) = schema.minimum {
+                    if *x < min || (exclusive && *x == min) {
+                        out.push(Violation::new(location, format!("must be at least {min}")));
+                    }
+                }
+                if let Some((max, exclusive)) = schema.maximum {
+                    if *x > max || (exclusive && *x == max) {
+                        out.push(Violation::new(location, format!("must be at most {max}")));
+                    }
+                }
+            }
+            Value::String(x) => {
+                let length = x.chars().count();
+                if schema.min_length.is_some_and(|min| length < min) {
+                    out.push(Violation::new(location, "string is too short"));
+                }
+                if schema.max_length.is_some_and(|max| length > max) {
+                    out.push(Violation::new(location, "string is too long"));
+                }
+            }
+            Value::Array(items) => {
+                if schema.min_items.is_some_and(|min| items.len() < min) {
+                    out.push(Violation::new(location, "array has too few items"));
+                }
+                if schema.max_items.is_some_and(|max| items.len() > max) {
+                    out.push(Violation::new(location, "array has too many items"));
+                }
+                if let Some(item_schema) = &schema.items {
+                    for (i, item) in items.iter().enumerate() {
+                        item_schema.validate(
+                            item,
+                            components,
+                            &format!("{location}/{i}"),
+                            depth + 1,
+                            out,
+                        );
+                    }
+                }
+            }
+            Value::Object(members) => {
+                for name in &schema.required {
+                    if value.get(name).is_none() {
+                        out.push(Violation::new(
+                            format!("{location}/{name}"),
+                            "required property is missing",
+                        ));
+                    }
+                }
+                for (name, member) in members {
+                    let member_location = format!("{location}/{name}");
+                    match schema.properties.iter().find(|(x, _)| x == name) {
+                        Some((_, property)) => {
+                            property.validate(member, components, &member_location, depth + 1, out)
+                        }
+                        None if schema.deny_additional_properties => out.push(Violation::new(
+                            member_location,
+                            "additional property is not allowed",
+                        )),
+                        None => {
+                            if let Some(additional) = &schema.additional_properties {
+                                additional.validate(
+                                    member,
+                                    components,
+                                    &member_location,
+                                    depth + 1,
+                                    out,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+            Value::Null | Value::Bool(_) => (),
+        }
+        for sub in &schema.all_of {
+            sub.validate(value, components, location, depth + 1, out);
+        }
+        let matching = |subs: &[Schema]| {
+            subs.iter()
+                .filter(|sub| {
+                    let mut scratch = vec![];
+                    sub.validate(value, components, location, depth + 1, &mut scratch);
+                    scratch.is_empty()
+                })
+                .count()
+        };
+        if !schema.any_of.is_empty() && matching(&schema.any_of) == 0 {
+            out.push(Violation::new(
+                location,
+                "value does not match any allowed schema",
+            ));
+        }
+        if !schema.one_of.is_empty() && matching(&schema.one_of) != 1 {
+            out.push(Violation::new(
+                location,
+                "value does not match exactly one allowed schema",
+            ));
+        }
+    }
+
+    /// Converts a raw parameter string into a typed value according to this schema
+    fn coerce(&self, components: &HashMap<String, Schema>, raw: &[String]) -> Value {
+        let Ok(schema) = self.resolve(components) else {
+            return Value::Null;
+        };
+        if schema.types.contains(&JsonType::Array) {
+            let items = schema.items.as_deref();
+            return Value::Array(
+                raw.iter()
+                    .flat_map(|x| x.split(','))
+                    .map(|x| match items {
+                        Some(items) => items.coerce(components, &[x.to_string()]),
+                        None => Value::String(x.to_string()),
+                    })
+                    .collect(),
+            );
+        }
+        let raw = raw.first().map(String::as_str).unwrap_or_default();
+        for ty in &schema.types {
+            match ty {
+                JsonType::Integer | JsonType::Number => {
+                    if let Ok(x) = raw.parse::<f64>() {
+                        return Value::Number(x);
+                    }
+                }
+                JsonType::Boolean => match raw {
+                    "true" => return Value::Bool(true),
+                    "false" => return Value::Bool(false),
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        Value::String(raw.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParameterLocation {
+    Path,
+    Query,
+    Header,
+}
+
+#[derive(Clone, Debug)]
+struct Parameter {
+    name: String,
+    location: ParameterLocation,
+    required: bool,
+    schema: Option<Schema>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Parameter(String),
+}
+
+#[derive(Clone, Debug)]
+struct Operation {
+    method: String,
+    segments: Vec<Segment>,
+    parameters: Vec<Parameter>,
+    body_required: bool,
+    body_schema: Option<Schema>,
+}
+
+impl Operation {
+    /// Returns the number of literal segments matched and the captured path parameters
+    fn match_path<'a>(&self, segments: &[&'a str]) -> Option<(usize, Vec<(&str, &'a str)>)> {
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+        let mut literals = 0;
+        let mut captures = vec![];
+        for (expected, actual) in self.segments.iter().zip(segments) {
+            match expected {
+                Segment::Literal(x) if x == actual => literals += 1,
+                Segment::Literal(_) => return None,
+                Segment::Parameter(name) => captures.push((name.as_str(), *actual)),
+            }
+        }
+        Some((literals, captures))
+    }
+}
+
+/// Validates requests against operations compiled from an OpenAPI spec.
+/// Compile once in [`crate::RootContext::on_configure`] and share with each [`RequestValidation`] through an [`Rc`].
+#[derive(Clone, Debug)]
+pub struct OpenApiValidator {
+    operations: Vec<Operation>,
+    components: HashMap<String, Schema>,
+    max_body_bytes: usize,
+    reject_unknown_operations: bool,
+}
+
+impl OpenApiValidator {
+    /// Compiles the `paths` and `components.schemas` of a JSON OpenAPI document.
+    pub fn compile(spec: impl AsRef<[u8]>) -> Result<Self, OpenApiError> {
+        let spec = Value::parse(spec).map_err(|e| OpenApiError(e.to_string()))?;
+        let mut components = HashMap::new();
+        if let Some(schemas) = spec
+            .pointer("/components/schemas")
+            .and_then(Value::as_object)
+        {
+            for (name, schema) in schemas {
+                components.insert(name.clone(), Schema::compile(schema)?);
+            }
+        }
+        let mut operations = vec![];
+        for (path, item) in spec
+            .get("paths")
+            .and_then(Value::as_object)
+            .unwrap_or_default()
+        {
+            let segments = split_path(path)
+                .into_iter()
+                .map(
+                    |x| match x.strip_prefix('{').and_then(|x| x.strip_suffix('}')) {
+                        Some(name) => Segment::Parameter(name.to_string()),
+                        None => Segment::Literal(x.to_string()),
+                    },
+                )
+                .collect::<Vec<_>>();
+            let shared = compile_parameters(&spec, item.get("parameters"))?;
+            for method in METHODS {
+                let Some(operation) = item.get(method) else {
+                    continue;
+                };
+                let mut parameters = shared.clone();
+                for parameter in compile_parameters(&spec, operation.get("parameters"))? {
+                    parameters.retain(|x: &Parameter| {
+                        x.name != parameter.name || x.location != parameter.location
+                    });
+                    parameters.push(parameter);
+                }
+                let (body_required, body_schema) = match operation.get("requestBody") {
+                    Some(body) => {
+                        let body = resolve_component(&spec, body, "requestBodies")?;
+                        let schema = body
+                            .get("content")
+                            .and_then(Value::as_object)
+                            .unwrap_or_default()
+                            .iter()
+                            .find(|(content_type, _)| is_json_content_type(content_type))
+                            .and_then(|(_, media)| media.get("schema"))
+                            .map(Schema::compile)
+                            .transpose()?;
+                        (
+                            body.get("required").and_then(Value::as_bool) == Some(true),
+                            schema,
+                        )
+                    }
+                    None => (false, None),
+                };
+                operations.push(Operation {
+                    method: method.to_ascii_uppercase(),
+                    segments: segments.clone(),
+                    parameters,
+                    body_required,
+                    body_schema,
+                });
+            }
+        }
+        Ok(Self {
+            operations,
+            components,
+            max_body_bytes: 1024 * 1024,
+            reject_unknown_operations: false,
+        })
+    }
+
+    /// Largest request body buffered for validation. Larger bodies are rejected with a 413. Default is 1 MiB.
+    pub fn max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+
+    /// If `true`, requests not matching any operation in the spec are rejected. Default is `false`.
+    pub fn reject_unknown_operations(mut self, reject: bool) -> Self {
+        self.reject_unknown_operations = reject;
+        self
+    }
+
+    /// Validates the method, path, query and headers of a request.
+    /// `header` looks up a header by lowercase name. Returns the index of the matched operation, if any.
+    pub fn validate_head(
+        &self,
+        method: &str,
+        path: &str,
+        header: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<usize>, Vec<Violation>> {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let segments = split_path(path);
+        let mut best: Option<(usize, usize)> = None;
+        for (index, operation) in self.operations.iter().enumerate() {
+            if !operation.method.eq_ignore_ascii_case(method) {
+                continue;
+            }
+            if let Some((literals, _)) = operation.match_path(&segments) {
+                if best.is_none_or(|(_, x)| literals > x) {
+                    best = Some((index, literals));
+                }
+            }
+        }
+        let Some((index, _)) = best else {
+            if self.reject_unknown_operations {
+                return Err(vec![Violation::new(
+                    "path",
+                    format!("no operation for {method} {path}"),
+                )]);
+            }
+            return Ok(None);
+        };
+        let captures = self.operations[index]
+            .match_path(&segments)
+            .map(|(_, x)| x)
+            .unwrap_or_default();
+        let query = parse_query(query);
+        let mut violations = vec![];
+        for parameter in &self.operations[index].parameters {
+            let raw: Vec<String> = match parameter.location {
+                ParameterLocation::Path => captures
+                    .iter()
+                    .filter(|(name, _)| *name == parameter.name)
+                    .map(|(_, value)| HeaderStr::from(*value).percent_decode().to_utf8_lossy())
+                    .collect(),
+                ParameterLocation::Query => query
+                    .iter()
+                    .filter(|(name, _)| *name == parameter.name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                ParameterLocation::Header => header(&parameter.name.to_ascii_lowercase())
+                    .into_iter()
+                    .collect(),
+            };
+            let location = match parameter.location {
+                ParameterLocation::Path => format!("path.{}", parameter.name),
+                ParameterLocation::Query => format!("query.{}", parameter.name),
+                ParameterLocation::Header => format!("header.{}", parameter.name),
+            };
+            if raw.is_empty() {
+                if parameter.required {
+                    violations.push(Violation::new(location, "required parameter is missing"));
+                }
+                continue;
+            }
+            if let Some(schema) = &parameter.schema {
+                let value = schema.coerce(&self.components, &raw);
+                schema.validate(&value, &self.components, &location, 0, &mut violations);
+            }
+        }
+        if violations.is_empty() {
+            Ok(Some(index))
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Validates a complete JSON request body against the body schema of operation `index`
+    pub fn validate_body(&self, index: usize, body: &[u8]) -> Result<(), Vec<Violation>> {
+        let Some(schema) = self
+            .operations
+            .get(index)
+            .and_then(|x| x.body_schema.as_ref())
+        else {
+            return Ok(());
+        };
+        let value = Value::parse(body)
+            .map_err(|e| vec![Violation::new("body", format!("invalid json: {e}"))])?;
+        let mut violations = vec![];
+        schema.validate(&value, &self.components, "body", 0, &mut violations);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+/// Per-request validation state. Call [`RequestValidation::on_request_headers`] and [`RequestValidation::on_request_body`]
+/// from the matching [`crate::HttpContext`] callbacks. Headers, path and query are validated immediately, bodies are buffered only
+/// when the matched operation has a JSON body schema.
+pub struct RequestValidation {
+    validator: Rc<OpenApiValidator>,
+    operation: Option<usize>,
+    rejected: bool,
+}
+
+impl RequestValidation {
+    pub fn new(validator: Rc<OpenApiValidator>) -> Self {
+        Self {
+            validator,
+            operation: None,
+            rejected: false,
+        }
+    }
+
+    /// Returns `true` if the request was rejected
+    pub fn rejected(&self) -> bool {
+        self.rejected
+    }
+
+    pub fn on_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|x| String::from_utf8_lossy(&x).into_owned())
+        };
+        let method = header(":method").unwrap_or_default();
+        let path = header(":path").unwrap_or_default();
+        match self.validator.validate_head(&method, &path, header) {
+            Ok(operation) => self.operation = operation,
+            Err(violations) => return self.reject_headers(headers, 400, &violations),
+        }
+        let Some(operation) = self.operation.map(|x| &self.validator.operations[x]) else {
+            return FilterHeadersStatus::Continue;
+        };
+        if headers.end_of_stream() {
+            if operation.body_required {
+                let violation = Violation::new("body", "request body is required");
+                return self.reject_headers(headers, 400, &[violation]);
+            }
+            return FilterHeadersStatus::Continue;
+        }
+        if operation.body_schema.is_some() {
+            let content_type = header("content-type").unwrap_or_default();
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            if !is_json_content_type(mime) {
+                let violation = Violation::new(
+                    "header.content-type",
+                    format!("unsupported content type '{mime}'"),
+                );
+                return self.reject_headers(headers, 400, &[violation]);
+            }
+        }
+        FilterHeadersStatus::Continue
+    }
+
+    pub fn on_request_body(&mut self, body: &RequestBody) -> FilterDataStatus {
+        let Some(index) = self.operation else {
+            return FilterDataStatus::Continue;
+        };
+        if self.rejected || self.validator.operations[index].body_schema.is_none() {
+            return FilterDataStatus::Continue;
+        }
+        if body.body_size() > self.validator.max_body_bytes {
+            let violation = Violation::new(
+                "body",
+                format!(
+                    "body exceeds {} bytes and cannot be validated",
+                    self.validator.max_body_bytes
+                ),
+            );
+            self.reject(body, 413, &[violation]);
+            return FilterDataStatus::StopIterationNoBuffer;
+        }
+        if !body.end_of_stream() {
+            return FilterDataStatus::StopAllIterationAndBuffer;
+        }
+        let data = body.all().unwrap_or_default();
+        match self.validator.validate_body(index, &data) {
+            Ok(()) => FilterDataStatus::Continue,
+            Err(violations) => {
+                self.reject(body, 400, &violations);
+                FilterDataStatus::StopIterationNoBuffer
+            }
+        }
+    }
+
+    fn reject_headers(
+        &mut self,
+        headers: &RequestHeaders,
+        status_code: u32,
+        violations: &[Violation],
+    ) -> FilterHeadersStatus {
+        self.reject(headers, status_code, violations);
+        FilterHeadersStatus::StopIteration
+    }
+
+    fn reject(&mut self, control: &impl HttpControl, status_code: u32, violations: &[Violation]) {
+        self.rejected = true;
+        let body = violation_response(violations).to_bytes();
+        if let Err(e) = control.send_http_response(
+            status_code,
+            &[("content-type", b"application/json")],
+            Some(&body),
+        ) {
+            warn!("failed to send validation response: {e:?}");
+        }
+    }
+}
+
+/// Builds the structured JSON body sent when a request is rejected
+pub fn violation_response(violations: &[Violation]) -> Value {
+    Value::Object(vec![
+        ("error".to_string(), "request validation failed".into()),
+        (
+            "violations".to_string(),
+            Value::Array(
+                violations
+                    .iter()
+                    .map(|x| {
+                        Value::Object(vec![
+                            ("location".to_string(), x.location.as_str().into()),
+                            ("message".to_string(), x.message.as_str().into()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+fn is_json_content_type(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type == "application/json" || content_type.ends_with("+json")
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|x| !x.is_empty()).collect()
+}
+
+/// Resolves a `#/components/{kind}/{name}` reference in `value`, if present
+fn resolve_component<'a>(
+    spec: &'a Value,
+    value: &'a Value,
+    kind: &str,
+) -> Result<&'a Value, OpenApiError> {
+    let Some(reference) = value.get("$ref").and_then(Value::as_str) else {
+        return Ok(value);
+    };
+    reference
+        .strip_prefix('#')
+        .filter(|x| x.starts_with(&format!("/components/{kind}/")))
+        .and_then(|x| spec.pointer(x))
+        .ok_or_else(|| OpenApiError(format!("unresolved $ref {reference}")))
+}
+
+fn compile_parameters(
+    spec: &Value,
+    parameters: Option<&Value>,
+) -> Result<Vec<Parameter>, OpenApiError> {
+    let mut out = vec![];
+    for parameter in parameters.and_then(Value::as_array).unwrap_or_default() {
+        let parameter = resolve_component(spec, parameter, "parameters")?;
+        let name = parameter
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| OpenApiError("parameter is missing a name".to_string()))?;
+        let location = match parameter.get("in").and_then(Value::as_str) {
+            Some("path") => ParameterLocation::Path,
+            Some("query") => ParameterLocation::Query,
+            Some("header") => ParameterLocation::Header,
+            // cookie parameters are not validated
+            Some("cookie") => continue,
+            other => {
+                return Err(OpenApiError(format!(
+                    "parameter '{name}' has invalid location {other:?}"
+                )))
+            }
+        };
+        out.push(Parameter {
+            name: name.to_string(),
+            location,
+            required: location == ParameterLocation::Path
+                || parameter.get("required").and_then(Value::as_bool) == Some(true),
+            schema: parameter.get("schema").map(Schema::compile).transpose()?,
+        });
+    }
+    Ok(out)
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            let (name, value) = x.split_once('=').unwrap_or((x, ""));
+            let decode =
+                |x: &str| String::from_utf8_lossy(&percent_decode(x.as_bytes(), true)).into_owned();
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r##"{
+        "paths": {
+            "/users/{id}": {
+                "parameters": [{"name": "id", "in": "path", "schema": {"type": "integer", "minimum": 1}}],
+                "get": {
+                    "parameters": [
+                        {"name": "fields", "in": "query", "schema": {"type": "array", "items": {"type": "string", "enum": ["name", "email"]}}},
+                        {"name": "X-Tenant", "in": "header", "required": true, "schema": {"type": "string", "minLength": 3}}
+                    ]
+                },
+                "put": {
+                    "requestBody": {"required": true, "content": {"application/json": {"schema": {"$ref": "#/components/schemas/User"}}}}
+                }
+            },
+            "/users/me": {"get": {}}
+        },
+        "components": {"schemas": {"User": {
+            "type": "object",
+            "required": ["name"],
+            "additionalProperties": false,
+            "properties": {"name": {"type": "string"}, "age": {"type": "integer", "nullable": true}}
+        }}}
+    }"##;
+
+    fn locations(result: Result<impl fmt::Debug, Vec<Violation>>) -> Vec<String> {
+        result
+            .unwrap_err()
+            .into_iter()
+            .map(|x| x.location)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_head() {
+        let validator = OpenApiValidator::compile(SPEC).unwrap();
+        let tenant = |name: &str| (name == "x-tenant").then(|| "acme".to_string());
+        assert_eq!(
+            validator.validate_head("GET", "/users/5?fields=name&fields=email", tenant),
+            Ok(Some(0))
+        );
+        assert_eq!(
+            validator.validate_head("GET", "/users/me", |_| None),
+            Ok(Some(2))
+        );
+        assert_eq!(validator.validate_head("GET", "/other", |_| None), Ok(None));
+        assert_eq!(
+            locations(validator.validate_head("GET", "/users/0?fields=age", |_| None)),
+            vec!["path.id", "query.fields/0", "header.X-Tenant"]
+        );
+    }
+
+    #[test]
+    fn test_validate_body() {
+        let validator = OpenApiValidator::compile(SPEC).unwrap();
+        assert_eq!(
+            validator.validate_body(1, br#"{"name":"a","age":null}"#),
+            Ok(())
+        );
+        assert_eq!(
+            locations(validator.validate_body(1, br#"{"age":1.5,"extra":1}"#)),
+            vec!["body/name", "body/age", "body/extra"]
+        );
+        assert_eq!(locations(validator.validate_body(1, b"{")), vec!["body"]);
+    }
+}
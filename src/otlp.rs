@@ -0,0 +1,553 @@
+//! OpenTelemetry tracing for filter operations. Spans are parented through W3C trace context headers,
+//! queued when they end, and exported in batches as OTLP/HTTP protobuf via [`HttpCall`].
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::RandomState, VecDeque},
+    fmt::Write,
+    hash::{BuildHasher, Hasher},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use prost::Message;
+
+use crate::{
+    time::{now, unix_nanos},
+    Backoff, Counter, HttpCall, HttpHeaderControl, Upstream,
+};
+
+/// Generated OTLP trace messages
+pub mod proto {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/opentelemetry.proto.collector.trace.v1.rs"
+    ));
+}
+use proto::{
+    any_value, status::StatusCode, AnyValue, ExportTraceServiceRequest, InstrumentationScope,
+    KeyValue, Resource, ResourceSpans, ScopeSpans,
+};
+
+pub use proto::span::SpanKind;
+
+/// W3C trace context identifying a span
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    /// Vendor specific `tracestate` header value, propagated as-is
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// Parses a version 00 `traceparent` header value, i.e. `00-<trace-id>-<parent-id>-<flags>`
+    pub fn parse_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let trace_id: [u8; 16] = decode_hex(trace_id)?;
+        let span_id: [u8; 8] = decode_hex(span_id)?;
+        let [flags] = decode_hex::<1>(flags.get(..2)?)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+            trace_state: None,
+        })
+    }
+
+    /// Reads the `traceparent` and `tracestate` headers
+    pub fn from_headers(headers: &impl HttpHeaderControl) -> Option<Self> {
+        let traceparent = headers.get("traceparent")?;
+        let mut out = Self::parse_traceparent(std::str::from_utf8(&traceparent).ok()?)?;
+        out.trace_state = headers
+            .get("tracestate")
+            .and_then(|x| String::from_utf8(x).ok())
+            .filter(|x| !x.is_empty());
+        Some(out)
+    }
+
+    /// Formats this context as a `traceparent` header value
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+
+    /// Sets the `traceparent` and `tracestate` headers, i.e. to propagate a client span to an upstream
+    pub fn inject(&self, headers: &impl HttpHeaderControl) {
+        headers.set("traceparent", self.traceparent());
+        match &self.trace_state {
+            Some(trace_state) => headers.set("tracestate", trace_state),
+            None => headers.remove("tracestate"),
+        }
+    }
+
+    /// A context for a new span in the same trace
+    fn child(&self) -> Self {
+        Self {
+            span_id: random_id(),
+            ..self.clone()
+        }
+    }
+
+    fn new_root() -> Self {
+        Self {
+            trace_id: random_id(),
+            span_id: random_id(),
+            sampled: true,
+            trace_state: None,
+        }
+    }
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(out, "{byte:02x}").unwrap();
+    }
+    out
+}
+
+thread_local! {
+    static ID_COUNTER: Cell<u64> = const { Cell::new(0) };
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut out = [0u8; N];
+    for chunk in out.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(ID_COUNTER.with(|x| {
+            x.set(x.get().wrapping_add(1));
+            x.get()
+        }));
+        hasher.write_u64(unix_nanos(now()));
+        let value = hasher.finish().to_le_bytes();
+        chunk.copy_from_slice(&value[..chunk.len()]);
+    }
+    out
+}
+
+/// Value of a span attribute
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Bool(bool),
+    Int(i64),
+    Double(f64),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<f64> for AttributeValue {
+    fn from(value: f64) -> Self {
+        Self::Double(value)
+    }
+}
+
+fn key_value(key: &str, value: &AttributeValue) -> KeyValue {
+    let value = match value {
+        AttributeValue::String(x) => any_value::Value::StringValue(x.clone()),
+        AttributeValue::Bool(x) => any_value::Value::BoolValue(*x),
+        AttributeValue::Int(x) => any_value::Value::IntValue(*x),
+        AttributeValue::Double(x) => any_value::Value::DoubleValue(*x),
+    };
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+/// An operation being traced. The span ends when dropped, and is queued for export if sampled and an [`OtlpExporter`] is installed.
+#[derive(Debug)]
+pub struct Span {
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<(String, AttributeValue)>,
+    error: Option<String>,
+}
+
+impl Span {
+    fn new(
+        name: impl Into<String>,
+        kind: SpanKind,
+        context: TraceContext,
+        parent_span_id: Option<[u8; 8]>,
+    ) -> Self {
+        Self {
+            context,
+            parent_span_id,
+            name: name.into(),
+            kind,
+            start: now(),
+            attributes: vec![],
+            error: None,
+        }
+    }
+
+    /// Starts a span in a new trace
+    pub fn root(name: impl Into<String>, kind: SpanKind) -> Self {
+        Self::new(name, kind, TraceContext::new_root(), None)
+    }
+
+    /// Starts a span with a remote parent, i.e. from [`TraceContext::from_headers`]
+    pub fn with_parent(name: impl Into<String>, kind: SpanKind, parent: &TraceContext) -> Self {
+        Self::new(name, kind, parent.child(), Some(parent.span_id))
+    }
+
+    /// Starts a server span for the current request, continuing the trace from its `traceparent` header if present
+    pub fn for_request(headers: &impl HttpHeaderControl, name: impl Into<String>) -> Self {
+        let mut out = match TraceContext::from_headers(headers) {
+            Some(parent) => Self::with_parent(name, SpanKind::Server, &parent),
+            None => Self::root(name, SpanKind::Server),
+        };
+        for (key, header) in [
+            ("http.request.method", ":method"),
+            ("url.path", ":path"),
+            ("server.address", ":authority"),
+        ] {
+            if let Some(value) = headers.get(header) {
+                out.set_attribute(key, String::from_utf8_lossy(&value).into_owned());
+            }
+        }
+        out
+    }
+
+    /// Starts a child span, i.e. a [`SpanKind::Client`] span for a callout
+    pub fn child(&self, name: impl Into<String>, kind: SpanKind) -> Self {
+        Self::new(name, kind, self.context.child(), Some(self.context.span_id))
+    }
+
+    pub fn context(&self) -> &TraceContext {
+        &self.context
+    }
+
+    pub fn set_attribute(&mut self, key: impl Into<String>, value: impl Into<AttributeValue>) {
+        self.attributes.push((key.into(), value.into()));
+    }
+
+    /// Marks the span as failed
+    pub fn set_error(&mut self, message: impl Into<String>) {
+        self.error = Some(message.into());
+    }
+
+    /// Ends the span now. Equivalent to dropping it.
+    pub fn end(self) {}
+
+    fn to_proto(&self, end: SystemTime) -> proto::Span {
+        proto::Span {
+            trace_id: self.context.trace_id.to_vec(),
+            span_id: self.context.span_id.to_vec(),
+            trace_state: self.context.trace_state.clone().unwrap_or_default(),
+            parent_span_id: self.parent_span_id.map(|x| x.to_vec()).unwrap_or_default(),
+            name: self.name.clone(),
+            kind: self.kind as i32,
+            start_time_unix_nano: unix_nanos(self.start),
+            end_time_unix_nano: unix_nanos(end),
+            attributes: self
+                .attributes
+                .iter()
+                .map(|(key, value)| key_value(key, value))
+                .collect(),
+            status: self.error.as_ref().map(|message| proto::Status {
+                message: message.clone(),
+                code: StatusCode::Error as i32,
+            }),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.context.sampled {
+            return;
+        }
+        PIPELINE.with_borrow_mut(|pipeline| {
+            let Some(exporter) = &pipeline.exporter else {
+                return;
+            };
+            if pipeline.pending.len() >= exporter.max_queue {
+                Counter::define("otlp_spans_dropped").increment(1);
+                return;
+            }
+            let span = self.to_proto(now());
+            pipeline.pending.push_back(span);
+        });
+    }
+}
+
+#[derive(Default)]
+struct Pipeline {
+    exporter: Option<OtlpExporter>,
+    pending: VecDeque<proto::Span>,
+}
+
+thread_local! {
+    static PIPELINE: RefCell<Pipeline> = RefCell::default();
+}
+
+/// Exports ended spans to an OTLP/HTTP collector. Install it once, then call [`flush`] periodically, i.e. from [`crate::RootContext::on_tick`].
+#[derive(Clone, Debug)]
+pub struct OtlpExporter {
+    pub upstream: Upstream<'static>,
+    /// `:authority` of export requests
+    pub authority: String,
+    /// Default is `/v1/traces`
+    pub path: String,
+    /// `service.name` resource attribute
+    pub service_name: String,
+    pub resource_attributes: Vec<(String, AttributeValue)>,
+    /// Spans per export request. Default is 512.
+    pub max_batch: usize,
+    /// Spans queued before new spans are dropped. Default is 2048.
+    pub max_queue: usize,
+    /// Default is 5 seconds.
+    pub timeout: Duration,
+    /// Delays exports after failures. Spans keep queueing meanwhile. Default is 1 second, up to 5 minutes.
+    pub backoff: Backoff,
+}
+
+impl OtlpExporter {
+    pub fn new(
+        upstream: Upstream<'static>,
+        authority: impl Into<String>,
+        service_name: impl Into<String>,
+    ) -> Self {
+        Self {
+            upstream,
+            authority: authority.into(),
+            path: "/v1/traces".to_string(),
+            service_name: service_name.into(),
+            resource_attributes: vec![],
+            max_batch: 512,
+            max_queue: 2048,
+            timeout: Duration::from_secs(5),
+            backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(300)),
+        }
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn resource_attribute(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<AttributeValue>,
+    ) -> Self {
+        self.resource_attributes.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn max_batch(mut self, max_batch: usize) -> Self {
+        self.max_batch = max_batch.max(1);
+        self
+    }
+
+    pub fn max_queue(mut self, max_queue: usize) -> Self {
+        self.max_queue = max_queue;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Starts collecting ended spans for this exporter on the current thread
+    pub fn install(self) {
+        PIPELINE.with_borrow_mut(|pipeline| pipeline.exporter = Some(self));
+    }
+
+    fn export_request(&self, spans: Vec<proto::Span>) -> ExportTraceServiceRequest {
+        let mut attributes = vec![key_value(
+            "service.name",
+            &AttributeValue::String(self.service_name.clone()),
+        )];
+        attributes.extend(
+            self.resource_attributes
+                .iter()
+                .map(|(key, value)| key_value(key, value)),
+        );
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource { attributes }),
+                scope_spans: vec![ScopeSpans {
+                    scope: Some(InstrumentationScope {
+                        name: env!("CARGO_PKG_NAME").to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    }),
+                    spans,
+                }],
+            }],
+        }
+    }
+
+    fn send(&self, spans: Vec<proto::Span>) {
+        let body = self.export_request(spans).encode_to_vec();
+        let result = HttpCall {
+            upstream: self.upstream.clone(),
+            headers: vec![
+                (":method", b"POST"),
+                (":path", self.path.as_bytes()),
+                (":authority", self.authority.as_bytes()),
+                ("content-type", b"application/x-protobuf"),
+            ],
+            trailers: vec![],
+            body: Some(&body),
+            timeout: Some(self.timeout),
+            inherit_request_policy: false,
+            retry: None,
+            callback: Some(Box::new(|_, response| {
+                let status = response.header(":status").unwrap_or_default();
+                if status.starts_with(b"2") {
+                    with_backoff(Backoff::succeed);
+                } else {
+                    warn!(
+                        "otlp export failed with status {}",
+                        String::from_utf8_lossy(&status)
+                    );
+                    Counter::define("otlp_export_failed").increment(1);
+                    with_backoff(|x| {
+                        x.fail();
+                    });
+                }
+            })),
+        }
+        .dispatch();
+        if let Err(e) = result {
+            warn!("failed to dispatch otlp export: {e:?}");
+            Counter::define("otlp_export_failed").increment(1);
+            with_backoff(|x| {
+                x.fail();
+            });
+        }
+    }
+}
+
+fn with_backoff(f: impl FnOnce(&Backoff)) {
+    PIPELINE.with_borrow(|pipeline| {
+        if let Some(exporter) = &pipeline.exporter {
+            f(&exporter.backoff);
+        }
+    });
+}
+
+/// Exports all queued spans in batches, unless backing off after a failed export. Returns the number of spans exported.
+pub fn flush() -> usize {
+    let (exporter, batches) = PIPELINE.with_borrow_mut(|pipeline| {
+        let Some(exporter) = pipeline.exporter.clone() else {
+            return (None, vec![]);
+        };
+        if !exporter.backoff.is_ready() {
+            return (None, vec![]);
+        }
+        let mut batches = vec![];
+        while !pipeline.pending.is_empty() {
+            let size = pipeline.pending.len().min(exporter.max_batch);
+            batches.push(pipeline.pending.drain(..size).collect::<Vec<_>>());
+        }
+        (Some(exporter), batches)
+    });
+    let Some(exporter) = exporter else {
+        return 0;
+    };
+    let mut count = 0;
+    for batch in batches {
+        count += batch.len();
+        exporter.send(batch);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse_traceparent(value).unwrap();
+        assert!(context.sampled);
+        assert_eq!(
+            context.span_id,
+            [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]
+        );
+        assert_eq!(context.traceparent(), value);
+        assert!(TraceContext::parse_traceparent(
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+        )
+        .is_none());
+        assert!(TraceContext::parse_traceparent("00-4bf92f35-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::parse_traceparent(
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_export_request() {
+        let exporter = OtlpExporter::new(Upstream::EMPTY, "collector", "filter")
+            .resource_attribute("deployment.environment", "test");
+        let request = exporter.export_request(vec![proto::Span::default()]);
+        let decoded = ExportTraceServiceRequest::decode(&*request.encode_to_vec()).unwrap();
+        let resource = &decoded.resource_spans[0];
+        assert_eq!(resource.resource.as_ref().unwrap().attributes.len(), 2);
+        assert_eq!(resource.scope_spans[0].spans.len(), 1);
+    }
+}
@@ -0,0 +1,63 @@
+//! Lightweight timers for profiling plugin sections in production.
+//!
+//! ```ignore
+//! let _span = perf::span("scan_body");
+//! // ... elapsed time is recorded into the `perf_scan_body_us` histogram when `_span` drops
+//! ```
+//!
+//! With the `perf-noop` feature, spans compile to nothing.
+
+#[cfg(not(feature = "perf-noop"))]
+use std::time::Instant;
+
+#[cfg(not(feature = "perf-noop"))]
+use crate::{time::instant_now, Histogram};
+
+/// Starts timing a section named `name`. Elapsed microseconds are recorded into the histogram `perf_{name}_us` when the span is dropped.
+#[cfg(not(feature = "perf-noop"))]
+pub fn span(name: &'static str) -> Span {
+    Span {
+        name,
+        start: Some(instant_now()),
+    }
+}
+
+/// Starts timing a section named `name`. This build has `perf-noop` enabled, so nothing is recorded.
+#[cfg(feature = "perf-noop")]
+#[inline(always)]
+pub fn span(_name: &'static str) -> Span {
+    Span {}
+}
+
+/// A running timer started by [`span`]
+#[must_use = "a span records when dropped, binding it to `_` ends it immediately"]
+pub struct Span {
+    #[cfg(not(feature = "perf-noop"))]
+    name: &'static str,
+    #[cfg(not(feature = "perf-noop"))]
+    start: Option<Instant>,
+}
+
+impl Span {
+    /// Discards the span without recording it
+    #[allow(unused_mut)]
+    pub fn cancel(mut self) {
+        #[cfg(not(feature = "perf-noop"))]
+        {
+            self.start = None;
+        }
+    }
+
+    /// Records the span now rather than on drop
+    pub fn finish(self) {}
+}
+
+#[cfg(not(feature = "perf-noop"))]
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(start) = self.start.take() {
+            let elapsed = instant_now().saturating_duration_since(start);
+            Histogram::define(format!("perf_{}_us", self.name)).record(elapsed.as_micros() as u64);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+
+use crate::{HeaderType, Status};
+
+/// Phase of an HTTP context, i.e. the last HTTP callback the host invoked on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HttpPhase {
+    RequestHeaders,
+    RequestBody,
+    RequestTrailers,
+    ResponseHeaders,
+    ResponseBody,
+    ResponseTrailers,
+    Log,
+}
+
+impl HttpPhase {
+    /// Phase of the currently active HTTP context. `None` outside of an HTTP context, or before its first callback.
+    pub fn current() -> Option<Self> {
+        crate::dispatcher::http_phase().map(|state| state.phase)
+    }
+}
+
+impl fmt::Display for HttpPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HttpPhase::RequestHeaders => "request headers",
+            HttpPhase::RequestBody => "request body",
+            HttpPhase::RequestTrailers => "request trailers",
+            HttpPhase::ResponseHeaders => "response headers",
+            HttpPhase::ResponseBody => "response body",
+            HttpPhase::ResponseTrailers => "response trailers",
+            HttpPhase::Log => "log",
+        })
+    }
+}
+
+/// An API was called in an HTTP phase where the host cannot honor it,
+/// i.e. sending a local response after the response headers were forwarded downstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WrongPhase {
+    /// The API that was called
+    pub operation: &'static str,
+    /// The phase the context was in
+    pub current: HttpPhase,
+    /// The phase the API must be called in, before headers of that phase are forwarded
+    pub required: HttpPhase,
+}
+
+impl fmt::Display for WrongPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not valid during {}, it requires {}",
+            self.operation, self.current, self.required
+        )
+    }
+}
+
+impl std::error::Error for WrongPhase {}
+
+/// Error of high-level HTTP APIs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HttpError {
+    /// The host rejected the call
+    Status(Status),
+    /// The call was rejected before reaching the host
+    WrongPhase(WrongPhase),
+}
+
+impl From<Status> for HttpError {
+    fn from(value: Status) -> Self {
+        HttpError::Status(value)
+    }
+}
+
+impl From<WrongPhase> for HttpError {
+    fn from(value: WrongPhase) -> Self {
+        HttpError::WrongPhase(value)
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Status(status) => write!(f, "{status:?}"),
+            HttpError::WrongPhase(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Which callbacks one direction of an HTTP context received so far.
+/// Lets trailer callbacks tell trailers following a body apart from trailers directly after headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BodyProgress {
+    /// Whether the headers callback ran
+    pub headers_seen: bool,
+    /// Whether the headers ended the stream, i.e. a headers-only request or a GRPC trailers-only response
+    pub headers_end_of_stream: bool,
+    /// Number of body callbacks received
+    pub body_callbacks: u32,
+    /// Size of the body buffer in the last body callback
+    pub last_body_size: usize,
+    /// Whether a body callback ended the stream
+    pub body_end_of_stream: bool,
+    /// Whether the trailers callback ran
+    pub trailers_seen: bool,
+}
+
+impl BodyProgress {
+    /// Progress of the request of the active HTTP context
+    pub fn request() -> Option<Self> {
+        crate::dispatcher::http_phase().map(|state| state.request)
+    }
+
+    /// Progress of the response of the active HTTP context
+    pub fn response() -> Option<Self> {
+        crate::dispatcher::http_phase().map(|state| state.response)
+    }
+
+    /// Whether any body callback occurred. Hosts skip body callbacks for empty bodies, even when trailers follow.
+    pub fn body_seen(&self) -> bool {
+        self.body_callbacks > 0
+    }
+}
+
+/// Per HTTP context phase tracking, maintained by the dispatcher
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PhaseState {
+    pub phase: HttpPhase,
+    pub request_headers_sent: bool,
+    pub response_headers_sent: bool,
+    pub request: BodyProgress,
+    pub response: BodyProgress,
+    /// Response callbacks continue without reaching the context, see [`crate::HttpControl::skip_response`]
+    pub skip_response: bool,
+}
+
+impl PhaseState {
+    pub fn new(phase: HttpPhase) -> Self {
+        Self {
+            phase,
+            request_headers_sent: false,
+            response_headers_sent: false,
+            request: BodyProgress::default(),
+            response: BodyProgress::default(),
+            skip_response: false,
+        }
+    }
+
+    fn progress(&mut self) -> Option<&mut BodyProgress> {
+        match self.phase {
+            HttpPhase::RequestHeaders | HttpPhase::RequestBody | HttpPhase::RequestTrailers => {
+                Some(&mut self.request)
+            }
+            HttpPhase::ResponseHeaders | HttpPhase::ResponseBody | HttpPhase::ResponseTrailers => {
+                Some(&mut self.response)
+            }
+            HttpPhase::Log => None,
+        }
+    }
+
+    /// Records the callback for the current phase
+    pub fn received(&mut self, end_of_stream: bool, body_size: usize) {
+        let phase = self.phase;
+        let Some(progress) = self.progress() else {
+            return;
+        };
+        match phase {
+            HttpPhase::RequestHeaders | HttpPhase::ResponseHeaders => {
+                progress.headers_seen = true;
+                progress.headers_end_of_stream = end_of_stream;
+            }
+            HttpPhase::RequestBody | HttpPhase::ResponseBody => {
+                progress.body_callbacks += 1;
+                progress.last_body_size = body_size;
+                progress.body_end_of_stream = end_of_stream;
+            }
+            _ => progress.trailers_seen = true,
+        }
+    }
+
+    /// Records whether the callback for the current phase let the stream continue, forwarding the headers of its direction.
+    pub fn continued(&mut self, continued: bool) {
+        if !continued {
+            return;
+        }
+        match self.phase {
+            HttpPhase::RequestHeaders | HttpPhase::RequestBody | HttpPhase::RequestTrailers => {
+                self.request_headers_sent = true
+            }
+            HttpPhase::ResponseHeaders | HttpPhase::ResponseBody | HttpPhase::ResponseTrailers => {
+                self.response_headers_sent = true
+            }
+            HttpPhase::Log => (),
+        }
+    }
+
+    pub fn check_send_response(&self, operation: &'static str) -> Result<(), WrongPhase> {
+        if self.response_headers_sent || self.phase == HttpPhase::Log {
+            return Err(WrongPhase {
+                operation,
+                current: self.phase,
+                required: HttpPhase::ResponseHeaders,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_header_write(
+        &self,
+        operation: &'static str,
+        header_type: &HeaderType,
+    ) -> Result<(), WrongPhase> {
+        let valid = match header_type {
+            HeaderType::RequestHeaders => !self.request_headers_sent,
+            HeaderType::RequestTrailers => self.phase == HttpPhase::RequestTrailers,
+            HeaderType::ResponseHeaders => {
+                self.phase >= HttpPhase::ResponseHeaders
+                    && self.phase != HttpPhase::Log
+                    && !self.response_headers_sent
+            }
+            HeaderType::ResponseTrailers => self.phase == HttpPhase::ResponseTrailers,
+            // metadata frames are not part of the phase sequence, the host rejects writes outside their callbacks
+            HeaderType::RequestMetadata | HeaderType::ResponseMetadata => true,
+        };
+        if valid {
+            return Ok(());
+        }
+        Err(WrongPhase {
+            operation,
+            current: self.phase,
+            required: match header_type {
+                HeaderType::RequestHeaders => HttpPhase::RequestHeaders,
+                HeaderType::RequestTrailers => HttpPhase::RequestTrailers,
+                HeaderType::ResponseHeaders => HttpPhase::ResponseHeaders,
+                HeaderType::ResponseTrailers => HttpPhase::ResponseTrailers,
+                HeaderType::RequestMetadata | HeaderType::ResponseMetadata => self.phase,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_response_after_headers_sent() {
+        let mut state = PhaseState::new(HttpPhase::RequestHeaders);
+        state.continued(true);
+        state.phase = HttpPhase::ResponseHeaders;
+        assert!(state.check_send_response("send_http_response").is_ok());
+        state.continued(false);
+        state.phase = HttpPhase::ResponseBody;
+        assert!(state.check_send_response("send_http_response").is_ok());
+        state.continued(true);
+        let err = state.check_send_response("send_http_response").unwrap_err();
+        assert_eq!(err.current, HttpPhase::ResponseBody);
+        assert_eq!(err.required, HttpPhase::ResponseHeaders);
+    }
+
+    #[test]
+    fn test_body_progress() {
+        let mut state = PhaseState::new(HttpPhase::RequestHeaders);
+        state.received(false, 0);
+        state.phase = HttpPhase::RequestTrailers;
+        state.received(true, 0);
+        assert!(state.request.trailers_seen);
+        assert!(!state.request.body_seen());
+        state.phase = HttpPhase::ResponseHeaders;
+        state.received(false, 0);
+        state.phase = HttpPhase::ResponseBody;
+        state.received(false, 10);
+        state.received(true, 25);
+        assert_eq!(state.response.body_callbacks, 2);
+        assert_eq!(state.response.last_body_size, 25);
+        assert!(state.response.body_end_of_stream && !state.response.trailers_seen);
+    }
+
+    #[test]
+    fn test_header_write() {
+        let mut state = PhaseState::new(HttpPhase::RequestHeaders);
+        assert!(state
+            .check_header_write("set", &HeaderType::RequestHeaders)
+            .is_ok());
+        assert!(state
+            .check_header_write("set", &HeaderType::ResponseHeaders)
+            .is_err());
+        state.continued(true);
+        state.phase = HttpPhase::RequestBody;
+        let err = state
+            .check_header_write("set", &HeaderType::RequestHeaders)
+            .unwrap_err();
+        assert_eq!(err.required, HttpPhase::RequestHeaders);
+        state.phase = HttpPhase::ResponseTrailers;
+        assert!(state
+            .check_header_write("add", &HeaderType::ResponseTrailers)
+            .is_ok());
+        assert!(state
+            .check_header_write("add", &HeaderType::RequestTrailers)
+            .is_err());
+    }
+}
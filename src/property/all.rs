@@ -3,7 +3,7 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use super::envoy::{Attributes, ListenerDirection, Metadata, Node};
+use super::envoy::{Attributes, ListenerDirection, Locality, Metadata, Node};
 
 #[derive(Debug)]
 pub struct AllAttributes {
@@ -155,6 +155,9 @@ pub struct AllUpstreamAttributes {
     pub sha256_peer_certificate_digest: Option<String>,
     pub local_address: Option<String>,
     pub transport_failure_reason: Option<String>,
+    pub hostname: Option<String>,
+    pub locality: Option<Locality>,
+    pub request_attempt_count: Option<u64>,
 }
 
 impl AllUpstreamAttributes {
@@ -172,6 +175,9 @@ impl AllUpstreamAttributes {
             sha256_peer_certificate_digest: a.upstream.sha256_peer_certificate_digest(),
             local_address: a.upstream.local_address(),
             transport_failure_reason: a.upstream.transport_failure_reason(),
+            hostname: a.upstream.hostname(),
+            locality: a.upstream.locality(),
+            request_attempt_count: a.upstream.request_attempt_count(),
         }
     }
 }
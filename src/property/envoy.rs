@@ -19,7 +19,7 @@ use std::{
 
 use log::warn;
 
-use crate::property::all::AllAttributes;
+use crate::{cert::PeerCertificate, property::all::AllAttributes};
 
 use super::{get_property_bool, get_property_decode, get_property_int, get_property_string};
 
@@ -85,11 +85,12 @@ impl RequestAttributes {
 
     /// Request method e.g. “GET”
     pub fn method(&self) -> Option<String> {
-        get_property_string("request.scheme")
+        get_property_string("request.method")
     }
 
     /// All request headers indexed by the lower-cased header name
     /// Header values in request.headers associative array are comma-concatenated in case of multiple values.
+    /// Split them with [`crate::split_header_value`] or [`crate::expand_joined_headers`], which keep i.e. `Set-Cookie` and `Date` intact.
     pub fn headers(&self) -> Option<Vec<(String, Vec<u8>)>> {
         let headers = get_property_decode::<attributes_proto::StringMap>("request.headers")?;
         Some(headers.map.into_iter().map(|x| (x.key, x.value)).collect())
@@ -182,6 +183,7 @@ impl ResponseAttributes {
 
     /// All response headers indexed by the lower-cased header name
     /// Header values in response.headers associative array are comma-concatenated in case of multiple values.
+    /// Split them with [`crate::split_header_value`] or [`crate::expand_joined_headers`], which keep i.e. `Set-Cookie` and `Date` intact.
     pub fn headers(&self) -> Option<Vec<(String, Vec<u8>)>> {
         let headers = get_property_decode::<attributes_proto::StringMap>("response.headers")?;
         Some(headers.map.into_iter().map(|x| (x.key, x.value)).collect())
@@ -189,6 +191,7 @@ impl ResponseAttributes {
 
     /// All response trailers indexed by the lower-cased trailer name
     /// Header values in response.trailers associative array are comma-concatenated in case of multiple values.
+    /// Split them with [`crate::split_header_value`] or [`crate::expand_joined_headers`], which keep i.e. `Set-Cookie` and `Date` intact.
     pub fn trailers(&self) -> Option<Vec<(String, Vec<u8>)>> {
         let headers = get_property_decode::<attributes_proto::StringMap>("response.trailers")?;
         Some(headers.map.into_iter().map(|x| (x.key, x.value)).collect())
@@ -289,6 +292,20 @@ impl ConnectionAttributes {
     pub fn termination_details(&self) -> Option<String> {
         get_property_string("connection.termination_details")
     }
+
+    /// The peer certificate properties of the downstream TLS connection. `None` without TLS.
+    /// The certificates themselves are read from `x-forwarded-client-cert`, see [`crate::cert`].
+    pub fn peer_certificate(&self) -> Option<PeerCertificate> {
+        self.tls_version()?;
+        Some(PeerCertificate {
+            presented: self.mtls(),
+            subject: self.subject_peer_certificate(),
+            dns_san: self.dns_san_peer_certificate(),
+            uri_san: self.uri_san_peer_certificate(),
+            sha256_digest: self.sha256_peer_certificate_digest(),
+            failure_reason: get_property_string("connection.transport_failure_reason"),
+        })
+    }
 }
 
 /// The following attributes are available once the upstream connection is established
@@ -354,6 +371,37 @@ impl UpstreamAttributes {
     pub fn transport_failure_reason(&self) -> Option<String> {
         get_property_string("upstream.transport_failure_reason")
     }
+
+    /// Hostname of the upstream host, as set on its endpoint
+    pub fn hostname(&self) -> Option<String> {
+        get_property_string("upstream.hostname").filter(|x| !x.is_empty())
+    }
+
+    /// Locality of the upstream host
+    pub fn locality(&self) -> Option<Locality> {
+        get_property_decode("upstream.locality")
+    }
+
+    /// Number of times the request was attempted upstream, i.e. 1 without retries
+    pub fn request_attempt_count(&self) -> Option<u64> {
+        get_property_int("upstream.request_attempt_count").map(|x| x as u64)
+    }
+
+    /// The peer certificate properties of the upstream TLS connection. `None` without TLS, unless the handshake failed.
+    pub fn peer_certificate(&self) -> Option<PeerCertificate> {
+        let failure_reason = self.transport_failure_reason();
+        if self.tls_version().is_none() && failure_reason.is_none() {
+            return None;
+        }
+        Some(PeerCertificate {
+            presented: None,
+            subject: self.subject_peer_certificate(),
+            dns_san: self.dns_san_peer_certificate(),
+            uri_san: self.uri_san_peer_certificate(),
+            sha256_digest: self.sha256_peer_certificate_digest(),
+            failure_reason,
+        })
+    }
 }
 
 /// Data exchanged between filters is available as the following attributes
@@ -409,7 +457,7 @@ impl ConfigurationAttributes {
 }
 
 #[repr(i64)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ListenerDirection {
     Unspecified = 0,
     Inbound = 1,
@@ -0,0 +1,177 @@
+//! Typed access to well-known Envoy filter state objects, which steer Envoy filters such as `tcp_proxy` or the router.
+//!
+//! Envoy only honors these keys for objects created by their registered factory, not for plain `set_property` values.
+//! [`FilterStateKey::set`] goes through the `set_envoy_filter_state` foreign function, which builds the object from
+//! the string encoding of the value. Reads go through the `filter_state` attribute.
+
+use std::{marker::PhantomData, net::SocketAddr};
+
+use crate::{check_concern, hostcalls, Status};
+
+/// How long a filter state object lives
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum LifeSpan {
+    FilterChain = 0,
+    #[default]
+    DownstreamRequest = 1,
+    DownstreamConnection = 2,
+}
+
+/// The string encoding Envoy's object factory expects for a filter state value
+pub trait FilterStateValue: Sized {
+    fn encode(&self) -> String;
+
+    fn decode(value: &str) -> Option<Self>;
+}
+
+impl FilterStateValue for String {
+    fn encode(&self) -> String {
+        self.clone()
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        Some(value.to_string())
+    }
+}
+
+impl FilterStateValue for u32 {
+    fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        value.trim().parse().ok()
+    }
+}
+
+impl FilterStateValue for SocketAddr {
+    fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        value.trim().parse().ok()
+    }
+}
+
+/// Comma-separated lists, i.e. ALPN protocols
+impl FilterStateValue for Vec<String> {
+    fn encode(&self) -> String {
+        self.join(",")
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        Some(
+            value
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect(),
+        )
+    }
+}
+
+/// A well-known filter state key and the type of its value
+#[derive(Clone, Copy, Debug)]
+pub struct FilterStateKey<T> {
+    pub name: &'static str,
+    _value: PhantomData<fn() -> T>,
+}
+
+/// Upstream cluster `tcp_proxy` connects to, overriding its configured cluster
+pub const TCP_PROXY_CLUSTER: FilterStateKey<String> =
+    FilterStateKey::new("envoy.tcp_proxy.cluster");
+/// Buffer limit of the `tcp_proxy` connection in bytes
+pub const TCP_PROXY_BUFFER_LIMIT: FilterStateKey<u32> =
+    FilterStateKey::new("envoy.tcp_proxy.per_connection_buffer_limit_bytes");
+/// SNI sent to the upstream
+pub const UPSTREAM_SERVER_NAME: FilterStateKey<String> =
+    FilterStateKey::new("envoy.network.upstream_server_name");
+/// ALPN protocols offered to the upstream
+pub const APPLICATION_PROTOCOLS: FilterStateKey<Vec<String>> =
+    FilterStateKey::new("envoy.network.application_protocols");
+/// Names the upstream certificate must present as a SAN
+pub const UPSTREAM_SUBJECT_ALT_NAMES: FilterStateKey<Vec<String>> =
+    FilterStateKey::new("envoy.network.upstream_subject_alt_names");
+/// Original destination the upstream connection is made to, for `ORIGINAL_DST` clusters
+pub const ORIGINAL_DST_ADDRESS: FilterStateKey<SocketAddr> =
+    FilterStateKey::new("envoy.network.transport_socket.original_dst_address");
+/// Key hashed by a `filter_state` hash policy, i.e. for ring hash or maglev load balancing
+pub const HASH_KEY: FilterStateKey<String> = FilterStateKey::new("envoy.hash_key");
+/// Host of a dynamic forward proxy upstream
+pub const DYNAMIC_HOST: FilterStateKey<String> = FilterStateKey::new("envoy.upstream.dynamic_host");
+/// Port of a dynamic forward proxy upstream
+pub const DYNAMIC_PORT: FilterStateKey<u32> = FilterStateKey::new("envoy.upstream.dynamic_port");
+
+impl<T: FilterStateValue> FilterStateKey<T> {
+    /// Declares a key not covered by the constants here. Envoy must have an object factory registered for `name`.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _value: PhantomData,
+        }
+    }
+
+    /// Reads the current value, if set
+    pub fn get(&self) -> Option<T> {
+        let raw = check_concern(
+            "filter-state-get",
+            hostcalls::get_property(["filter_state", self.name]),
+        )
+        .flatten()?;
+        T::decode(std::str::from_utf8(&raw).ok()?)
+    }
+
+    /// Sets the value for the life of the downstream request
+    pub fn set(&self, value: &T) -> Result<(), Status> {
+        self.set_with_span(value, LifeSpan::default())
+    }
+
+    /// Sets the value for the given life span. Connection level keys like [`TCP_PROXY_CLUSTER`] need
+    /// [`LifeSpan::DownstreamConnection`] when set from a network filter.
+    pub fn set_with_span(&self, value: &T, span: LifeSpan) -> Result<(), Status> {
+        hostcalls::call_foreign_function(
+            "set_envoy_filter_state",
+            Some(set_arguments(self.name, &value.encode(), span)),
+        )?;
+        Ok(())
+    }
+}
+
+/// Encodes `envoy.source.extensions.common.wasm.SetEnvoyFilterStateArguments`
+fn set_arguments(path: &str, value: &str, span: LifeSpan) -> Vec<u8> {
+    use prost::encoding::{int32, string};
+
+    let mut out = vec![];
+    string::encode(1, &path.to_string(), &mut out);
+    string::encode(2, &value.to_string(), &mut out);
+    if span as i32 != 0 {
+        int32::encode(3, &(span as i32), &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_arguments() {
+        assert_eq!(
+            set_arguments("a.b", "xy", LifeSpan::DownstreamConnection),
+            b"\x0a\x03a.b\x12\x02xy\x18\x02"
+        );
+        assert_eq!(
+            set_arguments("k", "", LifeSpan::FilterChain),
+            b"\x0a\x01k\x12\x00"
+        );
+        assert_eq!(
+            Vec::<String>::decode("h2, http/1.1"),
+            Some(vec!["h2".to_string(), "http/1.1".to_string()])
+        );
+        assert_eq!(
+            SocketAddr::decode("10.0.0.1:443").map(|x| x.encode()),
+            Some("10.0.0.1:443".to_string())
+        );
+    }
+}
@@ -1,9 +1,16 @@
 use log::warn;
 
-use crate::{hostcalls, log_concern};
+use crate::{hostcalls, log_concern, HeaderStr};
 
 pub mod all;
 pub mod envoy;
+pub mod filter_state;
+
+mod projection;
+pub use projection::*;
+
+mod stats;
+pub use stats::*;
 
 pub fn get_property(name: impl AsRef<str>) -> Option<Vec<u8>> {
     log_concern(
@@ -16,6 +23,11 @@ pub fn get_property_string(name: impl AsRef<str>) -> Option<String> {
     get_property(name).map(|x| String::from_utf8_lossy(&x).into_owned())
 }
 
+/// Reads a property without converting it, see [`HeaderStr`]
+pub fn get_property_str(name: impl AsRef<str>) -> Option<HeaderStr> {
+    get_property(name).map(HeaderStr::new)
+}
+
 pub fn set_property(name: impl AsRef<str>, value: impl AsRef<[u8]>) {
     log_concern(
         "set-property",
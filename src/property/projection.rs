@@ -0,0 +1,180 @@
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+use super::get_property;
+
+/// Conversion from a raw property value, following the host encoding of CEL value types.
+pub trait FromPropertyValue: Sized {
+    /// Decodes a present property value
+    fn from_property(raw: Vec<u8>) -> Option<Self>;
+
+    /// Value used when the property is absent. Only [`Option`] succeeds here.
+    fn from_missing() -> Option<Self> {
+        None
+    }
+}
+
+impl FromPropertyValue for Vec<u8> {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        Some(raw)
+    }
+}
+
+impl FromPropertyValue for String {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        Some(String::from_utf8_lossy(&raw).into_owned())
+    }
+}
+
+impl FromPropertyValue for i64 {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        Some(i64::from_le_bytes(raw.try_into().ok()?))
+    }
+}
+
+impl FromPropertyValue for u64 {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        Some(u64::from_le_bytes(raw.try_into().ok()?))
+    }
+}
+
+impl FromPropertyValue for f64 {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        Some(f64::from_le_bytes(raw.try_into().ok()?))
+    }
+}
+
+impl FromPropertyValue for bool {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        match raw[..] {
+            [x] => Some(x != 0),
+            _ => None,
+        }
+    }
+}
+
+impl FromPropertyValue for SystemTime {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        let raw = <prost_types::Timestamp as prost::Message>::decode(&raw[..]).ok()?;
+        if raw.seconds < 0 || raw.nanos < 0 {
+            return None;
+        }
+        Some(SystemTime::UNIX_EPOCH + Duration::new(raw.seconds as u64, raw.nanos as u32))
+    }
+}
+
+impl FromPropertyValue for Duration {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        let raw = <prost_types::Duration as prost::Message>::decode(&raw[..]).ok()?;
+        if raw.seconds < 0 || raw.nanos < 0 {
+            return None;
+        }
+        Some(Duration::new(raw.seconds as u64, raw.nanos as u32))
+    }
+}
+
+impl<T: FromPropertyValue> FromPropertyValue for Option<T> {
+    fn from_property(raw: Vec<u8>) -> Option<Self> {
+        Some(T::from_property(raw))
+    }
+
+    fn from_missing() -> Option<Self> {
+        Some(None)
+    }
+}
+
+/// Decodes a single property value for field `path`, logging failures.
+pub fn decode_property<T: FromPropertyValue>(path: &str, raw: Option<Vec<u8>>) -> Option<T> {
+    let out = match raw {
+        Some(raw) => T::from_property(raw),
+        None => T::from_missing(),
+    };
+    if out.is_none() {
+        warn!("property '{path}' is missing or has an unexpected type");
+    }
+    out
+}
+
+/// A set of values materialized from a list of property paths. Implemented for tuples of [`FromPropertyValue`]
+/// and for structs declared with [`crate::property_projection`].
+pub trait FromProperties: Sized {
+    /// Builds `Self` from raw values in the same order as the requested paths
+    fn from_properties(paths: &[&str], values: Vec<Option<Vec<u8>>>) -> Option<Self>;
+}
+
+macro_rules! tuple_from_properties {
+    ($($name:ident),+) => {
+        impl<$($name: FromPropertyValue),+> FromProperties for ($($name,)+) {
+            fn from_properties(paths: &[&str], values: Vec<Option<Vec<u8>>>) -> Option<Self> {
+                let mut values = paths.iter().zip(values);
+                Some(($({
+                    let (path, raw) = values.next()?;
+                    decode_property::<$name>(path, raw)?
+                },)+))
+            }
+        }
+    };
+}
+
+tuple_from_properties!(A);
+tuple_from_properties!(A, B);
+tuple_from_properties!(A, B, C);
+tuple_from_properties!(A, B, C, D);
+tuple_from_properties!(A, B, C, D, E);
+tuple_from_properties!(A, B, C, D, E, F);
+tuple_from_properties!(A, B, C, D, E, F, G);
+tuple_from_properties!(A, B, C, D, E, F, G, H);
+
+/// Reads each property in `paths` and materializes them into `T`.
+/// Returns `None` if a non-[`Option`] field is missing or cannot be decoded.
+pub fn get_properties<T: FromProperties>(paths: &[&str]) -> Option<T> {
+    let values = paths.iter().map(get_property).collect();
+    T::from_properties(paths, values)
+}
+
+/// Declares a struct whose fields are projected from properties.
+/// ```
+/// proxy_sdk::property_projection! {
+///     pub struct RequestInfo {
+///         pub path: String = "request.path",
+///         pub size: Option<i64> = "request.size",
+///     }
+/// }
+/// ```
+/// The struct implements [`FromProperties`] and gains `RequestInfo::PATHS` and `RequestInfo::get()`.
+#[macro_export]
+macro_rules! property_projection {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($field_vis:vis $field:ident: $ty:ty = $path:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($field_vis $field: $ty,)*
+        }
+
+        impl $name {
+            pub const PATHS: &'static [&'static str] = &[$($path),*];
+
+            /// Reads all projected properties
+            pub fn get() -> Option<Self> {
+                $crate::property::get_properties::<Self>(Self::PATHS)
+            }
+        }
+
+        impl $crate::property::FromProperties for $name {
+            fn from_properties(paths: &[&str], values: Vec<Option<Vec<u8>>>) -> Option<Self> {
+                let mut values = paths.iter().zip(values);
+                Some(Self {
+                    $($field: {
+                        let (path, raw) = values.next()?;
+                        $crate::property::decode_property::<$ty>(path, raw)?
+                    },)*
+                })
+            }
+        }
+    };
+}
@@ -0,0 +1,278 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use super::envoy::{Attributes, Locality};
+
+/// Envoy response flags bit-vector, as returned by `response.flags`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ResponseFlags(pub u64);
+
+impl ResponseFlags {
+    pub const FAILED_LOCAL_HEALTH_CHECK: u64 = 1 << 0;
+    pub const NO_HEALTHY_UPSTREAM: u64 = 1 << 1;
+    pub const UPSTREAM_REQUEST_TIMEOUT: u64 = 1 << 2;
+    pub const LOCAL_RESET: u64 = 1 << 3;
+    pub const UPSTREAM_REMOTE_RESET: u64 = 1 << 4;
+    pub const UPSTREAM_CONNECTION_FAILURE: u64 = 1 << 5;
+    pub const UPSTREAM_CONNECTION_TERMINATION: u64 = 1 << 6;
+    pub const UPSTREAM_OVERFLOW: u64 = 1 << 7;
+    pub const NO_ROUTE_FOUND: u64 = 1 << 8;
+    pub const DELAY_INJECTED: u64 = 1 << 9;
+    pub const FAULT_INJECTED: u64 = 1 << 10;
+    pub const RATE_LIMITED: u64 = 1 << 11;
+    pub const UNAUTHORIZED_EXTERNAL_SERVICE: u64 = 1 << 12;
+    pub const RATE_LIMIT_SERVICE_ERROR: u64 = 1 << 13;
+    pub const DOWNSTREAM_CONNECTION_TERMINATION: u64 = 1 << 14;
+    pub const UPSTREAM_RETRY_LIMIT_EXCEEDED: u64 = 1 << 15;
+    pub const STREAM_IDLE_TIMEOUT: u64 = 1 << 16;
+    pub const INVALID_ENVOY_REQUEST_HEADERS: u64 = 1 << 17;
+    pub const DOWNSTREAM_PROTOCOL_ERROR: u64 = 1 << 18;
+    pub const UPSTREAM_MAX_STREAM_DURATION_REACHED: u64 = 1 << 19;
+    pub const RESPONSE_FROM_CACHE_FILTER: u64 = 1 << 20;
+    pub const NO_FILTER_CONFIG_FOUND: u64 = 1 << 21;
+    pub const DURATION_TIMEOUT: u64 = 1 << 22;
+    pub const UPSTREAM_PROTOCOL_ERROR: u64 = 1 << 23;
+    pub const NO_CLUSTER_FOUND: u64 = 1 << 24;
+    pub const OVERLOAD_MANAGER: u64 = 1 << 25;
+    pub const DNS_RESOLUTION_FAILED: u64 = 1 << 26;
+    pub const DROP_OVERLOAD: u64 = 1 << 27;
+    pub const DOWNSTREAM_REMOTE_RESET: u64 = 1 << 28;
+
+    /// Short codes in bit order, as used by `%RESPONSE_FLAGS%` in Envoy access logs
+    const SHORT_CODES: [&'static str; 29] = [
+        "LH", "UH", "UT", "LR", "UR", "UF", "UC", "UO", "NR", "DI", "FI", "RL", "UAEX", "RLSE",
+        "DC", "URX", "SI", "IH", "DPE", "UMSDR", "RFCF", "NFCF", "DT", "UPE", "NC", "OM", "DF",
+        "DO", "DR",
+    ];
+
+    /// Returns `true` if all bits of `flag` are set
+    pub fn contains(&self, flag: u64) -> bool {
+        self.0 & flag == flag
+    }
+
+    /// Returns `true` if the flag with the short code `code` is set, i.e. `UT` for an upstream request timeout
+    pub fn contains_code(&self, code: &str) -> bool {
+        Self::SHORT_CODES
+            .iter()
+            .position(|x| *x == code)
+            .is_some_and(|bit| self.0 & (1 << bit) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Short codes of all set flags, i.e. `["UH", "UF"]`
+    pub fn short_codes(&self) -> Vec<&'static str> {
+        Self::SHORT_CODES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.0 & (1 << i) != 0)
+            .map(|(_, code)| *code)
+            .collect()
+    }
+}
+
+impl From<u64> for ResponseFlags {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+/// Formats like Envoy's `%RESPONSE_FLAGS%`: comma separated short codes, or `-` if none are set
+impl fmt::Display for ResponseFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("-");
+        }
+        f.write_str(&self.short_codes().join(","))
+    }
+}
+
+/// An address reported by the host. Non IP addresses (i.e. unix sockets or internal listeners) are kept as is.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    Socket(SocketAddr),
+    Other(String),
+}
+
+impl Address {
+    pub fn parse(raw: &str) -> Self {
+        match raw.parse() {
+            Ok(addr) => Address::Socket(addr),
+            Err(_) => Address::Other(raw.to_string()),
+        }
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Address::Socket(addr) => Some(*addr),
+            Address::Other(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Socket(addr) => addr.fmt(f),
+            Address::Other(raw) => f.write_str(raw),
+        }
+    }
+}
+
+/// Final attributes of a completed HTTP request, read at once in `on_log`
+#[derive(Clone, Debug, Default)]
+pub struct FinalStats {
+    pub start_time: Option<SystemTime>,
+    pub duration: Option<Duration>,
+    /// Request body size
+    pub bytes_received: Option<usize>,
+    /// Request size including headers
+    pub total_bytes_received: Option<usize>,
+    /// Response body size
+    pub bytes_sent: Option<usize>,
+    /// Response size including headers and trailers
+    pub total_bytes_sent: Option<usize>,
+    pub response_code: Option<u32>,
+    pub response_code_details: Option<String>,
+    pub grpc_status: Option<u32>,
+    pub response_flags: ResponseFlags,
+    pub downstream_address: Option<SocketAddr>,
+    pub upstream_host: Option<Address>,
+    pub upstream_cluster: Option<String>,
+    pub upstream_transport_failure_reason: Option<String>,
+    pub route_name: Option<String>,
+}
+
+impl FinalStats {
+    /// Reads the final attributes of the active HTTP context. Only complete during `on_log`.
+    pub fn collect() -> Self {
+        let attributes = Attributes::get();
+        Self {
+            start_time: attributes.request.time(),
+            duration: attributes.request.duration(),
+            bytes_received: attributes.request.size(),
+            total_bytes_received: attributes.request.total_size(),
+            bytes_sent: attributes.response.size(),
+            total_bytes_sent: attributes.response.total_size(),
+            response_code: attributes.response.code(),
+            response_code_details: attributes.response.code_details(),
+            grpc_status: attributes.response.grpc_status(),
+            response_flags: attributes.response.flags().unwrap_or_default().into(),
+            downstream_address: attributes.connection.source_address(),
+            upstream_host: super::get_property_string("upstream.address")
+                .map(|raw| Address::parse(&raw)),
+            upstream_cluster: attributes.configuration.cluster_name(),
+            upstream_transport_failure_reason: attributes.upstream.transport_failure_reason(),
+            route_name: attributes.configuration.route_name(),
+        }
+    }
+}
+
+/// Where a request was routed, i.e. for exporters reporting which upstream served it.
+/// Complete once the upstream responded: in response callbacks or `on_log`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RoutingResult {
+    pub route_name: Option<String>,
+    pub cluster_name: Option<String>,
+    pub upstream_address: Option<Address>,
+    pub upstream_hostname: Option<String>,
+    pub upstream_locality: Option<Locality>,
+    /// Local address of the upstream connection
+    pub upstream_local_address: Option<Address>,
+    /// Attempts made upstream, including the first one
+    pub attempt_count: Option<u64>,
+}
+
+impl RoutingResult {
+    /// Reads the routing attributes of the active HTTP context
+    pub fn collect() -> Self {
+        let attributes = Attributes::get();
+        Self {
+            route_name: attributes.configuration.route_name(),
+            cluster_name: attributes.configuration.cluster_name(),
+            upstream_address: super::get_property_string("upstream.address")
+                .map(|raw| Address::parse(&raw)),
+            upstream_hostname: attributes.upstream.hostname(),
+            upstream_locality: attributes.upstream.locality(),
+            upstream_local_address: attributes
+                .upstream
+                .local_address()
+                .map(|raw| Address::parse(&raw)),
+            attempt_count: attributes.upstream.request_attempt_count(),
+        }
+    }
+
+    /// Whether an upstream host was selected
+    pub fn is_routed(&self) -> bool {
+        self.upstream_address.is_some()
+    }
+
+    /// Attempts after the first one
+    pub fn retries(&self) -> Option<u64> {
+        self.attempt_count.map(|x| x.saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_flags() {
+        let flags = ResponseFlags(
+            ResponseFlags::NO_HEALTHY_UPSTREAM | ResponseFlags::UPSTREAM_CONNECTION_FAILURE,
+        );
+        assert!(flags.contains(ResponseFlags::NO_HEALTHY_UPSTREAM));
+        assert!(!flags.contains(ResponseFlags::RATE_LIMITED));
+        assert!(flags.contains_code("UF"));
+        assert!(!flags.contains_code("LH"));
+        assert!(!flags.contains_code("XX"));
+        assert_eq!(flags.to_string(), "UH,UF");
+        assert_eq!(ResponseFlags::default().to_string(), "-");
+        assert_eq!(
+            ResponseFlags(ResponseFlags::DOWNSTREAM_REMOTE_RESET).short_codes(),
+            ["DR"]
+        );
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(
+            Address::parse("10.0.0.1:8080").socket_addr(),
+            Some("10.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            Address::parse("/var/run/app.sock"),
+            Address::Other("/var/run/app.sock".to_string())
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_routing_result() {
+        use crate::testing::{reset_host, set_property};
+
+        reset_host();
+        assert!(!RoutingResult::collect().is_routed());
+        set_property(["xds", "cluster_name"], "backend");
+        set_property(["upstream", "address"], "10.0.0.2:443");
+        set_property(["upstream", "request_attempt_count"], 3u64.to_le_bytes());
+        let routing = RoutingResult::collect();
+        assert!(routing.is_routed());
+        assert_eq!(routing.cluster_name.as_deref(), Some("backend"));
+        assert_eq!(
+            routing
+                .upstream_address
+                .as_ref()
+                .and_then(Address::socket_addr),
+            Some("10.0.0.2:443".parse().unwrap())
+        );
+        assert_eq!(routing.retries(), Some(2));
+        assert_eq!(routing.upstream_locality, None);
+    }
+}
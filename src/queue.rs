@@ -31,7 +31,7 @@ impl Queue {
     }
 
     /// Registers a callback that is called whenever data is available in the queue to be dequeued.
-    /// Only one of `on_enqueue` or `on_receive` can be set at the same time.
+    /// Callbacks are registered per root context. Multiple callbacks for the same queue are all called, in registration order.
     pub fn on_enqueue<R: RootContext>(self, callback: impl FnMut(&mut R, Queue) + 'static) -> Self {
         crate::dispatcher::register_queue_callback(self.0, callback);
         self
@@ -39,7 +39,7 @@ impl Queue {
 
     /// Registers a callback that is called whenever data is available in the queue to be dequeued.
     /// Also dequeues anything on the queue. It may call the callback multiple times for each item, if multiple are present.
-    /// Only one of `on_enqueue` or `on_receive` can be set at the same time.
+    /// Callbacks are registered per root context. Since each item is only dequeued once, a receiving callback drains the queue before later callbacks run.
     pub fn on_receive<R: RootContext>(
         self,
         mut callback: impl FnMut(&mut R, Queue, Vec<u8>) + 'static,
@@ -51,6 +51,18 @@ impl Queue {
         });
         self
     }
+
+    /// Routes notifications for this queue to the current HTTP or stream context's `on_queue_ready`, i.e. to push messages into a long-lived session.
+    /// The context is entered as the effective context before delivery. The subscription ends when the context is deleted or calls [`Queue::unsubscribe`].
+    /// Returns `false` if called outside of an HTTP or stream context.
+    pub fn subscribe_context(&self) -> bool {
+        crate::dispatcher::register_queue_context(self.0)
+    }
+
+    /// Removes the subscription of the current HTTP or stream context, or all callbacks the current root context registered for this queue when called from a root context.
+    pub fn unsubscribe(&self) {
+        crate::dispatcher::unregister_queue_callbacks(self.0);
+    }
 }
 
 impl PartialEq<u32> for Queue {
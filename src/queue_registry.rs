@@ -0,0 +1,308 @@
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::{
+    check_concern, json::Value, now, property::get_property_string, time::unix_millis, Queue,
+    RootContext, SharedValue, Status,
+};
+
+/// A queue announced to a [`QueueRegistry`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueEntry {
+    pub vm_id: String,
+    pub queue: String,
+    pub announced_at: SystemTime,
+}
+
+impl QueueEntry {
+    fn to_value(&self) -> Value {
+        Value::Object(vec![
+            ("vm_id".to_string(), Value::String(self.vm_id.clone())),
+            ("queue".to_string(), Value::String(self.queue.clone())),
+            (
+                "at".to_string(),
+                Value::Number(unix_millis(self.announced_at) as f64),
+            ),
+        ])
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(Self {
+            vm_id: value.get("vm_id")?.as_str()?.to_string(),
+            queue: value.get("queue")?.as_str()?.to_string(),
+            announced_at: UNIX_EPOCH + Duration::from_millis(value.get("at")?.as_i64()? as u64),
+        })
+    }
+
+    /// Resolves the announced queue
+    pub fn resolve(&self) -> Option<Queue> {
+        check_concern(
+            "queue-registry-resolve",
+            Queue::resolve(&self.vm_id, &self.queue),
+        )
+        .flatten()
+    }
+}
+
+/// Discovery of shared queues across VM IDs, for plugins built on this SDK that feed each other, i.e. a detection
+/// plugin enqueuing findings to an enforcement plugin.
+///
+/// Shared data is scoped to a VM ID, while queues can be resolved in any VM ID. Each VM ID keeps a directory of the
+/// queues announced to it in its own shared data, fed through an inbox queue registered by [`QueueRegistry::listen`].
+/// [`QueueRegistry::announce`] records a queue in the directory of its own VM ID and enqueues it to the inbox of every
+/// peer VM ID. Entries expire after the TTL, so call [`QueueRegistry::refresh`] from `on_tick` to keep announcements
+/// alive and reach peers started later.
+///
+/// ```ignore
+/// // enforcement plugin, VM ID "enforcement"
+/// let registry = QueueRegistry::new("acme").peer("detection");
+/// registry.listen::<Root>()?;
+/// registry.announce(Queue::register("blocks")?, "blocks")?;
+/// // detection plugin, VM ID "detection"
+/// let registry = QueueRegistry::new("acme").peer("enforcement");
+/// registry.listen::<Root>()?;
+/// if let Some(queue) = registry.resolve_any("blocks") {
+///     queue.enqueue(finding)?;
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct QueueRegistry {
+    namespace: String,
+    vm_id: String,
+    peers: Vec<String>,
+    ttl: Duration,
+    announced: Rc<RefCell<Vec<String>>>,
+}
+
+impl QueueRegistry {
+    /// A registry shared by the plugins using the same `namespace`. Reads the VM ID of this VM from `plugin_vm_id`.
+    pub fn new(namespace: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            vm_id: get_property_string("plugin_vm_id").unwrap_or_default(),
+            peers: vec![],
+            ttl: Duration::from_secs(60),
+            announced: Default::default(),
+        }
+    }
+
+    /// Overrides the VM ID of this VM
+    pub fn vm_id(mut self, vm_id: impl Into<String>) -> Self {
+        self.vm_id = vm_id.into();
+        self
+    }
+
+    /// VM ID of this VM
+    pub fn local_vm_id(&self) -> &str {
+        &self.vm_id
+    }
+
+    /// Announces queues to the VM ID `vm_id` too
+    pub fn peer(mut self, vm_id: impl Into<String>) -> Self {
+        self.peers.push(vm_id.into());
+        self
+    }
+
+    /// Time an announcement stays in directories without a refresh. Default is 60 seconds.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn inbox_name(&self) -> String {
+        format!("{}:queue-registry", self.namespace)
+    }
+
+    fn directory(&self) -> SharedValue<Value> {
+        SharedValue::new(format!("{}:queue-directory", self.namespace))
+    }
+
+    /// Registers the inbox of this VM ID, recording the queues peers announce to it. Call from `on_vm_start` of a root context of type `R`.
+    pub fn listen<R: RootContext>(&self) -> Result<Queue, Status> {
+        let registry = self.clone();
+        Ok(
+            Queue::register(self.inbox_name())?.on_receive(move |_: &mut R, _, data| {
+                match Value::parse(&data)
+                    .ok()
+                    .as_ref()
+                    .and_then(QueueEntry::from_value)
+                {
+                    Some(entry) => registry.record(entry),
+                    None => warn!("{}: invalid queue announcement", registry.namespace),
+                }
+            }),
+        )
+    }
+
+    /// Announces `queue`, registered by this VM under `name`, to this VM ID and to the peers
+    pub fn announce(&self, queue: Queue, name: impl Into<String>) -> Result<Queue, Status> {
+        let name = name.into();
+        self.send(&name);
+        let mut announced = self.announced.borrow_mut();
+        if !announced.contains(&name) {
+            announced.push(name);
+        }
+        Ok(queue)
+    }
+
+    /// Announces again every queue announced by this VM
+    pub fn refresh(&self) {
+        for name in self.announced.borrow().iter() {
+            self.send(name);
+        }
+    }
+
+    fn send(&self, name: &str) {
+        let entry = QueueEntry {
+            vm_id: self.vm_id.clone(),
+            queue: name.to_string(),
+            announced_at: now(),
+        };
+        self.record(entry.clone());
+        let message = entry.to_value().to_bytes();
+        for peer in self.peers.iter().filter(|x| **x != self.vm_id) {
+            let inbox = QueueEntry {
+                vm_id: peer.clone(),
+                queue: self.inbox_name(),
+                announced_at: entry.announced_at,
+            };
+            // peers not started yet are reached by a later refresh
+            if let Some(inbox) = inbox.resolve() {
+                check_concern("queue-registry-announce", inbox.enqueue(&message));
+            }
+        }
+    }
+
+    /// Announcements before this are expired
+    fn horizon(&self) -> SystemTime {
+        now().checked_sub(self.ttl).unwrap_or(UNIX_EPOCH)
+    }
+
+    fn record(&self, entry: QueueEntry) {
+        let horizon = self.horizon();
+        let updated = self.directory().update(|directory| {
+            let mut entries: Vec<Value> = directory
+                .as_ref()
+                .and_then(|x| x.as_array())
+                .unwrap_or_default()
+                .iter()
+                .filter(|x| {
+                    QueueEntry::from_value(x).is_some_and(|x| {
+                        x.announced_at >= horizon
+                            && (x.vm_id != entry.vm_id || x.queue != entry.queue)
+                    })
+                })
+                .cloned()
+                .collect();
+            entries.push(entry.to_value());
+            Value::Array(entries)
+        });
+        if updated.is_none() {
+            warn!("{}: queue directory contended", self.namespace);
+        }
+    }
+
+    /// Queues in the directory of this VM ID, announced within the TTL, latest first
+    pub fn entries(&self) -> Vec<QueueEntry> {
+        let horizon = self.horizon();
+        let mut entries: Vec<QueueEntry> = self
+            .directory()
+            .read()
+            .as_ref()
+            .and_then(|x| x.as_array())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(QueueEntry::from_value)
+            .filter(|x| x.announced_at >= horizon)
+            .collect();
+        entries.sort_by_key(|x| Reverse(x.announced_at));
+        entries
+    }
+
+    /// Live announcements of queue `name`, in any VM ID, latest first
+    pub fn discover(&self, name: &str) -> Vec<QueueEntry> {
+        self.entries()
+            .into_iter()
+            .filter(|x| x.queue == name)
+            .collect()
+    }
+
+    /// Resolves queue `name` from the latest announcement that still resolves, falling back to probing this VM ID
+    /// and the peers, in order
+    pub fn resolve_any(&self, name: &str) -> Option<Queue> {
+        self.discover(name)
+            .iter()
+            .find_map(QueueEntry::resolve)
+            .or_else(|| {
+                std::iter::once(&self.vm_id)
+                    .chain(&self.peers)
+                    .find_map(|vm_id| {
+                        check_concern("queue-registry-resolve", Queue::resolve(vm_id, name))
+                            .flatten()
+                    })
+            })
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        dispatcher::{self, proxy_on_context_create, proxy_on_queue_ready},
+        testing::{reset_host, set_property, set_time},
+        BaseContext, Context,
+    };
+
+    #[derive(Default)]
+    struct Root;
+
+    impl BaseContext for Root {}
+
+    impl RootContext for Root {
+        fn create_context(&mut self) -> Context {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_queue_registry() {
+        reset_host();
+        set_time(UNIX_EPOCH + Duration::from_secs(1000));
+        set_property(["plugin_vm_id"], "enforcement");
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+
+        // the test host has a single VM ID, resolving the inbox of any peer to its own
+        let enforcement = QueueRegistry::new("acme").peer("detection");
+        assert_eq!(enforcement.vm_id, "enforcement");
+        let inbox = enforcement.listen::<Root>().unwrap();
+        let blocks = Queue::register("blocks").unwrap();
+        enforcement.announce(blocks, "blocks").unwrap();
+        assert_eq!(enforcement.discover("blocks").len(), 1);
+
+        let detection = QueueRegistry::new("acme")
+            .vm_id("detection")
+            .peer("enforcement");
+        detection
+            .announce(Queue::register("findings").unwrap(), "findings")
+            .unwrap();
+        proxy_on_queue_ready(1, inbox.0 as usize);
+        let mut vm_ids: Vec<_> = enforcement.entries().into_iter().map(|x| x.vm_id).collect();
+        vm_ids.sort();
+        assert_eq!(vm_ids, ["detection", "enforcement"]);
+        assert_eq!(enforcement.discover("findings")[0].vm_id, "detection");
+        assert!(enforcement.resolve_any("blocks") == Some(blocks));
+        assert!(enforcement.resolve_any("missing").is_none());
+
+        set_time(UNIX_EPOCH + Duration::from_secs(1090));
+        assert!(enforcement.entries().is_empty());
+        enforcement.refresh();
+        assert_eq!(enforcement.entries().len(), 1);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    cell::Cell,
+    fmt,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use log::warn;
+
+use crate::{time::now, Counter, ShardedCounter, Status};
+
+/// Which limit of a [`CalloutQuota`] was exceeded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QuotaLimit {
+    Calls,
+    Bytes,
+}
+
+/// A callout was rejected by its [`CalloutQuota`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    /// Name of the quota
+    pub quota: String,
+    pub limit: QuotaLimit,
+    /// Amount used in the current window, excluding the rejected call
+    pub used: u64,
+    pub max: u64,
+    /// Time until the current window ends
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "callout quota '{}' exceeded: {} {} of {} used, retry after {:?}",
+            self.quota,
+            self.used,
+            match self.limit {
+                QuotaLimit::Calls => "calls",
+                QuotaLimit::Bytes => "bytes",
+            },
+            self.max,
+            self.retry_after
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Error of dispatching a callout within a [`CalloutQuota`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CalloutError {
+    /// The quota rejected the call before it was dispatched
+    QuotaExceeded(QuotaExceeded),
+    /// The host rejected the call
+    Status(Status),
+}
+
+impl From<Status> for CalloutError {
+    fn from(value: Status) -> Self {
+        CalloutError::Status(value)
+    }
+}
+
+impl From<QuotaExceeded> for CalloutError {
+    fn from(value: QuotaExceeded) -> Self {
+        CalloutError::QuotaExceeded(value)
+    }
+}
+
+impl fmt::Display for CalloutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalloutError::QuotaExceeded(e) => e.fmt(f),
+            CalloutError::Status(status) => write!(f, "{status:?}"),
+        }
+    }
+}
+
+impl std::error::Error for CalloutError {}
+
+/// Caps the number of calls and request bytes sent to an upstream per fixed time window, across all VMs of the VM ID.
+/// Protects internal services from a plugin stuck in a callout loop.
+///
+/// Usage is kept in [`ShardedCounter`]s, so limits are approximate under concurrent dispatch from many VMs.
+/// Bytes count the request body, or the message for GRPC calls. Rejections increment `{name}_quota_rejected`.
+/// Use with [`crate::HttpCall::dispatch_within`] and [`crate::GrpcCall::dispatch_within`].
+#[derive(Clone, Debug)]
+pub struct CalloutQuota {
+    name: String,
+    window: Duration,
+    max_calls: Option<u64>,
+    max_bytes: Option<u64>,
+    shards: u32,
+    cleared: Cell<Option<u64>>,
+}
+
+impl CalloutQuota {
+    /// Creates or references the quota `name`, i.e. named after the upstream it protects. Unlimited until a limit is set.
+    /// Every VM must use the same window and shard count.
+    pub fn new(name: impl Into<String>, window: Duration) -> Self {
+        Self {
+            name: name.into(),
+            window: window.max(Duration::from_secs(1)),
+            max_calls: None,
+            max_bytes: None,
+            shards: 8,
+            cleared: Cell::new(None),
+        }
+    }
+
+    /// Maximum calls per window
+    pub fn max_calls(mut self, max_calls: u64) -> Self {
+        self.max_calls = Some(max_calls);
+        self
+    }
+
+    /// Maximum request bytes per window
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Shards of the usage counters. Default is 8.
+    pub fn shards(mut self, shards: u32) -> Self {
+        self.shards = shards;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Counters rotate over three slots: the current window, the next one which is cleared ahead of use,
+    /// and the previous one which may still see late writes.
+    fn counter(&self, limit: &str, window: u64) -> ShardedCounter {
+        ShardedCounter::new(
+            format!("{}:quota:{limit}:{}", self.name, window % 3),
+            self.shards,
+        )
+    }
+
+    /// Reserves one call of `bytes` in the current window, or returns the exceeded limit without reserving anything
+    pub fn acquire(&self, bytes: u64) -> Result<(), QuotaExceeded> {
+        let now = now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (window, retry_after) = window_of(now, self.window);
+        let limits = [
+            (QuotaLimit::Calls, "calls", self.max_calls, 1),
+            (QuotaLimit::Bytes, "bytes", self.max_bytes, bytes),
+        ];
+        if self.cleared.get() != Some(window) {
+            self.cleared.set(Some(window));
+            for key in ["calls", "bytes"] {
+                self.counter(key, window + 1).reset();
+            }
+        }
+        let mut reserved: Vec<(ShardedCounter, u64)> = vec![];
+        for (limit, key, max, amount) in limits {
+            let Some(max) = max else {
+                continue;
+            };
+            let counter = self.counter(key, window);
+            let used = counter.get().max(0) as u64;
+            if used.saturating_add(amount) > max {
+                for (counter, amount) in reserved {
+                    counter.add(-(amount as i64));
+                }
+                warn!("callout quota '{}' exceeded for {limit:?}", self.name);
+                Counter::define(format!("{}_quota_rejected", self.name)).increment(1);
+                return Err(QuotaExceeded {
+                    quota: self.name.clone(),
+                    limit,
+                    used,
+                    max,
+                    retry_after,
+                });
+            }
+            counter.add(amount as i64);
+            reserved.push((counter, amount));
+        }
+        Ok(())
+    }
+
+    /// Usage of the current window as `(calls, bytes)`
+    pub fn usage(&self) -> (u64, u64) {
+        let now = now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let (window, _) = window_of(now, self.window);
+        (
+            self.counter("calls", window).get().max(0) as u64,
+            self.counter("bytes", window).get().max(0) as u64,
+        )
+    }
+}
+
+/// Index of the window containing `now`, and the time until it ends
+fn window_of(now: Duration, window: Duration) -> (u64, Duration) {
+    let window_ms = window.as_millis().max(1);
+    let now_ms = now.as_millis();
+    let index = now_ms / window_ms;
+    let remaining = (index + 1) * window_ms - now_ms;
+    (index as u64, Duration::from_millis(remaining as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_of() {
+        let window = Duration::from_secs(60);
+        assert_eq!(
+            window_of(Duration::from_secs(125), window),
+            (2, Duration::from_secs(55))
+        );
+        assert_eq!(
+            window_of(Duration::from_secs(120), window),
+            (2, Duration::from_secs(60))
+        );
+    }
+}
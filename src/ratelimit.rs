@@ -0,0 +1,741 @@
+//! Descriptor based rate limiting, modeled after Envoy's rate limit service (RLS).
+//! Descriptors are evaluated either locally against token buckets stored in [`SharedData`](crate::SharedData) or remotely by a RLS server via [`GrpcCall`].
+//!
+//! For the common case of one limit per client, [`KeyedRateLimiter`] limits by a single key, i.e. the client IP or an API key header:
+//!
+//! ```ignore
+//! fn on_http_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
+//!     let ip = headers.attributes().connection.source_address().map(|x| x.ip().to_string());
+//!     if !LIMITER.enforce(headers, &ip.unwrap_or_default()) {
+//!         return FilterHeadersStatus::StopIteration;
+//!     }
+//!     FilterHeadersStatus::Continue
+//! }
+//! ```
+
+use std::time::Duration;
+
+use log::warn;
+use prost::Message;
+
+use crate::{
+    time::{format_retry_after, now, unix_nanos},
+    GrpcCall, GrpcCallResponse, GrpcCode, HttpControl, HttpHeaderControl, SharedCodec, SharedValue,
+    Status, Upstream,
+};
+
+mod rls_proto {
+    include!(concat!(env!("OUT_DIR"), "/envoy.service.ratelimit.v3.rs"));
+}
+use rls_proto::{
+    rate_limit_descriptor::{Entry, RateLimitOverride},
+    rate_limit_response::{rate_limit::Unit, Code},
+    RateLimitRequest, RateLimitResponse,
+};
+
+/// Number of requests allowed per unit of time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests_per_unit: u32,
+    pub unit: Duration,
+}
+
+impl RateLimit {
+    pub const fn per_second(requests_per_unit: u32) -> Self {
+        Self {
+            requests_per_unit,
+            unit: Duration::from_secs(1),
+        }
+    }
+
+    pub const fn per_minute(requests_per_unit: u32) -> Self {
+        Self {
+            requests_per_unit,
+            unit: Duration::from_secs(60),
+        }
+    }
+
+    pub const fn per_hour(requests_per_unit: u32) -> Self {
+        Self {
+            requests_per_unit,
+            unit: Duration::from_secs(60 * 60),
+        }
+    }
+
+    pub const fn per_day(requests_per_unit: u32) -> Self {
+        Self {
+            requests_per_unit,
+            unit: Duration::from_secs(60 * 60 * 24),
+        }
+    }
+
+    fn to_override(self) -> RateLimitOverride {
+        let unit = match self.unit.as_secs() {
+            1 => Unit::Second,
+            60 => Unit::Minute,
+            3600 => Unit::Hour,
+            86400 => Unit::Day,
+            _ => {
+                warn!(
+                    "rate limit unit {:?} is not representable in RLS, sending as per-second",
+                    self.unit
+                );
+                return RateLimitOverride {
+                    requests_per_unit: (self.requests_per_unit as u64 / self.unit.as_secs().max(1))
+                        .max(1) as u32,
+                    unit: Unit::Second as i32,
+                };
+            }
+        };
+        RateLimitOverride {
+            requests_per_unit: self.requests_per_unit,
+            unit: unit as i32,
+        }
+    }
+}
+
+/// A list of hierarchical key/value entries identifying what is being limited, as in Envoy RLS descriptors.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Descriptor {
+    pub entries: Vec<(String, String)>,
+    /// Optional limit override. Used as the bucket size for local evaluation and sent as an override to remote services.
+    pub limit: Option<RateLimit>,
+}
+
+impl Descriptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an arbitrary entry
+    pub fn entry(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.entries.push((key.into(), value.into()));
+        self
+    }
+
+    /// Appends a `generic_key` entry
+    pub fn generic_key(self, value: impl Into<String>) -> Self {
+        self.entry("generic_key", value)
+    }
+
+    /// Appends a `remote_address` entry for the downstream peer IP.
+    /// If the source address is unavailable, no entry is added.
+    pub fn remote_address(self, attributes: &crate::property::envoy::Attributes) -> Self {
+        match attributes.connection.source_address() {
+            Some(address) => self.entry("remote_address", address.ip().to_string()),
+            None => self,
+        }
+    }
+
+    /// Appends an entry keyed by `descriptor_key` with the value of the `header` header.
+    /// Returns `None` if the header is missing, mirroring Envoy which skips the descriptor in that case.
+    pub fn request_header(
+        self,
+        headers: &impl HttpHeaderControl,
+        header: impl AsRef<str>,
+        descriptor_key: impl Into<String>,
+    ) -> Option<Self> {
+        let value = headers.get(header)?;
+        Some(self.entry(descriptor_key, String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    /// Sets the limit override
+    pub fn with_limit(mut self, limit: RateLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    fn shared_data_key(&self, domain: &str) -> String {
+        let mut key = format!("ratelimit:{domain}");
+        for (name, value) in &self.entries {
+            key.push(':');
+            key.push_str(name);
+            key.push('=');
+            key.push_str(value);
+        }
+        key
+    }
+
+    fn to_proto(&self) -> rls_proto::RateLimitDescriptor {
+        rls_proto::RateLimitDescriptor {
+            entries: self
+                .entries
+                .iter()
+                .map(|(key, value)| Entry {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            limit: self.limit.map(RateLimit::to_override),
+        }
+    }
+}
+
+/// Outcome of a rate limit check
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RateLimitVerdict {
+    /// All descriptors are under limit
+    Ok,
+    /// At least one descriptor is over limit
+    OverLimit,
+    /// The backend failed to produce a verdict
+    Unknown,
+}
+
+/// A local limit applied to descriptors that match `entries`. A `None` value matches any value for that key.
+#[derive(Clone, Debug)]
+pub struct LocalRule {
+    pub entries: Vec<(String, Option<String>)>,
+    pub limit: RateLimit,
+}
+
+impl LocalRule {
+    fn matches(&self, descriptor: &Descriptor) -> bool {
+        self.entries.len() == descriptor.entries.len()
+            && self.entries.iter().zip(descriptor.entries.iter()).all(
+                |((rule_key, rule_value), (key, value))| {
+                    rule_key == key && rule_value.as_ref().is_none_or(|x| x == value)
+                },
+            )
+    }
+}
+
+/// Configuration for locally evaluated token buckets stored in [`SharedData`](crate::SharedData), shared by all VMs of the VM ID.
+#[derive(Clone, Debug, Default)]
+pub struct LocalRateLimits {
+    /// Rules are evaluated in order. A descriptor's own `limit` takes priority over rules.
+    pub rules: Vec<LocalRule>,
+    /// Limit for descriptors matching no rule. If `None`, unmatched descriptors are not limited.
+    pub default_limit: Option<RateLimit>,
+}
+
+/// Configuration for a remote Envoy RLS compatible service.
+#[derive(Clone, Debug)]
+pub struct RemoteRateLimit {
+    pub upstream: Upstream<'static>,
+    /// Timeout for the `ShouldRateLimit` call. Default is 20ms like Envoy.
+    pub timeout: Duration,
+}
+
+impl RemoteRateLimit {
+    pub fn new(upstream: Upstream<'static>) -> Self {
+        Self {
+            upstream,
+            timeout: Duration::from_millis(20),
+        }
+    }
+}
+
+/// Where descriptors are evaluated
+#[derive(Clone, Debug)]
+pub enum RateLimitBackend {
+    Local(LocalRateLimits),
+    Remote(RemoteRateLimit),
+}
+
+/// Result of [`RateLimiter::check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitCheck {
+    /// The verdict was available immediately. The callback was not and will not be called.
+    Decided(RateLimitVerdict),
+    /// The verdict will be delivered to the callback. HTTP filters should pause until then.
+    Pending,
+}
+
+/// Evaluates rate limit descriptors within a domain against a local or remote backend.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    pub domain: String,
+    pub backend: RateLimitBackend,
+}
+
+impl RateLimiter {
+    const SERVICE: &'static str = "envoy.service.ratelimit.v3.RateLimitService";
+    const METHOD: &'static str = "ShouldRateLimit";
+
+    pub fn new(domain: impl Into<String>, backend: RateLimitBackend) -> Self {
+        Self {
+            domain: domain.into(),
+            backend,
+        }
+    }
+
+    /// Checks `descriptors`, consuming `hits` from each matched limit.
+    /// Local backends always return [`RateLimitCheck::Decided`]. Remote backends return [`RateLimitCheck::Pending`] and call `callback` when the service responds.
+    /// A failure to dispatch the remote call is returned as `Decided(RateLimitVerdict::Unknown)`.
+    pub fn check(
+        &self,
+        descriptors: &[Descriptor],
+        hits: u32,
+        callback: impl FnOnce(RateLimitVerdict) + 'static,
+    ) -> RateLimitCheck {
+        match &self.backend {
+            RateLimitBackend::Local(local) => {
+                RateLimitCheck::Decided(self.check_local(local, descriptors, hits))
+            }
+            RateLimitBackend::Remote(remote) => {
+                match self.check_remote(remote, descriptors, hits, callback) {
+                    Ok(()) => RateLimitCheck::Pending,
+                    Err(e) => {
+                        warn!("failed to dispatch rate limit request: {e:?}");
+                        RateLimitCheck::Decided(RateLimitVerdict::Unknown)
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_local(
+        &self,
+        local: &LocalRateLimits,
+        descriptors: &[Descriptor],
+        hits: u32,
+    ) -> RateLimitVerdict {
+        let mut verdict = RateLimitVerdict::Ok;
+        for descriptor in descriptors {
+            let limit = descriptor.limit.or_else(|| {
+                local
+                    .rules
+                    .iter()
+                    .find(|rule| rule.matches(descriptor))
+                    .map(|rule| rule.limit)
+                    .or(local.default_limit)
+            });
+            let Some(limit) = limit else {
+                continue;
+            };
+            match Self::take_tokens(&descriptor.shared_data_key(&self.domain), limit, hits) {
+                RateLimitVerdict::Ok => (),
+                RateLimitVerdict::OverLimit => verdict = RateLimitVerdict::OverLimit,
+                RateLimitVerdict::Unknown if verdict == RateLimitVerdict::Ok => {
+                    verdict = RateLimitVerdict::Unknown
+                }
+                RateLimitVerdict::Unknown => (),
+            }
+        }
+        verdict
+    }
+
+    fn take_tokens(key: &str, limit: RateLimit, hits: u32) -> RateLimitVerdict {
+        update_state(key, |state, now| take_tokens(state, now, limit, hits))
+            .map_or(RateLimitVerdict::Unknown, |x| x.verdict)
+    }
+
+    fn check_remote(
+        &self,
+        remote: &RemoteRateLimit,
+        descriptors: &[Descriptor],
+        hits: u32,
+        callback: impl FnOnce(RateLimitVerdict) + 'static,
+    ) -> Result<(), Status> {
+        let request = RateLimitRequest {
+            domain: self.domain.clone(),
+            descriptors: descriptors.iter().map(Descriptor::to_proto).collect(),
+            hits_addend: hits,
+        }
+        .encode_to_vec();
+        GrpcCall {
+            upstream: remote.upstream.clone(),
+            service: Self::SERVICE,
+            method: Self::METHOD,
+            initial_metadata: vec![],
+            message: Some(request.into()),
+            timeout: Some(remote.timeout),
+            callback: Some(Box::new(move |_, response| {
+                callback(Self::parse_response(response))
+            })),
+        }
+        .dispatch()?;
+        Ok(())
+    }
+
+    fn parse_response(response: &GrpcCallResponse) -> RateLimitVerdict {
+        if response.status_code() != GrpcCode::Ok {
+            warn!(
+                "rate limit service failed: {:?} {}",
+                response.status_code(),
+                response.status_message().unwrap_or_default()
+            );
+            return RateLimitVerdict::Unknown;
+        }
+        let body = response.full_body().unwrap_or_default();
+        match RateLimitResponse::decode(&body[..]) {
+            Ok(response) => match Code::from_i32(response.overall_code) {
+                Some(Code::Ok) => RateLimitVerdict::Ok,
+                Some(Code::OverLimit) => RateLimitVerdict::OverLimit,
+                _ => RateLimitVerdict::Unknown,
+            },
+            Err(e) => {
+                warn!("failed to decode rate limit response: {e:?}");
+                RateLimitVerdict::Unknown
+            }
+        }
+    }
+}
+
+/// Runs `f` on the limiter state at `key` until it is written. `f` gets the current state and the time in nanoseconds
+/// since the Unix epoch. `None` if the key stayed contended.
+fn update_state<T: SharedCodec>(
+    key: &str,
+    mut f: impl FnMut(Option<T>, u64) -> (T, RateLimitDecision),
+) -> Option<RateLimitDecision> {
+    let mut decision = None;
+    let written = SharedValue::new(key).update(|state| {
+        let (state, x) = f(state, unix_nanos(now()));
+        decision = Some(x);
+        state
+    });
+    if written.is_none() {
+        warn!("rate limit state '{key}' is too contended, giving up");
+        return None;
+    }
+    decision
+}
+
+fn decode_u64s<const N: usize>(value: &[u8]) -> Option<[u64; N]> {
+    if value.len() != N * 8 {
+        return None;
+    }
+    let mut out = [0u64; N];
+    for (x, chunk) in out.iter_mut().zip(value.chunks_exact(8)) {
+        *x = u64::from_le_bytes(chunk.try_into().unwrap());
+    }
+    Some(out)
+}
+
+/// Token bucket, stored as `[last_refill_nanos, tokens_milli]` in little endian
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Bucket {
+    last_refill: u64,
+    tokens_milli: u64,
+}
+
+impl SharedCodec for Bucket {
+    fn encode(&self) -> Vec<u8> {
+        [self.last_refill, self.tokens_milli]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect()
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        let [last_refill, tokens_milli] = decode_u64s(value)?;
+        Some(Self {
+            last_refill,
+            tokens_milli,
+        })
+    }
+}
+
+/// Two fixed windows, stored as `[start_nanos, previous, current]` in little endian
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Window {
+    start: u64,
+    previous: u64,
+    current: u64,
+}
+
+impl SharedCodec for Window {
+    fn encode(&self) -> Vec<u8> {
+        [self.start, self.previous, self.current]
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect()
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        let [start, previous, current] = decode_u64s(value)?;
+        Some(Self {
+            start,
+            previous,
+            current,
+        })
+    }
+}
+
+fn take_tokens(
+    state: Option<Bucket>,
+    now: u64,
+    limit: RateLimit,
+    hits: u32,
+) -> (Bucket, RateLimitDecision) {
+    let capacity = limit.requests_per_unit as u64 * 1000;
+    let unit_nanos = limit.unit.as_nanos().max(1) as u64;
+    let cost = hits as u64 * 1000;
+    let state = state.unwrap_or(Bucket {
+        last_refill: now,
+        tokens_milli: capacity,
+    });
+    let elapsed = now.saturating_sub(state.last_refill);
+    let refill = (elapsed as u128 * capacity as u128 / unit_nanos as u128) as u64;
+    let tokens = state.tokens_milli.saturating_add(refill).min(capacity);
+    let (tokens, verdict, retry_after) = if tokens >= cost {
+        (tokens - cost, RateLimitVerdict::Ok, None)
+    } else {
+        let missing = (cost - tokens) as u128 * unit_nanos as u128 / capacity.max(1) as u128;
+        (
+            tokens,
+            RateLimitVerdict::OverLimit,
+            Some(Duration::from_nanos(missing as u64)),
+        )
+    };
+    let decision = RateLimitDecision {
+        verdict,
+        limit: limit.requests_per_unit,
+        remaining: (tokens / 1000) as u32,
+        retry_after,
+    };
+    let state = Bucket {
+        last_refill: now,
+        tokens_milli: tokens,
+    };
+    (state, decision)
+}
+
+/// Sliding window approximated from two fixed windows. The count of the previous window is weighted by how much of it
+/// the sliding window still covers.
+fn slide_window(
+    state: Option<Window>,
+    now: u64,
+    limit: RateLimit,
+    hits: u32,
+) -> (Window, RateLimitDecision) {
+    let unit = limit.unit.as_nanos().max(1) as u64;
+    let start = now - now % unit;
+    let (previous, current) = match state {
+        Some(state) if state.start == start => (state.previous, state.current),
+        Some(state) if state.start + unit == start => (state.current, 0),
+        _ => (0, 0),
+    };
+    let elapsed = now - start;
+    let weighted = (previous as u128 * (unit - elapsed) as u128 / unit as u128) as u64;
+    let allowed = limit.requests_per_unit as u64;
+    let hits = hits as u64;
+    let (current, verdict, retry_after) = if weighted + current + hits <= allowed {
+        (current + hits, RateLimitVerdict::Ok, None)
+    } else {
+        // the previous window's weight decays until the request fits, or the window rolls over
+        let retry_after = match allowed.checked_sub(current + hits) {
+            Some(room) if previous > 0 => {
+                let covered = (room as u128 * unit as u128 / previous as u128) as u64;
+                (unit - covered.min(unit)).saturating_sub(elapsed).max(1)
+            }
+            _ => unit - elapsed,
+        };
+        (
+            current,
+            RateLimitVerdict::OverLimit,
+            Some(Duration::from_nanos(retry_after)),
+        )
+    };
+    let decision = RateLimitDecision {
+        verdict,
+        limit: limit.requests_per_unit,
+        remaining: allowed.saturating_sub(weighted + current) as u32,
+        retry_after,
+    };
+    let state = Window {
+        start,
+        previous,
+        current,
+    };
+    (state, decision)
+}
+
+/// How a [`KeyedRateLimiter`] counts requests
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RateLimitAlgorithm {
+    /// Allows bursts of up to the limit, refilling continuously
+    #[default]
+    TokenBucket,
+    /// Allows the limit within any window of the unit, approximated from two fixed windows
+    SlidingWindow,
+}
+
+/// Outcome of [`KeyedRateLimiter::check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    pub verdict: RateLimitVerdict,
+    /// Requests allowed per unit
+    pub limit: u32,
+    /// Requests left before the limit is reached
+    pub remaining: u32,
+    /// When an over limit request could be allowed
+    pub retry_after: Option<Duration>,
+}
+
+impl RateLimitDecision {
+    /// Whether the request is allowed. [`RateLimitVerdict::Unknown`] counts as allowed if `fail_open`.
+    pub fn is_allowed(&self, fail_open: bool) -> bool {
+        match self.verdict {
+            RateLimitVerdict::Ok => true,
+            RateLimitVerdict::OverLimit => false,
+            RateLimitVerdict::Unknown => fail_open,
+        }
+    }
+
+    /// `x-ratelimit-limit`, `x-ratelimit-remaining` and, when over limit, `retry-after` in whole seconds
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut out = vec![
+            ("x-ratelimit-limit", self.limit.to_string()),
+            ("x-ratelimit-remaining", self.remaining.to_string()),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            out.push(("retry-after", format_retry_after(retry_after)));
+        }
+        out
+    }
+}
+
+/// Limits requests per key, i.e. per client IP or API key, with state in [`SharedData`](crate::SharedData) shared by all VMs of the VM ID.
+#[derive(Clone, Debug)]
+pub struct KeyedRateLimiter {
+    name: String,
+    limit: RateLimit,
+    algorithm: RateLimitAlgorithm,
+    fail_open: bool,
+    status_code: u32,
+}
+
+impl KeyedRateLimiter {
+    /// A token bucket limiter named `name`, the namespace of its keys
+    pub fn new(name: impl Into<String>, limit: RateLimit) -> Self {
+        Self {
+            name: name.into(),
+            limit,
+            algorithm: RateLimitAlgorithm::TokenBucket,
+            fail_open: true,
+            status_code: 429,
+        }
+    }
+
+    pub fn algorithm(mut self, algorithm: RateLimitAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Whether requests are allowed when the state stays contended. Default is `true`.
+    pub fn fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    /// Status of the local response sent by [`KeyedRateLimiter::enforce`]. Default is 429.
+    pub fn status_code(mut self, status_code: u32) -> Self {
+        self.status_code = status_code;
+        self
+    }
+
+    /// Counts `hits` requests for `key`
+    pub fn check_hits(&self, key: &str, hits: u32) -> RateLimitDecision {
+        let limit = self.limit;
+        let key = format!("ratelimit:{}/{key}", self.name);
+        match self.algorithm {
+            RateLimitAlgorithm::TokenBucket => {
+                update_state(&key, |state, now| take_tokens(state, now, limit, hits))
+            }
+            RateLimitAlgorithm::SlidingWindow => {
+                update_state(&key, |state, now| slide_window(state, now, limit, hits))
+            }
+        }
+        .unwrap_or(RateLimitDecision {
+            verdict: RateLimitVerdict::Unknown,
+            limit: limit.requests_per_unit,
+            remaining: 0,
+            retry_after: None,
+        })
+    }
+
+    /// Counts a request for `key`
+    pub fn check(&self, key: &str) -> RateLimitDecision {
+        self.check_hits(key, 1)
+    }
+
+    /// Counts a request for `key`, sending the local response with the rate limit headers if over limit.
+    /// Returns `true` if the request may continue.
+    pub fn enforce(&self, http: &impl HttpControl, key: &str) -> bool {
+        let decision = self.check(key);
+        if decision.is_allowed(self.fail_open) {
+            return true;
+        }
+        let headers = decision.headers();
+        let headers: Vec<(&str, &[u8])> = headers
+            .iter()
+            .map(|(name, value)| (*name, value.as_bytes()))
+            .collect();
+        if let Err(e) = http.send_http_response(self.status_code, &headers, None) {
+            warn!("failed to send rate limit response: {e:?}");
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = RateLimit::per_second(2);
+        let (state, first) = take_tokens(None, 0, limit, 1);
+        assert_eq!(first.remaining, 1);
+        let (state, _) = take_tokens(Some(state), 0, limit, 1);
+        let (state, over) = take_tokens(Some(state), 0, limit, 1);
+        assert_eq!(over.verdict, RateLimitVerdict::OverLimit);
+        assert_eq!(over.retry_after, Some(Duration::from_millis(500)));
+        let (_, refilled) = take_tokens(Some(state), SECOND / 2, limit, 1);
+        assert_eq!(refilled.verdict, RateLimitVerdict::Ok);
+    }
+
+    #[test]
+    fn test_state_codec() {
+        let (bucket, _) = take_tokens(None, SECOND, RateLimit::per_second(2), 1);
+        assert_eq!(Bucket::decode(&bucket.encode()), Some(bucket));
+        let (window, _) = slide_window(None, SECOND, RateLimit::per_second(2), 1);
+        assert_eq!(Window::decode(&window.encode()), Some(window));
+        assert_eq!(Window::decode(&bucket.encode()), None);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limit = RateLimit::per_second(4);
+        let mut state = None;
+        for _ in 0..4 {
+            let (next, decision) = slide_window(state, 10 * SECOND, limit, 1);
+            assert_eq!(decision.verdict, RateLimitVerdict::Ok);
+            state = Some(next);
+        }
+        let state = state.unwrap();
+        let (_, over) = slide_window(Some(state), 10 * SECOND, limit, 1);
+        assert_eq!(over.verdict, RateLimitVerdict::OverLimit);
+        assert_eq!(over.retry_after, Some(Duration::from_secs(1)));
+        // a quarter into the next window, the previous window still weighs 3
+        let (state, decision) = slide_window(Some(state), 11 * SECOND + SECOND / 4, limit, 1);
+        assert_eq!(decision.verdict, RateLimitVerdict::Ok);
+        assert_eq!(decision.remaining, 0);
+        let (_, over) = slide_window(Some(state), 11 * SECOND + SECOND / 4, limit, 1);
+        assert_eq!(over.retry_after, Some(Duration::from_millis(250)));
+        assert_eq!(over.headers()[2], ("retry-after", "1".to_string()));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_enforce() {
+        use crate::{property::envoy::Attributes, testing::reset_host, RequestHeaders};
+
+        reset_host();
+        let limiter = KeyedRateLimiter::new("api", RateLimit::per_minute(1));
+        let headers = RequestHeaders {
+            header_count: 0,
+            end_of_stream: true,
+            attributes: Attributes::get(),
+        };
+        assert!(limiter.enforce(&headers, "10.0.0.1"));
+        assert!(limiter.enforce(&headers, "10.0.0.2"));
+        assert!(!limiter.enforce(&headers, "10.0.0.1"));
+        let response = crate::testing::host::with_host(|host| host.local_response.clone()).unwrap();
+        assert_eq!(response.status_code, 429);
+    }
+}
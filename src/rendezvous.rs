@@ -0,0 +1,309 @@
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use crate::{dispatcher, downcast_box::DowncastBox, hash::Xxh64, QueueRegistry, RootContext};
+
+/// Index of the member of `members` owning `key` under rendezvous (highest random weight) hashing, `None` if there
+/// are no members. Removing a member only moves the keys it owned, and adding one only moves keys to it.
+pub fn rendezvous_index(key: &[u8], members: &[impl AsRef<str>]) -> Option<usize> {
+    members
+        .iter()
+        .enumerate()
+        .map(|(i, member)| {
+            let member = member.as_ref();
+            (
+                Xxh64::hash(Xxh64::hash(0, member.as_bytes()), key),
+                member,
+                i,
+            )
+        })
+        .max()
+        .map(|(_, _, i)| i)
+}
+
+/// A change of the members of a [`Rendezvous`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rebalance {
+    pub previous: Vec<String>,
+    pub members: Vec<String>,
+}
+
+impl Rebalance {
+    /// Members that were not members before
+    pub fn joined(&self) -> Vec<&str> {
+        self.members
+            .iter()
+            .filter(|x| !self.previous.contains(x))
+            .map(|x| &**x)
+            .collect()
+    }
+
+    /// Members that are gone
+    pub fn left(&self) -> Vec<&str> {
+        self.previous
+            .iter()
+            .filter(|x| !self.members.contains(x))
+            .map(|x| &**x)
+            .collect()
+    }
+
+    /// Owner of `key` before the change
+    pub fn previous_owner(&self, key: &[u8]) -> Option<&str> {
+        Some(&self.previous[rendezvous_index(key, &self.previous)?])
+    }
+
+    pub fn owner(&self, key: &[u8]) -> Option<&str> {
+        Some(&self.members[rendezvous_index(key, &self.members)?])
+    }
+
+    /// Whether `key` moved to another member
+    pub fn moved(&self, key: &[u8]) -> bool {
+        self.previous_owner(key) != self.owner(key)
+    }
+}
+
+type OnRebalance = Box<dyn Fn(&mut DowncastBox<dyn RootContext>, &Rebalance)>;
+
+struct RendezvousState {
+    registry: QueueRegistry,
+    role: String,
+    members: RefCell<Vec<String>>,
+    on_rebalance: Option<OnRebalance>,
+}
+
+/// Assigns keys to VM IDs with rendezvous hashing, i.e. to pick the VM exporting the data of each tenant.
+///
+/// Members are the VM IDs with a live announcement of the queue `role` in a [`QueueRegistry`], so every VM announcing
+/// it takes a share of the keys, and a VM that stops refreshing its announcement hands its keys over once it expires.
+/// Assignments only move for the keys of VMs joining or leaving. Call [`Rendezvous::refresh`] from `on_tick`, after
+/// [`QueueRegistry::refresh`], to pick up membership changes. Cheap to clone: clones share their state.
+///
+/// ```ignore
+/// let registry = QueueRegistry::new("acme").peer("exporter-b");
+/// registry.listen::<Root>()?;
+/// registry.announce(Queue::register("exports")?, "exports")?;
+/// let exports = Rendezvous::builder(registry, "exports")
+///     .on_rebalance(|root: &mut Root, rebalance| root.tenants.retain(|x| !rebalance.moved(x.as_bytes())))
+///     .build();
+/// // in on_tick
+/// exports.refresh();
+/// if exports.is_local(tenant.as_bytes()) {
+///     export(tenant);
+/// }
+/// ```
+pub struct Rendezvous(Rc<RendezvousState>);
+
+impl Clone for Rendezvous {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl Rendezvous {
+    /// Members are the VM IDs announcing the queue `role` to `registry`
+    pub fn builder(registry: QueueRegistry, role: impl Into<String>) -> RendezvousBuilder {
+        RendezvousBuilder(RendezvousState {
+            registry,
+            role: role.into(),
+            members: RefCell::new(vec![]),
+            on_rebalance: None,
+        })
+    }
+
+    /// Members as of the last refresh, sorted
+    pub fn members(&self) -> Vec<String> {
+        self.0.members.borrow().clone()
+    }
+
+    /// Reads the members from the registry. Returns `true` if they changed, after scheduling the rebalance callback to
+    /// run once the current callback returns.
+    pub fn refresh(&self) -> bool {
+        let mut members: Vec<String> = self
+            .0
+            .registry
+            .discover(&self.0.role)
+            .into_iter()
+            .map(|x| x.vm_id)
+            .collect();
+        members.sort();
+        members.dedup();
+        let previous = self.0.members.replace(members.clone());
+        if previous == members {
+            return false;
+        }
+        if self.0.on_rebalance.is_some() {
+            let rendezvous = self.clone();
+            let rebalance = Rebalance { previous, members };
+            dispatcher::defer_boxed(Box::new(move |root| {
+                if let Some(on_rebalance) = &rendezvous.0.on_rebalance {
+                    on_rebalance(root, &rebalance);
+                }
+            }));
+        }
+        true
+    }
+
+    /// Index of the member owning `key` in [`Rendezvous::members`]
+    pub fn index(&self, key: &[u8]) -> Option<usize> {
+        rendezvous_index(key, &self.0.members.borrow())
+    }
+
+    /// VM ID owning `key`, `None` until a member was found
+    pub fn owner(&self, key: &[u8]) -> Option<String> {
+        let members = self.0.members.borrow();
+        Some(members[rendezvous_index(key, &members)?].clone())
+    }
+
+    /// Whether this VM owns `key`
+    pub fn is_local(&self, key: &[u8]) -> bool {
+        self.owner(key)
+            .is_some_and(|x| x == self.0.registry.local_vm_id())
+    }
+}
+
+impl fmt::Debug for Rendezvous {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rendezvous")
+            .field("registry", &self.0.registry)
+            .field("role", &self.0.role)
+            .field("members", &self.0.members.borrow())
+            .finish()
+    }
+}
+
+/// Options of a [`Rendezvous`]
+pub struct RendezvousBuilder(RendezvousState);
+
+impl RendezvousBuilder {
+    /// Called when the members changed, on the root context that refreshed
+    pub fn on_rebalance<R: RootContext + 'static>(
+        mut self,
+        callback: impl Fn(&mut R, &Rebalance) + 'static,
+    ) -> Self {
+        self.0.on_rebalance = Some(Box::new(move |root, rebalance| {
+            callback(
+                root.as_any_mut().downcast_mut().expect("invalid root type"),
+                rebalance,
+            )
+        }));
+        self
+    }
+
+    pub fn build(self) -> Rendezvous {
+        Rendezvous(Rc::new(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendezvous_index() {
+        assert_eq!(rendezvous_index(b"tenant", &[] as &[&str]), None);
+        let members = ["a", "b", "c"];
+        let keys: Vec<String> = (0..3000).map(|x| format!("tenant-{x}")).collect();
+        let mut counts = [0; 3];
+        for key in &keys {
+            counts[rendezvous_index(key.as_bytes(), &members).unwrap()] += 1;
+        }
+        assert!(counts.iter().all(|x| (800..1200).contains(x)), "{counts:?}");
+
+        let rebalance = Rebalance {
+            previous: vec!["a".into(), "b".into(), "c".into()],
+            members: vec!["a".into(), "c".into(), "d".into()],
+        };
+        assert_eq!(rebalance.joined(), ["d"]);
+        assert_eq!(rebalance.left(), ["b"]);
+        for key in &keys {
+            let key = key.as_bytes();
+            if rebalance.moved(key) {
+                assert!(
+                    rebalance.previous_owner(key) == Some("b") || rebalance.owner(key) == Some("d")
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_rendezvous_membership() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        use crate::{
+            dispatcher::{proxy_on_context_create, proxy_on_tick},
+            testing::{reset_host, set_property, set_time},
+            BaseContext, Context, Queue,
+        };
+
+        thread_local! {
+            static REBALANCED: RefCell<Vec<Rebalance>> = const { RefCell::new(vec![]) };
+            static RENDEZVOUS: RefCell<Option<Rendezvous>> = const { RefCell::new(None) };
+        }
+
+        struct Root(Rendezvous);
+
+        impl Default for Root {
+            fn default() -> Self {
+                let rendezvous = Rendezvous::builder(QueueRegistry::new("acme"), "exports")
+                    .on_rebalance(|_: &mut Root, rebalance: &Rebalance| {
+                        REBALANCED.with_borrow_mut(|x| x.push(rebalance.clone()))
+                    })
+                    .build();
+                RENDEZVOUS.with_borrow_mut(|x| *x = Some(rendezvous.clone()));
+                Self(rendezvous)
+            }
+        }
+
+        impl BaseContext for Root {}
+
+        impl RootContext for Root {
+            fn on_tick(&mut self) {
+                self.0.refresh();
+            }
+
+            fn create_context(&mut self) -> Context {
+                unreachable!()
+            }
+        }
+
+        fn rebalanced() -> Vec<Rebalance> {
+            REBALANCED.with_borrow_mut(std::mem::take)
+        }
+
+        reset_host();
+        set_time(UNIX_EPOCH + Duration::from_secs(1000));
+        set_property(["plugin_vm_id"], "exporter-a");
+        dispatcher::reset_local(Root::default);
+        proxy_on_context_create(1, 0);
+        let rendezvous = RENDEZVOUS.with_borrow(|x| x.clone().unwrap());
+
+        // the test host shares one directory between all VM IDs
+        let a = QueueRegistry::new("acme");
+        let b = QueueRegistry::new("acme").vm_id("exporter-b");
+        let exports = Queue::register("exports").unwrap();
+        a.announce(exports, "exports").unwrap();
+        proxy_on_tick(1);
+        assert_eq!(rebalanced()[0].joined(), ["exporter-a"]);
+        assert!(rendezvous.is_local(b"tenant-1"));
+        proxy_on_tick(1);
+        assert!(rebalanced().is_empty());
+
+        set_time(UNIX_EPOCH + Duration::from_secs(1030));
+        b.announce(exports, "exports").unwrap();
+        proxy_on_tick(1);
+        assert_eq!(rebalanced()[0].joined(), ["exporter-b"]);
+        assert_eq!(rendezvous.members(), ["exporter-a", "exporter-b"]);
+        let owners: Vec<_> = (0..100)
+            .map(|x| rendezvous.is_local(format!("tenant-{x}").as_bytes()))
+            .collect();
+        assert!(owners.contains(&true) && owners.contains(&false));
+
+        // the announcement of exporter-a expires
+        set_time(UNIX_EPOCH + Duration::from_secs(1070));
+        proxy_on_tick(1);
+        assert_eq!(rebalanced()[0].left(), ["exporter-a"]);
+        assert_eq!(rendezvous.owner(b"tenant-1").as_deref(), Some("exporter-b"));
+        assert_eq!(rendezvous.index(b"tenant-1"), Some(0));
+        assert!(!rendezvous.is_local(b"tenant-1"));
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use crate::{dispatcher, Queue, RootContext, Status};
+
+/// Hands work from HTTP and stream contexts to their root context.
+///
+/// Stream callbacks cannot borrow the root context, as the SDK holds it while dispatching. Instead, [`RootHandle::spawn`]
+/// queues a closure that runs with the root context when it is next active: at its next `on_tick`, or right after the
+/// current callback through a shared queue once [`RootHandle::enable_wakeup`] was called.
+/// Unlike [`crate::defer`], the work runs as the root context, and survives the context that spawned it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RootHandle {
+    root_context_id: u32,
+}
+
+impl RootHandle {
+    /// Handle to the root context of the active context
+    pub fn current() -> Self {
+        Self {
+            root_context_id: dispatcher::root_id(),
+        }
+    }
+
+    pub fn root_context_id(&self) -> u32 {
+        self.root_context_id
+    }
+
+    /// Queues `work` to run with the root context. Work runs in the order it was spawned.
+    /// It is dropped if the root context is deleted first.
+    pub fn spawn<R: RootContext + 'static>(&self, work: impl FnOnce(&mut R) + 'static) {
+        dispatcher::spawn_boxed(
+            self.root_context_id,
+            Box::new(move |root| {
+                work(root.as_any_mut().downcast_mut().expect("invalid root type"))
+            }),
+        );
+    }
+
+    /// Number of spawned work items that have not run yet
+    pub fn pending(&self) -> usize {
+        dispatcher::spawned_count(self.root_context_id)
+    }
+
+    /// Registers a shared queue that wakes the root context whenever work is spawned, instead of waiting for its next tick.
+    /// Call from the root context, i.e. in `on_configure`. The queue name is unique to this root context.
+    pub fn enable_wakeup(&self) -> Result<(), Status> {
+        // every worker thread runs its own copy of this root context, with the same id
+        let nonce = RandomState::new().build_hasher().finish();
+        let name = format!("proxy-sdk:spawn:{}:{nonce:016x}", self.root_context_id);
+        let queue = Queue::register(name)?;
+        dispatcher::set_spawn_wakeup(self.root_context_id, queue.0);
+        Ok(())
+    }
+}
@@ -0,0 +1,679 @@
+//! Detection rules loaded at runtime, without redeploying the plugin.
+//!
+//! A [`RuleBundle`] is a versioned list of rules (a pattern plus metadata) in a small checksummed binary format.
+//! Bundles are fetched with an [`HttpCall`] ([`RuleSet::fetch`]) or pushed on a [`Queue`] ([`RuleSet::receive_from`]),
+//! validated, compiled into [`Matcher`]s and swapped into a [`RuleSet`] if they are newer than the active bundle.
+//!
+//! Regex automata can't be serialized portably, so bundles carry pattern sources which are compiled once on load.
+//! A bundle that fails to decode or compile is rejected as a whole and the active bundle is kept.
+//!
+//! Exact and prefix rules, i.e. path or header value lists, are compiled together into a single [`PrefixSet`],
+//! so [`CompiledRules::match_value`] costs the same for a handful of patterns as for tens of thousands.
+//!
+//! proxy-wasm has no broadcast primitive. The VM that receives a bundle publishes it in [`SharedData`], and every other
+//! VM of the VM ID picks it up in [`RuleSet::sync`], which only reads a version key unless a newer bundle exists.
+//! Call it from `on_tick`. Contexts hold an `Rc` of the bundle they started with, so a swap never changes the rules
+//! under an in-flight request.
+
+use std::{cell::RefCell, fmt, ops::Range, rc::Rc, time::Duration};
+
+use log::{info, warn};
+
+use crate::{
+    hash::Xxh64,
+    matcher::{LiteralMatcher, Matcher, PrefixSet, PrefixSetBuilder, Regex},
+    Counter, Gauge, HttpCall, Queue, RootContext, SharedData, Status, Upstream,
+};
+
+const MAGIC: &[u8; 4] = b"PSRB";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+/// Error produced while decoding or installing a [`RuleBundle`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleError {
+    /// The bundle ended early
+    Truncated,
+    /// The bundle doesn't start with the bundle magic
+    BadMagic,
+    /// The bundle was written by a newer, unknown format
+    UnsupportedFormat(u8),
+    /// The trailing checksum doesn't match the contents
+    Checksum,
+    /// A string field is not valid UTF-8
+    InvalidUtf8,
+    /// A rule has an unknown kind
+    InvalidKind(u8),
+    /// A rule pattern failed to compile
+    InvalidPattern { id: String, error: String },
+    /// The bundle is not newer than the active bundle
+    Stale { active: u64, received: u64 },
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleError::Truncated => write!(f, "rule bundle is truncated"),
+            RuleError::BadMagic => write!(f, "not a rule bundle"),
+            RuleError::UnsupportedFormat(x) => write!(f, "unsupported rule bundle format {x}"),
+            RuleError::Checksum => write!(f, "rule bundle checksum mismatch"),
+            RuleError::InvalidUtf8 => write!(f, "rule bundle contains invalid utf-8"),
+            RuleError::InvalidKind(x) => write!(f, "unknown rule kind {x}"),
+            RuleError::InvalidPattern { id, error } => {
+                write!(f, "rule '{id}' has an invalid pattern: {error}")
+            }
+            RuleError::Stale { active, received } => {
+                write!(
+                    f,
+                    "rule bundle version {received} is not newer than {active}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+/// How a rule pattern is matched
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RuleKind {
+    Literal,
+    LiteralIgnoreCase,
+    Regex,
+    /// Matches a whole value equal to the pattern, i.e. a path or header value checked with [`CompiledRules::match_value`].
+    /// Exact and prefix rules of a bundle are compiled into a single [`PrefixSet`].
+    Exact,
+    /// Matches a value starting with the pattern
+    Prefix,
+}
+
+impl RuleKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            RuleKind::Literal => 0,
+            RuleKind::LiteralIgnoreCase => 1,
+            RuleKind::Regex => 2,
+            RuleKind::Exact => 3,
+            RuleKind::Prefix => 4,
+        }
+    }
+
+    fn from_u8(value: u8) -> Result<Self, RuleError> {
+        Ok(match value {
+            0 => RuleKind::Literal,
+            1 => RuleKind::LiteralIgnoreCase,
+            2 => RuleKind::Regex,
+            3 => RuleKind::Exact,
+            4 => RuleKind::Prefix,
+            x => return Err(RuleError::InvalidKind(x)),
+        })
+    }
+}
+
+/// A single detection rule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub id: String,
+    pub kind: RuleKind,
+    pub pattern: Vec<u8>,
+    /// Free form metadata, i.e. a category or severity
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Rule {
+    pub fn new(id: impl Into<String>, kind: RuleKind, pattern: impl Into<Vec<u8>>) -> Self {
+        Self {
+            id: id.into(),
+            kind,
+            pattern: pattern.into(),
+            metadata: vec![],
+        }
+    }
+
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.push((key.into(), value.into()));
+        self
+    }
+
+    /// Value of metadata `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(x, _)| x == key)
+            .map(|(_, value)| &**value)
+    }
+
+    /// Matcher of a scanning rule. `None` for exact and prefix rules.
+    fn compile(&self) -> Result<Option<Box<dyn Matcher>>, RuleError> {
+        Ok(Some(match self.kind {
+            RuleKind::Exact | RuleKind::Prefix => return Ok(None),
+            RuleKind::Literal => Box::new(LiteralMatcher::new(self.pattern.clone())),
+            RuleKind::LiteralIgnoreCase => {
+                Box::new(LiteralMatcher::new(self.pattern.clone()).ignore_case())
+            }
+            RuleKind::Regex => {
+                let invalid = |error: String| RuleError::InvalidPattern {
+                    id: self.id.clone(),
+                    error,
+                };
+                let source =
+                    std::str::from_utf8(&self.pattern).map_err(|e| invalid(e.to_string()))?;
+                Box::new(Regex::new(source).map_err(|e| invalid(e.to_string()))?)
+            }
+        }))
+    }
+}
+
+/// A versioned set of rules, as distributed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuleBundle {
+    pub version: u64,
+    pub rules: Vec<Rule>,
+}
+
+impl RuleBundle {
+    pub fn new(version: u64) -> Self {
+        Self {
+            version,
+            rules: vec![],
+        }
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Serializes the bundle, i.e. for a rules server or a test fixture
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(FORMAT_VERSION);
+        out.extend_from_slice(&self.version.to_le_bytes());
+        out.extend_from_slice(&(self.rules.len() as u32).to_le_bytes());
+        for rule in &self.rules {
+            write_bytes(&mut out, rule.id.as_bytes());
+            out.push(rule.kind.to_u8());
+            write_bytes(&mut out, &rule.pattern);
+            out.extend_from_slice(&(rule.metadata.len() as u32).to_le_bytes());
+            for (key, value) in &rule.metadata {
+                write_bytes(&mut out, key.as_bytes());
+                write_bytes(&mut out, value.as_bytes());
+            }
+        }
+        let checksum = Xxh64::hash(0, &out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Parses and validates a serialized bundle. Patterns are not compiled.
+    pub fn decode(data: &[u8]) -> Result<Self, RuleError> {
+        let version = peek_version(data)?;
+        if data[MAGIC.len()] != FORMAT_VERSION {
+            return Err(RuleError::UnsupportedFormat(data[MAGIC.len()]));
+        }
+        let Some(body_len) = data.len().checked_sub(8).filter(|x| *x >= HEADER_LEN) else {
+            return Err(RuleError::Truncated);
+        };
+        let (body, checksum) = data.split_at(body_len);
+        if Xxh64::hash(0, body).to_le_bytes() != checksum {
+            return Err(RuleError::Checksum);
+        }
+
+        let mut reader = Reader(&body[HEADER_LEN..]);
+        let count = reader.u32()?;
+        let mut rules = Vec::with_capacity((count as usize).min(1024));
+        for _ in 0..count {
+            let id = reader.string()?;
+            let kind = RuleKind::from_u8(reader.take(1)?[0])?;
+            let pattern = reader.bytes()?.to_vec();
+            let metadata_count = reader.u32()?;
+            let mut metadata = Vec::with_capacity((metadata_count as usize).min(64));
+            for _ in 0..metadata_count {
+                metadata.push((reader.string()?, reader.string()?));
+            }
+            rules.push(Rule {
+                id,
+                kind,
+                pattern,
+                metadata,
+            });
+        }
+        if !reader.0.is_empty() {
+            return Err(RuleError::Truncated);
+        }
+        Ok(Self { version, rules })
+    }
+
+    /// Compiles every rule. Fails on the first invalid pattern.
+    pub fn compile(self) -> Result<CompiledRules, RuleError> {
+        let mut matchers = vec![];
+        // rules sharing an anchored pattern, in order of first appearance
+        let mut anchored: Vec<(RuleKind, &[u8], Vec<usize>)> = vec![];
+        for (i, rule) in self.rules.iter().enumerate() {
+            if let Some(matcher) = rule.compile()? {
+                matchers.push((i, matcher));
+                continue;
+            }
+            match anchored
+                .iter_mut()
+                .find(|(kind, pattern, _)| *kind == rule.kind && *pattern == &rule.pattern[..])
+            {
+                Some((_, _, rules)) => rules.push(i),
+                None => anchored.push((rule.kind, &rule.pattern, vec![i])),
+            }
+        }
+        let anchored = anchored
+            .into_iter()
+            .fold(
+                PrefixSetBuilder::new(),
+                |builder, (kind, pattern, rules)| {
+                    if kind == RuleKind::Prefix {
+                        builder.prefix(pattern, rules)
+                    } else {
+                        builder.exact(pattern, rules)
+                    }
+                },
+            )
+            .build();
+        Ok(CompiledRules {
+            bundle: self,
+            matchers,
+            anchored,
+        })
+    }
+}
+
+/// Reads the version of a serialized bundle without validating the rest of it
+fn peek_version(data: &[u8]) -> Result<u64, RuleError> {
+    if data.len() < HEADER_LEN {
+        return Err(RuleError::Truncated);
+    }
+    if &data[..MAGIC.len()] != MAGIC {
+        return Err(RuleError::BadMagic);
+    }
+    Ok(u64::from_le_bytes(
+        data[MAGIC.len() + 1..HEADER_LEN].try_into().unwrap(),
+    ))
+}
+
+fn write_bytes(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], RuleError> {
+        if self.0.len() < len {
+            return Err(RuleError::Truncated);
+        }
+        let (out, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(out)
+    }
+
+    fn u32(&mut self) -> Result<u32, RuleError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], RuleError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String, RuleError> {
+        std::str::from_utf8(self.bytes()?)
+            .map(str::to_string)
+            .map_err(|_| RuleError::InvalidUtf8)
+    }
+}
+
+/// A match of a rule in scanned data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleMatch<'a> {
+    pub rule: &'a Rule,
+    pub range: Range<usize>,
+}
+
+/// A bundle with compiled matchers, ready for scanning
+pub struct CompiledRules {
+    bundle: RuleBundle,
+    /// Scanning rules by index
+    matchers: Vec<(usize, Box<dyn Matcher>)>,
+    /// Indices of exact and prefix rules by pattern
+    anchored: PrefixSet<Vec<usize>>,
+}
+
+impl CompiledRules {
+    pub fn version(&self) -> u64 {
+        self.bundle.version
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.bundle.rules
+    }
+
+    /// All matches of all rules in `data`, ordered by position. Exact and prefix rules match from the start of `data`.
+    pub fn scan<'a>(&'a self, data: &[u8]) -> Vec<RuleMatch<'a>> {
+        let rules = &self.bundle.rules;
+        let mut out = self
+            .matchers
+            .iter()
+            .flat_map(|(i, matcher)| {
+                matcher
+                    .find_all(data)
+                    .into_iter()
+                    .map(move |range| RuleMatch {
+                        rule: &rules[*i],
+                        range,
+                    })
+            })
+            .chain(
+                self.anchored
+                    .find_all(data)
+                    .into_iter()
+                    .flat_map(|(len, indices)| {
+                        indices.iter().map(move |i| RuleMatch {
+                            rule: &rules[*i],
+                            range: 0..len,
+                        })
+                    }),
+            )
+            .collect::<Vec<_>>();
+        out.sort_by_key(|x| (x.range.start, x.range.end));
+        out
+    }
+
+    /// Exact and prefix rules matching a whole value, i.e. a request path or a header value, best match first:
+    /// exact rules, then prefix rules from the longest prefix. Runs in time linear in the length of `value`.
+    pub fn match_value(&self, value: &[u8]) -> Vec<&Rule> {
+        self.anchored
+            .find_all(value)
+            .into_iter()
+            .flat_map(|(_, indices)| indices.iter().map(|i| &self.bundle.rules[*i]))
+            .collect()
+    }
+}
+
+/// Matches of all rules, with overlapping matches dropped in favor of the earliest one.
+/// Lets a bundle drive [`crate::transform::redact`].
+impl Matcher for CompiledRules {
+    fn find_all(&self, data: &[u8]) -> Vec<Range<usize>> {
+        let mut out: Vec<Range<usize>> = vec![];
+        for found in self.scan(data) {
+            if out.last().is_some_and(|x| x.end > found.range.start) {
+                continue;
+            }
+            out.push(found.range);
+        }
+        out
+    }
+
+    fn is_match(&self, data: &[u8]) -> bool {
+        self.anchored.is_match(data) || self.matchers.iter().any(|(_, x)| x.is_match(data))
+    }
+}
+
+/// Checks `candidate` may replace a bundle at version `active`
+fn check_newer(active: Option<u64>, candidate: u64) -> Result<(), RuleError> {
+    match active {
+        Some(active) if candidate <= active => Err(RuleError::Stale {
+            active,
+            received: candidate,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// The active rule bundle of this VM, and its distribution to the other VMs of the VM ID.
+/// Cheap to clone: clones share the active bundle, so a root context can hand one to each HTTP context.
+#[derive(Clone)]
+pub struct RuleSet {
+    name: Rc<str>,
+    active: Rc<RefCell<Option<Rc<CompiledRules>>>>,
+}
+
+impl RuleSet {
+    /// Creates an empty rule set. `name` prefixes its [`SharedData`] keys and metrics.
+    pub fn new(name: impl AsRef<str>) -> Self {
+        Self {
+            name: name.as_ref().into(),
+            active: Default::default(),
+        }
+    }
+
+    /// The active bundle, if any was installed. Hold on to it for the life of a request to scan with consistent rules.
+    pub fn current(&self) -> Option<Rc<CompiledRules>> {
+        self.active.borrow().clone()
+    }
+
+    /// Version of the active bundle
+    pub fn version(&self) -> Option<u64> {
+        self.active.borrow().as_ref().map(|x| x.version())
+    }
+
+    /// Validates and compiles a serialized bundle, and makes it active in this VM if it is newer than the active one.
+    /// Other VMs are not affected, see [`RuleSet::publish`].
+    pub fn install(&self, data: &[u8]) -> Result<u64, RuleError> {
+        let result = peek_version(data)
+            .and_then(|version| check_newer(self.version(), version))
+            .and_then(|()| RuleBundle::decode(data)?.compile());
+        let compiled = match result {
+            Ok(compiled) => compiled,
+            Err(e) => {
+                if !matches!(e, RuleError::Stale { .. }) {
+                    Counter::define(format!("{}_rules_rejected", self.name)).increment(1);
+                }
+                return Err(e);
+            }
+        };
+        let version = compiled.version();
+        *self.active.borrow_mut() = Some(Rc::new(compiled));
+        Gauge::define(format!("{}_rules_version", self.name)).record(version);
+        info!("{} rules: installed version {version}", self.name);
+        Ok(version)
+    }
+
+    fn bundle_key(&self) -> String {
+        format!("{}:rules", self.name)
+    }
+
+    fn version_key(&self) -> String {
+        format!("{}:rules-version", self.name)
+    }
+
+    /// Installs a bundle in this VM and stores it in [`SharedData`] for the other VMs to [`RuleSet::sync`].
+    /// A newer bundle already in shared data is never overwritten.
+    pub fn publish(&self, data: &[u8]) -> Result<u64, RuleError> {
+        let version = self.install(data)?;
+        let bundle = SharedData::from_key(self.bundle_key());
+        for _ in 0..8 {
+            let (stored, cas) = bundle.get_with_cas();
+            if stored.is_some_and(|x| peek_version(&x).is_ok_and(|x| x >= version)) {
+                break;
+            }
+            let stored = match cas {
+                Some(cas) => bundle.set_with_cas(data, cas),
+                None => {
+                    bundle.set(data);
+                    true
+                }
+            };
+            if stored {
+                SharedData::from_key(self.version_key()).set(version.to_le_bytes());
+                break;
+            }
+        }
+        Ok(version)
+    }
+
+    /// Installs the bundle published by another VM if it is newer than the active one.
+    /// Returns `true` if a bundle was installed.
+    pub fn sync(&self) -> bool {
+        let Some(published) = SharedData::from_key(self.version_key())
+            .get()
+            .and_then(|x| Some(u64::from_le_bytes(x.try_into().ok()?)))
+        else {
+            return false;
+        };
+        if check_newer(self.version(), published).is_err() {
+            return false;
+        }
+        let Some(data) = SharedData::from_key(self.bundle_key()).get() else {
+            return false;
+        };
+        match self.install(&data) {
+            Ok(_) => true,
+            Err(RuleError::Stale { .. }) => false,
+            Err(e) => {
+                warn!("{} rules: published bundle rejected: {e}", self.name);
+                false
+            }
+        }
+    }
+
+    /// Publishes every bundle pushed on `queue`
+    pub fn receive_from<R: RootContext>(&self, queue: Queue) -> Queue {
+        let rules = self.clone();
+        queue.on_receive(move |_: &mut R, _, data| {
+            if let Err(e) = rules.publish(&data) {
+                warn!("{} rules: pushed bundle rejected: {e}", rules.name);
+            }
+        })
+    }
+
+    /// Fetches a bundle with a GET of `path` and publishes it. The active version is sent in `x-rules-version`,
+    /// so the server may answer `304` when there is nothing new.
+    pub fn fetch(
+        &self,
+        upstream: Upstream<'_>,
+        authority: &str,
+        path: &str,
+        timeout: Duration,
+    ) -> Result<(), Status> {
+        let rules = self.clone();
+        let version = self.version().unwrap_or_default().to_string();
+        HttpCall {
+            upstream,
+            headers: vec![
+                (":method", b"GET"),
+                (":path", path.as_bytes()),
+                (":authority", authority.as_bytes()),
+                ("x-rules-version", version.as_bytes()),
+            ],
+            trailers: vec![],
+            body: None,
+            timeout: Some(timeout),
+            inherit_request_policy: false,
+            retry: None,
+            callback: Some(Box::new(move |_, response| {
+                let status = response.header(":status").unwrap_or_default();
+                if status == b"304" {
+                    return;
+                }
+                if status != b"200" {
+                    warn!(
+                        "{} rules: fetch failed with status {}",
+                        rules.name,
+                        String::from_utf8_lossy(&status)
+                    );
+                    Counter::define(format!("{}_rules_fetch_failed", rules.name)).increment(1);
+                    return;
+                }
+                let body = response.full_body().unwrap_or_default();
+                match rules.publish(&body) {
+                    Ok(_) | Err(RuleError::Stale { .. }) => (),
+                    Err(e) => warn!("{} rules: fetched bundle rejected: {e}", rules.name),
+                }
+            })),
+        }
+        .dispatch()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle() -> RuleBundle {
+        RuleBundle::new(3)
+            .rule(
+                Rule::new("ssn", RuleKind::Regex, r"\d{3}-\d{2}-\d{4}")
+                    .metadata("severity", "high"),
+            )
+            .rule(Rule::new("secret", RuleKind::LiteralIgnoreCase, "secret"))
+    }
+
+    #[test]
+    fn test_bundle_roundtrip() {
+        let encoded = bundle().encode();
+        assert_eq!(peek_version(&encoded), Ok(3));
+        assert_eq!(RuleBundle::decode(&encoded), Ok(bundle()));
+
+        let mut corrupt = encoded.clone();
+        corrupt[20] ^= 1;
+        assert_eq!(RuleBundle::decode(&corrupt), Err(RuleError::Checksum));
+        assert_eq!(
+            RuleBundle::decode(&encoded[..encoded.len() - 1]),
+            Err(RuleError::Checksum)
+        );
+        assert_eq!(RuleBundle::decode(b"nope"), Err(RuleError::Truncated));
+    }
+
+    #[test]
+    fn test_compiled_rules() {
+        let compiled = bundle().compile().unwrap();
+        let data = b"SECRET ssn 123-45-6789";
+        let found = compiled.scan(data);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].rule.id, "secret");
+        assert_eq!(found[1].rule.get("severity"), Some("high"));
+        assert_eq!(&data[found[1].range.clone()], b"123-45-6789");
+        assert_eq!(compiled.find_all(data), vec![0..6, 11..22]);
+
+        let invalid = RuleBundle::new(1).rule(Rule::new("bad", RuleKind::Regex, "("));
+        assert!(matches!(
+            invalid.compile(),
+            Err(RuleError::InvalidPattern { id, .. }) if id == "bad"
+        ));
+        assert!(check_newer(Some(3), 4).is_ok());
+        assert_eq!(
+            check_newer(Some(3), 3),
+            Err(RuleError::Stale {
+                active: 3,
+                received: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_anchored_rules() {
+        let bundle = bundle()
+            .rule(Rule::new("admin", RuleKind::Prefix, "/admin/"))
+            .rule(Rule::new("admin-audit", RuleKind::Prefix, "/admin/"))
+            .rule(Rule::new("health", RuleKind::Exact, "/healthz"))
+            .rule(Rule::new("root", RuleKind::Prefix, "/"));
+        let decoded = RuleBundle::decode(&bundle.encode()).unwrap();
+        assert_eq!(decoded, bundle);
+        let compiled = decoded.compile().unwrap();
+
+        let ids = |value: &[u8]| {
+            compiled
+                .match_value(value)
+                .into_iter()
+                .map(|x| x.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(b"/admin/users"), ["admin", "admin-audit", "root"]);
+        assert_eq!(ids(b"/healthz"), ["health", "root"]);
+        assert_eq!(ids(b"/healthz/ready"), ["root"]);
+        assert!(ids(b"admin").is_empty());
+
+        let found = compiled.scan(b"/admin/secret");
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[0].range, 0..1);
+        assert_eq!(found[3].rule.id, "secret");
+        assert!(compiled.is_match(b"/x"));
+    }
+}
@@ -0,0 +1,281 @@
+//! Envoy runtime keys, for plugins that honor the same runtime kill-switches operators use for native filters.
+//!
+//! Envoy does not expose its runtime to Wasm directly. Deployments surface keys through properties, i.e. a `filter_state`
+//! object set by another filter, or through a foreign function taking the key and returning its value.
+//! [`Runtime`] reads keys from the sources it is configured with, and [`RuntimeWatcher`] reports changes from `on_tick`.
+//!
+//! ```ignore
+//! let runtime = Runtime::new().property_prefix(["filter_state", "runtime"]);
+//! if !runtime.feature_enabled("leaksignal.scan_bodies", 100.0, request_hash) {
+//!     return FilterHeadersStatus::Continue;
+//! }
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{hostcalls, instant_now};
+
+/// Where [`Runtime`] reads keys from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeSource {
+    /// The property at this path followed by the key as a single segment
+    Property(Vec<String>),
+    /// A foreign function called with the key, returning the value
+    ForeignFunction(String),
+}
+
+/// Reads runtime keys from a list of sources. The first source with a value wins.
+#[derive(Clone, Debug, Default)]
+pub struct Runtime {
+    sources: Vec<RuntimeSource>,
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads keys from properties under `prefix`, i.e. `["filter_state", "runtime"]`
+    pub fn property_prefix<S: Into<String>>(mut self, prefix: impl IntoIterator<Item = S>) -> Self {
+        self.sources.push(RuntimeSource::Property(
+            prefix.into_iter().map(Into::into).collect(),
+        ));
+        self
+    }
+
+    /// Reads keys through a foreign function registered by the host
+    pub fn foreign_function(mut self, name: impl Into<String>) -> Self {
+        self.sources
+            .push(RuntimeSource::ForeignFunction(name.into()));
+        self
+    }
+
+    pub fn sources(&self) -> &[RuntimeSource] {
+        &self.sources
+    }
+
+    /// Raw value of a key. Missing sources and keys are not errors.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.sources.iter().find_map(|source| {
+            let raw = match source {
+                RuntimeSource::Property(prefix) => {
+                    hostcalls::get_property(prefix.iter().map(|x| &**x).chain([key]))
+                }
+                RuntimeSource::ForeignFunction(name) => {
+                    hostcalls::call_foreign_function(name, Some(key))
+                }
+            };
+            let raw = raw.ok().flatten()?;
+            Some(String::from_utf8_lossy(&raw).trim().to_string())
+        })
+    }
+
+    /// A boolean key, i.e. a `reloadable_features` flag. Accepts `true`/`false` and `1`/`0`.
+    pub fn get_bool(&self, key: &str, default: bool) -> bool {
+        self.get(key)
+            .and_then(|x| parse_bool(&x))
+            .unwrap_or(default)
+    }
+
+    pub fn get_int(&self, key: &str, default: i64) -> i64 {
+        self.get(key)
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(default)
+    }
+
+    /// A fractional key as a fraction between 0 and 1. Values are percentages (`25`, `25%`) or `numerator/denominator`.
+    /// `default_percent` applies if the key is missing or invalid.
+    pub fn fraction(&self, key: &str, default_percent: f64) -> f64 {
+        self.get(key)
+            .and_then(|x| parse_fraction(&x))
+            .unwrap_or(default_percent / 100.0)
+            .clamp(0.0, 1.0)
+    }
+
+    /// Whether a feature gated by a fractional key is enabled for `stable_value`, i.e. a hash of the request id.
+    /// Like Envoy's `feature_enabled`, the same value gets the same answer while the fraction is unchanged.
+    pub fn feature_enabled(&self, key: &str, default_percent: f64, stable_value: u64) -> bool {
+        const DENOMINATOR: u64 = 1_000_000;
+        let fraction = self.fraction(key, default_percent);
+        ((stable_value % DENOMINATOR) as f64) < fraction * DENOMINATOR as f64
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match &*value.to_ascii_lowercase() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_fraction(value: &str) -> Option<f64> {
+    if let Some((numerator, denominator)) = value.split_once('/') {
+        let denominator: f64 = denominator.trim().parse().ok()?;
+        if denominator <= 0.0 {
+            return None;
+        }
+        return Some(numerator.trim().parse::<f64>().ok()? / denominator);
+    }
+    let percent: f64 = value.trim_end_matches('%').trim().parse().ok()?;
+    percent.is_finite().then_some(percent / 100.0)
+}
+
+type WatchCallback = Box<dyn Fn(&str, Option<&str>)>;
+
+struct Watch {
+    key: String,
+    /// `None` until first read
+    value: Option<Option<String>>,
+    callback: WatchCallback,
+}
+
+/// Re-reads runtime keys at most once per interval and calls back on changes. Call [`RuntimeWatcher::poll`] from `on_tick`.
+/// The first poll calls back with the initial value of every key.
+pub struct RuntimeWatcher {
+    runtime: Runtime,
+    interval: Duration,
+    last_poll: Cell<Option<Instant>>,
+    watches: RefCell<Vec<Watch>>,
+}
+
+impl RuntimeWatcher {
+    /// Polls every 10 seconds by default
+    pub fn new(runtime: Runtime) -> Self {
+        Self {
+            runtime,
+            interval: Duration::from_secs(10),
+            last_poll: Cell::new(None),
+            watches: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Calls `callback` with the key and its new value whenever it changes
+    pub fn watch(
+        self,
+        key: impl Into<String>,
+        callback: impl Fn(&str, Option<&str>) + 'static,
+    ) -> Self {
+        self.watches.borrow_mut().push(Watch {
+            key: key.into(),
+            value: None,
+            callback: Box::new(callback),
+        });
+        self
+    }
+
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    /// Last value read for a watched key
+    pub fn value(&self, key: &str) -> Option<String> {
+        self.watches
+            .borrow()
+            .iter()
+            .find(|x| x.key == key)
+            .and_then(|x| x.value.clone().flatten())
+    }
+
+    /// Reads watched keys if the interval elapsed. Returns the number of keys that changed.
+    pub fn poll(&self) -> usize {
+        let now = instant_now();
+        if self
+            .last_poll
+            .get()
+            .is_some_and(|x| now.saturating_duration_since(x) < self.interval)
+        {
+            return 0;
+        }
+        let Ok(mut watches) = self.watches.try_borrow_mut() else {
+            return 0;
+        };
+        self.last_poll.set(Some(now));
+        let mut changed = 0;
+        for watch in watches.iter_mut() {
+            let value = self.runtime.get(&watch.key);
+            if watch.value.as_ref() == Some(&value) {
+                continue;
+            }
+            changed += 1;
+            (watch.callback)(&watch.key, value.as_deref());
+            watch.value = Some(value);
+        }
+        changed
+    }
+}
+
+impl fmt::Debug for RuntimeWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RuntimeWatcher")
+            .field("runtime", &self.runtime)
+            .field("interval", &self.interval)
+            .field(
+                "keys",
+                &self
+                    .watches
+                    .borrow()
+                    .iter()
+                    .map(|x| &x.key)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_fraction("25"), Some(0.25));
+        assert_eq!(parse_fraction("12.5%"), Some(0.125));
+        assert_eq!(parse_fraction("1/8"), Some(0.125));
+        assert_eq!(parse_fraction("1/0"), None);
+        assert_eq!(parse_bool("TRUE"), Some(true));
+        assert_eq!(parse_bool("0"), Some(false));
+        assert_eq!(parse_bool("yes"), None);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_watch() {
+        use std::rc::Rc;
+
+        crate::testing::reset_host();
+        let runtime = Runtime::new()
+            .foreign_function("get_runtime")
+            .property_prefix(["filter_state", "runtime"]);
+        crate::testing::set_property(["filter_state", "runtime", "envoy.scan"], "50%");
+        assert_eq!(runtime.fraction("envoy.scan", 100.0), 0.5);
+        assert!(runtime.feature_enabled("envoy.scan", 100.0, 1_000_001));
+        assert!(!runtime.feature_enabled("envoy.scan", 100.0, 700_000));
+        assert!(runtime.get_bool("envoy.missing", true));
+
+        let changes = Rc::new(RefCell::new(vec![]));
+        let watcher = RuntimeWatcher::new(runtime)
+            .interval(Duration::ZERO)
+            .watch("envoy.scan", {
+                let changes = changes.clone();
+                move |_, value| changes.borrow_mut().push(value.map(str::to_string))
+            });
+        assert_eq!(watcher.poll(), 1);
+        assert_eq!(watcher.poll(), 0);
+        crate::testing::set_property(["filter_state", "runtime", "envoy.scan"], "0");
+        assert_eq!(watcher.poll(), 1);
+        assert_eq!(
+            *changes.borrow(),
+            vec![Some("50%".to_string()), Some("0".to_string())]
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use std::{cell::Cell, time::Duration};
+
+use log::debug;
+
+use crate::{instant_now, Gauge, SharedData};
+
+/// Adjusts the share of requests an optional feature (i.e. body scanning, tracing or mirroring) runs on, so that its
+/// average cost per request stays within a latency budget.
+///
+/// Sampled calls report their latency with [`AdaptiveSampler::observe`] or [`AdaptiveSampler::run`]. On each
+/// [`AdaptiveSampler::adjust`], i.e. from `on_tick`, the rate is moved towards `budget / average latency` and stored in
+/// [`SharedData`], starting from the rate other VMs stored, so all VMs converge on one rate.
+/// The effective rate is exposed in parts per million as `{name}_sample_rate_ppm`.
+/// Latencies are averaged per instance, so HTTP contexts should observe into the sampler the root context adjusts,
+/// i.e. one in a `thread_local`.
+#[derive(Debug)]
+pub struct AdaptiveSampler {
+    name: String,
+    budget: Duration,
+    min_rate: f64,
+    max_rate: f64,
+    max_step: f64,
+    smoothing: f64,
+    rate: Cell<f64>,
+    credit: Cell<f64>,
+    average: Cell<Option<f64>>,
+    observed: Cell<u32>,
+}
+
+impl AdaptiveSampler {
+    /// Creates a sampler for the feature `name`, spending on average at most `budget` per request.
+    /// Starts sampling every request.
+    pub fn new(name: impl Into<String>, budget: Duration) -> Self {
+        Self {
+            name: name.into(),
+            budget,
+            min_rate: 0.001,
+            max_rate: 1.0,
+            max_step: 2.0,
+            smoothing: 0.2,
+            rate: Cell::new(1.0),
+            credit: Cell::new(0.0),
+            average: Cell::new(None),
+            observed: Cell::new(0),
+        }
+    }
+
+    /// Lowest rate the sampler backs off to. Default is 0.001.
+    pub fn min_rate(mut self, min_rate: f64) -> Self {
+        self.min_rate = min_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Highest rate the sampler recovers to. Default is 1.
+    pub fn max_rate(mut self, max_rate: f64) -> Self {
+        self.max_rate = max_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Rate before the first adjustment
+    pub fn initial_rate(self, rate: f64) -> Self {
+        self.rate.set(rate.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Largest factor the rate changes by in one adjustment. Default is 2.
+    pub fn max_step(mut self, max_step: f64) -> Self {
+        self.max_step = max_step.max(1.0);
+        self
+    }
+
+    /// Weight of new observations in the moving latency average. Default is 0.2.
+    pub fn smoothing(mut self, smoothing: f64) -> Self {
+        self.smoothing = smoothing.clamp(0.01, 1.0);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Current sampling rate in `0..=1`
+    pub fn rate(&self) -> f64 {
+        self.rate.get()
+    }
+
+    /// Moving average latency of sampled calls
+    pub fn average_latency(&self) -> Option<Duration> {
+        self.average.get().map(Duration::from_secs_f64)
+    }
+
+    /// Returns `true` if this request should be sampled. Sampled requests are spread evenly rather than randomly,
+    /// i.e. a rate of 0.25 samples every 4th request.
+    pub fn should_sample(&self) -> bool {
+        let credit = self.credit.get() + self.rate.get();
+        if credit >= 1.0 {
+            self.credit.set(credit - 1.0);
+            true
+        } else {
+            self.credit.set(credit);
+            false
+        }
+    }
+
+    /// Runs `f` if this request is sampled, recording its latency. Returns `None` if not sampled.
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> Option<T> {
+        if !self.should_sample() {
+            return None;
+        }
+        let start = instant_now();
+        let out = f();
+        self.observe(instant_now().saturating_duration_since(start));
+        Some(out)
+    }
+
+    /// Records the latency of a sampled call made outside of [`AdaptiveSampler::run`], i.e. one spanning callbacks.
+    pub fn observe(&self, elapsed: Duration) {
+        let elapsed = elapsed.as_secs_f64();
+        self.average.set(Some(match self.average.get() {
+            Some(average) => average + (elapsed - average) * self.smoothing,
+            None => elapsed,
+        }));
+        self.observed.set(self.observed.get() + 1);
+    }
+
+    fn key(&self) -> String {
+        format!("{}:sample-rate", self.name)
+    }
+
+    /// Recomputes the rate from latencies observed since the last adjustment and publishes it to the other VMs.
+    /// Without new observations, only picks up the rate published by other VMs.
+    pub fn adjust(&self) {
+        let data = SharedData::from_key(self.key());
+        for _ in 0..4 {
+            let (stored, cas) = data.get_with_cas();
+            let shared = stored
+                .and_then(|x| Some(f64::from_le_bytes(x.try_into().ok()?)))
+                .filter(|x| x.is_finite())
+                .unwrap_or(self.rate.get());
+            let average = self.average.get().filter(|_| self.observed.get() > 0);
+            let Some(average) = average else {
+                self.set_rate(shared);
+                return;
+            };
+            let rate = next_rate(
+                shared,
+                average,
+                self.budget.as_secs_f64(),
+                self.max_step,
+                self.min_rate,
+                self.max_rate,
+            );
+            let stored = match cas {
+                Some(cas) => data.set_with_cas(rate.to_le_bytes(), cas),
+                None => {
+                    data.set(rate.to_le_bytes());
+                    true
+                }
+            };
+            if stored {
+                debug!(
+                    "'{}' sample rate {shared:.4} -> {rate:.4} at {:?} average latency",
+                    self.name,
+                    Duration::from_secs_f64(average)
+                );
+                self.observed.set(0);
+                self.set_rate(rate);
+                return;
+            }
+        }
+    }
+
+    fn set_rate(&self, rate: f64) {
+        let rate = rate.clamp(self.min_rate, self.max_rate);
+        self.rate.set(rate);
+        Gauge::define(format!("{}_sample_rate_ppm", self.name)).record((rate * 1e6) as u64);
+    }
+}
+
+/// Rate at which `average` seconds per sampled call costs `budget` seconds per request,
+/// changed by at most `max_step` times from `current`
+fn next_rate(
+    current: f64,
+    average: f64,
+    budget: f64,
+    max_step: f64,
+    min_rate: f64,
+    max_rate: f64,
+) -> f64 {
+    let target = if average <= 0.0 {
+        max_rate
+    } else {
+        budget / average
+    };
+    let current = current.max(min_rate).max(f64::EPSILON);
+    target
+        .clamp(current / max_step, current * max_step)
+        .clamp(min_rate, max_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_rate() {
+        // 1ms per scan with a 100us budget converges on 10%, halving at most per step
+        let mut rate = 1.0;
+        for _ in 0..5 {
+            rate = next_rate(rate, 0.001, 0.0001, 2.0, 0.001, 1.0);
+        }
+        assert!((rate - 0.1).abs() < 1e-9);
+        assert_eq!(next_rate(0.1, 0.00001, 0.0001, 2.0, 0.001, 1.0), 0.2);
+        assert_eq!(next_rate(0.002, 10.0, 0.0001, 2.0, 0.001, 1.0), 0.001);
+    }
+
+    #[test]
+    fn test_should_sample() {
+        let sampler = AdaptiveSampler::new("test", Duration::from_millis(1)).initial_rate(0.25);
+        let sampled = (0..100).filter(|_| sampler.should_sample()).count();
+        assert_eq!(sampled, 25);
+    }
+}
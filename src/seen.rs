@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use crate::{
+    hash::Xxh64, now, time::unix_millis, BodyReader, Counter, HttpBodyControl, SharedData,
+};
+
+/// Remembers hashes of recently scanned bodies in [`SharedData`], so identical payloads (i.e. static assets) can skip scanning.
+/// Hashes are kept in a fixed number of slots, so mostly unique bodies don't grow shared data: a hash recorded in a
+/// taken slot replaces the one there.
+/// Lookups increment `{name}_seen_hit` or `{name}_seen_miss`; bodies over the size limit increment `{name}_seen_skipped`.
+#[derive(Clone, Debug)]
+pub struct SeenCache {
+    name: String,
+    ttl: Duration,
+    max_body_size: usize,
+    chunk_size: usize,
+    slots: u64,
+}
+
+/// Result of [`SeenCache::check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeenBody {
+    /// An identical body was recorded within the TTL, scanning can be skipped
+    Seen,
+    /// The body has not been recorded. Pass the hash to [`SeenCache::record`] once the body scanned clean.
+    Unseen(u64),
+    /// The body exceeds the size limit and was not hashed
+    TooLarge,
+}
+
+impl SeenCache {
+    /// Creates a cache named `name` with a 10 minute TTL for bodies up to 16 MiB, in 4096 slots.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ttl: Duration::from_secs(600),
+            max_body_size: 16 * 1024 * 1024,
+            chunk_size: 64 * 1024,
+            slots: 4096,
+        }
+    }
+
+    /// Number of shared data keys hashes are kept in, bounding the hashes remembered at once
+    pub fn slots(mut self, slots: u64) -> Self {
+        self.slots = slots.max(1);
+        self
+    }
+
+    /// How long a recorded hash is valid for
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Bodies larger than this are not hashed
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Size of chunks read from the host while hashing
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Streams `body` through XXH64. Returns `None` if it exceeds the size limit.
+    pub fn hash_body(&self, body: &impl HttpBodyControl) -> Option<u64> {
+        let reader = BodyReader::new(body);
+        if reader.size() > self.max_body_size {
+            return None;
+        }
+        let mut hasher = Xxh64::new(0);
+        reader.read_chunks(self.chunk_size, |chunk| {
+            hasher.update(chunk);
+            true
+        });
+        Some(hasher.digest())
+    }
+
+    /// Hashes the fully buffered `body` and looks it up
+    pub fn check(&self, body: &impl HttpBodyControl) -> SeenBody {
+        let Some(hash) = self.hash_body(body) else {
+            Counter::define(format!("{}_seen_skipped", self.name)).increment(1);
+            return SeenBody::TooLarge;
+        };
+        if self.contains(hash) {
+            SeenBody::Seen
+        } else {
+            SeenBody::Unseen(hash)
+        }
+    }
+
+    /// Returns `true` if `hash` was recorded and has not expired
+    pub fn contains(&self, hash: u64) -> bool {
+        let seen = SharedData::from_key(self.key(hash))
+            .get()
+            .and_then(|value| decode_slot(&value))
+            .is_some_and(|(slot_hash, expiry)| slot_hash == hash && expiry > unix_millis(now()));
+        let metric = if seen { "hit" } else { "miss" };
+        Counter::define(format!("{}_seen_{metric}", self.name)).increment(1);
+        seen
+    }
+
+    /// Records `hash` as scanned, for the configured TTL, replacing the hash in its slot
+    pub fn record(&self, hash: u64) {
+        let expiry = unix_millis(now()).saturating_add(self.ttl.as_millis() as u64);
+        let mut value = hash.to_le_bytes().to_vec();
+        value.extend_from_slice(&expiry.to_le_bytes());
+        SharedData::from_key(self.key(hash)).set(value);
+    }
+
+    fn key(&self, hash: u64) -> String {
+        format!("{}_seen:{:x}", self.name, hash % self.slots)
+    }
+}
+
+/// Hash and expiry of a slot
+fn decode_slot(value: &[u8]) -> Option<(u64, u64)> {
+    let (hash, expiry) = value.split_first_chunk::<8>()?;
+    Some((
+        u64::from_le_bytes(*hash),
+        u64::from_le_bytes(expiry.try_into().ok()?),
+    ))
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::{
+        hostcalls::BufferType,
+        property::envoy::Attributes,
+        testing::{host::with_host, metric, reset_host, set_time},
+        RequestBody,
+    };
+
+    fn body(data: &[u8]) -> RequestBody {
+        with_host(|host| {
+            host.buffers
+                .insert(BufferType::HttpRequestBody as u32, data.to_vec())
+        });
+        RequestBody {
+            body_size: data.len(),
+            end_of_stream: true,
+            attributes: Attributes::get(),
+        }
+    }
+
+    #[test]
+    fn test_seen_cache() {
+        reset_host();
+        set_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let cache = SeenCache::new("scan").chunk_size(3).max_body_size(16);
+        let hash = cache.hash_body(&body(b"hello world")).unwrap();
+        assert_eq!(hash, Xxh64::hash(0, b"hello world"));
+        assert_eq!(cache.check(&body(b"hello world")), SeenBody::Unseen(hash));
+        cache.record(hash);
+        assert_eq!(cache.check(&body(b"hello world")), SeenBody::Seen);
+        assert_eq!(
+            cache.check(&body(b"hello there")),
+            SeenBody::Unseen(Xxh64::hash(0, b"hello there"))
+        );
+        assert_eq!(cache.check(&body(&[0; 17])), SeenBody::TooLarge);
+        assert_eq!(metric("scan_seen_hit"), Some(1));
+        assert_eq!(metric("scan_seen_miss"), Some(2));
+        assert_eq!(metric("scan_seen_skipped"), Some(1));
+
+        set_time(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_601));
+        assert_eq!(cache.check(&body(b"hello world")), SeenBody::Unseen(hash));
+    }
+
+    #[test]
+    fn test_seen_cache_slots() {
+        reset_host();
+        let cache = SeenCache::new("scan").slots(2);
+        for hash in 0..100 {
+            cache.record(hash);
+        }
+        assert_eq!(with_host(|host| host.shared_data.len()), 2);
+        // a later hash in the same slot replaces an earlier one
+        assert!(cache.contains(99));
+        assert!(cache.contains(98));
+        assert!(!cache.contains(97));
+    }
+}
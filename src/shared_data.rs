@@ -1,4 +1,17 @@
-use crate::{check_concern, hostcalls, Status};
+use std::{
+    cell::Cell,
+    marker::PhantomData,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    check_concern,
+    hash::Xxh64,
+    hostcalls,
+    json::Value,
+    time::{unix_millis, unix_nanos},
+    Status,
+};
 
 /// A VM ID local atomic field. Any WASM VM in the same VM ID can read or write to any key in it's VM ID.
 /// SharedData cannot cross VM IDs.
@@ -49,3 +62,684 @@ impl<T: AsRef<str>> SharedData<T> {
         }
     }
 }
+
+/// The byte encoding of a [`SharedValue`]
+pub trait SharedCodec: Sized {
+    fn encode(&self) -> Vec<u8>;
+
+    fn decode(value: &[u8]) -> Option<Self>;
+}
+
+macro_rules! le_codec {
+    ($($t:ty),*) => {
+        $(
+            /// Little endian, as used by [`ShardedCounter`]
+            impl SharedCodec for $t {
+                fn encode(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode(value: &[u8]) -> Option<Self> {
+                    Some(Self::from_le_bytes(value.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+le_codec!(u32, u64, i32, i64);
+
+impl SharedCodec for bool {
+    fn encode(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        match value {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl SharedCodec for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        Some(value.to_vec())
+    }
+}
+
+impl SharedCodec for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        String::from_utf8(value.to_vec()).ok()
+    }
+}
+
+/// JSON text, for structured values
+impl SharedCodec for Value {
+    fn encode(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        Value::parse(value).ok()
+    }
+}
+
+/// A protobuf message stored in its wire encoding
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProtoValue<M>(pub M);
+
+impl<M: prost::Message + Default> SharedCodec for ProtoValue<M> {
+    fn encode(&self) -> Vec<u8> {
+        self.0.encode_to_vec()
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        M::decode(value).ok().map(ProtoValue)
+    }
+}
+
+/// A typed [`SharedData`] key. [`SharedValue::update`] runs the get, modify and CAS set loop,
+/// so concurrent updates from other workers are never lost.
+///
+/// A key that was never set has no CAS number, so workers racing to set it first cannot detect each other.
+/// Where that matters, set a first value with [`SharedValue::init`], i.e. from `on_vm_start`.
+pub struct SharedValue<T> {
+    data: SharedData<String>,
+    max_retries: u32,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: SharedCodec> SharedValue<T> {
+    /// References the value at `key`
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            data: SharedData::from_key(key.into()),
+            max_retries: 16,
+            _value: PhantomData,
+        }
+    }
+
+    /// CAS attempts of [`SharedValue::update`] before giving up. Default is 16.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries.max(1);
+        self
+    }
+
+    pub fn key(&self) -> &str {
+        &self.data.0
+    }
+
+    /// The current value. `None` if unset or not decodable as `T`.
+    pub fn read(&self) -> Option<T> {
+        T::decode(&self.data.get()?)
+    }
+
+    /// Unconditionally replaces the value
+    pub fn write(&self, value: &T) {
+        self.data.set(value.encode());
+    }
+
+    /// Sets `value` if the key was never set. Returns `false` if it was.
+    pub fn init(&self, value: &T) -> bool {
+        self.update_encoded(|current| match current {
+            Some(_) => None,
+            None => Some(value.encode()),
+        })
+        .is_some()
+    }
+
+    /// Replaces the value with `f` of the current value, retrying with the latest value whenever another worker
+    /// updated it in between. `f` may run several times. Returns the value written, or `None` if the key stayed contended.
+    pub fn update(&self, mut f: impl FnMut(Option<T>) -> T) -> Option<T> {
+        let mut written = None;
+        self.update_encoded(|current| {
+            let value = f(current);
+            let encoded = value.encode();
+            written = Some(value);
+            Some(encoded)
+        })?;
+        written
+    }
+
+    /// `None` if `f` declined to write or the key stayed contended
+    fn update_encoded(&self, mut f: impl FnMut(Option<T>) -> Option<Vec<u8>>) -> Option<()> {
+        for _ in 0..self.max_retries {
+            let (value, cas) = self.data.get_with_cas();
+            let encoded = f(value.as_deref().and_then(T::decode))?;
+            let updated = match cas {
+                Some(cas) => self.data.set_with_cas(encoded, cas),
+                None => {
+                    self.data.set(encoded);
+                    true
+                }
+            };
+            if updated {
+                return Some(());
+            }
+        }
+        None
+    }
+}
+
+impl<T> Clone for SharedValue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            max_retries: self.max_retries,
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for SharedValue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedValue")
+            .field("key", &self.data.0)
+            .field("max_retries", &self.max_retries)
+            .finish()
+    }
+}
+
+thread_local! {
+    static WORKER_SEED: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Seed identifying this VM/worker, used to pick its home shard
+pub(crate) fn worker_seed() -> u64 {
+    if let Some(seed) = WORKER_SEED.get() {
+        return seed;
+    }
+    let mut hasher = Xxh64::new(0);
+    let nanos = unix_nanos(crate::now());
+    hasher.update(&nanos.to_le_bytes());
+    hasher.update(format!("{:?}", std::thread::current().id()).as_bytes());
+    let seed = hasher.digest();
+    WORKER_SEED.set(Some(seed));
+    seed
+}
+
+/// A counter in [`SharedData`] split across `shards` keys. Each worker updates its own home shard,
+/// moving to the next one when CAS keeps failing, and reads sum all shards.
+/// Avoids CAS retry storms when many workers count the same thing, i.e. for rate limits and quotas.
+#[derive(Clone, Debug)]
+pub struct ShardedCounter {
+    name: String,
+    shards: u32,
+    max_retries: u32,
+}
+
+impl ShardedCounter {
+    /// Creates or references the counter `name` split across `shards` keys. Every worker must use the same shard count.
+    pub fn new(name: impl Into<String>, shards: u32) -> Self {
+        Self {
+            name: name.into(),
+            shards: shards.max(1),
+            max_retries: 4,
+        }
+    }
+
+    /// CAS attempts on one shard before moving to the next. Default is 4.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries.max(1);
+        self
+    }
+
+    fn key(&self, shard: u32) -> String {
+        format!("{}:{shard}", self.name)
+    }
+
+    fn decode(value: Option<Vec<u8>>) -> i64 {
+        value
+            .and_then(|x| x.try_into().ok())
+            .map(i64::from_le_bytes)
+            .unwrap_or_default()
+    }
+
+    /// Adds `delta`, returning the new value of the updated shard, or `None` if every shard stayed contended
+    pub fn add(&self, delta: i64) -> Option<i64> {
+        let home = (worker_seed() % self.shards as u64) as u32;
+        for offset in 0..self.shards {
+            let data = SharedData::from_key(self.key((home + offset) % self.shards));
+            for _ in 0..self.max_retries {
+                let (value, cas) = data.get_with_cas();
+                let value = Self::decode(value).wrapping_add(delta);
+                let updated = match cas {
+                    Some(cas) => data.set_with_cas(value.to_le_bytes(), cas),
+                    None => {
+                        data.set(value.to_le_bytes());
+                        true
+                    }
+                };
+                if updated {
+                    return Some(value);
+                }
+            }
+        }
+        None
+    }
+
+    /// Sum of all shards
+    pub fn get(&self) -> i64 {
+        (0..self.shards)
+            .map(|shard| Self::decode(SharedData::from_key(self.key(shard)).get()))
+            .fold(0, i64::wrapping_add)
+    }
+
+    /// Sets every shard to 0. Updates racing with a reset may be lost.
+    pub fn reset(&self) {
+        for shard in 0..self.shards {
+            SharedData::from_key(self.key(shard)).set(0i64.to_le_bytes());
+        }
+    }
+}
+
+/// A value with an optional expiry. Encoded as the expiry in milliseconds since the Unix epoch (0 if none, little endian)
+/// followed by the value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expiring<T> {
+    pub value: T,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl<T> Expiring<T> {
+    /// Expires `ttl` from now, or never if `None`
+    pub fn new(value: T, ttl: Option<Duration>) -> Self {
+        Self {
+            value,
+            expires_at: ttl.map(|ttl| crate::now() + ttl),
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+
+    /// The value, unless expired
+    pub fn live(self, now: SystemTime) -> Option<T> {
+        (!self.is_expired(now)).then_some(self.value)
+    }
+}
+
+/// Like [`unix_millis`], but never zero, which encodes "no expiry"
+fn epoch_millis(time: SystemTime) -> u64 {
+    unix_millis(time).max(1)
+}
+
+impl<T: SharedCodec> SharedCodec for Expiring<T> {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = self
+            .expires_at
+            .map_or(0, epoch_millis)
+            .to_le_bytes()
+            .to_vec();
+        out.extend_from_slice(&self.value.encode());
+        out
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        let (expiry, value) = value.split_at_checked(8)?;
+        let expiry = u64::from_le_bytes(expiry.try_into().ok()?);
+        Some(Self {
+            value: T::decode(value)?,
+            expires_at: (expiry != 0).then(|| UNIX_EPOCH + Duration::from_millis(expiry)),
+        })
+    }
+}
+
+/// A counter in a single [`SharedData`] key, updated with CAS so concurrent increments are never lost.
+/// With a TTL the count restarts from zero once the TTL elapsed since the first increment, i.e. a fixed rate limit window.
+/// Where many workers increment the same counter at once, [`ShardedCounter`] retries less.
+#[derive(Clone, Debug)]
+pub struct SharedCounter {
+    value: SharedValue<Expiring<i64>>,
+    ttl: Option<Duration>,
+}
+
+impl SharedCounter {
+    /// Creates or references the counter at `key`
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            value: SharedValue::new(key),
+            ttl: None,
+        }
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// CAS attempts before giving up. Default is 16.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.value = self.value.max_retries(max_retries);
+        self
+    }
+
+    pub fn key(&self) -> &str {
+        self.value.key()
+    }
+
+    /// Adds `delta`, returning the new value, or `None` if the key stayed contended
+    pub fn add(&self, delta: i64) -> Option<i64> {
+        let now = crate::now();
+        self.value
+            .update(|current| match current.filter(|x| !x.is_expired(now)) {
+                Some(current) => Expiring {
+                    value: current.value.wrapping_add(delta),
+                    expires_at: current.expires_at,
+                },
+                None => Expiring {
+                    value: delta,
+                    expires_at: self.ttl.map(|ttl| now + ttl),
+                },
+            })
+            .map(|x| x.value)
+    }
+
+    pub fn increment(&self) -> Option<i64> {
+        self.add(1)
+    }
+
+    /// The current count, 0 if unset or expired
+    pub fn get(&self) -> i64 {
+        self.value
+            .read()
+            .and_then(|x| x.live(crate::now()))
+            .unwrap_or_default()
+    }
+
+    /// When the current window ends, with a TTL
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.value.read()?.expires_at
+    }
+
+    /// Restarts the count from zero. Updates racing with a reset may be lost.
+    pub fn reset(&self) {
+        self.value.write(&Expiring {
+            value: 0,
+            expires_at: Some(UNIX_EPOCH),
+        });
+    }
+}
+
+/// Entries of one [`SharedMap`] shard, with their expiry in milliseconds since the Unix epoch (0 if none)
+#[derive(Default)]
+struct Bucket(Vec<(String, u64, Vec<u8>)>);
+
+impl SharedCodec for Bucket {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for (key, expiry, value) in &self.0 {
+            out.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            out.extend_from_slice(key.as_bytes());
+            out.extend_from_slice(&expiry.to_le_bytes());
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    fn decode(mut value: &[u8]) -> Option<Self> {
+        fn take<'a>(value: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            let (out, rest) = value.split_at_checked(len)?;
+            *value = rest;
+            Some(out)
+        }
+        let mut entries = vec![];
+        while !value.is_empty() {
+            let len = u32::from_le_bytes(take(&mut value, 4)?.try_into().ok()?) as usize;
+            let key = String::from_utf8(take(&mut value, len)?.to_vec()).ok()?;
+            let expiry = u64::from_le_bytes(take(&mut value, 8)?.try_into().ok()?);
+            let len = u32::from_le_bytes(take(&mut value, 4)?.try_into().ok()?) as usize;
+            entries.push((key, expiry, take(&mut value, len)?.to_vec()));
+        }
+        Some(Self(entries))
+    }
+}
+
+impl Bucket {
+    fn live(&self, key: &str, now: u64) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(x, expiry, _)| x == key && (*expiry == 0 || *expiry > now))
+            .map(|(_, _, value)| &**value)
+    }
+
+    /// Drops expired entries and `key`
+    fn prune(&mut self, key: &str, now: u64) {
+        self.0
+            .retain(|(x, expiry, _)| x != key && (*expiry == 0 || *expiry > now));
+    }
+}
+
+/// A map in [`SharedData`], split across `shards` keys under a prefix. Keys hash to a shard, which holds all of its
+/// entries in one value updated with CAS, so writes to different shards never contend.
+///
+/// With a TTL, entries expire that long after they were last written. Expired entries are dropped whenever their
+/// shard is written, so the memory used stays bounded by the live entries. Every worker must use the same shard count.
+pub struct SharedMap<T> {
+    prefix: String,
+    shards: u32,
+    ttl: Option<Duration>,
+    max_retries: u32,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: SharedCodec> SharedMap<T> {
+    /// Creates or references the map under `prefix`, split across `shards` keys
+    pub fn new(prefix: impl Into<String>, shards: u32) -> Self {
+        Self {
+            prefix: prefix.into(),
+            shards: shards.max(1),
+            ttl: None,
+            max_retries: 16,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// CAS attempts per write before giving up. Default is 16.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries.max(1);
+        self
+    }
+
+    fn shard(&self, shard: u32) -> SharedValue<Bucket> {
+        SharedValue::new(format!("{}:{shard}", self.prefix)).max_retries(self.max_retries)
+    }
+
+    fn shard_of(&self, key: &str) -> SharedValue<Bucket> {
+        self.shard((Xxh64::hash(0, key.as_bytes()) % self.shards as u64) as u32)
+    }
+
+    fn expiry(&self, now: SystemTime) -> u64 {
+        self.ttl.map_or(0, |ttl| epoch_millis(now + ttl))
+    }
+
+    /// The live value of `key`
+    pub fn get(&self, key: &str) -> Option<T> {
+        let now = epoch_millis(crate::now());
+        T::decode(self.shard_of(key).read()?.live(key, now)?)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Sets `key` to `value`. Returns `false` if the shard stayed contended.
+    pub fn insert(&self, key: &str, value: &T) -> bool {
+        self.write_entry(key, |_| Some(value.encode()))
+    }
+
+    /// Replaces the value of `key` with `f` of its live value, retrying whenever another worker wrote the shard in between.
+    /// `f` may run several times. Returns the value written, or `None` if the shard stayed contended.
+    pub fn update(&self, key: &str, mut f: impl FnMut(Option<T>) -> T) -> Option<T> {
+        let mut written = None;
+        let updated = self.write_entry(key, |current| {
+            let value = f(current);
+            let encoded = value.encode();
+            written = Some(value);
+            Some(encoded)
+        });
+        written.filter(|_| updated)
+    }
+
+    /// Removes `key`. Returns `false` if the shard stayed contended.
+    pub fn remove(&self, key: &str) -> bool {
+        self.write_entry(key, |_| None)
+    }
+
+    /// Rewrites the shard of `key` with the entry `f` returns, or without it for `None`, dropping expired entries
+    fn write_entry(&self, key: &str, mut f: impl FnMut(Option<T>) -> Option<Vec<u8>>) -> bool {
+        let now = crate::now();
+        let millis = epoch_millis(now);
+        self.shard_of(key)
+            .update(|bucket| {
+                let mut bucket = bucket.unwrap_or_default();
+                let current = bucket.live(key, millis).and_then(T::decode);
+                let value = f(current);
+                bucket.prune(key, millis);
+                if let Some(value) = value {
+                    bucket.0.push((key.to_string(), self.expiry(now), value));
+                }
+                bucket
+            })
+            .is_some()
+    }
+
+    /// All live entries, reading every shard
+    pub fn entries(&self) -> Vec<(String, T)> {
+        let now = epoch_millis(crate::now());
+        (0..self.shards)
+            .filter_map(|shard| self.shard(shard).read())
+            .flat_map(|bucket| bucket.0)
+            .filter(|(_, expiry, _)| *expiry == 0 || *expiry > now)
+            .filter_map(|(key, _, value)| Some((key, T::decode(&value)?)))
+            .collect()
+    }
+
+    /// Number of live entries, reading every shard
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for SharedMap<T> {
+    fn clone(&self) -> Self {
+        Self {
+            prefix: self.prefix.clone(),
+            shards: self.shards,
+            ttl: self.ttl,
+            max_retries: self.max_retries,
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for SharedMap<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedMap")
+            .field("prefix", &self.prefix)
+            .field("shards", &self.shards)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codecs() {
+        assert_eq!(u64::decode(&7u64.encode()), Some(7));
+        assert_eq!(i32::decode(&[1, 2]), None);
+        assert_eq!(bool::decode(&true.encode()), Some(true));
+        let value = Value::parse(r#"{"a":[1,2]}"#).unwrap();
+        assert_eq!(Value::decode(&value.encode()), Some(value));
+        let duration = ProtoValue(prost_types::Duration {
+            seconds: 3,
+            nanos: 0,
+        });
+        assert_eq!(ProtoValue::decode(&duration.encode()), Some(duration));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_shared_value() {
+        use crate::testing::{host::with_host, reset_host};
+
+        reset_host();
+        let value = SharedValue::<u64>::new("requests");
+        assert_eq!(value.read(), None);
+        assert!(value.init(&1));
+        assert!(!value.init(&5));
+        assert_eq!(value.update(|x| x.unwrap_or_default() + 1), Some(2));
+
+        // another worker writes while `f` runs, so the first attempt must be retried
+        let mut attempts = 0;
+        let updated = value.update(|x| {
+            attempts += 1;
+            if attempts == 1 {
+                with_host(|host| host.shared_data.get_mut("requests").unwrap().1 += 1);
+            }
+            x.unwrap_or_default() * 10
+        });
+        assert_eq!(updated, Some(20));
+        assert_eq!(attempts, 2);
+        assert_eq!(value.read(), Some(20));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_counter_and_map() {
+        use crate::testing::{reset_host, set_time};
+
+        reset_host();
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        set_time(start);
+        let counter = SharedCounter::new("hits").ttl(Duration::from_secs(60));
+        assert_eq!(counter.get(), 0);
+        assert_eq!(counter.increment(), Some(1));
+        assert_eq!(counter.add(4), Some(5));
+        assert_eq!(counter.expires_at(), Some(start + Duration::from_secs(60)));
+        set_time(start + Duration::from_secs(60));
+        assert_eq!(counter.get(), 0);
+        assert_eq!(counter.increment(), Some(1));
+        counter.reset();
+        assert_eq!(counter.get(), 0);
+
+        let map = SharedMap::<u64>::new("sessions", 4).ttl(Duration::from_secs(10));
+        set_time(start);
+        assert!(map.insert("a", &1));
+        assert!(map.insert("b", &2));
+        assert_eq!(map.update("a", |x| x.unwrap_or_default() + 10), Some(11));
+        assert_eq!(map.get("a"), Some(11));
+        let mut entries = map.entries();
+        entries.sort();
+        assert_eq!(entries, vec![("a".to_string(), 11), ("b".to_string(), 2)]);
+        assert!(map.remove("b"));
+        assert!(!map.contains_key("b"));
+        set_time(start + Duration::from_secs(10));
+        assert_eq!(map.get("a"), None);
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+//! Snapshots of VM-local root context state in [`SharedData`], restored by the next VM of the VM ID.
+//!
+//! State kept in linear memory, i.e. caches in a `RefCell`, is lost when a VM is replaced: on a crash, on a plugin update,
+//! or when Envoy recreates VMs on configuration change. Shared data outlives the VMs, so state saved there with [`save`]
+//! can be [`restore`]d from `on_vm_start` of the next VM. Save from `on_done`, and periodically from `on_tick`, as a
+//! crashed VM never gets `on_done`.
+//!
+//! SDK state already kept in shared data, i.e. rate-limit buckets, rule bundles and [`crate::SeenCache`] entries,
+//! survives VM restarts without snapshots. Shared data lives in the Envoy process: after a hot restart, the new process
+//! starts with empty shared data and no snapshot.
+//!
+//! Snapshots carry the version of the state they were saved with, so newer plugins can migrate older state, and a
+//! checksum, so corrupted snapshots are rejected instead of restored. All VMs of the VM ID share a key: the last VM saving wins.
+//!
+//! ```ignore
+//! fn on_vm_start(&mut self, _: Option<Vec<u8>>) -> bool {
+//!     if let Err(e) = snapshot::restore_within("plugin:cache", &mut self.cache, Duration::from_secs(300)) {
+//!         info!("starting with an empty cache: {e}");
+//!     }
+//!     true
+//! }
+//!
+//! fn on_done(&mut self) -> bool {
+//!     snapshot::save("plugin:cache", &self.cache);
+//!     true
+//! }
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{hash::Xxh64, now, SharedData};
+
+const MAGIC: &[u8; 4] = b"PSSN";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + 8;
+
+/// State that can be saved to a snapshot and restored from one
+pub trait Snapshot {
+    /// Version of the serialized state. Bump it when the encoding changes.
+    const VERSION: u32;
+
+    fn save(&self) -> Vec<u8>;
+
+    /// Replaces the state with a snapshot saved with `version`, at most [`Snapshot::VERSION`]
+    fn restore(&mut self, version: u32, data: &[u8]) -> Result<(), String>;
+}
+
+/// A snapshot that was restored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Restored {
+    /// Version of the state when saved
+    pub version: u32,
+    pub saved_at: SystemTime,
+}
+
+/// Error produced when restoring a snapshot. The state is left unchanged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// No snapshot was saved under the key
+    Missing,
+    /// The snapshot ended early
+    Truncated,
+    /// The data is not a snapshot
+    BadMagic,
+    /// The snapshot was written by a newer, unknown format
+    UnsupportedFormat(u8),
+    /// The trailing checksum doesn't match the contents
+    Checksum,
+    /// The state was saved by a newer plugin
+    NewerVersion { saved: u32, supported: u32 },
+    /// The snapshot is older than the accepted age
+    Stale(Duration),
+    /// [`Snapshot::restore`] failed
+    Rejected(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Missing => write!(f, "no snapshot"),
+            SnapshotError::Truncated => write!(f, "snapshot truncated"),
+            SnapshotError::BadMagic => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedFormat(x) => write!(f, "unsupported snapshot format {x}"),
+            SnapshotError::Checksum => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::NewerVersion { saved, supported } => write!(
+                f,
+                "snapshot version {saved} is newer than the supported version {supported}"
+            ),
+            SnapshotError::Stale(age) => write!(f, "snapshot is stale, saved {age:?} ago"),
+            SnapshotError::Rejected(e) => write!(f, "snapshot rejected: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Frames `payload` as a snapshot of state `version` saved at `saved_at`
+pub fn encode(version: u32, saved_at: SystemTime, payload: &[u8]) -> Vec<u8> {
+    let saved_at = saved_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len() + 8);
+    out.extend_from_slice(MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&version.to_le_bytes());
+    out.extend_from_slice(&saved_at.to_le_bytes());
+    out.extend_from_slice(payload);
+    out.extend_from_slice(&Xxh64::hash(0, &out).to_le_bytes());
+    out
+}
+
+/// Checks the framing and checksum of a snapshot, returning its version, save time and payload
+pub fn decode(data: &[u8]) -> Result<(u32, SystemTime, &[u8]), SnapshotError> {
+    if data.len() < HEADER_LEN + 8 {
+        return Err(SnapshotError::Truncated);
+    }
+    if &data[..4] != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    if data[4] != FORMAT_VERSION {
+        return Err(SnapshotError::UnsupportedFormat(data[4]));
+    }
+    let (body, checksum) = data.split_at(data.len() - 8);
+    if Xxh64::hash(0, body).to_le_bytes() != checksum {
+        return Err(SnapshotError::Checksum);
+    }
+    let version = u32::from_le_bytes(body[5..9].try_into().unwrap());
+    let saved_at = u64::from_le_bytes(body[9..17].try_into().unwrap());
+    Ok((
+        version,
+        UNIX_EPOCH + Duration::from_millis(saved_at),
+        &body[HEADER_LEN..],
+    ))
+}
+
+/// Saves `state` under `key`
+pub fn save<S: Snapshot>(key: &str, state: &S) {
+    SharedData::from_key(key).set(encode(S::VERSION, now(), &state.save()));
+}
+
+/// Restores `state` from the snapshot under `key`, of any age
+pub fn restore<S: Snapshot>(key: &str, state: &mut S) -> Result<Restored, SnapshotError> {
+    restore_inner(key, state, None)
+}
+
+/// Restores `state` from the snapshot under `key` if it was saved within `max_age`, i.e. for caches that would be
+/// mostly expired anyway
+pub fn restore_within<S: Snapshot>(
+    key: &str,
+    state: &mut S,
+    max_age: Duration,
+) -> Result<Restored, SnapshotError> {
+    restore_inner(key, state, Some(max_age))
+}
+
+fn restore_inner<S: Snapshot>(
+    key: &str,
+    state: &mut S,
+    max_age: Option<Duration>,
+) -> Result<Restored, SnapshotError> {
+    let data = SharedData::from_key(key)
+        .get()
+        .filter(|x| !x.is_empty())
+        .ok_or(SnapshotError::Missing)?;
+    let (version, saved_at, payload) = decode(&data)?;
+    if version > S::VERSION {
+        return Err(SnapshotError::NewerVersion {
+            saved: version,
+            supported: S::VERSION,
+        });
+    }
+    let age = now().duration_since(saved_at).unwrap_or_default();
+    if max_age.is_some_and(|x| age > x) {
+        return Err(SnapshotError::Stale(age));
+    }
+    state
+        .restore(version, payload)
+        .map_err(SnapshotError::Rejected)?;
+    Ok(Restored { version, saved_at })
+}
+
+/// Drops the snapshot under `key`, i.e. once restored by a plugin that must not restore it twice
+pub fn discard(key: &str) {
+    SharedData::from_key(key).set([]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_framing() {
+        let saved_at = UNIX_EPOCH + Duration::from_secs(1000);
+        let encoded = encode(2, saved_at, b"state");
+        assert_eq!(decode(&encoded), Ok((2, saved_at, &b"state"[..])));
+
+        let mut corrupt = encoded.clone();
+        corrupt[HEADER_LEN] ^= 1;
+        assert_eq!(decode(&corrupt), Err(SnapshotError::Checksum));
+        assert_eq!(decode(&encoded[..10]), Err(SnapshotError::Truncated));
+        assert_eq!(decode(&[0; 32]), Err(SnapshotError::BadMagic));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_save_restore() {
+        use crate::testing::{reset_host, set_time};
+
+        #[derive(Debug, Default, PartialEq)]
+        struct Cache(Vec<String>);
+
+        impl Snapshot for Cache {
+            const VERSION: u32 = 2;
+
+            fn save(&self) -> Vec<u8> {
+                self.0.join("\n").into_bytes()
+            }
+
+            fn restore(&mut self, version: u32, data: &[u8]) -> Result<(), String> {
+                let data = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+                self.0 = data
+                    .split('\n')
+                    // version 1 stored upper case entries
+                    .map(|x| match version {
+                        1 => x.to_lowercase(),
+                        _ => x.to_string(),
+                    })
+                    .collect();
+                Ok(())
+            }
+        }
+
+        reset_host();
+        set_time(UNIX_EPOCH + Duration::from_secs(1000));
+        let mut cache = Cache::default();
+        assert_eq!(restore("cache", &mut cache), Err(SnapshotError::Missing));
+
+        save("cache", &Cache(vec!["a".to_string(), "b".to_string()]));
+        set_time(UNIX_EPOCH + Duration::from_secs(1060));
+        assert_eq!(
+            restore_within("cache", &mut cache, Duration::from_secs(30)),
+            Err(SnapshotError::Stale(Duration::from_secs(60)))
+        );
+        assert_eq!(cache, Cache::default());
+        let restored = restore("cache", &mut cache).unwrap();
+        assert_eq!(restored.version, 2);
+        assert_eq!(cache.0, ["a", "b"]);
+
+        SharedData::from_key("cache").set(encode(1, now(), b"A\nC"));
+        restore("cache", &mut cache).unwrap();
+        assert_eq!(cache.0, ["a", "c"]);
+        SharedData::from_key("cache").set(encode(3, now(), b""));
+        assert_eq!(
+            restore("cache", &mut cache),
+            Err(SnapshotError::NewerVersion {
+                saved: 3,
+                supported: 2
+            })
+        );
+        discard("cache");
+        assert_eq!(restore("cache", &mut cache), Err(SnapshotError::Missing));
+    }
+}
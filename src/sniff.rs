@@ -0,0 +1,386 @@
+//! Protocol detection from the first bytes of a connection, for stream (L4) filters.
+
+use std::fmt;
+
+use crate::{property::set_property, stream::accumulate_prefix, StreamDataControl};
+
+/// A protocol recognized by [`ProtocolSniffer`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// TLS, starting with a ClientHello
+    Tls,
+    Http1,
+    /// HTTP/2 with prior knowledge (connection preface)
+    Http2,
+    Ssh,
+    Rdp,
+    /// MySQL, detected from the server greeting
+    MySql,
+    Postgres,
+    Unknown,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tls => "tls",
+            Protocol::Http1 => "http/1",
+            Protocol::Http2 => "http/2",
+            Protocol::Ssh => "ssh",
+            Protocol::Rdp => "rdp",
+            Protocol::MySql => "mysql",
+            Protocol::Postgres => "postgres",
+            Protocol::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const HTTP1_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
+/// Postgres StartupMessage protocol 3.0, SSLRequest and GSSENCRequest codes
+const POSTGRES_CODES: &[[u8; 4]] = &[
+    [0x00, 0x03, 0x00, 0x00],
+    [0x04, 0xd2, 0x16, 0x2f],
+    [0x04, 0xd2, 0x16, 0x30],
+];
+
+/// Result of matching a signature against a possibly incomplete prefix
+#[derive(PartialEq)]
+enum Signature {
+    Match,
+    Partial,
+    Mismatch,
+}
+
+fn literal(data: &[u8], signature: &[u8]) -> Signature {
+    if data.len() >= signature.len() {
+        if data.starts_with(signature) {
+            Signature::Match
+        } else {
+            Signature::Mismatch
+        }
+    } else if signature.starts_with(data) {
+        Signature::Partial
+    } else {
+        Signature::Mismatch
+    }
+}
+
+fn tls(data: &[u8]) -> Signature {
+    // record type handshake, version 3.x, then handshake type client_hello
+    let expected: [fn(u8) -> bool; 6] = [
+        |x| x == 0x16,
+        |x| x == 0x03,
+        |x| x <= 0x04,
+        |_| true,
+        |_| true,
+        |x| x == 0x01,
+    ];
+    prefix_with(data, &expected)
+}
+
+fn rdp(data: &[u8]) -> Signature {
+    // TPKT version 3, then an X.224 connection request
+    let expected: [fn(u8) -> bool; 6] = [
+        |x| x == 0x03,
+        |x| x == 0x00,
+        |_| true,
+        |_| true,
+        |_| true,
+        |x| x & 0xf0 == 0xe0,
+    ];
+    prefix_with(data, &expected)
+}
+
+fn postgres(data: &[u8]) -> Signature {
+    if data.len() < 8 {
+        // length is big endian and small, so the first two bytes are zero
+        return if data.iter().take(2).all(|x| *x == 0) {
+            Signature::Partial
+        } else {
+            Signature::Mismatch
+        };
+    }
+    let length = u32::from_be_bytes(data[..4].try_into().unwrap());
+    if (8..=10000).contains(&length) && POSTGRES_CODES.iter().any(|x| data[4..8] == x[..]) {
+        Signature::Match
+    } else {
+        Signature::Mismatch
+    }
+}
+
+fn mysql_greeting(data: &[u8]) -> Signature {
+    // 3 byte length, sequence 0, protocol version 10
+    let expected: [fn(u8) -> bool; 5] = [|_| true, |_| true, |x| x == 0, |x| x == 0, |x| x == 0x0a];
+    prefix_with(data, &expected)
+}
+
+fn prefix_with(data: &[u8], expected: &[fn(u8) -> bool]) -> Signature {
+    if data.iter().zip(expected).any(|(x, f)| !f(*x)) {
+        Signature::Mismatch
+    } else if data.len() >= expected.len() {
+        Signature::Match
+    } else {
+        Signature::Partial
+    }
+}
+
+/// Classifies the first bytes sent by a client. Returns `None` if more data is needed.
+pub fn classify_client(data: &[u8]) -> Option<Protocol> {
+    if data.is_empty() {
+        return None;
+    }
+    let mut candidates = vec![(literal(data, HTTP2_PREFACE), Protocol::Http2)];
+    candidates.extend(
+        HTTP1_METHODS
+            .iter()
+            .map(|method| (literal(data, method), Protocol::Http1)),
+    );
+    candidates.extend([
+        (tls(data), Protocol::Tls),
+        (literal(data, b"SSH-"), Protocol::Ssh),
+        (rdp(data), Protocol::Rdp),
+        (postgres(data), Protocol::Postgres),
+    ]);
+    if let Some((_, protocol)) = candidates.iter().find(|(x, _)| *x == Signature::Match) {
+        return Some(*protocol);
+    }
+    if candidates.iter().any(|(x, _)| *x == Signature::Partial) {
+        return None;
+    }
+    Some(Protocol::Unknown)
+}
+
+/// Classifies the first bytes sent by a server, for protocols where the server speaks first. Returns `None` if more data is needed.
+pub fn classify_server(data: &[u8]) -> Option<Protocol> {
+    if data.is_empty() {
+        return None;
+    }
+    match (literal(data, b"SSH-"), mysql_greeting(data)) {
+        (Signature::Match, _) => Some(Protocol::Ssh),
+        (_, Signature::Match) => Some(Protocol::MySql),
+        (Signature::Partial, _) | (_, Signature::Partial) => None,
+        _ => Some(Protocol::Unknown),
+    }
+}
+
+/// Classifies the protocol of a connection from its first bytes.
+/// Feed it from [`crate::StreamContext::on_downstream_data`] and [`crate::StreamContext::on_upstream_data`] until [`ProtocolSniffer::protocol`] is set.
+/// Server-first protocols (MySQL) are detected when upstream data arrives before any downstream data.
+#[derive(Clone, Debug)]
+pub struct ProtocolSniffer {
+    client: Vec<u8>,
+    server: Vec<u8>,
+    server_unknown: bool,
+    max_bytes: usize,
+    protocol: Option<Protocol>,
+    filter_state_key: Option<String>,
+}
+
+impl Default for ProtocolSniffer {
+    fn default() -> Self {
+        Self {
+            client: vec![],
+            server: vec![],
+            server_unknown: false,
+            max_bytes: 64,
+            protocol: None,
+            filter_state_key: None,
+        }
+    }
+}
+
+impl ProtocolSniffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes inspected before giving up with [`Protocol::Unknown`]. Default is 64.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Writes the detected protocol name to this filter state key (via `set_property`) for use in routing decisions.
+    pub fn filter_state_key(mut self, key: impl Into<String>) -> Self {
+        self.filter_state_key = Some(key.into());
+        self
+    }
+
+    /// The detected protocol, if known yet
+    pub fn protocol(&self) -> Option<Protocol> {
+        self.protocol
+    }
+
+    /// Inspects downstream data. Returns the protocol once known.
+    pub fn on_downstream_data(&mut self, data: &impl StreamDataControl) -> Option<Protocol> {
+        if self.protocol.is_some() {
+            return self.protocol;
+        }
+        accumulate_prefix(&mut self.client, data, self.max_bytes);
+        let protocol = match classify_client(&self.client) {
+            Some(protocol) => protocol,
+            None if self.client.len() >= self.max_bytes || data.end_of_stream() => {
+                Protocol::Unknown
+            }
+            None => return None,
+        };
+        self.detected(protocol);
+        Some(protocol)
+    }
+
+    /// Inspects upstream data. Only detects server-first protocols, and only before any downstream data was seen.
+    pub fn on_upstream_data(&mut self, data: &impl StreamDataControl) -> Option<Protocol> {
+        if self.protocol.is_some() || self.server_unknown || !self.client.is_empty() {
+            return self.protocol;
+        }
+        accumulate_prefix(&mut self.server, data, self.max_bytes);
+        match classify_server(&self.server) {
+            // the client may still speak first
+            Some(Protocol::Unknown) => self.server_unknown = true,
+            Some(protocol) => {
+                self.detected(protocol);
+                return Some(protocol);
+            }
+            None if self.server.len() >= self.max_bytes => self.server_unknown = true,
+            None => (),
+        }
+        None
+    }
+
+    fn detected(&mut self, protocol: Protocol) {
+        self.protocol = Some(protocol);
+        if let Some(key) = &self.filter_state_key {
+            set_property(key, protocol.as_str());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_client() {
+        assert_eq!(
+            classify_client(b"GET / HTTP/1.1\r\n"),
+            Some(Protocol::Http1)
+        );
+        assert_eq!(classify_client(b"GE"), None);
+        assert_eq!(classify_client(b"PRI * HTTP/2.0"), None);
+        assert_eq!(classify_client(HTTP2_PREFACE), Some(Protocol::Http2));
+        assert_eq!(
+            classify_client(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00]),
+            Some(Protocol::Tls)
+        );
+        assert_eq!(classify_client(b"SSH-2.0-OpenSSH"), Some(Protocol::Ssh));
+        assert_eq!(
+            classify_client(&[0x03, 0x00, 0x00, 0x2b, 0x26, 0xe0, 0x00]),
+            Some(Protocol::Rdp)
+        );
+        assert_eq!(
+            classify_client(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]),
+            Some(Protocol::Postgres)
+        );
+        assert_eq!(
+            classify_client(b"\xff\xfe garbage"),
+            Some(Protocol::Unknown)
+        );
+    }
+
+    #[test]
+    fn test_classify_server() {
+        assert_eq!(
+            classify_server(&[0x4a, 0x00, 0x00, 0x00, 0x0a, b'8']),
+            Some(Protocol::MySql)
+        );
+        assert_eq!(classify_server(b"SSH-2.0"), Some(Protocol::Ssh));
+        assert_eq!(classify_server(b"220 smtp"), Some(Protocol::Unknown));
+    }
+
+    #[cfg(feature = "testing")]
+    mod stream {
+        use super::*;
+        use crate::{
+            property::get_property, testing::TcpScenario, BaseContext, Context, DownstreamData,
+            FilterStreamStatus, RootContext, StreamContext,
+        };
+
+        struct Root {
+            stop: bool,
+        }
+
+        impl BaseContext for Root {}
+
+        impl RootContext for Root {
+            fn create_context(&mut self) -> Context {
+                Context::Stream(Box::new(Sniffing {
+                    stop: self.stop,
+                    sniffer: ProtocolSniffer::new().filter_state_key("sniffed"),
+                }))
+            }
+        }
+
+        struct Sniffing {
+            stop: bool,
+            sniffer: ProtocolSniffer,
+        }
+
+        impl BaseContext for Sniffing {}
+
+        impl StreamContext for Sniffing {
+            fn on_downstream_data(&mut self, data: &DownstreamData) -> FilterStreamStatus {
+                if self.sniffer.on_downstream_data(data).is_none() && self.stop {
+                    return FilterStreamStatus::StopIteration;
+                }
+                FilterStreamStatus::Continue
+            }
+        }
+
+        #[test]
+        fn test_sniff_redelivered() {
+            let outcome = TcpScenario::new(|| Root { stop: true })
+                .connect()
+                .downstream("GE")
+                .expect(FilterStreamStatus::StopIteration)
+                .downstream("T / HTTP/1.1\r\n")
+                .expect(FilterStreamStatus::Continue)
+                .run();
+            assert_eq!(outcome.forwarded_upstream, b"GET / HTTP/1.1\r\n");
+            assert_eq!(get_property("sniffed").as_deref(), Some(&b"http/1"[..]));
+        }
+
+        #[test]
+        fn test_sniff_continued() {
+            // the second chunk starts with the first, but is new data since the first was let through
+            let outcome = TcpScenario::new(|| Root { stop: false })
+                .connect()
+                .downstream([0])
+                .downstream([0, 0, 8, 0x04, 0xd2, 0x16, 0x2f])
+                .run();
+            assert_eq!(
+                outcome.forwarded_upstream,
+                [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]
+            );
+            assert_eq!(get_property("sniffed").as_deref(), Some(&b"postgres"[..]));
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::ops::RangeBounds;
+use std::{any::Any, ops::RangeBounds};
 
 use crate::{
     calculate_range,
@@ -6,6 +6,8 @@ use crate::{
     hostcalls::{self, BufferType},
     log_concern,
     property::envoy::Attributes,
+    queue::Queue,
+    zeroize::ZeroizingVec,
 };
 
 /// Defines control functions for streams
@@ -45,6 +47,12 @@ pub trait StreamDataControl {
     /// If true, this will be the last downstream data for this context.
     fn end_of_stream(&self) -> bool;
 
+    /// If true, the previous call for this direction returned [`FilterStreamStatus::StopIteration`],
+    /// so this data starts with the chunk that was seen then.
+    fn redelivered(&self) -> bool {
+        false
+    }
+
     /// Get all data
     fn all(&self) -> Option<Vec<u8>> {
         self.get(..)
@@ -59,6 +67,16 @@ pub trait StreamDataControl {
         )
     }
 
+    /// Get a range of data, wiped on drop. See [`crate::zeroize`].
+    fn get_zeroizing(&self, range: impl RangeBounds<usize>) -> Option<ZeroizingVec> {
+        self.get(range).map(ZeroizingVec::from)
+    }
+
+    /// Get all data, wiped on drop
+    fn all_zeroizing(&self) -> Option<ZeroizingVec> {
+        self.get_zeroizing(..)
+    }
+
     /// Replace a range of data with `value`.
     fn set(&self, range: impl RangeBounds<usize>, value: &[u8]) {
         let (start, size) = calculate_range(range, self.data_size());
@@ -91,6 +109,23 @@ pub trait StreamDataControl {
     }
 }
 
+/// Appends up to `max_bytes` of stream data to `buffer`.
+/// A filter that stops iteration gets buffered data redelivered, so a [redelivered](StreamDataControl::redelivered) chunk replaces `buffer` rather than duplicating it.
+pub(crate) fn accumulate_prefix(
+    buffer: &mut Vec<u8>,
+    data: &impl StreamDataControl,
+    max_bytes: usize,
+) {
+    let size = data.data_size().min(max_bytes);
+    let chunk = data.get(..size).unwrap_or_default();
+    if data.redelivered() {
+        *buffer = chunk;
+    } else {
+        buffer.extend_from_slice(&chunk);
+    }
+    buffer.truncate(max_bytes);
+}
+
 #[repr(usize)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 #[non_exhaustive]
@@ -106,7 +141,7 @@ pub enum StreamType {
 }
 
 impl StreamType {
-    const fn get(&self) -> &'static str {
+    pub(crate) const fn get(&self) -> &'static str {
         match self {
             Self::Upstream => "get-upstream-data",
             Self::Downstream => "get-downstream-data",
@@ -120,7 +155,7 @@ impl StreamType {
         }
     }
 
-    const fn buffer(&self) -> BufferType {
+    pub(crate) const fn buffer(&self) -> BufferType {
         match self {
             Self::Upstream => BufferType::UpstreamData,
             Self::Downstream => BufferType::DownstreamData,
@@ -132,6 +167,7 @@ impl StreamType {
 pub struct UpstreamData {
     pub(crate) data_size: usize,
     pub(crate) end_of_stream: bool,
+    pub(crate) redelivered: bool,
     pub(crate) attributes: Attributes,
 }
 
@@ -151,12 +187,17 @@ impl StreamDataControl for UpstreamData {
     fn end_of_stream(&self) -> bool {
         self.end_of_stream
     }
+
+    fn redelivered(&self) -> bool {
+        self.redelivered
+    }
 }
 
 /// Downstream data reference for a Stream filter
 pub struct DownstreamData {
     pub(crate) data_size: usize,
     pub(crate) end_of_stream: bool,
+    pub(crate) redelivered: bool,
     pub(crate) attributes: Attributes,
 }
 
@@ -176,6 +217,10 @@ impl StreamDataControl for DownstreamData {
     fn end_of_stream(&self) -> bool {
         self.end_of_stream
     }
+
+    fn redelivered(&self) -> bool {
+        self.redelivered
+    }
 }
 
 #[repr(usize)]
@@ -210,7 +255,7 @@ impl StreamControl for StreamClose {
 
 /// Trait to implement stream filters (L4 filters).
 #[allow(unused_variables)]
-pub trait StreamContext: BaseContext {
+pub trait StreamContext: BaseContext + Any {
     /// Called on a new connection.
     /// TODO: FilterStreamStatus effect unknown.
     fn on_new_connection(&mut self) -> FilterStreamStatus {
@@ -236,4 +281,7 @@ pub trait StreamContext: BaseContext {
 
     /// Called when an upstream connection closes.
     fn on_upstream_close(&mut self, data: &StreamClose) {}
+
+    /// Called when a queue subscribed to with [`crate::Queue::subscribe_context`] has data available.
+    fn on_queue_ready(&mut self, queue: Queue) {}
 }
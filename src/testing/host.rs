@@ -0,0 +1,767 @@
+//! In-memory implementation of the `proxy_*` hostcalls. State is thread local, so tests may run in parallel.
+
+#![allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::Level;
+
+#[cfg(not(feature = "abi-0-2-0"))]
+use crate::hostcalls::StreamType;
+use crate::{
+    hostcalls::{return_bytes, BufferType, LogLevel, MapType, MetricType},
+    Status,
+};
+
+/// Hostcall that changed the state of a stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamAction {
+    ResumeDownstream,
+    ResumeUpstream,
+    CloseDownstream,
+    CloseUpstream,
+    ResumeHttpRequest,
+    ResumeHttpResponse,
+    ResetHttpRequest,
+    ResetHttpResponse,
+}
+
+/// A response sent with `proxy_send_local_response`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalResponse {
+    pub status_code: u32,
+    pub details: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    /// GRPC status, if the response is a GRPC error
+    pub grpc_status: Option<i32>,
+}
+
+/// A call made with `proxy_http_call`. The host answers none, respond with
+/// [`crate::dispatcher::proxy_on_http_call_response`] and the call's token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpCallRecord {
+    pub token: u32,
+    pub upstream: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, Vec<u8>)>,
+    pub timeout: Duration,
+}
+
+struct Metric {
+    name: String,
+    kind: MetricType,
+    value: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct MockHost {
+    pub(crate) buffers: HashMap<u32, Vec<u8>>,
+    pub(crate) header_maps: HashMap<u32, Vec<(String, Vec<u8>)>>,
+    pub(crate) local_response: Option<LocalResponse>,
+    pub(crate) properties: HashMap<Vec<u8>, Vec<u8>>,
+    /// Value and CAS number by key
+    pub(crate) shared_data: HashMap<String, (Vec<u8>, u32)>,
+    /// Shared queues by ID minus one
+    queues: Vec<(String, VecDeque<Vec<u8>>)>,
+    metrics: Vec<Metric>,
+    pub(crate) logs: Vec<(Level, String)>,
+    pub(crate) stream_actions: Vec<StreamAction>,
+    pub(crate) written_upstream: Vec<u8>,
+    pub(crate) written_downstream: Vec<u8>,
+    pub(crate) http_calls: Vec<HttpCallRecord>,
+    time: Option<SystemTime>,
+    tick_period: Option<Duration>,
+    log_level: Option<LogLevel>,
+}
+
+thread_local! {
+    static HOST: RefCell<MockHost> = RefCell::default();
+}
+
+pub(crate) fn with_host<R>(f: impl FnOnce(&mut MockHost) -> R) -> R {
+    HOST.with_borrow_mut(f)
+}
+
+/// Clears all host state of the current thread
+pub fn reset_host() {
+    with_host(|host| *host = MockHost::default());
+}
+
+/// Sets a property returned by `proxy_get_property`, i.e. `set_property(["source", "address"], "10.0.0.1:1234")`
+pub fn set_property<S: AsRef<str>>(path: impl IntoIterator<Item = S>, value: impl Into<Vec<u8>>) {
+    let mut key = vec![];
+    crate::serialize_property_path_into(path, &mut key);
+    with_host(|host| host.properties.insert(key, value.into()));
+}
+
+/// Sets the realtime clock. Default is the system clock.
+pub fn set_time(time: SystemTime) {
+    with_host(|host| host.time = Some(time));
+}
+
+/// Sets the level returned by `proxy_get_log_level`. Default is `Trace`.
+pub fn set_log_level(level: Level) {
+    with_host(|host| host.log_level = Some(level.into()));
+}
+
+/// Current value of the metric `name`. Histograms hold their last recorded value.
+pub fn metric(name: &str) -> Option<u64> {
+    with_host(|host| {
+        host.metrics
+            .iter()
+            .find(|x| x.name == name)
+            .map(|x| x.value)
+    })
+}
+
+/// All messages logged through `proxy_log`
+pub fn logs() -> Vec<(Level, String)> {
+    with_host(|host| host.logs.clone())
+}
+
+/// Enqueues `data` on the shared queue `name` the way another VM would, returning the queue ID.
+/// `None` if the plugin did not register the queue.
+pub fn enqueue(name: &str, data: impl Into<Vec<u8>>) -> Option<u32> {
+    with_host(|host| {
+        let id = host.queues.iter().position(|(x, _)| x == name)?;
+        host.queues[id].1.push_back(data.into());
+        Some(id as u32 + 1)
+    })
+}
+
+/// Items left on the shared queue `name`
+pub fn queued(name: &str) -> Vec<Vec<u8>> {
+    with_host(|host| {
+        host.queues
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, items)| items.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Calls dispatched with `proxy_http_call`, in order
+pub fn http_calls() -> Vec<HttpCallRecord> {
+    with_host(|host| host.http_calls.clone())
+}
+
+/// Tick period set by the plugin, if any
+pub fn tick_period() -> Option<Duration> {
+    with_host(|host| host.tick_period)
+}
+
+unsafe fn slice<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() || size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, size)
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_log(
+    level: LogLevel,
+    message_data: *const u8,
+    message_size: usize,
+) -> Status {
+    let level = match level {
+        LogLevel::Trace => Level::Trace,
+        LogLevel::Debug => Level::Debug,
+        LogLevel::Info => Level::Info,
+        LogLevel::Warn => Level::Warn,
+        LogLevel::Error | LogLevel::Critical => Level::Error,
+    };
+    let message = String::from_utf8_lossy(slice(message_data, message_size)).into_owned();
+    with_host(|host| host.logs.push((level, message)));
+    Status::Ok
+}
+
+#[cfg(not(feature = "abi-0-2-0"))]
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_log_level(return_level: *mut LogLevel) -> Status {
+    *return_level = with_host(|host| host.log_level.unwrap_or(LogLevel::Trace));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    let time = with_host(|host| host.time).unwrap_or_else(SystemTime::now);
+    *return_time = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+    with_host(|host| {
+        host.tick_period = (period != 0).then(|| Duration::from_millis(period as u64))
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    max_size: usize,
+    return_buffer_data: *mut *mut u8,
+    return_buffer_size: *mut usize,
+) -> Status {
+    with_host(|host| {
+        let Some(buffer) = host.buffers.get(&(buffer_type as u32)) else {
+            return Status::NotFound;
+        };
+        let start = start.min(buffer.len());
+        let end = start.saturating_add(max_size).min(buffer.len());
+        return_bytes(&buffer[start..end], return_buffer_data, return_buffer_size);
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    size: usize,
+    buffer_data: *const u8,
+    buffer_size: usize,
+) -> Status {
+    let data = slice(buffer_data, buffer_size);
+    with_host(|host| {
+        let buffer = host.buffers.entry(buffer_type as u32).or_default();
+        let start = start.min(buffer.len());
+        let end = start.saturating_add(size).min(buffer.len());
+        buffer.splice(start..end, data.iter().copied());
+        Status::Ok
+    })
+}
+
+impl MockHost {
+    pub(crate) fn header_map(&mut self, map_type: MapType) -> &mut Vec<(String, Vec<u8>)> {
+        self.header_maps.entry(map_type as u32).or_default()
+    }
+}
+
+unsafe fn header_name(key_data: *const u8, key_size: usize) -> String {
+    String::from_utf8_lossy(slice(key_data, key_size)).to_ascii_lowercase()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_pairs(
+    map_type: MapType,
+    return_map_data: *mut *mut u8,
+    return_map_size: *mut usize,
+) -> Status {
+    let mut serialized = vec![];
+    with_host(|host| {
+        let map = host.header_map(map_type);
+        let map = map
+            .iter()
+            .map(|(name, value)| (&**name, &**value))
+            .collect::<Vec<_>>();
+        crate::serialize_map_into(&map, &mut serialized);
+    });
+    return_bytes(&serialized, return_map_data, return_map_size);
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_header_map_pairs(
+    map_type: MapType,
+    map_data: *const u8,
+    map_size: usize,
+) -> Status {
+    match crate::hostcalls::deserialize_map_bytes(slice(map_data, map_size)) {
+        Ok(map) => {
+            with_host(|host| *host.header_map(map_type) = map);
+            Status::Ok
+        }
+        Err(e) => e,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let name = header_name(key_data, key_size);
+    with_host(|host| {
+        match host
+            .header_map(map_type)
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(&name))
+        {
+            Some((_, value)) => {
+                return_bytes(value, return_value_data, return_value_size);
+                Status::Ok
+            }
+            None => Status::NotFound,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_replace_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let name = header_name(key_data, key_size);
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| {
+        let map = host.header_map(map_type);
+        match map.iter().position(|(x, _)| x.eq_ignore_ascii_case(&name)) {
+            Some(i) => {
+                map[i].1 = value;
+                let mut j = i + 1;
+                while j < map.len() {
+                    if map[j].0.eq_ignore_ascii_case(&name) {
+                        map.remove(j);
+                    } else {
+                        j += 1;
+                    }
+                }
+            }
+            None => map.push((name, value)),
+        }
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_remove_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+) -> Status {
+    let name = header_name(key_data, key_size);
+    with_host(|host| {
+        host.header_map(map_type)
+            .retain(|(x, _)| !x.eq_ignore_ascii_case(&name))
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_add_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let name = header_name(key_data, key_size);
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| host.header_map(map_type).push((name, value)));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let path = slice(path_data, path_size);
+    with_host(|host| match host.properties.get(path) {
+        Some(value) => {
+            return_bytes(value, return_value_data, return_value_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_property(
+    path_data: *const u8,
+    path_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let path = slice(path_data, path_size).to_vec();
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| host.properties.insert(path, value));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = String::from_utf8_lossy(slice(key_data, key_size)).into_owned();
+    let Some((value, cas)) = with_host(|host| host.shared_data.get(&key).cloned()) else {
+        return Status::NotFound;
+    };
+    return_bytes(&value, return_value_data, return_value_size);
+    *return_cas = cas;
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = String::from_utf8_lossy(slice(key_data, key_size)).into_owned();
+    let value = slice(value_data, value_size).to_vec();
+    with_host(|host| {
+        let current = host.shared_data.get(&key).map_or(0, |(_, cas)| *cas);
+        if cas != 0 && cas != current {
+            return Status::CasMismatch;
+        }
+        host.shared_data.insert(key, (value, current + 1));
+        Status::Ok
+    })
+}
+
+/// Queues live in a single VM, so VM IDs are ignored
+#[no_mangle]
+pub unsafe extern "C" fn proxy_register_shared_queue(
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = String::from_utf8_lossy(slice(name_data, name_size)).into_owned();
+    *return_id = with_host(|host| {
+        if let Some(id) = host.queues.iter().position(|(x, _)| *x == name) {
+            return id as u32 + 1;
+        }
+        host.queues.push((name, VecDeque::new()));
+        host.queues.len() as u32
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_resolve_shared_queue(
+    _vm_id_data: *const u8,
+    _vm_id_size: usize,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = slice(name_data, name_size);
+    match with_host(|host| host.queues.iter().position(|(x, _)| x.as_bytes() == name)) {
+        Some(id) => {
+            *return_id = id as u32 + 1;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_dequeue_shared_queue(
+    queue_id: u32,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let item = with_host(|host| {
+        let (_, items) = host.queues.get_mut((queue_id as usize).wrapping_sub(1))?;
+        Some(items.pop_front())
+    });
+    match item {
+        Some(Some(item)) => {
+            return_bytes(&item, return_value_data, return_value_size);
+            Status::Ok
+        }
+        Some(None) => Status::Empty,
+        None => Status::NotFound,
+    }
+}
+
+/// Plugins are not notified of their own enqueues. Deliver `on_queue_ready` explicitly, i.e. with [`super::HttpStep::Enqueue`].
+#[no_mangle]
+pub unsafe extern "C" fn proxy_enqueue_shared_queue(
+    queue_id: u32,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let value = slice(value_data, value_size).to_vec();
+    with_host(
+        |host| match host.queues.get_mut((queue_id as usize).wrapping_sub(1)) {
+            Some((_, items)) => {
+                items.push_back(value);
+                Status::Ok
+            }
+            None => Status::NotFound,
+        },
+    )
+}
+
+#[cfg(not(feature = "abi-0-2-0"))]
+#[no_mangle]
+pub extern "C" fn proxy_continue_stream(stream_type: StreamType) -> Status {
+    let action = match stream_type {
+        StreamType::HttpRequest => StreamAction::ResumeHttpRequest,
+        StreamType::HttpResponse => StreamAction::ResumeHttpResponse,
+        StreamType::Downstream => StreamAction::ResumeDownstream,
+        StreamType::Upstream => StreamAction::ResumeUpstream,
+    };
+    with_host(|host| host.stream_actions.push(action));
+    Status::Ok
+}
+
+#[cfg(not(feature = "abi-0-2-0"))]
+#[no_mangle]
+pub extern "C" fn proxy_close_stream(stream_type: StreamType) -> Status {
+    let action = match stream_type {
+        StreamType::HttpRequest => StreamAction::ResetHttpRequest,
+        StreamType::HttpResponse => StreamAction::ResetHttpResponse,
+        StreamType::Downstream => StreamAction::CloseDownstream,
+        StreamType::Upstream => StreamAction::CloseUpstream,
+    };
+    with_host(|host| host.stream_actions.push(action));
+    Status::Ok
+}
+
+#[cfg(feature = "abi-0-2-0")]
+#[no_mangle]
+pub extern "C" fn proxy_continue_request() -> Status {
+    with_host(|host| host.stream_actions.push(StreamAction::ResumeHttpRequest));
+    Status::Ok
+}
+
+#[cfg(feature = "abi-0-2-0")]
+#[no_mangle]
+pub extern "C" fn proxy_continue_response() -> Status {
+    with_host(|host| host.stream_actions.push(StreamAction::ResumeHttpResponse));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_send_local_response(
+    status_code: u32,
+    status_code_details_data: *const u8,
+    status_code_details_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    grpc_status: i32,
+) -> Status {
+    let headers = match crate::hostcalls::deserialize_map_bytes(slice(headers_data, headers_size)) {
+        Ok(headers) => headers,
+        Err(e) => return e,
+    };
+    let response = LocalResponse {
+        status_code,
+        details: String::from_utf8_lossy(slice(status_code_details_data, status_code_details_size))
+            .into_owned(),
+        headers,
+        body: slice(body_data, body_size).to_vec(),
+        grpc_status: (grpc_status >= 0).then_some(grpc_status),
+    };
+    with_host(|host| host.local_response = Some(response));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    trailers_data: *const u8,
+    trailers_size: usize,
+    timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let headers = crate::hostcalls::deserialize_map_bytes(slice(headers_data, headers_size));
+    let trailers = crate::hostcalls::deserialize_map_bytes(slice(trailers_data, trailers_size));
+    let (Ok(headers), Ok(trailers)) = (headers, trailers) else {
+        return Status::ParseFailure;
+    };
+    with_host(|host| {
+        let token = host.http_calls.len() as u32 + 1;
+        host.http_calls.push(HttpCallRecord {
+            token,
+            upstream: String::from_utf8_lossy(slice(upstream_data, upstream_size)).into_owned(),
+            headers,
+            body: slice(body_data, body_size).to_vec(),
+            trailers,
+            timeout: Duration::from_millis(timeout.into()),
+        });
+        *return_token = token;
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_grpc_call(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _message_data_data: *const u8,
+    _message_data_size: usize,
+    _timeout: u32,
+    _return_callout_id: *mut u32,
+) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_grpc_stream(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _return_stream_id: *mut u32,
+) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_grpc_send(
+    _token: u32,
+    _message_ptr: *const u8,
+    _message_len: usize,
+    _end_stream: bool,
+) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_cancel(_token_id: u32) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_close(_token_id: u32) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_status(
+    _return_code: *mut u32,
+    _return_message_data: *mut *mut u8,
+    _return_message_size: *mut usize,
+) -> Status {
+    Status::Unimplemented
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_effective_context(_context_id: u32) -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_call_foreign_function(
+    _function_name_data: *const u8,
+    _function_name_size: usize,
+    _arguments_data: *const u8,
+    _arguments_size: usize,
+    _results_data: *mut *mut u8,
+    _results_size: *mut usize,
+) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_done() -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_define_metric(
+    metric_type: MetricType,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = String::from_utf8_lossy(slice(name_data, name_size)).into_owned();
+    *return_id = with_host(|host| {
+        if let Some(id) = host.metrics.iter().position(|x| x.name == name) {
+            return id as u32;
+        }
+        host.metrics.push(Metric {
+            name,
+            kind: metric_type,
+            value: 0,
+        });
+        host.metrics.len() as u32 - 1
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    match with_host(|host| host.metrics.get(metric_id as usize).map(|x| x.value)) {
+        Some(value) => {
+            *return_value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    with_host(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some(metric) => {
+            metric.value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    with_host(|host| match host.metrics.get_mut(metric_id as usize) {
+        Some(metric) if metric.kind != MetricType::Histogram => {
+            metric.value = metric.value.saturating_add_signed(offset);
+            Status::Ok
+        }
+        Some(_) => Status::BadArgument,
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_write_upstream(data: *const u8, size: usize) -> Status {
+    let data = slice(data, size);
+    with_host(|host| host.written_upstream.extend_from_slice(data));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_write_downstream(data: *const u8, size: usize) -> Status {
+    let data = slice(data, size);
+    with_host(|host| host.written_downstream.extend_from_slice(data));
+    Status::Ok
+}
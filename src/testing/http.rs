@@ -0,0 +1,551 @@
+use crate::{
+    dispatcher::{
+        proxy_on_configure, proxy_on_context_create, proxy_on_delete, proxy_on_done, proxy_on_log,
+        proxy_on_queue_ready, proxy_on_request_body, proxy_on_request_headers,
+        proxy_on_request_trailers, proxy_on_response_body, proxy_on_response_headers,
+        proxy_on_response_trailers, proxy_on_tick, proxy_on_vm_start,
+    },
+    hostcalls::{BufferType, MapType},
+    FilterDataStatus, FilterHeadersStatus, FilterTrailersStatus, RootContext,
+};
+
+use super::{
+    host::{reset_host, with_host, LocalResponse, StreamAction},
+    HttpMessage,
+};
+
+const ROOT_CONTEXT_ID: usize = 1;
+const HTTP_CONTEXT_ID: usize = 2;
+
+/// An event delivered to an HTTP filter. Headers include pseudo-headers, i.e. `:path` or `:status`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpStep {
+    RequestHeaders {
+        headers: Vec<(String, Vec<u8>)>,
+        end_of_stream: bool,
+    },
+    RequestBody {
+        data: Vec<u8>,
+        end_of_stream: bool,
+    },
+    RequestTrailers(Vec<(String, Vec<u8>)>),
+    ResponseHeaders {
+        headers: Vec<(String, Vec<u8>)>,
+        end_of_stream: bool,
+    },
+    ResponseBody {
+        data: Vec<u8>,
+        end_of_stream: bool,
+    },
+    ResponseTrailers(Vec<(String, Vec<u8>)>),
+    /// Calls `on_tick` of the root context
+    Tick,
+    /// Enqueues `data` on a queue registered by the plugin, then calls `on_queue_ready` of the root context
+    Enqueue {
+        queue: String,
+        data: Vec<u8>,
+    },
+}
+
+/// Status returned by an HTTP callback
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpStatus {
+    Headers(FilterHeadersStatus),
+    Data(FilterDataStatus),
+    Trailers(FilterTrailersStatus),
+}
+
+impl From<FilterHeadersStatus> for HttpStatus {
+    fn from(value: FilterHeadersStatus) -> Self {
+        Self::Headers(value)
+    }
+}
+
+impl From<FilterDataStatus> for HttpStatus {
+    fn from(value: FilterDataStatus) -> Self {
+        Self::Data(value)
+    }
+}
+
+impl From<FilterTrailersStatus> for HttpStatus {
+    fn from(value: FilterTrailersStatus) -> Self {
+        Self::Trailers(value)
+    }
+}
+
+fn header_list<K: Into<String>, V: Into<Vec<u8>>>(
+    headers: impl IntoIterator<Item = (K, V)>,
+) -> Vec<(String, Vec<u8>)> {
+    headers
+        .into_iter()
+        .map(|(name, value)| (name.into(), value.into()))
+        .collect()
+}
+
+/// Declarative scenario for an HTTP filter, delivering headers, body chunks and trailers one callback at a time.
+/// Steps run against the in-memory host in order, after the root context is created and configured.
+///
+/// ```ignore
+/// let outcome = HttpScenario::new(MyRoot::default)
+///     .request_headers([(":path", "/upload")]).expect(FilterHeadersStatus::Continue)
+///     .request_body("hel").expect(FilterDataStatus::StopAllIterationAndBuffer)
+///     .request_body_end("lo").expect(FilterDataStatus::Continue)
+///     .response_headers_end([(":status", "200")])
+///     .run();
+/// assert_eq!(outcome.request.body, b"HELLO");
+/// ```
+pub struct HttpScenario<R> {
+    root: Box<dyn Fn() -> R>,
+    vm_configuration: Option<Vec<u8>>,
+    configuration: Option<Vec<u8>>,
+    properties: Vec<(Vec<String>, Vec<u8>)>,
+    steps: Vec<(HttpStep, Option<HttpStatus>)>,
+}
+
+impl<R: RootContext + 'static> HttpScenario<R> {
+    pub fn new(root: impl Fn() -> R + 'static) -> Self {
+        Self {
+            root: Box::new(root),
+            vm_configuration: None,
+            configuration: None,
+            properties: vec![],
+            steps: vec![],
+        }
+    }
+
+    /// Configuration passed to `on_vm_start`
+    pub fn vm_configuration(mut self, configuration: impl Into<Vec<u8>>) -> Self {
+        self.vm_configuration = Some(configuration.into());
+        self
+    }
+
+    /// Configuration passed to `on_configure`
+    pub fn configuration(mut self, configuration: impl Into<Vec<u8>>) -> Self {
+        self.configuration = Some(configuration.into());
+        self
+    }
+
+    /// Sets a request property, i.e. `property(["source", "address"], "10.0.0.1:1234")`
+    pub fn property<S: AsRef<str>>(
+        mut self,
+        path: impl IntoIterator<Item = S>,
+        value: impl Into<Vec<u8>>,
+    ) -> Self {
+        let path = path.into_iter().map(|x| x.as_ref().to_string()).collect();
+        self.properties.push((path, value.into()));
+        self
+    }
+
+    pub fn step(mut self, step: HttpStep) -> Self {
+        self.steps.push((step, None));
+        self
+    }
+
+    /// Delivers request headers, followed by a body or trailers
+    pub fn request_headers<K: Into<String>, V: Into<Vec<u8>>>(
+        self,
+        headers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.step(HttpStep::RequestHeaders {
+            headers: header_list(headers),
+            end_of_stream: false,
+        })
+    }
+
+    /// Delivers request headers of a request without body
+    pub fn request_headers_end<K: Into<String>, V: Into<Vec<u8>>>(
+        self,
+        headers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.step(HttpStep::RequestHeaders {
+            headers: header_list(headers),
+            end_of_stream: true,
+        })
+    }
+
+    /// Delivers a chunk of the request body
+    pub fn request_body(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(HttpStep::RequestBody {
+            data: data.into(),
+            end_of_stream: false,
+        })
+    }
+
+    /// Delivers the last chunk of the request body
+    pub fn request_body_end(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(HttpStep::RequestBody {
+            data: data.into(),
+            end_of_stream: true,
+        })
+    }
+
+    pub fn request_trailers<K: Into<String>, V: Into<Vec<u8>>>(
+        self,
+        trailers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.step(HttpStep::RequestTrailers(header_list(trailers)))
+    }
+
+    /// Delivers response headers, followed by a body or trailers
+    pub fn response_headers<K: Into<String>, V: Into<Vec<u8>>>(
+        self,
+        headers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.step(HttpStep::ResponseHeaders {
+            headers: header_list(headers),
+            end_of_stream: false,
+        })
+    }
+
+    /// Delivers response headers of a response without body
+    pub fn response_headers_end<K: Into<String>, V: Into<Vec<u8>>>(
+        self,
+        headers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.step(HttpStep::ResponseHeaders {
+            headers: header_list(headers),
+            end_of_stream: true,
+        })
+    }
+
+    /// Delivers a chunk of the response body
+    pub fn response_body(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(HttpStep::ResponseBody {
+            data: data.into(),
+            end_of_stream: false,
+        })
+    }
+
+    /// Delivers the last chunk of the response body
+    pub fn response_body_end(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(HttpStep::ResponseBody {
+            data: data.into(),
+            end_of_stream: true,
+        })
+    }
+
+    pub fn response_trailers<K: Into<String>, V: Into<Vec<u8>>>(
+        self,
+        trailers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.step(HttpStep::ResponseTrailers(header_list(trailers)))
+    }
+
+    pub fn tick(self) -> Self {
+        self.step(HttpStep::Tick)
+    }
+
+    /// Enqueues `data` on the queue `name`, registered by the plugin, and notifies the root context
+    pub fn enqueue(self, queue: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        self.step(HttpStep::Enqueue {
+            queue: queue.into(),
+            data: data.into(),
+        })
+    }
+
+    /// Asserts the status returned for the previous step when the scenario runs
+    pub fn expect(mut self, status: impl Into<HttpStatus>) -> Self {
+        let status = status.into();
+        let (step, expected) = self
+            .steps
+            .last_mut()
+            .expect("no step to expect a status of");
+        let matching = matches!(
+            (&*step, status),
+            (
+                HttpStep::RequestHeaders { .. } | HttpStep::ResponseHeaders { .. },
+                HttpStatus::Headers(_)
+            ) | (
+                HttpStep::RequestBody { .. } | HttpStep::ResponseBody { .. },
+                HttpStatus::Data(_)
+            ) | (
+                HttpStep::RequestTrailers(_) | HttpStep::ResponseTrailers(_),
+                HttpStatus::Trailers(_)
+            )
+        );
+        assert!(matching, "{step:?} does not return a {status:?}");
+        *expected = Some(status);
+        self
+    }
+
+    /// Runs all steps, then completes and deletes the HTTP context. Panics if an expected status does not match.
+    pub fn run(self) -> HttpOutcome {
+        reset_host();
+        crate::metrics::reset_all_caches();
+        crate::dispatcher::reset_local(self.root);
+        for (path, value) in self.properties {
+            super::host::set_property(path, value);
+        }
+        let vm_configuration_size = self.vm_configuration.as_ref().map_or(0, Vec::len);
+        let configuration_size = self.configuration.as_ref().map_or(0, Vec::len);
+        with_host(|host| {
+            if let Some(configuration) = self.vm_configuration {
+                host.buffers
+                    .insert(BufferType::VmConfiguration as u32, configuration);
+            }
+            if let Some(configuration) = self.configuration {
+                host.buffers
+                    .insert(BufferType::PluginConfiguration as u32, configuration);
+            }
+        });
+
+        proxy_on_context_create(ROOT_CONTEXT_ID, 0);
+        assert_ne!(
+            proxy_on_vm_start(ROOT_CONTEXT_ID, vm_configuration_size),
+            0,
+            "on_vm_start failed"
+        );
+        assert_ne!(
+            proxy_on_configure(ROOT_CONTEXT_ID, configuration_size),
+            0,
+            "on_configure failed"
+        );
+        proxy_on_context_create(HTTP_CONTEXT_ID, ROOT_CONTEXT_ID);
+
+        let mut outcome = HttpOutcome::default();
+        for (i, (step, expected)) in self.steps.into_iter().enumerate() {
+            let status = match &step {
+                HttpStep::RequestHeaders {
+                    headers,
+                    end_of_stream,
+                } => Some(deliver_headers(
+                    MapType::HttpRequestHeaders,
+                    headers,
+                    *end_of_stream,
+                    &mut outcome.request,
+                    proxy_on_request_headers,
+                )),
+                HttpStep::RequestBody {
+                    data,
+                    end_of_stream,
+                } => Some(deliver_body(
+                    BufferType::HttpRequestBody,
+                    data,
+                    *end_of_stream,
+                    &mut outcome.request,
+                    proxy_on_request_body,
+                )),
+                HttpStep::RequestTrailers(trailers) => Some(deliver_trailers(
+                    MapType::HttpRequestTrailers,
+                    trailers,
+                    &mut outcome.request,
+                    proxy_on_request_trailers,
+                )),
+                HttpStep::ResponseHeaders {
+                    headers,
+                    end_of_stream,
+                } => Some(deliver_headers(
+                    MapType::HttpResponseHeaders,
+                    headers,
+                    *end_of_stream,
+                    &mut outcome.response,
+                    proxy_on_response_headers,
+                )),
+                HttpStep::ResponseBody {
+                    data,
+                    end_of_stream,
+                } => Some(deliver_body(
+                    BufferType::HttpResponseBody,
+                    data,
+                    *end_of_stream,
+                    &mut outcome.response,
+                    proxy_on_response_body,
+                )),
+                HttpStep::ResponseTrailers(trailers) => Some(deliver_trailers(
+                    MapType::HttpResponseTrailers,
+                    trailers,
+                    &mut outcome.response,
+                    proxy_on_response_trailers,
+                )),
+                HttpStep::Tick => {
+                    proxy_on_tick(ROOT_CONTEXT_ID);
+                    None
+                }
+                HttpStep::Enqueue { queue, data } => {
+                    let queue_id = super::host::enqueue(queue, data.clone())
+                        .unwrap_or_else(|| panic!("step {i}: queue {queue} is not registered"));
+                    proxy_on_queue_ready(ROOT_CONTEXT_ID, queue_id as usize);
+                    None
+                }
+            };
+            if let Some(expected) = expected {
+                assert_eq!(status, Some(expected), "step {i}: {step:?}");
+            }
+            outcome.steps.push((step, status));
+        }
+
+        proxy_on_done(HTTP_CONTEXT_ID);
+        proxy_on_log(HTTP_CONTEXT_ID);
+        proxy_on_delete(HTTP_CONTEXT_ID);
+
+        with_host(|host| {
+            outcome.local_response = host.local_response.take();
+            outcome.stream_actions = std::mem::take(&mut host.stream_actions);
+        });
+        outcome
+    }
+}
+
+/// Sets the header map, calls the filter, and records the headers as modified by the filter
+fn deliver_headers(
+    map_type: MapType,
+    headers: &[(String, Vec<u8>)],
+    end_of_stream: bool,
+    message: &mut HttpMessage,
+    callback: extern "C" fn(usize, usize, usize) -> FilterHeadersStatus,
+) -> HttpStatus {
+    with_host(|host| *host.header_map(map_type) = headers.to_vec());
+    let status = callback(HTTP_CONTEXT_ID, headers.len(), end_of_stream as usize);
+    message.headers = with_host(|host| host.header_map(map_type).clone());
+    status.into()
+}
+
+/// Appends `data` to the buffer, calls the filter, and forwards the buffer if it continued.
+/// Stopped data stays buffered and is redelivered with the next chunk, like Envoy does.
+fn deliver_body(
+    buffer_type: BufferType,
+    data: &[u8],
+    end_of_stream: bool,
+    message: &mut HttpMessage,
+    callback: extern "C" fn(usize, usize, usize) -> FilterDataStatus,
+) -> HttpStatus {
+    let size = with_host(|host| {
+        let buffer = host.buffers.entry(buffer_type as u32).or_default();
+        buffer.extend_from_slice(data);
+        buffer.len()
+    });
+    let status = callback(HTTP_CONTEXT_ID, size, end_of_stream as usize);
+    if status == FilterDataStatus::Continue {
+        with_host(|host| {
+            if let Some(buffer) = host.buffers.get_mut(&(buffer_type as u32)) {
+                message.body.append(buffer);
+            }
+        });
+    }
+    status.into()
+}
+
+fn deliver_trailers(
+    map_type: MapType,
+    trailers: &[(String, Vec<u8>)],
+    message: &mut HttpMessage,
+    callback: extern "C" fn(usize, usize) -> FilterTrailersStatus,
+) -> HttpStatus {
+    with_host(|host| *host.header_map(map_type) = trailers.to_vec());
+    let status = callback(HTTP_CONTEXT_ID, trailers.len());
+    message.trailers = with_host(|host| host.header_map(map_type).clone());
+    status.into()
+}
+
+/// Result of [`HttpScenario::run`]. Metrics, logs and queues remain readable through [`super::metric`], [`super::logs`]
+/// and [`super::queued`].
+#[derive(Debug, Default)]
+pub struct HttpOutcome {
+    /// Each step with the status it returned, if any
+    pub steps: Vec<(HttpStep, Option<HttpStatus>)>,
+    /// The request as modified by the filter: headers and trailers as last delivered, and the body let through
+    pub request: HttpMessage,
+    /// The response as modified by the filter, like `request`
+    pub response: HttpMessage,
+    /// Response sent by the filter, if any
+    pub local_response: Option<LocalResponse>,
+    /// Stream resumes and resets requested by the filter, in order
+    pub stream_actions: Vec<StreamAction>,
+}
+
+impl HttpOutcome {
+    /// Statuses returned by steps that return one, in order
+    pub fn statuses(&self) -> Vec<HttpStatus> {
+        self.steps
+            .iter()
+            .filter_map(|(_, status)| *status)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{metric, queued},
+        BaseContext, Context, Counter, HttpBodyControl, HttpContext, HttpControl,
+        HttpHeaderControl, Queue, RequestBody, RequestHeaders, ResponseHeaders,
+    };
+
+    #[derive(Default)]
+    struct UploadRoot {
+        announcements: Vec<Vec<u8>>,
+    }
+
+    impl BaseContext for UploadRoot {}
+
+    impl RootContext for UploadRoot {
+        fn on_vm_start(&mut self, _configuration: Option<Vec<u8>>) -> bool {
+            Queue::register("announcements")
+                .unwrap()
+                .on_receive(|root: &mut UploadRoot, _, data| root.announcements.push(data));
+            Queue::register("uploads").is_ok()
+        }
+
+        fn create_context(&mut self) -> Context {
+            Context::Http(Box::new(UploadFilter))
+        }
+
+        fn on_tick(&mut self) {
+            Counter::define("announcements").increment(self.announcements.len() as i64);
+        }
+    }
+
+    /// Buffers the request body, uppercases it, and reports its size on the `uploads` queue
+    struct UploadFilter;
+
+    impl BaseContext for UploadFilter {}
+
+    impl HttpContext for UploadFilter {
+        fn on_http_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
+            headers.set("x-upload", "1");
+            FilterHeadersStatus::Continue
+        }
+
+        fn on_http_request_body(&mut self, body: &RequestBody) -> FilterDataStatus {
+            if !body.end_of_stream() {
+                return FilterDataStatus::StopAllIterationAndBuffer;
+            }
+            let data = body.all().unwrap_or_default();
+            body.replace(&data.to_ascii_uppercase());
+            let queue = Queue::resolve("", "uploads").unwrap().unwrap();
+            queue.enqueue(data.len().to_string()).unwrap();
+            FilterDataStatus::Continue
+        }
+
+        fn on_http_response_headers(&mut self, headers: &ResponseHeaders) -> FilterHeadersStatus {
+            headers.remove("server");
+            FilterHeadersStatus::Continue
+        }
+    }
+
+    #[test]
+    fn test_http_scenario() {
+        let outcome = HttpScenario::new(UploadRoot::default)
+            .request_headers([(":path", "/upload")])
+            .expect(FilterHeadersStatus::Continue)
+            .request_body("hel")
+            .expect(FilterDataStatus::StopAllIterationAndBuffer)
+            .request_body_end("lo")
+            .expect(FilterDataStatus::Continue)
+            .response_headers_end([(":status", "200"), ("server", "envoy")])
+            .expect(FilterHeadersStatus::Continue)
+            .enqueue("announcements", "maintenance")
+            .tick()
+            .run();
+        assert_eq!(outcome.request.body, b"HELLO");
+        assert_eq!(outcome.request.get_header("x-upload"), Some(&b"1"[..]));
+        assert_eq!(outcome.response.status(), Some(200));
+        assert_eq!(outcome.response.get_header("server"), None);
+        assert_eq!(outcome.local_response, None);
+        assert_eq!(outcome.statuses().len(), 4);
+        assert_eq!(queued("uploads"), vec![b"5".to_vec()]);
+        assert!(queued("announcements").is_empty());
+        assert_eq!(metric("announcements"), Some(1));
+    }
+}
@@ -0,0 +1,21 @@
+//! Test harness running filters natively against an in-memory host.
+//!
+//! [`HttpScenario`] and [`TcpScenario`] drive a filter callback by callback, while [`FilterService`] passes whole
+//! messages through it.
+//!
+//! Enabling the `testing` feature defines the `proxy_*` hostcalls in this crate, so only enable it for tests.
+
+pub(crate) mod host;
+pub use host::{
+    enqueue, http_calls, logs, metric, queued, reset_host, set_log_level, set_property, set_time,
+    tick_period, HttpCallRecord, LocalResponse, StreamAction,
+};
+
+mod http;
+pub use http::*;
+
+mod stream;
+pub use stream::*;
+
+mod service;
+pub use service::*;
@@ -0,0 +1,393 @@
+use crate::{
+    dispatcher::{
+        proxy_on_configure, proxy_on_context_create, proxy_on_delete, proxy_on_done, proxy_on_log,
+        proxy_on_request_body, proxy_on_request_headers, proxy_on_request_trailers,
+        proxy_on_response_body, proxy_on_response_headers, proxy_on_response_trailers,
+        proxy_on_vm_start,
+    },
+    hostcalls::{BufferType, MapType},
+    FilterDataStatus, FilterHeadersStatus, FilterTrailersStatus, HttpPhase, RootContext,
+};
+
+use super::host::{reset_host, with_host, StreamAction};
+
+const ROOT_CONTEXT_ID: usize = 1;
+
+/// A request or response passing through a [`FilterService`]. Headers include pseudo-headers, i.e. `:path` or `:status`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HttpMessage {
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub trailers: Vec<(String, Vec<u8>)>,
+}
+
+impl HttpMessage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn trailer(mut self, name: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.trailers.push((name.into(), value.into()));
+        self
+    }
+
+    /// First value of header `name`
+    pub fn get_header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &**value)
+    }
+
+    /// Value of the `:status` pseudo-header
+    pub fn status(&self) -> Option<u32> {
+        std::str::from_utf8(self.get_header(":status")?)
+            .ok()?
+            .parse()
+            .ok()
+    }
+}
+
+/// Error of [`FilterService::call`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ServiceError {
+    /// The filter stopped iteration in its last callback of a direction without resuming, i.e. waiting on a callout
+    Stalled(HttpPhase),
+    /// The HTTP stream was reset by the filter
+    Reset,
+}
+
+/// Runs an HTTP filter natively around an inner service, the way a proxy would around its upstream.
+///
+/// Shaped like a `tower` `Layer` applied to a `Service`: `call` passes each request through the filter's request callbacks,
+/// hands the modified request to the inner service unless the filter sent a local response, then passes the response
+/// through the response callbacks. Embedding crates can wrap it in their own `tower::Service` impl.
+///
+/// The root context is created and configured on the first call and kept for the life of the service, while each call
+/// gets a new HTTP context. Host state is thread local, so only use one service per thread at a time.
+///
+/// ```ignore
+/// let mut service = FilterService::new(MyRoot::default, |request: HttpMessage| {
+///     HttpMessage::new().header(":status", "200").body(request.body)
+/// });
+/// let response = service.call(HttpMessage::new().header(":path", "/").body("hi")).unwrap();
+/// ```
+pub struct FilterService<R> {
+    root: Option<Box<dyn Fn() -> R>>,
+    vm_configuration: Option<Vec<u8>>,
+    configuration: Option<Vec<u8>>,
+    inner: Box<dyn FnMut(HttpMessage) -> HttpMessage>,
+    next_context_id: usize,
+}
+
+impl<R: RootContext + 'static> FilterService<R> {
+    pub fn new(
+        root: impl Fn() -> R + 'static,
+        inner: impl FnMut(HttpMessage) -> HttpMessage + 'static,
+    ) -> Self {
+        Self {
+            root: Some(Box::new(root)),
+            vm_configuration: None,
+            configuration: None,
+            inner: Box::new(inner),
+            next_context_id: ROOT_CONTEXT_ID + 1,
+        }
+    }
+
+    /// Configuration passed to `on_vm_start`
+    pub fn vm_configuration(mut self, configuration: impl Into<Vec<u8>>) -> Self {
+        self.vm_configuration = Some(configuration.into());
+        self
+    }
+
+    /// Configuration passed to `on_configure`
+    pub fn configuration(mut self, configuration: impl Into<Vec<u8>>) -> Self {
+        self.configuration = Some(configuration.into());
+        self
+    }
+
+    fn start(&mut self) {
+        let Some(root) = self.root.take() else {
+            return;
+        };
+        reset_host();
+        crate::metrics::reset_all_caches();
+        crate::dispatcher::reset_local(root);
+        let vm_configuration_size = self.vm_configuration.as_ref().map_or(0, Vec::len);
+        let configuration_size = self.configuration.as_ref().map_or(0, Vec::len);
+        with_host(|host| {
+            if let Some(configuration) = self.vm_configuration.take() {
+                host.buffers
+                    .insert(BufferType::VmConfiguration as u32, configuration);
+            }
+            if let Some(configuration) = self.configuration.take() {
+                host.buffers
+                    .insert(BufferType::PluginConfiguration as u32, configuration);
+            }
+        });
+        proxy_on_context_create(ROOT_CONTEXT_ID, 0);
+        assert_ne!(
+            proxy_on_vm_start(ROOT_CONTEXT_ID, vm_configuration_size),
+            0,
+            "on_vm_start failed"
+        );
+        assert_ne!(
+            proxy_on_configure(ROOT_CONTEXT_ID, configuration_size),
+            0,
+            "on_configure failed"
+        );
+    }
+
+    /// Passes `request` through the filter and the inner service
+    pub fn call(&mut self, request: HttpMessage) -> Result<HttpMessage, ServiceError> {
+        self.start();
+        let context_id = self.next_context_id;
+        self.next_context_id += 1;
+        with_host(|host| {
+            host.local_response = None;
+            host.stream_actions.clear();
+        });
+        proxy_on_context_create(context_id, ROOT_CONTEXT_ID);
+
+        let result = deliver(context_id, request, Direction::Request).and_then(|request| {
+            if let Some(response) = take_local_response() {
+                return Ok(response);
+            }
+            let response = (self.inner)(request);
+            let response = deliver(context_id, response, Direction::Response)?;
+            Ok(take_local_response().unwrap_or(response))
+        });
+
+        proxy_on_done(context_id);
+        proxy_on_log(context_id);
+        proxy_on_delete(context_id);
+        result
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Request,
+    Response,
+}
+
+fn take_local_response() -> Option<HttpMessage> {
+    let response = with_host(|host| host.local_response.take())?;
+    let mut headers = vec![(
+        ":status".to_string(),
+        response.status_code.to_string().into_bytes(),
+    )];
+    headers.extend(response.headers);
+    if let Some(grpc_status) = response.grpc_status {
+        headers.push((
+            "grpc-status".to_string(),
+            grpc_status.to_string().into_bytes(),
+        ));
+    }
+    Some(HttpMessage {
+        headers,
+        body: response.body,
+        trailers: vec![],
+    })
+}
+
+/// Calls the header, body and trailer callbacks of one direction with the whole message, returning the message
+/// as modified by the filter
+fn deliver(
+    context_id: usize,
+    message: HttpMessage,
+    direction: Direction,
+) -> Result<HttpMessage, ServiceError> {
+    let (headers_map, body_buffer, trailers_map) = match direction {
+        Direction::Request => (
+            MapType::HttpRequestHeaders,
+            BufferType::HttpRequestBody,
+            MapType::HttpRequestTrailers,
+        ),
+        Direction::Response => (
+            MapType::HttpResponseHeaders,
+            BufferType::HttpResponseBody,
+            MapType::HttpResponseTrailers,
+        ),
+    };
+    let has_body = !message.body.is_empty();
+    let has_trailers = !message.trailers.is_empty();
+    let (header_count, trailer_count) = (message.headers.len(), message.trailers.len());
+    with_host(|host| {
+        *host.header_map(headers_map) = message.headers;
+        *host.header_map(trailers_map) = message.trailers;
+        host.buffers.insert(body_buffer as u32, message.body);
+    });
+    let responded = || with_host(|host| host.local_response.is_some());
+
+    let mut stalled = None;
+    let headers_status = match direction {
+        Direction::Request => proxy_on_request_headers,
+        Direction::Response => proxy_on_response_headers,
+    }(
+        context_id,
+        header_count,
+        (!has_body && !has_trailers) as usize,
+    );
+    if headers_status != FilterHeadersStatus::Continue
+        && headers_status != FilterHeadersStatus::ContinueAndEndStream
+    {
+        stalled = Some(match direction {
+            Direction::Request => HttpPhase::RequestHeaders,
+            Direction::Response => HttpPhase::ResponseHeaders,
+        });
+    }
+    if has_body && !responded() {
+        let size = with_host(|host| host.buffers[&(body_buffer as u32)].len());
+        let status = match direction {
+            Direction::Request => proxy_on_request_body,
+            Direction::Response => proxy_on_response_body,
+        }(context_id, size, !has_trailers as usize);
+        stalled = (status != FilterDataStatus::Continue).then_some(match direction {
+            Direction::Request => HttpPhase::RequestBody,
+            Direction::Response => HttpPhase::ResponseBody,
+        });
+    }
+    if has_trailers && !responded() {
+        let status = match direction {
+            Direction::Request => proxy_on_request_trailers,
+            Direction::Response => proxy_on_response_trailers,
+        }(context_id, trailer_count);
+        stalled = (status != FilterTrailersStatus::Continue).then_some(match direction {
+            Direction::Request => HttpPhase::RequestTrailers,
+            Direction::Response => HttpPhase::ResponseTrailers,
+        });
+    }
+
+    let (resumed, reset) = with_host(|host| {
+        let (resume, reset) = match direction {
+            Direction::Request => (
+                StreamAction::ResumeHttpRequest,
+                StreamAction::ResetHttpRequest,
+            ),
+            Direction::Response => (
+                StreamAction::ResumeHttpResponse,
+                StreamAction::ResetHttpResponse,
+            ),
+        };
+        (
+            host.stream_actions.contains(&resume),
+            host.stream_actions.contains(&reset),
+        )
+    });
+    if reset {
+        return Err(ServiceError::Reset);
+    }
+    if let Some(phase) = stalled {
+        if !resumed && !responded() {
+            return Err(ServiceError::Stalled(phase));
+        }
+    }
+    Ok(with_host(|host| HttpMessage {
+        headers: std::mem::take(host.header_map(headers_map)),
+        body: host
+            .buffers
+            .remove(&(body_buffer as u32))
+            .unwrap_or_default(),
+        trailers: std::mem::take(host.header_map(trailers_map)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BaseContext, Context, HttpContext, HttpControl, HttpHeaderControl, RequestHeaders,
+        ResponseHeaders,
+    };
+
+    #[derive(Default)]
+    struct AuthRoot;
+
+    impl BaseContext for AuthRoot {}
+
+    impl RootContext for AuthRoot {
+        fn create_context(&mut self) -> Context {
+            Context::Http(Box::new(AuthFilter))
+        }
+    }
+
+    thread_local! {
+        static DELETED: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    }
+
+    /// Rejects requests without `authorization`, and tags responses
+    struct AuthFilter;
+
+    impl BaseContext for AuthFilter {
+        fn on_delete(&mut self) {
+            DELETED.set(DELETED.get() + 1);
+        }
+    }
+
+    impl HttpContext for AuthFilter {
+        fn on_http_request_headers(&mut self, headers: &RequestHeaders) -> FilterHeadersStatus {
+            if headers.get("authorization").is_none() {
+                headers
+                    .send_http_response(401, &[], Some(b"denied"))
+                    .unwrap();
+                return FilterHeadersStatus::StopIteration;
+            }
+            headers.set("x-user", "alice");
+            if headers.get(":path").as_deref() == Some(b"/healthz") {
+                headers.skip_response();
+            }
+            FilterHeadersStatus::Continue
+        }
+
+        fn on_http_response_headers(&mut self, headers: &ResponseHeaders) -> FilterHeadersStatus {
+            headers.set("x-filtered", "1");
+            FilterHeadersStatus::Continue
+        }
+    }
+
+    #[test]
+    fn test_filter_service() {
+        let mut service = FilterService::new(AuthRoot::default, |request: HttpMessage| {
+            let user = request.get_header("x-user").unwrap_or_default().to_vec();
+            HttpMessage::new().header(":status", "200").body(user)
+        });
+        let response = service
+            .call(
+                HttpMessage::new()
+                    .header(":path", "/")
+                    .header("authorization", "token"),
+            )
+            .unwrap();
+        assert_eq!(response.status(), Some(200));
+        assert_eq!(response.body, b"alice");
+        assert_eq!(response.get_header("x-filtered"), Some(&b"1"[..]));
+
+        let denied = service
+            .call(HttpMessage::new().header(":path", "/"))
+            .unwrap();
+        assert_eq!(denied.status(), Some(401));
+        assert_eq!(denied.body, b"denied");
+        assert_eq!(DELETED.get(), 2);
+
+        let health = service
+            .call(
+                HttpMessage::new()
+                    .header(":path", "/healthz")
+                    .header("authorization", "token"),
+            )
+            .unwrap();
+        assert_eq!(health.status(), Some(200));
+        assert_eq!(health.get_header("x-filtered"), None);
+    }
+}
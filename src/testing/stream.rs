@@ -0,0 +1,381 @@
+use crate::{
+    dispatcher::{
+        proxy_on_configure, proxy_on_context_create, proxy_on_delete, proxy_on_done,
+        proxy_on_downstream_connection_close, proxy_on_downstream_data, proxy_on_log,
+        proxy_on_new_connection, proxy_on_tick, proxy_on_upstream_connection_close,
+        proxy_on_upstream_data, proxy_on_vm_start,
+    },
+    hostcalls::BufferType,
+    CloseType, FilterStreamStatus, RootContext,
+};
+
+use super::host::{reset_host, with_host, StreamAction};
+
+const ROOT_CONTEXT_ID: usize = 1;
+const STREAM_CONTEXT_ID: usize = 2;
+
+/// An event delivered to a stream filter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TcpStep {
+    Connect,
+    Downstream {
+        data: Vec<u8>,
+        end_of_stream: bool,
+    },
+    Upstream {
+        data: Vec<u8>,
+        end_of_stream: bool,
+    },
+    DownstreamClose(CloseType),
+    UpstreamClose(CloseType),
+    /// Calls `on_tick` of the root context
+    Tick,
+}
+
+/// Declarative scenario for a stream (L4) filter. Steps run against the in-memory host in order,
+/// after the root context is created and configured.
+///
+/// ```ignore
+/// let outcome = TcpScenario::new(MyRoot::default)
+///     .connect()
+///     .downstream(b"PING").expect(FilterStreamStatus::StopIteration)
+///     .downstream_end(b"\r\n").expect(FilterStreamStatus::Continue)
+///     .close_downstream(CloseType::Remote)
+///     .run();
+/// assert_eq!(outcome.forwarded_upstream, b"PING\r\n");
+/// ```
+pub struct TcpScenario<R> {
+    root: Box<dyn Fn() -> R>,
+    vm_configuration: Option<Vec<u8>>,
+    configuration: Option<Vec<u8>>,
+    properties: Vec<(Vec<String>, Vec<u8>)>,
+    steps: Vec<(TcpStep, Option<FilterStreamStatus>)>,
+}
+
+impl<R: RootContext + 'static> TcpScenario<R> {
+    pub fn new(root: impl Fn() -> R + 'static) -> Self {
+        Self {
+            root: Box::new(root),
+            vm_configuration: None,
+            configuration: None,
+            properties: vec![],
+            steps: vec![],
+        }
+    }
+
+    /// Configuration passed to `on_vm_start`
+    pub fn vm_configuration(mut self, configuration: impl Into<Vec<u8>>) -> Self {
+        self.vm_configuration = Some(configuration.into());
+        self
+    }
+
+    /// Configuration passed to `on_configure`
+    pub fn configuration(mut self, configuration: impl Into<Vec<u8>>) -> Self {
+        self.configuration = Some(configuration.into());
+        self
+    }
+
+    /// Sets a connection property, i.e. `property(["source", "address"], "10.0.0.1:1234")`
+    pub fn property<S: AsRef<str>>(
+        mut self,
+        path: impl IntoIterator<Item = S>,
+        value: impl Into<Vec<u8>>,
+    ) -> Self {
+        let path = path.into_iter().map(|x| x.as_ref().to_string()).collect();
+        self.properties.push((path, value.into()));
+        self
+    }
+
+    pub fn step(mut self, step: TcpStep) -> Self {
+        self.steps.push((step, None));
+        self
+    }
+
+    /// Opens the connection, calling `on_new_connection`
+    pub fn connect(self) -> Self {
+        self.step(TcpStep::Connect)
+    }
+
+    /// Delivers a chunk of downstream data
+    pub fn downstream(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(TcpStep::Downstream {
+            data: data.into(),
+            end_of_stream: false,
+        })
+    }
+
+    /// Delivers the last chunk of downstream data, half-closing the downstream
+    pub fn downstream_end(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(TcpStep::Downstream {
+            data: data.into(),
+            end_of_stream: true,
+        })
+    }
+
+    /// Delivers a chunk of upstream data
+    pub fn upstream(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(TcpStep::Upstream {
+            data: data.into(),
+            end_of_stream: false,
+        })
+    }
+
+    /// Delivers the last chunk of upstream data, half-closing the upstream
+    pub fn upstream_end(self, data: impl Into<Vec<u8>>) -> Self {
+        self.step(TcpStep::Upstream {
+            data: data.into(),
+            end_of_stream: true,
+        })
+    }
+
+    pub fn close_downstream(self, close_type: CloseType) -> Self {
+        self.step(TcpStep::DownstreamClose(close_type))
+    }
+
+    pub fn close_upstream(self, close_type: CloseType) -> Self {
+        self.step(TcpStep::UpstreamClose(close_type))
+    }
+
+    pub fn tick(self) -> Self {
+        self.step(TcpStep::Tick)
+    }
+
+    /// Asserts the status returned for the previous step when the scenario runs
+    pub fn expect(mut self, status: FilterStreamStatus) -> Self {
+        let (step, expected) = self
+            .steps
+            .last_mut()
+            .expect("no step to expect a status of");
+        assert!(
+            matches!(
+                step,
+                TcpStep::Connect | TcpStep::Downstream { .. } | TcpStep::Upstream { .. }
+            ),
+            "{step:?} does not return a status"
+        );
+        *expected = Some(status);
+        self
+    }
+
+    /// Runs all steps, then completes and deletes the stream context. Panics if an expected status does not match.
+    pub fn run(self) -> TcpOutcome {
+        reset_host();
+        crate::metrics::reset_all_caches();
+        crate::dispatcher::reset_local(self.root);
+        for (path, value) in self.properties {
+            super::host::set_property(path, value);
+        }
+        let vm_configuration_size = self.vm_configuration.as_ref().map_or(0, Vec::len);
+        let configuration_size = self.configuration.as_ref().map_or(0, Vec::len);
+        with_host(|host| {
+            if let Some(configuration) = self.vm_configuration {
+                host.buffers
+                    .insert(BufferType::VmConfiguration as u32, configuration);
+            }
+            if let Some(configuration) = self.configuration {
+                host.buffers
+                    .insert(BufferType::PluginConfiguration as u32, configuration);
+            }
+        });
+
+        proxy_on_context_create(ROOT_CONTEXT_ID, 0);
+        assert_ne!(
+            proxy_on_vm_start(ROOT_CONTEXT_ID, vm_configuration_size),
+            0,
+            "on_vm_start failed"
+        );
+        assert_ne!(
+            proxy_on_configure(ROOT_CONTEXT_ID, configuration_size),
+            0,
+            "on_configure failed"
+        );
+        proxy_on_context_create(STREAM_CONTEXT_ID, ROOT_CONTEXT_ID);
+
+        let mut outcome = TcpOutcome::default();
+        for (i, (step, expected)) in self.steps.into_iter().enumerate() {
+            let status = match &step {
+                TcpStep::Connect => Some(proxy_on_new_connection(STREAM_CONTEXT_ID)),
+                TcpStep::Downstream {
+                    data,
+                    end_of_stream,
+                } => Some(deliver(
+                    BufferType::DownstreamData,
+                    data,
+                    *end_of_stream,
+                    &mut outcome.forwarded_upstream,
+                    proxy_on_downstream_data,
+                )),
+                TcpStep::Upstream {
+                    data,
+                    end_of_stream,
+                } => Some(deliver(
+                    BufferType::UpstreamData,
+                    data,
+                    *end_of_stream,
+                    &mut outcome.forwarded_downstream,
+                    proxy_on_upstream_data,
+                )),
+                TcpStep::DownstreamClose(close_type) => {
+                    proxy_on_downstream_connection_close(STREAM_CONTEXT_ID, *close_type);
+                    None
+                }
+                TcpStep::UpstreamClose(close_type) => {
+                    proxy_on_upstream_connection_close(STREAM_CONTEXT_ID, *close_type);
+                    None
+                }
+                TcpStep::Tick => {
+                    proxy_on_tick(ROOT_CONTEXT_ID);
+                    None
+                }
+            };
+            if let Some(expected) = expected {
+                assert_eq!(status, Some(expected), "step {i}: {step:?}");
+            }
+            outcome.steps.push((step, status));
+        }
+
+        proxy_on_done(STREAM_CONTEXT_ID);
+        proxy_on_log(STREAM_CONTEXT_ID);
+        proxy_on_delete(STREAM_CONTEXT_ID);
+
+        with_host(|host| {
+            outcome.written_upstream = std::mem::take(&mut host.written_upstream);
+            outcome.written_downstream = std::mem::take(&mut host.written_downstream);
+            outcome.stream_actions = std::mem::take(&mut host.stream_actions);
+        });
+        outcome
+    }
+}
+
+/// Appends `data` to the buffer, calls the filter, and forwards the buffer if it continued.
+/// Stopped data stays buffered and is redelivered with the next chunk, like Envoy does.
+fn deliver(
+    buffer_type: BufferType,
+    data: &[u8],
+    end_of_stream: bool,
+    forwarded: &mut Vec<u8>,
+    callback: extern "C" fn(usize, usize, usize) -> FilterStreamStatus,
+) -> FilterStreamStatus {
+    let size = with_host(|host| {
+        let buffer = host.buffers.entry(buffer_type as u32).or_default();
+        buffer.extend_from_slice(data);
+        buffer.len()
+    });
+    let status = callback(STREAM_CONTEXT_ID, size, end_of_stream as usize);
+    if status == FilterStreamStatus::Continue {
+        with_host(|host| {
+            if let Some(buffer) = host.buffers.get_mut(&(buffer_type as u32)) {
+                forwarded.append(buffer);
+            }
+        });
+    }
+    status
+}
+
+/// Result of [`TcpScenario::run`]. Metrics and logs remain readable through [`super::metric`] and [`super::logs`].
+#[derive(Debug, Default)]
+pub struct TcpOutcome {
+    /// Each step with the status it returned, if any
+    pub steps: Vec<(TcpStep, Option<FilterStreamStatus>)>,
+    /// Downstream data, as modified by the filter, that was let through to the upstream
+    pub forwarded_upstream: Vec<u8>,
+    /// Upstream data, as modified by the filter, that was let through to the downstream
+    pub forwarded_downstream: Vec<u8>,
+    /// Data injected with `write_upstream`
+    pub written_upstream: Vec<u8>,
+    /// Data injected with `write_downstream`
+    pub written_downstream: Vec<u8>,
+    /// Stream resumes and closes requested by the filter, in order
+    pub stream_actions: Vec<StreamAction>,
+}
+
+impl TcpOutcome {
+    /// Statuses returned by steps that return one, in order
+    pub fn statuses(&self) -> Vec<FilterStreamStatus> {
+        self.steps
+            .iter()
+            .filter_map(|(_, status)| *status)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::metric, BaseContext, Context, Counter, DownstreamData, Gauge, RootHandle,
+        StreamContext, StreamControl, StreamDataControl, UpstreamData,
+    };
+
+    #[derive(Default)]
+    struct LineRoot {
+        lines: u64,
+    }
+
+    impl BaseContext for LineRoot {}
+
+    impl RootContext for LineRoot {
+        fn create_context(&mut self) -> Context {
+            Context::Stream(Box::new(LineFilter))
+        }
+
+        fn on_tick(&mut self) {
+            Gauge::define("root_lines").record(self.lines);
+        }
+    }
+
+    /// Buffers downstream data until a full line, uppercases it, and closes on `QUIT` from upstream
+    struct LineFilter;
+
+    impl BaseContext for LineFilter {}
+
+    impl StreamContext for LineFilter {
+        fn on_new_connection(&mut self) -> FilterStreamStatus {
+            FilterStreamStatus::Continue
+        }
+
+        fn on_downstream_data(&mut self, data: &DownstreamData) -> FilterStreamStatus {
+            let line = data.all().unwrap_or_default();
+            if !line.ends_with(b"\n") && !data.end_of_stream() {
+                return FilterStreamStatus::StopIteration;
+            }
+            data.replace(&line.to_ascii_uppercase());
+            Counter::define("lines").increment(1);
+            RootHandle::current().spawn(|root: &mut LineRoot| root.lines += 1);
+            FilterStreamStatus::Continue
+        }
+
+        fn on_upstream_data(&mut self, data: &UpstreamData) -> FilterStreamStatus {
+            if data.all().unwrap_or_default() == b"QUIT" {
+                data.write_downstream(b"bye");
+                data.close_downstream();
+            }
+            FilterStreamStatus::Continue
+        }
+    }
+
+    #[test]
+    fn test_tcp_scenario() {
+        let outcome = TcpScenario::new(LineRoot::default)
+            .connect()
+            .expect(FilterStreamStatus::Continue)
+            .downstream("hel")
+            .expect(FilterStreamStatus::StopIteration)
+            .downstream("lo\n")
+            .expect(FilterStreamStatus::Continue)
+            .downstream_end("end")
+            .expect(FilterStreamStatus::Continue)
+            .upstream("QUIT")
+            .close_upstream(CloseType::Remote)
+            .tick()
+            .run();
+        assert_eq!(outcome.forwarded_upstream, b"HELLO\nEND");
+        assert_eq!(outcome.forwarded_downstream, b"QUIT");
+        assert_eq!(outcome.written_downstream, b"bye");
+        #[cfg(not(feature = "abi-0-2-0"))]
+        assert_eq!(outcome.stream_actions, vec![StreamAction::CloseDownstream]);
+        assert_eq!(outcome.statuses().len(), 5);
+        assert_eq!(metric("lines"), Some(2));
+        assert_eq!(metric("root_lines"), Some(2));
+    }
+}
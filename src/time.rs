@@ -1,4 +1,4 @@
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::{check_concern, hostcalls, log_concern};
 
@@ -40,3 +40,187 @@ pub fn instant_now() -> Instant {
 pub fn set_tick_period(period: Duration) {
     log_concern("set-tick-period", hostcalls::set_tick_period(period));
 }
+
+/// Milliseconds from the Unix epoch to `time`, zero for times before it
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Nanoseconds from the Unix epoch to `time`, zero for times before it
+pub(crate) fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Weekdays from the one of the Unix epoch
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+/// Parses an HTTP date, as in `Date`, `Expires` or `Last-Modified`. Accepts IMF-fixdate
+/// (`Sun, 06 Nov 1994 08:49:37 GMT`) and the obsolete RFC 850 (`Sunday, 06-Nov-94 08:49:37 GMT`) and asctime
+/// (`Sun Nov  6 08:49:37 1994`) formats, as RFC 7231 asks of recipients. The weekday is not checked.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (day, month, year, time) = match parts[..] {
+        [_, day, month, year, time, "GMT"] => (day, month, year.parse::<u64>().ok()?, time),
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let (day, month) = (date.next()?, date.next()?);
+            let year = match date.next()?.parse::<u64>().ok()? {
+                year @ 0..=69 => year + 2000,
+                year @ 70..=99 => year + 1900,
+                year => year,
+            };
+            (day, month, year, time)
+        }
+        [_, month, day, time, year] => (day, month, year.parse::<u64>().ok()?, time),
+        _ => return None,
+    };
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|x| *x == month)? as u64 + 1;
+    let mut time = time.split(':').map(|x| x.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some()
+        || year < 1970
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    let days = days_from_civil(year, month, day)?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second))
+}
+
+/// Formats `time` as an IMF-fixdate, i.e. `Sun, 06 Nov 1994 08:49:37 GMT`. Times before the Unix epoch format as the
+/// epoch.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Days since the Unix epoch of a proleptic Gregorian date, `None` for dates before the epoch
+pub(crate) fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    let (y, m) = if month <= 2 {
+        (year.checked_sub(1)?, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146097 + doe).checked_sub(719468)
+}
+
+/// Proleptic Gregorian `(year, month, day)` of days since the Unix epoch
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let doe = (days + 719468) % 146097;
+    let era = (days + 719468) / 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (era * 400 + yoe + (month <= 2) as u64, month, day)
+}
+
+/// Parses a `Retry-After` header, either in seconds or as an HTTP date, into the delay from `now`. Dates in the past
+/// are no delay.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if !value.is_empty() && value.bytes().all(|x| x.is_ascii_digit()) {
+        return Some(Duration::from_secs(value.parse().unwrap_or(u64::MAX)));
+    }
+    Some(
+        parse_http_date(value)?
+            .duration_since(now)
+            .unwrap_or_default(),
+    )
+}
+
+/// Formats `delay` as `Retry-After` seconds, rounded up so clients do not come back early
+pub fn format_retry_after(delay: Duration) -> String {
+    (delay.as_secs() + (delay.subsec_nanos() > 0) as u64).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        for value in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            assert_eq!(parse_http_date(value), Some(time), "{value}");
+        }
+        assert_eq!(
+            format_http_date(UNIX_EPOCH),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        let leap = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(format_http_date(leap), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(parse_http_date(&format_http_date(leap)), Some(leap));
+        assert_eq!(
+            parse_http_date("Thursday, 01-Jan-37 00:00:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(2114380800))
+        );
+
+        for value in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Wed, 31 Dec 1969 23:59:59 GMT",
+        ] {
+            assert_eq!(parse_http_date(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("-1", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(format_retry_after(Duration::from_millis(1001)), "2");
+        assert_eq!(format_retry_after(Duration::from_secs(3)), "3");
+    }
+}
@@ -0,0 +1,332 @@
+//! TLS inspection for stream (L4) filters on listeners that don't terminate TLS.
+
+use std::fmt;
+
+use crate::{stream::accumulate_prefix, StreamDataControl};
+
+const RECORD_HEADER_SIZE: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// Fields of interest from a TLS ClientHello
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// `legacy_version` field, i.e. `0x0303` for TLS 1.2 and 1.3
+    pub legacy_version: u16,
+    pub cipher_suites: Vec<u16>,
+    /// Host name from the server_name extension
+    pub server_name: Option<String>,
+    /// Protocols from the application_layer_protocol_negotiation extension
+    pub alpn: Vec<String>,
+    /// Versions from the supported_versions extension
+    pub supported_versions: Vec<u16>,
+}
+
+/// Errors parsing a ClientHello
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientHelloError {
+    /// The data is not a TLS handshake
+    NotTls,
+    /// The handshake is not a ClientHello or is malformed
+    Malformed(&'static str),
+    /// The ClientHello exceeds the configured maximum size
+    TooLarge,
+}
+
+impl fmt::Display for ClientHelloError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientHelloError::NotTls => write!(f, "not a tls handshake"),
+            ClientHelloError::Malformed(e) => write!(f, "malformed client hello: {e}"),
+            ClientHelloError::TooLarge => write!(f, "client hello too large"),
+        }
+    }
+}
+
+impl std::error::Error for ClientHelloError {}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize, field: &'static str) -> Result<&'a [u8], ClientHelloError> {
+        if self.data.len() < n {
+            return Err(ClientHelloError::Malformed(field));
+        }
+        let (out, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(out)
+    }
+
+    fn u8(&mut self, field: &'static str) -> Result<u8, ClientHelloError> {
+        Ok(self.bytes(1, field)?[0])
+    }
+
+    fn u16(&mut self, field: &'static str) -> Result<u16, ClientHelloError> {
+        let raw = self.bytes(2, field)?;
+        Ok(u16::from_be_bytes([raw[0], raw[1]]))
+    }
+
+    /// Reads a vector prefixed with a 1 or 2 byte length
+    fn vec(
+        &mut self,
+        length_bytes: usize,
+        field: &'static str,
+    ) -> Result<Reader<'a>, ClientHelloError> {
+        let length = match length_bytes {
+            1 => self.u8(field)? as usize,
+            _ => self.u16(field)? as usize,
+        };
+        Ok(Reader {
+            data: self.bytes(length, field)?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+impl ClientHello {
+    /// Parses a ClientHello from raw TLS records, which may be fragmented across several records.
+    /// Returns `Ok(None)` if more data is needed.
+    pub fn parse(records: &[u8]) -> Result<Option<Self>, ClientHelloError> {
+        let mut handshake = Vec::new();
+        let mut rest = records;
+        loop {
+            if handshake.len() >= 4 {
+                let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]);
+                if handshake.len() >= 4 + length as usize {
+                    return Self::parse_handshake(&handshake[..4 + length as usize]).map(Some);
+                }
+            }
+            if rest.len() < RECORD_HEADER_SIZE {
+                if !rest.is_empty() && rest[0] != CONTENT_TYPE_HANDSHAKE {
+                    return Err(ClientHelloError::NotTls);
+                }
+                return Ok(None);
+            }
+            if rest[0] != CONTENT_TYPE_HANDSHAKE || rest[1] != 0x03 {
+                return Err(ClientHelloError::NotTls);
+            }
+            let length = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            let Some(fragment) = rest.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + length) else {
+                // record incomplete, but its partial payload may already hold enough of the handshake header
+                handshake.extend_from_slice(&rest[RECORD_HEADER_SIZE..]);
+                if handshake
+                    .first()
+                    .is_some_and(|x| *x != HANDSHAKE_CLIENT_HELLO)
+                {
+                    return Err(ClientHelloError::Malformed("not a client hello"));
+                }
+                return Ok(None);
+            };
+            handshake.extend_from_slice(fragment);
+            if handshake
+                .first()
+                .is_some_and(|x| *x != HANDSHAKE_CLIENT_HELLO)
+            {
+                return Err(ClientHelloError::Malformed("not a client hello"));
+            }
+            rest = &rest[RECORD_HEADER_SIZE + length..];
+        }
+    }
+
+    fn parse_handshake(handshake: &[u8]) -> Result<Self, ClientHelloError> {
+        let mut reader = Reader {
+            data: &handshake[4..],
+        };
+        let mut out = ClientHello {
+            legacy_version: reader.u16("legacy_version")?,
+            ..Default::default()
+        };
+        reader.bytes(32, "random")?;
+        reader.vec(1, "session_id")?;
+        let mut ciphers = reader.vec(2, "cipher_suites")?;
+        while !ciphers.is_empty() {
+            out.cipher_suites.push(ciphers.u16("cipher_suites")?);
+        }
+        reader.vec(1, "compression_methods")?;
+        if reader.is_empty() {
+            return Ok(out);
+        }
+        let mut extensions = reader.vec(2, "extensions")?;
+        while !extensions.is_empty() {
+            let kind = extensions.u16("extension_type")?;
+            let mut data = extensions.vec(2, "extension_data")?;
+            match kind {
+                EXTENSION_SERVER_NAME => {
+                    let mut names = data.vec(2, "server_name_list")?;
+                    while !names.is_empty() {
+                        let name_type = names.u8("name_type")?;
+                        let name = names.vec(2, "host_name")?;
+                        if name_type == 0 && out.server_name.is_none() {
+                            out.server_name = Some(String::from_utf8_lossy(name.data).into_owned());
+                        }
+                    }
+                }
+                EXTENSION_ALPN => {
+                    let mut protocols = data.vec(2, "protocol_name_list")?;
+                    while !protocols.is_empty() {
+                        let protocol = protocols.vec(1, "protocol_name")?;
+                        out.alpn
+                            .push(String::from_utf8_lossy(protocol.data).into_owned());
+                    }
+                }
+                EXTENSION_SUPPORTED_VERSIONS => {
+                    let mut versions = data.vec(1, "supported_versions")?;
+                    while !versions.is_empty() {
+                        out.supported_versions
+                            .push(versions.u16("supported_versions")?);
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Incrementally parses a ClientHello from [`crate::StreamContext::on_downstream_data`] chunks.
+#[derive(Clone, Debug)]
+pub struct ClientHelloParser {
+    buffer: Vec<u8>,
+    max_size: usize,
+    result: Option<Result<ClientHello, ClientHelloError>>,
+}
+
+impl Default for ClientHelloParser {
+    fn default() -> Self {
+        Self {
+            buffer: vec![],
+            max_size: 16 * 1024 + RECORD_HEADER_SIZE,
+            result: None,
+        }
+    }
+}
+
+impl ClientHelloParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum number of bytes buffered before failing with [`ClientHelloError::TooLarge`]. Default is one full TLS record.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// The parse result, once complete
+    pub fn result(&self) -> Option<&Result<ClientHello, ClientHelloError>> {
+        self.result.as_ref()
+    }
+
+    /// Appends raw bytes. Returns the result once the ClientHello is complete or parsing failed.
+    pub fn push(&mut self, data: &[u8]) -> Option<&Result<ClientHello, ClientHelloError>> {
+        if self.result.is_none() {
+            self.buffer.extend_from_slice(data);
+            self.advance();
+        }
+        self.result.as_ref()
+    }
+
+    /// Inspects downstream data. Buffered data redelivered after returning [`crate::FilterStreamStatus::StopIteration`] is not counted twice.
+    pub fn on_downstream_data(
+        &mut self,
+        data: &impl StreamDataControl,
+    ) -> Option<&Result<ClientHello, ClientHelloError>> {
+        if self.result.is_none() {
+            accumulate_prefix(&mut self.buffer, data, self.max_size + 1);
+            self.advance();
+        }
+        self.result.as_ref()
+    }
+
+    fn advance(&mut self) {
+        match ClientHello::parse(&self.buffer) {
+            Ok(None) if self.buffer.len() > self.max_size => {
+                self.result = Some(Err(ClientHelloError::TooLarge))
+            }
+            Ok(None) => return,
+            Ok(Some(hello)) => self.result = Some(Ok(hello)),
+            Err(e) => self.result = Some(Err(e)),
+        }
+        self.buffer = vec![];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello() -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x04, 0x13, 0x01, 0xc0, 0x2f]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        let mut extensions = vec![];
+        // server_name
+        extensions.extend_from_slice(&[0x00, 0x00, 0x00, 0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b]);
+        extensions.extend_from_slice(b"example.com");
+        // alpn
+        extensions.extend_from_slice(&[0x00, 0x10, 0x00, 0x0e, 0x00, 0x0c, 0x02]);
+        extensions.extend_from_slice(b"h2");
+        extensions.push(0x08);
+        extensions.extend_from_slice(b"http/1.1");
+        // supported_versions
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        handshake
+    }
+
+    fn records(handshake: &[u8], fragment: usize) -> Vec<u8> {
+        let mut out = vec![];
+        for chunk in handshake.chunks(fragment) {
+            out.extend_from_slice(&[0x16, 0x03, 0x01]);
+            out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_fragmented() {
+        let expected = ClientHello {
+            legacy_version: 0x0303,
+            cipher_suites: vec![0x1301, 0xc02f],
+            server_name: Some("example.com".to_string()),
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            supported_versions: vec![0x0304],
+        };
+        let data = records(&client_hello(), 7);
+        let mut parser = ClientHelloParser::new();
+        for chunk in data.chunks(3) {
+            assert!(parser.result().is_none());
+            parser.push(chunk);
+        }
+        assert_eq!(parser.result(), Some(&Ok(expected)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            ClientHello::parse(b"GET / HTTP/1.1"),
+            Err(ClientHelloError::NotTls)
+        );
+        assert_eq!(
+            ClientHello::parse(&records(&[0x02, 0, 0, 0], 4)),
+            Err(ClientHelloError::Malformed("not a client hello"))
+        );
+        assert_eq!(ClientHello::parse(&[0x16, 0x03]), Ok(None));
+    }
+}
@@ -0,0 +1,537 @@
+//! OAuth2 access tokens for authenticated callouts.
+//!
+//! A [`TokenManager`] acquires tokens from a token endpoint with the client credentials or token exchange grant,
+//! shares them with the other VMs of the VM ID through [`SharedData`], and refreshes them before they expire.
+//! Only one refresh is in flight across all VMs at a time.
+//!
+//! ```ignore
+//! // in on_tick
+//! self.tokens.refresh_if_needed();
+//! // in an HTTP context
+//! self.tokens.dispatch_http(HttpCall { .. })?;
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    base64, current_context_id, dispatcher::defer_boxed, downcast_box::DowncastBox, json::Value,
+    now, with_context, Counter, GrpcCall, GrpcCancelHandle, HttpCall, HttpCallResponse,
+    RootContext, SharedData, Status, Upstream,
+};
+
+/// An access token with its expiry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub access_token: String,
+    /// Token type, almost always `Bearer`
+    pub token_type: String,
+    pub expires_at: SystemTime,
+}
+
+impl Token {
+    /// Value of the `authorization` header carrying this token
+    pub fn authorization(&self) -> String {
+        format!("{} {}", self.token_type, self.access_token)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let expires_at = self
+            .expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut out = expires_at.to_le_bytes().to_vec();
+        out.extend_from_slice(self.token_type.as_bytes());
+        out.push(b'\n');
+        out.extend_from_slice(self.access_token.as_bytes());
+        out
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let (expires_at, rest) = data.split_at_checked(8)?;
+        let expires_at = u64::from_le_bytes(expires_at.try_into().ok()?);
+        let rest = std::str::from_utf8(rest).ok()?;
+        let (token_type, access_token) = rest.split_once('\n')?;
+        Some(Self {
+            access_token: access_token.to_string(),
+            token_type: token_type.to_string(),
+            expires_at: UNIX_EPOCH + Duration::from_millis(expires_at),
+        })
+    }
+
+    /// Parses a token endpoint response (RFC 6749 section 5.1). Tokens without `expires_in` are valid for `default_ttl`.
+    fn from_response(body: &[u8], now: SystemTime, default_ttl: Duration) -> Result<Self, String> {
+        let value = Value::parse(body).map_err(|e| e.to_string())?;
+        if let Some(error) = value.get("error").and_then(Value::as_str) {
+            let description = value
+                .get("error_description")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            return Err(format!("{error} {description}").trim_end().to_string());
+        }
+        let access_token = value
+            .get("access_token")
+            .and_then(Value::as_str)
+            .ok_or("missing access_token")?;
+        let token_type = match value.get("token_type").and_then(Value::as_str) {
+            Some(x) if x.eq_ignore_ascii_case("bearer") => "Bearer",
+            Some(x) => x,
+            None => "Bearer",
+        };
+        let ttl = value
+            .get("expires_in")
+            .and_then(Value::as_i64)
+            .map_or(default_ttl, |x| Duration::from_secs(x.max(0) as u64));
+        Ok(Self {
+            access_token: access_token.to_string(),
+            token_type: token_type.to_string(),
+            expires_at: now + ttl,
+        })
+    }
+}
+
+/// Error of acquiring a token
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TokenError {
+    /// No valid token is cached yet. A refresh was started if possible.
+    Unavailable,
+    /// The token request could not be dispatched
+    Dispatch(Status),
+    /// The token endpoint rejected the request
+    Endpoint(String),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Unavailable => write!(f, "no access token available"),
+            TokenError::Dispatch(status) => write!(f, "failed to request token: {status:?}"),
+            TokenError::Endpoint(e) => write!(f, "token endpoint failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// How tokens are requested
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Grant {
+    /// `client_credentials` grant (RFC 6749 section 4.4)
+    ClientCredentials {
+        client_id: String,
+        client_secret: String,
+    },
+    /// `token-exchange` grant (RFC 8693), i.e. exchanging a projected service account token.
+    /// Client credentials are optional.
+    TokenExchange {
+        subject_token: String,
+        subject_token_type: String,
+        client: Option<(String, String)>,
+    },
+}
+
+type Waiter = Box<dyn FnOnce(&mut DowncastBox<dyn RootContext>, Result<Token, TokenError>)>;
+
+struct Inner {
+    name: String,
+    upstream: Upstream<'static>,
+    authority: String,
+    path: String,
+    grant: Grant,
+    scope: Option<String>,
+    audience: Option<String>,
+    timeout: Duration,
+    refresh_before: Duration,
+    default_ttl: Duration,
+    cached: RefCell<Option<Token>>,
+    refreshing: Cell<bool>,
+    waiters: RefCell<Vec<(u32, Waiter)>>,
+}
+
+/// Acquires, caches and refreshes OAuth2 access tokens for one token endpoint and client.
+/// Cheap to clone: clones share the cache, so a root context can hand one to each HTTP context.
+/// `{name}_token_refreshes` and `{name}_token_refresh_failed` count refreshes.
+#[derive(Clone)]
+pub struct TokenManager(Rc<Inner>);
+
+impl TokenManager {
+    /// Requests tokens from `path` on `upstream` with `grant`. `name` identifies the token in [`SharedData`] and metrics.
+    pub fn builder(
+        name: impl Into<String>,
+        upstream: Upstream<'static>,
+        authority: impl Into<String>,
+        path: impl Into<String>,
+        grant: Grant,
+    ) -> TokenManagerBuilder {
+        TokenManagerBuilder(Inner {
+            name: name.into(),
+            upstream,
+            authority: authority.into(),
+            path: path.into(),
+            grant,
+            scope: None,
+            audience: None,
+            timeout: Duration::from_secs(5),
+            refresh_before: Duration::from_secs(60),
+            default_ttl: Duration::from_secs(300),
+            cached: RefCell::new(None),
+            refreshing: Cell::new(false),
+            waiters: RefCell::new(vec![]),
+        })
+    }
+
+    fn token_key(&self) -> String {
+        format!("{}:token", self.0.name)
+    }
+
+    fn lock_key(&self) -> String {
+        format!("{}:token-refresh", self.0.name)
+    }
+
+    /// A token valid for at least the refresh margin, from this VM or shared by another VM
+    pub fn token(&self) -> Option<Token> {
+        let now = now();
+        let fresh = |token: &Token| token.expires_at > now + self.0.refresh_before;
+        if let Some(token) = self.0.cached.borrow().as_ref().filter(|x| fresh(x)) {
+            return Some(token.clone());
+        }
+        let token = SharedData::from_key(self.token_key())
+            .get()
+            .and_then(|x| Token::decode(&x))
+            .filter(fresh)?;
+        *self.0.cached.borrow_mut() = Some(token.clone());
+        Some(token)
+    }
+
+    /// Starts a refresh if no fresh token is available. Call from `on_tick` to refresh ahead of expiry,
+    /// and to serve waiters once another VM's refresh completed.
+    pub fn refresh_if_needed(&self) {
+        match self.token() {
+            Some(token) => self.resolve(Ok(token)),
+            None => {
+                if let Err(e) = self.refresh() {
+                    warn!("{} token refresh failed: {e}", self.0.name);
+                }
+            }
+        }
+    }
+
+    /// Calls `callback` with a token, right away if one is cached, otherwise once a refresh completes.
+    /// The callback runs in the context that called this, if it still exists.
+    pub fn with_token<R: RootContext + 'static>(
+        &self,
+        callback: impl FnOnce(&mut R, Result<Token, TokenError>) + 'static,
+    ) {
+        let waiter: Waiter = Box::new(move |root, result| {
+            callback(
+                root.as_any_mut().downcast_mut().expect("invalid root type"),
+                result,
+            )
+        });
+        if let Some(token) = self.token() {
+            defer_boxed(Box::new(move |root| waiter(root, Ok(token))));
+            return;
+        }
+        self.0
+            .waiters
+            .borrow_mut()
+            .push((current_context_id(), waiter));
+        if let Err(e) = self.refresh() {
+            self.resolve(Err(e));
+        }
+    }
+
+    /// Takes the refresh lock shared by all VMs, unless a refresh is already in flight
+    fn lock(&self) -> bool {
+        let lock = SharedData::from_key(self.lock_key());
+        let (held, cas) = lock.get_with_cas();
+        let now_ms = now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let held_until = held
+            .and_then(|x| Some(u64::from_le_bytes(x.try_into().ok()?)))
+            .unwrap_or_default();
+        if held_until > now_ms {
+            return false;
+        }
+        let until = (now_ms + self.0.timeout.as_millis() as u64).to_le_bytes();
+        match cas {
+            Some(cas) => lock.set_with_cas(until, cas),
+            None => {
+                lock.set(until);
+                true
+            }
+        }
+    }
+
+    fn unlock(&self) {
+        SharedData::from_key(self.lock_key()).set(0u64.to_le_bytes());
+    }
+
+    /// Requests a new token. Returns `Ok` without requesting anything if a refresh is already in flight in any VM.
+    fn refresh(&self) -> Result<(), TokenError> {
+        if self.0.refreshing.get() || !self.lock() {
+            return Ok(());
+        }
+        let body = self.request_body();
+        let mut headers: Vec<(&str, &[u8])> = vec![
+            (":method", b"POST"),
+            (":path", self.0.path.as_bytes()),
+            (":authority", self.0.authority.as_bytes()),
+            ("content-type", b"application/x-www-form-urlencoded"),
+            ("accept", b"application/json"),
+        ];
+        let basic = self.client().map(|(id, secret)| {
+            format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", form_encode(id), form_encode(secret)).as_bytes())
+            )
+        });
+        if let Some(basic) = &basic {
+            headers.push(("authorization", basic.as_bytes()));
+        }
+        let manager = self.clone();
+        let result = HttpCall {
+            upstream: self.0.upstream.clone(),
+            headers,
+            trailers: vec![],
+            body: Some(body.as_bytes()),
+            timeout: Some(self.0.timeout),
+            inherit_request_policy: false,
+            retry: None,
+            callback: Some(Box::new(move |_, response| manager.on_response(response))),
+        }
+        .dispatch();
+        if let Err(e) = result {
+            self.unlock();
+            Counter::define(format!("{}_token_refresh_failed", self.0.name)).increment(1);
+            return Err(TokenError::Dispatch(e));
+        }
+        self.0.refreshing.set(true);
+        Ok(())
+    }
+
+    fn client(&self) -> Option<(&str, &str)> {
+        match &self.0.grant {
+            Grant::ClientCredentials {
+                client_id,
+                client_secret,
+            } => Some((client_id, client_secret)),
+            Grant::TokenExchange { client, .. } => {
+                client.as_ref().map(|(id, secret)| (&**id, &**secret))
+            }
+        }
+    }
+
+    fn request_body(&self) -> String {
+        let mut fields = match &self.0.grant {
+            Grant::ClientCredentials { .. } => vec![("grant_type", "client_credentials")],
+            Grant::TokenExchange {
+                subject_token,
+                subject_token_type,
+                ..
+            } => vec![
+                (
+                    "grant_type",
+                    "urn:ietf:params:oauth:grant-type:token-exchange",
+                ),
+                ("subject_token", &**subject_token),
+                ("subject_token_type", &**subject_token_type),
+            ],
+        };
+        if let Some(scope) = &self.0.scope {
+            fields.push(("scope", scope));
+        }
+        if let Some(audience) = &self.0.audience {
+            fields.push(("audience", audience));
+        }
+        fields
+            .into_iter()
+            .map(|(key, value)| format!("{key}={}", form_encode(value)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    fn on_response(&self, response: &HttpCallResponse) {
+        self.0.refreshing.set(false);
+        self.unlock();
+        let status = response.header(":status").unwrap_or_default();
+        let body = response.full_body().unwrap_or_default();
+        let result = if status != b"200" && !body.starts_with(b"{") {
+            Err(format!("status {}", String::from_utf8_lossy(&status)))
+        } else {
+            Token::from_response(&body, now(), self.0.default_ttl)
+        };
+        match result {
+            Ok(token) => {
+                debug!("{} token refreshed", self.0.name);
+                Counter::define(format!("{}_token_refreshes", self.0.name)).increment(1);
+                SharedData::from_key(self.token_key()).set(token.encode());
+                *self.0.cached.borrow_mut() = Some(token.clone());
+                self.resolve(Ok(token));
+            }
+            Err(e) => {
+                warn!("{} token refresh failed: {e}", self.0.name);
+                Counter::define(format!("{}_token_refresh_failed", self.0.name)).increment(1);
+                self.resolve(Err(TokenError::Endpoint(e)));
+            }
+        }
+    }
+
+    /// Runs all waiters in the context they were registered from
+    fn resolve(&self, result: Result<Token, TokenError>) {
+        let waiters = std::mem::take(&mut *self.0.waiters.borrow_mut());
+        for (context_id, waiter) in waiters {
+            let pending = RefCell::new(Some((waiter, result.clone())));
+            let defer = || {
+                if let Some((waiter, result)) = pending.borrow_mut().take() {
+                    defer_boxed(Box::new(move |root| waiter(root, result)));
+                }
+            };
+            if with_context(context_id, defer).is_err() {
+                defer();
+            }
+        }
+    }
+
+    /// Dispatches `call` with the current token in its `authorization` header.
+    /// Returns [`TokenError::Unavailable`] without dispatching if there is no token yet, starting a refresh.
+    pub fn dispatch_http(&self, call: HttpCall<'_>) -> Result<(), TokenError> {
+        let authorization = self.authorization()?;
+        let mut headers = call.headers;
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("authorization"));
+        headers.push(("authorization", authorization.as_bytes()));
+        HttpCall {
+            upstream: call.upstream,
+            headers,
+            trailers: call.trailers,
+            body: call.body,
+            timeout: call.timeout,
+            callback: call.callback,
+            inherit_request_policy: call.inherit_request_policy,
+            retry: call.retry,
+        }
+        .dispatch()
+        .map_err(TokenError::Dispatch)
+    }
+
+    /// Dispatches `call` with the current token in its `authorization` metadata, see [`TokenManager::dispatch_http`]
+    pub fn dispatch_grpc(&self, call: GrpcCall<'_>) -> Result<GrpcCancelHandle, TokenError> {
+        let authorization = self.authorization()?;
+        let mut initial_metadata = call.initial_metadata;
+        initial_metadata.retain(|(name, _)| !name.eq_ignore_ascii_case("authorization"));
+        initial_metadata.push(("authorization", authorization.as_bytes()));
+        GrpcCall {
+            upstream: call.upstream,
+            service: call.service,
+            method: call.method,
+            initial_metadata,
+            message: call.message,
+            timeout: call.timeout,
+            callback: call.callback,
+        }
+        .dispatch()
+        .map_err(TokenError::Dispatch)
+    }
+
+    fn authorization(&self) -> Result<String, TokenError> {
+        match self.token() {
+            Some(token) => Ok(token.authorization()),
+            None => {
+                self.refresh()?;
+                Err(TokenError::Unavailable)
+            }
+        }
+    }
+}
+
+/// Options of a [`TokenManager`]
+pub struct TokenManagerBuilder(Inner);
+
+impl TokenManagerBuilder {
+    /// Space separated scopes to request
+    pub fn scope(mut self, scope: impl Into<String>) -> Self {
+        self.0.scope = Some(scope.into());
+        self
+    }
+
+    /// Audience to request, for endpoints that support it
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.0.audience = Some(audience.into());
+        self
+    }
+
+    /// Timeout of token requests. Default is 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.0.timeout = timeout;
+        self
+    }
+
+    /// Tokens expiring within this margin are refreshed. Default is 60 seconds.
+    pub fn refresh_before(mut self, refresh_before: Duration) -> Self {
+        self.0.refresh_before = refresh_before;
+        self
+    }
+
+    /// Lifetime of tokens returned without `expires_in`. Default is 5 minutes.
+    pub fn default_ttl(mut self, default_ttl: Duration) -> Self {
+        self.0.default_ttl = default_ttl;
+        self
+    }
+
+    pub fn build(self) -> TokenManager {
+        TokenManager(Rc::new(self.0))
+    }
+}
+
+/// `application/x-www-form-urlencoded` encoding of a value
+fn form_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                out.push(byte as char)
+            }
+            b' ' => out.push('+'),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_response() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let token = Token::from_response(
+            br#"{"access_token":"abc","token_type":"bearer","expires_in":3600}"#,
+            now,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert_eq!(token.authorization(), "Bearer abc");
+        assert_eq!(token.expires_at, now + Duration::from_secs(3600));
+        assert_eq!(Token::decode(&token.encode()), Some(token));
+
+        assert_eq!(
+            Token::from_response(
+                br#"{"error":"invalid_client"}"#,
+                now,
+                Duration::from_secs(60)
+            ),
+            Err("invalid_client".to_string())
+        );
+        assert_eq!(form_encode("a b&c=d/é"), "a+b%26c%3Dd%2F%C3%A9");
+    }
+}
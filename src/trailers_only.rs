@@ -0,0 +1,119 @@
+use crate::{
+    json::Value, BodyProgress, GrpcCode, HeaderStr, HttpControl, HttpError, HttpHeaderControl,
+    ResponseHeaders,
+};
+
+/// A GRPC error sent as a trailers-only response: a single headers block carrying `grpc-status`, with no body or trailers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GrpcTrailersOnly {
+    /// Raw `grpc-status` value
+    pub status: u32,
+    /// Percent-decoded `grpc-message`
+    pub message: Option<String>,
+}
+
+impl GrpcTrailersOnly {
+    /// Reads `grpc-status` and `grpc-message` from a headers block. `None` when there is no valid `grpc-status`.
+    pub fn from_headers<N: AsRef<str>, V: AsRef<[u8]>>(headers: &[(N, V)]) -> Option<Self> {
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|(x, _)| x.as_ref().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_ref())
+        };
+        let status = std::str::from_utf8(get("grpc-status")?)
+            .ok()?
+            .trim()
+            .parse()
+            .ok()?;
+        let message =
+            get("grpc-message").map(|x| HeaderStr::new(x).percent_decode().to_utf8_lossy());
+        Some(Self { status, message })
+    }
+
+    /// Detects a trailers-only response in response headers: the headers end the stream and carry `grpc-status`
+    pub fn detect(headers: &ResponseHeaders) -> Option<Self> {
+        if !headers.end_of_stream() {
+            return None;
+        }
+        Self::from_headers(&headers.all())
+    }
+
+    pub fn code(&self) -> GrpcCode {
+        GrpcCode::from(self.status)
+    }
+
+    /// HTTP status for REST clients, following the `google.rpc.Code` mapping used by GRPC-JSON transcoders
+    pub fn http_status(&self) -> u32 {
+        match self.status {
+            0 => 200,
+            1 => 499,
+            3 | 9 | 11 => 400,
+            4 => 504,
+            5 => 404,
+            6 | 10 => 409,
+            7 => 403,
+            8 => 429,
+            12 => 501,
+            14 => 503,
+            16 => 401,
+            _ => 500,
+        }
+    }
+
+    /// JSON error body, i.e. `{"code":5,"message":"not found"}`
+    pub fn json_body(&self) -> Vec<u8> {
+        Value::Object(vec![
+            ("code".to_string(), Value::from(self.status as i64)),
+            (
+                "message".to_string(),
+                Value::from(self.message.clone().unwrap_or_default()),
+            ),
+        ])
+        .to_bytes()
+    }
+
+    /// Replaces a trailers-only GRPC error in `headers` with a JSON HTTP response.
+    /// Returns `Ok(false)` without sending anything if the response is not a trailers-only GRPC error.
+    pub fn transcode(headers: &ResponseHeaders) -> Result<bool, HttpError> {
+        let Some(error) = Self::detect(headers).filter(|x| x.status != 0) else {
+            return Ok(false);
+        };
+        headers.send_http_response(
+            error.http_status(),
+            &[("content-type", b"application/json")],
+            Some(&error.json_body()),
+        )?;
+        Ok(true)
+    }
+
+    /// Whether the response of the active HTTP context ended in its headers, which for GRPC means trailers-only
+    pub fn response_was_trailers_only() -> bool {
+        BodyProgress::response().is_some_and(|x| x.headers_end_of_stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trailers_only() {
+        let headers: Vec<(&str, &[u8])> = vec![
+            (":status", b"200"),
+            ("content-type", b"application/grpc"),
+            ("grpc-status", b"5"),
+            ("grpc-message", b"user%20not%20found%zz"),
+        ];
+        let error = GrpcTrailersOnly::from_headers(&headers).unwrap();
+        assert_eq!(error.code(), GrpcCode::NotFound);
+        assert_eq!(error.message.as_deref(), Some("user not found%zz"));
+        assert_eq!(error.http_status(), 404);
+        assert_eq!(
+            error.json_body(),
+            br#"{"code":5,"message":"user not found%zz"}"#
+        );
+        let headers: Vec<(&str, &[u8])> = vec![(":status", b"200")];
+        assert_eq!(GrpcTrailersOnly::from_headers(&headers), None);
+    }
+}
@@ -0,0 +1,7 @@
+//! Utilities that rewrite HTTP bodies in flight.
+
+mod truncate;
+pub use truncate::*;
+
+mod redact;
+pub use redact::*;
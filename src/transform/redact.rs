@@ -0,0 +1,782 @@
+use std::{fmt, ops::Range};
+
+use log::warn;
+
+use crate::{
+    json::Value, matcher::Matcher, BodyLimitAction, Counter, FilterDataStatus, HttpBodyControl,
+    HttpHeaderControl, HttpType,
+};
+
+/// How a matched value is masked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mask {
+    /// Replaces the whole value with a fixed string
+    Full(String),
+    /// Keeps `prefix` leading and `suffix` trailing characters, replacing the rest with `mask_char`
+    Partial {
+        prefix: usize,
+        suffix: usize,
+        mask_char: char,
+    },
+    /// Replaces the value with a salted 64-bit FNV-1a hash. Stable for correlation, but not a cryptographic hash.
+    Hash { salt: Vec<u8> },
+    /// Replaces every character of the value with `mask_char`
+    Fill { mask_char: char },
+}
+
+impl Default for Mask {
+    fn default() -> Self {
+        Mask::Full("[REDACTED]".to_string())
+    }
+}
+
+impl Mask {
+    /// Masks `value`
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Mask::Full(replacement) => replacement.clone(),
+            Mask::Partial {
+                prefix,
+                suffix,
+                mask_char,
+            } => {
+                let length = value.chars().count();
+                if prefix + suffix >= length {
+                    return mask_char.to_string().repeat(length);
+                }
+                value
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| {
+                        if i < *prefix || i >= length - suffix {
+                            c
+                        } else {
+                            *mask_char
+                        }
+                    })
+                    .collect()
+            }
+            Mask::Hash { salt } => {
+                let mut hash = 0xcbf29ce484222325u64;
+                for byte in salt.iter().chain(value.as_bytes()) {
+                    hash ^= *byte as u64;
+                    hash = hash.wrapping_mul(0x100000001b3);
+                }
+                format!("hash:{hash:016x}")
+            }
+            Mask::Fill { mask_char } => mask_char.to_string().repeat(value.chars().count()),
+        }
+    }
+
+    /// Masks `value` in place, keeping its length in bytes, for [`Redactor::length_preserving`].
+    /// Each masked byte becomes the mask character, or `*` if it is not ASCII or would break JSON strings.
+    /// [`Mask::Full`] and [`Mask::Hash`] mask every byte with `*`.
+    pub fn apply_in_place(&self, value: &mut [u8]) {
+        let (prefix, suffix, mask_char) = match self {
+            Mask::Partial {
+                prefix,
+                suffix,
+                mask_char,
+            } => (*prefix, *suffix, *mask_char),
+            Mask::Fill { mask_char } => (0, 0, *mask_char),
+            Mask::Full(_) | Mask::Hash { .. } => (0, 0, '*'),
+        };
+        let mask_byte = match mask_char {
+            '"' | '\\' => b'*',
+            x if x.is_ascii() => x as u8,
+            _ => b'*',
+        };
+        // prefix and suffix count characters, kept whole
+        let boundaries: Vec<usize> = match std::str::from_utf8(value) {
+            Ok(x) => x.char_indices().map(|(i, _)| i).collect(),
+            Err(_) => (0..value.len()).collect(),
+        };
+        let (start, end) = if prefix + suffix >= boundaries.len() {
+            (0, value.len())
+        } else {
+            (
+                boundaries[prefix],
+                boundaries
+                    .get(boundaries.len() - suffix)
+                    .copied()
+                    .unwrap_or(value.len()),
+            )
+        };
+        value[start..end].fill(mask_byte);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+/// A JSON path such as `$.user.email`, `$.items[*].card` or `$['odd key'][0]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonPath(Vec<PathSegment>);
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Option<Self> {
+        let mut rest = path.strip_prefix('$')?;
+        let mut segments = vec![];
+        while !rest.is_empty() {
+            if let Some(tail) = rest.strip_prefix('.') {
+                let end = tail.find(['.', '[']).unwrap_or(tail.len());
+                let key = &tail[..end];
+                segments.push(match key {
+                    "" => return None,
+                    "*" => PathSegment::Wildcard,
+                    key => PathSegment::Key(key.to_string()),
+                });
+                rest = &tail[end..];
+            } else if let Some(tail) = rest.strip_prefix('[') {
+                let end = tail.find(']')?;
+                let inner = &tail[..end];
+                segments.push(if inner == "*" {
+                    PathSegment::Wildcard
+                } else if let Some(key) = inner
+                    .strip_prefix('\'')
+                    .and_then(|x| x.strip_suffix('\''))
+                    .or_else(|| inner.strip_prefix('"').and_then(|x| x.strip_suffix('"')))
+                {
+                    PathSegment::Key(key.to_string())
+                } else {
+                    PathSegment::Index(inner.parse().ok()?)
+                });
+                rest = &tail[end + 1..];
+            } else {
+                return None;
+            }
+        }
+        Some(Self(segments))
+    }
+
+    /// Calls `f` on every value matched by this path, returning the number of values visited.
+    pub fn visit_mut(&self, value: &mut Value, f: &mut impl FnMut(&mut Value)) -> usize {
+        Self::visit_segments(&self.0, value, f)
+    }
+
+    fn visit_segments(
+        segments: &[PathSegment],
+        value: &mut Value,
+        f: &mut impl FnMut(&mut Value),
+    ) -> usize {
+        let Some((segment, rest)) = segments.split_first() else {
+            f(value);
+            return 1;
+        };
+        match (segment, value) {
+            (PathSegment::Key(key), Value::Object(members)) => members
+                .iter_mut()
+                .filter(|(name, _)| name == key)
+                .map(|(_, value)| Self::visit_segments(rest, value, f))
+                .sum(),
+            (PathSegment::Index(index), Value::Array(items)) => items
+                .get_mut(*index)
+                .map(|value| Self::visit_segments(rest, value, f))
+                .unwrap_or_default(),
+            (PathSegment::Wildcard, Value::Array(items)) => items
+                .iter_mut()
+                .map(|value| Self::visit_segments(rest, value, f))
+                .sum(),
+            (PathSegment::Wildcard, Value::Object(members)) => members
+                .iter_mut()
+                .map(|(_, value)| Self::visit_segments(rest, value, f))
+                .sum(),
+            _ => 0,
+        }
+    }
+}
+
+/// What a [`RedactionRule`] applies to
+pub enum RedactionTarget {
+    /// A request or response header, by name
+    Header(String),
+    /// Values at a path in JSON bodies
+    JsonPath(JsonPath),
+    /// Matches of a pattern anywhere in bodies
+    Pattern(Box<dyn Matcher>),
+}
+
+impl fmt::Debug for RedactionTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header(name) => f.debug_tuple("Header").field(name).finish(),
+            Self::JsonPath(path) => f.debug_tuple("JsonPath").field(path).finish(),
+            Self::Pattern(_) => f.debug_tuple("Pattern").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A named redaction rule. Each rule reports the number of values it masked to the counter `{metric_prefix}{name}`.
+#[derive(Debug)]
+pub struct RedactionRule {
+    pub name: String,
+    pub target: RedactionTarget,
+    pub mask: Mask,
+}
+
+impl RedactionRule {
+    pub fn header(name: impl Into<String>, header: impl Into<String>, mask: Mask) -> Self {
+        Self {
+            name: name.into(),
+            target: RedactionTarget::Header(header.into().to_ascii_lowercase()),
+            mask,
+        }
+    }
+
+    /// Returns `None` if `path` is not a valid [`JsonPath`]
+    pub fn json_path(name: impl Into<String>, path: &str, mask: Mask) -> Option<Self> {
+        Some(Self {
+            name: name.into(),
+            target: RedactionTarget::JsonPath(JsonPath::parse(path)?),
+            mask,
+        })
+    }
+
+    pub fn pattern(name: impl Into<String>, matcher: impl Matcher + 'static, mask: Mask) -> Self {
+        Self {
+            name: name.into(),
+            target: RedactionTarget::Pattern(Box::new(matcher)),
+            mask,
+        }
+    }
+}
+
+/// Masks sensitive values in headers and bodies according to a set of [`RedactionRule`]s.
+#[derive(Debug)]
+pub struct Redactor {
+    rules: Vec<RedactionRule>,
+    metric_prefix: String,
+    max_body_bytes: usize,
+    on_oversize: Option<BodyLimitAction>,
+    length_preserving: bool,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            metric_prefix: "redacted_".to_string(),
+            max_body_bytes: 1024 * 1024,
+            on_oversize: None,
+            length_preserving: false,
+        }
+    }
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rule(mut self, rule: RedactionRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Prefix of the per-rule counters. Default is `redacted_`.
+    pub fn metric_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.metric_prefix = prefix.into();
+        self
+    }
+
+    /// Largest body buffered for redaction. Larger bodies are handled according to [`Redactor::on_oversize`]. Default is
+    /// 1 MiB.
+    pub fn max_body_bytes(mut self, max_body_bytes: usize) -> Self {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+
+    /// What to do with bodies larger than [`Redactor::max_body_bytes`]. Each one increments
+    /// `{metric_prefix}oversize_bodies`.
+    ///
+    /// [`BodyLimitAction::Reject`] sends a local response with its status. [`BodyLimitAction::PassThrough`] lets the
+    /// body through, masked chunk by chunk in length-preserving mode, else unmasked. Default is to reject requests
+    /// with 413 and responses with 500, as an unmasked body would leak what the rules protect.
+    pub fn on_oversize(mut self, on_oversize: BodyLimitAction) -> Self {
+        self.on_oversize = Some(on_oversize);
+        self
+    }
+
+    /// Masks body values with as many bytes as they had, see [`Mask::apply_in_place`]. Default is `false`.
+    ///
+    /// Bodies keep their size, so `content-length` and byte offsets downstream stay valid, and bodies can be masked
+    /// after their headers were sent, see [`Redactor::redact_chunk`]. JSON path rules only mask string values,
+    /// as other values cannot be masked at the same length.
+    pub fn length_preserving(mut self, length_preserving: bool) -> Self {
+        self.length_preserving = length_preserving;
+        self
+    }
+
+    fn record(&self, rule: &RedactionRule, count: usize) {
+        if count > 0 {
+            Counter::define(format!("{}{}", self.metric_prefix, rule.name)).increment(count as i64);
+        }
+    }
+
+    fn has_body_rules(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|x| !matches!(x.target, RedactionTarget::Header(_)))
+    }
+
+    /// Masks header values matching header rules in place. Removes `content-length` if body rules may change the
+    /// size of the body, so call it on the headers of every body passed to [`Redactor::redact_body`].
+    pub fn redact_headers(&self, headers: &impl HttpHeaderControl) {
+        if self.has_body_rules() && !self.length_preserving {
+            headers.remove("content-length");
+        }
+        for rule in &self.rules {
+            let RedactionTarget::Header(name) = &rule.target else {
+                continue;
+            };
+            if let Some(value) = headers.get(name) {
+                headers.set(name, rule.mask.apply(&String::from_utf8_lossy(&value)));
+                self.record(rule, 1);
+            }
+        }
+    }
+
+    /// Applies JSON path rules (if `body` is JSON) and pattern rules to a complete body.
+    /// Returns `None` if nothing was masked.
+    pub fn redact_bytes(&self, body: &[u8]) -> Option<Vec<u8>> {
+        let (out, counts) = self.apply_body_rules(body);
+        for (rule, count) in self.rules.iter().zip(counts) {
+            self.record(rule, count);
+        }
+        out
+    }
+
+    /// Applies body rules, returning the masked body (if changed) and the number of values masked per rule
+    fn apply_body_rules(&self, body: &[u8]) -> (Option<Vec<u8>>, Vec<usize>) {
+        if self.length_preserving {
+            return self.apply_body_rules_in_place(body, true);
+        }
+        let mut counts = vec![0; self.rules.len()];
+        let mut out = None::<Vec<u8>>;
+        let has_json_rules = self
+            .rules
+            .iter()
+            .any(|x| matches!(x.target, RedactionTarget::JsonPath(_)));
+        if has_json_rules {
+            if let Ok(mut value) = Value::parse(body) {
+                let mut changed = false;
+                for (i, rule) in self.rules.iter().enumerate() {
+                    let RedactionTarget::JsonPath(path) = &rule.target else {
+                        continue;
+                    };
+                    let count = path.visit_mut(&mut value, &mut |x| {
+                        let raw = match x {
+                            Value::String(x) => std::mem::take(x),
+                            ref other => other.to_string(),
+                        };
+                        *x = Value::String(rule.mask.apply(&raw));
+                    });
+                    changed |= count > 0;
+                    counts[i] = count;
+                }
+                if changed {
+                    out = Some(value.to_bytes());
+                }
+            }
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            let RedactionTarget::Pattern(matcher) = &rule.target else {
+                continue;
+            };
+            let current = out.as_deref().unwrap_or(body);
+            let matches = matcher.find_all(current);
+            if matches.is_empty() {
+                continue;
+            }
+            let mut next = Vec::with_capacity(current.len());
+            let mut last = 0;
+            for range in &matches {
+                next.extend_from_slice(&current[last..range.start]);
+                let masked = rule
+                    .mask
+                    .apply(&String::from_utf8_lossy(&current[range.clone()]));
+                next.extend_from_slice(masked.as_bytes());
+                last = range.end;
+            }
+            next.extend_from_slice(&current[last..]);
+            counts[i] = matches.len();
+            out = Some(next);
+        }
+        (out, counts)
+    }
+
+    /// Length-preserving variant of [`Redactor::apply_body_rules`]. JSON path rules are skipped unless `json`.
+    fn apply_body_rules_in_place(&self, body: &[u8], json: bool) -> (Option<Vec<u8>>, Vec<usize>) {
+        let mut counts = vec![0; self.rules.len()];
+        let mut out = body.to_vec();
+        let is_json = json
+            && self
+                .rules
+                .iter()
+                .any(|x| matches!(x.target, RedactionTarget::JsonPath(_)))
+            && Value::parse(body).is_ok();
+        for (i, rule) in self.rules.iter().enumerate() {
+            let spans = match &rule.target {
+                RedactionTarget::JsonPath(path) if is_json => {
+                    let mut spans = vec![];
+                    JsonSpans {
+                        input: body,
+                        pos: 0,
+                        path,
+                        stack: vec![],
+                        spans: &mut spans,
+                    }
+                    .value();
+                    spans
+                }
+                RedactionTarget::Pattern(matcher) => matcher.find_all(&out),
+                _ => continue,
+            };
+            for range in &spans {
+                rule.mask.apply_in_place(&mut out[range.clone()]);
+            }
+            counts[i] = spans.len();
+        }
+        let changed = counts.iter().any(|x| *x > 0);
+        (changed.then_some(out), counts)
+    }
+
+    /// Masks pattern matches in the current body chunk without buffering, in length-preserving mode.
+    /// Use it for bodies streamed after their headers were sent. Matches spanning two chunks are not found,
+    /// and JSON path rules do not apply.
+    pub fn redact_chunk(&self, body: &impl HttpBodyControl) -> FilterDataStatus {
+        if !self.length_preserving {
+            return FilterDataStatus::Continue;
+        }
+        let Some(chunk) = body.all() else {
+            return FilterDataStatus::Continue;
+        };
+        let (out, counts) = self.apply_body_rules_in_place(&chunk, false);
+        for (rule, count) in self.rules.iter().zip(counts) {
+            self.record(rule, count);
+        }
+        if let Some(out) = out {
+            body.replace(&out);
+        }
+        FilterDataStatus::Continue
+    }
+
+    /// Buffers a request or response body and masks it once complete
+    pub fn redact_body<B: HttpBodyControl>(&self, body: &B) -> FilterDataStatus {
+        if !self.has_body_rules() {
+            return FilterDataStatus::Continue;
+        }
+        if body.body_size() > self.max_body_bytes {
+            Counter::define(format!("{}oversize_bodies", self.metric_prefix)).increment(1);
+            let on_oversize = self.on_oversize.unwrap_or(match B::TYPE {
+                HttpType::Request => BodyLimitAction::Reject(413),
+                HttpType::Response => BodyLimitAction::Reject(500),
+            });
+            return match on_oversize {
+                BodyLimitAction::PassThrough => self.redact_chunk(body),
+                BodyLimitAction::Reject(status) => {
+                    warn!(
+                        "rejecting body of {} bytes, over the redaction limit",
+                        body.body_size()
+                    );
+                    if let Err(e) = body.send_http_response(status, &[], None) {
+                        warn!("failed to reject oversized body: {e:?}");
+                    }
+                    FilterDataStatus::StopIterationNoBuffer
+                }
+            };
+        }
+        if !body.end_of_stream() {
+            return FilterDataStatus::StopAllIterationAndBuffer;
+        }
+        if let Some(redacted) = body.all().and_then(|x| self.redact_bytes(&x)) {
+            body.replace(&redacted);
+        }
+        FilterDataStatus::Continue
+    }
+}
+
+/// Finds the contents of string values at a path in well-formed JSON, as byte ranges of the source
+struct JsonSpans<'a> {
+    input: &'a [u8],
+    pos: usize,
+    path: &'a JsonPath,
+    stack: Vec<PathSegment>,
+    spans: &'a mut Vec<Range<usize>>,
+}
+
+impl JsonSpans<'_> {
+    fn whitespace(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|x| x.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn is_match(&self) -> bool {
+        self.stack.len() == self.path.0.len()
+            && self
+                .path
+                .0
+                .iter()
+                .zip(&self.stack)
+                .all(|(segment, at)| segment == &PathSegment::Wildcard || segment == at)
+    }
+
+    /// Skips a string, returning the range of its contents
+    fn string(&mut self) -> Range<usize> {
+        self.pos += 1;
+        let start = self.pos;
+        while let Some(x) = self.input.get(self.pos) {
+            match x {
+                b'\\' => self.pos += 2,
+                b'"' => break,
+                _ => self.pos += 1,
+            }
+        }
+        let end = self.pos.min(self.input.len());
+        self.pos += 1;
+        start..end
+    }
+
+    fn value(&mut self) {
+        self.whitespace();
+        match self.input.get(self.pos) {
+            Some(b'"') => {
+                let range = self.string();
+                if self.is_match() {
+                    self.spans.push(range);
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                loop {
+                    self.whitespace();
+                    if self.input.get(self.pos) != Some(&b'"') {
+                        break;
+                    }
+                    let start = self.pos;
+                    self.string();
+                    let key = match Value::parse(&self.input[start..self.pos]) {
+                        Ok(Value::String(key)) => key,
+                        _ => return,
+                    };
+                    self.whitespace();
+                    self.pos += 1; // ':'
+                    self.stack.push(PathSegment::Key(key));
+                    self.value();
+                    self.stack.pop();
+                    self.whitespace();
+                    if self.input.get(self.pos) == Some(&b',') {
+                        self.pos += 1;
+                    }
+                }
+                self.pos += 1;
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                for index in 0.. {
+                    self.whitespace();
+                    if matches!(self.input.get(self.pos), None | Some(b']')) {
+                        break;
+                    }
+                    self.stack.push(PathSegment::Index(index));
+                    self.value();
+                    self.stack.pop();
+                    self.whitespace();
+                    if self.input.get(self.pos) == Some(&b',') {
+                        self.pos += 1;
+                    }
+                }
+                self.pos += 1;
+            }
+            Some(_) => {
+                while self
+                    .input
+                    .get(self.pos)
+                    .is_some_and(|x| !matches!(x, b',' | b']' | b'}') && !x.is_ascii_whitespace())
+                {
+                    self.pos += 1;
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::Regex;
+
+    #[test]
+    fn test_mask() {
+        let partial = Mask::Partial {
+            prefix: 2,
+            suffix: 2,
+            mask_char: '*',
+        };
+        assert_eq!(partial.apply("4111111111111111"), "41************11");
+        assert_eq!(partial.apply("abc"), "***");
+        let hash = Mask::Hash { salt: vec![] };
+        assert_eq!(hash.apply("a"), hash.apply("a"));
+        assert_ne!(hash.apply("a"), hash.apply("b"));
+    }
+
+    #[test]
+    fn test_redact_bytes() {
+        let redactor = Redactor::new()
+            .rule(RedactionRule::json_path("email", "$.users[*].email", Mask::default()).unwrap())
+            .rule(RedactionRule::pattern(
+                "ssn",
+                Regex::new(r"\d{3}-\d{2}-\d{4}").unwrap(),
+                Mask::Full("xxx".to_string()),
+            ));
+        let body = br#"{"users":[{"email":"a@b.c","note":"ssn 123-45-6789"},{"email":1}]}"#;
+        let (out, counts) = redactor.apply_body_rules(body);
+        assert_eq!(
+            out.unwrap(),
+            br#"{"users":[{"email":"[REDACTED]","note":"ssn xxx"},{"email":"[REDACTED]"}]}"#
+        );
+        assert_eq!(counts, vec![2, 1]);
+        assert_eq!(redactor.apply_body_rules(b"nothing"), (None, vec![0, 0]));
+    }
+
+    #[test]
+    fn test_length_preserving() {
+        let mut value = *b"4111111111111111";
+        Mask::Partial {
+            prefix: 4,
+            suffix: 4,
+            mask_char: '#',
+        }
+        .apply_in_place(&mut value);
+        assert_eq!(&value, b"4111########1111");
+        let mut value = "café".as_bytes().to_vec();
+        Mask::Fill { mask_char: 'é' }.apply_in_place(&mut value);
+        assert_eq!(value, b"*****");
+
+        let redactor = Redactor::new()
+            .length_preserving(true)
+            .rule(
+                RedactionRule::json_path(
+                    "email",
+                    "$.users[*].email",
+                    Mask::Fill { mask_char: 'x' },
+                )
+                .unwrap(),
+            )
+            .rule(RedactionRule::pattern(
+                "ssn",
+                Regex::new(r"\d{3}-\d{2}-\d{4}").unwrap(),
+                Mask::default(),
+            ));
+        let body = br#"{"users":[{"email":"a@b.c","note":"ssn 123-45-6789"},{"email":1},{"email":"q\"d"}]}"#;
+        let (out, counts) = redactor.apply_body_rules(body);
+        let out = out.unwrap();
+        assert_eq!(
+            out,
+            br#"{"users":[{"email":"xxxxx","note":"ssn ***********"},{"email":1},{"email":"xxxx"}]}"#
+        );
+        assert_eq!(out.len(), body.len());
+        assert_eq!(counts, vec![2, 1]);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_redact_http() {
+        use crate::{
+            hostcalls::{BufferType, MapType},
+            property::envoy::Attributes,
+            testing::{host::with_host, metric, reset_host},
+            RequestBody, RequestHeaders,
+        };
+
+        fn request(headers: &[(&str, &str)], body: &[u8]) -> (RequestHeaders, RequestBody) {
+            with_host(|host| {
+                *host.header_map(MapType::HttpRequestHeaders) = headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                    .collect();
+                host.buffers
+                    .insert(BufferType::HttpRequestBody as u32, body.to_vec());
+            });
+            (
+                RequestHeaders {
+                    header_count: headers.len(),
+                    end_of_stream: false,
+                    attributes: Attributes::get(),
+                },
+                RequestBody {
+                    body_size: body.len(),
+                    end_of_stream: true,
+                    attributes: Attributes::get(),
+                },
+            )
+        }
+
+        fn request_body() -> Vec<u8> {
+            with_host(|host| host.buffers[&(BufferType::HttpRequestBody as u32)].clone())
+        }
+
+        reset_host();
+        let ssn = || {
+            RedactionRule::pattern(
+                "ssn",
+                Regex::new(r"\d{3}-\d{2}-\d{4}").unwrap(),
+                Mask::default(),
+            )
+        };
+        let redactor = Redactor::new().max_body_bytes(32).rule(ssn());
+        let (headers, body) = request(&[("content-length", "15")], b"ssn 123-45-6789");
+        redactor.redact_headers(&headers);
+        assert_eq!(headers.get("content-length"), None);
+        assert_eq!(redactor.redact_body(&body), FilterDataStatus::Continue);
+        assert_eq!(request_body(), b"ssn [REDACTED]");
+
+        // length-preserving bodies keep their content-length
+        let preserving = Redactor::new().length_preserving(true).rule(ssn());
+        let (headers, _) = request(&[("content-length", "15")], b"ssn 123-45-6789");
+        preserving.redact_headers(&headers);
+        assert_eq!(headers.get("content-length"), Some(b"15".to_vec()));
+
+        // oversized bodies are rejected unless let through
+        let (_, body) = request(&[], &[b'1'; 33]);
+        assert_eq!(
+            redactor.redact_body(&body),
+            FilterDataStatus::StopIterationNoBuffer
+        );
+        assert_eq!(
+            with_host(|host| host.local_response.as_ref().map(|x| x.status_code)),
+            Some(413)
+        );
+        let passthrough = Redactor::new()
+            .max_body_bytes(32)
+            .on_oversize(BodyLimitAction::PassThrough)
+            .rule(ssn());
+        assert_eq!(passthrough.redact_body(&body), FilterDataStatus::Continue);
+        assert_eq!(metric("redacted_oversize_bodies"), Some(2));
+    }
+
+    #[test]
+    fn test_json_path_parse() {
+        assert_eq!(
+            JsonPath::parse("$.a['b c'][2].*"),
+            Some(JsonPath(vec![
+                PathSegment::Key("a".to_string()),
+                PathSegment::Key("b c".to_string()),
+                PathSegment::Index(2),
+                PathSegment::Wildcard,
+            ]))
+        );
+        assert_eq!(JsonPath::parse("a.b"), None);
+        assert_eq!(JsonPath::parse("$..a"), None);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{
+    FilterDataStatus, FilterHeadersStatus, HttpBodyControl, HttpControl, HttpHeaderControl,
+    ResponseBody, ResponseHeaders,
+};
+
+/// How a body is cut, derived from the response `content-type`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TruncateMode {
+    /// Cut on a UTF-8 boundary and append the text marker
+    Text,
+    /// Cut after the last complete top-level element and close the array/object so the result stays valid JSON
+    Json,
+    /// Cut at exactly the limit with no marker
+    Binary,
+}
+
+impl TruncateMode {
+    /// Picks a mode for a `content-type` header value
+    pub fn from_content_type(content_type: &[u8]) -> Self {
+        let content_type = String::from_utf8_lossy(content_type).to_ascii_lowercase();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if mime == "application/json" || mime.ends_with("+json") {
+            TruncateMode::Json
+        } else if mime.starts_with("text/")
+            || mime.ends_with("+xml")
+            || mime == "application/xml"
+            || mime == "application/javascript"
+        {
+            TruncateMode::Text
+        } else {
+            TruncateMode::Binary
+        }
+    }
+}
+
+/// Cuts response bodies at a fixed number of bytes, appending a content-type appropriate marker.
+/// Call [`Truncator::on_response_headers`] and [`Truncator::on_response_body`] from the matching [`crate::HttpContext`] callbacks.
+/// Compressed responses (any `content-encoding` other than `identity`) are passed through untouched.
+#[derive(Clone, Debug)]
+pub struct Truncator {
+    limit: usize,
+    text_marker: Vec<u8>,
+    json_marker: Option<Vec<u8>>,
+    truncated_header: Option<String>,
+    mode: TruncateMode,
+    seen: usize,
+    active: bool,
+    truncated: bool,
+}
+
+impl Truncator {
+    /// Creates a truncator cutting bodies after `limit` bytes.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            text_marker: b"...truncated".to_vec(),
+            json_marker: Some(b"\"...truncated\"".to_vec()),
+            truncated_header: None,
+            mode: TruncateMode::Binary,
+            seen: 0,
+            active: true,
+            truncated: false,
+        }
+    }
+
+    /// Marker appended to text bodies. Default is `...truncated`.
+    pub fn text_marker(mut self, marker: impl Into<Vec<u8>>) -> Self {
+        self.text_marker = marker.into();
+        self
+    }
+
+    /// Raw JSON value appended as a final element to truncated JSON arrays, or `None` for no marker. Default is `"...truncated"`.
+    pub fn json_marker(mut self, marker: Option<impl Into<Vec<u8>>>) -> Self {
+        self.json_marker = marker.map(Into::into);
+        self
+    }
+
+    /// Header set to `true` on responses whose `content-length` shows they will be truncated.
+    pub fn truncated_header(mut self, name: impl Into<String>) -> Self {
+        self.truncated_header = Some(name.into());
+        self
+    }
+
+    /// Returns `true` once a body has been cut
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Inspects content headers and removes `content-length` when the body may be truncated.
+    pub fn on_response_headers(&mut self, headers: &ResponseHeaders) -> FilterHeadersStatus {
+        if let Some(encoding) = headers.get("content-encoding") {
+            if !encoding.eq_ignore_ascii_case(b"identity") {
+                self.active = false;
+                return FilterHeadersStatus::Continue;
+            }
+        }
+        self.mode = headers
+            .get("content-type")
+            .map(|x| TruncateMode::from_content_type(&x))
+            .unwrap_or(TruncateMode::Binary);
+        let content_length = headers
+            .get("content-length")
+            .and_then(|x| std::str::from_utf8(&x).ok()?.trim().parse::<usize>().ok());
+        match content_length {
+            Some(length) if length <= self.limit => self.active = false,
+            Some(_) => {
+                headers.remove("content-length");
+                if let Some(name) = &self.truncated_header {
+                    headers.set(name, "true");
+                }
+            }
+            None => headers.remove("content-length"),
+        }
+        FilterHeadersStatus::Continue
+    }
+
+    /// Truncates the response body. JSON bodies are buffered up to the limit so they can be cut on an element boundary.
+    pub fn on_response_body(&mut self, body: &ResponseBody) -> FilterDataStatus {
+        if !self.active {
+            return FilterDataStatus::Continue;
+        }
+        if self.truncated {
+            body.clear();
+            return FilterDataStatus::Continue;
+        }
+        match self.mode {
+            TruncateMode::Json => {
+                // buffered data is redelivered, so body_size is the total so far
+                if body.body_size() <= self.limit {
+                    return if body.end_of_stream() {
+                        FilterDataStatus::Continue
+                    } else {
+                        FilterDataStatus::StopAllIterationAndBuffer
+                    };
+                }
+                let Some(data) = body.all() else {
+                    return FilterDataStatus::Continue;
+                };
+                body.replace(&truncate_json(
+                    &data,
+                    self.limit,
+                    self.json_marker.as_deref(),
+                ));
+                self.truncated = true;
+            }
+            TruncateMode::Text | TruncateMode::Binary => {
+                let size = body.body_size();
+                if self.seen + size <= self.limit {
+                    self.seen += size;
+                    return FilterDataStatus::Continue;
+                }
+                let keep = self.limit - self.seen;
+                let Some(mut data) = body.get(..keep) else {
+                    return FilterDataStatus::Continue;
+                };
+                if self.mode == TruncateMode::Text {
+                    data.truncate(utf8_boundary(&data, data.len()));
+                    data.extend_from_slice(&self.text_marker);
+                }
+                body.replace(&data);
+                self.seen = self.limit;
+                self.truncated = true;
+            }
+        }
+        FilterDataStatus::Continue
+    }
+}
+
+/// Largest index `<= at` that does not split a UTF-8 sequence
+fn utf8_boundary(data: &[u8], at: usize) -> usize {
+    let mut at = at.min(data.len());
+    // back off at most 3 continuation bytes
+    for _ in 0..3 {
+        if at == 0 || at == data.len() || data[at] & 0xC0 != 0x80 {
+            break;
+        }
+        at -= 1;
+    }
+    at
+}
+
+/// Cuts a JSON document to at most `limit` bytes (plus closing syntax and marker) on a top level element boundary.
+/// Top level scalars cannot be cut into valid JSON and are cut at `limit` unchanged.
+pub fn truncate_json(data: &[u8], limit: usize, marker: Option<&[u8]>) -> Vec<u8> {
+    if data.len() <= limit {
+        return data.to_vec();
+    }
+    let start = data
+        .iter()
+        .position(|x| !x.is_ascii_whitespace())
+        .unwrap_or(0);
+    let closing = match data.get(start) {
+        Some(b'[') => b']',
+        Some(b'{') => b'}',
+        _ => return data[..limit].to_vec(),
+    };
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    // end of the last complete top-level element, exclusive
+    let mut cut = None;
+    for (i, c) in data.iter().enumerate().skip(start) {
+        if i > limit {
+            break;
+        }
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            b'"' => in_string = true,
+            b'[' | b'{' => depth += 1,
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            b',' if depth == 1 => cut = Some(i),
+            _ => (),
+        }
+    }
+    let mut out = match cut {
+        Some(cut) => data[..cut].to_vec(),
+        None => data[..=start].to_vec(),
+    };
+    if closing == b']' {
+        if let Some(marker) = marker {
+            if cut.is_some() {
+                out.push(b',');
+            }
+            out.extend_from_slice(marker);
+        }
+    }
+    out.push(closing);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_json_array() {
+        let data = br#"[{"a":"x,y"},{"b":2},{"c":3}]"#;
+        assert_eq!(
+            truncate_json(data, 20, Some(b"\"...\"")),
+            br#"[{"a":"x,y"},{"b":2},"..."]"#
+        );
+        assert_eq!(truncate_json(data, 20, None), br#"[{"a":"x,y"},{"b":2}]"#);
+        assert_eq!(truncate_json(data, 5, None), b"[]");
+        assert_eq!(truncate_json(data, 100, None), data);
+    }
+
+    #[test]
+    fn test_truncate_json_object() {
+        let data = br#"{"a":[1,2,3],"b":"\",","c":true}"#;
+        assert_eq!(
+            truncate_json(data, 28, Some(b"0")),
+            br#"{"a":[1,2,3],"b":"\","}"#
+        );
+    }
+
+    #[test]
+    fn test_utf8_boundary() {
+        let data = "aé€".as_bytes();
+        assert_eq!(utf8_boundary(data, 2), 1);
+        assert_eq!(utf8_boundary(data, 3), 3);
+        assert_eq!(utf8_boundary(data, 5), 3);
+    }
+}
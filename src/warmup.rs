@@ -0,0 +1,264 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    hostcalls, instant_now, log_concern, with_context, Gauge, HttpControl, RequestHeaders,
+};
+
+/// What requests see while a [`Warmup`] is in progress, or after it failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WarmupPolicy {
+    /// Let requests through unprocessed
+    PassThrough,
+    /// Hold requests until warmup completes. Requests are rejected with a 503 if it fails.
+    Pause,
+    /// Reject requests with the given status code
+    Reject(u32),
+}
+
+/// Outcome of [`Warmup::admit`]
+pub enum Admission<T> {
+    /// Warmup completed, process the request with the built state
+    Ready(Rc<T>),
+    /// Let the request through unprocessed
+    PassThrough,
+    /// The request is held and will be resumed later. Return `StopIteration`.
+    Paused,
+    /// A local response was sent. Return `StopIteration`.
+    Rejected,
+}
+
+type Step<T> = Box<dyn FnOnce(&mut T) -> Result<(), String>>;
+
+enum State<T> {
+    Warming {
+        partial: T,
+        steps: VecDeque<Step<T>>,
+        total: usize,
+    },
+    Ready(Rc<T>),
+    Failed(String),
+    /// A step is running
+    Busy,
+}
+
+/// Builds expensive state, i.e. compiled matchers, in steps spread across ticks instead of all at once in `on_configure`,
+/// keeping each callback within the host's watchdog budget.
+///
+/// Each [`Warmup::advance`] runs steps until the slice budget is spent. Call it once from `on_configure` and then from `on_tick`
+/// until it returns `true`. Meanwhile, HTTP contexts call [`Warmup::admit`], which applies the [`WarmupPolicy`].
+/// Progress is exposed as `{name}_warmup_progress_pct` and readiness as `{name}_ready`.
+/// Requests held by [`WarmupPolicy`] are resumed by [`Warmup::advance`], so the root context and its HTTP contexts must use the
+/// same instance, i.e. an `Rc<Warmup<T>>` handed to each HTTP context it creates.
+pub struct Warmup<T> {
+    name: String,
+    policy: WarmupPolicy,
+    slice_budget: Duration,
+    state: RefCell<State<T>>,
+    paused: RefCell<Vec<u32>>,
+}
+
+impl<T: 'static> Warmup<T> {
+    /// Starts building from `initial`. Passes traffic through and runs steps for 10ms per slice by default.
+    pub fn new(name: impl Into<String>, initial: T) -> Self {
+        let name = name.into();
+        Gauge::define(format!("{name}_ready")).record(0);
+        Self {
+            name,
+            policy: WarmupPolicy::PassThrough,
+            slice_budget: Duration::from_millis(10),
+            state: RefCell::new(State::Warming {
+                partial: initial,
+                steps: VecDeque::new(),
+                total: 0,
+            }),
+            paused: RefCell::new(vec![]),
+        }
+    }
+
+    /// Adds a step. Steps run in order, and an error fails the warmup.
+    pub fn step(self, step: impl FnOnce(&mut T) -> Result<(), String> + 'static) -> Self {
+        if let State::Warming { steps, total, .. } = &mut *self.state.borrow_mut() {
+            steps.push_back(Box::new(step));
+            *total += 1;
+        }
+        self
+    }
+
+    pub fn policy(mut self, policy: WarmupPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Time spent running steps per [`Warmup::advance`]. At least one step runs per slice.
+    pub fn slice_budget(mut self, slice_budget: Duration) -> Self {
+        self.slice_budget = slice_budget;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The built state, once ready
+    pub fn get(&self) -> Option<Rc<T>> {
+        match &*self.state.borrow() {
+            State::Ready(x) => Some(x.clone()),
+            _ => None,
+        }
+    }
+
+    /// Error of the failed step, if the warmup failed
+    pub fn error(&self) -> Option<String> {
+        match &*self.state.borrow() {
+            State::Failed(e) => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    /// Runs steps for one slice. Returns `true` once warmup is over, successfully or not.
+    pub fn advance(&self) -> bool {
+        let state = std::mem::replace(&mut *self.state.borrow_mut(), State::Busy);
+        let next = match state {
+            State::Warming {
+                partial,
+                steps,
+                total,
+            } => self.run_slice(partial, steps, total, instant_now()),
+            State::Busy => {
+                warn!("warmup '{}' advanced from within a step", self.name);
+                return false;
+            }
+            done => done,
+        };
+        let done = !matches!(next, State::Warming { .. });
+        *self.state.borrow_mut() = next;
+        if done {
+            self.release_paused();
+        }
+        done
+    }
+
+    fn run_slice(
+        &self,
+        mut partial: T,
+        mut steps: VecDeque<Step<T>>,
+        total: usize,
+        start: Instant,
+    ) -> State<T> {
+        while let Some(step) = steps.pop_front() {
+            if let Err(e) = step(&mut partial) {
+                warn!("warmup '{}' failed: {e}", self.name);
+                return State::Failed(e);
+            }
+            if instant_now().saturating_duration_since(start) >= self.slice_budget {
+                break;
+            }
+        }
+        let progress = (total - steps.len()) * 100 / total.max(1);
+        Gauge::define(format!("{}_warmup_progress_pct", self.name)).record(progress as u64);
+        if !steps.is_empty() {
+            return State::Warming {
+                partial,
+                steps,
+                total,
+            };
+        }
+        info!("warmup '{}' complete", self.name);
+        Gauge::define(format!("{}_ready", self.name)).record(1);
+        State::Ready(Rc::new(partial))
+    }
+
+    /// Resumes or rejects requests held by [`WarmupPolicy::Pause`]
+    fn release_paused(&self) {
+        let failed = self.error().is_some();
+        for context_id in std::mem::take(&mut *self.paused.borrow_mut()) {
+            let _ = with_context(context_id, || {
+                if failed {
+                    log_concern(
+                        "warmup-reject",
+                        hostcalls::send_http_response(503, &[], None),
+                    );
+                } else {
+                    log_concern("warmup-resume", hostcalls::resume_http_request());
+                }
+            });
+        }
+    }
+
+    /// Decides what the current request does, from `on_http_request_headers`
+    pub fn admit(&self, headers: &RequestHeaders) -> Admission<T> {
+        let failed = match &*self.state.borrow() {
+            State::Ready(x) => return Admission::Ready(x.clone()),
+            State::Failed(_) => true,
+            _ => false,
+        };
+        match self.policy {
+            WarmupPolicy::PassThrough => Admission::PassThrough,
+            WarmupPolicy::Pause if !failed => {
+                self.paused.borrow_mut().push(crate::current_context_id());
+                Admission::Paused
+            }
+            WarmupPolicy::Pause => self.reject(headers, 503),
+            WarmupPolicy::Reject(status) => self.reject(headers, status),
+        }
+    }
+
+    fn reject(&self, headers: &RequestHeaders, status: u32) -> Admission<T> {
+        if let Err(e) = headers.send_http_response(status, &[], None) {
+            warn!("warmup '{}' failed to reject request: {e:?}", self.name);
+        }
+        Admission::Rejected
+    }
+}
+
+impl<T> fmt::Debug for Warmup<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match &*self.state.borrow() {
+            State::Warming { steps, total, .. } => {
+                format!("warming {}/{total}", total - steps.len())
+            }
+            State::Ready(_) => "ready".to_string(),
+            State::Failed(e) => format!("failed: {e}"),
+            State::Busy => "busy".to_string(),
+        };
+        f.debug_struct("Warmup")
+            .field("name", &self.name)
+            .field("state", &state)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advance() {
+        let warmup = Warmup::new("warmup", vec![])
+            .slice_budget(Duration::ZERO)
+            .step(|x: &mut Vec<u32>| {
+                x.push(1);
+                Ok(())
+            })
+            .step(|x| {
+                x.push(2);
+                Ok(())
+            });
+        assert!(!warmup.advance());
+        assert!(warmup.get().is_none());
+        assert!(warmup.advance());
+        assert_eq!(*warmup.get().unwrap(), vec![1, 2]);
+
+        let failing = Warmup::new("failing", ()).step(|_| Err("bad pattern".to_string()));
+        assert!(failing.advance());
+        assert_eq!(failing.error().as_deref(), Some("bad pattern"));
+    }
+}
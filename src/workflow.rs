@@ -0,0 +1,629 @@
+//! Named-step state machines for multi-callout request processing.
+//!
+//! A [`WorkflowDefinition`] declares steps and is shared between requests (i.e. kept in the root context).
+//! Each request starts its own [`RequestWorkflow`], stored in the HTTP context, which runs steps until one waits on a callout.
+//! Callout callbacks continue the workflow through a [`Resume`] handle, and the final [`Decision`] is applied to the paused request.
+//!
+//! ```ignore
+//! let definition = WorkflowDefinition::new("authz")
+//!     .callout("fetch_token", |state: &mut State, resume| {
+//!         match fetch_token(state, move |token| resume.complete(|state| { state.token = token; Transition::Next("check") })) {
+//!             Ok(()) => Transition::Wait,
+//!             Err(e) => Transition::Fail(format!("{e:?}")),
+//!         }
+//!     })
+//!     .decision("check", |state| if state.token.is_some() { Transition::Finish(Decision::Allow) } else { ... });
+//!
+//! // in on_http_request_headers
+//! self.workflow = Some(definition.start(State::default()));
+//! self.workflow.as_ref().unwrap().filter_status(headers)
+//! ```
+
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use log::{debug, warn};
+
+use crate::{
+    auth::Decision, property::envoy::Attributes, Counter, FilterHeadersStatus, RequestHeaders,
+};
+
+/// Upper bound on steps run without waiting, in case steps transition in a cycle
+const MAX_TRANSITIONS: usize = 64;
+
+/// What a step does, used for logs and [`RequestWorkflow`] rendering
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StepKind {
+    /// Dispatches an outbound call and waits for it
+    Callout,
+    /// Modifies workflow state
+    Transform,
+    /// Picks the next step from workflow state
+    Decision,
+}
+
+/// Returned by a step to move the workflow along
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transition {
+    /// Run the named step
+    Next(&'static str),
+    /// Wait for a [`Resume`] handle to be completed
+    Wait,
+    /// End the workflow, applying the decision to the request
+    Finish(Decision),
+    /// End the workflow with the definition's failure decision
+    Fail(String),
+}
+
+/// Current state of a [`RequestWorkflow`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WorkflowStatus {
+    /// Running the named step
+    Running(&'static str),
+    /// Waiting on a callout started by the named step
+    Waiting(&'static str),
+    Finished(Decision),
+    Failed {
+        step: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for WorkflowStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkflowStatus::Running(step) => write!(f, "running {step}"),
+            WorkflowStatus::Waiting(step) => write!(f, "waiting on {step}"),
+            WorkflowStatus::Finished(Decision::Allow) => write!(f, "allowed"),
+            WorkflowStatus::Finished(Decision::Deny { status_code, .. }) => {
+                write!(f, "denied ({status_code})")
+            }
+            WorkflowStatus::Failed { step, reason } => write!(f, "failed at {step}: {reason}"),
+        }
+    }
+}
+
+type CalloutAction<S> = Box<dyn Fn(&mut S, Resume<S>) -> Transition>;
+
+/// Result handler of a completed [`Resume`], with the entry of its step
+type Completion<S> = (usize, Box<dyn FnOnce(&mut S) -> Transition>);
+
+enum StepAction<S> {
+    Callout(CalloutAction<S>),
+    Transform(Box<dyn Fn(&mut S) -> Transition>),
+    Decision(Box<dyn Fn(&S) -> Transition>),
+}
+
+struct Step<S> {
+    name: &'static str,
+    action: StepAction<S>,
+}
+
+impl<S> Step<S> {
+    fn kind(&self) -> StepKind {
+        match self.action {
+            StepAction::Callout(_) => StepKind::Callout,
+            StepAction::Transform(_) => StepKind::Transform,
+            StepAction::Decision(_) => StepKind::Decision,
+        }
+    }
+}
+
+/// Declares the steps of a workflow. The first step declared is the initial step.
+pub struct WorkflowDefinition<S> {
+    name: String,
+    steps: Vec<Step<S>>,
+    failure_decision: Decision,
+    metrics: bool,
+}
+
+impl<S: 'static> WorkflowDefinition<S> {
+    /// Creates an empty workflow. Failed workflows deny with a 500 by default.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            steps: vec![],
+            failure_decision: Decision::Deny {
+                status_code: 500,
+                body: None,
+            },
+            metrics: true,
+        }
+    }
+
+    /// Adds a step that dispatches a call, returning [`Transition::Wait`] and completing `resume` from the call's callback
+    pub fn callout(
+        mut self,
+        name: &'static str,
+        action: impl Fn(&mut S, Resume<S>) -> Transition + 'static,
+    ) -> Self {
+        self.steps.push(Step {
+            name,
+            action: StepAction::Callout(Box::new(action)),
+        });
+        self
+    }
+
+    /// Adds a step that modifies workflow state
+    pub fn transform(
+        mut self,
+        name: &'static str,
+        action: impl Fn(&mut S) -> Transition + 'static,
+    ) -> Self {
+        self.steps.push(Step {
+            name,
+            action: StepAction::Transform(Box::new(action)),
+        });
+        self
+    }
+
+    /// Adds a step that only inspects workflow state
+    pub fn decision(
+        mut self,
+        name: &'static str,
+        action: impl Fn(&S) -> Transition + 'static,
+    ) -> Self {
+        self.steps.push(Step {
+            name,
+            action: StepAction::Decision(Box::new(action)),
+        });
+        self
+    }
+
+    /// Decision applied when a step fails or a transition is invalid
+    pub fn failure_decision(mut self, decision: Decision) -> Self {
+        self.failure_decision = decision;
+        self
+    }
+
+    /// Whether to count step entries as `workflow_{name}_{step}`, and outcomes as `workflow_{name}_finished` and `workflow_{name}_failed`. Default is `true`.
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Wraps the definition so it can be started from many HTTP contexts
+    pub fn build(self) -> Rc<Self> {
+        Rc::new(self)
+    }
+
+    /// Starts a workflow for the current request, running steps until one waits or the workflow ends.
+    pub fn start(self: &Rc<Self>, state: S) -> RequestWorkflow<S> {
+        let workflow = RequestWorkflow {
+            definition: self.clone(),
+            run: Rc::new(RefCell::new(Run {
+                state,
+                status: WorkflowStatus::Running(""),
+                history: vec![],
+                in_callback: true,
+            })),
+            completed: Rc::new(RefCell::new(None)),
+        };
+        let transition = match self.steps.first() {
+            Some(step) => Transition::Next(step.name),
+            None => Transition::Fail("workflow has no steps".to_string()),
+        };
+        workflow.drive(transition);
+        workflow.run.borrow_mut().in_callback = false;
+        workflow
+    }
+
+    fn step(&self, name: &str) -> Option<&Step<S>> {
+        self.steps.iter().find(|step| step.name == name)
+    }
+
+    fn count(&self, suffix: &str) {
+        if self.metrics {
+            Counter::define(format!("workflow_{}_{suffix}", self.name)).increment(1);
+        }
+    }
+}
+
+struct Run<S> {
+    state: S,
+    status: WorkflowStatus,
+    history: Vec<&'static str>,
+    /// `true` while started from the HTTP callback, where the decision is returned through [`RequestWorkflow::filter_status`] instead of applied
+    in_callback: bool,
+}
+
+/// A running workflow for a single request, stored in the HTTP context
+pub struct RequestWorkflow<S> {
+    definition: Rc<WorkflowDefinition<S>>,
+    run: Rc<RefCell<Run<S>>>,
+    /// A [`Resume`] completed while its step was running and borrowing `run`, with the step's entry
+    completed: Rc<RefCell<Option<Completion<S>>>>,
+}
+
+impl<S> Clone for RequestWorkflow<S> {
+    fn clone(&self) -> Self {
+        Self {
+            definition: self.definition.clone(),
+            run: self.run.clone(),
+            completed: self.completed.clone(),
+        }
+    }
+}
+
+impl<S: 'static> RequestWorkflow<S> {
+    pub fn status(&self) -> WorkflowStatus {
+        self.run.borrow().status.clone()
+    }
+
+    /// Names of the steps run so far, in order
+    pub fn history(&self) -> Vec<&'static str> {
+        self.run.borrow().history.clone()
+    }
+
+    /// Reads the workflow state
+    pub fn with_state<T>(&self, f: impl FnOnce(&S) -> T) -> T {
+        f(&self.run.borrow().state)
+    }
+
+    /// Filter status for the request headers callback that started the workflow.
+    /// Pauses the request while waiting, sends the local response for denials, and continues allowed requests.
+    pub fn filter_status(&self, headers: &RequestHeaders) -> FilterHeadersStatus {
+        let decision = match self.status() {
+            WorkflowStatus::Running(_) | WorkflowStatus::Waiting(_) => {
+                return FilterHeadersStatus::StopIteration
+            }
+            WorkflowStatus::Finished(decision) => decision,
+            WorkflowStatus::Failed { .. } => self.definition.failure_decision.clone(),
+        };
+        match decision {
+            Decision::Allow => FilterHeadersStatus::Continue,
+            deny => {
+                deny.apply(headers);
+                FilterHeadersStatus::StopIteration
+            }
+        }
+    }
+
+    fn drive(&self, mut transition: Transition) {
+        for _ in 0..MAX_TRANSITIONS {
+            let Ok(mut run) = self.run.try_borrow_mut() else {
+                warn!(
+                    "workflow {} was continued from within one of its own steps",
+                    self.definition.name
+                );
+                return;
+            };
+            let current = match &run.status {
+                WorkflowStatus::Running(step) | WorkflowStatus::Waiting(step) => *step,
+                _ => return,
+            };
+            match transition {
+                Transition::Next(name) => {
+                    let Some(step) = self.definition.step(name) else {
+                        drop(run);
+                        transition = Transition::Fail(format!("unknown step {name}"));
+                        continue;
+                    };
+                    debug!(
+                        "workflow {}: {current} -> {name} ({:?})",
+                        self.definition.name,
+                        step.kind()
+                    );
+                    self.definition.count(name);
+                    run.status = WorkflowStatus::Running(name);
+                    run.history.push(name);
+                    let run = &mut *run;
+                    let entry = run.history.len();
+                    transition = match &step.action {
+                        StepAction::Callout(action) => action(
+                            &mut run.state,
+                            Resume {
+                                workflow: self.clone(),
+                                step: name,
+                                entry,
+                            },
+                        ),
+                        StepAction::Transform(action) => action(&mut run.state),
+                        StepAction::Decision(action) => action(&run.state),
+                    };
+                    // a callout completed synchronously, i.e. from a cache or with an immediate error
+                    let completed = self.completed.borrow_mut().take();
+                    if let Some((completed, f)) = completed {
+                        if completed == entry && transition == Transition::Wait {
+                            transition = f(&mut run.state);
+                        } else {
+                            debug!(
+                                "workflow {}: dropping completion of {name}",
+                                self.definition.name
+                            );
+                        }
+                    }
+                }
+                Transition::Wait => {
+                    debug!("workflow {}: waiting on {current}", self.definition.name);
+                    run.status = WorkflowStatus::Waiting(current);
+                    return;
+                }
+                Transition::Finish(decision) => {
+                    debug!("workflow {}: finished at {current}", self.definition.name);
+                    self.definition.count("finished");
+                    run.status = WorkflowStatus::Finished(decision.clone());
+                    let in_callback = run.in_callback;
+                    drop(run);
+                    if !in_callback {
+                        self.apply(decision);
+                    }
+                    return;
+                }
+                Transition::Fail(reason) => {
+                    warn!(
+                        "workflow {} failed at {current}: {reason}",
+                        self.definition.name
+                    );
+                    self.definition.count("failed");
+                    run.status = WorkflowStatus::Failed {
+                        step: current,
+                        reason,
+                    };
+                    let in_callback = run.in_callback;
+                    drop(run);
+                    if !in_callback {
+                        self.apply(self.definition.failure_decision.clone());
+                    }
+                    return;
+                }
+            }
+        }
+        let current = match self.status() {
+            WorkflowStatus::Running(step) => step,
+            _ => return,
+        };
+        self.run.borrow_mut().status = WorkflowStatus::Failed {
+            step: current,
+            reason: "too many transitions".to_string(),
+        };
+        warn!(
+            "workflow {} exceeded {MAX_TRANSITIONS} transitions",
+            self.definition.name
+        );
+        self.definition.count("failed");
+        if !self.run.borrow().in_callback {
+            self.apply(self.definition.failure_decision.clone());
+        }
+    }
+
+    /// Resumes or rejects the paused request, from a callout callback
+    fn apply(&self, decision: Decision) {
+        decision.apply(&RequestHeaders {
+            header_count: 0,
+            end_of_stream: false,
+            attributes: Attributes::get(),
+        });
+    }
+}
+
+/// Renders as `name: step -> step (status)`, for logs
+impl<S> fmt::Display for RequestWorkflow<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let run = self.run.borrow();
+        write!(
+            f,
+            "{}: {} ({})",
+            self.definition.name,
+            run.history.join(" -> "),
+            run.status
+        )
+    }
+}
+
+/// Continues a workflow waiting on a callout. Completing a handle after the workflow moved on is ignored.
+/// A handle completed before its step returned takes effect once the step returns [`Transition::Wait`].
+pub struct Resume<S> {
+    workflow: RequestWorkflow<S>,
+    step: &'static str,
+    /// Length of the history when the step was entered, telling apart entries of the same step
+    entry: usize,
+}
+
+impl<S: 'static> Resume<S> {
+    /// Updates workflow state with the callout result and transitions to the returned step
+    pub fn complete(self, f: impl FnOnce(&mut S) -> Transition + 'static) {
+        let transition = {
+            let Ok(mut run) = self.workflow.run.try_borrow_mut() else {
+                // the run is borrowed by a running step, which picks this up once it returns
+                *self.workflow.completed.borrow_mut() = Some((self.entry, Box::new(f)));
+                return;
+            };
+            if run.status != WorkflowStatus::Waiting(self.step) || run.history.len() != self.entry {
+                debug!(
+                    "workflow {} callout {} completed while {}",
+                    self.workflow.definition.name, self.step, run.status
+                );
+                return;
+            }
+            f(&mut run.state)
+        };
+        self.workflow.drive(transition);
+    }
+
+    /// The workflow this handle continues
+    pub fn workflow(&self) -> &RequestWorkflow<S> {
+        &self.workflow
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::testing::{host::with_host, metric, reset_host, StreamAction};
+
+    thread_local! {
+        static RESUMES: RefCell<Vec<Resume<State>>> = const { RefCell::new(vec![]) };
+    }
+
+    #[derive(Default)]
+    struct State {
+        token: Option<String>,
+        attempts: u32,
+    }
+
+    fn headers() -> RequestHeaders {
+        RequestHeaders {
+            header_count: 0,
+            end_of_stream: false,
+            attributes: Attributes::get(),
+        }
+    }
+
+    fn check(state: &State) -> Transition {
+        match state.token {
+            Some(_) => Transition::Finish(Decision::Allow),
+            None => Transition::Finish(Decision::Deny {
+                status_code: 403,
+                body: None,
+            }),
+        }
+    }
+
+    fn resume(f: impl FnOnce(&mut State) -> Transition + 'static) {
+        let resume = RESUMES.with_borrow_mut(|x| x.remove(0));
+        resume.complete(f);
+    }
+
+    #[test]
+    fn test_workflow_resume() {
+        reset_host();
+        let definition = WorkflowDefinition::new("authz")
+            .callout("fetch", |_: &mut State, resume| {
+                RESUMES.with_borrow_mut(|x| x.push(resume));
+                Transition::Wait
+            })
+            .decision("check", check)
+            .build();
+        let workflow = definition.start(State::default());
+        assert_eq!(workflow.status(), WorkflowStatus::Waiting("fetch"));
+        assert_eq!(
+            workflow.filter_status(&headers()),
+            FilterHeadersStatus::StopIteration
+        );
+
+        resume(|state| {
+            state.token = Some("token".to_string());
+            Transition::Next("check")
+        });
+        assert_eq!(workflow.status(), WorkflowStatus::Finished(Decision::Allow));
+        assert_eq!(workflow.history(), ["fetch", "check"]);
+        assert_eq!(workflow.to_string(), "authz: fetch -> check (allowed)");
+        assert_eq!(
+            with_host(|host| host.stream_actions.clone()),
+            [StreamAction::ResumeHttpRequest]
+        );
+        assert_eq!(metric("workflow_authz_fetch"), Some(1));
+        assert_eq!(metric("workflow_authz_finished"), Some(1));
+    }
+
+    #[test]
+    fn test_workflow_unknown_step() {
+        reset_host();
+        let definition = WorkflowDefinition::new("authz")
+            .transform("first", |_: &mut State| Transition::Next("missing"))
+            .build();
+        let workflow = definition.start(State::default());
+        assert_eq!(
+            workflow.status(),
+            WorkflowStatus::Failed {
+                step: "first",
+                reason: "unknown step missing".to_string()
+            }
+        );
+        // the failure decision is returned to the starting callback rather than applied
+        assert!(with_host(|host| host.local_response.is_none()));
+        assert_eq!(
+            workflow.filter_status(&headers()),
+            FilterHeadersStatus::StopIteration
+        );
+        assert_eq!(
+            with_host(|host| host.local_response.as_ref().map(|x| x.status_code)),
+            Some(500)
+        );
+        assert_eq!(metric("workflow_authz_failed"), Some(1));
+    }
+
+    #[test]
+    fn test_workflow_cycle() {
+        reset_host();
+        let definition = WorkflowDefinition::new("cycle")
+            .transform("a", |state: &mut State| {
+                state.attempts += 1;
+                Transition::Next("b")
+            })
+            .decision("b", |_| Transition::Next("a"))
+            .metrics(false)
+            .build();
+        let workflow = definition.start(State::default());
+        assert!(matches!(
+            workflow.status(),
+            WorkflowStatus::Failed { reason, .. } if reason == "too many transitions"
+        ));
+        assert_eq!(workflow.history().len(), MAX_TRANSITIONS);
+        assert_eq!(
+            workflow.with_state(|x| x.attempts),
+            MAX_TRANSITIONS as u32 / 2
+        );
+    }
+
+    #[test]
+    fn test_workflow_stale_resume() {
+        reset_host();
+        let definition = WorkflowDefinition::new("authz")
+            .callout("fetch", |state: &mut State, resume| {
+                RESUMES.with_borrow_mut(|x| x.push(resume));
+                state.attempts += 1;
+                // the first attempt gives up on its callout and retries
+                match state.attempts {
+                    1 => Transition::Next("fetch"),
+                    _ => Transition::Wait,
+                }
+            })
+            .decision("check", check)
+            .build();
+        let workflow = definition.start(State::default());
+        assert_eq!(workflow.status(), WorkflowStatus::Waiting("fetch"));
+
+        // the handle of the abandoned first attempt is ignored, even though the same step waits
+        resume(|_| Transition::Fail("stale".to_string()));
+        assert_eq!(workflow.status(), WorkflowStatus::Waiting("fetch"));
+        resume(|_| Transition::Next("check"));
+        assert_eq!(
+            workflow.status(),
+            WorkflowStatus::Finished(Decision::Deny {
+                status_code: 403,
+                body: None
+            })
+        );
+        assert_eq!(
+            with_host(|host| host.local_response.as_ref().map(|x| x.status_code)),
+            Some(403)
+        );
+    }
+
+    #[test]
+    fn test_workflow_synchronous_completion() {
+        reset_host();
+        let definition = WorkflowDefinition::new("authz")
+            .callout("fetch", |_: &mut State, resume| {
+                // i.e. a cache hit
+                resume.complete(|state| {
+                    state.token = Some("cached".to_string());
+                    Transition::Next("check")
+                });
+                Transition::Wait
+            })
+            .decision("check", check)
+            .build();
+        let workflow = definition.start(State::default());
+        assert_eq!(workflow.status(), WorkflowStatus::Finished(Decision::Allow));
+        assert_eq!(workflow.history(), ["fetch", "check"]);
+        assert_eq!(
+            workflow.filter_status(&headers()),
+            FilterHeadersStatus::Continue
+        );
+        assert!(with_host(|host| host.stream_actions.is_empty()));
+    }
+}